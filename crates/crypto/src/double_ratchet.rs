@@ -9,27 +9,51 @@
 //! The Double Ratchet combines:
 //! - **DH Ratchet:** Generates new DH key pairs and performs new DH exchanges
 //! - **Symmetric Key Ratchet:** Derives new message keys from chain keys
+//! - **Header Encryption:** Message headers are encrypted with header keys that
+//!   advance with every DH ratchet step; the receiver finds the right key by
+//!   trial decryption with its current and next header keys
 //!
 //! ## Security Properties
 //!
 //! - **Forward Secrecy:** Past messages remain secure even if current keys compromised
 //! - **Post-Compromise Security:** New DH ratchet step restores security after compromise
 //! - **Message Loss Resilience:** Can handle out-of-order or lost messages
+//! - **Unlinkability:** Ratchet keys and message numbers never appear in the
+//!   clear, so relays and dead drops can't link messages by ratchet key
+//! - **Bounded Skipping:** At most `MAX_SKIP` skipped message keys are stored;
+//!   the oldest are dropped first
+//!
+//! ## Persistence
+//!
+//! `export_state` seals the full state, skipped message keys included, with
+//! AES-256-GCM under a caller-held state key. The format version byte leads
+//! the blob and is authenticated with it; `import_state` upgrades states
+//! written by older versions of the format. Sessions restored from version 1
+//! states predate header encryption and keep sending plaintext headers.
 
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
 use serde::{Deserialize, Serialize};
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 use crate::error::{CryptoError, Result};
-use crate::kdf::{kdf_ck, kdf_rk};
+use crate::kdf::{hkdf_sha256, kdf_ck, kdf_rk, kdf_rk_he};
 use crate::keys::KeyPair;
-use crate::utils::random_bytes;
+use crate::utils::{concat, random_bytes};
 
 /// Maximum number of skipped message keys to store
 const MAX_SKIP: usize = 1000;
 
+/// Current version of the serialized state format
+pub const STATE_VERSION: u8 = 2;
+
+/// Domain separator authenticated with every serialized state
+const STATE_CONTEXT: &[u8] = b"InvisibleRatchetState";
+
+/// Domain separator authenticated with every encrypted header
+const HEADER_CONTEXT: &[u8] = b"InvisibleRatchetHeader";
+
 /// Double Ratchet state
-#[derive(Debug, Zeroize, ZeroizeOnDrop)]
+#[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
 pub struct DoubleRatchet {
     /// DH ratchet key pair
     #[zeroize(skip)]
@@ -49,23 +73,91 @@ pub struct DoubleRatchet {
     recv_count: u32,
     /// Previous sending chain length (for header)
     prev_chain_len: u32,
+    /// Header keys (`None` for sessions with plaintext headers)
+    header_keys: Option<HeaderKeys>,
     /// Skipped message keys for out-of-order messages
-    #[zeroize(skip)]
     skipped_keys: Vec<SkippedKey>,
 }
 
+/// Header keys of a session with header encryption
+#[derive(Debug, Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct HeaderKeys {
+    /// Header key of the current sending chain
+    send: Option<Vec<u8>>,
+    /// Header key of the current receiving chain
+    recv: Option<Vec<u8>>,
+    /// Header key of the next sending chain
+    next_send: Option<Vec<u8>>,
+    /// Header key of the next receiving chain
+    next_recv: Option<Vec<u8>>,
+}
+
 /// A skipped message key for handling out-of-order messages
 #[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
 struct SkippedKey {
-    /// The DH public key used
-    #[zeroize(skip)]
-    public_key: Vec<u8>,
+    /// The chain's header key, or its DH public key with plaintext headers
+    chain: Vec<u8>,
     /// The message number
     message_num: u32,
     /// The message key
     message_key: Vec<u8>,
 }
 
+/// Serialized ratchet state, format version 1
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct StateV1 {
+    dh_self: KeyPair,
+    dh_remote: Vec<u8>,
+    root_key: Vec<u8>,
+    chain_key_send: Vec<u8>,
+    chain_key_recv: Vec<u8>,
+    send_count: u32,
+    recv_count: u32,
+    prev_chain_len: u32,
+    skipped_keys: Vec<SkippedKeyV1>,
+}
+
+/// Serialized ratchet state, format version 2 (adds header keys)
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct StateV2 {
+    dh_self: KeyPair,
+    dh_remote: Vec<u8>,
+    root_key: Vec<u8>,
+    chain_key_send: Vec<u8>,
+    chain_key_recv: Vec<u8>,
+    send_count: u32,
+    recv_count: u32,
+    prev_chain_len: u32,
+    header_keys: Option<HeaderKeys>,
+    skipped_keys: Vec<SkippedKeyV1>,
+}
+
+/// Serialized skipped message key, format versions 1 and 2
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct SkippedKeyV1 {
+    chain: Vec<u8>,
+    message_num: u32,
+    message_key: Vec<u8>,
+}
+
+impl StateV2 {
+    /// Upgrade a version 1 state, which has plaintext headers
+    fn from_v1(mut state: StateV1) -> Self {
+        Self {
+            dh_self: state.dh_self.clone(),
+            dh_remote: std::mem::take(&mut state.dh_remote),
+            root_key: std::mem::take(&mut state.root_key),
+            chain_key_send: std::mem::take(&mut state.chain_key_send),
+            chain_key_recv: std::mem::take(&mut state.chain_key_recv),
+            send_count: state.send_count,
+            recv_count: state.recv_count,
+            prev_chain_len: state.prev_chain_len,
+            header_keys: None,
+            skipped_keys: std::mem::take(&mut state.skipped_keys),
+        }
+    }
+}
+
 /// Message header containing ratchet information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageHeader {
@@ -77,11 +169,20 @@ pub struct MessageHeader {
     pub message_num: u32,
 }
 
+/// Message header as sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WireHeader {
+    /// Header in the clear (sessions restored from before header encryption)
+    Plain(MessageHeader),
+    /// Header encrypted under the sending chain's header key
+    Encrypted(Vec<u8>),
+}
+
 /// Encrypted message with header
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedMessage {
     /// Message header
-    pub header: MessageHeader,
+    pub header: WireHeader,
     /// Ciphertext
     pub ciphertext: Vec<u8>,
 }
@@ -93,38 +194,44 @@ impl DoubleRatchet {
     /// * `shared_secret` - Shared secret from X3DH
     /// * `remote_public_key` - Bob's signed pre-key public key
     pub fn init_alice(shared_secret: &[u8], remote_public_key: Vec<u8>) -> Result<Self> {
-        // Initialize with shared secret as root key
-        let root_key = shared_secret.to_vec();
+        // Split the shared secret into the root key and both initial header keys
+        let (root_key, header_key_alice, next_header_key_bob) = initial_keys(shared_secret)?;
 
-        // Generate initial DH key pair
-        let dh_self = KeyPair::generate()?;
-
-        // Perform initial DH ratchet step with remote public key
-        let dh_output = dh_self.dh(&remote_public_key)?;
-
-        // Derive new root key and sending chain key
-        let (new_root_key, chain_key_send) = kdf_rk(&root_key, &dh_output)?;
-
-        Ok(Self {
-            dh_self,
+        let mut ratchet = Self {
+            // Generate initial DH key pair
+            dh_self: KeyPair::generate()?,
             dh_remote: remote_public_key,
-            root_key: new_root_key,
-            chain_key_send,
+            root_key,
+            chain_key_send: Vec::new(),
             chain_key_recv: vec![0u8; 32], // Will be initialized on first receive
             send_count: 0,
             recv_count: 0,
             prev_chain_len: 0,
+            header_keys: Some(HeaderKeys {
+                send: Some(header_key_alice),
+                recv: None,
+                next_send: None,
+                next_recv: Some(next_header_key_bob),
+            }),
             skipped_keys: Vec::new(),
-        })
+        };
+
+        // Perform initial DH ratchet step with remote public key
+        let dh_output = ratchet.dh_self.dh(&ratchet.dh_remote)?;
+        ratchet.chain_key_send = ratchet.root_step(&dh_output, true)?;
+
+        Ok(ratchet)
     }
 
     /// Initialize Double Ratchet from X3DH shared secret (Bob - receiving first)
     ///
+    /// Bob can't send until he has received Alice's first message.
+    ///
     /// # Arguments
     /// * `shared_secret` - Shared secret from X3DH
     /// * `keypair` - Bob's signed pre-key pair
     pub fn init_bob(shared_secret: &[u8], keypair: KeyPair) -> Result<Self> {
-        let root_key = shared_secret.to_vec();
+        let (root_key, header_key_alice, next_header_key_bob) = initial_keys(shared_secret)?;
 
         Ok(Self {
             dh_self: keypair,
@@ -135,23 +242,117 @@ impl DoubleRatchet {
             send_count: 0,
             recv_count: 0,
             prev_chain_len: 0,
+            header_keys: Some(HeaderKeys {
+                send: None,
+                recv: None,
+                next_send: Some(next_header_key_bob),
+                next_recv: Some(header_key_alice),
+            }),
             skipped_keys: Vec::new(),
         })
     }
 
+    /// Serialize the full ratchet state, sealed under `state_key`
+    ///
+    /// The state holds the keys for every message still to come in this
+    /// session, so the state key must be guarded like the identity keys.
+    ///
+    /// # Arguments
+    /// * `state_key` - 32-byte key the state is encrypted and authenticated with
+    ///
+    /// # Returns
+    /// * `version || nonce || ciphertext`, for `import_state`
+    pub fn export_state(&self, state_key: &[u8]) -> Result<Vec<u8>> {
+        let state = StateV2 {
+            dh_self: self.dh_self.clone(),
+            dh_remote: self.dh_remote.clone(),
+            root_key: self.root_key.clone(),
+            chain_key_send: self.chain_key_send.clone(),
+            chain_key_recv: self.chain_key_recv.clone(),
+            send_count: self.send_count,
+            recv_count: self.recv_count,
+            prev_chain_len: self.prev_chain_len,
+            header_keys: self.header_keys.clone(),
+            skipped_keys: self
+                .skipped_keys
+                .iter()
+                .map(|k| SkippedKeyV1 {
+                    chain: k.chain.clone(),
+                    message_num: k.message_num,
+                    message_key: k.message_key.clone(),
+                })
+                .collect(),
+        };
+        let encoded = Zeroizing::new(bincode::serialize(&state)?);
+
+        let mut sealed = vec![STATE_VERSION];
+        sealed.extend_from_slice(&Self::aead_encrypt(
+            state_key,
+            &encoded,
+            &state_aad(STATE_VERSION),
+        )?);
+
+        Ok(sealed)
+    }
+
+    /// Restore a ratchet from `export_state` output
+    ///
+    /// States written by an older format version are upgraded. Fails if the
+    /// state was modified or sealed under a different key.
+    ///
+    /// # Arguments
+    /// * `sealed` - Serialized state
+    /// * `state_key` - Key the state was exported with
+    pub fn import_state(sealed: &[u8], state_key: &[u8]) -> Result<Self> {
+        let (version, ciphertext) = sealed
+            .split_first()
+            .ok_or_else(|| CryptoError::RatchetStateError("Empty state".to_string()))?;
+        if !(1..=STATE_VERSION).contains(version) {
+            return Err(CryptoError::RatchetStateError(format!(
+                "Unsupported state version {}",
+                version
+            )));
+        }
+
+        let encoded = Zeroizing::new(
+            Self::aead_decrypt(state_key, ciphertext, &state_aad(*version))
+                .map_err(|_| CryptoError::AuthenticationFailed)?,
+        );
+
+        // Older formats are decoded here and upgraded step by step
+        let mut state: StateV2 = match version {
+            1 => StateV2::from_v1(bincode::deserialize(&encoded)?),
+            _ => bincode::deserialize(&encoded)?,
+        };
+
+        Ok(Self {
+            dh_self: state.dh_self.clone(),
+            dh_remote: std::mem::take(&mut state.dh_remote),
+            root_key: std::mem::take(&mut state.root_key),
+            chain_key_send: std::mem::take(&mut state.chain_key_send),
+            chain_key_recv: std::mem::take(&mut state.chain_key_recv),
+            send_count: state.send_count,
+            recv_count: state.recv_count,
+            prev_chain_len: state.prev_chain_len,
+            header_keys: state.header_keys.take(),
+            skipped_keys: state
+                .skipped_keys
+                .iter_mut()
+                .map(|k| SkippedKey {
+                    chain: std::mem::take(&mut k.chain),
+                    message_num: k.message_num,
+                    message_key: std::mem::take(&mut k.message_key),
+                })
+                .collect(),
+        })
+    }
+
     /// Encrypt a message
     ///
     /// # Arguments
     /// * `plaintext` - The message to encrypt
     /// * `associated_data` - Additional authenticated data
     pub fn encrypt(&mut self, plaintext: &[u8], associated_data: &[u8]) -> Result<EncryptedMessage> {
-        // Derive message key from sending chain
-        let (new_chain_key, message_key) = kdf_ck(&self.chain_key_send)?;
-        self.chain_key_send = new_chain_key;
-
-        // Encrypt plaintext with message key
-        let ciphertext = self.aead_encrypt(&message_key, plaintext, associated_data)?;
-
         // Build message header
         let header = MessageHeader {
             public_key: self.dh_self.public_key().to_vec(),
@@ -159,6 +360,28 @@ impl DoubleRatchet {
             message_num: self.send_count,
         };
 
+        // The encrypted header is authenticated with the message
+        let (header, associated_data) = match &self.header_keys {
+            Some(keys) => {
+                let header_key = keys.send.as_ref().ok_or_else(|| {
+                    CryptoError::RatchetStateError(
+                        "Can't send before receiving the first message".to_string(),
+                    )
+                })?;
+                let encrypted_header = encrypt_header(header_key, &header)?;
+                let associated_data = concat(&[associated_data, &encrypted_header]);
+                (WireHeader::Encrypted(encrypted_header), associated_data)
+            }
+            None => (WireHeader::Plain(header), associated_data.to_vec()),
+        };
+
+        // Derive message key from sending chain
+        let (new_chain_key, message_key) = kdf_ck(&self.chain_key_send)?;
+
+        // Encrypt plaintext with message key
+        let ciphertext = Self::aead_encrypt(&message_key, plaintext, &associated_data)?;
+
+        self.chain_key_send = new_chain_key;
         self.send_count += 1;
 
         Ok(EncryptedMessage { header, ciphertext })
@@ -166,6 +389,9 @@ impl DoubleRatchet {
 
     /// Decrypt a message
     ///
+    /// The state only changes if the message decrypts, so forged or replayed
+    /// messages can't advance or corrupt the ratchet.
+    ///
     /// # Arguments
     /// * `message` - The encrypted message to decrypt
     /// * `associated_data` - Additional authenticated data
@@ -173,19 +399,96 @@ impl DoubleRatchet {
         &mut self,
         message: &EncryptedMessage,
         associated_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut next = self.clone();
+
+        let plaintext = match (&message.header, next.header_keys.is_some()) {
+            (WireHeader::Encrypted(header), true) => {
+                next.decrypt_encrypted_header(header, &message.ciphertext, associated_data)?
+            }
+            (WireHeader::Plain(header), false) => {
+                next.decrypt_plain_header(header, &message.ciphertext, associated_data)?
+            }
+            _ => {
+                return Err(CryptoError::InvalidMessageFormat(
+                    "Header encryption doesn't match the session".to_string(),
+                ))
+            }
+        };
+
+        *self = next;
+        Ok(plaintext)
+    }
+
+    /// Decrypt a message with an encrypted header
+    fn decrypt_encrypted_header(
+        &mut self,
+        encrypted_header: &[u8],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let associated_data = concat(&[associated_data, encrypted_header]);
+
+        if let Some(message_key) = self.try_skipped_header_keys(encrypted_header) {
+            return Self::aead_decrypt(&message_key, ciphertext, &associated_data);
+        }
+
+        // Trial decryption: the current receiving chain, then the next one
+        let keys = self
+            .header_keys
+            .as_ref()
+            .ok_or_else(|| CryptoError::RatchetStateError("No header keys".to_string()))?;
+        let current = keys
+            .recv
+            .as_ref()
+            .and_then(|key| decrypt_header(key, encrypted_header).ok());
+        let header = match current {
+            Some(header) => header,
+            None => {
+                let header = keys
+                    .next_recv
+                    .as_ref()
+                    .and_then(|key| decrypt_header(key, encrypted_header).ok())
+                    .ok_or_else(|| {
+                        CryptoError::DecryptionFailed("Unknown header key".to_string())
+                    })?;
+
+                // Keep the keys of messages still to come in the old chain
+                self.skip_message_keys(header.prev_chain_len)?;
+                self.dh_ratchet(&header)?;
+                header
+            }
+        };
+
+        self.skip_message_keys(header.message_num)?;
+
+        // Derive message key from receiving chain
+        let (new_chain_key, message_key) = kdf_ck(&self.chain_key_recv)?;
+        self.chain_key_recv = new_chain_key;
+        self.recv_count += 1;
+
+        Self::aead_decrypt(&message_key, ciphertext, &associated_data)
+    }
+
+    /// Decrypt a message with a plaintext header
+    fn decrypt_plain_header(
+        &mut self,
+        header: &MessageHeader,
+        ciphertext: &[u8],
+        associated_data: &[u8],
     ) -> Result<Vec<u8>> {
         // Check if this is a message with a new DH ratchet key
-        if message.header.public_key != self.dh_remote {
-            self.dh_ratchet(&message.header)?;
+        if header.public_key != self.dh_remote {
+            self.dh_ratchet(header)?;
         }
 
         // Check if we need to skip messages
-        if message.header.message_num > self.recv_count {
-            self.skip_message_keys(message.header.message_num)?;
+        if header.message_num > self.recv_count {
+            self.skip_message_keys(header.message_num)?;
         }
 
         // Try to get message key (might be skipped key)
-        let message_key = if let Some(key) = self.try_skipped_keys(message) {
+        let message_key = if let Some(key) = self.try_skipped_keys(header) {
             key
         } else {
             // Derive message key from receiving chain
@@ -196,7 +499,7 @@ impl DoubleRatchet {
         };
 
         // Decrypt ciphertext
-        self.aead_decrypt(&message_key, &message.ciphertext, associated_data)
+        Self::aead_decrypt(&message_key, ciphertext, associated_data)
     }
 
     /// Perform DH ratchet step when receiving message with new DH key
@@ -206,6 +509,12 @@ impl DoubleRatchet {
         self.send_count = 0;
         self.recv_count = 0;
 
+        // The next header keys become current
+        if let Some(keys) = &mut self.header_keys {
+            keys.send = keys.next_send.take();
+            keys.recv = keys.next_recv.take();
+        }
+
         // Update remote DH public key
         self.dh_remote = header.public_key.clone();
 
@@ -213,9 +522,7 @@ impl DoubleRatchet {
         let dh_output = self.dh_self.dh(&self.dh_remote)?;
 
         // Derive new root key and receiving chain key
-        let (new_root_key, chain_key_recv) = kdf_rk(&self.root_key, &dh_output)?;
-        self.root_key = new_root_key;
-        self.chain_key_recv = chain_key_recv;
+        self.chain_key_recv = self.root_step(&dh_output, false)?;
 
         // Generate new DH key pair for next ratchet
         self.dh_self = KeyPair::generate()?;
@@ -224,26 +531,60 @@ impl DoubleRatchet {
         let dh_output2 = self.dh_self.dh(&self.dh_remote)?;
 
         // Derive new root key and sending chain key
-        let (new_root_key2, chain_key_send) = kdf_rk(&self.root_key, &dh_output2)?;
-        self.root_key = new_root_key2;
-        self.chain_key_send = chain_key_send;
+        self.chain_key_send = self.root_step(&dh_output2, true)?;
 
         Ok(())
     }
 
+    /// Advance the root chain, returning the new chain key
+    ///
+    /// With header encryption this also derives the next header key for the
+    /// new chain's direction.
+    fn root_step(&mut self, dh_output: &[u8], sending: bool) -> Result<Vec<u8>> {
+        match &mut self.header_keys {
+            Some(keys) => {
+                let (root_key, chain_key, next_header_key) = kdf_rk_he(&self.root_key, dh_output)?;
+                if sending {
+                    keys.next_send = Some(next_header_key);
+                } else {
+                    keys.next_recv = Some(next_header_key);
+                }
+                self.root_key = root_key;
+                Ok(chain_key)
+            }
+            None => {
+                let (root_key, chain_key) = kdf_rk(&self.root_key, dh_output)?;
+                self.root_key = root_key;
+                Ok(chain_key)
+            }
+        }
+    }
+
     /// Skip message keys for out-of-order messages
+    ///
+    /// Only the newest `MAX_SKIP` skipped keys are kept.
     fn skip_message_keys(&mut self, until: u32) -> Result<()> {
-        if self.recv_count + (MAX_SKIP as u32) < until {
+        if self.recv_count.saturating_add(MAX_SKIP as u32) < until {
             return Err(CryptoError::RatchetStateError(
                 "Too many skipped messages".to_string(),
             ));
         }
 
+        // Skipped keys are filed under the chain's header key, or its ratchet
+        // key with plaintext headers; there's no chain before the first ratchet
+        let chain = match &self.header_keys {
+            Some(keys) => match &keys.recv {
+                Some(header_key) => header_key.clone(),
+                None => return Ok(()),
+            },
+            None => self.dh_remote.clone(),
+        };
+
         while self.recv_count < until {
             let (new_chain_key, message_key) = kdf_ck(&self.chain_key_recv)?;
 
             self.skipped_keys.push(SkippedKey {
-                public_key: self.dh_remote.clone(),
+                chain: chain.clone(),
                 message_num: self.recv_count,
                 message_key,
             });
@@ -252,22 +593,53 @@ impl DoubleRatchet {
             self.recv_count += 1;
         }
 
+        if self.skipped_keys.len() > MAX_SKIP {
+            let excess = self.skipped_keys.len() - MAX_SKIP;
+            self.skipped_keys.drain(..excess);
+        }
+
         Ok(())
     }
 
     /// Try to find a skipped message key
-    fn try_skipped_keys(&mut self, message: &EncryptedMessage) -> Option<Vec<u8>> {
-        if let Some(pos) = self.skipped_keys.iter().position(|k| {
-            k.public_key == message.header.public_key && k.message_num == message.header.message_num
-        }) {
+    fn try_skipped_keys(&mut self, header: &MessageHeader) -> Option<Vec<u8>> {
+        if let Some(pos) = self
+            .skipped_keys
+            .iter()
+            .position(|k| k.chain == header.public_key && k.message_num == header.message_num)
+        {
             Some(self.skipped_keys.remove(pos).message_key.clone())
         } else {
             None
         }
     }
 
+    /// Try to find a skipped message key by trial decryption of the header
+    ///
+    /// Each distinct header key is tried once.
+    fn try_skipped_header_keys(&mut self, encrypted_header: &[u8]) -> Option<Vec<u8>> {
+        let mut tried: Option<(&[u8], Option<MessageHeader>)> = None;
+        let mut pos = None;
+
+        for (index, key) in self.skipped_keys.iter().enumerate() {
+            if tried.as_ref().map(|(chain, _)| *chain) != Some(key.chain.as_slice()) {
+                let header = decrypt_header(&key.chain, encrypted_header).ok();
+                tried = Some((key.chain.as_slice(), header));
+            }
+
+            if let Some((_, Some(header))) = &tried {
+                if header.message_num == key.message_num {
+                    pos = Some(index);
+                    break;
+                }
+            }
+        }
+
+        pos.map(|pos| self.skipped_keys.remove(pos).message_key.clone())
+    }
+
     /// Encrypt with AEAD (AES-256-GCM)
-    fn aead_encrypt(&self, key: &[u8], plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+    fn aead_encrypt(key: &[u8], plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
         let unbound_key = UnboundKey::new(&AES_256_GCM, key)
             .map_err(|_| CryptoError::EncryptionFailed("Invalid key".to_string()))?;
 
@@ -290,7 +662,7 @@ impl DoubleRatchet {
     }
 
     /// Decrypt with AEAD (AES-256-GCM)
-    fn aead_decrypt(&self, key: &[u8], ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+    fn aead_decrypt(key: &[u8], ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
         if ciphertext.len() < 12 {
             return Err(CryptoError::DecryptionFailed(
                 "Ciphertext too short".to_string(),
@@ -321,6 +693,39 @@ impl DoubleRatchet {
     }
 }
 
+/// Split the X3DH shared secret into the root key, Alice's first header key
+/// and Bob's first next-header key
+fn initial_keys(shared_secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let output = Zeroizing::new(hkdf_sha256(
+        shared_secret,
+        None,
+        b"InvisibleRatchetInit",
+        96,
+    )?);
+
+    Ok((
+        output[0..32].to_vec(),
+        output[32..64].to_vec(),
+        output[64..96].to_vec(),
+    ))
+}
+
+/// Encrypt a header under a header key
+fn encrypt_header(header_key: &[u8], header: &MessageHeader) -> Result<Vec<u8>> {
+    DoubleRatchet::aead_encrypt(header_key, &bincode::serialize(header)?, HEADER_CONTEXT)
+}
+
+/// Decrypt a header, failing if it wasn't encrypted under `header_key`
+fn decrypt_header(header_key: &[u8], encrypted_header: &[u8]) -> Result<MessageHeader> {
+    let header = DoubleRatchet::aead_decrypt(header_key, encrypted_header, HEADER_CONTEXT)?;
+    Ok(bincode::deserialize(&header)?)
+}
+
+/// Associated data authenticated with a serialized state
+fn state_aad(version: u8) -> Vec<u8> {
+    [STATE_CONTEXT, &[version]].concat()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -353,17 +758,255 @@ mod tests {
 
     #[test]
     fn test_aead_encrypt_decrypt() {
-        let shared_secret = vec![1u8; 32];
-        let remote_key = vec![2u8; 32];
-        let alice = DoubleRatchet::init_alice(&shared_secret, remote_key).unwrap();
-
         let key = vec![0u8; 32];
         let plaintext = b"Hello, World!";
         let ad = b"associated data";
 
-        let ciphertext = alice.aead_encrypt(&key, plaintext, ad).unwrap();
-        let decrypted = alice.aead_decrypt(&key, &ciphertext, ad).unwrap();
+        let ciphertext = DoubleRatchet::aead_encrypt(&key, plaintext, ad).unwrap();
+        let decrypted = DoubleRatchet::aead_decrypt(&key, &ciphertext, ad).unwrap();
 
         assert_eq!(decrypted, plaintext);
     }
+    fn pair() -> (DoubleRatchet, DoubleRatchet) {
+        let shared_secret = vec![1u8; 32];
+        let bob_key = KeyPair::generate().unwrap();
+        let alice =
+            DoubleRatchet::init_alice(&shared_secret, bob_key.public_key().to_vec()).unwrap();
+        let bob = DoubleRatchet::init_bob(&shared_secret, bob_key).unwrap();
+
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let state_key = [7u8; 32];
+        let (mut alice, mut bob) = pair();
+
+        // Leave Bob with a skipped key for the first message
+        let first = alice.encrypt(b"first", b"ad").unwrap();
+        let second = alice.encrypt(b"second", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&second, b"ad").unwrap(), b"second");
+
+        let mut alice =
+            DoubleRatchet::import_state(&alice.export_state(&state_key).unwrap(), &state_key)
+                .unwrap();
+        let mut bob =
+            DoubleRatchet::import_state(&bob.export_state(&state_key).unwrap(), &state_key)
+                .unwrap();
+        assert_eq!(bob.skipped_keys.len(), 1);
+
+        assert_eq!(bob.decrypt(&first, b"ad").unwrap(), b"first");
+        let reply = bob.encrypt(b"reply", b"ad").unwrap();
+        assert_eq!(alice.decrypt(&reply, b"ad").unwrap(), b"reply");
+        let third = alice.encrypt(b"third", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&third, b"ad").unwrap(), b"third");
+    }
+
+    #[test]
+    fn test_state_authentication() {
+        let state_key = [7u8; 32];
+        let (alice, _) = pair();
+        let sealed = alice.export_state(&state_key).unwrap();
+        assert_eq!(sealed[0], STATE_VERSION);
+
+        assert!(DoubleRatchet::import_state(&sealed, &[8u8; 32]).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(DoubleRatchet::import_state(&tampered, &state_key).is_err());
+        assert!(DoubleRatchet::import_state(&[], &state_key).is_err());
+
+        // Unknown versions, and old version bytes on a new state
+        let mut tampered = sealed.clone();
+        tampered[0] = STATE_VERSION + 1;
+        assert!(DoubleRatchet::import_state(&tampered, &state_key).is_err());
+        tampered[0] = 0;
+        assert!(DoubleRatchet::import_state(&tampered, &state_key).is_err());
+    }
+
+    #[test]
+    fn test_header_encryption() {
+        let (mut alice, mut bob) = pair();
+
+        // Bob can't send before he has a sending header key
+        assert!(bob.encrypt(b"early", b"ad").is_err());
+
+        let first = alice.encrypt(b"first", b"ad").unwrap();
+        let second = alice.encrypt(b"second", b"ad").unwrap();
+
+        // Neither the ratchet key nor the message number is visible
+        let WireHeader::Encrypted(encrypted_header) = &first.header else {
+            panic!("plaintext header");
+        };
+        let public_key = alice.dh_self.public_key();
+        assert!(!encrypted_header.windows(public_key.len()).any(|w| w == public_key));
+        let WireHeader::Encrypted(other_header) = &second.header else {
+            panic!("plaintext header");
+        };
+        assert_eq!(encrypted_header.len(), other_header.len());
+
+        // The header is authenticated with the message
+        let mut swapped = second.clone();
+        swapped.header = first.header.clone();
+        assert!(bob.decrypt(&swapped, b"ad").is_err());
+        assert_eq!(bob.recv_count, 0);
+
+        // Out of order across a DH ratchet step
+        assert_eq!(bob.decrypt(&second, b"ad").unwrap(), b"second");
+        let reply = bob.encrypt(b"reply", b"ad").unwrap();
+        assert_eq!(alice.decrypt(&reply, b"ad").unwrap(), b"reply");
+        let third = alice.encrypt(b"third", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&third, b"ad").unwrap(), b"third");
+        assert_eq!(bob.decrypt(&first, b"ad").unwrap(), b"first");
+
+        // Replays fail without touching the state
+        assert!(bob.decrypt(&first, b"ad").is_err());
+        assert!(bob.decrypt(&third, b"ad").is_err());
+        let fourth = alice.encrypt(b"fourth", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&fourth, b"ad").unwrap(), b"fourth");
+
+        // Plaintext headers aren't accepted by a session with header encryption
+        let mut plain = alice.encrypt(b"plain", b"ad").unwrap();
+        plain.header = WireHeader::Plain(MessageHeader {
+            public_key: alice.dh_self.public_key().to_vec(),
+            prev_chain_len: 0,
+            message_num: 0,
+        });
+        assert!(bob.decrypt(&plain, b"ad").is_err());
+    }
+
+    #[test]
+    fn test_skipped_keys_bounded() {
+        let (mut alice, mut bob) = pair();
+
+        // Too far ahead in one chain
+        let mut ahead = alice.clone();
+        ahead.send_count = MAX_SKIP as u32 + 1;
+        let message = ahead.encrypt(b"ahead", b"ad").unwrap();
+        assert!(bob.decrypt(&message, b"ad").is_err());
+
+        // 300 keys skipped in the first chain, and 299 more when it's closed
+        let first_chain: Vec<_> = (0..600)
+            .map(|_| alice.encrypt(b"first", b"ad").unwrap())
+            .collect();
+        assert_eq!(bob.decrypt(&first_chain[300], b"ad").unwrap(), b"first");
+        let reply = bob.encrypt(b"reply", b"ad").unwrap();
+        alice.decrypt(&reply, b"ad").unwrap();
+
+        // 499 in the second chain push the oldest 98 out
+        let second_chain: Vec<_> = (0..500)
+            .map(|_| alice.encrypt(b"second", b"ad").unwrap())
+            .collect();
+        assert_eq!(bob.decrypt(&second_chain[499], b"ad").unwrap(), b"second");
+        assert_eq!(bob.skipped_keys.len(), MAX_SKIP);
+
+        assert!(bob.decrypt(&first_chain[97], b"ad").is_err());
+        assert_eq!(bob.decrypt(&first_chain[98], b"ad").unwrap(), b"first");
+        assert_eq!(bob.decrypt(&first_chain[599], b"ad").unwrap(), b"first");
+        assert_eq!(bob.decrypt(&second_chain[0], b"ad").unwrap(), b"second");
+    }
+
+    /// States written by every released format version must stay readable
+    ///
+    /// Each fixture was exported under the key 0x07 * 32 by the version that
+    /// introduced it, with a state built by `fixture_state`.
+    #[test]
+    fn test_state_format_upgrades() {
+        let fixtures = [(1u8, STATE_V1_FIXTURE), (2, STATE_V2_FIXTURE)];
+
+        for (version, fixture) in fixtures {
+            let sealed = hex::decode(fixture.concat()).unwrap();
+            assert_eq!(sealed[0], version);
+
+            let state = DoubleRatchet::import_state(&sealed, &[7u8; 32]).unwrap();
+            let expected = fixture_state(version);
+            assert_eq!(state.dh_self.private_key(), expected.dh_self.private_key());
+            assert_eq!(state.dh_self.public_key(), expected.dh_self.public_key());
+            assert_eq!(state.dh_remote, expected.dh_remote);
+            assert_eq!(state.root_key, expected.root_key);
+            assert_eq!(state.chain_key_send, expected.chain_key_send);
+            assert_eq!(state.chain_key_recv, expected.chain_key_recv);
+            assert_eq!(state.send_count, expected.send_count);
+            assert_eq!(state.recv_count, expected.recv_count);
+            assert_eq!(state.prev_chain_len, expected.prev_chain_len);
+            assert_eq!(
+                state.header_keys.as_ref().map(|k| (&k.send, &k.recv)),
+                expected.header_keys.as_ref().map(|k| (&k.send, &k.recv))
+            );
+            assert_eq!(
+                state.header_keys.as_ref().map(|k| (&k.next_send, &k.next_recv)),
+                expected.header_keys.as_ref().map(|k| (&k.next_send, &k.next_recv))
+            );
+            assert_eq!(state.skipped_keys.len(), expected.skipped_keys.len());
+            for (key, expected) in state.skipped_keys.iter().zip(expected.skipped_keys.iter()) {
+                assert_eq!(key.chain, expected.chain);
+                assert_eq!(key.message_num, expected.message_num);
+                assert_eq!(key.message_key, expected.message_key);
+            }
+        }
+    }
+
+    /// Version 1 states have plaintext headers
+    fn fixture_state(version: u8) -> DoubleRatchet {
+        let header_keys = (version >= 2).then(|| HeaderKeys {
+            send: Some(vec![10u8; 32]),
+            recv: Some(vec![11u8; 32]),
+            next_send: None,
+            next_recv: Some(vec![12u8; 32]),
+        });
+        let chain = match &header_keys {
+            Some(keys) => keys.recv.clone().unwrap(),
+            None => vec![2u8; 32],
+        };
+
+        DoubleRatchet {
+            dh_self: KeyPair::from_private([1u8; 32]),
+            dh_remote: vec![2u8; 32],
+            root_key: vec![3u8; 32],
+            chain_key_send: vec![4u8; 32],
+            chain_key_recv: vec![5u8; 32],
+            send_count: 6,
+            recv_count: 7,
+            prev_chain_len: 8,
+            header_keys,
+            skipped_keys: vec![SkippedKey {
+                chain,
+                message_num: 3,
+                message_key: vec![9u8; 32],
+            }],
+        }
+    }
+
+    const STATE_V1_FIXTURE: &[&str] = &[
+        "01bc41c6106f8d2204b2597a61806cb73b2379568461b022b3b7ade1470fcd3c",
+        "fa00b2cc616b4528c8c26c347074e4960358738f01323fac9c791b2e32a007a5",
+        "f08ef7c42cab42b8680d71c99953e087bb4c75c910df1c9bc7aad050b2037a31",
+        "4325b9f18e8ccbab4581385388a6adc7d6e80a9187121fb39d17f4376a21408e",
+        "81c0603b97e3ddc836f5461b89e0e350aef8453a3863abc8fd63a363267f7eff",
+        "fcfbc015907982e49ae1dd95b6ac87375c78b8c236840b5230b2fc152a426629",
+        "7ba16f2e2803297efa7b085aa3fe8523d258c2e4cfea3bb8e17d7f69bf931c30",
+        "d95ef42291a42b1d7846cc495c1b06464c1dfd4cb30b7ae30ffd9b418b74563b",
+        "18bd13eff332946fe3a4f1ad177991ca7211be50ca908c1663d39ce8945f0bf7",
+        "44d6588bae18a368bc1aad5979308e8bf8a8f1f49a9482994a0b418a7acf05b8",
+        "e7b413bd4107a14bd07012e471565ce3abb38bc6a657b9f0d7ed90dba8e0438c",
+        "db9db515d5d38b7bc305b0fe40f8e2599765a2cacd",
+    ];
+
+    const STATE_V2_FIXTURE: &[&str] = &[
+        "02bc93bcb0999aac89823ac3251df296946f0e530315b9639658ab74c1cb58bb",
+        "f95727e23606f2b826884c69641dbf1c58eb621a027d630dc5985074598f63f1",
+        "fb0e857856ed74d01a909adb44ef95b1b6cfda453a806190df95f5336855ef2e",
+        "758e281e81242431dd630059727eb6cc59d93907506026f8acadbcc93a0445f6",
+        "05019a24ce9626ee7882d25d905e809681f26f5c4a89e1b064e3379ea6a6e48f",
+        "806fa85662338426f3821ea2517f620a688e79b04e4c042658d841d81d12a482",
+        "78cb2329ca1829a857a2d5b09148f8ef3b6e971259b4a34f72630e6af05a01bd",
+        "ffb8843b82c5fa99060d6117fe1ebe02dabae7b0e1849b52d9d3d1e3e0935db2",
+        "94f0777a6a589d89c73ab83fe04e0c22bb88c86ba7a2a142668e324c1137c14d",
+        "ff32575a45852641de8e69a1ebc50723ff8a4c97a7c359888b2f973676ad37f9",
+        "089a7d9003c01078fd21f17be85d0a29acba19fb8c66398037fa6248d64ca574",
+        "a3dc0077390f6daa4dd386a606dd034cff9adce34507db3a7302bd85faec590c",
+        "1fce69bb2ffb926a5f39ad48efdc495fbb3835c27fdcdadc67676110c1506b1c",
+        "76dece9af9eac228d248f856a79c537c78e6e36c690eaf2d435aee4f27b673e4",
+        "480d875a0ec93c1d9ad965b93c7476963d5b057885d9f6d54c2889d6f3ac417e",
+        "968fcee50fa5cad8d90bb570eedbf067fb69",
+    ];
 }
@@ -48,6 +48,10 @@ pub enum CryptoError {
     #[error("Ratchet state error: {0}")]
     RatchetStateError(String),
 
+    /// Device list or device session error
+    #[error("Device error: {0}")]
+    DeviceError(String),
+
     /// Generic cryptographic error
     #[error("Cryptographic operation failed: {0}")]
     CryptoError(String),
@@ -53,6 +53,20 @@ pub fn kdf_rk(root_key: &[u8], dh_output: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
     Ok((root_key, chain_key))
 }
 
+/// Derive a root key, chain key and next header key from a shared secret
+/// (for Double Ratchet with header encryption)
+///
+/// Returns (root_key, chain_key, next_header_key) tuple
+pub fn kdf_rk_he(root_key: &[u8], dh_output: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let output = hkdf_sha256(dh_output, Some(root_key), b"WhisperRatchetHeader", 96)?;
+
+    let root_key = output[0..32].to_vec();
+    let chain_key = output[32..64].to_vec();
+    let next_header_key = output[64..96].to_vec();
+
+    Ok((root_key, chain_key, next_header_key))
+}
+
 /// Derive a chain key and message key from a chain key (for Double Ratchet)
 ///
 /// Returns (chain_key, message_key) tuple
@@ -105,6 +119,18 @@ mod tests {
         assert_ne!(new_root, root_key);
     }
 
+    #[test]
+    fn test_kdf_rk_he() {
+        let root_key = vec![0u8; 32];
+        let dh_output = vec![1u8; 32];
+
+        let (new_root, chain, next_header) = kdf_rk_he(&root_key, &dh_output).unwrap();
+        assert_eq!(next_header.len(), 32);
+        assert_ne!(new_root, chain);
+        assert_ne!(chain, next_header);
+        assert_ne!((new_root, chain), kdf_rk(&root_key, &dh_output).unwrap());
+    }
+
     #[test]
     fn test_kdf_ck() {
         let chain_key = vec![0u8; 32];
@@ -0,0 +1,681 @@
+//! ML-KEM-768 Key Encapsulation
+//!
+//! Implements the module-lattice KEM of FIPS 203 with the ML-KEM-768
+//! parameter set, used by PQXDH to protect session keys against an adversary
+//! who records traffic today and gets a quantum computer later.
+//!
+//! ## Architecture
+//!
+//! - **K-PKE:** The CPA-secure public key encryption scheme over
+//!   Z_q[X]/(X^256 + 1), with polynomial products computed in the NTT domain
+//! - **Fujisaki-Okamoto Transform:** Decapsulation re-encrypts the decrypted
+//!   message and returns a pseudorandom key on a mismatch, making the KEM
+//!   CCA-secure
+//! - **Keys:** Key pairs are derived from a 64-byte seed `d || z`, so they
+//!   can be reproduced from the seed alone
+//!
+//! ## Security Properties
+//!
+//! - **Post-Quantum:** Security rests on Module-LWE, not on discrete logs
+//! - **Implicit Rejection:** Invalid ciphertexts decapsulate to a key
+//!   unrelated to any other, without revealing that they were invalid
+//! - **Input Checks:** Encapsulation rejects public keys with coefficients
+//!   outside Z_q, as FIPS 203 requires
+
+use serde::{Deserialize, Serialize};
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Digest, Sha3_256, Sha3_512, Shake128, Shake256};
+use subtle::{ConditionallySelectable, ConstantTimeEq};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::error::{CryptoError, Result};
+
+/// Size of ML-KEM-768 encapsulation (public) keys
+pub const MLKEM768_PUBLIC_KEY_SIZE: usize = 384 * K + 32;
+
+/// Size of ML-KEM-768 decapsulation (private) keys
+pub const MLKEM768_PRIVATE_KEY_SIZE: usize = 768 * K + 96;
+
+/// Size of ML-KEM-768 ciphertexts
+pub const MLKEM768_CIPHERTEXT_SIZE: usize = 32 * (DU * K + DV);
+
+/// Size of ML-KEM shared secrets
+pub const MLKEM_SHARED_SECRET_SIZE: usize = 32;
+
+/// Size of the seed a key pair is derived from
+pub const MLKEM_SEED_SIZE: usize = 64;
+
+const N: usize = 256;
+const Q: u32 = 3329;
+const K: usize = 3;
+const ETA1: usize = 2;
+const ETA2: usize = 2;
+const DU: usize = 10;
+const DV: usize = 4;
+
+/// Size of an encoded polynomial with 12-bit coefficients
+const POLY_BYTES: usize = 384;
+
+/// Shift of the Barrett division by q in `compress`
+const BARRETT_SHIFT: u32 = 35;
+
+/// ceil(2^35 / q): `(x * BARRETT_MULTIPLIER) >> BARRETT_SHIFT` is x / q for
+/// every x below 2^23, which covers the numerators `compress` divides
+const BARRETT_MULTIPLIER: u64 = (1 << BARRETT_SHIFT) / Q as u64 + 1;
+
+/// 17^BitRev7(i) mod q
+const ZETAS: [u32; 128] = zetas();
+
+type Poly = [u32; N];
+
+/// An ML-KEM-768 key pair
+#[derive(Debug, Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct KemKeyPair {
+    /// Encapsulation key (safe to share)
+    #[zeroize(skip)]
+    public: Vec<u8>,
+    /// Decapsulation key (must remain secret; empty for a remote public key)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    private: Vec<u8>,
+}
+
+impl KemKeyPair {
+    /// Generate a new random key pair
+    pub fn generate() -> Result<Self> {
+        let seed = Zeroizing::new(crate::utils::random_bytes(MLKEM_SEED_SIZE)?);
+        let mut seed_bytes = Zeroizing::new([0u8; MLKEM_SEED_SIZE]);
+        seed_bytes.copy_from_slice(&seed);
+
+        Ok(Self::from_seed(&seed_bytes))
+    }
+
+    /// Derive a key pair from its seed `d || z` (ML-KEM.KeyGen_internal)
+    pub fn from_seed(seed: &[u8; MLKEM_SEED_SIZE]) -> Self {
+        let (d, z) = seed.split_at(32);
+        let (public, pke_private) = pke_keygen(d);
+
+        let mut private = Vec::with_capacity(MLKEM768_PRIVATE_KEY_SIZE);
+        private.extend_from_slice(&pke_private);
+        private.extend_from_slice(&public);
+        private.extend_from_slice(&Sha3_256::digest(&public));
+        private.extend_from_slice(z);
+
+        Self { public, private }
+    }
+
+    /// Create from public key only (for remote keys)
+    pub fn from_public(public: Vec<u8>) -> Self {
+        Self {
+            public,
+            private: Vec::new(),
+        }
+    }
+
+    /// Copy of the key pair without the private key
+    pub fn public(&self) -> Self {
+        Self::from_public(self.public.clone())
+    }
+
+    /// Get the public key
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// Recover the shared secret from a ciphertext (ML-KEM.Decaps)
+    ///
+    /// A ciphertext that wasn't produced for this key yields a pseudorandom
+    /// secret instead of an error, so the caller only notices through a
+    /// failing key agreement.
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if self.private.len() != MLKEM768_PRIVATE_KEY_SIZE {
+            return Err(CryptoError::InvalidKey(
+                "No private key available".to_string(),
+            ));
+        }
+        if ciphertext.len() != MLKEM768_CIPHERTEXT_SIZE {
+            return Err(CryptoError::InvalidKey(
+                "Invalid ciphertext length".to_string(),
+            ));
+        }
+
+        let pke_private = &self.private[..POLY_BYTES * K];
+        let public = &self.private[POLY_BYTES * K..POLY_BYTES * K + MLKEM768_PUBLIC_KEY_SIZE];
+        let hash = &self.private[MLKEM768_PRIVATE_KEY_SIZE - 64..MLKEM768_PRIVATE_KEY_SIZE - 32];
+        let z = &self.private[MLKEM768_PRIVATE_KEY_SIZE - 32..];
+
+        let message = pke_decrypt(pke_private, ciphertext);
+        let (mut shared_secret, randomness) = g(&[&message[..], hash]);
+        let reencrypted = pke_encrypt(public, &message[..], &randomness);
+
+        let mut rejection = [0u8; 32];
+        Shake256::default()
+            .chain(z)
+            .chain(ciphertext)
+            .finalize_xof()
+            .read(&mut rejection);
+
+        let matches = reencrypted.ct_eq(ciphertext);
+        for (byte, rejected) in shared_secret.iter_mut().zip(rejection.iter()) {
+            *byte = u8::conditional_select(rejected, byte, matches);
+        }
+        rejection.zeroize();
+
+        Ok(shared_secret.to_vec())
+    }
+}
+
+/// Encapsulate a fresh shared secret to a public key (ML-KEM.Encaps)
+///
+/// # Returns
+/// * Ciphertext to send to the key's owner
+/// * Shared secret
+pub fn encapsulate(public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let randomness = Zeroizing::new(crate::utils::random_bytes(32)?);
+    let mut message = Zeroizing::new([0u8; 32]);
+    message.copy_from_slice(&randomness);
+
+    encapsulate_with(public_key, &message)
+}
+
+/// Deterministic encapsulation (ML-KEM.Encaps_internal)
+fn encapsulate_with(public_key: &[u8], message: &[u8; 32]) -> Result<(Vec<u8>, Vec<u8>)> {
+    check_public_key(public_key)?;
+
+    let (shared_secret, randomness) = g(&[message, &Sha3_256::digest(public_key)]);
+    let ciphertext = pke_encrypt(public_key, message, &randomness);
+
+    Ok((ciphertext, shared_secret.to_vec()))
+}
+
+/// Encapsulation key check of FIPS 203 section 7.2
+fn check_public_key(public_key: &[u8]) -> Result<()> {
+    if public_key.len() != MLKEM768_PUBLIC_KEY_SIZE {
+        return Err(CryptoError::InvalidKey(
+            "Invalid public key length".to_string(),
+        ));
+    }
+
+    for chunk in public_key[..POLY_BYTES * K].chunks(POLY_BYTES) {
+        if decode(chunk, 12).iter().any(|c| *c >= Q) {
+            return Err(CryptoError::InvalidKey(
+                "Public key not reduced".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// G = SHA3-512, split into two 32-byte halves
+fn g(parts: &[&[u8]]) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    let mut hasher = Sha3_512::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    let mut output = hasher.finalize();
+
+    let mut first = Zeroizing::new([0u8; 32]);
+    let mut second = Zeroizing::new([0u8; 32]);
+    first.copy_from_slice(&output[..32]);
+    second.copy_from_slice(&output[32..]);
+    output.zeroize();
+    (first, second)
+}
+
+/// K-PKE.KeyGen
+fn pke_keygen(d: &[u8]) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+    let (rho, sigma) = g(&[d, &[K as u8]]);
+    let matrix = expand_matrix(&rho);
+
+    let mut counter = 0u8;
+    let mut secret = [[0u32; N]; K];
+    for poly in secret.iter_mut() {
+        *poly = sample_cbd(&prf(&sigma, counter, ETA1), ETA1);
+        ntt(poly);
+        counter += 1;
+    }
+    let mut error = [[0u32; N]; K];
+    for poly in error.iter_mut() {
+        *poly = sample_cbd(&prf(&sigma, counter, ETA1), ETA1);
+        ntt(poly);
+        counter += 1;
+    }
+
+    let mut public = Vec::with_capacity(MLKEM768_PUBLIC_KEY_SIZE);
+    for (row, error) in matrix.iter().zip(error.iter()) {
+        let mut t = inner_product(row.iter(), &secret);
+        add_assign(&mut t, error);
+        public.extend_from_slice(&encode(&t, 12));
+    }
+    public.extend_from_slice(&rho[..]);
+
+    let mut private = Zeroizing::new(Vec::with_capacity(POLY_BYTES * K));
+    for poly in secret.iter() {
+        private.extend_from_slice(&encode(poly, 12));
+    }
+    secret.zeroize();
+    error.zeroize();
+
+    (public, private)
+}
+
+/// K-PKE.Encrypt
+fn pke_encrypt(public: &[u8], message: &[u8], randomness: &[u8; 32]) -> Vec<u8> {
+    let mut t = [[0u32; N]; K];
+    for (poly, chunk) in t.iter_mut().zip(public.chunks(POLY_BYTES)) {
+        *poly = decode(chunk, 12);
+    }
+    let mut rho = [0u8; 32];
+    rho.copy_from_slice(&public[POLY_BYTES * K..]);
+    let matrix = expand_matrix(&rho);
+
+    let mut counter = 0u8;
+    let mut y = [[0u32; N]; K];
+    for poly in y.iter_mut() {
+        *poly = sample_cbd(&prf(randomness, counter, ETA1), ETA1);
+        ntt(poly);
+        counter += 1;
+    }
+    let mut e1 = [[0u32; N]; K];
+    for poly in e1.iter_mut() {
+        *poly = sample_cbd(&prf(randomness, counter, ETA2), ETA2);
+        counter += 1;
+    }
+    let e2 = sample_cbd(&prf(randomness, counter, ETA2), ETA2);
+
+    let mut ciphertext = Vec::with_capacity(MLKEM768_CIPHERTEXT_SIZE);
+    for (i, e1) in e1.iter().enumerate() {
+        // Column i of the matrix: A^T y
+        let mut u = inner_product(matrix.iter().map(|row| &row[i]), &y);
+        inv_ntt(&mut u);
+        add_assign(&mut u, e1);
+        ciphertext.extend_from_slice(&encode(&compress(&u, DU), DU));
+    }
+
+    let mut v = inner_product(t.iter(), &y);
+    inv_ntt(&mut v);
+    add_assign(&mut v, &e2);
+    add_assign(&mut v, &decompress(&decode(message, 1), 1));
+    ciphertext.extend_from_slice(&encode(&compress(&v, DV), DV));
+
+    y.zeroize();
+    ciphertext
+}
+
+/// K-PKE.Decrypt
+fn pke_decrypt(private: &[u8], ciphertext: &[u8]) -> Zeroizing<[u8; 32]> {
+    let (c1, c2) = ciphertext.split_at(32 * DU * K);
+
+    let mut u = [[0u32; N]; K];
+    for (poly, chunk) in u.iter_mut().zip(c1.chunks(32 * DU)) {
+        *poly = decompress(&decode(chunk, DU), DU);
+        ntt(poly);
+    }
+    let v = decompress(&decode(c2, DV), DV);
+
+    let mut secret = [[0u32; N]; K];
+    for (poly, chunk) in secret.iter_mut().zip(private.chunks(POLY_BYTES)) {
+        *poly = decode(chunk, 12);
+    }
+
+    let mut w = inner_product(secret.iter(), &u);
+    inv_ntt(&mut w);
+    for (w, v) in w.iter_mut().zip(v.iter()) {
+        *w = (v + Q - *w) % Q;
+    }
+
+    let mut message = Zeroizing::new([0u8; 32]);
+    message.copy_from_slice(&encode(&compress(&w, 1), 1));
+    secret.zeroize();
+    w.zeroize();
+    message
+}
+
+/// Sample the NTT-domain matrix A from rho (A[i][j] = SampleNTT(rho || j || i))
+fn expand_matrix(rho: &[u8; 32]) -> [[Poly; K]; K] {
+    let mut matrix = [[[0u32; N]; K]; K];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, poly) in row.iter_mut().enumerate() {
+            *poly = sample_ntt(rho, j as u8, i as u8);
+        }
+    }
+    matrix
+}
+
+/// SampleNTT: rejection sampling from SHAKE128
+fn sample_ntt(rho: &[u8; 32], j: u8, i: u8) -> Poly {
+    let mut reader = Shake128::default().chain(rho).chain([j, i]).finalize_xof();
+
+    let mut poly = [0u32; N];
+    let mut filled = 0;
+    let mut bytes = [0u8; 3];
+    while filled < N {
+        reader.read(&mut bytes);
+        let d1 = u32::from(bytes[0]) | (u32::from(bytes[1] & 0x0F) << 8);
+        let d2 = u32::from(bytes[1] >> 4) | (u32::from(bytes[2]) << 4);
+
+        if d1 < Q {
+            poly[filled] = d1;
+            filled += 1;
+        }
+        if d2 < Q && filled < N {
+            poly[filled] = d2;
+            filled += 1;
+        }
+    }
+    poly
+}
+
+/// PRF_eta(s, b) = SHAKE256(s || b), 64 * eta bytes
+fn prf(seed: &[u8; 32], counter: u8, eta: usize) -> Zeroizing<Vec<u8>> {
+    let mut output = Zeroizing::new(vec![0u8; 64 * eta]);
+    Shake256::default()
+        .chain(seed)
+        .chain([counter])
+        .finalize_xof()
+        .read(&mut output);
+    output
+}
+
+/// SamplePolyCBD_eta: centered binomial distribution
+fn sample_cbd(bytes: &[u8], eta: usize) -> Poly {
+    let bit = |index: usize| u32::from((bytes[index / 8] >> (index % 8)) & 1);
+
+    let mut poly = [0u32; N];
+    for (i, coefficient) in poly.iter_mut().enumerate() {
+        let x: u32 = (0..eta).map(|j| bit(2 * i * eta + j)).sum();
+        let y: u32 = (0..eta).map(|j| bit(2 * i * eta + eta + j)).sum();
+        *coefficient = (x + Q - y) % Q;
+    }
+    poly
+}
+
+/// Sum of NTT-domain products of two vectors of polynomials
+fn inner_product<'a>(row: impl Iterator<Item = &'a Poly>, vector: &[Poly; K]) -> Poly {
+    let mut sum = [0u32; N];
+    for (a, b) in row.zip(vector.iter()) {
+        add_assign(&mut sum, &multiply_ntts(a, b));
+    }
+    sum
+}
+
+fn add_assign(a: &mut Poly, b: &Poly) {
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        *a = (*a + b) % Q;
+    }
+}
+
+/// MultiplyNTTs: 128 products of degree-one polynomials
+fn multiply_ntts(a: &Poly, b: &Poly) -> Poly {
+    let mut product = [0u32; N];
+    for i in 0..N / 2 {
+        // gamma = 17^(2 BitRev7(i) + 1) = zeta_{64 + i/2}, negated for odd i
+        let zeta = ZETAS[64 + i / 2];
+        let gamma = if i % 2 == 0 { zeta } else { Q - zeta };
+
+        let (a0, a1) = (a[2 * i], a[2 * i + 1]);
+        let (b0, b1) = (b[2 * i], b[2 * i + 1]);
+        product[2 * i] = (a0 * b0 % Q + a1 * b1 % Q * gamma) % Q;
+        product[2 * i + 1] = (a0 * b1 + a1 * b0) % Q;
+    }
+    product
+}
+
+/// Number-theoretic transform (FIPS 203 algorithm 9)
+fn ntt(poly: &mut Poly) {
+    let mut k = 1;
+    let mut len = 128;
+    while len >= 2 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k += 1;
+            for j in start..start + len {
+                let t = zeta * poly[j + len] % Q;
+                poly[j + len] = (poly[j] + Q - t) % Q;
+                poly[j] = (poly[j] + t) % Q;
+            }
+        }
+        len /= 2;
+    }
+}
+
+/// Inverse number-theoretic transform (FIPS 203 algorithm 10)
+fn inv_ntt(poly: &mut Poly) {
+    let mut k = 127;
+    let mut len = 2;
+    while len <= 128 {
+        for start in (0..N).step_by(2 * len) {
+            let zeta = ZETAS[k];
+            k -= 1;
+            for j in start..start + len {
+                let t = poly[j];
+                poly[j] = (t + poly[j + len]) % Q;
+                poly[j + len] = zeta * ((poly[j + len] + Q - t) % Q) % Q;
+            }
+        }
+        len *= 2;
+    }
+
+    // 128^-1 mod q
+    for coefficient in poly.iter_mut() {
+        *coefficient = *coefficient * 3303 % Q;
+    }
+}
+
+/// Compress_d: round(2^d / q * x) mod 2^d
+///
+/// Decryption compresses secret-dependent coefficients, and a division
+/// instruction's timing depends on its operands (KyberSlash), so the division
+/// by q is a Barrett multiply and shift.
+fn compress(poly: &Poly, bits: usize) -> Poly {
+    let mut compressed = [0u32; N];
+    for (c, x) in compressed.iter_mut().zip(poly.iter()) {
+        let numerator = (u64::from(*x) << bits) + u64::from(Q / 2);
+        let scaled = (numerator * BARRETT_MULTIPLIER) >> BARRETT_SHIFT;
+        *c = (scaled as u32) & ((1 << bits) - 1);
+    }
+    compressed
+}
+
+/// Decompress_d: round(q / 2^d * y)
+fn decompress(poly: &Poly, bits: usize) -> Poly {
+    let mut decompressed = [0u32; N];
+    for (d, y) in decompressed.iter_mut().zip(poly.iter()) {
+        *d = (y * Q + (1 << (bits - 1))) >> bits;
+    }
+    decompressed
+}
+
+/// ByteEncode_d: pack 256 d-bit integers, least significant bit first
+fn encode(poly: &Poly, bits: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; 32 * bits];
+    for (i, coefficient) in poly.iter().enumerate() {
+        for b in 0..bits {
+            let index = i * bits + b;
+            bytes[index / 8] |= (((coefficient >> b) & 1) as u8) << (index % 8);
+        }
+    }
+    bytes
+}
+
+/// ByteDecode_d: unpack 256 d-bit integers
+fn decode(bytes: &[u8], bits: usize) -> Poly {
+    let mut poly = [0u32; N];
+    for (i, coefficient) in poly.iter_mut().enumerate() {
+        for b in 0..bits {
+            let index = i * bits + b;
+            *coefficient |= u32::from((bytes[index / 8] >> (index % 8)) & 1) << b;
+        }
+    }
+    poly
+}
+
+const fn zetas() -> [u32; 128] {
+    let mut table = [0u32; 128];
+    let mut i = 0;
+    while i < 128 {
+        // BitRev7(i)
+        let mut reversed = 0;
+        let mut bit = 0;
+        while bit < 7 {
+            reversed |= ((i >> bit) & 1) << (6 - bit);
+            bit += 1;
+        }
+
+        let mut power = 1u32;
+        let mut e = 0;
+        while e < reversed {
+            power = power * 17 % Q;
+            e += 1;
+        }
+        table[i] = power;
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kem_roundtrip() {
+        let key_pair = KemKeyPair::generate().unwrap();
+        assert_eq!(key_pair.public_key().len(), MLKEM768_PUBLIC_KEY_SIZE);
+
+        let (ciphertext, shared_secret) = encapsulate(key_pair.public_key()).unwrap();
+        assert_eq!(ciphertext.len(), MLKEM768_CIPHERTEXT_SIZE);
+        assert_eq!(key_pair.decapsulate(&ciphertext).unwrap(), shared_secret);
+
+        // Implicit rejection
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        let rejected = key_pair.decapsulate(&tampered).unwrap();
+        assert_ne!(rejected, shared_secret);
+        assert_eq!(key_pair.decapsulate(&tampered).unwrap(), rejected);
+
+        // A public copy can't decapsulate
+        assert!(key_pair.public().decapsulate(&ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_kem_rejects_unreduced_key() {
+        let key_pair = KemKeyPair::generate().unwrap();
+        let mut public = key_pair.public_key().to_vec();
+        // First coefficient = 0xFFF >= q
+        public[0] = 0xFF;
+        public[1] |= 0x0F;
+
+        assert!(encapsulate(&public).is_err());
+    }
+
+    /// Vectors checked against an independent FIPS 203 implementation
+    /// (OpenSSL through pyca/cryptography), which derives the same public key
+    /// from the seed and decapsulates the ciphertext to the same secret
+    ///
+    /// Seed d || z = 0x00..0x3f, encapsulation message m = 0x40..0x5f. The
+    /// public key and ciphertext are checked through their SHA3-256 digests.
+    #[test]
+    fn test_kem_vectors() {
+        let seed: [u8; 64] = core::array::from_fn(|i| i as u8);
+        let message: [u8; 32] = core::array::from_fn(|i| 0x40 + i as u8);
+
+        let key_pair = KemKeyPair::from_seed(&seed);
+        assert_eq!(
+            hex::encode(Sha3_256::digest(key_pair.public_key())),
+            "a24e16d8f8f9383a95b77050f4d9fd2f5733eec1d63ef3c23ebf9918173669a7"
+        );
+
+        let (ciphertext, shared_secret) =
+            encapsulate_with(key_pair.public_key(), &message).unwrap();
+        assert_eq!(
+            hex::encode(Sha3_256::digest(&ciphertext)),
+            "b4cfbd24cef67afd3764276c6980e0f88f8e9ca57f59b7f12fe1a9c1e72f4710"
+        );
+        assert_eq!(
+            hex::encode(&shared_secret),
+            "9cddd089ffe70e3996e76f7c8d06746df34d07e8657bc0fcf2bb0e1c3084aea1"
+        );
+        assert_eq!(key_pair.decapsulate(&ciphertext).unwrap(), shared_secret);
+    }
+
+    #[test]
+    fn test_compress_matches_division() {
+        for bits in [1, DV, DU, 11] {
+            let mut poly = [0u32; N];
+            for start in (0..Q).step_by(N) {
+                for (i, x) in poly.iter_mut().enumerate() {
+                    *x = (start + i as u32).min(Q - 1);
+                }
+
+                let compressed = compress(&poly, bits);
+                for (x, c) in poly.iter().zip(compressed.iter()) {
+                    let expected = (((x << bits) + Q / 2) / Q) & ((1 << bits) - 1);
+                    assert_eq!(*c, expected, "x = {}, d = {}", x, bits);
+                }
+            }
+        }
+    }
+
+    /// Known answers from OpenSSL 3.5's FIPS 203 implementation, laid out
+    /// like the ACVP ML-KEM keyGen and encapDecap tests
+    ///
+    /// Case i uses the seed d || z = SHAKE256("ML-KEM-768 seed i", 64) and
+    /// the encapsulation message m = SHAKE256("ML-KEM-768 message i", 32).
+    /// Keys and ciphertexts are checked through their SHA3-256 digests. The
+    /// last value is the implicit rejection key for the ciphertext with the
+    /// low bit of its last byte flipped.
+    #[test]
+    fn test_kem_known_answers() {
+        let cases = [
+            (
+                "f913b471a05de211dbbcbce316c018c7ed9b46ba04cf4959263e7a409c38d5c6",
+                "a0e74fa455343af34b641bf4f456d81a430752a1c56ff59dde1e2dc2ae004c92",
+                "b02b10b0d85a90ddda061f156a94fd894f7085be61ed74d0be74d11d54c7c143",
+                "5b95251c586d1b88accfc502b32adf471e76568e667ce25f361ea5b003d5a849",
+                "6627adac2509dfe62b3ecf71778eca6561e34d6a6f10c7c1c2f1622ab78dfcd7",
+            ),
+            (
+                "0feddbbae986fcedaaa8c6d58adf8576a4543fe7fb41c38e0eb40ea75c6f3dd9",
+                "612f332f88ed70a2503083493b185e9f130ef8bf434c9a8d476f6becb113c781",
+                "ac981500b8106c873ea9a13b0a32fb803161fb3d78139b89c23f522b3ba391ac",
+                "6aef0f6516b05a9b83bc346a71bf8bfda108f6b15b671cf2c5def3f9ec6cde2e",
+                "3e820cded7057ebbe8489b702f734ee300b7415c2956467f77cdfda68d6bbbfe",
+            ),
+            (
+                "c0482bc9e4880b5a508bba03e98eae701d6834f6e8f8b1f0bbfc911219cf95d3",
+                "49e2d7e74900ac2e50f19beedf63aaa82ad3978f46b1ead8440edc1a3a08f6c5",
+                "b82116479edcae7860fb6efb7cc37ee0239043595a912d136fdc4e903cbea711",
+                "9d86e61518c8d01e4dfde7b87c2213054d541ceaa7ac083667bf150cc9a9236f",
+                "9d66af90df900d35c893cfd673d76fff54203635bd6387b97d72c37a35ff00c7",
+            ),
+        ];
+
+        for (i, (ek, dk, c, k, rejected)) in cases.into_iter().enumerate() {
+            let mut seed = [0u8; 64];
+            let mut xof = Shake256::default();
+            xof.update(format!("ML-KEM-768 seed {}", i).as_bytes());
+            xof.finalize_xof().read(&mut seed);
+            let mut message = [0u8; 32];
+            let mut xof = Shake256::default();
+            xof.update(format!("ML-KEM-768 message {}", i).as_bytes());
+            xof.finalize_xof().read(&mut message);
+
+            // keyGen
+            let key_pair = KemKeyPair::from_seed(&seed);
+            assert_eq!(hex::encode(Sha3_256::digest(key_pair.public_key())), ek);
+            assert_eq!(hex::encode(Sha3_256::digest(&key_pair.private)), dk);
+
+            // encapDecap: encapsulation, decapsulation and implicit rejection
+            let (ciphertext, shared_secret) =
+                encapsulate_with(key_pair.public_key(), &message).unwrap();
+            assert_eq!(hex::encode(Sha3_256::digest(&ciphertext)), c);
+            assert_eq!(hex::encode(&shared_secret), k);
+            assert_eq!(hex::encode(key_pair.decapsulate(&ciphertext).unwrap()), k);
+
+            let mut modified = ciphertext;
+            modified[MLKEM768_CIPHERTEXT_SIZE - 1] ^= 1;
+            assert_eq!(hex::encode(key_pair.decapsulate(&modified).unwrap()), rejected);
+        }
+    }
+}
@@ -1,6 +1,9 @@
 //! Cryptographic key types and management
 //!
 //! Implements Ed25519 keys for identity and X25519 keys for encryption.
+//! Ed25519 keys can't do Diffie-Hellman, so an identity's X25519 key for
+//! X3DH is a separate `IdentityDhKey` certified by its Ed25519 key.
+//! PQXDH adds a signed ML-KEM-768 pre-key, `SignedKemPreKey`.
 //! All sensitive key material is zeroized on drop.
 
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
@@ -8,6 +11,7 @@ use serde::{Deserialize, Serialize};
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
 use crate::error::{CryptoError, Result};
+use crate::kem::KemKeyPair;
 
 /// Size of Ed25519 public keys (32 bytes)
 pub const ED25519_PUBLIC_KEY_SIZE: usize = 32;
@@ -24,7 +28,8 @@ pub struct KeyPair {
     /// Public key (safe to share)
     #[zeroize(skip)]
     public: Vec<u8>,
-    /// Private key (must remain secret)
+    /// Private key (must remain secret; empty for a remote public key)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     private: Vec<u8>,
 }
 
@@ -46,10 +51,40 @@ impl KeyPair {
         })
     }
 
+    /// Restore a key pair from its private key
+    pub fn from_private(private: [u8; X25519_KEY_SIZE]) -> Self {
+        use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519Secret};
+
+        let private_key = X25519Secret::from(private);
+        let public_key = X25519PublicKey::from(&private_key);
+
+        Self {
+            public: public_key.as_bytes().to_vec(),
+            private: private_key.to_bytes().to_vec(),
+        }
+    }
+
+    /// Create from public key only (for remote keys)
+    pub fn from_public(public: Vec<u8>) -> Self {
+        Self {
+            public,
+            private: Vec::new(),
+        }
+    }
+
+    /// Copy of the key pair without the private key
+    pub fn public(&self) -> Self {
+        Self::from_public(self.public.clone())
+    }
+
     /// Perform Diffie-Hellman key agreement
     pub fn dh(&self, their_public: &[u8]) -> Result<Vec<u8>> {
         use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519Secret};
 
+        if self.private.is_empty() {
+            return Err(CryptoError::InvalidKey("No private key available".to_string()));
+        }
+
         // Reconstruct our private key
         let private_bytes: [u8; 32] = self.private
             .as_slice()
@@ -109,6 +144,16 @@ impl IdentityKey {
         })
     }
 
+    /// Restore an identity key from its Ed25519 secret key
+    pub fn from_private(private: [u8; ED25519_PRIVATE_KEY_SIZE]) -> Self {
+        let signing_key = SigningKey::from_bytes(&private);
+
+        Self {
+            public: signing_key.verifying_key().to_bytes().to_vec(),
+            private: Some(signing_key.to_bytes().to_vec()),
+        }
+    }
+
     /// Sign a message
     pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
         let private = self.private.as_ref()
@@ -165,6 +210,73 @@ impl IdentityKey {
     pub fn is_owned(&self) -> bool {
         self.private.is_some()
     }
+
+    /// Copy of the identity key without the private key
+    pub fn public(&self) -> Self {
+        Self::from_public(self.public.clone())
+    }
+}
+
+/// X25519 identity key for X3DH
+///
+/// Signed by the Ed25519 identity key it belongs to, so a peer that trusts
+/// the identity key can trust Diffie-Hellman results with this key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityDhKey {
+    /// The X25519 key pair
+    key_pair: KeyPair,
+    /// Identity key signature over the public key
+    signature: Vec<u8>,
+}
+
+impl IdentityDhKey {
+    /// Domain separator for the identity key signature
+    const SIGNATURE_CONTEXT: &'static [u8] = b"InvisibleIdentityDhKey";
+
+    /// Generate a new X25519 identity key for `identity_key`
+    pub fn generate(identity_key: &IdentityKey) -> Result<Self> {
+        Self::new(KeyPair::generate()?, identity_key)
+    }
+
+    /// Certify an existing X25519 key pair with `identity_key`
+    pub fn new(key_pair: KeyPair, identity_key: &IdentityKey) -> Result<Self> {
+        let signature = identity_key.sign(&Self::signed_message(key_pair.public_key()))?;
+
+        Ok(Self {
+            key_pair,
+            signature,
+        })
+    }
+
+    /// Verify that `identity_key` signed this key
+    pub fn verify(&self, identity_key: &IdentityKey) -> Result<()> {
+        identity_key.verify(
+            &Self::signed_message(self.key_pair.public_key()),
+            &self.signature,
+        )
+    }
+
+    /// Get public key
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key()
+    }
+
+    /// Get the key pair
+    pub fn key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    /// Copy of the key without the private key
+    pub fn public(&self) -> Self {
+        Self {
+            key_pair: self.key_pair.public(),
+            signature: self.signature.clone(),
+        }
+    }
+
+    fn signed_message(public_key: &[u8]) -> Vec<u8> {
+        [Self::SIGNATURE_CONTEXT, public_key].concat()
+    }
 }
 
 /// Signed pre-key for X3DH
@@ -183,8 +295,11 @@ pub struct SignedPreKey {
 impl SignedPreKey {
     /// Generate a new signed pre-key
     pub fn generate(id: u32, identity_key: &IdentityKey) -> Result<Self> {
-        let key_pair = KeyPair::generate()?;
+        Self::new(id, KeyPair::generate()?, identity_key)
+    }
 
+    /// Sign an existing key pair as a pre-key
+    pub fn new(id: u32, key_pair: KeyPair, identity_key: &IdentityKey) -> Result<Self> {
         // Sign the public key with identity key
         let signature = identity_key.sign(key_pair.public_key())?;
 
@@ -213,6 +328,99 @@ impl SignedPreKey {
     pub fn public_key(&self) -> &[u8] {
         self.key_pair.public_key()
     }
+
+    /// Get the key pair
+    pub fn key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    /// Copy of the pre-key without the private key
+    pub fn public(&self) -> Self {
+        Self {
+            key_pair: self.key_pair.public(),
+            signature: self.signature.clone(),
+            id: self.id,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// Signed ML-KEM-768 pre-key for PQXDH
+///
+/// Signed with a domain separator so an X25519 pre-key signature can't be
+/// passed off as one over a KEM key, or the reverse.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedKemPreKey {
+    /// The KEM key pair
+    key_pair: KemKeyPair,
+    /// Identity key signature over the public key
+    signature: Vec<u8>,
+    /// Key ID for rotation tracking
+    id: u32,
+    /// Timestamp when created
+    timestamp: u64,
+}
+
+impl SignedKemPreKey {
+    /// Domain separator for the identity key signature
+    const SIGNATURE_CONTEXT: &'static [u8] = b"InvisibleKemPreKey";
+
+    /// Generate a new signed KEM pre-key
+    pub fn generate(id: u32, identity_key: &IdentityKey) -> Result<Self> {
+        Self::new(id, KemKeyPair::generate()?, identity_key)
+    }
+
+    /// Sign an existing KEM key pair as a pre-key
+    pub fn new(id: u32, key_pair: KemKeyPair, identity_key: &IdentityKey) -> Result<Self> {
+        let signature = identity_key.sign(&Self::signed_message(key_pair.public_key()))?;
+
+        Ok(Self {
+            key_pair,
+            signature,
+            id,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        })
+    }
+
+    /// Verify that `identity_key` signed this pre-key
+    pub fn verify(&self, identity_key: &IdentityKey) -> Result<()> {
+        identity_key.verify(
+            &Self::signed_message(self.key_pair.public_key()),
+            &self.signature,
+        )
+    }
+
+    /// Get the key ID
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Get public key
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key()
+    }
+
+    /// Get the key pair
+    pub fn key_pair(&self) -> &KemKeyPair {
+        &self.key_pair
+    }
+
+    /// Copy of the pre-key without the private key
+    pub fn public(&self) -> Self {
+        Self {
+            key_pair: self.key_pair.public(),
+            signature: self.signature.clone(),
+            id: self.id,
+            timestamp: self.timestamp,
+        }
+    }
+
+    fn signed_message(public_key: &[u8]) -> Vec<u8> {
+        [Self::SIGNATURE_CONTEXT, public_key].concat()
+    }
 }
 
 /// One-time pre-key for X3DH
@@ -227,8 +435,12 @@ pub struct OneTimePreKey {
 impl OneTimePreKey {
     /// Generate a new one-time pre-key
     pub fn generate(id: u32) -> Result<Self> {
-        let key_pair = KeyPair::generate()?;
-        Ok(Self { key_pair, id })
+        Ok(Self::new(id, KeyPair::generate()?))
+    }
+
+    /// Use an existing key pair as a one-time pre-key
+    pub fn new(id: u32, key_pair: KeyPair) -> Self {
+        Self { key_pair, id }
     }
 
     /// Get the key ID
@@ -240,6 +452,16 @@ impl OneTimePreKey {
     pub fn public_key(&self) -> &[u8] {
         self.key_pair.public_key()
     }
+
+    /// Get the key pair
+    pub fn key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    /// Copy of the pre-key without the private key
+    pub fn public(&self) -> Self {
+        Self::new(self.id, self.key_pair.public())
+    }
 }
 
 #[cfg(test)]
@@ -270,4 +492,34 @@ mod tests {
         // Verify signature
         assert!(spk.verify(&identity).is_ok());
     }
+
+    #[test]
+    fn test_identity_dh_key() {
+        let identity = IdentityKey::generate().unwrap();
+        let dh_key = IdentityDhKey::generate(&identity).unwrap();
+        assert!(dh_key.verify(&identity).is_ok());
+
+        // A published copy verifies but can't agree
+        let published = dh_key.public();
+        assert!(published.verify(&identity.public()).is_ok());
+        assert!(published.key_pair().dh(dh_key.public_key()).is_err());
+
+        // Not signed by another identity, and not usable as a pre-key signature
+        let other = IdentityKey::generate().unwrap();
+        assert!(dh_key.verify(&other).is_err());
+        assert!(identity.verify(dh_key.public_key(), &dh_key.signature).is_err());
+    }
+
+    #[test]
+    fn test_signed_kem_pre_key() {
+        let identity = IdentityKey::generate().unwrap();
+        let pre_key = SignedKemPreKey::generate(7, &identity).unwrap();
+        assert_eq!(pre_key.id(), 7);
+        assert!(pre_key.verify(&identity.public()).is_ok());
+        assert!(pre_key.public().key_pair().decapsulate(&[0u8; 1088]).is_err());
+
+        let other = IdentityKey::generate().unwrap();
+        assert!(pre_key.verify(&other).is_err());
+        assert!(identity.verify(pre_key.public_key(), &pre_key.signature).is_err());
+    }
 }
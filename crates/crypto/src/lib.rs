@@ -6,6 +6,7 @@
 //! - X3DH key agreement protocol
 //! - Double Ratchet encryption
 //! - Post-quantum key exchange (PQXDH)
+//! - Multi-device sessions (Sesame)
 //! - Ed25519 signatures
 //! - Key derivation and management
 //!
@@ -26,15 +27,19 @@
 
 pub mod error;
 pub mod keys;
+pub mod kem;
 pub mod x3dh;
 pub mod double_ratchet;
+pub mod sesame;
 pub mod kdf;
 pub mod utils;
 
 pub use error::{CryptoError, Result};
-pub use keys::{IdentityKey, SignedPreKey, OneTimePreKey, KeyPair};
+pub use keys::{IdentityKey, IdentityDhKey, SignedPreKey, SignedKemPreKey, OneTimePreKey, KeyPair};
+pub use kem::KemKeyPair;
 pub use x3dh::X3DHSession;
 pub use double_ratchet::DoubleRatchet;
+pub use sesame::SessionManager;
 
 /// Library version
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,865 @@
+//! Multi-Device Session Management (Sesame)
+//!
+//! Implements Signal's Sesame algorithm for users with several devices that
+//! share one identity key. Each device publishes its own pre-key bundle, and
+//! the identity key signs the list of the user's current devices. A message
+//! is encrypted once per device: to each of the recipient's devices and to
+//! the sender's own other devices, so every device sees the conversation.
+//!
+//! ## Architecture
+//!
+//! - **Device Lists:** Versioned lists binding device IDs to X25519 identity
+//!   keys, signed by the identity key; an older version never replaces a
+//!   newer one
+//! - **Device Records:** Per device, an active session and a few inactive
+//!   ones. A session created by an incoming initial message, or one a
+//!   message decrypts with, becomes active; this resolves two devices
+//!   initiating sessions with each other at the same time
+//! - **Initial Messages:** A new session's X3DH initial message rides along
+//!   with every message on it until the first reply arrives
+//!
+//! ## Security Properties
+//!
+//! - **Device Authentication:** Bundles and initial messages are only
+//!   accepted from devices in the owner's signed device list, with the
+//!   listed X25519 identity key
+//! - **Removal:** Sessions with a device are deleted when a newer device list
+//!   drops it, and its messages are rejected from then on
+//! - **Device Binding:** Session associated data includes both device IDs
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::double_ratchet::{DoubleRatchet, EncryptedMessage};
+use crate::error::{CryptoError, Result};
+use crate::keys::{IdentityDhKey, IdentityKey};
+use crate::utils::concat;
+use crate::x3dh::{InitialMessage, PreKeyBundle, X3DHInitiator, X3DHResponder, X3DHSession};
+
+/// Device identifier, unique among one user's devices
+pub type DeviceId = u32;
+
+/// Maximum number of sessions kept per remote device, active one included
+const MAX_SESSIONS_PER_DEVICE: usize = 5;
+
+/// A device in a signed device list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    /// Device ID
+    pub device_id: DeviceId,
+    /// The device's X25519 identity key (public)
+    pub identity_dh_key: Vec<u8>,
+}
+
+impl DeviceEntry {
+    /// Entry for a device with the given X25519 identity key
+    pub fn new(device_id: DeviceId, identity_dh_key: &IdentityDhKey) -> Self {
+        Self {
+            device_id,
+            identity_dh_key: identity_dh_key.public_key().to_vec(),
+        }
+    }
+}
+
+/// A user's current devices, signed by their identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceList {
+    /// Version, increased with every change
+    version: u64,
+    /// Devices ordered by ID
+    devices: Vec<DeviceEntry>,
+    /// Identity key signature over the version and devices
+    signature: Vec<u8>,
+}
+
+impl DeviceList {
+    /// Domain separator for the identity key signature
+    const SIGNATURE_CONTEXT: &'static [u8] = b"InvisibleDeviceList";
+
+    /// Sign a device list
+    ///
+    /// # Arguments
+    /// * `identity_key` - The user's identity key, with its private key
+    /// * `version` - Must be higher than the previously published list's
+    /// * `devices` - The user's devices; IDs must be unique
+    pub fn new(
+        identity_key: &IdentityKey,
+        version: u64,
+        mut devices: Vec<DeviceEntry>,
+    ) -> Result<Self> {
+        devices.sort_by_key(|d| d.device_id);
+        if devices.windows(2).any(|w| w[0].device_id == w[1].device_id) {
+            return Err(CryptoError::DeviceError("Duplicate device ID".to_string()));
+        }
+
+        let signature = identity_key.sign(&Self::signed_message(version, &devices)?)?;
+
+        Ok(Self {
+            version,
+            devices,
+            signature,
+        })
+    }
+
+    /// Verify that `identity_key` signed this list
+    pub fn verify(&self, identity_key: &IdentityKey) -> Result<()> {
+        identity_key.verify(
+            &Self::signed_message(self.version, &self.devices)?,
+            &self.signature,
+        )
+    }
+
+    /// Get the version
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get the devices
+    pub fn devices(&self) -> &[DeviceEntry] {
+        &self.devices
+    }
+
+    /// Look up a device by ID
+    pub fn device(&self, device_id: DeviceId) -> Option<&DeviceEntry> {
+        self.devices.iter().find(|d| d.device_id == device_id)
+    }
+
+    fn signed_message(version: u64, devices: &[DeviceEntry]) -> Result<Vec<u8>> {
+        Ok(concat(&[
+            Self::SIGNATURE_CONTEXT,
+            &version.to_be_bytes(),
+            &bincode::serialize(devices)?,
+        ]))
+    }
+}
+
+/// Changes made by accepting a new device list
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceListChange {
+    /// Devices that are new, or whose X25519 identity key changed
+    pub added: Vec<DeviceId>,
+    /// Devices that were dropped, or whose X25519 identity key changed;
+    /// their sessions were deleted
+    pub removed: Vec<DeviceId>,
+}
+
+/// A device's pre-key bundle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceBundle {
+    /// ID of the device publishing the bundle
+    pub device_id: DeviceId,
+    /// The device's pre-key bundle
+    pub bundle: PreKeyBundle,
+}
+
+/// A message encrypted for one device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceMessage {
+    /// Sending device
+    pub sender_device: DeviceId,
+    /// Identity key (public) of the receiving device's owner
+    pub recipient: Vec<u8>,
+    /// Receiving device
+    pub recipient_device: DeviceId,
+    /// X3DH initial message, until the session has been confirmed
+    pub initial_message: Option<InitialMessage>,
+    /// The encrypted message
+    pub message: EncryptedMessage,
+}
+
+/// A session with one remote device
+#[derive(Debug)]
+struct Session {
+    /// Double Ratchet state
+    ratchet: DoubleRatchet,
+    /// Associated data for every message in the session
+    associated_data: Vec<u8>,
+    /// Initial message to send along until the first reply (initiator only)
+    pending_initial: Option<InitialMessage>,
+    /// Ephemeral key of the initial message that created the session
+    /// (responder only), to recognize repeated initial messages
+    base_key: Option<Vec<u8>>,
+}
+
+impl Session {
+    /// Session associated data: X3DH associated data and both device IDs
+    fn associated_data(
+        x3dh: &X3DHSession,
+        initiator_device: DeviceId,
+        responder_device: DeviceId,
+    ) -> Vec<u8> {
+        concat(&[
+            x3dh.associated_data(),
+            &initiator_device.to_be_bytes(),
+            &responder_device.to_be_bytes(),
+        ])
+    }
+}
+
+/// Sessions with a remote device, the active one first
+#[derive(Debug, Default)]
+struct DeviceRecord {
+    sessions: Vec<Session>,
+}
+
+impl DeviceRecord {
+    /// Make a new session the active one, dropping the oldest beyond the limit
+    fn activate(&mut self, session: Session) {
+        self.sessions.insert(0, session);
+        self.sessions.truncate(MAX_SESSIONS_PER_DEVICE);
+    }
+}
+
+/// A user's verified device list and sessions with their devices
+#[derive(Debug)]
+struct UserRecord {
+    device_list: DeviceList,
+    devices: BTreeMap<DeviceId, DeviceRecord>,
+}
+
+/// Sessions of one local device with every device of its contacts and with
+/// its owner's other devices
+#[derive(Debug)]
+pub struct SessionManager {
+    /// This device's ID
+    device_id: DeviceId,
+    /// This device's identity and pre-keys
+    responder: X3DHResponder,
+    /// Users by identity public key
+    users: HashMap<Vec<u8>, UserRecord>,
+}
+
+impl SessionManager {
+    /// Create a session manager for a local device
+    ///
+    /// # Arguments
+    /// * `device_id` - This device's ID in its owner's device list
+    /// * `responder` - This device's identity key, X25519 identity key and pre-keys
+    pub fn new(device_id: DeviceId, responder: X3DHResponder) -> Self {
+        Self {
+            device_id,
+            responder,
+            users: HashMap::new(),
+        }
+    }
+
+    /// Get this device's ID
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    /// This device's entry for its owner's device list
+    pub fn device_entry(&self) -> DeviceEntry {
+        DeviceEntry::new(self.device_id, self.responder.identity_dh_key())
+    }
+
+    /// Get this device's pre-key bundle to publish
+    pub fn bundle(&self) -> DeviceBundle {
+        DeviceBundle {
+            device_id: self.device_id,
+            bundle: self.responder.get_bundle(),
+        }
+    }
+
+    /// Accept a user's device list, including this device owner's own
+    ///
+    /// Sessions with devices the list drops, or whose X25519 identity key it
+    /// changes, are deleted. Fails if the list isn't signed by `user`, or is
+    /// older than the one already accepted.
+    ///
+    /// # Arguments
+    /// * `user` - The user's identity key
+    /// * `device_list` - Their device list
+    ///
+    /// # Returns
+    /// * `DeviceListChange` - Devices added and removed
+    pub fn update_device_list(
+        &mut self,
+        user: &IdentityKey,
+        device_list: DeviceList,
+    ) -> Result<DeviceListChange> {
+        device_list.verify(user)?;
+
+        let record = match self.users.get_mut(user.public_key()) {
+            Some(record) => record,
+            None => {
+                let change = DeviceListChange {
+                    added: device_list.devices().iter().map(|d| d.device_id).collect(),
+                    removed: Vec::new(),
+                };
+                self.users.insert(
+                    user.public_key().to_vec(),
+                    UserRecord {
+                        device_list,
+                        devices: BTreeMap::new(),
+                    },
+                );
+                return Ok(change);
+            }
+        };
+
+        if device_list.version() < record.device_list.version() {
+            return Err(CryptoError::DeviceError(format!(
+                "Device list version {} is older than {}",
+                device_list.version(),
+                record.device_list.version()
+            )));
+        }
+        if device_list.version() == record.device_list.version() {
+            if device_list.devices() != record.device_list.devices() {
+                return Err(CryptoError::DeviceError(format!(
+                    "Conflicting device lists for version {}",
+                    device_list.version()
+                )));
+            }
+            return Ok(DeviceListChange::default());
+        }
+
+        let mut change = DeviceListChange::default();
+        for old in record.device_list.devices() {
+            if device_list.device(old.device_id) != Some(old) {
+                change.removed.push(old.device_id);
+                record.devices.remove(&old.device_id);
+            }
+        }
+        for new in device_list.devices() {
+            if record.device_list.device(new.device_id) != Some(new) {
+                change.added.push(new.device_id);
+            }
+        }
+
+        record.device_list = device_list;
+        Ok(change)
+    }
+
+    /// Devices of `user` that messages to them need a session with first
+    ///
+    /// Fetch these devices' bundles and pass them to `add_bundle`.
+    pub fn missing_sessions(&self, user: &IdentityKey) -> Vec<DeviceId> {
+        let own = user.public_key() == self.responder.identity_key().public_key();
+
+        self.users
+            .get(user.public_key())
+            .map(|record| {
+                record
+                    .device_list
+                    .devices()
+                    .iter()
+                    .map(|d| d.device_id)
+                    .filter(|id| !(own && *id == self.device_id))
+                    .filter(|id| {
+                        record
+                            .devices
+                            .get(id)
+                            .map_or(true, |device| device.sessions.is_empty())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Start a session with one of `user`'s devices from its bundle
+    ///
+    /// The new session becomes the device's active session. Fails unless the
+    /// device is in the user's device list with the bundle's X25519 identity
+    /// key.
+    pub fn add_bundle(&mut self, user: &IdentityKey, bundle: &DeviceBundle) -> Result<()> {
+        let record = self
+            .users
+            .get_mut(user.public_key())
+            .ok_or_else(|| CryptoError::DeviceError("No device list for user".to_string()))?;
+        let entry = listed_device(&record.device_list, bundle.device_id)?;
+
+        if bundle.bundle.identity_key.public_key() != user.public_key()
+            || bundle.bundle.identity_dh_key.public_key() != entry.identity_dh_key.as_slice()
+        {
+            return Err(CryptoError::DeviceError(format!(
+                "Bundle doesn't match device {}",
+                bundle.device_id
+            )));
+        }
+
+        let initiator = X3DHInitiator::new(
+            self.responder.identity_key().clone(),
+            self.responder.identity_dh_key().clone(),
+        );
+        let (x3dh, initial_message) = initiator.initiate(&bundle.bundle)?;
+        let ratchet = DoubleRatchet::init_alice(
+            x3dh.shared_secret(),
+            bundle.bundle.signed_pre_key.public_key().to_vec(),
+        )?;
+
+        record
+            .devices
+            .entry(bundle.device_id)
+            .or_default()
+            .activate(Session {
+                ratchet,
+                associated_data: Session::associated_data(&x3dh, self.device_id, bundle.device_id),
+                pending_initial: Some(initial_message),
+                base_key: None,
+            });
+
+        Ok(())
+    }
+
+    /// Encrypt a message for every device of `recipient` and every other
+    /// device of this device's owner
+    ///
+    /// Fails without encrypting anything if a device has no session yet; see
+    /// `missing_sessions`.
+    ///
+    /// # Returns
+    /// * One message per device
+    pub fn encrypt(
+        &mut self,
+        recipient: &IdentityKey,
+        plaintext: &[u8],
+    ) -> Result<Vec<DeviceMessage>> {
+        let own_key = self.responder.identity_key().public_key().to_vec();
+        let mut users = vec![recipient.public_key().to_vec()];
+        if recipient.public_key() != own_key.as_slice() && self.users.contains_key(&own_key) {
+            users.push(own_key.clone());
+        }
+
+        if !self.users.contains_key(recipient.public_key()) {
+            return Err(CryptoError::DeviceError(
+                "No device list for recipient".to_string(),
+            ));
+        }
+        for user in &users {
+            let record = &self.users[user];
+            for entry in record.device_list.devices() {
+                let is_self = *user == own_key && entry.device_id == self.device_id;
+                let has_session = record
+                    .devices
+                    .get(&entry.device_id)
+                    .is_some_and(|device| !device.sessions.is_empty());
+                if !is_self && !has_session {
+                    return Err(CryptoError::DeviceError(format!(
+                        "No session with device {}",
+                        entry.device_id
+                    )));
+                }
+            }
+        }
+
+        let mut messages = Vec::new();
+        for user in &users {
+            let record = self.users.get_mut(user).expect("checked above");
+            for (device_id, device) in record.devices.iter_mut() {
+                if *user == own_key && *device_id == self.device_id {
+                    continue;
+                }
+                let Some(session) = device.sessions.first_mut() else {
+                    continue;
+                };
+
+                messages.push(DeviceMessage {
+                    sender_device: self.device_id,
+                    recipient: user.clone(),
+                    recipient_device: *device_id,
+                    initial_message: session.pending_initial.clone(),
+                    message: session
+                        .ratchet
+                        .encrypt(plaintext, &session.associated_data)?,
+                });
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Decrypt a message from one of `sender`'s devices
+    ///
+    /// An initial message starts a new session, which becomes the device's
+    /// active session. Other messages are tried against the device's active
+    /// session first, then its inactive ones.
+    pub fn decrypt(&mut self, sender: &IdentityKey, message: &DeviceMessage) -> Result<Vec<u8>> {
+        if message.recipient != self.responder.identity_key().public_key()
+            || message.recipient_device != self.device_id
+        {
+            return Err(CryptoError::DeviceError(format!(
+                "Message is for another device ({})",
+                message.recipient_device
+            )));
+        }
+
+        let record = self
+            .users
+            .get_mut(sender.public_key())
+            .ok_or_else(|| CryptoError::DeviceError("No device list for sender".to_string()))?;
+        let entry = listed_device(&record.device_list, message.sender_device)?;
+        let device = record.devices.entry(message.sender_device).or_default();
+
+        // A repeated initial message belongs to the session it created
+        let existing = message.initial_message.as_ref().and_then(|initial| {
+            device
+                .sessions
+                .iter()
+                .position(|s| s.base_key.as_deref() == Some(initial.ephemeral_key.as_slice()))
+        });
+
+        match (&message.initial_message, existing) {
+            (Some(initial), None) => {
+                if initial.identity_key.public_key() != sender.public_key()
+                    || initial.identity_dh_key.public_key() != entry.identity_dh_key.as_slice()
+                {
+                    return Err(CryptoError::DeviceError(format!(
+                        "Initial message doesn't match device {}",
+                        message.sender_device
+                    )));
+                }
+
+                // The one-time pre-key is only used up once the message
+                // decrypts, so a forged initial message can't burn it
+                let x3dh = self.responder.derive(initial)?;
+                let mut session = Session {
+                    ratchet: DoubleRatchet::init_bob(
+                        x3dh.shared_secret(),
+                        self.responder.signed_pre_key().key_pair().clone(),
+                    )?,
+                    associated_data: Session::associated_data(
+                        &x3dh,
+                        message.sender_device,
+                        self.device_id,
+                    ),
+                    pending_initial: None,
+                    base_key: Some(initial.ephemeral_key.clone()),
+                };
+
+                let plaintext = session
+                    .ratchet
+                    .decrypt(&message.message, &session.associated_data)?;
+                if let Some(id) = initial.one_time_pre_key_id {
+                    self.responder.consume_one_time_pre_key(id);
+                }
+                device.activate(session);
+                Ok(plaintext)
+            }
+            _ => {
+                let candidates: Vec<usize> = match existing {
+                    Some(index) => vec![index],
+                    None => (0..device.sessions.len()).collect(),
+                };
+
+                for index in candidates {
+                    let session = &mut device.sessions[index];
+                    if let Ok(plaintext) = session
+                        .ratchet
+                        .decrypt(&message.message, &session.associated_data)
+                    {
+                        // The peer has the session, so it's confirmed
+                        session.pending_initial = None;
+                        let session = device.sessions.remove(index);
+                        device.sessions.insert(0, session);
+                        return Ok(plaintext);
+                    }
+                }
+
+                Err(CryptoError::DecryptionFailed(format!(
+                    "No session with device {} decrypts the message",
+                    message.sender_device
+                )))
+            }
+        }
+    }
+}
+
+/// Look up a device in a device list
+fn listed_device(device_list: &DeviceList, device_id: DeviceId) -> Result<&DeviceEntry> {
+    device_list.device(device_id).ok_or_else(|| {
+        CryptoError::DeviceError(format!("Device {} isn't in the device list", device_id))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{OneTimePreKey, SignedPreKey};
+
+    fn device(identity: &IdentityKey, device_id: DeviceId) -> SessionManager {
+        let responder = X3DHResponder::new(
+            identity.clone(),
+            IdentityDhKey::generate(identity).unwrap(),
+            SignedPreKey::generate(1, identity).unwrap(),
+            (1..=4)
+                .map(|id| OneTimePreKey::generate(id).unwrap())
+                .collect(),
+        );
+
+        SessionManager::new(device_id, responder)
+    }
+
+    fn device_list(
+        identity: &IdentityKey,
+        version: u64,
+        devices: &[&SessionManager],
+    ) -> DeviceList {
+        let entries = devices.iter().map(|d| d.device_entry()).collect();
+        DeviceList::new(identity, version, entries).unwrap()
+    }
+
+    /// Accept `list` on every device in `devices`
+    fn publish(devices: &mut [&mut SessionManager], owner: &IdentityKey, list: &DeviceList) {
+        for device in devices.iter_mut() {
+            device
+                .update_device_list(&owner.public(), list.clone())
+                .unwrap();
+        }
+    }
+
+    /// Create the sessions `sender` is missing for `recipient`'s `devices`
+    fn connect(sender: &mut SessionManager, recipient: &IdentityKey, devices: &[&SessionManager]) {
+        for device_id in sender.missing_sessions(&recipient.public()) {
+            let device = devices.iter().find(|d| d.device_id() == device_id).unwrap();
+            sender
+                .add_bundle(&recipient.public(), &device.bundle())
+                .unwrap();
+        }
+    }
+
+    fn deliver(
+        messages: &[DeviceMessage],
+        sender: &IdentityKey,
+        recipient: &mut SessionManager,
+    ) -> Vec<u8> {
+        let message = messages
+            .iter()
+            .find(|m| {
+                m.recipient == recipient.responder.identity_key().public_key()
+                    && m.recipient_device == recipient.device_id()
+            })
+            .unwrap();
+        recipient.decrypt(&sender.public(), message).unwrap()
+    }
+
+    #[test]
+    fn test_device_list() {
+        let identity = IdentityKey::generate().unwrap();
+        let phone = device(&identity, 1);
+        let laptop = device(&identity, 2);
+
+        let list = device_list(&identity, 1, &[&laptop, &phone]);
+        assert!(list.verify(&identity.public()).is_ok());
+        assert_eq!(list.devices()[0].device_id, 1);
+        assert!(list.device(3).is_none());
+
+        let other = IdentityKey::generate().unwrap();
+        assert!(list.verify(&other).is_err());
+        let mut tampered = list.clone();
+        tampered.version = 2;
+        assert!(tampered.verify(&identity).is_err());
+
+        assert!(DeviceList::new(
+            &identity,
+            1,
+            vec![phone.device_entry(), phone.device_entry()]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_multi_device_fan_out() {
+        let alice = IdentityKey::generate().unwrap();
+        let bob = IdentityKey::generate().unwrap();
+        let mut alice_phone = device(&alice, 1);
+        let mut alice_desktop = device(&alice, 2);
+        let mut bob_phone = device(&bob, 1);
+        let mut bob_laptop = device(&bob, 2);
+
+        let alice_list = device_list(&alice, 1, &[&alice_phone, &alice_desktop]);
+        let bob_list = device_list(&bob, 1, &[&bob_phone, &bob_laptop]);
+        let mut everyone = [
+            &mut alice_phone,
+            &mut alice_desktop,
+            &mut bob_phone,
+            &mut bob_laptop,
+        ];
+        publish(&mut everyone, &alice, &alice_list);
+        publish(&mut everyone, &bob, &bob_list);
+
+        // Alice's phone needs sessions with Bob's devices and her desktop
+        assert!(alice_phone.encrypt(&bob.public(), b"hi").is_err());
+        assert_eq!(alice_phone.missing_sessions(&bob.public()), vec![1, 2]);
+        assert_eq!(alice_phone.missing_sessions(&alice.public()), vec![2]);
+        connect(&mut alice_phone, &bob, &[&bob_phone, &bob_laptop]);
+        connect(&mut alice_phone, &alice, &[&alice_desktop]);
+
+        // Two messages before any reply both carry the initial message
+        for text in [&b"hi"[..], b"again"] {
+            let messages = alice_phone.encrypt(&bob.public(), text).unwrap();
+            assert_eq!(messages.len(), 3);
+            assert!(messages.iter().all(|m| m.initial_message.is_some()));
+            assert_eq!(deliver(&messages, &alice, &mut bob_phone), text);
+            assert_eq!(deliver(&messages, &alice, &mut bob_laptop), text);
+            assert_eq!(deliver(&messages, &alice, &mut alice_desktop), text);
+        }
+
+        // Bob's phone replies to Alice's devices and his laptop
+        connect(&mut bob_phone, &alice, &[&alice_phone, &alice_desktop]);
+        connect(&mut bob_phone, &bob, &[&bob_laptop]);
+        let messages = bob_phone.encrypt(&alice.public(), b"hello").unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(deliver(&messages, &bob, &mut alice_phone), b"hello");
+        assert_eq!(deliver(&messages, &bob, &mut alice_desktop), b"hello");
+        assert_eq!(deliver(&messages, &bob, &mut bob_laptop), b"hello");
+
+        // The reply confirmed the session with Bob's phone
+        let messages = alice_phone.encrypt(&bob.public(), b"thanks").unwrap();
+        let to_phone = messages
+            .iter()
+            .find(|m| m.recipient == bob.public_key() && m.recipient_device == 1);
+        assert!(to_phone.unwrap().initial_message.is_none());
+        assert_eq!(deliver(&messages, &alice, &mut bob_phone), b"thanks");
+
+        // Messages from unlisted devices or other users are rejected
+        let messages = alice_phone.encrypt(&bob.public(), b"bye").unwrap();
+        let to_laptop = messages
+            .iter()
+            .find(|m| m.recipient == bob.public_key() && m.recipient_device == 2)
+            .unwrap();
+        let mut forged = to_laptop.clone();
+        forged.sender_device = 9;
+        assert!(bob_laptop.decrypt(&alice.public(), &forged).is_err());
+        let stranger = IdentityKey::generate().unwrap();
+        assert!(bob_laptop.decrypt(&stranger.public(), to_laptop).is_err());
+        assert!(bob_phone.decrypt(&alice.public(), to_laptop).is_err());
+        assert_eq!(
+            bob_laptop.decrypt(&alice.public(), to_laptop).unwrap(),
+            b"bye"
+        );
+    }
+
+    #[test]
+    fn test_device_removal() {
+        let alice = IdentityKey::generate().unwrap();
+        let bob = IdentityKey::generate().unwrap();
+        let mut alice_phone = device(&alice, 1);
+        let bob_phone = device(&bob, 1);
+        let mut bob_laptop = device(&bob, 2);
+
+        let bob_list = device_list(&bob, 1, &[&bob_phone, &bob_laptop]);
+        let change = alice_phone
+            .update_device_list(&bob.public(), bob_list.clone())
+            .unwrap();
+        assert_eq!(change.added, vec![1, 2]);
+        publish(&mut [&mut bob_laptop], &bob, &bob_list);
+        let alice_list = device_list(&alice, 1, &[&alice_phone]);
+        publish(&mut [&mut bob_laptop], &alice, &alice_list);
+        connect(&mut alice_phone, &bob, &[&bob_phone, &bob_laptop]);
+        connect(&mut bob_laptop, &alice, &[&alice_phone]);
+        connect(&mut bob_laptop, &bob, &[&bob_phone]);
+        let from_laptop = bob_laptop.encrypt(&alice.public(), b"bye").unwrap();
+
+        // Bob drops his laptop
+        let new_list = device_list(&bob, 2, &[&bob_phone]);
+        let change = alice_phone
+            .update_device_list(&bob.public(), new_list.clone())
+            .unwrap();
+        assert_eq!(
+            change,
+            DeviceListChange {
+                added: vec![],
+                removed: vec![2]
+            }
+        );
+        assert_eq!(alice_phone.encrypt(&bob.public(), b"hi").unwrap().len(), 1);
+        assert!(alice_phone.decrypt(&bob.public(), &from_laptop[0]).is_err());
+
+        // Rollback, conflicting and forged lists
+        assert!(alice_phone
+            .update_device_list(&bob.public(), bob_list)
+            .is_err());
+        let conflicting = device_list(&bob, 2, &[&bob_phone, &bob_laptop]);
+        assert!(alice_phone
+            .update_device_list(&bob.public(), conflicting)
+            .is_err());
+        let mallory = IdentityKey::generate().unwrap();
+        let forged = device_list(&mallory, 3, &[&bob_phone, &bob_laptop]);
+        assert!(alice_phone
+            .update_device_list(&bob.public(), forged)
+            .is_err());
+        assert_eq!(
+            alice_phone
+                .update_device_list(&bob.public(), new_list)
+                .unwrap(),
+            DeviceListChange::default()
+        );
+
+        // A re-provisioned device replaces the old one
+        let new_phone = device(&bob, 1);
+        let list = device_list(&bob, 3, &[&new_phone]);
+        let change = alice_phone.update_device_list(&bob.public(), list).unwrap();
+        assert_eq!(
+            change,
+            DeviceListChange {
+                added: vec![1],
+                removed: vec![1]
+            }
+        );
+        assert!(alice_phone
+            .add_bundle(&bob.public(), &bob_phone.bundle())
+            .is_err());
+        assert!(alice_phone
+            .add_bundle(&bob.public(), &new_phone.bundle())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_simultaneous_initiation() {
+        let alice = IdentityKey::generate().unwrap();
+        let bob = IdentityKey::generate().unwrap();
+        let mut alice_phone = device(&alice, 1);
+        let mut bob_phone = device(&bob, 1);
+
+        let alice_list = device_list(&alice, 1, &[&alice_phone]);
+        let bob_list = device_list(&bob, 1, &[&bob_phone]);
+        publish(&mut [&mut alice_phone, &mut bob_phone], &alice, &alice_list);
+        publish(&mut [&mut alice_phone, &mut bob_phone], &bob, &bob_list);
+        connect(&mut alice_phone, &bob, &[&bob_phone]);
+        connect(&mut bob_phone, &alice, &[&alice_phone]);
+
+        // Both send before either receives
+        let from_alice = alice_phone.encrypt(&bob.public(), b"from alice").unwrap();
+        let from_bob = bob_phone.encrypt(&alice.public(), b"from bob").unwrap();
+        assert_eq!(deliver(&from_alice, &alice, &mut bob_phone), b"from alice");
+        assert_eq!(deliver(&from_bob, &bob, &mut alice_phone), b"from bob");
+
+        // Each now sends on the other's session, and they converge
+        let messages = alice_phone.encrypt(&bob.public(), b"one").unwrap();
+        assert_eq!(deliver(&messages, &alice, &mut bob_phone), b"one");
+        let messages = bob_phone.encrypt(&alice.public(), b"two").unwrap();
+        assert_eq!(deliver(&messages, &bob, &mut alice_phone), b"two");
+        let messages = alice_phone.encrypt(&bob.public(), b"three").unwrap();
+        assert_eq!(deliver(&messages, &alice, &mut bob_phone), b"three");
+    }
+
+    #[test]
+    fn test_undecryptable_initial_message_keeps_pre_key() {
+        let alice = IdentityKey::generate().unwrap();
+        let bob = IdentityKey::generate().unwrap();
+        let mut alice_phone = device(&alice, 1);
+        let mut bob_phone = device(&bob, 1);
+
+        let alice_list = device_list(&alice, 1, &[&alice_phone]);
+        let bob_list = device_list(&bob, 1, &[&bob_phone]);
+        let mut everyone = [&mut alice_phone, &mut bob_phone];
+        publish(&mut everyone, &alice, &alice_list);
+        publish(&mut everyone, &bob, &bob_list);
+        connect(&mut alice_phone, &bob, &[&bob_phone]);
+
+        let messages = alice_phone.encrypt(&bob.public(), b"hi").unwrap();
+        let pre_keys = bob_phone.responder.one_time_pre_key_count();
+
+        // A tampered first message neither uses up the pre-key nor starts a session
+        let mut tampered = messages[0].clone();
+        tampered.message.ciphertext[0] ^= 1;
+        assert!(bob_phone.decrypt(&alice.public(), &tampered).is_err());
+        assert_eq!(bob_phone.responder.one_time_pre_key_count(), pre_keys);
+
+        assert_eq!(deliver(&messages, &alice, &mut bob_phone), b"hi");
+        assert_eq!(bob_phone.responder.one_time_pre_key_count(), pre_keys - 1);
+    }
+}
@@ -6,24 +6,44 @@
 //! ## Protocol Overview
 //!
 //! 1. Bob publishes identity key (IK_B), signed pre-key (SPK_B), and one-time pre-keys (OPK_B)
-//! 2. Alice fetches Bob's key bundle
+//! 2. Alice fetches Bob's key bundle and verifies its signatures
 //! 3. Alice generates ephemeral key (EK_A) and computes DH operations
 //! 4. Alice derives shared secret SK = KDF(DH1 || DH2 || DH3 || DH4)
-//! 5. Alice sends initial message with IK_A, EK_A, and optionally used OPK_B id
-//! 6. Bob computes same shared secret from received keys
+//! 5. Alice sends initial message with IK_A, EK_A, and the ids of the pre-keys used
+//! 6. Bob computes same shared secret from received keys and deletes OPK_B
+//!
+//! Identity keys are Ed25519, which can't do Diffie-Hellman, so the DH steps
+//! use each party's `IdentityDhKey`: a separate X25519 identity key signed by
+//! the Ed25519 identity key.
+//!
+//! ## PQXDH
+//!
+//! A bundle may also carry a signed ML-KEM-768 pre-key (PQSPK_B). Alice then
+//! encapsulates a secret SS to it, sends the ciphertext along, and derives
+//! SK = KDF(DH1 || DH2 || DH3 [|| DH4] || SS). Bundles without a KEM pre-key
+//! fall back to classic X3DH, unless Alice requires post-quantum agreement.
 //!
 //! ## Security Properties
 //!
 //! - **Forward Secrecy:** Compromise of long-term keys doesn't reveal past session keys
 //! - **Deniability:** No cryptographic proof of who sent messages
 //! - **Asynchronous:** Parties can establish shared secret without being online simultaneously
+//! - **Authentication:** Bundles and initial messages are rejected unless their
+//!   X25519 keys are signed by the claimed identity key
+//! - **Post-Quantum Secrecy:** A PQXDH session key stays secret from an
+//!   adversary who records the handshake and later breaks X25519
+//! - **Downgrade Resistance:** The two versions use different KDF labels, and
+//!   an initiator requiring PQXDH refuses bundles without a KEM pre-key
 
 use serde::{Deserialize, Serialize};
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 use crate::error::{CryptoError, Result};
 use crate::kdf::hkdf_sha256;
-use crate::keys::{IdentityKey, KeyPair, OneTimePreKey, SignedPreKey};
+use crate::kem::encapsulate;
+use crate::keys::{
+    IdentityDhKey, IdentityKey, KeyPair, OneTimePreKey, SignedKemPreKey, SignedPreKey,
+};
 use crate::utils::concat;
 
 /// X3DH session that holds the derived shared secret
@@ -48,14 +68,50 @@ impl X3DHSession {
 }
 
 /// Pre-key bundle published by Bob for others to initiate sessions
+///
+/// Holds public keys only.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PreKeyBundle {
     /// Bob's identity key (public)
     pub identity_key: IdentityKey,
+    /// Bob's X25519 identity key (public), signed by `identity_key`
+    pub identity_dh_key: IdentityDhKey,
     /// Bob's signed pre-key (public)
     pub signed_pre_key: SignedPreKey,
     /// Bob's one-time pre-key (public, optional)
     pub one_time_pre_key: Option<OneTimePreKey>,
+    /// Bob's ML-KEM pre-key (public), present if Bob supports PQXDH
+    #[serde(default)]
+    pub kem_pre_key: Option<SignedKemPreKey>,
+}
+
+/// Key agreement protocol an initial message was made with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProtocolVersion {
+    /// Classic X3DH over X25519
+    #[default]
+    X3dh,
+    /// X3DH with an added ML-KEM-768 encapsulation
+    Pqxdh,
+}
+
+impl ProtocolVersion {
+    /// HKDF info string of the version
+    fn kdf_info(self) -> &'static [u8] {
+        match self {
+            ProtocolVersion::X3dh => b"X3DHv1",
+            ProtocolVersion::Pqxdh => b"PQXDHv1",
+        }
+    }
+}
+
+/// ML-KEM encapsulation to Bob's KEM pre-key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KemCiphertext {
+    /// ID of the KEM pre-key encapsulated to
+    pub pre_key_id: u32,
+    /// ML-KEM-768 ciphertext
+    pub ciphertext: Vec<u8>,
 }
 
 /// Initial message from Alice to Bob containing her public keys
@@ -63,10 +119,20 @@ pub struct PreKeyBundle {
 pub struct InitialMessage {
     /// Alice's identity key (public)
     pub identity_key: IdentityKey,
+    /// Alice's X25519 identity key (public), signed by `identity_key`
+    pub identity_dh_key: IdentityDhKey,
     /// Alice's ephemeral key (public)
     pub ephemeral_key: Vec<u8>,
+    /// ID of the signed pre-key used
+    pub signed_pre_key_id: u32,
     /// ID of the one-time pre-key used (if any)
     pub one_time_pre_key_id: Option<u32>,
+    /// Protocol the session key was derived with
+    #[serde(default)]
+    pub version: ProtocolVersion,
+    /// Encapsulated KEM secret (PQXDH only)
+    #[serde(default)]
+    pub kem_ciphertext: Option<KemCiphertext>,
 }
 
 /// Initiator side of X3DH (Alice)
@@ -74,16 +140,42 @@ pub struct InitialMessage {
 pub struct X3DHInitiator {
     /// Alice's identity key
     identity_key: IdentityKey,
+    /// Alice's X25519 identity key
+    identity_dh_key: IdentityDhKey,
+    /// Refuse bundles without a KEM pre-key
+    require_post_quantum: bool,
 }
 
 impl X3DHInitiator {
     /// Create a new X3DH initiator with an identity key
-    pub fn new(identity_key: IdentityKey) -> Self {
-        Self { identity_key }
+    ///
+    /// # Arguments
+    /// * `identity_key` - Alice's Ed25519 identity key
+    /// * `identity_dh_key` - Alice's X25519 identity key, with its private key
+    pub fn new(identity_key: IdentityKey, identity_dh_key: IdentityDhKey) -> Self {
+        Self {
+            identity_key,
+            identity_dh_key,
+            require_post_quantum: false,
+        }
+    }
+
+    /// Only agree with bundles that offer PQXDH
+    ///
+    /// Bundle keys are signed one by one, so an attacker serving the bundle
+    /// can strip the KEM pre-key; this makes that fail instead of silently
+    /// falling back to X3DH.
+    pub fn with_post_quantum_required(mut self) -> Self {
+        self.require_post_quantum = true;
+        self
     }
 
     /// Perform X3DH key agreement as the initiator (Alice)
     ///
+    /// Uses PQXDH if the bundle has a KEM pre-key. Fails if the bundle's keys
+    /// aren't signed by its identity key, or if post-quantum agreement is
+    /// required and the bundle has no KEM pre-key.
+    ///
     /// # Arguments
     /// * `bundle` - Bob's pre-key bundle
     ///
@@ -91,83 +183,89 @@ impl X3DHInitiator {
     /// * `X3DHSession` - The established session with shared secret
     /// * `InitialMessage` - Message to send to Bob
     pub fn initiate(&self, bundle: &PreKeyBundle) -> Result<(X3DHSession, InitialMessage)> {
+        bundle.identity_dh_key.verify(&bundle.identity_key)?;
+        bundle.signed_pre_key.verify(&bundle.identity_key)?;
+        match &bundle.kem_pre_key {
+            Some(kem_pre_key) => kem_pre_key.verify(&bundle.identity_key)?,
+            None if self.require_post_quantum => {
+                return Err(CryptoError::KeyAgreementFailed(
+                    "Bundle has no KEM pre-key".to_string(),
+                ))
+            }
+            None => {}
+        }
+
         // Generate ephemeral key for this session
         let ephemeral_key = KeyPair::generate()?;
 
-        // Perform Diffie-Hellman operations
+        self.agree(bundle, &ephemeral_key)
+    }
+
+    /// Key agreement with a given ephemeral key
+    fn agree(
+        &self,
+        bundle: &PreKeyBundle,
+        ephemeral_key: &KeyPair,
+    ) -> Result<(X3DHSession, InitialMessage)> {
         // DH1 = DH(IK_A, SPK_B)
         // DH2 = DH(EK_A, IK_B)
         // DH3 = DH(EK_A, SPK_B)
         // DH4 = DH(EK_A, OPK_B) if OPK_B exists
-
-        // TODO: Implement actual X25519 DH operations
-        // For now, using placeholders
-        let dh1 = self.dh(
-            self.identity_key.public_key(),
-            bundle.signed_pre_key.public_key(),
-        )?;
-        let dh2 = self.dh(
-            ephemeral_key.public_key(),
-            bundle.identity_key.public_key(),
-        )?;
-        let dh3 = self.dh(
-            ephemeral_key.public_key(),
-            bundle.signed_pre_key.public_key(),
-        )?;
-
-        let mut dh_outputs = vec![dh1, dh2, dh3];
-
-        let one_time_pre_key_id = if let Some(ref opk) = bundle.one_time_pre_key {
-            let dh4 = self.dh(ephemeral_key.public_key(), opk.public_key())?;
-            dh_outputs.push(dh4);
-            Some(opk.id())
-        } else {
-            None
+        let mut dh_outputs = vec![
+            dh(
+                self.identity_dh_key.key_pair(),
+                bundle.signed_pre_key.public_key(),
+            )?,
+            dh(ephemeral_key, bundle.identity_dh_key.public_key())?,
+            dh(ephemeral_key, bundle.signed_pre_key.public_key())?,
+        ];
+
+        let one_time_pre_key_id = match &bundle.one_time_pre_key {
+            Some(opk) => {
+                dh_outputs.push(dh(ephemeral_key, opk.public_key())?);
+                Some(opk.id())
+            }
+            None => None,
         };
 
-        // Concatenate all DH outputs
-        let dh_concat = concat(&dh_outputs.iter().map(|v| v.as_slice()).collect::<Vec<_>>());
-
-        // Derive shared secret using HKDF
-        let shared_secret = hkdf_sha256(&dh_concat, None, b"X3DHv1", 32)?;
+        // SS = ML-KEM-Encaps(PQSPK_B) for PQXDH
+        let (version, kem_ciphertext, kem_secret) = match &bundle.kem_pre_key {
+            Some(kem_pre_key) => {
+                let (ciphertext, shared_secret) = encapsulate(kem_pre_key.public_key())?;
+                let kem_ciphertext = KemCiphertext {
+                    pre_key_id: kem_pre_key.id(),
+                    ciphertext,
+                };
+                (
+                    ProtocolVersion::Pqxdh,
+                    Some(kem_ciphertext),
+                    Some(Zeroizing::new(shared_secret)),
+                )
+            }
+            None => (ProtocolVersion::X3dh, None, None),
+        };
 
-        // Build associated data (IK_A || IK_B)
-        let associated_data = concat(&[
+        // Associated data (IK_A || IK_B)
+        let session = derive_session(
+            version,
+            &dh_outputs,
+            kem_secret.as_deref().map(Vec::as_slice),
             self.identity_key.public_key(),
             bundle.identity_key.public_key(),
-        ]);
-
-        let session = X3DHSession {
-            shared_secret,
-            associated_data,
-        };
+        )?;
 
         let initial_message = InitialMessage {
-            identity_key: self.identity_key.clone(),
+            identity_key: self.identity_key.public(),
+            identity_dh_key: self.identity_dh_key.public(),
             ephemeral_key: ephemeral_key.public_key().to_vec(),
+            signed_pre_key_id: bundle.signed_pre_key.id(),
             one_time_pre_key_id,
+            version,
+            kem_ciphertext,
         };
 
         Ok((session, initial_message))
     }
-
-    /// Perform Diffie-Hellman key agreement
-    fn dh(&self, _our_public: &[u8], their_public: &[u8]) -> Result<Vec<u8>> {
-        use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
-        use rand::rngs::OsRng;
-
-        // Generate temp key for DH
-        // TODO: In production, use actual stored private keys
-        let our_secret = StaticSecret::random_from_rng(OsRng);
-
-        let their_pub_bytes: [u8; 32] = their_public
-            .try_into()
-            .map_err(|_| CryptoError::InvalidKey("Invalid public key length".to_string()))?;
-        let their_public_key = X25519PublicKey::from(their_pub_bytes);
-
-        let shared_secret = our_secret.diffie_hellman(&their_public_key);
-        Ok(shared_secret.as_bytes().to_vec())
-    }
 }
 
 /// Responder side of X3DH (Bob)
@@ -175,125 +273,286 @@ impl X3DHInitiator {
 pub struct X3DHResponder {
     /// Bob's identity key
     identity_key: IdentityKey,
+    /// Bob's X25519 identity key
+    identity_dh_key: IdentityDhKey,
     /// Bob's signed pre-key
     signed_pre_key: SignedPreKey,
-    /// Bob's one-time pre-keys
+    /// Bob's unused one-time pre-keys
     one_time_pre_keys: Vec<OneTimePreKey>,
+    /// Bob's KEM pre-key, if he offers PQXDH
+    kem_pre_key: Option<SignedKemPreKey>,
 }
 
 impl X3DHResponder {
     /// Create a new X3DH responder with key material
     pub fn new(
         identity_key: IdentityKey,
+        identity_dh_key: IdentityDhKey,
         signed_pre_key: SignedPreKey,
         one_time_pre_keys: Vec<OneTimePreKey>,
     ) -> Self {
         Self {
             identity_key,
+            identity_dh_key,
             signed_pre_key,
             one_time_pre_keys,
+            kem_pre_key: None,
         }
     }
 
+    /// Offer PQXDH with a signed KEM pre-key
+    pub fn with_kem_pre_key(mut self, kem_pre_key: SignedKemPreKey) -> Self {
+        self.kem_pre_key = Some(kem_pre_key);
+        self
+    }
+
     /// Get the pre-key bundle to publish
+    ///
+    /// Offers the oldest unused one-time pre-key; the server distributing
+    /// bundles should hand each one-time pre-key out only once.
     pub fn get_bundle(&self) -> PreKeyBundle {
         PreKeyBundle {
-            identity_key: self.identity_key.clone(),
-            signed_pre_key: self.signed_pre_key.clone(),
-            one_time_pre_key: self.one_time_pre_keys.first().cloned(),
+            identity_key: self.identity_key.public(),
+            identity_dh_key: self.identity_dh_key.public(),
+            signed_pre_key: self.signed_pre_key.public(),
+            one_time_pre_key: self.one_time_pre_keys.first().map(OneTimePreKey::public),
+            kem_pre_key: self.kem_pre_key.as_ref().map(SignedKemPreKey::public),
         }
     }
 
+    /// Get the identity key
+    pub fn identity_key(&self) -> &IdentityKey {
+        &self.identity_key
+    }
+
+    /// Get the X25519 identity key
+    pub fn identity_dh_key(&self) -> &IdentityDhKey {
+        &self.identity_dh_key
+    }
+
+    /// Get the signed pre-key
+    ///
+    /// Its key pair is Bob's initial Double Ratchet key.
+    pub fn signed_pre_key(&self) -> &SignedPreKey {
+        &self.signed_pre_key
+    }
+
+    /// Number of one-time pre-keys not used yet
+    pub fn one_time_pre_key_count(&self) -> usize {
+        self.one_time_pre_keys.len()
+    }
+
     /// Respond to an initial message from Alice
     ///
+    /// The one-time pre-key Alice used is deleted, so replaying the message
+    /// fails. Fails if Alice's X25519 identity key isn't signed by her
+    /// identity key, the message names a pre-key Bob doesn't have, or its
+    /// version doesn't match whether it carries a KEM ciphertext.
+    ///
+    /// # Arguments
+    /// * `msg` - The initial message from Alice
+    ///
+    /// # Returns
+    /// * `X3DHSession` - The established session with shared secret
+    pub fn respond(&mut self, msg: &InitialMessage) -> Result<X3DHSession> {
+        let session = self.derive(msg)?;
+        if let Some(id) = msg.one_time_pre_key_id {
+            self.consume_one_time_pre_key(id);
+        }
+        Ok(session)
+    }
+
+    /// Derive the session for an initial message from Alice without using up
+    /// its one-time pre-key
+    ///
+    /// For callers that first check the message's payload decrypts; they
+    /// must call [`Self::consume_one_time_pre_key`] once it does, or the
+    /// message can be replayed. Fails in the same cases as [`Self::respond`].
+    ///
     /// # Arguments
     /// * `msg` - The initial message from Alice
     ///
     /// # Returns
     /// * `X3DHSession` - The established session with shared secret
-    pub fn respond(&self, msg: &InitialMessage) -> Result<X3DHSession> {
+    pub fn derive(&self, msg: &InitialMessage) -> Result<X3DHSession> {
+        msg.identity_dh_key.verify(&msg.identity_key)?;
+
+        if msg.signed_pre_key_id != self.signed_pre_key.id() {
+            return Err(CryptoError::KeyAgreementFailed(format!(
+                "Unknown signed pre-key {}",
+                msg.signed_pre_key_id
+            )));
+        }
+
+        let one_time_pre_key = match msg.one_time_pre_key_id {
+            Some(id) => Some(
+                self.one_time_pre_keys
+                    .iter()
+                    .find(|k| k.id() == id)
+                    .ok_or_else(|| {
+                        CryptoError::KeyAgreementFailed(format!(
+                            "Unknown one-time pre-key {}",
+                            id
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+
         // Perform the same DH operations as Alice
         // DH1 = DH(SPK_B, IK_A)
         // DH2 = DH(IK_B, EK_A)
         // DH3 = DH(SPK_B, EK_A)
         // DH4 = DH(OPK_B, EK_A) if OPK was used
-
-        // TODO: Implement actual X25519 DH operations
-        let dh1 = self.dh(
-            self.signed_pre_key.public_key(),
-            msg.identity_key.public_key(),
-        )?;
-        let dh2 = self.dh(self.identity_key.public_key(), &msg.ephemeral_key)?;
-        let dh3 = self.dh(self.signed_pre_key.public_key(), &msg.ephemeral_key)?;
-
-        let mut dh_outputs = vec![dh1, dh2, dh3];
-
-        if let Some(opk_id) = msg.one_time_pre_key_id {
-            // Find the one-time pre-key that was used
-            if let Some(opk) = self.one_time_pre_keys.iter().find(|k| k.id() == opk_id) {
-                let dh4 = self.dh(opk.public_key(), &msg.ephemeral_key)?;
-                dh_outputs.push(dh4);
-
-                // TODO: Remove used one-time pre-key from storage
-            }
+        let mut dh_outputs = vec![
+            dh(
+                self.signed_pre_key.key_pair(),
+                msg.identity_dh_key.public_key(),
+            )?,
+            dh(self.identity_dh_key.key_pair(), &msg.ephemeral_key)?,
+            dh(self.signed_pre_key.key_pair(), &msg.ephemeral_key)?,
+        ];
+
+        if let Some(opk) = one_time_pre_key {
+            dh_outputs.push(dh(opk.key_pair(), &msg.ephemeral_key)?);
         }
 
-        // Concatenate all DH outputs (must be in same order as Alice)
-        let dh_concat = concat(&dh_outputs.iter().map(|v| v.as_slice()).collect::<Vec<_>>());
-
-        // Derive shared secret using HKDF
-        let shared_secret = hkdf_sha256(&dh_concat, None, b"X3DHv1", 32)?;
+        // SS = ML-KEM-Decaps(PQSPK_B, CT) for PQXDH
+        let kem_secret = match (msg.version, &msg.kem_ciphertext) {
+            (ProtocolVersion::X3dh, None) => None,
+            (ProtocolVersion::Pqxdh, Some(kem_ciphertext)) => {
+                let kem_pre_key = self
+                    .kem_pre_key
+                    .as_ref()
+                    .filter(|k| k.id() == kem_ciphertext.pre_key_id)
+                    .ok_or_else(|| {
+                        CryptoError::KeyAgreementFailed(format!(
+                            "Unknown KEM pre-key {}",
+                            kem_ciphertext.pre_key_id
+                        ))
+                    })?;
+                Some(Zeroizing::new(
+                    kem_pre_key
+                        .key_pair()
+                        .decapsulate(&kem_ciphertext.ciphertext)?,
+                ))
+            }
+            _ => {
+                return Err(CryptoError::KeyAgreementFailed(
+                    "KEM ciphertext doesn't match protocol version".to_string(),
+                ))
+            }
+        };
 
-        // Build associated data (IK_A || IK_B)
-        let associated_data = concat(&[
+        // Associated data (IK_A || IK_B)
+        derive_session(
+            msg.version,
+            &dh_outputs,
+            kem_secret.as_deref().map(Vec::as_slice),
             msg.identity_key.public_key(),
             self.identity_key.public_key(),
-        ]);
+        )
+    }
 
-        Ok(X3DHSession {
-            shared_secret,
-            associated_data,
-        })
+    /// Delete a one-time pre-key once a session using it is established
+    ///
+    /// Unknown IDs are ignored.
+    pub fn consume_one_time_pre_key(&mut self, id: u32) {
+        self.one_time_pre_keys.retain(|k| k.id() != id);
     }
+}
 
-    /// Perform Diffie-Hellman key agreement
-    fn dh(&self, _our_public: &[u8], their_public: &[u8]) -> Result<Vec<u8>> {
-        use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
-        use rand::rngs::OsRng;
+/// Perform Diffie-Hellman key agreement
+///
+/// Rejects low-order public keys, which would make the output all zeros.
+fn dh(our_key: &KeyPair, their_public: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let shared_secret = Zeroizing::new(our_key.dh(their_public)?);
 
-        // Generate temp key for DH
-        // TODO: In production, use actual stored private keys
-        let our_secret = StaticSecret::random_from_rng(OsRng);
+    if shared_secret.iter().all(|b| *b == 0) {
+        return Err(CryptoError::KeyAgreementFailed(
+            "Non-contributory public key".to_string(),
+        ));
+    }
 
-        let their_pub_bytes: [u8; 32] = their_public
-            .try_into()
-            .map_err(|_| CryptoError::InvalidKey("Invalid public key length".to_string()))?;
-        let their_public_key = X25519PublicKey::from(their_pub_bytes);
+    Ok(shared_secret)
+}
 
-        let shared_secret = our_secret.diffie_hellman(&their_public_key);
-        Ok(shared_secret.as_bytes().to_vec())
+/// Derive the session from the DH outputs and the KEM secret
+///
+/// SK = HKDF(F || DH1 || DH2 || DH3 [|| DH4] [|| SS]) with F = 32 0xFF bytes
+/// and a zero salt, as in the X3DH and PQXDH specifications. The info string
+/// names the version, so the two protocols never share a key.
+fn derive_session(
+    version: ProtocolVersion,
+    dh_outputs: &[Zeroizing<Vec<u8>>],
+    kem_secret: Option<&[u8]>,
+    initiator_identity: &[u8],
+    responder_identity: &[u8],
+) -> Result<X3DHSession> {
+    let mut input_key_material = Zeroizing::new(vec![0xFFu8; 32]);
+    for output in dh_outputs {
+        input_key_material.extend_from_slice(output);
+    }
+    if let Some(kem_secret) = kem_secret {
+        input_key_material.extend_from_slice(kem_secret);
     }
+
+    let shared_secret = hkdf_sha256(
+        &input_key_material,
+        Some(&[0u8; 32]),
+        version.kdf_info(),
+        32,
+    )?;
+    let associated_data = concat(&[initiator_identity, responder_identity]);
+
+    Ok(X3DHSession {
+        shared_secret,
+        associated_data,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_prekey_bundle_creation() {
+    fn responder(one_time_pre_keys: u32) -> X3DHResponder {
         let identity = IdentityKey::generate().unwrap();
+        let identity_dh_key = IdentityDhKey::generate(&identity).unwrap();
         let signed_pre_key = SignedPreKey::generate(1, &identity).unwrap();
-        let one_time_pre_key = OneTimePreKey::generate(1).unwrap();
+        let one_time_pre_keys = (1..=one_time_pre_keys)
+            .map(|id| OneTimePreKey::generate(id).unwrap())
+            .collect();
 
-        let bundle = PreKeyBundle {
-            identity_key: identity,
-            signed_pre_key,
-            one_time_pre_key: Some(one_time_pre_key),
-        };
+        X3DHResponder::new(identity, identity_dh_key, signed_pre_key, one_time_pre_keys)
+    }
+
+    fn initiator() -> X3DHInitiator {
+        let identity = IdentityKey::generate().unwrap();
+        let identity_dh_key = IdentityDhKey::generate(&identity).unwrap();
+
+        X3DHInitiator::new(identity, identity_dh_key)
+    }
+
+    fn key(hex: &str) -> KeyPair {
+        let bytes: [u8; 32] = hex::decode(hex).unwrap().try_into().unwrap();
+        KeyPair::from_private(bytes)
+    }
+
+    #[test]
+    fn test_prekey_bundle_creation() {
+        let responder = responder(1);
+        let bundle = responder.get_bundle();
 
         assert!(!bundle.identity_key.public_key().is_empty());
         assert!(!bundle.signed_pre_key.public_key().is_empty());
         assert!(bundle.one_time_pre_key.is_some());
+
+        // Only public keys are published
+        assert!(!bundle.identity_key.is_owned());
+        assert!(bundle.signed_pre_key.key_pair().private_key().is_empty());
+        let published = bincode::serialize(&bundle).unwrap();
+        let private = responder.signed_pre_key().key_pair().private_key();
+        assert!(!published.windows(private.len()).any(|w| w == private));
     }
 
     #[test]
@@ -311,22 +570,179 @@ mod tests {
     }
 
     #[test]
-    fn test_x3dh_initiator_creation() {
-        let identity = IdentityKey::generate().unwrap();
-        let _initiator = X3DHInitiator::new(identity);
+    fn test_x3dh_agreement() {
+        let alice = initiator();
+        let mut bob = responder(2);
+
+        // With a one-time pre-key
+        let (alice_session, message) = alice.initiate(&bob.get_bundle()).unwrap();
+        assert_eq!(message.one_time_pre_key_id, Some(1));
+        let bob_session = bob.respond(&message).unwrap();
+        assert_eq!(alice_session.shared_secret(), bob_session.shared_secret());
+        assert_eq!(alice_session.associated_data(), bob_session.associated_data());
+
+        // The pre-key is consumed: a replay fails and the next bundle moves on
+        assert_eq!(bob.one_time_pre_key_count(), 1);
+        assert!(bob.respond(&message).is_err());
+        assert_eq!(bob.get_bundle().one_time_pre_key.unwrap().id(), 2);
+
+        // Without one
+        let mut bundle = bob.get_bundle();
+        bundle.one_time_pre_key = None;
+        let (alice_session, message) = alice.initiate(&bundle).unwrap();
+        let bob_session = bob.respond(&message).unwrap();
+        assert_eq!(alice_session.shared_secret(), bob_session.shared_secret());
+        assert_eq!(bob.one_time_pre_key_count(), 1);
     }
 
     #[test]
-    fn test_x3dh_responder_bundle() {
+    fn test_x3dh_derive_then_consume() {
+        let alice = initiator();
+        let mut bob = responder(2);
+
+        // Deriving leaves the pre-key in place until it's consumed
+        let (alice_session, message) = alice.initiate(&bob.get_bundle()).unwrap();
+        let bob_session = bob.derive(&message).unwrap();
+        assert_eq!(alice_session.shared_secret(), bob_session.shared_secret());
+        assert_eq!(bob.one_time_pre_key_count(), 2);
+        assert!(bob.derive(&message).is_ok());
+
+        bob.consume_one_time_pre_key(message.one_time_pre_key_id.unwrap());
+        assert_eq!(bob.one_time_pre_key_count(), 1);
+        assert!(bob.derive(&message).is_err());
+    }
+
+    #[test]
+    fn test_x3dh_rejects_forged_keys() {
+        let alice = initiator();
+        let mut bob = responder(1);
+        let mallory = responder(1);
+
+        // Signed pre-key signed by someone else
+        let mut bundle = bob.get_bundle();
+        bundle.signed_pre_key = mallory.get_bundle().signed_pre_key;
+        assert!(alice.initiate(&bundle).is_err());
+
+        // X25519 identity key signed by someone else
+        let mut bundle = bob.get_bundle();
+        bundle.identity_dh_key = mallory.get_bundle().identity_dh_key;
+        assert!(alice.initiate(&bundle).is_err());
+
+        // Alice's X25519 identity key swapped in transit
+        let (_, mut message) = alice.initiate(&bob.get_bundle()).unwrap();
+        message.identity_dh_key = mallory.get_bundle().identity_dh_key;
+        assert!(bob.respond(&message).is_err());
+
+        // Low-order ephemeral key
+        let (_, mut message) = alice.initiate(&bob.get_bundle()).unwrap();
+        message.ephemeral_key = vec![0u8; 32];
+        assert!(bob.respond(&message).is_err());
+    }
+
+    #[test]
+    fn test_pqxdh_agreement() {
+        let alice = initiator();
+        let mut classic = responder(1);
         let identity = IdentityKey::generate().unwrap();
-        let signed_pre_key = SignedPreKey::generate(1, &identity).unwrap();
-        let one_time_pre_keys = vec![OneTimePreKey::generate(1).unwrap()];
+        let mut bob = X3DHResponder::new(
+            identity.clone(),
+            IdentityDhKey::generate(&identity).unwrap(),
+            SignedPreKey::generate(1, &identity).unwrap(),
+            Vec::new(),
+        )
+        .with_kem_pre_key(SignedKemPreKey::generate(5, &identity).unwrap());
+
+        let (alice_session, message) = alice.initiate(&bob.get_bundle()).unwrap();
+        assert_eq!(message.version, ProtocolVersion::Pqxdh);
+        assert_eq!(message.kem_ciphertext.as_ref().unwrap().pre_key_id, 5);
+        let bob_session = bob.respond(&message).unwrap();
+        assert_eq!(alice_session.shared_secret(), bob_session.shared_secret());
+
+        // The KEM secret is part of the key
+        let mut tampered = message.clone();
+        tampered.kem_ciphertext.as_mut().unwrap().ciphertext[0] ^= 1;
+        let tampered_session = bob.respond(&tampered).unwrap();
+        assert_ne!(tampered_session.shared_secret(), alice_session.shared_secret());
+
+        // Version and ciphertext must agree
+        let mut tampered = message.clone();
+        tampered.version = ProtocolVersion::X3dh;
+        assert!(bob.respond(&tampered).is_err());
+        let mut tampered = message.clone();
+        tampered.kem_ciphertext = None;
+        assert!(bob.respond(&tampered).is_err());
+
+        // Classic bundles still work, unless post-quantum agreement is required
+        let (alice_session, message) = alice.initiate(&classic.get_bundle()).unwrap();
+        assert_eq!(message.version, ProtocolVersion::X3dh);
+        assert!(message.kem_ciphertext.is_none());
+        let classic_session = classic.respond(&message).unwrap();
+        assert_eq!(alice_session.shared_secret(), classic_session.shared_secret());
+
+        let strict = initiator().with_post_quantum_required();
+        assert!(strict.initiate(&classic.get_bundle()).is_err());
+        assert!(strict.initiate(&bob.get_bundle()).is_ok());
+
+        // Stripping or swapping the KEM pre-key in transit
+        let mut stripped = bob.get_bundle();
+        stripped.kem_pre_key = None;
+        assert!(strict.initiate(&stripped).is_err());
+        let mut forged = bob.get_bundle();
+        forged.kem_pre_key = Some(SignedKemPreKey::generate(5, &classic.identity_key).unwrap());
+        assert!(alice.initiate(&forged).is_err());
+    }
 
-        let responder = X3DHResponder::new(identity, signed_pre_key, one_time_pre_keys);
-        let bundle = responder.get_bundle();
+    /// Regression vectors
+    ///
+    /// Recorded from this implementation to catch changes to the key
+    /// derivation; they aren't checked against another implementation.
+    ///
+    /// X25519 private keys: IK_A and IK_B are the RFC 7748 section 6.1 keys,
+    /// EK_A, SPK_B and OPK_B are the bytes 0x00..0x1f, 0x20..0x3f and
+    /// 0x40..0x5f. SK = HKDF-SHA256(salt = 32 zero bytes,
+    /// IKM = 32 0xFF bytes || DH1 || DH2 || DH3 [|| DH4], info = "X3DHv1").
+    #[test]
+    fn test_x3dh_regression_vectors() {
+        let ik_a = key("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let ik_b = key("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let ek_a = key("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        let spk_b = key("202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f");
+        let opk_b = key("404142434445464748494a4b4c4d4e4f505152535455565758595a5b5c5d5e5f");
+
+        let alice_identity = IdentityKey::from_private([1u8; 32]);
+        let bob_identity = IdentityKey::from_private([2u8; 32]);
+        let alice = X3DHInitiator::new(
+            alice_identity.clone(),
+            IdentityDhKey::new(ik_a, &alice_identity).unwrap(),
+        );
+        let mut bob = X3DHResponder::new(
+            bob_identity.clone(),
+            IdentityDhKey::new(ik_b, &bob_identity).unwrap(),
+            SignedPreKey::new(1, spk_b, &bob_identity).unwrap(),
+            vec![OneTimePreKey::new(1, opk_b)],
+        );
+
+        let cases = [
+            (true, "cc9db1bd13a7a8c9b50aa559cfe42d4f635eb07affc5811cae9e107e955c32ea"),
+            (false, "d5c197a782dc6a20d087384a22c8ccb47413c6c3e59175f292e423c30d895ee8"),
+        ];
+        for (with_opk, expected) in cases {
+            let mut bundle = bob.get_bundle();
+            if !with_opk {
+                bundle.one_time_pre_key = None;
+            }
 
-        assert!(!bundle.identity_key.public_key().is_empty());
-        assert!(!bundle.signed_pre_key.public_key().is_empty());
-        assert!(bundle.one_time_pre_key.is_some());
+            let (session, message) = alice.agree(&bundle, &ek_a).unwrap();
+            assert_eq!(hex::encode(session.shared_secret()), expected);
+            assert_eq!(
+                hex::encode(session.associated_data()),
+                [
+                    hex::encode(alice_identity.public_key()),
+                    hex::encode(bob_identity.public_key()),
+                ]
+                .concat()
+            );
+            assert_eq!(hex::encode(bob.respond(&message).unwrap().shared_secret()), expected);
+        }
     }
 }
@@ -23,6 +23,10 @@ pub enum RelayError {
     /// Invalid packet format
     #[error("Invalid packet: {0}")]
     InvalidPacket(String),
+
+    /// Key management failed
+    #[error("Key error: {0}")]
+    KeyError(String),
 }
 
 impl From<invisible_scrambler::ScramblerError> for RelayError {
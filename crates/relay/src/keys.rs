@@ -0,0 +1,364 @@
+//! Epoch Key Ring
+//!
+//! Per-epoch Sphinx key pairs held by a relay.
+//!
+//! The relay generates key pairs for the current epoch and a few upcoming
+//! ones so clients can build routes ahead of a rotation. Keys are erased once
+//! their epoch's grace window has passed, which gives the mixnet forward
+//! secrecy: a node compromised later cannot unwrap packets captured earlier.
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use invisible_crypto::keys::KeyPair;
+use invisible_scrambler::epoch::{Epoch, EpochKey, EpochSchedule};
+use zeroize::Zeroizing;
+
+use crate::error::{RelayError, Result};
+
+/// Key pairs for the current, upcoming, and grace-window epochs
+#[derive(Debug)]
+pub struct EpochKeyRing {
+    schedule: EpochSchedule,
+    /// Number of future epochs to hold keys for
+    publish_ahead: u64,
+    keys: BTreeMap<Epoch, KeyPair>,
+    /// Key file (in-memory only if unset)
+    path: Option<PathBuf>,
+}
+
+impl EpochKeyRing {
+    /// Create a key ring, loading persisted keys if `path` is set
+    ///
+    /// # Arguments
+    /// * `schedule` - Key rotation schedule
+    /// * `publish_ahead` - Number of future epochs to generate keys for
+    /// * `path` - Key file; keys survive restarts only if set
+    pub fn open(schedule: EpochSchedule, publish_ahead: u64, path: Option<PathBuf>) -> Result<Self> {
+        let keys = match &path {
+            Some(path) => load_keys(path)?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(Self {
+            schedule,
+            publish_ahead,
+            keys,
+            path,
+        })
+    }
+
+    /// Generate missing keys and erase expired ones
+    ///
+    /// # Returns
+    /// * `true` if the set of held keys changed
+    pub fn rotate(&mut self, now: SystemTime) -> Result<bool> {
+        let current = self.schedule.epoch_at(now);
+        let oldest = self.schedule.oldest_accepted(now);
+        let before = self.keys.len();
+
+        // Dropped key pairs are zeroized
+        self.keys = self.keys.split_off(&oldest);
+        let mut changed = self.keys.len() != before;
+
+        for epoch in current..=current.saturating_add(self.publish_ahead) {
+            if let Entry::Vacant(entry) = self.keys.entry(epoch) {
+                let keypair = KeyPair::generate()
+                    .map_err(|e| RelayError::KeyError(e.to_string()))?;
+                entry.insert(keypair);
+                changed = true;
+            }
+        }
+
+        if changed {
+            if let Some(path) = &self.path {
+                store_keys(path, &self.keys)?;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Key pair for unwrapping a packet built for `epoch`
+    ///
+    /// Returns `None` if the epoch isn't accepted at `now`, even if its key
+    /// hasn't been erased yet.
+    pub fn key_for_packet(&self, epoch: Epoch, now: SystemTime) -> Option<&KeyPair> {
+        if !self.schedule.accepts(epoch, now) {
+            return None;
+        }
+
+        self.keys.get(&epoch)
+    }
+
+    /// Public keys for the current and upcoming epochs
+    pub fn published_keys(&self, now: SystemTime) -> Vec<EpochKey> {
+        let current = self.schedule.epoch_at(now);
+
+        self.keys
+            .range(current..)
+            .map(|(epoch, keypair)| EpochKey {
+                epoch: *epoch,
+                public_key: keypair.public_key().to_vec(),
+            })
+            .collect()
+    }
+
+    /// Key rotation schedule
+    pub fn schedule(&self) -> &EpochSchedule {
+        &self.schedule
+    }
+}
+
+/// Load a 32-byte secret from `path`, generating and storing one if missing
+///
+/// The file is readable by the owner only.
+pub(crate) fn load_or_create_secret(path: &Path) -> Result<Zeroizing<[u8; 32]>> {
+    if let Some(secret) = read_secret(path)? {
+        return Ok(secret);
+    }
+
+    use rand::RngCore;
+
+    let mut secret = Zeroizing::new([0u8; 32]);
+    rand::thread_rng().fill_bytes(secret.as_mut());
+
+    replace_private(path, secret.as_ref())?;
+    Ok(secret)
+}
+
+/// Load a 32-byte secret from `path`, failing if it is missing
+pub(crate) fn load_secret(path: &Path) -> Result<Zeroizing<[u8; 32]>> {
+    read_secret(path)?.ok_or_else(|| {
+        RelayError::KeyError(format!("Missing secret file {}", path.display()))
+    })
+}
+
+/// Read a 32-byte secret, or `None` if the file doesn't exist
+fn read_secret(path: &Path) -> Result<Option<Zeroizing<[u8; 32]>>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => Zeroizing::new(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(io_error("read", path, e)),
+    };
+
+    let mut secret = Zeroizing::new([0u8; 32]);
+    if bytes.len() != secret.len() {
+        return Err(RelayError::KeyError(format!(
+            "Corrupt secret file {}",
+            path.display()
+        )));
+    }
+    secret.copy_from_slice(&bytes);
+    Ok(Some(secret))
+}
+
+fn load_keys(path: &Path) -> Result<BTreeMap<Epoch, KeyPair>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => Zeroizing::new(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(io_error("read", path, e)),
+    };
+
+    bincode::deserialize(&bytes)
+        .map_err(|e| RelayError::KeyError(format!("Corrupt key file {}: {}", path.display(), e)))
+}
+
+/// Atomically replace the key file, readable by the owner only
+fn store_keys(path: &Path, keys: &BTreeMap<Epoch, KeyPair>) -> Result<()> {
+    // Serialize into an exactly sized buffer so no reallocation leaves copies behind
+    let size = bincode::serialized_size(keys).map_err(|e| RelayError::KeyError(e.to_string()))?;
+    let mut bytes = Zeroizing::new(Vec::with_capacity(size as usize));
+    bincode::serialize_into(&mut *bytes, keys)
+        .map_err(|e| RelayError::KeyError(e.to_string()))?;
+
+    replace_private(path, &bytes)
+}
+
+/// Durably replace `path` with `bytes` and scrub the replaced contents
+///
+/// The temp file is synced before the rename and the directory after it, so a
+/// crash leaves either the old or the new file. The old file is then
+/// overwritten with zeros, so erased keys don't linger in its blocks.
+fn replace_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    if let Err(e) = write_private(&tmp_path, bytes) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    // Keep a handle on the file being replaced (renaming over an open file
+    // only works on unix)
+    let old = if cfg!(unix) {
+        fs::OpenOptions::new().write(true).open(path).ok()
+    } else {
+        None
+    };
+    fs::rename(&tmp_path, path).map_err(|e| io_error("rename", path, e))?;
+    sync_dir(path)?;
+
+    if let Some(old) = old {
+        scrub(old, path)?;
+    }
+
+    Ok(())
+}
+
+/// Overwrite a replaced (unlinked) file with zeros
+fn scrub(mut file: fs::File, path: &Path) -> Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let len = file.metadata().map_err(|e| io_error("stat", path, e))?.len();
+    file.seek(SeekFrom::Start(0)).map_err(|e| io_error("seek", path, e))?;
+    let zeros = [0u8; 4096];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n]).map_err(|e| io_error("scrub", path, e))?;
+        remaining -= n as u64;
+    }
+
+    file.sync_all().map_err(|e| io_error("sync", path, e))
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| io_error("open", path, e))?;
+
+    file.write_all(bytes).map_err(|e| io_error("write", path, e))?;
+    file.sync_all().map_err(|e| io_error("sync", path, e))
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let mut file = fs::File::create(path).map_err(|e| io_error("open", path, e))?;
+    file.write_all(bytes).map_err(|e| io_error("write", path, e))?;
+    file.sync_all().map_err(|e| io_error("sync", path, e))
+}
+
+/// Sync the directory holding `path` so a rename within it is durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| io_error("sync", dir, e))
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn io_error(action: &str, path: &Path, e: std::io::Error) -> RelayError {
+    RelayError::KeyError(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn schedule() -> EpochSchedule {
+        EpochSchedule {
+            genesis: 0,
+            epoch_duration: Duration::from_secs(100),
+            grace_period: Duration::from_secs(10),
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_rotation() {
+        let mut ring = EpochKeyRing::open(schedule(), 2, None).unwrap();
+
+        assert!(ring.rotate(at(150)).unwrap());
+        let published: Vec<_> = ring.published_keys(at(150)).iter().map(|k| k.epoch).collect();
+        assert_eq!(published, vec![1, 2, 3]);
+        assert!(!ring.rotate(at(160)).unwrap());
+
+        // Epoch 1 stays usable through the grace window after the boundary
+        ring.rotate(at(205)).unwrap();
+        assert!(ring.key_for_packet(1, at(205)).is_some());
+        assert!(ring.key_for_packet(2, at(205)).is_some());
+
+        // ...and is erased once it closes
+        ring.rotate(at(215)).unwrap();
+        assert!(ring.key_for_packet(1, at(215)).is_none());
+        assert!(!ring.keys.contains_key(&1));
+    }
+
+    #[test]
+    fn test_future_epoch_not_accepted() {
+        let mut ring = EpochKeyRing::open(schedule(), 2, None).unwrap();
+        ring.rotate(at(150)).unwrap();
+
+        // Published, but not yet in effect
+        assert!(ring.key_for_packet(3, at(150)).is_none());
+        assert!(ring.key_for_packet(3, at(300)).is_some());
+    }
+
+    #[test]
+    fn test_keys_persisted_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+
+        let mut ring = EpochKeyRing::open(schedule(), 1, Some(path.clone())).unwrap();
+        ring.rotate(at(150)).unwrap();
+        let published = ring.published_keys(at(150));
+        drop(ring);
+
+        let mut ring = EpochKeyRing::open(schedule(), 1, Some(path.clone())).unwrap();
+        assert!(!ring.rotate(at(150)).unwrap());
+        assert_eq!(ring.published_keys(at(150)), published);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replaced_key_file_scrubbed() {
+        use std::io::Read;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.bin");
+
+        let mut ring = EpochKeyRing::open(schedule(), 1, Some(path.clone())).unwrap();
+        ring.rotate(at(150)).unwrap();
+        let mut replaced = fs::File::open(&path).unwrap();
+
+        // Epoch 1 is erased on rotation; the old file must not keep its key
+        ring.rotate(at(215)).unwrap();
+        let mut contents = Vec::new();
+        replaced.read_to_end(&mut contents).unwrap();
+        assert!(!contents.is_empty());
+        assert!(contents.iter().all(|&b| b == 0));
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
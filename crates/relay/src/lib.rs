@@ -17,9 +17,11 @@
 )]
 
 pub mod error;
+pub mod keys;
 pub mod node;
 pub mod server;
 
 pub use error::{RelayError, Result};
+pub use keys::EpochKeyRing;
 pub use node::{MixNode, NodeConfig, NodeStats};
 pub use server::RelayServer;
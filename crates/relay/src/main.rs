@@ -20,7 +20,7 @@ async fn main() -> anyhow::Result<()> {
     );
 
     // Create mix node
-    let node = MixNode::new(config.clone());
+    let node = MixNode::new(config.clone())?;
 
     // Create and start server
     let mut server = RelayServer::new(node);
@@ -1,18 +1,38 @@
 //! Mix Node Implementation
 //!
 //! Relay nodes process Sphinx packets through the mixnet.
+//!
+//! Sphinx keys rotate per epoch (see `invisible_scrambler::epoch`); a packet
+//! is unwrapped with the key of the epoch named in its header.
+//!
+//! Dead drop changes are copied to sibling relays signed with the relay's
+//! identity key. A relay applies a change only if the sender is one of its
+//! configured siblings and the consensus lists it under the signing key.
 
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use invisible_crypto::keys::IdentityKey;
 
 use invisible_scrambler::{
-    dead_drop::{DeadDropNode, DeadDropConfig},
+    dead_drop::{
+        AccessToken, DeadDropNode, DeadDropConfig, DropId, FetchedPage, LeaseId, StoredMessage,
+    },
+    directory::{RelayDescriptor, SignedConsensus, SignedDescriptor},
+    drop_store::LogStore,
+    epoch::{EpochKey, EpochSchedule},
     mixnet::{GeoLocation, Jurisdiction, MixNodeState, MixStrategy},
+    network::WireMessage,
+    replay::ReplayCache,
+    stamp::{StoreStamp, TokenBatch, TokenRequest},
     sphinx::{SphinxPacket, process_packet, ProcessedPacket},
 };
 
 use crate::error::{Result, RelayError};
+use crate::keys::{load_or_create_secret, load_secret, EpochKeyRing};
 
 /// Mix node configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,10 +41,6 @@ pub struct NodeConfig {
     pub node_id: [u8; 32],
     /// Node layer (0-4)
     pub layer: u8,
-    /// Private key
-    pub private_key: Vec<u8>,
-    /// Public key
-    pub public_key: Vec<u8>,
     /// Listen address
     pub listen_addr: SocketAddr,
     /// Geographic location
@@ -33,6 +49,25 @@ pub struct NodeConfig {
     pub mix_strategy: MixStrategy,
     /// Dead drop config
     pub dead_drop_config: DeadDropConfig,
+    /// Advertised bandwidth (bytes/s)
+    pub bandwidth: u64,
+    /// Operator running the node, if declared
+    pub operator: Option<String>,
+    /// Key rotation schedule
+    pub epoch_schedule: EpochSchedule,
+    /// Number of future epochs to publish keys for
+    pub publish_ahead: u64,
+    /// Epoch key file (keys are lost on restart if unset)
+    pub key_store_path: Option<PathBuf>,
+    /// Replay cache file (in-memory only if unset)
+    pub replay_cache_path: Option<PathBuf>,
+    /// Encrypted dead drop log (in-memory only if unset); its key, store
+    /// token secret and spent stamps are kept next to it with `.key`,
+    /// `.stamp` and `.spent` extensions
+    pub dead_drop_path: Option<PathBuf>,
+    /// Sibling relays that hold copies of this node's dead drops, and whose
+    /// copies this node holds
+    pub dead_drop_replicas: Vec<SocketAddr>,
 }
 
 impl Default for NodeConfig {
@@ -42,8 +77,6 @@ impl Default for NodeConfig {
         Self {
             node_id: [0u8; 32],
             layer: 0,
-            private_key: vec![0u8; 32],
-            public_key: vec![0u8; 32],
             listen_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
             location: GeoLocation {
                 country: "CH".to_string(),
@@ -51,6 +84,14 @@ impl Default for NodeConfig {
             },
             mix_strategy: MixStrategy::default(),
             dead_drop_config: DeadDropConfig::default(),
+            bandwidth: 10 * 1024 * 1024, // 10 MiB/s
+            operator: None,
+            epoch_schedule: EpochSchedule::default(),
+            publish_ahead: 2,
+            key_store_path: None,
+            replay_cache_path: None,
+            dead_drop_path: None,
+            dead_drop_replicas: Vec::new(),
         }
     }
 }
@@ -64,10 +105,81 @@ pub struct NodeStats {
     pub packets_forwarded: u64,
     /// Packets delivered
     pub packets_delivered: u64,
-    /// Current batch size
+    /// Packets held by the mixer
     pub current_batch_size: usize,
     /// Dead drop messages
     pub dead_drop_messages: usize,
+    /// Replayed packets rejected
+    pub packets_replayed: u64,
+}
+
+/// Domain separator for signatures on dead drop changes
+const REPLICA_CONTEXT: &[u8] = b"InvisibleDeadDropReplica-v1";
+
+/// Dead drop change exchanged between sibling relays
+#[derive(Debug, Serialize)]
+enum ReplicaChange {
+    /// Message stored at the sibling
+    Store {
+        drop_id: DropId,
+        access_token: AccessToken,
+        message: StoredMessage,
+        stamp: Option<StoreStamp>,
+    },
+    /// Messages delivered at the sibling
+    Delete {
+        access_token: AccessToken,
+        message_ids: Vec<[u8; 16]>,
+    },
+}
+
+impl ReplicaChange {
+    /// Split a replication frame into its sender, change and signature
+    fn from_wire(message: WireMessage) -> Option<(Vec<u8>, Self, Vec<u8>)> {
+        match message {
+            WireMessage::ReplicateDeadDrop {
+                origin,
+                drop_id,
+                access_token,
+                message,
+                stamp,
+                signature,
+            } => Some((origin, Self::Store { drop_id, access_token, message, stamp }, signature)),
+            WireMessage::DeleteReplicas { origin, access_token, message_ids, signature } => {
+                Some((origin, Self::Delete { access_token, message_ids }, signature))
+            }
+            _ => None,
+        }
+    }
+
+    /// Replication frame carrying the change
+    fn into_wire(self, origin: Vec<u8>, signature: Vec<u8>) -> WireMessage {
+        match self {
+            Self::Store { drop_id, access_token, message, stamp } => {
+                WireMessage::ReplicateDeadDrop {
+                    origin,
+                    drop_id,
+                    access_token,
+                    message,
+                    stamp,
+                    signature,
+                }
+            }
+            Self::Delete { access_token, message_ids } => {
+                WireMessage::DeleteReplicas { origin, access_token, message_ids, signature }
+            }
+        }
+    }
+
+    /// Bytes the sending relay signs
+    fn signed_bytes(&self, origin: &[u8]) -> Result<Vec<u8>> {
+        let encoded = bincode::serialize(&(origin, self))
+            .map_err(|e| RelayError::InvalidPacket(e.to_string()))?;
+
+        let mut bytes = REPLICA_CONTEXT.to_vec();
+        bytes.extend_from_slice(&encoded);
+        Ok(bytes)
+    }
 }
 
 /// Mix node
@@ -75,49 +187,115 @@ pub struct NodeStats {
 pub struct MixNode {
     config: NodeConfig,
     mix_state: MixNodeState,
+    keys: EpochKeyRing,
     dead_drop: DeadDropNode,
+    replay_cache: ReplayCache,
+    /// Consensus served to clients, if this relay mirrors the directory
+    consensus: Option<SignedConsensus>,
+    /// Identity key dead drop changes are signed with
+    identity: Option<IdentityKey>,
     stats: NodeStats,
     output_queue: VecDeque<(SphinxPacket, SocketAddr)>,
+    /// Dead drop changes to send to sibling relays
+    replication_queue: VecDeque<(SocketAddr, WireMessage)>,
 }
 
 impl MixNode {
     /// Create new mix node
-    pub fn new(config: NodeConfig) -> Self {
+    ///
+    /// Loads persisted epoch keys, the replay cache and the dead drop log if
+    /// their paths are set, and generates keys for the current and upcoming
+    /// epochs.
+    pub fn new(config: NodeConfig) -> Result<Self> {
+        let now = SystemTime::now();
+
+        let mut keys = EpochKeyRing::open(
+            config.epoch_schedule.clone(),
+            config.publish_ahead,
+            config.key_store_path.clone(),
+        )?;
+        keys.rotate(now)?;
+
         let mix_node = invisible_scrambler::mixnet::MixNode {
             id: config.node_id,
             layer: config.layer,
-            public_key: config.public_key.clone(),
+            epoch_keys: keys.published_keys(now),
             address: config.listen_addr.to_string(),
             location: config.location.clone(),
+            bandwidth: config.bandwidth,
+            operator: config.operator.clone(),
         };
 
         let mix_state = MixNodeState::new(mix_node, config.mix_strategy.clone());
-        let dead_drop = DeadDropNode::new(config.dead_drop_config.clone());
+        let dead_drop = match &config.dead_drop_path {
+            Some(path) => {
+                // A new key can't read an existing log, so never make one for it
+                let key_path = path.with_extension("key");
+                let key = if path.exists() {
+                    load_secret(&key_path)?
+                } else {
+                    load_or_create_secret(&key_path)?
+                };
+                let store = LogStore::open(path, &key)?;
+                let stamp_secret = load_or_create_secret(&path.with_extension("stamp"))?;
+                DeadDropNode::with_store(config.dead_drop_config.clone(), Box::new(store))
+                    .with_persistent_stamps(&stamp_secret, path.with_extension("spent"))?
+            }
+            None => DeadDropNode::new(config.dead_drop_config.clone()),
+        };
 
-        Self {
+        let mut replay_cache = match &config.replay_cache_path {
+            Some(path) => ReplayCache::open(path)?,
+            None => ReplayCache::new(),
+        };
+        // Tags of earlier epochs belong to retired keys
+        replay_cache.retain_from(config.epoch_schedule.oldest_accepted(now))?;
+
+        Ok(Self {
             config,
             mix_state,
+            keys,
+            replay_cache,
+            consensus: None,
+            identity: None,
+            stats: NodeStats {
+                dead_drop_messages: dead_drop.stats().total_messages,
+                ..NodeStats::default()
+            },
             dead_drop,
-            stats: NodeStats::default(),
             output_queue: VecDeque::new(),
-        }
+            replication_queue: VecDeque::new(),
+        })
     }
 
     /// Process incoming packet
     pub async fn process_packet(&mut self, packet: SphinxPacket) -> Result<()> {
         self.stats.packets_received += 1;
 
-        match process_packet(&packet, &self.config.private_key)? {
-            ProcessedPacket::Forward { packet, next_hop } => {
-                self.mix_state.add_packet(packet);
+        let epoch = packet.header.epoch;
+        let keypair = self.keys.key_for_packet(epoch, SystemTime::now())
+            .ok_or_else(|| RelayError::InvalidPacket(format!("No key for epoch {}", epoch)))?;
 
-                tracing::debug!("Packet queued for forwarding");
+        let processed = process_packet(&packet, keypair.private_key())?;
 
-                if self.mix_state.should_forward() {
-                    self.forward_batch().await?;
-                }
+        // Drop packets this node has already processed under this epoch's key
+        let replay_tag = *processed.replay_tag();
+        if !self.replay_cache.check_and_insert(epoch, replay_tag)? {
+            self.stats.packets_replayed += 1;
+            tracing::warn!("Dropping replayed packet");
+
+            return Err(RelayError::InvalidPacket("Replayed packet".to_string()));
+        }
+
+        match processed {
+            ProcessedPacket::Forward { packet, next_hop, delay, .. } => {
+                self.mix_state.add_packet(packet, next_hop, delay);
+
+                tracing::debug!(%next_hop, delay_ms = delay.as_millis(), "Packet queued for forwarding");
+
+                self.release_ready();
             }
-            ProcessedPacket::Deliver { message } => {
+            ProcessedPacket::Deliver { message, .. } => {
                 self.handle_final_payload(message).await?;
                 self.stats.packets_delivered += 1;
             }
@@ -126,16 +304,19 @@ impl MixNode {
         Ok(())
     }
 
-    async fn forward_batch(&mut self) -> Result<()> {
-        let packets = self.mix_state.mix_and_extract();
-
-        for packet in packets {
-            let next_hop = self.config.listen_addr; // TODO: Extract from packet
+    /// Move packets the mixer has released to the output queue
+    pub fn release_ready(&mut self) {
+        for (packet, next_hop) in self.mix_state.ready_packets() {
             self.output_queue.push_back((packet, next_hop));
             self.stats.packets_forwarded += 1;
         }
 
-        Ok(())
+        self.stats.current_batch_size = self.mix_state.queued();
+    }
+
+    /// Earliest time at which the mixer may release packets
+    pub fn next_release(&self) -> Option<Instant> {
+        self.mix_state.next_deadline()
     }
 
     async fn handle_final_payload(&mut self, payload: Vec<u8>) -> Result<()> {
@@ -148,8 +329,12 @@ impl MixNode {
         Ok(())
     }
 
+    /// Store a dead drop message delivered through the mixnet
+    ///
+    /// Payload layout: `DEADROP_STORE:` | drop ID (32) | access token (32) |
+    /// stamp length (2, big-endian, 0 for none) | bincode stamp | message
     fn handle_dead_drop_store(&mut self, payload: Vec<u8>) -> Result<()> {
-        if payload.len() < 78 {
+        if payload.len() < 80 {
             return Err(RelayError::InvalidPacket("Payload too short".to_string()));
         }
 
@@ -159,33 +344,289 @@ impl MixNode {
         let mut access_token = [0u8; 32];
         access_token.copy_from_slice(&payload[46..78]);
 
-        let message = payload[78..].to_vec();
+        let stamp_len = u16::from_be_bytes([payload[78], payload[79]]) as usize;
+        let stamp_bytes = payload
+            .get(80..80 + stamp_len)
+            .ok_or_else(|| RelayError::InvalidPacket("Truncated stamp".to_string()))?;
+        let stamp = match stamp_len {
+            0 => None,
+            _ => Some(
+                bincode::deserialize(stamp_bytes)
+                    .map_err(|e| RelayError::InvalidPacket(e.to_string()))?,
+            ),
+        };
 
-        self.dead_drop.store_message(drop_id, access_token, message)?;
+        let message = payload[80 + stamp_len..].to_vec();
+
+        self.store_dead_drop(drop_id, access_token, message, stamp)?;
+
+        Ok(())
+    }
+
+    /// Store a message received over the wire in the dead drop
+    ///
+    /// The stamp must satisfy the relay's stamp policy. The message is queued
+    /// for replication to the sibling relays.
+    pub fn store_dead_drop(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        payload: Vec<u8>,
+        stamp: Option<StoreStamp>,
+    ) -> Result<[u8; 16]> {
+        let message = self
+            .dead_drop
+            .store_stamped(drop_id, access_token, payload, stamp.as_ref())?;
+        self.stats.dead_drop_messages = self.dead_drop.stats().total_messages;
+
+        let message_id = message.id;
+        self.replicate(ReplicaChange::Store { drop_id, access_token, message, stamp });
+
+        Ok(message_id)
+    }
+
+    /// Issue store tokens for a dead drop
+    ///
+    /// Token keys are derived from the relay's stamp secret, so siblings
+    /// replicating the drop don't accept each other's tokens.
+    pub fn issue_store_tokens(
+        &mut self,
+        drop_id: &DropId,
+        request: &TokenRequest,
+    ) -> Result<TokenBatch> {
+        Ok(self.dead_drop.issue_tokens(drop_id, request)?)
+    }
+
+    /// Retrieve messages from the dead drop
+    ///
+    /// Deletion of the retrieved messages is queued for the sibling relays.
+    pub fn retrieve_dead_drop(&mut self, access_token: &AccessToken) -> Result<Vec<StoredMessage>> {
+        let messages = self.dead_drop.retrieve_messages(access_token)?;
+        self.stats.dead_drop_messages = self.dead_drop.stats().total_messages;
+
+        if !messages.is_empty() {
+            self.replicate(ReplicaChange::Delete {
+                access_token: *access_token,
+                message_ids: messages.iter().map(|m| m.id).collect(),
+            });
+        }
+
+        Ok(messages)
+    }
+
+    /// Fetch a page of dead drop messages under a lease
+    pub fn fetch_dead_drop(
+        &mut self,
+        access_token: &AccessToken,
+        limit: usize,
+    ) -> Result<FetchedPage> {
+        let page = self.dead_drop.fetch_messages(access_token, limit)?;
+        self.stats.dead_drop_messages = self.dead_drop.stats().total_messages;
+
+        Ok(page)
+    }
+
+    /// Delete dead drop messages fetched under a lease
+    ///
+    /// Deletion of the acknowledged messages is queued for the sibling relays.
+    ///
+    /// # Returns
+    /// * Number of messages deleted
+    pub fn ack_dead_drop(
+        &mut self,
+        access_token: &AccessToken,
+        lease_id: &LeaseId,
+        message_ids: &[[u8; 16]],
+    ) -> Result<usize> {
+        let acked = self.dead_drop.ack_messages(access_token, lease_id, message_ids)?;
+        self.stats.dead_drop_messages = self.dead_drop.stats().total_messages;
+
+        let deleted = acked.len();
+        if !acked.is_empty() {
+            self.replicate(ReplicaChange::Delete {
+                access_token: *access_token,
+                message_ids: acked,
+            });
+        }
+
+        Ok(deleted)
+    }
+
+    /// Apply a dead drop change sent by a sibling relay
+    ///
+    /// A copied message must carry the stamp the sibling accepted it with.
+    /// Copies are deleted only for the access token they were retrieved
+    /// with, the same proof the sibling required.
+    ///
+    /// # Arguments
+    /// * `message` - `ReplicateDeadDrop` or `DeleteReplicas` frame
+    pub fn apply_replication(&mut self, message: WireMessage) -> Result<()> {
+        let (origin, change, signature) = ReplicaChange::from_wire(message)
+            .ok_or_else(|| RelayError::InvalidPacket("Not a dead drop change".to_string()))?;
+        self.verify_sibling(&origin, &change, &signature)?;
+
+        match change {
+            ReplicaChange::Store { drop_id, access_token, message, stamp } => {
+                self.dead_drop
+                    .store_replica(drop_id, access_token, message, stamp.as_ref())?;
+            }
+            ReplicaChange::Delete { access_token, message_ids } => {
+                let removed = self.dead_drop.remove_messages(&access_token, &message_ids)?;
+                tracing::debug!(removed, "Deleted dead drop replicas");
+            }
+        }
         self.stats.dead_drop_messages = self.dead_drop.stats().total_messages;
 
         Ok(())
     }
 
+    /// Check that a dead drop change was signed by a sibling relay
+    ///
+    /// The signing key must be the one the consensus lists for one of the
+    /// configured sibling addresses.
+    fn verify_sibling(
+        &self,
+        origin: &[u8],
+        change: &ReplicaChange,
+        signature: &[u8],
+    ) -> Result<()> {
+        let consensus = self
+            .consensus
+            .as_ref()
+            .filter(|consensus| consensus.document.is_valid_at(SystemTime::now()))
+            .ok_or_else(|| RelayError::KeyError("No consensus to check siblings".to_string()))?;
+
+        let listed = consensus.document.relays.iter().any(|relay| {
+            relay.descriptor.identity_key == origin
+                && relay
+                    .descriptor
+                    .address
+                    .parse()
+                    .is_ok_and(|addr| self.config.dead_drop_replicas.contains(&addr))
+        });
+        if !listed {
+            return Err(RelayError::KeyError(
+                "Dead drop change from a relay that isn't a sibling".to_string(),
+            ));
+        }
+
+        IdentityKey::from_public(origin.to_vec())
+            .verify(&change.signed_bytes(origin)?, signature)
+            .map_err(|e| RelayError::KeyError(e.to_string()))
+    }
+
+    /// Sign a dead drop change and queue it for every sibling relay
+    ///
+    /// Siblings reject unsigned changes, so without an identity key nothing
+    /// is queued.
+    fn replicate(&mut self, change: ReplicaChange) {
+        if self.config.dead_drop_replicas.is_empty() {
+            return;
+        }
+        let identity = match &self.identity {
+            Some(identity) => identity,
+            None => {
+                tracing::warn!("No identity key to sign dead drop replication with");
+                return;
+            }
+        };
+
+        let origin = identity.public_key().to_vec();
+        let signature = match change.signed_bytes(&origin).and_then(|bytes| {
+            identity.sign(&bytes).map_err(|e| RelayError::KeyError(e.to_string()))
+        }) {
+            Ok(signature) => signature,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to sign dead drop replication");
+                return;
+            }
+        };
+
+        let message = change.into_wire(origin, signature);
+        for sibling in &self.config.dead_drop_replicas {
+            self.replication_queue.push_back((*sibling, message.clone()));
+        }
+    }
+
+    /// Get next dead drop change to send to a sibling relay
+    pub fn next_replication(&mut self) -> Option<(SocketAddr, WireMessage)> {
+        self.replication_queue.pop_front()
+    }
+
     /// Get next output packet
     pub fn next_output(&mut self) -> Option<(SphinxPacket, SocketAddr)> {
         self.output_queue.pop_front()
     }
 
     /// Periodic maintenance
+    ///
+    /// Rotates epoch keys and drops replay tags of epochs whose keys are gone.
     pub async fn maintain(&mut self) -> Result<()> {
-        self.dead_drop.cleanup_expired();
-
-        if self.mix_state.should_forward() {
-            self.forward_batch().await?;
+        let now = SystemTime::now();
+        if self.keys.rotate(now)? {
+            self.mix_state.node.epoch_keys = self.keys.published_keys(now);
+            self.replay_cache.retain_from(self.config.epoch_schedule.oldest_accepted(now))?;
+            tracing::info!(epoch = self.config.epoch_schedule.epoch_at(now), "Rotated epoch keys");
         }
 
+        self.dead_drop.cleanup_expired()?;
+
+        self.release_ready();
+
         self.stats.dead_drop_messages = self.dead_drop.stats().total_messages;
-        self.stats.current_batch_size = self.output_queue.len();
 
         Ok(())
     }
 
+    /// Public keys for the current and upcoming epochs
+    pub fn published_keys(&self) -> Vec<EpochKey> {
+        self.keys.published_keys(SystemTime::now())
+    }
+
+    /// Signed descriptor for submission to the directory authorities
+    ///
+    /// # Arguments
+    /// * `identity` - The relay's long-term Ed25519 identity key
+    pub fn descriptor(&self, identity: &IdentityKey) -> Result<SignedDescriptor> {
+        let published = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let descriptor = RelayDescriptor {
+            identity_key: identity.public_key().to_vec(),
+            layer: self.config.layer,
+            address: self.config.listen_addr.to_string(),
+            location: self.config.location.clone(),
+            epoch_keys: self.published_keys(),
+            bandwidth: self.config.bandwidth,
+            operator: self.config.operator.clone(),
+            published,
+        };
+
+        Ok(descriptor.sign(identity)?)
+    }
+
+    /// Set the identity key dead drop changes are signed with
+    ///
+    /// Siblings accept the changes only if the consensus lists this relay
+    /// under the key.
+    pub fn set_identity(&mut self, identity: IdentityKey) {
+        self.identity = Some(identity);
+    }
+
+    /// Set the consensus this relay serves to clients
+    ///
+    /// Dead drop changes from siblings are checked against it too.
+    pub fn set_consensus(&mut self, consensus: SignedConsensus) {
+        self.consensus = Some(consensus);
+    }
+
+    /// Consensus served to clients
+    pub fn consensus(&self) -> Option<&SignedConsensus> {
+        self.consensus.as_ref()
+    }
+
     /// Get statistics
     pub fn stats(&self) -> NodeStats {
         self.stats.clone()
@@ -205,18 +646,28 @@ impl MixNode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use invisible_scrambler::directory::ConsensusDocument;
+    use invisible_scrambler::stamp::{store_context, BlindedTokens, WorkStamp};
+    use std::time::Duration;
 
     fn create_test_config(layer: u8) -> NodeConfig {
         let mut config = NodeConfig::default();
         config.layer = layer;
         config.node_id = [layer; 32];
+        config.dead_drop_config.stamps.work_bits = 8;
         config
     }
 
+    fn work_stamp(drop_id: &DropId, payload: &[u8]) -> StoreStamp {
+        let context = store_context(drop_id, payload);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        StoreStamp::Work(WorkStamp::mint(&context, 8, now))
+    }
+
     #[tokio::test]
     async fn test_node_creation() {
         let config = create_test_config(0);
-        let node = MixNode::new(config);
+        let node = MixNode::new(config).unwrap();
 
         assert_eq!(node.layer(), 0);
         assert_eq!(node.stats().packets_received, 0);
@@ -225,21 +676,314 @@ mod tests {
     #[tokio::test]
     async fn test_dead_drop_store() {
         let config = create_test_config(0);
-        let mut node = MixNode::new(config);
+        let mut node = MixNode::new(config).unwrap();
 
         let mut payload = b"DEADROP_STORE:".to_vec();
         payload.extend_from_slice(&[1u8; 32]); // drop_id
         payload.extend_from_slice(&[2u8; 32]); // access_token
+
+        // Unstamped
+        let mut unstamped = payload.clone();
+        unstamped.extend_from_slice(&0u16.to_be_bytes());
+        unstamped.extend_from_slice(b"message");
+        assert!(node.handle_dead_drop_store(unstamped).is_err());
+
+        let stamp = bincode::serialize(&work_stamp(&[1u8; 32], b"message")).unwrap();
+        payload.extend_from_slice(&(stamp.len() as u16).to_be_bytes());
+        payload.extend_from_slice(&stamp);
         payload.extend_from_slice(b"message");
 
         node.handle_dead_drop_store(payload).unwrap();
         assert_eq!(node.stats().dead_drop_messages, 1);
     }
 
+    #[tokio::test]
+    async fn test_dead_drop_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(0);
+        config.dead_drop_path = Some(dir.path().join("drops.log"));
+        config.dead_drop_replicas = vec!["10.1.2.3:9443".parse().unwrap()];
+
+        let identity = IdentityKey::generate().unwrap();
+        let mut node = MixNode::new(config.clone()).unwrap();
+        node.set_identity(identity.clone());
+        let stamp = work_stamp(&[1u8; 32], b"message");
+        let message_id = node
+            .store_dead_drop([1u8; 32], [2u8; 32], b"message".to_vec(), Some(stamp.clone()))
+            .unwrap();
+
+        // Store tokens issued before the restart
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let policy = config.dead_drop_config.stamps.clone();
+        let (request, blinded) = BlindedTokens::new([1u8; 32], 1, &policy, now);
+        let batch = node.issue_store_tokens(&[1u8; 32], &request).unwrap();
+        let token = blinded.finalize(&batch).unwrap().remove(0);
+
+        // Queued for the sibling
+        match node.next_replication() {
+            Some((sibling, WireMessage::ReplicateDeadDrop { message, .. })) => {
+                assert_eq!(sibling, config.dead_drop_replicas[0]);
+                assert_eq!(message.id, message_id);
+            }
+            _ => panic!("Expected a replication message"),
+        }
+        drop(node);
+
+        let mut node = MixNode::new(config.clone()).unwrap();
+        node.set_identity(identity);
+        assert_eq!(node.stats().dead_drop_messages, 1);
+
+        // The stamp stays spent across the restart
+        assert!(node
+            .store_dead_drop([1u8; 32], [2u8; 32], b"message".to_vec(), Some(stamp))
+            .is_err());
+        let stamp = token.redeem(b"after restart");
+        node.store_dead_drop([1u8; 32], [2u8; 32], b"after restart".to_vec(), Some(stamp))
+            .unwrap();
+        assert!(matches!(
+            node.next_replication(),
+            Some((_, WireMessage::ReplicateDeadDrop { .. }))
+        ));
+        let messages = node.retrieve_dead_drop(&[2u8; 32]).unwrap();
+        assert_eq!(messages[0].id, message_id);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            node.next_replication(),
+            Some((_, WireMessage::DeleteReplicas { .. }))
+        ));
+        drop(node);
+
+        // Without its key the log stays untouched instead of being replaced
+        let log_path = dir.path().join("drops.log");
+        let log = std::fs::read(&log_path).unwrap();
+        std::fs::remove_file(log_path.with_extension("key")).unwrap();
+        assert!(MixNode::new(config).is_err());
+        assert_eq!(std::fs::read(&log_path).unwrap(), log);
+        assert!(!log_path.with_extension("key").exists());
+    }
+
+    /// Consensus listing the given relays
+    fn consensus_of(relays: &[(&MixNode, &IdentityKey)]) -> SignedConsensus {
+        let descriptors = relays
+            .iter()
+            .map(|(node, identity)| node.descriptor(identity).unwrap())
+            .collect();
+        let hour = Duration::from_secs(3600);
+
+        SignedConsensus::new(ConsensusDocument::build(
+            descriptors,
+            EpochSchedule::default(),
+            SystemTime::now(),
+            hour,
+            hour,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_replication_only_from_siblings() {
+        let origin_addr: SocketAddr = "10.1.2.3:9443".parse().unwrap();
+        let sibling_addr: SocketAddr = "10.1.2.4:9443".parse().unwrap();
+        let stranger_addr: SocketAddr = "10.1.2.5:9443".parse().unwrap();
+        let relay = |listen_addr, replicas| {
+            let mut config = create_test_config(0);
+            config.listen_addr = listen_addr;
+            config.dead_drop_replicas = replicas;
+            let identity = IdentityKey::generate().unwrap();
+            let mut node = MixNode::new(config).unwrap();
+            node.set_identity(identity.clone());
+            (node, identity)
+        };
+
+        let (mut origin, origin_key) = relay(origin_addr, vec![sibling_addr]);
+        let (mut sibling, sibling_key) = relay(sibling_addr, vec![origin_addr]);
+        let (mut stranger, stranger_key) = relay(stranger_addr, vec![sibling_addr]);
+
+        let stamp = work_stamp(&[1u8; 32], b"message");
+        let message_id = origin
+            .store_dead_drop([1u8; 32], [2u8; 32], b"message".to_vec(), Some(stamp))
+            .unwrap();
+        let (_, replica) = origin.next_replication().unwrap();
+
+        // Nothing is accepted before the consensus names the siblings
+        assert!(sibling.apply_replication(replica.clone()).is_err());
+        sibling.set_consensus(consensus_of(&[
+            (&origin, &origin_key),
+            (&sibling, &sibling_key),
+            (&stranger, &stranger_key),
+        ]));
+
+        // A listed relay that isn't configured as a sibling is refused
+        let stamp = work_stamp(&[1u8; 32], b"spam");
+        stranger
+            .store_dead_drop([1u8; 32], [2u8; 32], b"spam".to_vec(), Some(stamp))
+            .unwrap();
+        let (_, spam) = stranger.next_replication().unwrap();
+        assert!(sibling.apply_replication(spam).is_err());
+
+        // So is a change the sibling didn't sign
+        let mut forged = replica.clone();
+        if let WireMessage::ReplicateDeadDrop { message, .. } = &mut forged {
+            message.payload = b"forged".to_vec();
+        }
+        assert!(sibling.apply_replication(forged).is_err());
+        assert_eq!(sibling.stats().dead_drop_messages, 0);
+
+        sibling.apply_replication(replica.clone()).unwrap();
+        assert_eq!(sibling.stats().dead_drop_messages, 1);
+
+        // Deleting copies takes the token they were stored under
+        let change = ReplicaChange::Delete {
+            access_token: [3u8; 32],
+            message_ids: vec![message_id],
+        };
+        let origin_id = origin_key.public_key().to_vec();
+        let signature = origin_key.sign(&change.signed_bytes(&origin_id).unwrap()).unwrap();
+        sibling.apply_replication(change.into_wire(origin_id, signature)).unwrap();
+        assert_eq!(sibling.stats().dead_drop_messages, 1);
+
+        // The stamp is spent, so the copy can't be replayed after delivery
+        origin.retrieve_dead_drop(&[2u8; 32]).unwrap();
+        let (_, delete) = origin.next_replication().unwrap();
+
+        sibling.apply_replication(delete).unwrap();
+        assert_eq!(sibling.stats().dead_drop_messages, 0);
+        assert!(sibling.apply_replication(replica).is_err());
+        assert!(sibling.retrieve_dead_drop(&[2u8; 32]).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_encoded_next_hop() {
+        use invisible_scrambler::sphinx::{build_packet, RouteSpec};
+        use std::time::Duration;
+
+        let mut config = create_test_config(0);
+        config.mix_strategy = MixStrategy::Threshold {
+            batch_size: 1,
+            min_delay: Duration::ZERO,
+            max_delay: Duration::from_secs(30),
+        };
+        let mut node = MixNode::new(config).unwrap();
+        let key = node.published_keys().remove(0);
+
+        let next_hop: SocketAddr = "10.1.2.3:9443".parse().unwrap();
+        let route = RouteSpec {
+            node_keys: vec![key.public_key, vec![9u8; 32]],
+            node_addresses: vec!["127.0.0.1:8080".parse().unwrap(), next_hop],
+            delays: vec![Duration::from_millis(10), Duration::from_millis(10)],
+            epoch: key.epoch,
+            destination: vec![0u8; 32],
+        };
+        let packet = build_packet(&route, b"message").unwrap();
+
+        node.process_packet(packet).await.unwrap();
+
+        let (_, addr) = node.next_output().unwrap();
+        assert_eq!(addr, next_hop);
+        assert_eq!(node.stats().packets_forwarded, 1);
+    }
+
+    #[tokio::test]
+    async fn test_poisson_mixing_honors_header_delay() {
+        use invisible_scrambler::sphinx::{build_packet, RouteSpec};
+        use std::time::Duration;
+
+        let mut config = create_test_config(0);
+        config.mix_strategy = MixStrategy::Poisson { max_delay: Duration::from_secs(60) };
+        let mut node = MixNode::new(config).unwrap();
+        let key = node.published_keys().remove(0);
+
+        let route = RouteSpec {
+            node_keys: vec![key.public_key, vec![9u8; 32]],
+            node_addresses: vec!["127.0.0.1:8080".parse().unwrap(), "10.1.2.3:9443".parse().unwrap()],
+            delays: vec![Duration::from_millis(50), Duration::from_millis(10)],
+            epoch: key.epoch,
+            destination: vec![0u8; 32],
+        };
+        node.process_packet(build_packet(&route, b"message").unwrap()).await.unwrap();
+
+        // Held for this hop's delay, then released
+        assert!(node.next_output().is_none());
+        let release = node.next_release().unwrap();
+        tokio::time::sleep(release.saturating_duration_since(Instant::now())).await;
+        node.release_ready();
+        assert!(node.next_output().is_some());
+        assert_eq!(node.stats().packets_forwarded, 1);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_packet_rejected() {
+        use invisible_scrambler::sphinx::{build_packet, RouteSpec};
+        use std::time::Duration;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(0);
+        config.key_store_path = Some(dir.path().join("keys.bin"));
+        config.replay_cache_path = Some(dir.path().join("replay.log"));
+
+        let mut node = MixNode::new(config.clone()).unwrap();
+        let key = node.published_keys().remove(0);
+
+        let route = RouteSpec {
+            node_keys: vec![key.public_key],
+            node_addresses: vec!["127.0.0.1:8080".parse().unwrap()],
+            delays: vec![Duration::from_millis(0)],
+            epoch: key.epoch,
+            destination: vec![0u8; 32],
+        };
+        let packet = build_packet(&route, b"message").unwrap();
+
+        node.process_packet(packet.clone()).await.unwrap();
+        assert!(node.process_packet(packet.clone()).await.is_err());
+
+        let stats = node.stats();
+        assert_eq!(stats.packets_delivered, 1);
+        assert_eq!(stats.packets_replayed, 1);
+        drop(node);
+
+        // Keys and the cache survive a restart
+        let mut node = MixNode::new(config).unwrap();
+        assert!(node.process_packet(packet).await.is_err());
+        assert_eq!(node.stats().packets_replayed, 1);
+        assert_eq!(node.stats().packets_delivered, 0);
+    }
+
+    #[tokio::test]
+    async fn test_packet_for_unaccepted_epoch_rejected() {
+        use invisible_scrambler::sphinx::{build_packet, RouteSpec};
+        use std::time::Duration;
+
+        let mut node = MixNode::new(create_test_config(0)).unwrap();
+
+        // Keys for the next epoch are published but not yet in effect
+        let key = node.published_keys().remove(1);
+        let route = RouteSpec {
+            node_keys: vec![key.public_key],
+            node_addresses: vec!["127.0.0.1:8080".parse().unwrap()],
+            delays: vec![Duration::from_millis(0)],
+            epoch: key.epoch,
+            destination: vec![0u8; 32],
+        };
+        let packet = build_packet(&route, b"message").unwrap();
+
+        assert!(node.process_packet(packet).await.is_err());
+        assert_eq!(node.stats().packets_delivered, 0);
+    }
+
+    #[tokio::test]
+    async fn test_descriptor_signed() {
+        let node = MixNode::new(create_test_config(3)).unwrap();
+        let identity = IdentityKey::generate().unwrap();
+
+        let descriptor = node.descriptor(&identity).unwrap();
+        descriptor.verify().unwrap();
+        assert_eq!(descriptor.descriptor.layer, 3);
+        assert_eq!(descriptor.descriptor.epoch_keys, node.published_keys());
+    }
+
     #[tokio::test]
     async fn test_stats() {
         let config = create_test_config(0);
-        let node = MixNode::new(config);
+        let node = MixNode::new(config).unwrap();
 
         let stats = node.stats();
         assert_eq!(stats.packets_received, 0);
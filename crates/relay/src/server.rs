@@ -1,97 +1,760 @@
 //! Relay Server
 //!
 //! Network server for processing Sphinx packets.
+//!
+//! The relay speaks the same wire protocol as the client's `PacketTransmitter`
+//! and `DeadDropProtocol`: each TCP connection carries length-prefixed,
+//! bincode-encoded `WireMessage` frames.
+//!
+//! Accepted connections are unwrapped by the server's `PluggableTransport`
+//! (plain TCP by default) before any frames are read. Packets forwarded to the
+//! next hop use plain relay-to-relay connections. `Probe` frames are echoed
+//! back so clients can measure which transports get through their network.
+//!
+//! Dead drop stores and retrievals are copied to the node's sibling relays
+//! as signed `ReplicateDeadDrop` and `DeleteReplicas` frames. Replication is
+//! best effort: a sibling that is down misses the change, and one that can't
+//! verify the signature drops it.
+//!
+//! Packets leave when the node's mixing strategy releases them; the main loop
+//! sleeps until the mixer's next deadline and is woken when a packet arrives.
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::UdpSocket;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, Notify};
 use tokio::time;
 
-use crate::error::Result;
+use invisible_scrambler::network::{
+    read_message, write_message, MixNodeAddr, NetworkConfig, PacketTransmitter, WireMessage,
+};
+use invisible_scrambler::transport::{BoxedStream, PlainTransport, PluggableTransport};
+
+use crate::error::{RelayError, Result};
 use crate::node::{MixNode, NodeStats};
 
 /// Relay server
 #[derive(Debug)]
 pub struct RelayServer {
-    node: MixNode,
-    socket: Option<UdpSocket>,
+    node: Arc<Mutex<MixNode>>,
+    listener: Option<TcpListener>,
+    transmitter: Arc<PacketTransmitter>,
+    /// Transport that unwraps accepted connections
+    transport: Arc<dyn PluggableTransport>,
+    /// Signals that the mixer may have an earlier deadline
+    mixer_wakeup: Arc<Notify>,
 }
 
 impl RelayServer {
     /// Create new relay server
     pub fn new(node: MixNode) -> Self {
+        Self::with_transport(node, Arc::new(PlainTransport))
+    }
+
+    /// Create relay server that accepts camouflaged connections
+    ///
+    /// # Arguments
+    /// * `node` - Mix node to serve
+    /// * `transport` - Transport applied to every accepted connection
+    pub fn with_transport(node: MixNode, transport: Arc<dyn PluggableTransport>) -> Self {
         Self {
-            node,
-            socket: None,
+            node: Arc::new(Mutex::new(node)),
+            listener: None,
+            transmitter: Arc::new(PacketTransmitter::new(NetworkConfig::default())),
+            transport,
+            mixer_wakeup: Arc::new(Notify::new()),
         }
     }
 
     /// Start server
     pub async fn start(&mut self, bind_addr: SocketAddr) -> Result<()> {
-        let socket = UdpSocket::bind(bind_addr).await?;
+        let listener = TcpListener::bind(bind_addr).await?;
         tracing::info!(%bind_addr, "Relay server listening");
 
-        self.socket = Some(socket);
+        self.listener = Some(listener);
         Ok(())
     }
 
+    /// Address the server is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let listener = self.listener.as_ref()
+            .ok_or_else(|| RelayError::NetworkError("Server not started".to_string()))?;
+
+        Ok(listener.local_addr()?)
+    }
+
     /// Run server main loop
-    pub async fn run(&mut self) -> Result<()> {
-        let socket = self.socket.as_ref()
-            .ok_or_else(|| crate::error::RelayError::NetworkError(
+    pub async fn run(&self) -> Result<()> {
+        let listener = self.listener.as_ref()
+            .ok_or_else(|| RelayError::NetworkError(
                 "Server not started".to_string()
             ))?;
 
-        let mut buf = vec![0u8; 65536];
         let mut maintenance_interval = time::interval(Duration::from_secs(60));
 
         loop {
+            let next_release = self.node.lock().await.next_release()
+                .map(time::Instant::from_std)
+                .unwrap_or_else(|| time::Instant::now() + Duration::from_secs(60));
+
             tokio::select! {
-                // Receive packets
-                result = socket.recv_from(&mut buf) => {
-                    let (len, _peer) = result?;
-                    tracing::debug!(size = len, "Received packet");
+                // Accept client and relay connections
+                result = listener.accept() => {
+                    let (stream, peer) = match result {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::warn!(error = %e, "Failed to accept connection");
+                            continue;
+                        }
+                    };
+
+                    let node = Arc::clone(&self.node);
+                    let transmitter = Arc::clone(&self.transmitter);
+                    let transport = Arc::clone(&self.transport);
+                    let mixer_wakeup = Arc::clone(&self.mixer_wakeup);
+
+                    tokio::spawn(async move {
+                        // The handshake runs here so slow clients don't block the accept loop
+                        let stream = match transport.accept(Box::new(stream)).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                tracing::debug!(%peer, error = %e, "Transport handshake failed");
+                                return;
+                            }
+                        };
+
+                        if let Err(e) = handle_connection(stream, node, transmitter, mixer_wakeup).await {
+                            tracing::debug!(%peer, error = %e, "Connection closed with error");
+                        }
+                    });
+                }
 
-                    // TODO: Deserialize and process Sphinx packet
+                // Release packets the mixer holds
+                _ = time::sleep_until(next_release) => {
+                    let mut node = self.node.lock().await;
+                    node.release_ready();
+                    dispatch_output(&mut node, &self.transmitter);
                 }
 
+                // A new packet may be due before the current deadline
+                _ = self.mixer_wakeup.notified() => {}
+
                 // Periodic maintenance
                 _ = maintenance_interval.tick() => {
-                    self.node.maintain().await?;
+                    let mut node = self.node.lock().await;
+                    node.maintain().await?;
+                    dispatch_output(&mut node, &self.transmitter);
                 }
             }
         }
     }
 
     /// Get statistics
-    pub fn stats(&self) -> NodeStats {
-        self.node.stats()
+    pub async fn stats(&self) -> NodeStats {
+        self.node.lock().await.stats()
     }
 }
 
+/// Serve wire messages on a single connection until the peer closes it
+async fn handle_connection(
+    mut stream: BoxedStream,
+    node: Arc<Mutex<MixNode>>,
+    transmitter: Arc<PacketTransmitter>,
+    mixer_wakeup: Arc<Notify>,
+) -> Result<()> {
+    while let Some(message) = read_message(&mut stream).await? {
+        let response = match message {
+            WireMessage::ForwardPacket { packet } => {
+                tracing::debug!("Received packet");
+
+                let mut node = node.lock().await;
+                if let Err(e) = node.process_packet(packet).await {
+                    tracing::debug!(error = %e, "Dropping packet");
+                }
+                dispatch_output(&mut node, &transmitter);
+                mixer_wakeup.notify_one();
+
+                None
+            }
+            WireMessage::StoreDeadDrop { drop_id, access_token, payload, stamp } => {
+                let mut node = node.lock().await;
+                let result = node.store_dead_drop(drop_id, access_token, payload, stamp);
+                dispatch_output(&mut node, &transmitter);
+
+                Some(match result {
+                    Ok(message_id) => WireMessage::StoreSuccess { message_id },
+                    Err(e) => WireMessage::Error { message: e.to_string() },
+                })
+            }
+            WireMessage::IssueStoreTokens { drop_id, request } => {
+                let result = node.lock().await.issue_store_tokens(&drop_id, &request);
+
+                Some(match result {
+                    Ok(batch) => WireMessage::StoreTokens { batch },
+                    Err(e) => WireMessage::Error { message: e.to_string() },
+                })
+            }
+            WireMessage::RetrieveDeadDrop { access_token } => {
+                let mut node = node.lock().await;
+                let result = node.retrieve_dead_drop(&access_token);
+                dispatch_output(&mut node, &transmitter);
+
+                Some(match result {
+                    Ok(messages) => WireMessage::RetrieveSuccess { messages },
+                    Err(e) => WireMessage::Error { message: e.to_string() },
+                })
+            }
+            WireMessage::FetchDeadDrop { access_token, limit } => {
+                let result = node.lock().await.fetch_dead_drop(&access_token, limit as usize);
+
+                Some(match result {
+                    Ok(page) => WireMessage::FetchSuccess {
+                        lease_id: page.lease_id,
+                        messages: page.messages,
+                        remaining: page.remaining as u32,
+                    },
+                    Err(e) => WireMessage::Error { message: e.to_string() },
+                })
+            }
+            WireMessage::AckDeadDrop { access_token, lease_id, message_ids } => {
+                let mut node = node.lock().await;
+                let result = node.ack_dead_drop(&access_token, &lease_id, &message_ids);
+                dispatch_output(&mut node, &transmitter);
+
+                Some(match result {
+                    Ok(deleted) => WireMessage::AckSuccess { deleted: deleted as u32 },
+                    Err(e) => WireMessage::Error { message: e.to_string() },
+                })
+            }
+            message @ (WireMessage::ReplicateDeadDrop { .. }
+            | WireMessage::DeleteReplicas { .. }) => {
+                if let Err(e) = node.lock().await.apply_replication(message) {
+                    tracing::warn!(error = %e, "Rejected dead drop replication");
+                }
+
+                None
+            }
+            WireMessage::FetchConsensus => {
+                let consensus = node.lock().await.consensus().cloned();
+
+                Some(match consensus {
+                    Some(consensus) => WireMessage::Consensus { consensus },
+                    None => WireMessage::Error {
+                        message: "No consensus available".to_string(),
+                    },
+                })
+            }
+            WireMessage::Probe { payload } => Some(WireMessage::ProbeEcho { payload }),
+            _ => Some(WireMessage::Error {
+                message: "Unexpected message".to_string(),
+            }),
+        };
+
+        if let Some(response) = response {
+            write_message(&mut stream, &response).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Drain the node's output queues
+///
+/// Each packet goes to its next hop and each dead drop change to its sibling.
+fn dispatch_output(node: &mut MixNode, transmitter: &Arc<PacketTransmitter>) {
+    while let Some((sibling, message)) = node.next_replication() {
+        tokio::spawn(async move {
+            if let Err(e) = send_replication(sibling, &message).await {
+                tracing::warn!(%sibling, error = %e, "Failed to replicate dead drop");
+            }
+        });
+    }
+
+    while let Some((packet, next_hop)) = node.next_output() {
+        let transmitter = Arc::clone(transmitter);
+
+        tokio::spawn(async move {
+            let addr = MixNodeAddr {
+                address: next_hop.to_string(),
+                public_key: Vec::new(),
+            };
+
+            if let Err(e) = transmitter.send_packet(&packet, &addr).await {
+                tracing::warn!(%next_hop, error = %e, "Failed to forward packet");
+            }
+        });
+    }
+}
+
+/// Send a dead drop change to a sibling relay
+async fn send_replication(sibling: SocketAddr, message: &WireMessage) -> Result<()> {
+    let config = NetworkConfig::default();
+    let mut stream = time::timeout(
+        Duration::from_millis(config.connect_timeout_ms),
+        TcpStream::connect(sibling),
+    )
+    .await
+    .map_err(|_| RelayError::NetworkError("Connection timeout".to_string()))??;
+
+    write_message(&mut stream, message).await?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::node::NodeConfig;
+    use invisible_crypto::keys::IdentityKey;
+    use invisible_scrambler::dead_drop::{DeadDropConfig, StoredMessage};
+    use invisible_scrambler::directory::{ConsensusDocument, SignedConsensus};
+    use invisible_scrambler::epoch::{EpochKey, EpochSchedule};
+    use invisible_scrambler::mixnet::MixStrategy;
+    use invisible_scrambler::camouflage::CamouflageLayer;
+    use invisible_scrambler::network::DeadDropProtocol;
+    use invisible_scrambler::obfs4::{IatMode, Obfs4Bridge};
+    use invisible_scrambler::probing::{ProbeConfig, ProbeOutcome, TransportProber};
+    use invisible_scrambler::utls::UTlsServer;
+    use invisible_scrambler::sphinx::{build_packet, RouteSpec};
+    use invisible_scrambler::stamp::{store_context, StampPolicy, StoreStamp, WorkStamp};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Spawn a relay, returning its address and current epoch key
+    async fn spawn_server(config: NodeConfig) -> (SocketAddr, EpochKey) {
+        let node = MixNode::new(config).unwrap();
+        let key = node.published_keys().remove(0);
+
+        let mut server = RelayServer::new(node);
+        server
+            .start(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(async move { server.run().await });
+
+        (addr, key)
+    }
+
+    /// Dead drop configuration with cheap work stamps
+    fn drop_config() -> DeadDropConfig {
+        DeadDropConfig {
+            stamps: StampPolicy { work_bits: 8, ..StampPolicy::default() },
+            ..DeadDropConfig::default()
+        }
+    }
+
+    fn node_config() -> NodeConfig {
+        NodeConfig {
+            dead_drop_config: drop_config(),
+            ..NodeConfig::default()
+        }
+    }
+
+    fn relay_config() -> NodeConfig {
+        NodeConfig {
+            mix_strategy: MixStrategy::Poisson { max_delay: Duration::from_secs(1) },
+            ..node_config()
+        }
+    }
+
+    fn dead_drop_store_payload(access_token: &[u8; 32], message: &[u8]) -> Vec<u8> {
+        let context = store_context(&[1u8; 32], message);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let bits = drop_config().stamps.work_bits;
+        let stamp = StoreStamp::Work(WorkStamp::mint(&context, bits, now));
+        let stamp = bincode::serialize(&stamp).unwrap();
+
+        let mut payload = b"DEADROP_STORE:".to_vec();
+        payload.extend_from_slice(&[1u8; 32]);
+        payload.extend_from_slice(access_token);
+        payload.extend_from_slice(&(stamp.len() as u16).to_be_bytes());
+        payload.extend_from_slice(&stamp);
+        payload.extend_from_slice(message);
+        payload
+    }
+
+    /// Spawn a relay behind `transport`, returning its address
+    async fn spawn_server_with_transport(transport: Arc<dyn PluggableTransport>) -> SocketAddr {
+        let node = MixNode::new(node_config()).unwrap();
+
+        let mut server = RelayServer::with_transport(node, transport);
+        server
+            .start(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        tokio::spawn(async move { server.run().await });
+
+        addr
+    }
+
+    /// Packets are processed asynchronously; poll until the message lands
+    async fn poll_dead_drop(node: &MixNodeAddr, access_token: &[u8; 32]) -> Vec<StoredMessage> {
+        let protocol = DeadDropProtocol::new(NetworkConfig::default(), drop_config());
+
+        for _ in 0..50 {
+            let messages = protocol.retrieve(node, access_token).await.unwrap();
+            if !messages.is_empty() {
+                return messages;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+
+        Vec::new()
+    }
+
+    /// Wait until a relay holds `count` dead drop messages
+    async fn wait_for_dead_drop_messages(server: &RelayServer, count: usize) {
+        for _ in 0..100 {
+            if server.stats().await.dead_drop_messages == count {
+                return;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Relay never held {} dead drop messages", count);
+    }
+
+    #[tokio::test]
+    async fn test_dead_drop_replicated_to_sibling() {
+        // Each relay lists the other as a sibling, so both addresses are
+        // picked before either node is created
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+        }
+        let (origin_addr, sibling_addr) = (addrs[0], addrs[1]);
+
+        let mut relays = Vec::new();
+        for (listen_addr, replica) in [(origin_addr, sibling_addr), (sibling_addr, origin_addr)] {
+            let identity = IdentityKey::generate().unwrap();
+            let mut node = MixNode::new(NodeConfig {
+                listen_addr,
+                dead_drop_replicas: vec![replica],
+                ..node_config()
+            })
+            .unwrap();
+            node.set_identity(identity.clone());
+            relays.push((node, identity));
+        }
+        let descriptors = relays
+            .iter()
+            .map(|(node, identity)| node.descriptor(identity).unwrap())
+            .collect();
+        let hour = Duration::from_secs(3600);
+        let consensus = SignedConsensus::new(ConsensusDocument::build(
+            descriptors,
+            EpochSchedule::default(),
+            SystemTime::now(),
+            hour,
+            hour,
+        ));
+
+        let mut servers = Vec::new();
+        for ((mut node, _), addr) in relays.into_iter().zip([origin_addr, sibling_addr]) {
+            node.set_consensus(consensus.clone());
+            let mut server = RelayServer::new(node);
+            server.start(addr).await.unwrap();
+            let server = Arc::new(server);
+            tokio::spawn({
+                let server = Arc::clone(&server);
+                async move { server.run().await }
+            });
+            servers.push(server);
+        }
+        let sibling = &servers[1];
+
+        let protocol = DeadDropProtocol::new(NetworkConfig::default(), drop_config());
+        let origin = MixNodeAddr { address: origin_addr.to_string(), public_key: Vec::new() };
+        let replica = MixNodeAddr { address: sibling_addr.to_string(), public_key: Vec::new() };
+        let access_token = [9u8; 32];
+
+        // Delivering at the origin deletes the copy
+        protocol.store(&origin, [1u8; 32], access_token, b"first".to_vec()).await.unwrap();
+        wait_for_dead_drop_messages(sibling, 1).await;
+        assert_eq!(protocol.retrieve(&origin, &access_token).await.unwrap().len(), 1);
+        wait_for_dead_drop_messages(sibling, 0).await;
+
+        // With the origin unreachable, the copy is fetched from the sibling
+        let message_id = protocol
+            .store(&origin, [1u8; 32], access_token, b"second".to_vec())
+            .await
+            .unwrap();
+        wait_for_dead_drop_messages(sibling, 1).await;
+
+        let offline = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let offline = MixNodeAddr { address: offline.to_string(), public_key: Vec::new() };
+        let messages = protocol
+            .retrieve_from_any(&[offline, replica], &access_token)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, message_id);
+        assert_eq!(messages[0].payload, b"second");
+    }
 
     #[tokio::test]
     async fn test_server_creation() {
-        let config = NodeConfig::default();
-        let node = MixNode::new(config);
+        let config = node_config();
+        let node = MixNode::new(config).unwrap();
         let server = RelayServer::new(node);
 
-        assert_eq!(server.stats().packets_received, 0);
+        assert_eq!(server.stats().await.packets_received, 0);
     }
 
     #[tokio::test]
     async fn test_server_start() {
-        use std::net::{IpAddr, Ipv4Addr};
-
-        let config = NodeConfig::default();
-        let node = MixNode::new(config);
+        let config = node_config();
+        let node = MixNode::new(config).unwrap();
         let mut server = RelayServer::new(node);
 
         let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
         server.start(bind_addr).await.unwrap();
+        assert_ne!(server.local_addr().unwrap().port(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dead_drop_over_wire() {
+        let (addr, _) = spawn_server(node_config()).await;
+        let node = MixNodeAddr {
+            address: addr.to_string(),
+            public_key: vec![0u8; 32],
+        };
+
+        let protocol = DeadDropProtocol::new(NetworkConfig::default(), drop_config());
+        let access_token = [7u8; 32];
+
+        let message_id = protocol
+            .store(&node, [9u8; 32], access_token, b"share".to_vec())
+            .await
+            .unwrap();
+
+        let messages = protocol.retrieve(&node, &access_token).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, message_id);
+        assert_eq!(messages[0].payload, b"share");
+    }
+
+    #[tokio::test]
+    async fn test_dead_drop_fetch_and_ack_over_wire() {
+        let (addr, _) = spawn_server(node_config()).await;
+        let node = MixNodeAddr {
+            address: addr.to_string(),
+            public_key: vec![0u8; 32],
+        };
+
+        let protocol = DeadDropProtocol::new(NetworkConfig::default(), drop_config());
+        let access_token = [7u8; 32];
+        for i in 0..5u8 {
+            protocol.store(&node, [9u8; 32], access_token, vec![i]).await.unwrap();
+        }
+
+        // Page through the drop, acknowledging each page
+        let mut received = Vec::new();
+        loop {
+            let page = protocol.fetch(&node, &access_token, 2).await.unwrap();
+            let ids = page.messages.iter().map(|m| m.id).collect::<Vec<_>>();
+            received.extend(page.messages.iter().map(|m| m.payload[0]));
+            let deleted = protocol.ack(&node, &access_token, &page.lease_id, ids).await.unwrap();
+            assert_eq!(deleted, page.messages.len());
+            if page.remaining == 0 {
+                break;
+            }
+        }
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+
+        let page = protocol.fetch(&node, &access_token, 2).await.unwrap();
+        assert!(page.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dead_drop_store_tokens_over_wire() {
+        let (addr, _) = spawn_server(node_config()).await;
+        let node = MixNodeAddr {
+            address: addr.to_string(),
+            public_key: vec![0u8; 32],
+        };
+
+        // The recipient obtains tokens for a contact
+        let recipient = DeadDropProtocol::new(NetworkConfig::default(), drop_config());
+        let tokens = recipient.issue_tokens(&node, [9u8; 32], 2).await.unwrap();
+
+        // The contact stores without doing any work, once per token
+        let contact = DeadDropProtocol::new(
+            NetworkConfig::default(),
+            DeadDropConfig {
+                stamps: StampPolicy { work_bits: 0, ..StampPolicy::default() },
+                ..drop_config()
+            },
+        );
+        let access_token = [7u8; 32];
+        contact
+            .store_with_token(&node, access_token, b"hello".to_vec(), &tokens[0])
+            .await
+            .unwrap();
+        assert!(contact
+            .store_with_token(&node, access_token, b"again".to_vec(), &tokens[0])
+            .await
+            .is_err());
+
+        // Unstamped stores are rejected
+        assert!(contact
+            .store(&node, [9u8; 32], access_token, b"spam".to_vec())
+            .await
+            .is_err());
+
+        let messages = recipient.retrieve(&node, &access_token).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_dead_drop_over_obfs4() {
+        let bridge = Arc::new(Obfs4Bridge::generate(IatMode::None));
+        let addr = spawn_server_with_transport(Arc::new(CamouflageLayer::obfs4_bridge(bridge.clone()))).await;
+        let node = MixNodeAddr {
+            address: addr.to_string(),
+            public_key: vec![0u8; 32],
+        };
+
+        let protocol = DeadDropProtocol::with_transport(
+            NetworkConfig::default(),
+            drop_config(),
+            Arc::new(CamouflageLayer::obfs4(bridge.client_config())),
+        );
+        let access_token = [7u8; 32];
+
+        let message_id = protocol
+            .store(&node, [9u8; 32], access_token, b"share".to_vec())
+            .await
+            .unwrap();
+
+        let messages = protocol.retrieve(&node, &access_token).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, message_id);
+
+        // Plain clients can't talk to an obfs4 relay
+        let plain = DeadDropProtocol::new(
+            NetworkConfig {
+                read_timeout_ms: 200,
+                ..NetworkConfig::default()
+            },
+            drop_config(),
+        );
+        assert!(plain.retrieve(&node, &access_token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dead_drop_over_utls() {
+        let tls = Arc::new(UTlsServer::generate("cdn.example.com").unwrap());
+        let addr = spawn_server_with_transport(Arc::new(CamouflageLayer::utls_server(tls.clone()))).await;
+        let node = MixNodeAddr {
+            address: addr.to_string(),
+            public_key: vec![0u8; 32],
+        };
+
+        let protocol = DeadDropProtocol::with_transport(
+            NetworkConfig::default(),
+            drop_config(),
+            Arc::new(CamouflageLayer::utls_firefox("cdn.example.com", tls.public_key().to_vec())),
+        );
+        let access_token = [7u8; 32];
+
+        let message_id = protocol
+            .store(&node, [9u8; 32], access_token, b"share".to_vec())
+            .await
+            .unwrap();
+
+        let messages = protocol.retrieve(&node, &access_token).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, message_id);
+    }
+
+    #[tokio::test]
+    async fn test_probe_echoed() {
+        let bridge = Arc::new(Obfs4Bridge::generate(IatMode::None));
+        let addr = spawn_server_with_transport(Arc::new(CamouflageLayer::obfs4_bridge(bridge.clone()))).await;
+        let node = MixNodeAddr {
+            address: addr.to_string(),
+            public_key: vec![0u8; 32],
+        };
+
+        let prober = TransportProber::new(ProbeConfig::default());
+        let result = prober
+            .probe(&node, &CamouflageLayer::obfs4(bridge.client_config()))
+            .await
+            .unwrap();
+        assert_eq!(result.outcome, ProbeOutcome::Reachable);
+    }
+
+    #[tokio::test]
+    async fn test_sphinx_packet_delivered_over_wire() {
+        let (addr, key) = spawn_server(relay_config()).await;
+        let node = MixNodeAddr {
+            address: addr.to_string(),
+            public_key: key.public_key.clone(),
+        };
+
+        // Single-hop packet carrying a dead drop store instruction
+        let access_token = [2u8; 32];
+        let payload = dead_drop_store_payload(&access_token, b"hello");
+
+        let route = RouteSpec {
+            node_keys: vec![key.public_key],
+            node_addresses: vec![addr],
+            delays: vec![Duration::from_millis(0)],
+            epoch: key.epoch,
+            destination: vec![0u8; 32],
+        };
+        let packet = build_packet(&route, &payload).unwrap();
+
+        PacketTransmitter::new(NetworkConfig::default())
+            .send_packet(&packet, &node)
+            .await
+            .unwrap();
+
+        let messages = poll_dead_drop(&node, &access_token).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_packet_forwarded_between_relays() {
+        let (entry_addr, entry_key) = spawn_server(relay_config()).await;
+        let (exit_addr, exit_key) = spawn_server(relay_config()).await;
+
+        let access_token = [4u8; 32];
+        let payload = dead_drop_store_payload(&access_token, b"two hops");
+
+        let route = RouteSpec {
+            node_keys: vec![entry_key.public_key.clone(), exit_key.public_key.clone()],
+            node_addresses: vec![entry_addr, exit_addr],
+            delays: vec![Duration::from_millis(100), Duration::from_millis(0)],
+            epoch: entry_key.epoch,
+            destination: vec![0u8; 32],
+        };
+        let packet = build_packet(&route, &payload).unwrap();
+
+        let entry = MixNodeAddr {
+            address: entry_addr.to_string(),
+            public_key: entry_key.public_key,
+        };
+        PacketTransmitter::new(NetworkConfig::default())
+            .send_packet(&packet, &entry)
+            .await
+            .unwrap();
+
+        // Only the exit relay should hold the delivered message
+        let exit = MixNodeAddr {
+            address: exit_addr.to_string(),
+            public_key: exit_key.public_key,
+        };
+        let messages = poll_dead_drop(&exit, &access_token).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, b"two hops");
     }
 }
@@ -5,22 +5,45 @@
 //!
 //! ## Camouflage Strategies
 //!
-//! - **obfs4:** Randomized handshake + stream cipher, looks like random noise
-//! - **uTLS:** Mimics browser TLS fingerprints (Chrome, Firefox, Safari, Edge)
-//! - **Domain Fronting:** HTTPS to CDN frontdoor, route to actual destination
+//! - **obfs4:** Authenticated handshake + framed stream cipher, looks like
+//!   random noise (connection-oriented, see `crate::obfs4`)
+//! - **uTLS:** Real TLS 1.3 with browser ClientHellos (Chrome, Firefox, Safari;
+//!   see `crate::utls`)
+//! - **Domain Fronting:** TLS to a CDN front (SNI = front domain, through the
+//!   uTLS client); the HTTP requests inside name the actual destination in
+//!   their Host header, and the server answers each with an HTTP response
 //!
 //! ## Security Properties
 //!
 //! - **DPI Resistance:** Traffic indistinguishable from target protocol
 //! - **Active Probing Resistance:** Invalid handshakes fail gracefully
 //! - **Fingerprint Diversity:** Multiple TLS fingerprints prevent clustering
+//!
+//! `CamouflageLayer` implements `PluggableTransport`, so every strategy can
+//! also wrap a live connection: obfs4 and uTLS run their handshakes and frame
+//! the stream, domain fronting sends it as a sequence of POST requests over
+//! uTLS. Like meek, the client keeps one request in flight and polls with
+//! empty requests (backing off while idle), since the server can only send
+//! data in responses.
+
+use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
 
 use crate::error::{Result, ScramblerError};
+use crate::obfs4::{self, Obfs4Bridge, Obfs4Reader, Obfs4Writer};
+use crate::utls::{self, UTlsReader, UTlsServer, UTlsWriter};
+use crate::transport::{
+    spawn_pump, BoxedStream, MessageFuture, MessageSink, MessageSource, PluggableTransport, TransportFuture,
+    PIPE_BUFFER_SIZE,
+};
 
 /// Camouflage transport type
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum TransportType {
     /// obfs4: Pluggable transport, looks like random noise
     Obfs4,
@@ -35,6 +58,9 @@ pub enum TransportType {
 }
 
 /// uTLS fingerprint parameters
+///
+/// Lists hold IANA names in the order the browser sends them; `"GREASE"`
+/// marks where a random GREASE value goes (RFC 8701).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UTlsFingerprint {
     /// Browser to mimic
@@ -45,8 +71,24 @@ pub struct UTlsFingerprint {
     pub cipher_suites: Vec<String>,
     /// Extensions
     pub extensions: Vec<String>,
+    /// Named groups in supported_groups
+    pub supported_groups: Vec<String>,
+    /// Groups the key_share extension carries shares for
+    pub key_share_groups: Vec<String>,
+    /// Signature algorithms
+    pub signature_algorithms: Vec<String>,
+    /// Versions in supported_versions
+    pub supported_versions: Vec<String>,
+    /// Certificate compression algorithms
+    pub cert_compression: Vec<String>,
     /// ALPN protocols
     pub alpn: Vec<String>,
+    /// Shuffle extension order per connection, as Chrome does
+    pub permute_extensions: bool,
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
 }
 
 impl UTlsFingerprint {
@@ -55,19 +97,60 @@ impl UTlsFingerprint {
         Self {
             browser: "Chrome".to_string(),
             tls_version: "1.3".to_string(),
-            cipher_suites: vec![
-                "TLS_AES_128_GCM_SHA256".to_string(),
-                "TLS_AES_256_GCM_SHA384".to_string(),
-                "TLS_CHACHA20_POLY1305_SHA256".to_string(),
-            ],
-            extensions: vec![
-                "server_name".to_string(),
-                "status_request".to_string(),
-                "supported_groups".to_string(),
-                "signature_algorithms".to_string(),
-                "key_share".to_string(),
-            ],
-            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            cipher_suites: names(&[
+                "GREASE",
+                "TLS_AES_128_GCM_SHA256",
+                "TLS_AES_256_GCM_SHA384",
+                "TLS_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+                "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+                "TLS_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_RSA_WITH_AES_128_CBC_SHA",
+                "TLS_RSA_WITH_AES_256_CBC_SHA",
+            ]),
+            extensions: names(&[
+                "GREASE",
+                "server_name",
+                "extended_master_secret",
+                "renegotiation_info",
+                "supported_groups",
+                "ec_point_formats",
+                "session_ticket",
+                "application_layer_protocol_negotiation",
+                "status_request",
+                "signature_algorithms",
+                "signed_certificate_timestamp",
+                "key_share",
+                "psk_key_exchange_modes",
+                "supported_versions",
+                "compress_certificate",
+                "application_settings",
+                "encrypted_client_hello",
+                "GREASE",
+            ]),
+            supported_groups: names(&["GREASE", "x25519", "secp256r1", "secp384r1"]),
+            key_share_groups: names(&["GREASE", "x25519"]),
+            signature_algorithms: names(&[
+                "ecdsa_secp256r1_sha256",
+                "rsa_pss_rsae_sha256",
+                "rsa_pkcs1_sha256",
+                "ecdsa_secp384r1_sha384",
+                "rsa_pss_rsae_sha384",
+                "rsa_pkcs1_sha384",
+                "rsa_pss_rsae_sha512",
+                "rsa_pkcs1_sha512",
+            ]),
+            supported_versions: names(&["GREASE", "1.3", "1.2"]),
+            cert_compression: names(&["brotli"]),
+            alpn: names(&["h2", "http/1.1"]),
+            permute_extensions: true,
         }
     }
 
@@ -76,20 +159,61 @@ impl UTlsFingerprint {
         Self {
             browser: "Firefox".to_string(),
             tls_version: "1.3".to_string(),
-            cipher_suites: vec![
-                "TLS_AES_128_GCM_SHA256".to_string(),
-                "TLS_CHACHA20_POLY1305_SHA256".to_string(),
-                "TLS_AES_256_GCM_SHA384".to_string(),
-            ],
-            extensions: vec![
-                "server_name".to_string(),
-                "status_request".to_string(),
-                "supported_groups".to_string(),
-                "signature_algorithms".to_string(),
-                "key_share".to_string(),
-                "padding".to_string(),
-            ],
-            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            cipher_suites: names(&[
+                "TLS_AES_128_GCM_SHA256",
+                "TLS_CHACHA20_POLY1305_SHA256",
+                "TLS_AES_256_GCM_SHA384",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA",
+                "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+                "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+                "TLS_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_RSA_WITH_AES_128_CBC_SHA",
+                "TLS_RSA_WITH_AES_256_CBC_SHA",
+            ]),
+            extensions: names(&[
+                "server_name",
+                "extended_master_secret",
+                "renegotiation_info",
+                "supported_groups",
+                "ec_point_formats",
+                "session_ticket",
+                "application_layer_protocol_negotiation",
+                "status_request",
+                "delegated_credentials",
+                "key_share",
+                "supported_versions",
+                "signature_algorithms",
+                "psk_key_exchange_modes",
+                "record_size_limit",
+                "padding",
+            ]),
+            supported_groups: names(&["x25519", "secp256r1", "secp384r1", "secp521r1", "ffdhe2048", "ffdhe3072"]),
+            key_share_groups: names(&["x25519", "secp256r1"]),
+            signature_algorithms: names(&[
+                "ecdsa_secp256r1_sha256",
+                "ecdsa_secp384r1_sha384",
+                "ecdsa_secp521r1_sha512",
+                "rsa_pss_rsae_sha256",
+                "rsa_pss_rsae_sha384",
+                "rsa_pss_rsae_sha512",
+                "rsa_pkcs1_sha256",
+                "rsa_pkcs1_sha384",
+                "rsa_pkcs1_sha512",
+                "ecdsa_sha1",
+                "rsa_pkcs1_sha1",
+            ]),
+            supported_versions: names(&["1.3", "1.2"]),
+            cert_compression: Vec::new(),
+            alpn: names(&["h2", "http/1.1"]),
+            permute_extensions: false,
         }
     }
 
@@ -98,38 +222,100 @@ impl UTlsFingerprint {
         Self {
             browser: "Safari".to_string(),
             tls_version: "1.3".to_string(),
-            cipher_suites: vec![
-                "TLS_AES_128_GCM_SHA256".to_string(),
-                "TLS_AES_256_GCM_SHA384".to_string(),
-            ],
-            extensions: vec![
-                "server_name".to_string(),
-                "supported_groups".to_string(),
-                "signature_algorithms".to_string(),
-                "key_share".to_string(),
-            ],
-            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            cipher_suites: names(&[
+                "GREASE",
+                "TLS_AES_128_GCM_SHA256",
+                "TLS_AES_256_GCM_SHA384",
+                "TLS_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA",
+                "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+                "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+                "TLS_RSA_WITH_AES_256_GCM_SHA384",
+                "TLS_RSA_WITH_AES_128_GCM_SHA256",
+                "TLS_RSA_WITH_AES_256_CBC_SHA",
+                "TLS_RSA_WITH_AES_128_CBC_SHA",
+                "TLS_ECDHE_ECDSA_WITH_3DES_EDE_CBC_SHA",
+                "TLS_ECDHE_RSA_WITH_3DES_EDE_CBC_SHA",
+                "TLS_RSA_WITH_3DES_EDE_CBC_SHA",
+            ]),
+            extensions: names(&[
+                "GREASE",
+                "server_name",
+                "extended_master_secret",
+                "renegotiation_info",
+                "supported_groups",
+                "ec_point_formats",
+                "application_layer_protocol_negotiation",
+                "status_request",
+                "signature_algorithms",
+                "signed_certificate_timestamp",
+                "key_share",
+                "psk_key_exchange_modes",
+                "supported_versions",
+                "compress_certificate",
+                "padding",
+                "GREASE",
+            ]),
+            supported_groups: names(&["GREASE", "x25519", "secp256r1", "secp384r1", "secp521r1"]),
+            key_share_groups: names(&["GREASE", "x25519"]),
+            signature_algorithms: names(&[
+                "ecdsa_secp256r1_sha256",
+                "rsa_pss_rsae_sha256",
+                "rsa_pkcs1_sha256",
+                "ecdsa_secp384r1_sha384",
+                "ecdsa_sha1",
+                "rsa_pss_rsae_sha384",
+                "rsa_pss_rsae_sha384",
+                "rsa_pkcs1_sha384",
+                "rsa_pss_rsae_sha512",
+                "rsa_pkcs1_sha512",
+                "rsa_pkcs1_sha1",
+            ]),
+            supported_versions: names(&["GREASE", "1.3", "1.2", "1.1", "1.0"]),
+            cert_compression: names(&["zlib"]),
+            alpn: names(&["h2", "http/1.1"]),
+            permute_extensions: false,
         }
     }
 }
 
-/// obfs4 handshake parameters
+/// uTLS client parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UTlsConfig {
+    /// Browser fingerprint of the ClientHello
+    pub fingerprint: UTlsFingerprint,
+    /// Server name sent in SNI
+    pub server_name: String,
+    /// Relay's pinned ECDSA P-256 public key (65-byte uncompressed point)
+    pub server_key: Vec<u8>,
+}
+
+/// obfs4 bridge line parameters
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Obfs4Config {
-    /// Node ID (public key hash)
+    /// Bridge node ID
     pub node_id: [u8; 32],
-    /// IAT (Inter-Arrival Time) mode
+    /// IAT (Inter-Arrival Time) mode: 0 = off, 1 = enabled, 2 = paranoid
     pub iat_mode: u8,
-    /// Certificate for authentication
+    /// Bridge's X25519 identity public key (32 bytes)
     pub cert: Vec<u8>,
 }
 
 /// Domain fronting configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DomainFrontingConfig {
-    /// Front domain (CDN domain in SNI/Host header)
+    /// Front domain (CDN domain sent in SNI)
     pub front_domain: String,
-    /// Actual destination (hidden in HTTP headers)
+    /// Front's pinned ECDSA P-256 public key (65-byte uncompressed point)
+    pub front_key: Vec<u8>,
+    /// Actual destination (Host header, only sent inside TLS)
     pub actual_destination: String,
     /// CDN provider
     pub provider: CdnProvider,
@@ -153,8 +339,8 @@ pub enum CdnProvider {
 pub enum TransportConfig {
     /// obfs4 configuration
     Obfs4(Obfs4Config),
-    /// uTLS with fingerprint
-    UTls(UTlsFingerprint),
+    /// uTLS with fingerprint and pinned relay
+    UTls(Box<UTlsConfig>),
     /// Domain fronting
     DomainFronting(DomainFrontingConfig),
 }
@@ -166,51 +352,112 @@ pub struct CamouflageLayer {
     transport: TransportType,
     /// Transport configuration
     config: TransportConfig,
+    /// Bridge identity for accepting obfs4 connections
+    bridge: Option<Arc<Obfs4Bridge>>,
+    /// TLS server for accepting uTLS and domain fronting connections
+    utls_server: Option<Arc<UTlsServer>>,
 }
 
 impl CamouflageLayer {
     /// Create a new camouflage layer
     pub fn new(transport: TransportType, config: TransportConfig) -> Self {
-        Self { transport, config }
+        Self {
+            transport,
+            config,
+            bridge: None,
+            utls_server: None,
+        }
     }
 
     /// Create obfs4 transport
     pub fn obfs4(config: Obfs4Config) -> Self {
+        Self::new(TransportType::Obfs4, TransportConfig::Obfs4(config))
+    }
+
+    /// Create obfs4 transport that can also accept connections
+    ///
+    /// # Arguments
+    /// * `bridge` - Bridge identity used by `accept`; `connect` targets the
+    ///   same bridge
+    pub fn obfs4_bridge(bridge: Arc<Obfs4Bridge>) -> Self {
         Self {
-            transport: TransportType::Obfs4,
-            config: TransportConfig::Obfs4(config),
+            bridge: Some(bridge.clone()),
+            ..Self::obfs4(bridge.client_config())
         }
     }
 
     /// Create uTLS transport with Chrome fingerprint
-    pub fn utls_chrome() -> Self {
-        Self {
-            transport: TransportType::UTlsChrome,
-            config: TransportConfig::UTls(UTlsFingerprint::chrome()),
-        }
+    ///
+    /// # Arguments
+    /// * `server_name` - Server name sent in SNI
+    /// * `server_key` - Relay's pinned ECDSA P-256 public key
+    pub fn utls_chrome(server_name: &str, server_key: Vec<u8>) -> Self {
+        Self::utls(TransportType::UTlsChrome, UTlsFingerprint::chrome(), server_name, server_key)
     }
 
     /// Create uTLS transport with Firefox fingerprint
-    pub fn utls_firefox() -> Self {
-        Self {
-            transport: TransportType::UTlsFirefox,
-            config: TransportConfig::UTls(UTlsFingerprint::firefox()),
-        }
+    ///
+    /// # Arguments
+    /// * `server_name` - Server name sent in SNI
+    /// * `server_key` - Relay's pinned ECDSA P-256 public key
+    pub fn utls_firefox(server_name: &str, server_key: Vec<u8>) -> Self {
+        Self::utls(TransportType::UTlsFirefox, UTlsFingerprint::firefox(), server_name, server_key)
     }
 
     /// Create uTLS transport with Safari fingerprint
-    pub fn utls_safari() -> Self {
+    ///
+    /// # Arguments
+    /// * `server_name` - Server name sent in SNI
+    /// * `server_key` - Relay's pinned ECDSA P-256 public key
+    pub fn utls_safari(server_name: &str, server_key: Vec<u8>) -> Self {
+        Self::utls(TransportType::UTlsSafari, UTlsFingerprint::safari(), server_name, server_key)
+    }
+
+    /// Create uTLS transport that can also accept connections
+    ///
+    /// # Arguments
+    /// * `server` - TLS server used by `accept`; `connect` targets the same
+    ///   server with the Chrome fingerprint
+    pub fn utls_server(server: Arc<UTlsServer>) -> Self {
         Self {
-            transport: TransportType::UTlsSafari,
-            config: TransportConfig::UTls(UTlsFingerprint::safari()),
+            utls_server: Some(server.clone()),
+            ..Self::new(
+                TransportType::UTlsChrome,
+                TransportConfig::UTls(Box::new(server.client_config(UTlsFingerprint::chrome()))),
+            )
         }
     }
 
+    fn utls(transport: TransportType, fingerprint: UTlsFingerprint, server_name: &str, server_key: Vec<u8>) -> Self {
+        let config = UTlsConfig {
+            fingerprint,
+            server_name: server_name.to_string(),
+            server_key,
+        };
+        Self::new(transport, TransportConfig::UTls(Box::new(config)))
+    }
+
     /// Create domain fronting transport
     pub fn domain_fronting(config: DomainFrontingConfig) -> Self {
+        Self::new(TransportType::DomainFronting, TransportConfig::DomainFronting(config))
+    }
+
+    /// Create domain fronting transport that can also accept connections
+    ///
+    /// # Arguments
+    /// * `config` - Fronting parameters; `accept` only answers requests for
+    ///   `actual_destination`
+    /// * `server` - TLS server standing in for the front; `connect` pins its
+    ///   key instead of `config.front_key`
+    pub fn domain_fronting_server(config: DomainFrontingConfig, server: Arc<UTlsServer>) -> Self {
+        let config = DomainFrontingConfig {
+            front_key: server.public_key().to_vec(),
+            ..config
+        };
+
         Self {
-            transport: TransportType::DomainFronting,
-            config: TransportConfig::DomainFronting(config),
+            utls_server: Some(server),
+            ..Self::domain_fronting(config)
         }
     }
 
@@ -223,10 +470,10 @@ impl CamouflageLayer {
     /// * Camouflaged data ready for transmission
     pub fn wrap(&self, data: &[u8]) -> Result<Vec<u8>> {
         match self.transport {
-            TransportType::Obfs4 => self.wrap_obfs4(data),
+            TransportType::Obfs4 => Err(requires_stream("obfs4")),
             TransportType::UTlsChrome
             | TransportType::UTlsFirefox
-            | TransportType::UTlsSafari => self.wrap_utls(data),
+            | TransportType::UTlsSafari => Err(requires_stream("uTLS")),
             TransportType::DomainFronting => self.wrap_domain_fronting(data),
         }
     }
@@ -240,324 +487,25 @@ impl CamouflageLayer {
     /// * Original raw data
     pub fn unwrap(&self, data: &[u8]) -> Result<Vec<u8>> {
         match self.transport {
-            TransportType::Obfs4 => self.unwrap_obfs4(data),
+            TransportType::Obfs4 => Err(requires_stream("obfs4")),
             TransportType::UTlsChrome
             | TransportType::UTlsFirefox
-            | TransportType::UTlsSafari => self.unwrap_utls(data),
+            | TransportType::UTlsSafari => Err(requires_stream("uTLS")),
             TransportType::DomainFronting => self.unwrap_domain_fronting(data),
         }
     }
 
-    /// Wrap data using obfs4
-    fn wrap_obfs4(&self, data: &[u8]) -> Result<Vec<u8>> {
-        use ring::rand::{SystemRandom, SecureRandom};
-        use ring::digest;
-
-        if let TransportConfig::Obfs4(config) = &self.config {
-            // obfs4 packet structure:
-            // [ephemeral_key(32)][MAC(32)][encrypted_length(2)][encrypted_data][random_padding]
-
-            let rng = SystemRandom::new();
-
-            // Generate ephemeral key for this packet
-            let mut ephemeral_key = [0u8; 32];
-            rng.fill(&mut ephemeral_key)
-                .map_err(|_| ScramblerError::CryptoError("RNG failed".to_string()))?;
-
-            // Derive encryption key from node_id and ephemeral key
-            let mut key_material = config.node_id.to_vec();
-            key_material.extend_from_slice(&ephemeral_key);
-            let digest_result = digest::digest(&digest::SHA256, &key_material);
-            let encryption_key = digest_result.as_ref();
-
-            // Encrypt data length (2 bytes)
-            let length = (data.len() as u16).to_be_bytes();
-            let mut encrypted_length = [0u8; 2];
-            for (i, &byte) in length.iter().enumerate() {
-                encrypted_length[i] = byte ^ encryption_key[i];
-            }
-
-            // Encrypt data using stream cipher (XOR with keystream)
-            let mut encrypted_data = data.to_vec();
-            for (i, byte) in encrypted_data.iter_mut().enumerate() {
-                *byte ^= encryption_key[(i + 2) % 32];
-            }
-
-            // Add random padding (8-256 bytes for length obfuscation)
-            let padding_len = (ephemeral_key[0] as usize % 249) + 8;
-            let mut padding = vec![0u8; padding_len];
-            rng.fill(&mut padding)
-                .map_err(|_| ScramblerError::CryptoError("RNG failed".to_string()))?;
-
-            // Compute MAC over everything
-            let mut mac_data = Vec::new();
-            mac_data.extend_from_slice(&ephemeral_key);
-            mac_data.extend_from_slice(&encrypted_length);
-            mac_data.extend_from_slice(&encrypted_data);
-            mac_data.extend_from_slice(&padding);
-
-            let mac_result = digest::digest(&digest::SHA256, &mac_data);
-            let mac = mac_result.as_ref();
-
-            // Assemble packet
-            let mut packet = Vec::new();
-            packet.extend_from_slice(&ephemeral_key);
-            packet.extend_from_slice(mac);
-            packet.extend_from_slice(&encrypted_length);
-            packet.extend_from_slice(&encrypted_data);
-            packet.extend_from_slice(&padding);
-
-            tracing::debug!(
-                packet_size = packet.len(),
-                data_size = data.len(),
-                padding_size = padding_len,
-                "obfs4 packet created"
-            );
-
-            Ok(packet)
-        } else {
-            Err(ScramblerError::ConfigError(
-                "Invalid obfs4 config".to_string(),
-            ))
-        }
-    }
-
-    /// Unwrap obfs4 data
-    fn unwrap_obfs4(&self, data: &[u8]) -> Result<Vec<u8>> {
-        use ring::digest;
-
-        if let TransportConfig::Obfs4(config) = &self.config {
-            // Minimum packet: ephemeral(32) + MAC(32) + length(2) + data(1) = 67 bytes
-            if data.len() < 67 {
-                return Err(ScramblerError::CryptoError(
-                    "obfs4 packet too short".to_string(),
-                ));
-            }
-
-            // Extract components
-            let ephemeral_key = &data[0..32];
-            let received_mac = &data[32..64];
-            let encrypted_length = &data[64..66];
-            let remaining = &data[66..];
-
-            // Derive decryption key
-            let mut key_material = config.node_id.to_vec();
-            key_material.extend_from_slice(ephemeral_key);
-            let digest_result = digest::digest(&digest::SHA256, &key_material);
-            let decryption_key = digest_result.as_ref();
-
-            // Decrypt length
-            let mut length_bytes = [0u8; 2];
-            for (i, &byte) in encrypted_length.iter().enumerate() {
-                length_bytes[i] = byte ^ decryption_key[i];
-            }
-            let data_length = u16::from_be_bytes(length_bytes) as usize;
-
-            if remaining.len() < data_length {
-                return Err(ScramblerError::CryptoError(
-                    "obfs4 packet truncated".to_string(),
-                ));
-            }
-
-            // Split data and padding
-            let encrypted_data = &remaining[0..data_length];
-            let padding = &remaining[data_length..];
-
-            // Verify MAC
-            let mut mac_data = Vec::new();
-            mac_data.extend_from_slice(ephemeral_key);
-            mac_data.extend_from_slice(encrypted_length);
-            mac_data.extend_from_slice(encrypted_data);
-            mac_data.extend_from_slice(padding);
-
-            let computed_mac = digest::digest(&digest::SHA256, &mac_data);
-
-            // Constant-time MAC comparison
-            if computed_mac.as_ref() != received_mac {
-                return Err(ScramblerError::CryptoError(
-                    "obfs4 MAC verification failed".to_string(),
-                ));
-            }
-
-            // Decrypt data
-            let mut decrypted = encrypted_data.to_vec();
-            for (i, byte) in decrypted.iter_mut().enumerate() {
-                *byte ^= decryption_key[(i + 2) % 32];
-            }
-
-            tracing::debug!(
-                packet_size = data.len(),
-                data_size = decrypted.len(),
-                "obfs4 packet decrypted"
-            );
-
-            Ok(decrypted)
-        } else {
-            Err(ScramblerError::ConfigError(
-                "Invalid obfs4 config".to_string(),
-            ))
-        }
-    }
-
-    /// Wrap data using uTLS
-    fn wrap_utls(&self, data: &[u8]) -> Result<Vec<u8>> {
-        use ring::rand::{SystemRandom, SecureRandom};
-
-        if let TransportConfig::UTls(fingerprint) = &self.config {
-            // TLS 1.3 Application Data record structure:
-            // [content_type(1)][version(2)][length(2)][encrypted_data][tag(16)]
-
-            let rng = SystemRandom::new();
-
-            // TLS 1.3 uses content type 0x17 (Application Data)
-            let content_type = 0x17u8;
-
-            // Legacy version field (always 0x0303 for TLS 1.3)
-            let version = [0x03, 0x03];
-
-            // Add zero padding to obfuscate length (0-15 bytes)
-            // TLS 1.3 uses zero padding, making it easier to remove
-            let mut padding_len_byte = [0u8; 1];
-            rng.fill(&mut padding_len_byte)
-                .map_err(|_| ScramblerError::CryptoError("RNG failed".to_string()))?;
-            let padding_len = (padding_len_byte[0] % 16) as usize;
-
-            let padding = vec![0u8; padding_len]; // Zero padding
-
-            // Inner plaintext: [data][padding][content_type(1)]
-            let mut inner_plaintext = data.to_vec();
-            inner_plaintext.extend_from_slice(&padding);
-            inner_plaintext.push(content_type);
-
-            // Simulate AEAD tag (16 bytes)
-            let mut tag = [0u8; 16];
-            rng.fill(&mut tag)
-                .map_err(|_| ScramblerError::CryptoError("RNG failed".to_string()))?;
-
-            // Derive encryption key deterministically from fingerprint
-            // In production, this would come from TLS handshake
-            use ring::digest;
-            let key_material = format!("utls_session_{}_{}", fingerprint.browser, fingerprint.tls_version);
-            let key_digest = digest::digest(&digest::SHA256, key_material.as_bytes());
-            let encryption_key = key_digest.as_ref();
-
-            let mut encrypted = inner_plaintext.clone();
-            for (i, byte) in encrypted.iter_mut().enumerate() {
-                *byte ^= encryption_key[i % 32];
-            }
-
-            // Construct TLS record
-            let record_len = (encrypted.len() + tag.len()) as u16;
-            let mut record = Vec::new();
-            record.push(content_type);
-            record.extend_from_slice(&version);
-            record.push((record_len >> 8) as u8);
-            record.push((record_len & 0xff) as u8);
-            record.extend_from_slice(&encrypted);
-            record.extend_from_slice(&tag);
-
-            tracing::debug!(
-                browser = %fingerprint.browser,
-                tls_version = %fingerprint.tls_version,
-                record_size = record.len(),
-                data_size = data.len(),
-                "uTLS record created"
-            );
-
-            Ok(record)
-        } else {
-            Err(ScramblerError::ConfigError(
-                "Invalid uTLS config".to_string(),
-            ))
-        }
-    }
-
-    /// Unwrap uTLS data
-    fn unwrap_utls(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // TLS record structure: [type(1)][version(2)][length(2)][encrypted+tag]
-        if data.len() < 5 {
-            return Err(ScramblerError::CryptoError(
-                "TLS record too short".to_string(),
-            ));
-        }
-
-        // Parse record header
-        let content_type = data[0];
-        let _version = &data[1..3];
-        let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
-
-        if content_type != 0x17 {
-            return Err(ScramblerError::CryptoError(
-                "Invalid TLS content type".to_string(),
-            ));
-        }
-
-        if data.len() < 5 + record_len {
-            return Err(ScramblerError::CryptoError(
-                "TLS record truncated".to_string(),
-            ));
-        }
-
-        // Extract encrypted data and tag
-        if record_len < 16 {
-            return Err(ScramblerError::CryptoError(
-                "TLS record too short for tag".to_string(),
-            ));
-        }
-
-        let encrypted_data = &data[5..5 + record_len - 16];
-        let _tag = &data[5 + record_len - 16..5 + record_len];
-
-        // Derive decryption key deterministically from fingerprint
-        // Must match the key derivation in wrap_utls
-        use ring::digest;
-
-        let decryption_key = if let TransportConfig::UTls(fingerprint) = &self.config {
-            let key_material = format!("utls_session_{}_{}", fingerprint.browser, fingerprint.tls_version);
-            let key_digest = digest::digest(&digest::SHA256, key_material.as_bytes());
-            key_digest.as_ref().to_vec()
-        } else {
-            return Err(ScramblerError::ConfigError("Invalid uTLS config".to_string()));
-        };
-
-        let mut decrypted = encrypted_data.to_vec();
-        for (i, byte) in decrypted.iter_mut().enumerate() {
-            *byte ^= decryption_key[i % 32];
-        }
-
-        // Remove padding and inner content type
-        // Inner plaintext format: [data][padding][content_type(1)]
-        if decrypted.is_empty() {
-            return Err(ScramblerError::CryptoError(
-                "Empty TLS plaintext".to_string(),
-            ));
-        }
-
-        // Find the actual content type at the end
-        let inner_content_type = decrypted.pop();
-        if inner_content_type != Some(0x17) {
-            // Restore if not found
-            if let Some(ct) = inner_content_type {
-                decrypted.push(ct);
-            }
-        }
-
-        // Remove trailing padding (zeros)
-        while decrypted.last() == Some(&0) {
-            decrypted.pop();
-        }
-
-        tracing::debug!(
-            record_size = data.len(),
-            plaintext_size = decrypted.len(),
-            "uTLS record decrypted"
-        );
-
-        Ok(decrypted)
-    }
-
     /// Wrap data using domain fronting
     fn wrap_domain_fronting(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.fronted_request(data, false)
+    }
+
+    /// Build the fronted POST request carrying `data`
+    ///
+    /// # Arguments
+    /// * `data` - Stream data (empty for a poll)
+    /// * `close` - Whether this is the last request of the session
+    fn fronted_request(&self, data: &[u8], close: bool) -> Result<Vec<u8>> {
         if let TransportConfig::DomainFronting(config) = &self.config {
             use ring::rand::{SystemRandom, SecureRandom};
 
@@ -584,12 +532,11 @@ impl CamouflageLayer {
                 encoded_data
             );
 
-            // Build HTTP/1.1 request (CDN typically uses HTTP/1.1 for backend)
+            // Sent inside TLS, so only the front sees the real Host; the SNI
+            // on the wire names the front domain
             let request = format!(
-                "POST /api/v2/sync HTTP/1.1\r\n\
+                "POST {} HTTP/1.1\r\n\
                  Host: {}\r\n\
-                 X-Forwarded-Host: {}\r\n\
-                 X-Forwarded-For: 1.2.3.4\r\n\
                  User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36\r\n\
                  Content-Type: application/json\r\n\
                  Accept: application/json\r\n\
@@ -599,15 +546,16 @@ impl CamouflageLayer {
                  Referer: https://{}/\r\n\
                  X-Request-ID: {}\r\n\
                  Content-Length: {}\r\n\
-                 Connection: keep-alive\r\n\
+                 Connection: {}\r\n\
                  \r\n\
                  {}",
-                config.front_domain,
+                FRONTED_PATH,
+                config.actual_destination,
+                config.actual_destination,
                 config.actual_destination,
-                config.front_domain,
-                config.front_domain,
                 request_id_hex,
                 json_body.len(),
+                if close { "close" } else { "keep-alive" },
                 json_body
             );
 
@@ -638,27 +586,7 @@ impl CamouflageLayer {
             .position(|w| w == b"\r\n\r\n")
             .ok_or_else(|| ScramblerError::CryptoError("Invalid HTTP request - no header terminator".to_string()))?;
 
-        // Extract body
-        let body = &data[header_end + 4..];
-
-        // Parse JSON body
-        let body_str = std::str::from_utf8(body)
-            .map_err(|e| ScramblerError::CryptoError(format!("Invalid UTF-8 in body: {}", e)))?;
-
-        let json: serde_json::Value = serde_json::from_str(body_str)
-            .map_err(|e| ScramblerError::CryptoError(format!("Invalid JSON in body: {}", e)))?;
-
-        // Extract payload field
-        let payload_str = json
-            .get("payload")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| ScramblerError::CryptoError("Missing 'payload' field in JSON".to_string()))?;
-
-        // Base64 decode the payload
-        use base64::{Engine as _, engine::general_purpose};
-        let decoded = general_purpose::STANDARD
-            .decode(payload_str)
-            .map_err(|e| ScramblerError::CryptoError(format!("Base64 decode failed: {}", e)))?;
+        let decoded = decode_payload(&data[header_end + 4..])?;
 
         tracing::debug!(
             request_size = data.len(),
@@ -673,10 +601,447 @@ impl CamouflageLayer {
     pub fn transport_type(&self) -> TransportType {
         self.transport
     }
+
+    /// Copy of this layer for encoding requests on one connection
+    fn request_codec(&self) -> Self {
+        Self::new(self.transport, self.config.clone())
+    }
+}
+
+impl PluggableTransport for CamouflageLayer {
+    fn connect(&self, stream: BoxedStream) -> TransportFuture<'_> {
+        Box::pin(async move {
+            match &self.config {
+                TransportConfig::Obfs4(config) => {
+                    let (reader, writer) = obfs4::connect(stream, config).await?.into_split();
+                    Ok(spawn_pump(reader, writer, MAX_RECORD_DATA))
+                }
+                TransportConfig::UTls(config) => {
+                    let (reader, writer) = utls::connect(stream, config).await?.into_split();
+                    Ok(spawn_pump(reader, writer, MAX_RECORD_DATA))
+                }
+                TransportConfig::DomainFronting(config) => {
+                    let front = UTlsConfig {
+                        fingerprint: UTlsFingerprint::chrome(),
+                        server_name: config.front_domain.clone(),
+                        server_key: config.front_key.clone(),
+                    };
+                    let (reader, writer) = utls::connect(stream, &front).await?.into_split();
+                    let tls = spawn_pump(reader, writer, MAX_RECORD_DATA);
+                    Ok(self.fronted_stream(tls, FrontedRole::Client))
+                }
+            }
+        })
+    }
+
+    fn accept(&self, stream: BoxedStream) -> TransportFuture<'_> {
+        Box::pin(async move {
+            match self.transport {
+                TransportType::Obfs4 => {
+                    let bridge = self.bridge.as_ref().ok_or_else(|| {
+                        ScramblerError::ConfigError(
+                            "obfs4 transport needs a bridge identity to accept connections".to_string(),
+                        )
+                    })?;
+                    let (reader, writer) = obfs4::accept(stream, bridge).await?.into_split();
+                    Ok(spawn_pump(reader, writer, MAX_RECORD_DATA))
+                }
+                TransportType::UTlsChrome | TransportType::UTlsFirefox | TransportType::UTlsSafari => {
+                    let stream: BoxedStream = Box::new(self.tls_server()?.accept(stream).await?);
+                    Ok(stream)
+                }
+                TransportType::DomainFronting => {
+                    let tls: BoxedStream = Box::new(self.tls_server()?.accept(stream).await?);
+                    Ok(self.fronted_stream(tls, FrontedRole::Server))
+                }
+            }
+        })
+    }
+}
+
+impl CamouflageLayer {
+    fn tls_server(&self) -> Result<&Arc<UTlsServer>> {
+        self.utls_server.as_ref().ok_or_else(|| {
+            ScramblerError::ConfigError(
+                "Transport needs a TLS server to accept connections".to_string(),
+            )
+        })
+    }
+
+    /// Carry a byte stream as fronted HTTP requests and responses
+    ///
+    /// # Arguments
+    /// * `tls` - Decrypted stream to or from the front
+    /// * `role` - Whether this end sends requests or answers them
+    fn fronted_stream(&self, tls: BoxedStream, role: FrontedRole) -> BoxedStream {
+        let (caller, pump) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+        let session = FrontedSession {
+            layer: self.request_codec(),
+            connection: HttpConnection::new(tls),
+            pump,
+        };
+
+        tokio::spawn(async move {
+            let result = match role {
+                FrontedRole::Client => session.run_client().await,
+                FrontedRole::Server => session.run_server().await,
+            };
+            if let Err(e) = result {
+                tracing::debug!(error = %e, "Fronted session closed with error");
+            }
+        });
+
+        Box::new(caller)
+    }
+}
+
+/// Largest chunk of stream data carried in one frame, record, or request
+const MAX_RECORD_DATA: usize = 8192;
+
+/// Largest HTTP header block accepted from a fronting peer
+const MAX_HTTP_HEADER_LENGTH: usize = 16 * 1024;
+
+/// Largest HTTP body accepted from a fronting peer
+const MAX_HTTP_BODY_LENGTH: usize = 4 * MAX_RECORD_DATA;
+
+/// Path fronted requests are sent to
+const FRONTED_PATH: &str = "/api/v2/sync";
+
+/// Shortest wait between empty polls once a fronted session went idle
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Longest wait between empty polls
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Which end of a fronted session this is
+#[derive(Debug, Clone, Copy)]
+enum FrontedRole {
+    /// Sends requests and polls for data
+    Client,
+    /// Answers each request with one response
+    Server,
+}
+
+/// Moves bytes between the caller's pipe and fronted HTTP messages
+///
+/// Closing either end ends the session: the last message carries
+/// `Connection: close`, and data the other side writes afterwards is dropped.
+struct FrontedSession {
+    layer: CamouflageLayer,
+    connection: HttpConnection,
+    pump: tokio::io::DuplexStream,
+}
+
+impl FrontedSession {
+    async fn run_client(self) -> Result<()> {
+        let (mut pump_read, mut pump_write) = tokio::io::split(self.pump);
+        let HttpConnection { mut reader, mut writer } = self.connection;
+        let mut buffer = vec![0u8; MAX_RECORD_DATA];
+        let mut poll = Duration::ZERO;
+
+        loop {
+            // Send what the caller wrote, or an empty poll once `poll` passed
+            let (sent, closing) = read_available(&mut pump_read, &mut buffer, poll).await?;
+            let request = self.layer.fronted_request(&buffer[..sent], closing)?;
+            writer.write_all(&request).await.map_err(io_error)?;
+            writer.flush().await.map_err(io_error)?;
+
+            let response = read_http_message(&mut reader).await?.ok_or_else(|| {
+                ScramblerError::NetworkError("Front closed the connection".to_string())
+            })?;
+            if !response.start_line.starts_with("HTTP/1.1 200 ") {
+                return Err(ScramblerError::NetworkError(format!(
+                    "Front refused request: {}",
+                    response.start_line
+                )));
+            }
+
+            let data = decode_payload(&response.body)?;
+            pump_write.write_all(&data).await.map_err(io_error)?;
+
+            if closing || response.close {
+                break;
+            }
+            poll = if sent > 0 || !data.is_empty() {
+                Duration::ZERO
+            } else {
+                (poll * 2).clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL)
+            };
+        }
+
+        pump_write.shutdown().await.map_err(io_error)?;
+        writer.shutdown().await.map_err(io_error)
+    }
+
+    async fn run_server(self) -> Result<()> {
+        let (mut pump_read, mut pump_write) = tokio::io::split(self.pump);
+        let HttpConnection { mut reader, mut writer } = self.connection;
+        let mut buffer = vec![0u8; MAX_RECORD_DATA];
+
+        while let Some(request) = read_http_message(&mut reader).await? {
+            if !self.layer.is_fronted_request(&request) {
+                writer.write_all(NOT_FOUND_RESPONSE).await.map_err(io_error)?;
+                writer.shutdown().await.map_err(io_error)?;
+                return Err(ScramblerError::NetworkError(format!(
+                    "Unexpected fronted request: {}",
+                    request.start_line
+                )));
+            }
+
+            let data = decode_payload(&request.body)?;
+            pump_write.write_all(&data).await.map_err(io_error)?;
+
+            // Answer right away with whatever the caller has written
+            let (sent, caller_closed) =
+                read_available(&mut pump_read, &mut buffer, Duration::ZERO).await?;
+            let closing = request.close || caller_closed;
+            writer.write_all(&fronted_response(&buffer[..sent], closing)).await.map_err(io_error)?;
+            writer.flush().await.map_err(io_error)?;
+
+            if closing {
+                break;
+            }
+        }
+
+        pump_write.shutdown().await.map_err(io_error)?;
+        writer.shutdown().await.map_err(io_error)
+    }
+}
+
+impl CamouflageLayer {
+    /// Whether `request` is a fronted POST for this layer's destination
+    fn is_fronted_request(&self, request: &HttpMessage) -> bool {
+        let TransportConfig::DomainFronting(config) = &self.config else {
+            return false;
+        };
+
+        request.start_line == format!("POST {} HTTP/1.1", FRONTED_PATH)
+            && request
+                .host
+                .as_deref()
+                .is_some_and(|host| host.eq_ignore_ascii_case(&config.actual_destination))
+    }
+}
+
+/// Read whatever the caller has written, waiting at most `wait`
+///
+/// # Returns
+/// * Bytes read into `buffer`, and whether the caller closed its end
+async fn read_available(
+    pump: &mut ReadHalf<tokio::io::DuplexStream>,
+    buffer: &mut [u8],
+    wait: Duration,
+) -> Result<(usize, bool)> {
+    match tokio::time::timeout(wait, pump.read(buffer)).await {
+        Ok(Ok(0)) => Ok((0, true)),
+        Ok(Ok(n)) => Ok((n, false)),
+        Ok(Err(e)) => Err(io_error(e)),
+        Err(_) => Ok((0, false)),
+    }
+}
+
+/// Response to anything that isn't a fronted request
+const NOT_FOUND_RESPONSE: &[u8] = b"HTTP/1.1 404 Not Found\r\n\
+    Content-Type: text/plain\r\n\
+    Content-Length: 9\r\n\
+    Connection: close\r\n\
+    \r\n\
+    Not Found";
+
+/// Build the response answering a fronted request
+///
+/// # Arguments
+/// * `data` - Stream data for the client (may be empty)
+/// * `close` - Whether the session ends with this response
+fn fronted_response(data: &[u8], close: bool) -> Vec<u8> {
+    use base64::{Engine as _, engine::general_purpose};
+
+    let json_body = format!(
+        r#"{{"status":"ok","payload":"{}"}}"#,
+        general_purpose::STANDARD.encode(data)
+    );
+
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: application/json\r\n\
+         Cache-Control: no-store\r\n\
+         Content-Length: {}\r\n\
+         Connection: {}\r\n\
+         \r\n\
+         {}",
+        json_body.len(),
+        if close { "close" } else { "keep-alive" },
+        json_body
+    )
+    .into_bytes()
+}
+
+/// Extract the base64 `payload` field of a fronted JSON body
+fn decode_payload(body: &[u8]) -> Result<Vec<u8>> {
+    // Parse JSON body
+    let body_str = std::str::from_utf8(body)
+        .map_err(|e| ScramblerError::CryptoError(format!("Invalid UTF-8 in body: {}", e)))?;
+
+    let json: serde_json::Value = serde_json::from_str(body_str)
+        .map_err(|e| ScramblerError::CryptoError(format!("Invalid JSON in body: {}", e)))?;
+
+    // Extract payload field
+    let payload_str = json
+        .get("payload")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ScramblerError::CryptoError("Missing 'payload' field in JSON".to_string()))?;
+
+    // Base64 decode the payload
+    use base64::{Engine as _, engine::general_purpose};
+    general_purpose::STANDARD
+        .decode(payload_str)
+        .map_err(|e| ScramblerError::CryptoError(format!("Base64 decode failed: {}", e)))
+}
+
+/// Parsed HTTP request or response
+struct HttpMessage {
+    /// Request or status line
+    start_line: String,
+    /// Host header
+    host: Option<String>,
+    /// Whether the sender asked to close the connection
+    close: bool,
+    body: Vec<u8>,
+}
+
+/// Decrypted stream a fronted session runs over
+struct HttpConnection {
+    reader: BufReader<ReadHalf<BoxedStream>>,
+    writer: WriteHalf<BoxedStream>,
+}
+
+impl HttpConnection {
+    fn new(stream: BoxedStream) -> Self {
+        let (read_half, write_half) = tokio::io::split(stream);
+        Self {
+            reader: BufReader::new(read_half),
+            writer: write_half,
+        }
+    }
+}
+
+/// Read an HTTP message framed by its Content-Length header
+///
+/// # Returns
+/// * `None` if the connection closed cleanly between messages
+async fn read_http_message(
+    reader: &mut BufReader<ReadHalf<BoxedStream>>,
+) -> Result<Option<HttpMessage>> {
+    if reader.fill_buf().await.map_err(io_error)?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut head = Vec::new();
+    let mut start_line = None;
+    let mut host = None;
+    let mut close = false;
+    let mut content_length = None;
+
+    loop {
+        let start = head.len();
+        let n = reader.read_until(b'\n', &mut head).await.map_err(io_error)?;
+        if n == 0 {
+            return Err(ScramblerError::NetworkError("HTTP message truncated".to_string()));
+        }
+        if head.len() > MAX_HTTP_HEADER_LENGTH {
+            return Err(ScramblerError::NetworkError("HTTP headers too long".to_string()));
+        }
+
+        let line = String::from_utf8_lossy(&head[start..]);
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if start_line.is_none() {
+            start_line = Some(line.to_string());
+            continue;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.parse::<usize>().map_err(|_| {
+                    ScramblerError::NetworkError("Invalid Content-Length".to_string())
+                })?);
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("connection") {
+                close = value.eq_ignore_ascii_case("close");
+            }
+        }
+    }
+
+    let length = content_length
+        .ok_or_else(|| ScramblerError::NetworkError("Missing Content-Length".to_string()))?;
+    if length > MAX_HTTP_BODY_LENGTH {
+        return Err(ScramblerError::NetworkError("HTTP body too long".to_string()));
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await.map_err(io_error)?;
+
+    Ok(Some(HttpMessage {
+        start_line: start_line.unwrap_or_default(),
+        host,
+        close,
+        body,
+    }))
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> MessageSink for Obfs4Writer<W> {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> MessageFuture<'a, ()> {
+        Box::pin(Obfs4Writer::send(self, data))
+    }
+
+    fn close(&mut self) -> MessageFuture<'_, ()> {
+        Box::pin(self.shutdown())
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> MessageSource for Obfs4Reader<R> {
+    fn recv(&mut self) -> MessageFuture<'_, Option<Vec<u8>>> {
+        Box::pin(Obfs4Reader::recv(self))
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> MessageSink for UTlsWriter<W> {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> MessageFuture<'a, ()> {
+        Box::pin(UTlsWriter::send(self, data))
+    }
+
+    fn close(&mut self) -> MessageFuture<'_, ()> {
+        Box::pin(self.shutdown())
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> MessageSource for UTlsReader<R> {
+    fn recv(&mut self) -> MessageFuture<'_, Option<Vec<u8>>> {
+        Box::pin(UTlsReader::recv(self))
+    }
+}
+
+fn io_error(error: std::io::Error) -> ScramblerError {
+    ScramblerError::NetworkError(error.to_string())
+}
+
+/// obfs4 and uTLS key their records from a per-connection handshake, so
+/// there is no one-shot encoding
+fn requires_stream(transport: &str) -> ScramblerError {
+    ScramblerError::ConfigError(format!(
+        "{} is connection-oriented; use it as a PluggableTransport",
+        transport
+    ))
 }
 
 /// Select appropriate camouflage transport based on network conditions
 ///
+/// `crate::probing` derives both conditions from probe results.
+///
 /// # Arguments
 /// * `dpi_detected` - Whether DPI is detected
 /// * `tls_blocked` - Whether TLS is blocked
@@ -708,51 +1073,92 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_obfs4_wrap_unwrap() {
+    fn test_obfs4_requires_stream() {
         let config = Obfs4Config {
             node_id: [1u8; 32],
             iat_mode: 0,
-            cert: vec![0u8; 64],
+            cert: vec![0u8; 32],
         };
 
         let layer = CamouflageLayer::obfs4(config);
-        let data = b"test message";
-
-        let wrapped = layer.wrap(data).unwrap();
-        assert!(wrapped.len() > data.len()); // Should have header
+        assert!(matches!(layer.wrap(b"test message"), Err(ScramblerError::ConfigError(_))));
+        assert!(matches!(layer.unwrap(b"test message"), Err(ScramblerError::ConfigError(_))));
+    }
 
-        let unwrapped = layer.unwrap(&wrapped).unwrap();
-        assert_eq!(unwrapped, data);
+    #[test]
+    fn test_utls_requires_stream() {
+        let layer = CamouflageLayer::utls_chrome("cdn.example.com", vec![4u8; 65]);
+        assert!(matches!(layer.wrap(b"test message"), Err(ScramblerError::ConfigError(_))));
+        assert!(matches!(layer.unwrap(b"test message"), Err(ScramblerError::ConfigError(_))));
     }
 
     #[test]
-    fn test_utls_wrap_unwrap() {
-        let layer = CamouflageLayer::utls_chrome();
+    fn test_domain_fronting_wrap_unwrap() {
+        let layer = CamouflageLayer::domain_fronting(fronting_config("invisible.example.com"));
         let data = b"test message";
 
         let wrapped = layer.wrap(data).unwrap();
-        assert!(wrapped.len() > data.len()); // Should have TLS header
+        assert!(wrapped.len() > data.len()); // Should have HTTP headers
+        assert!(String::from_utf8_lossy(&wrapped).contains("Host: invisible.example.com\r\n"));
 
         let unwrapped = layer.unwrap(&wrapped).unwrap();
         assert_eq!(unwrapped, data);
     }
 
-    #[test]
-    fn test_domain_fronting_wrap_unwrap() {
-        let config = DomainFrontingConfig {
+    fn fronting_config(actual_destination: &str) -> DomainFrontingConfig {
+        DomainFrontingConfig {
             front_domain: "cdn.cloudflare.com".to_string(),
-            actual_destination: "invisible.example.com".to_string(),
+            front_key: vec![],
+            actual_destination: actual_destination.to_string(),
             provider: CdnProvider::Cloudflare,
-        };
-
-        let layer = CamouflageLayer::domain_fronting(config);
-        let data = b"test message";
+        }
+    }
 
-        let wrapped = layer.wrap(data).unwrap();
-        assert!(wrapped.len() > data.len()); // Should have HTTP headers
+    #[tokio::test]
+    async fn test_fronting_hides_destination() {
+        let front = UTlsServer::generate("cdn.cloudflare.com").unwrap();
+        let layer = CamouflageLayer::domain_fronting(DomainFrontingConfig {
+            front_key: front.public_key().to_vec(),
+            ..fronting_config("invisible.example.com")
+        });
+
+        let (client, mut observer) = tokio::io::duplex(64 * 1024);
+        let connecting =
+            tokio::spawn(async move { layer.connect(Box::new(client)).await.map(|_| ()) });
+
+        // The ClientHello names the front; the real Host is only sent encrypted
+        let mut hello = vec![0u8; 4096];
+        let n = observer.read(&mut hello).await.unwrap();
+        let hello = &hello[..n];
+        assert_eq!(hello[0], 0x16);
+        assert!(hello.windows(18).any(|w| w == b"cdn.cloudflare.com"));
+        assert!(!hello.windows(21).any(|w| w == b"invisible.example.com"));
+        connecting.abort();
+    }
 
-        let unwrapped = layer.unwrap(&wrapped).unwrap();
-        assert_eq!(unwrapped, data);
+    #[tokio::test]
+    async fn test_fronting_rejects_other_destinations() {
+        let front = Arc::new(UTlsServer::generate("cdn.cloudflare.com").unwrap());
+        let server_config = fronting_config("invisible.example.com");
+        let server = CamouflageLayer::domain_fronting_server(server_config, front.clone());
+        let client_config = fronting_config("other.example.com");
+        let client = CamouflageLayer::domain_fronting_server(client_config, front);
+
+        let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+        let accepted = tokio::spawn(async move {
+            let mut stream = server.accept(Box::new(server_end)).await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut stream = client.connect(Box::new(client_end)).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+
+        // The server answers 404, so the session ends without delivering data
+        let mut reply = Vec::new();
+        assert!(stream.read_to_end(&mut reply).await.map_or(true, |n| n == 0));
+        assert!(accepted.await.unwrap().is_empty());
     }
 
     #[test]
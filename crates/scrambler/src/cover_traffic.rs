@@ -1,162 +1,452 @@
 //! Cover Traffic Generation
 //!
-//! Generates constant-rate cover traffic to hide real message patterns.
+//! Loopix-style cover traffic: three independent Poisson streams hide when,
+//! and whether, a client sends real messages.
+//!
+//! ## Architecture
+//!
+//! - **Loop Stream:** Packets that travel through the mixnet and come back to
+//!   the client through a single-use reply block
+//! - **Drop Stream:** Packets to random recipients that the exit node discards
+//! - **Payload Stream:** Each slot carries a queued real packet, or a drop
+//!   packet if nothing is queued, so real traffic never changes the send rate
+//! - **Loop Monitor:** Tracks outstanding loops; loops that don't return point
+//!   at nodes dropping or delaying traffic
+//!
+//! ## Security Properties
+//!
+//! - **Unobservability:** An observer sees the same Poisson rate whether or not
+//!   the client is communicating
+//! - **Active Attack Detection:** An adversary blocking or delaying packets to
+//!   isolate a client also loses the client's loops, which the client notices
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use std::time::Duration;
 use crate::error::Result;
-use crate::sphinx::SphinxPacket;
+use crate::mixnet::NodeId;
+use crate::sphinx::{
+    build_packet, build_surb, open_reply, surb_id, use_surb, RouteSpec, SphinxPacket, SurbId,
+    SurbSecrets, MAX_MESSAGE_SIZE,
+};
 
 /// Cover traffic configuration
 #[derive(Debug, Clone)]
 pub struct CoverTrafficConfig {
-    /// Rate of cover packets per second
-    pub rate: f64,
-    /// Jitter in packet timing (percentage)
-    pub jitter: f64,
+    /// Loop packets per second (0 disables the stream)
+    pub loop_rate: f64,
+    /// Drop packets per second (0 disables the stream)
+    pub drop_rate: f64,
+    /// Payload slots per second (0 disables the stream)
+    pub payload_rate: f64,
+    /// Extra time a loop may take beyond its route's delays before it counts as lost
+    pub loop_grace: Duration,
+    /// Number of recent loops the loss rate is computed over
+    pub loss_window: usize,
+    /// Loss rate above which an active attack is suspected
+    pub loss_threshold: f64,
 }
 
 impl Default for CoverTrafficConfig {
     fn default() -> Self {
         Self {
-            rate: 10.0,  // 10 packets/sec
-            jitter: 0.1, // 10% jitter
+            loop_rate: 2.0,                         // 2 loops/sec
+            drop_rate: 2.0,                         // 2 drops/sec
+            payload_rate: 5.0,                      // 5 slots/sec
+            loop_grace: Duration::from_secs(30),    // 30 seconds
+            loss_window: 100,
+            loss_threshold: 0.3,                    // 30% loss
         }
     }
 }
 
+/// Cover traffic stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CoverStream {
+    /// Loop packets back to the client
+    Loop,
+    /// Drop packets to random recipients
+    Drop,
+    /// Real packets, or drop packets when none are queued
+    Payload,
+}
+
+impl CoverStream {
+    /// All streams
+    pub const ALL: [CoverStream; 3] = [CoverStream::Loop, CoverStream::Drop, CoverStream::Payload];
+}
+
 /// Cover traffic generator
+///
+/// Schedules the three streams and builds their cover packets.
 #[derive(Debug)]
 pub struct CoverTrafficGenerator {
     config: CoverTrafficConfig,
+    /// Next send time of each enabled stream
+    schedule: HashMap<CoverStream, Instant>,
 }
 
 impl CoverTrafficGenerator {
     /// Create a new cover traffic generator
     pub fn new(config: CoverTrafficConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            schedule: HashMap::new(),
+        }
     }
 
-    /// Generate a cover packet
+    /// Rate of a stream (packets per second)
+    pub fn rate(&self, stream: CoverStream) -> f64 {
+        match stream {
+            CoverStream::Loop => self.config.loop_rate,
+            CoverStream::Drop => self.config.drop_rate,
+            CoverStream::Payload => self.config.payload_rate,
+        }
+    }
+
+    /// Draw the delay until a stream's next packet
+    ///
+    /// Inter-arrival times of a Poisson process are exponentially distributed.
     ///
-    /// Creates a realistic-looking Sphinx packet that is indistinguishable from real traffic.
-    /// Uses random routing, realistic payload sizes, and proper Sphinx construction.
-    pub fn generate_cover_packet(&self) -> Result<SphinxPacket> {
+    /// # Returns
+    /// * `None` if the stream is disabled
+    pub fn next_delay(&self, stream: CoverStream) -> Option<Duration> {
         use rand::Rng;
-        use crate::sphinx::{RouteSpec, build_packet, MAX_HOPS};
 
-        // Generate random route through mix nodes (3-5 hops)
-        let num_hops = rand::thread_rng().gen_range(3..=MAX_HOPS);
-        let mut node_keys = Vec::new();
+        let rate = self.rate(stream);
+        if rate <= 0.0 || !rate.is_finite() {
+            return None;
+        }
+
+        // 1 - u lies in (0, 1], so the logarithm is finite
+        let u: f64 = rand::thread_rng().gen();
+        Some(Duration::from_secs_f64(-(1.0 - u).ln() / rate))
+    }
+
+    /// Streams whose next packet is due at `now`
+    ///
+    /// Each returned stream is rescheduled. Streams are scheduled on first use.
+    pub fn due_streams(&mut self, now: Instant) -> Vec<CoverStream> {
+        let mut due = Vec::new();
+
+        for stream in CoverStream::ALL {
+            let Some(delay) = self.next_delay(stream) else {
+                self.schedule.remove(&stream);
+                continue;
+            };
 
-        for _ in 0..num_hops {
-            let mut key = vec![0u8; 32];
-            rand::thread_rng().fill(&mut key[..]);
-            node_keys.push(key);
+            match self.schedule.get(&stream).copied() {
+                None => {
+                    self.schedule.insert(stream, now + delay);
+                }
+                Some(at) if at <= now => {
+                    due.push(stream);
+                    // Advance from the slot, not from now, to keep the rate;
+                    // but don't build up a backlog after a stall
+                    self.schedule.insert(stream, (at + delay).max(now));
+                }
+                Some(_) => {}
+            }
         }
 
-        // Random destination address (32 bytes)
+        due
+    }
+
+    /// Earliest time a stream is due
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.schedule.values().min().copied()
+    }
+
+    /// Build a drop packet along `route` to a random recipient
+    ///
+    /// The exit node can't tell it from a real message to an unknown recipient
+    /// and discards it.
+    pub fn drop_packet(&self, route: &RouteSpec) -> Result<SphinxPacket> {
+        use rand::Rng;
+
+        let mut route = route.clone();
         let mut destination = vec![0u8; 32];
         rand::thread_rng().fill(&mut destination[..]);
+        route.destination = destination;
 
-        let route = RouteSpec {
-            node_keys,
-            destination,
-        };
-
-        // Generate realistic payload size distribution
         // Most messages are small (100-500 bytes), some larger (up to 2KB)
         let payload_size = if rand::thread_rng().gen_bool(0.8) {
-            // 80% small messages
             rand::thread_rng().gen_range(100..500)
         } else {
-            // 20% larger messages
-            rand::thread_rng().gen_range(500..2048)
+            rand::thread_rng().gen_range(500..=MAX_MESSAGE_SIZE)
         };
 
-        // Generate random payload data
         let mut payload = vec![0u8; payload_size];
         rand::thread_rng().fill(&mut payload[..]);
 
-        // Build proper Sphinx packet
         let packet = build_packet(&route, &payload)?;
 
-        tracing::debug!(
-            hops = num_hops,
-            payload_size = payload_size,
-            "Generated cover traffic packet"
-        );
+        tracing::trace!(hops = route.node_keys.len(), "Generated drop packet");
 
         Ok(packet)
     }
 
-    /// Calculate delay until next cover packet
-    pub fn next_delay(&self) -> Duration {
+    /// Build a loop packet along `route` that returns to `loop_address`
+    ///
+    /// # Returns
+    /// * First hop, packet, and the secrets to recognize the loop on return
+    pub fn loop_packet(
+        &self,
+        route: &RouteSpec,
+        loop_address: SocketAddr,
+    ) -> Result<(SocketAddr, SphinxPacket, SurbSecrets)> {
         use rand::Rng;
-        
-        let base_delay = 1.0 / self.config.rate;
-        let jitter = rand::thread_rng().gen_range(-self.config.jitter..self.config.jitter);
-        let delay_secs = base_delay * (1.0 + jitter);
-        
-        Duration::from_secs_f64(delay_secs)
+
+        let (surb, secrets) = build_surb(route, loop_address)?;
+
+        let mut nonce = [0u8; 32];
+        rand::thread_rng().fill(&mut nonce);
+        let (first_hop, packet) = use_surb(&surb, &nonce)?;
+
+        tracing::trace!(hops = route.node_keys.len(), "Generated loop packet");
+
+        Ok((first_hop, packet, secrets))
+    }
+}
+
+/// Loop packet awaiting its return
+#[derive(Debug)]
+struct PendingLoop {
+    secrets: SurbSecrets,
+    route: Vec<NodeId>,
+    deadline: Instant,
+}
+
+/// Tracks loop packets to detect nodes dropping the client's traffic
+#[derive(Debug)]
+pub struct LoopMonitor {
+    window: usize,
+    threshold: f64,
+    /// Outstanding loops by reply identifier
+    pending: HashMap<SurbId, PendingLoop>,
+    /// Recent outcomes (true = returned)
+    outcomes: VecDeque<bool>,
+}
+
+impl LoopMonitor {
+    /// Create a loop monitor
+    ///
+    /// # Arguments
+    /// * `window` - Number of recent loops the loss rate is computed over
+    /// * `threshold` - Loss rate above which an attack is suspected
+    pub fn new(window: usize, threshold: f64) -> Self {
+        Self {
+            window: window.max(1),
+            threshold,
+            pending: HashMap::new(),
+            outcomes: VecDeque::new(),
+        }
+    }
+
+    /// Record a sent loop
+    ///
+    /// # Arguments
+    /// * `secrets` - Secrets returned by `CoverTrafficGenerator::loop_packet`
+    /// * `route` - Nodes the loop travels through
+    /// * `deadline` - Time after which the loop counts as lost
+    pub fn register(&mut self, secrets: SurbSecrets, route: Vec<NodeId>, deadline: Instant) {
+        self.pending.insert(secrets.id, PendingLoop { secrets, route, deadline });
+    }
+
+    /// Match a packet received on the loop address
+    ///
+    /// # Returns
+    /// * The loop's route if the packet is one of our loops
+    pub fn receive(&mut self, packet: &SphinxPacket) -> Option<Vec<NodeId>> {
+        let id = surb_id(packet);
+        let pending = self.pending.get(&id)?;
+
+        // Forged packets can reuse an identifier but can't pass the AEAD check
+        if open_reply(&pending.secrets, packet).is_err() {
+            tracing::warn!("Discarding forged loop packet");
+            return None;
+        }
+
+        let pending = self.pending.remove(&id)?;
+        self.record(true);
+
+        Some(pending.route)
+    }
+
+    /// Expire loops past their deadline
+    ///
+    /// # Returns
+    /// * Routes of the lost loops
+    pub fn expire(&mut self, now: Instant) -> Vec<Vec<NodeId>> {
+        let expired: Vec<SurbId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut lost = Vec::with_capacity(expired.len());
+        for id in expired {
+            if let Some(pending) = self.pending.remove(&id) {
+                self.record(false);
+                lost.push(pending.route);
+            }
+        }
+
+        lost
+    }
+
+    /// Fraction of recent loops that didn't return
+    pub fn loss_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+
+        let lost = self.outcomes.iter().filter(|returned| !**returned).count();
+        lost as f64 / self.outcomes.len() as f64
+    }
+
+    /// Check whether loop loss suggests an active attack
+    ///
+    /// Only judged once a full window of loops has completed.
+    pub fn attack_suspected(&self) -> bool {
+        self.outcomes.len() >= self.window && self.loss_rate() > self.threshold
+    }
+
+    /// Number of loops awaiting return
+    pub fn outstanding(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn record(&mut self, returned: bool) {
+        self.outcomes.push_back(returned);
+        while self.outcomes.len() > self.window {
+            self.outcomes.pop_front();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sphinx::{process_packet, ProcessedPacket};
+    use invisible_crypto::keys::KeyPair;
+
+    fn route(keys: &[KeyPair]) -> RouteSpec {
+        RouteSpec {
+            node_keys: keys.iter().map(|k| k.public_key().to_vec()).collect(),
+            node_addresses: (0..keys.len())
+                .map(|i| format!("10.{}.0.1:9000", i).parse().unwrap())
+                .collect(),
+            delays: vec![Duration::from_millis(10); keys.len()],
+            epoch: 0,
+            destination: vec![0u8; 32],
+        }
+    }
+
+    /// Run a packet through every hop
+    fn traverse(keys: &[KeyPair], mut packet: SphinxPacket) -> ProcessedPacket {
+        for (i, key) in keys.iter().enumerate() {
+            match process_packet(&packet, key.private_key()).unwrap() {
+                ProcessedPacket::Forward { packet: next, .. } if i + 1 < keys.len() => packet = next,
+                processed => return processed,
+            }
+        }
+        unreachable!("route has at least one hop")
+    }
 
     #[test]
-    fn test_cover_traffic_generation() {
+    fn test_drop_packet_delivered_to_random_recipient() {
+        let keys: Vec<_> = (0..3).map(|_| KeyPair::generate().unwrap()).collect();
         let gen = CoverTrafficGenerator::new(CoverTrafficConfig::default());
-        let packet = gen.generate_cover_packet().unwrap();
-
-        // Verify packet has non-empty payload (realistic cover traffic)
-        assert!(!packet.payload.is_empty(), "Cover packet should have payload");
 
-        // Verify payload is within realistic size range
-        assert!(packet.payload.len() >= 100, "Payload too small");
-        assert!(packet.payload.len() <= 2048, "Payload too large");
+        let packet1 = gen.drop_packet(&route(&keys)).unwrap();
+        let packet2 = gen.drop_packet(&route(&keys)).unwrap();
+        assert_ne!(packet1.header.ephemeral_key, packet2.header.ephemeral_key);
+        assert_ne!(packet1.payload, packet2.payload);
 
-        // Verify header structure is valid
-        assert_eq!(packet.header.ephemeral_key.len(), 32, "Invalid ephemeral key size");
-        assert!(!packet.header.routing_info.is_empty(), "Routing info should not be empty");
-        assert_eq!(packet.header.mac.len(), 32, "Invalid MAC size");
+        match traverse(&keys, packet1) {
+            ProcessedPacket::Deliver { message, .. } => {
+                assert!(message.len() >= 100 && message.len() <= MAX_MESSAGE_SIZE);
+            }
+            _ => panic!("Drop packet should be delivered at the exit"),
+        }
     }
 
     #[test]
-    fn test_cover_traffic_timing() {
-        let config = CoverTrafficConfig {
-            rate: 10.0,
-            jitter: 0.1,
+    fn test_loop_packet_returns_to_client() {
+        let keys: Vec<_> = (0..3).map(|_| KeyPair::generate().unwrap()).collect();
+        let gen = CoverTrafficGenerator::new(CoverTrafficConfig::default());
+        let loop_address: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+
+        let (first_hop, packet, secrets) = gen.loop_packet(&route(&keys), loop_address).unwrap();
+        assert_eq!(first_hop, route(&keys).node_addresses[0]);
+
+        let mut monitor = LoopMonitor::new(10, 0.3);
+        monitor.register(secrets, vec![[1u8; 32]], Instant::now() + Duration::from_secs(60));
+
+        let returned = match traverse(&keys, packet) {
+            ProcessedPacket::Forward { packet, next_hop, .. } => {
+                assert_eq!(next_hop, loop_address);
+                packet
+            }
+            _ => panic!("Loop packet should be forwarded back to the client"),
         };
-        let gen = CoverTrafficGenerator::new(config);
 
-        // Test that delays are within expected range
-        for _ in 0..100 {
-            let delay = gen.next_delay();
-            let delay_secs = delay.as_secs_f64();
+        assert_eq!(monitor.receive(&returned), Some(vec![[1u8; 32]]));
+        assert_eq!(monitor.outstanding(), 0);
+        assert_eq!(monitor.loss_rate(), 0.0);
 
-            // Base delay is 1/rate = 0.1s
-            // With 10% jitter, should be between 0.09 and 0.11s
-            assert!(delay_secs >= 0.09 && delay_secs <= 0.11,
-                "Delay {} outside expected range", delay_secs);
-        }
+        // A loop is only counted once
+        assert!(monitor.receive(&returned).is_none());
     }
 
     #[test]
-    fn test_cover_traffic_indistinguishability() {
+    fn test_missing_loops_raise_alarm() {
+        let keys: Vec<_> = (0..2).map(|_| KeyPair::generate().unwrap()).collect();
         let gen = CoverTrafficGenerator::new(CoverTrafficConfig::default());
+        let loop_address: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let now = Instant::now();
+
+        let mut monitor = LoopMonitor::new(4, 0.3);
+        for _ in 0..4 {
+            let (_, _, secrets) = gen.loop_packet(&route(&keys), loop_address).unwrap();
+            monitor.register(secrets, vec![[2u8; 32]], now + Duration::from_secs(1));
+        }
 
-        // Generate multiple packets and verify they're different
-        let packet1 = gen.generate_cover_packet().unwrap();
-        let packet2 = gen.generate_cover_packet().unwrap();
+        assert!(monitor.expire(now).is_empty());
+        assert!(!monitor.attack_suspected());
+
+        let lost = monitor.expire(now + Duration::from_secs(1));
+        assert_eq!(lost.len(), 4);
+        assert_eq!(monitor.loss_rate(), 1.0);
+        assert!(monitor.attack_suspected());
+    }
+
+    #[test]
+    fn test_stream_scheduling() {
+        let config = CoverTrafficConfig {
+            loop_rate: 10.0,
+            drop_rate: 0.0,
+            payload_rate: 10.0,
+            ..CoverTrafficConfig::default()
+        };
+        let mut gen = CoverTrafficGenerator::new(config);
+        assert!(gen.next_delay(CoverStream::Drop).is_none());
 
-        // Different packets should have different ephemeral keys
-        assert_ne!(packet1.header.ephemeral_key, packet2.header.ephemeral_key,
-            "Cover packets should have different ephemeral keys");
+        // Mean of the exponential inter-arrival times is 1/rate
+        let mean = (0..2000)
+            .map(|_| gen.next_delay(CoverStream::Loop).unwrap().as_secs_f64())
+            .sum::<f64>()
+            / 2000.0;
+        assert!((mean - 0.1).abs() < 0.02, "Mean delay {} too far from 0.1s", mean);
 
-        // Different packets should have different payloads
-        assert_ne!(packet1.payload, packet2.payload,
-            "Cover packets should have different payloads");
+        // Streams are scheduled on first use and fire independently
+        let start = Instant::now();
+        assert!(gen.due_streams(start).is_empty());
+        let due = gen.due_streams(gen.next_deadline().unwrap());
+        assert!(!due.is_empty());
+        assert!(!due.contains(&CoverStream::Drop));
     }
 }
@@ -7,22 +7,48 @@
 //! ## Architecture
 //!
 //! - **Drop Points:** Random relay nodes store encrypted messages
+//! - **Rotating Addresses:** A `DropRatchet` shared by sender and recipient
+//!   derives a fresh drop ID and access token every drop epoch; the
+//!   previous epochs' drops stay pollable for a grace window
 //! - **Anonymous Retrieval:** Recipients poll with access token, no identity
 //! - **Ephemeral Storage:** Messages expire after TTL or retrieval
+//! - **Leased Fetches:** Fetched messages are leased, not deleted; an
+//!   acknowledgment deletes them and an expired lease makes them fetchable
+//!   again, so a reply lost in transit loses no mail. Large drops are
+//!   fetched a page at a time
+//! - **Storage Backends:** Messages live in a `DropStore`, in memory or in
+//!   an encrypted on-disk log that survives relay restarts
+//! - **Replication:** Relays may copy each drop to sibling relays, so a
+//!   recipient can still fetch while one node is offline
+//! - **Store Stamps:** Relays only accept stores carrying a proof of work or
+//!   an anonymous store token (see `stamp`), so strangers can't cheaply fill
+//!   a drop
 //! - **Cover Traffic:** Fake polls maintain constant query rate
 //!
 //! ## Security Properties
 //!
 //! - **Unlinkability:** No connection between sender and recipient
 //! - **Deniability:** Cannot prove who stored or retrieved a message
-//! - **Forward Secrecy:** Access tokens derived per-message
-//! - **Traffic Analysis Resistance:** Cover polls hide real retrievals
+//! - **Forward Secrecy:** Access tokens derived per-message; a drop ratchet
+//!   erases each epoch's addresses once its grace window ends
+//! - **Epoch Unlinkability:** Drop IDs of different epochs are unrelated to
+//!   each other and to the recipient's keys
+//! - **Traffic Analysis Resistance:** Cover polls come from decoy ratchets,
+//!   so they rotate and overlap across epochs exactly like real polls
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
 
+use invisible_crypto::kdf::hkdf_sha256;
+
+use crate::drop_store::{DropStore, MemoryStore};
+use crate::epoch::{Epoch, EpochSchedule};
 use crate::error::{Result, ScramblerError};
+use crate::stamp::{StampPolicy, StampVerifier, StoreStamp, TokenBatch, TokenRequest};
 
 /// Dead drop identifier (derived from recipient key)
 pub type DropId = [u8; 32];
@@ -30,6 +56,27 @@ pub type DropId = [u8; 32];
 /// Message access token (derived from shared secret)
 pub type AccessToken = [u8; 32];
 
+/// Fetch lease identifier (random)
+pub type LeaseId = [u8; 16];
+
+/// Payload bytes per fetched page, well inside a wire frame
+const MAX_PAGE_BYTES: usize = 512 * 1024;
+
+/// Decoy ratchets each client draws cover polls from
+const COVER_CONTACTS: usize = 4;
+
+fn default_lease_secs() -> u64 {
+    60
+}
+
+fn default_drop_schedule() -> EpochSchedule {
+    EpochSchedule {
+        genesis: 0,
+        epoch_duration: Duration::from_secs(86400), // 1 day
+        grace_period: Duration::from_secs(86400),   // message TTL
+    }
+}
+
 /// Dead drop configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeadDropConfig {
@@ -39,6 +86,16 @@ pub struct DeadDropConfig {
     pub max_messages: usize,
     /// Poll interval for cover traffic (ms)
     pub poll_interval_ms: u64,
+    /// How long fetched messages stay leased before redelivery (seconds)
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: u64,
+    /// Rotation of ratcheted drop addresses; the grace period is how long
+    /// an epoch's drop stays pollable after it ends
+    #[serde(default = "default_drop_schedule")]
+    pub drop_schedule: EpochSchedule,
+    /// Stamps stores must carry
+    #[serde(default)]
+    pub stamps: StampPolicy,
 }
 
 impl Default for DeadDropConfig {
@@ -47,6 +104,9 @@ impl Default for DeadDropConfig {
             message_ttl: 86400,      // 24 hours
             max_messages: 100,
             poll_interval_ms: 5000,  // 5 seconds
+            lease_secs: default_lease_secs(),
+            drop_schedule: default_drop_schedule(),
+            stamps: StampPolicy::default(),
         }
     }
 }
@@ -72,7 +132,7 @@ impl StoredMessage {
             .unwrap()
             .as_secs();
 
-        now >= self.stored_at + self.ttl
+        now >= self.stored_at.saturating_add(self.ttl)
     }
 
     /// Time until expiration (seconds)
@@ -82,7 +142,7 @@ impl StoredMessage {
             .unwrap()
             .as_secs();
 
-        let expiry_time = self.stored_at + self.ttl;
+        let expiry_time = self.stored_at.saturating_add(self.ttl);
         if now < expiry_time {
             Some(expiry_time - now)
         } else {
@@ -91,6 +151,25 @@ impl StoredMessage {
     }
 }
 
+/// Page of messages fetched under a lease
+#[derive(Debug, Clone)]
+pub struct FetchedPage {
+    /// Lease to acknowledge the messages under
+    pub lease_id: LeaseId,
+    /// Leased messages, oldest first
+    pub messages: Vec<StoredMessage>,
+    /// Unleased messages left in the drop after this page
+    pub remaining: usize,
+}
+
+/// Messages handed out by a fetch and not yet acknowledged
+#[derive(Debug)]
+struct Lease {
+    drop_id: DropId,
+    message_ids: Vec<[u8; 16]>,
+    expires_at: Instant,
+}
+
 /// Dead drop relay node
 ///
 /// Stores messages temporarily for anonymous retrieval.
@@ -98,22 +177,98 @@ impl StoredMessage {
 pub struct DeadDropNode {
     /// Configuration
     config: DeadDropConfig,
-    /// Drop ID -> Messages
-    drops: HashMap<DropId, Vec<StoredMessage>>,
-    /// Access token -> Drop ID (for retrieval)
-    access_tokens: HashMap<AccessToken, DropId>,
+    /// Message and access token storage
+    store: Box<dyn DropStore>,
+    /// Outstanding fetch leases (not persisted: a restart redelivers)
+    leases: HashMap<LeaseId, Lease>,
+    /// Store stamp checks and token issuance
+    stamps: StampVerifier,
 }
 
 impl DeadDropNode {
-    /// Create a new dead drop node
+    /// Create a new dead drop node that keeps messages in memory
     pub fn new(config: DeadDropConfig) -> Self {
+        Self::with_store(config, Box::new(MemoryStore::new()))
+    }
+
+    /// Create a dead drop node over a storage backend
+    ///
+    /// # Arguments
+    /// * `config` - Dead drop configuration
+    /// * `store` - Backend holding messages and access tokens
+    pub fn with_store(config: DeadDropConfig, store: Box<dyn DropStore>) -> Self {
+        use rand::RngCore;
+
+        let mut secret = Zeroizing::new([0u8; 32]);
+        rand::thread_rng().fill_bytes(secret.as_mut());
+
         Self {
+            stamps: StampVerifier::new(config.stamps.clone(), &secret),
             config,
-            drops: HashMap::new(),
-            access_tokens: HashMap::new(),
+            store,
+            leases: HashMap::new(),
         }
     }
 
+    /// Use a persistent secret for store tokens
+    ///
+    /// Tokens issued under a random secret stop working when the node
+    /// restarts; with the same secret they stay valid.
+    pub fn with_stamp_secret(mut self, secret: &[u8; 32]) -> Self {
+        self.stamps = StampVerifier::new(self.config.stamps.clone(), secret);
+        self
+    }
+
+    /// Use a persistent secret for store tokens and a log of spent stamps
+    ///
+    /// Like `with_stamp_secret`, but stamps spent before a restart can't be
+    /// replayed after it.
+    ///
+    /// # Arguments
+    /// * `secret` - Token key secret
+    /// * `spent_path` - Spent stamp log
+    pub fn with_persistent_stamps(
+        mut self,
+        secret: &[u8; 32],
+        spent_path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        self.stamps = StampVerifier::open(self.config.stamps.clone(), secret, spent_path)?;
+        Ok(self)
+    }
+
+    /// Store a message sent by a client
+    ///
+    /// Like `store`, but the stamp must satisfy the node's stamp policy.
+    ///
+    /// # Arguments
+    /// * `drop_id` - Drop identifier
+    /// * `access_token` - Token for retrieval
+    /// * `payload` - Encrypted message
+    /// * `stamp` - Proof of work or store token sent with the message
+    pub fn store_stamped(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        payload: Vec<u8>,
+        stamp: Option<&StoreStamp>,
+    ) -> Result<StoredMessage> {
+        self.stamps.verify(&drop_id, &payload, stamp, unix_now())?;
+        self.store(drop_id, access_token, payload)
+    }
+
+    /// Issue a batch of store tokens for a drop
+    ///
+    /// # Arguments
+    /// * `drop_id` - Drop the tokens authorize stores to
+    /// * `request` - Blinded tokens and the work paying for them
+    pub fn issue_tokens(&mut self, drop_id: &DropId, request: &TokenRequest) -> Result<TokenBatch> {
+        let batch = self.stamps.issue(drop_id, request, unix_now())?;
+
+        tracing::debug!(count = batch.evaluated.len(), "Store tokens issued");
+
+        Ok(batch)
+    }
+
     /// Store a message in a dead drop
     ///
     /// # Arguments
@@ -129,6 +284,19 @@ impl DeadDropNode {
         access_token: AccessToken,
         payload: Vec<u8>,
     ) -> Result<[u8; 16]> {
+        Ok(self.store(drop_id, access_token, payload)?.id)
+    }
+
+    /// Store a message in a dead drop
+    ///
+    /// Like `store_message`, but returns the message as stored so it can be
+    /// replicated to other nodes.
+    pub fn store(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        payload: Vec<u8>,
+    ) -> Result<StoredMessage> {
         // Generate message ID
         use rand::RngCore;
         let mut id = [0u8; 16];
@@ -147,21 +315,7 @@ impl DeadDropNode {
             ttl: self.config.message_ttl,
         };
 
-        // Get or create drop
-        let messages = self.drops.entry(drop_id).or_insert_with(Vec::new);
-
-        // Check capacity
-        if messages.len() >= self.config.max_messages {
-            return Err(ScramblerError::NetworkError(
-                "Drop is full".to_string(),
-            ));
-        }
-
-        // Store message
-        messages.push(message);
-
-        // Register access token
-        self.access_tokens.insert(access_token, drop_id);
+        self.insert(drop_id, access_token, message.clone())?;
 
         tracing::debug!(
             drop_id = ?drop_id,
@@ -169,7 +323,53 @@ impl DeadDropNode {
             "Message stored in dead drop"
         );
 
-        Ok(id)
+        Ok(message)
+    }
+
+    /// Store a copy of a message held by another node
+    ///
+    /// The message keeps its ID and expiry, but never outlives what this
+    /// node's own TTL allows from now; one that has expired is ignored. The
+    /// stamp it was stored with must pass `StampVerifier::verify_replica`, so
+    /// a copy can't be stored twice.
+    ///
+    /// # Arguments
+    /// * `drop_id` - Drop identifier
+    /// * `access_token` - Token registered when storing
+    /// * `message` - Message as stored at the other node
+    /// * `stamp` - Stamp the client stored the message with
+    pub fn store_replica(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        mut message: StoredMessage,
+        stamp: Option<&StoreStamp>,
+    ) -> Result<()> {
+        message.stored_at = message.stored_at.min(unix_now());
+        message.ttl = message.ttl.min(self.config.message_ttl);
+        if message.is_expired() {
+            return Ok(());
+        }
+
+        self.stamps
+            .verify_replica(&drop_id, &message.payload, stamp, unix_now())?;
+        self.insert(drop_id, access_token, message)
+    }
+
+    fn insert(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        message: StoredMessage,
+    ) -> Result<()> {
+        // Check capacity
+        if self.store.message_count(&drop_id) >= self.config.max_messages {
+            return Err(ScramblerError::NetworkError(
+                "Drop is full".to_string(),
+            ));
+        }
+
+        self.store.insert(drop_id, access_token, message)
     }
 
     /// Retrieve messages from a dead drop
@@ -180,51 +380,192 @@ impl DeadDropNode {
     /// # Returns
     /// * List of messages (or empty if no messages/invalid token)
     pub fn retrieve_messages(&mut self, access_token: &AccessToken) -> Result<Vec<StoredMessage>> {
+        // Remove expired messages
+        self.store.expire(unix_now())?;
+
         // Look up drop ID from access token
-        let drop_id = match self.access_tokens.get(access_token) {
-            Some(id) => *id,
+        let drop_id = match self.store.drop_for_token(access_token) {
+            Some(id) => id,
             None => {
                 // Invalid token - return empty to avoid leaking info
                 return Ok(Vec::new());
             }
         };
 
-        // Get messages from drop
-        let messages = self.drops.get_mut(&drop_id);
+        // Take all messages and clear drop
+        let retrieved = self.store.messages(&drop_id);
+        let ids: Vec<[u8; 16]> = retrieved.iter().map(|m| m.id).collect();
+        self.store.remove(&drop_id, &ids)?;
+        self.leases.retain(|_, lease| lease.drop_id != drop_id);
 
-        if let Some(msgs) = messages {
-            // Remove expired messages
-            msgs.retain(|m| !m.is_expired());
+        tracing::debug!(
+            drop_id = ?drop_id,
+            count = retrieved.len(),
+            "Messages retrieved from dead drop"
+        );
 
-            // Take all messages and clear drop
-            let retrieved = msgs.drain(..).collect();
+        Ok(retrieved)
+    }
 
-            tracing::debug!(
-                drop_id = ?drop_id,
-                count = msgs.len(),
-                "Messages retrieved from dead drop"
+    /// Fetch a page of messages under a lease
+    ///
+    /// Messages stay in the drop until acknowledged with `ack_messages`. They
+    /// are skipped by later fetches while the lease lasts and fetchable again
+    /// once it expires, so successive fetches page through the drop.
+    ///
+    /// # Arguments
+    /// * `access_token` - Access token for the drop
+    /// * `limit` - Maximum messages in the page (at least one is returned)
+    ///
+    /// # Returns
+    /// * Leased messages, oldest first (empty if no messages/invalid token)
+    pub fn fetch_messages(
+        &mut self,
+        access_token: &AccessToken,
+        limit: usize,
+    ) -> Result<FetchedPage> {
+        use rand::RngCore;
+
+        self.store.expire(unix_now())?;
+        self.expire_leases(Instant::now());
+
+        let mut lease_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut lease_id);
+
+        // Invalid token - an empty page, to avoid leaking info
+        let drop_id = match self.store.drop_for_token(access_token) {
+            Some(id) => id,
+            None => {
+                return Ok(FetchedPage {
+                    lease_id,
+                    messages: Vec::new(),
+                    remaining: 0,
+                })
+            }
+        };
+
+        let leased: HashSet<[u8; 16]> = self
+            .leases
+            .values()
+            .filter(|lease| lease.drop_id == drop_id)
+            .flat_map(|lease| lease.message_ids.iter().copied())
+            .collect();
+        let available: Vec<StoredMessage> = self
+            .store
+            .messages(&drop_id)
+            .into_iter()
+            .filter(|m| !leased.contains(&m.id))
+            .collect();
+
+        let mut messages = Vec::new();
+        let mut page_bytes = 0;
+        for message in &available {
+            let full = messages.len() >= limit.max(1)
+                || (!messages.is_empty() && page_bytes + message.payload.len() > MAX_PAGE_BYTES);
+            if full {
+                break;
+            }
+            page_bytes += message.payload.len();
+            messages.push(message.clone());
+        }
+        let remaining = available.len() - messages.len();
+
+        if !messages.is_empty() {
+            self.leases.insert(
+                lease_id,
+                Lease {
+                    drop_id,
+                    message_ids: messages.iter().map(|m| m.id).collect(),
+                    expires_at: Instant::now() + Duration::from_secs(self.config.lease_secs),
+                },
             );
+        }
 
-            Ok(retrieved)
-        } else {
-            // No messages
-            Ok(Vec::new())
+        tracing::debug!(
+            drop_id = ?drop_id,
+            count = messages.len(),
+            remaining,
+            "Messages leased from dead drop"
+        );
+
+        Ok(FetchedPage {
+            lease_id,
+            messages,
+            remaining,
+        })
+    }
+
+    /// Delete messages fetched under a lease
+    ///
+    /// Only messages the lease covers are deleted. Once a lease has expired
+    /// its acknowledgments are ignored, since the messages may have been
+    /// fetched again.
+    ///
+    /// # Returns
+    /// * Identifiers of the deleted messages
+    pub fn ack_messages(
+        &mut self,
+        access_token: &AccessToken,
+        lease_id: &LeaseId,
+        message_ids: &[[u8; 16]],
+    ) -> Result<Vec<[u8; 16]>> {
+        self.expire_leases(Instant::now());
+
+        let drop_id = match self.store.drop_for_token(access_token) {
+            Some(id) => id,
+            None => return Ok(Vec::new()),
+        };
+        let lease = match self.leases.get_mut(lease_id) {
+            Some(lease) if lease.drop_id == drop_id => lease,
+            _ => return Ok(Vec::new()),
+        };
+
+        let acked: Vec<[u8; 16]> = message_ids
+            .iter()
+            .filter(|id| lease.message_ids.contains(id))
+            .copied()
+            .collect();
+        lease.message_ids.retain(|id| !acked.contains(id));
+        if lease.message_ids.is_empty() {
+            self.leases.remove(lease_id);
         }
+
+        self.store.remove(&drop_id, &acked)?;
+
+        tracing::debug!(
+            drop_id = ?drop_id,
+            count = acked.len(),
+            "Messages acknowledged from dead drop"
+        );
+
+        Ok(acked)
     }
 
-    /// Clean up expired messages
-    pub fn cleanup_expired(&mut self) -> usize {
-        let mut removed = 0;
-
-        // Remove expired messages from each drop
-        for messages in self.drops.values_mut() {
-            let before = messages.len();
-            messages.retain(|m| !m.is_expired());
-            removed += before - messages.len();
+    /// Drop leases that expired by `now`, making their messages fetchable
+    fn expire_leases(&mut self, now: Instant) {
+        self.leases.retain(|_, lease| lease.expires_at > now);
+    }
+
+    /// Delete messages from the drop an access token retrieves
+    ///
+    /// # Returns
+    /// * Number of messages deleted (0 for an unknown token)
+    pub fn remove_messages(
+        &mut self,
+        access_token: &AccessToken,
+        message_ids: &[[u8; 16]],
+    ) -> Result<usize> {
+        match self.store.drop_for_token(access_token) {
+            Some(drop_id) => self.store.remove(&drop_id, message_ids),
+            None => Ok(0),
         }
+    }
 
-        // Remove empty drops
-        self.drops.retain(|_, msgs| !msgs.is_empty());
+    /// Clean up expired messages
+    pub fn cleanup_expired(&mut self) -> Result<usize> {
+        let removed = self.store.expire(unix_now())?;
+        self.expire_leases(Instant::now());
+        self.stamps.prune(unix_now())?;
 
         if removed > 0 {
             tracing::debug!(
@@ -233,21 +574,23 @@ impl DeadDropNode {
             );
         }
 
-        removed
+        Ok(removed)
     }
 
     /// Get statistics about the dead drop node
     pub fn stats(&self) -> DeadDropStats {
-        let total_drops = self.drops.len();
-        let total_messages: usize = self.drops.values().map(|m| m.len()).sum();
-
-        DeadDropStats {
-            total_drops,
-            total_messages,
-        }
+        self.store.stats()
     }
 }
 
+/// Current Unix time (seconds)
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 /// Dead drop statistics
 #[derive(Debug, Clone)]
 pub struct DeadDropStats {
@@ -257,6 +600,128 @@ pub struct DeadDropStats {
     pub total_messages: usize,
 }
 
+/// Drop ID and access token for one drop epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DropAddress {
+    /// Epoch the address is used in
+    pub epoch: Epoch,
+    /// Drop to store messages in
+    pub drop_id: DropId,
+    /// Token to store and retrieve messages with
+    pub access_token: AccessToken,
+}
+
+/// Ratchet of dead drop addresses shared by a sender and a recipient
+///
+/// Both sides start from the same secret and epoch and derive the same
+/// address for every later epoch. The chain only moves forward: once an
+/// epoch's grace window ends its address is erased and can't be derived
+/// again. Use one ratchet per direction of a conversation.
+pub struct DropRatchet {
+    schedule: EpochSchedule,
+    /// Epoch the chain key derives the address of
+    epoch: Epoch,
+    chain_key: Zeroizing<Vec<u8>>,
+    /// Addresses of epochs already ratcheted past, still pollable
+    retained: BTreeMap<Epoch, DropAddress>,
+}
+
+impl std::fmt::Debug for DropRatchet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DropRatchet")
+            .field("schedule", &self.schedule)
+            .field("epoch", &self.epoch)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DropRatchet {
+    /// Create a ratchet
+    ///
+    /// # Arguments
+    /// * `shared_secret` - Secret agreed by sender and recipient
+    /// * `start` - First epoch the ratchet derives an address for
+    /// * `schedule` - Drop epoch schedule
+    pub fn new(shared_secret: &[u8], start: Epoch, schedule: EpochSchedule) -> Result<Self> {
+        let chain_key = hkdf_sha256(
+            shared_secret,
+            Some(b"InvisibleDropRatchet"),
+            b"InvisibleDropChain",
+            32,
+        )?;
+
+        Ok(Self {
+            schedule,
+            epoch: start,
+            chain_key: Zeroizing::new(chain_key),
+            retained: BTreeMap::new(),
+        })
+    }
+
+    /// Address to store messages at during the epoch in effect at `now`
+    pub fn send_address(&mut self, now: SystemTime) -> Result<DropAddress> {
+        let epoch = self.schedule.epoch_at(now);
+        self.advance(epoch, now)?;
+
+        self.retained.get(&epoch).copied().ok_or_else(|| {
+            ScramblerError::CryptoError(format!("Drop address for epoch {} erased", epoch))
+        })
+    }
+
+    /// Addresses to poll at `now`, oldest first
+    ///
+    /// The current epoch's address plus those of earlier epochs whose grace
+    /// window hasn't ended.
+    pub fn poll_addresses(&mut self, now: SystemTime) -> Result<Vec<DropAddress>> {
+        self.advance(self.schedule.epoch_at(now), now)?;
+
+        Ok(self.retained.values().copied().collect())
+    }
+
+    /// Next epoch the chain would derive
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    /// Derive addresses up to `target` and erase those out of their grace window
+    fn advance(&mut self, target: Epoch, now: SystemTime) -> Result<()> {
+        while self.epoch <= target {
+            let material = Zeroizing::new(hkdf_sha256(
+                &self.chain_key,
+                None,
+                b"InvisibleDropAddress",
+                64,
+            )?);
+            let mut drop_id = [0u8; 32];
+            drop_id.copy_from_slice(&material[..32]);
+            let mut access_token = [0u8; 32];
+            access_token.copy_from_slice(&material[32..]);
+
+            self.retained.insert(
+                self.epoch,
+                DropAddress {
+                    epoch: self.epoch,
+                    drop_id,
+                    access_token,
+                },
+            );
+            self.chain_key = Zeroizing::new(hkdf_sha256(
+                &self.chain_key,
+                None,
+                b"InvisibleDropChain",
+                32,
+            )?);
+            self.epoch += 1;
+        }
+
+        let schedule = &self.schedule;
+        self.retained
+            .retain(|epoch, _| now < schedule.epoch_end(*epoch) + schedule.grace_period);
+
+        Ok(())
+    }
+}
+
 /// Dead drop client
 ///
 /// Stores and retrieves messages from dead drop relay nodes.
@@ -264,15 +729,34 @@ pub struct DeadDropStats {
 pub struct DeadDropClient {
     /// Configuration
     config: DeadDropConfig,
+    /// Decoy ratchets cover polls are drawn from
+    cover: Mutex<Vec<DropRatchet>>,
 }
 
 impl DeadDropClient {
     /// Create a new dead drop client
     pub fn new(config: DeadDropConfig) -> Self {
-        Self { config }
+        use rand::RngCore;
+
+        let start = config.drop_schedule.current_epoch();
+        let cover = (0..COVER_CONTACTS)
+            .filter_map(|_| {
+                let mut secret = Zeroizing::new([0u8; 32]);
+                rand::thread_rng().fill_bytes(secret.as_mut());
+                DropRatchet::new(secret.as_ref(), start, config.drop_schedule.clone()).ok()
+            })
+            .collect();
+
+        Self {
+            config,
+            cover: Mutex::new(cover),
+        }
     }
 
     /// Derive drop ID from recipient public key
+    ///
+    /// The ID never changes, so every message to the recipient lands in the
+    /// same drop; conversations should use a `DropRatchet` instead.
     pub fn derive_drop_id(&self, recipient_key: &[u8]) -> DropId {
         use ring::digest;
 
@@ -292,12 +776,39 @@ impl DeadDropClient {
         token
     }
 
+    /// Create a drop ratchet on this client's drop schedule
+    ///
+    /// # Arguments
+    /// * `shared_secret` - Secret agreed with the contact
+    /// * `start` - Epoch both sides start the ratchet at
+    pub fn drop_ratchet(&self, shared_secret: &[u8], start: Epoch) -> Result<DropRatchet> {
+        DropRatchet::new(shared_secret, start, self.config.drop_schedule.clone())
+    }
+
     /// Generate a cover poll (fake retrieval to hide real polls)
-    pub fn generate_cover_poll(&self) -> AccessToken {
-        use rand::RngCore;
-        let mut token = [0u8; 32];
-        rand::thread_rng().fill_bytes(&mut token);
-        token
+    ///
+    /// Returns the poll tokens of a randomly chosen decoy ratchet, so the
+    /// tokens repeat within an epoch, rotate at epoch boundaries and overlap
+    /// during grace windows like a real contact's. Send them exactly like
+    /// real polls.
+    pub fn generate_cover_poll(&self) -> Vec<AccessToken> {
+        use rand::Rng;
+
+        let mut cover = self.cover.lock().unwrap_or_else(|e| e.into_inner());
+        if cover.is_empty() {
+            return Vec::new();
+        }
+        let decoy = rand::thread_rng().gen_range(0..cover.len());
+
+        cover[decoy]
+            .poll_addresses(SystemTime::now())
+            .map(|addresses| addresses.iter().map(|a| a.access_token).collect())
+            .unwrap_or_default()
+    }
+
+    /// Stamp policy stores are minted for
+    pub fn stamp_policy(&self) -> &StampPolicy {
+        &self.config.stamps
     }
 
     /// Get next poll delay
@@ -309,6 +820,7 @@ impl DeadDropClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stamp::{store_context, BlindedTokens, WorkStamp};
 
     #[test]
     fn test_store_and_retrieve() {
@@ -339,6 +851,43 @@ mod tests {
         assert_eq!(messages.len(), 0);
     }
 
+    #[test]
+    fn test_stamped_store() {
+        let config = DeadDropConfig {
+            stamps: StampPolicy {
+                work_bits: 8,
+                ..StampPolicy::default()
+            },
+            ..DeadDropConfig::default()
+        };
+        let mut node = DeadDropNode::new(config.clone());
+        let client = DeadDropClient::new(config.clone());
+        let drop_id = [1u8; 32];
+        let access_token = [2u8; 32];
+
+        // Unstamped stores are rejected
+        assert!(node
+            .store_stamped(drop_id, access_token, b"spam".to_vec(), None)
+            .is_err());
+
+        let context = store_context(&drop_id, b"worked");
+        let bits = client.stamp_policy().work_bits;
+        let stamp = StoreStamp::Work(WorkStamp::mint(&context, bits, unix_now()));
+        node.store_stamped(drop_id, access_token, b"worked".to_vec(), Some(&stamp))
+            .unwrap();
+
+        // The recipient obtains tokens and hands them to a contact
+        let (request, blinded) = BlindedTokens::new(drop_id, 2, client.stamp_policy(), unix_now());
+        let batch = node.issue_tokens(&drop_id, &request).unwrap();
+        let tokens = blinded.finalize(&batch).unwrap();
+
+        let stamp = tokens[0].redeem(b"tokened");
+        node.store_stamped(drop_id, access_token, b"tokened".to_vec(), Some(&stamp))
+            .unwrap();
+
+        assert_eq!(node.retrieve_messages(&access_token).unwrap().len(), 2);
+    }
+
     #[test]
     fn test_invalid_access_token() {
         let config = DeadDropConfig::default();
@@ -437,7 +986,7 @@ mod tests {
         std::thread::sleep(Duration::from_millis(10));
 
         // Cleanup should remove all messages
-        let removed = node.cleanup_expired();
+        let removed = node.cleanup_expired().unwrap();
         assert_eq!(removed, 5);
 
         let stats = node.stats();
@@ -445,16 +994,241 @@ mod tests {
     }
 
     #[test]
-    fn test_cover_polls() {
+    fn test_fetch_and_ack() {
         let config = DeadDropConfig::default();
+        let mut node = DeadDropNode::new(config.clone());
+        let client = DeadDropClient::new(config);
+
+        let drop_id = client.derive_drop_id(b"recipient_public_key");
+        let access_token = client.derive_access_token(b"shared_secret_for_access");
+
+        for i in 0..5 {
+            let payload = format!("message {}", i).into_bytes();
+            node.store_message(drop_id, access_token, payload).unwrap();
+        }
+
+        // Pages skip messages leased by earlier fetches
+        let first = node.fetch_messages(&access_token, 2).unwrap();
+        assert_eq!(first.messages.len(), 2);
+        assert_eq!(first.remaining, 3);
+        assert_eq!(first.messages[0].payload, b"message 0");
+        let second = node.fetch_messages(&access_token, 10).unwrap();
+        assert_eq!(second.messages.len(), 3);
+        assert_eq!(second.remaining, 0);
+
+        // Fetching deletes nothing; acknowledging does
+        assert_eq!(node.stats().total_messages, 5);
+        let ids: Vec<_> = first.messages.iter().map(|m| m.id).collect();
+        assert_eq!(node.ack_messages(&access_token, &first.lease_id, &ids).unwrap(), ids);
+        assert_eq!(node.stats().total_messages, 3);
+
+        // Acknowledgments only cover the lease's own messages
+        let other = second.messages[0].id;
+        assert!(node.ack_messages(&access_token, &first.lease_id, &[other]).unwrap().is_empty());
+        let wrong_token = client.derive_access_token(b"wrong_secret");
+        assert!(node.ack_messages(&wrong_token, &second.lease_id, &[other]).unwrap().is_empty());
+        assert_eq!(node.stats().total_messages, 3);
+    }
+
+    #[test]
+    fn test_expired_lease_redelivers() {
+        let config = DeadDropConfig {
+            lease_secs: 0,
+            ..DeadDropConfig::default()
+        };
+
+        let mut node = DeadDropNode::new(config.clone());
         let client = DeadDropClient::new(config);
 
-        // Generate multiple cover polls
-        let token1 = client.generate_cover_poll();
-        let token2 = client.generate_cover_poll();
+        let drop_id = client.derive_drop_id(b"recipient_public_key");
+        let access_token = client.derive_access_token(b"shared_secret_for_access");
+        let message_id = node.store_message(drop_id, access_token, b"lost".to_vec()).unwrap();
+
+        // The reply to this fetch is lost
+        let lost = node.fetch_messages(&access_token, 10).unwrap();
+        assert_eq!(lost.messages[0].id, message_id);
+
+        let retry = node.fetch_messages(&access_token, 10).unwrap();
+        assert_eq!(retry.messages.len(), 1);
+        assert_eq!(retry.messages[0].id, message_id);
+
+        // A late acknowledgment under the expired lease is ignored
+        let acked = node.ack_messages(&access_token, &lost.lease_id, &[message_id]).unwrap();
+        assert!(acked.is_empty());
+        assert_eq!(node.stats().total_messages, 1);
+    }
+
+    #[test]
+    fn test_replica_and_restart() {
+        use crate::drop_store::LogStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drops.log");
+        let config = DeadDropConfig {
+            stamps: StampPolicy {
+                work_bits: 8,
+                ..StampPolicy::default()
+            },
+            ..DeadDropConfig::default()
+        };
+        let client = DeadDropClient::new(config.clone());
+
+        let drop_id = client.derive_drop_id(b"recipient_public_key");
+        let access_token = client.derive_access_token(b"shared_secret_for_access");
+
+        // A message stored at one node is copied to a sibling with its ID
+        let mut origin = DeadDropNode::new(config.clone());
+        let context = store_context(&drop_id, b"replicated");
+        let stamp = StoreStamp::Work(WorkStamp::mint(&context, 8, unix_now()));
+        let message = origin
+            .store_stamped(drop_id, access_token, b"replicated".to_vec(), Some(&stamp))
+            .unwrap();
+
+        let store = LogStore::open(&path, &[7u8; 32]).unwrap();
+        let mut sibling = DeadDropNode::with_store(config.clone(), Box::new(store));
+        assert!(sibling
+            .store_replica(drop_id, access_token, message.clone(), None)
+            .is_err());
+        sibling
+            .store_replica(drop_id, access_token, message.clone(), Some(&stamp))
+            .unwrap();
+
+        // A replayed copy spends the stamp again
+        assert!(sibling
+            .store_replica(drop_id, access_token, message.clone(), Some(&stamp))
+            .is_err());
+        assert_eq!(sibling.stats().total_messages, 1);
+
+        // Token stamps are keyed by the origin, so only their reuse is caught
+        let (request, blinded) = BlindedTokens::new(drop_id, 1, client.stamp_policy(), unix_now());
+        let batch = origin.issue_tokens(&drop_id, &request).unwrap();
+        let token = blinded.finalize(&batch).unwrap().remove(0).redeem(b"tokened");
+        let tokened = origin
+            .store_stamped(drop_id, access_token, b"tokened".to_vec(), Some(&token))
+            .unwrap();
+        sibling
+            .store_replica(drop_id, access_token, tokened.clone(), Some(&token))
+            .unwrap();
+        assert!(sibling
+            .store_replica(drop_id, access_token, tokened.clone(), Some(&token))
+            .is_err());
+        origin.remove_messages(&access_token, &[tokened.id]).unwrap();
+        sibling.remove_messages(&access_token, &[tokened.id]).unwrap();
+        drop(sibling);
+
+        // The sibling still holds it after a restart
+        let store = LogStore::open(&path, &[7u8; 32]).unwrap();
+        let mut sibling = DeadDropNode::with_store(config, Box::new(store));
+        assert_eq!(sibling.stats().total_messages, 1);
+
+        // Retrieval at the origin deletes the copy
+        let retrieved = origin.retrieve_messages(&access_token).unwrap();
+        assert_eq!(retrieved[0].id, message.id);
+        assert_eq!(sibling.remove_messages(&access_token, &[message.id]).unwrap(), 1);
+        assert!(sibling.retrieve_messages(&access_token).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_replica_ttl_clamped() {
+        let config = DeadDropConfig {
+            stamps: StampPolicy {
+                work_bits: 0,
+                ..StampPolicy::default()
+            },
+            ..DeadDropConfig::default()
+        };
+        let client = DeadDropClient::new(config.clone());
+        let drop_id = client.derive_drop_id(b"recipient_public_key");
+        let access_token = client.derive_access_token(b"shared_secret_for_access");
+
+        // Would overflow `stored_at + ttl` and never expire
+        let message = StoredMessage {
+            id: [1u8; 16],
+            payload: b"forever".to_vec(),
+            stored_at: u64::MAX,
+            ttl: u64::MAX,
+        };
+        assert!(!message.is_expired());
+
+        let mut node = DeadDropNode::new(config.clone());
+        node.store_replica(drop_id, access_token, message, None).unwrap();
+
+        let stored = node.retrieve_messages(&access_token).unwrap();
+        assert_eq!(stored[0].ttl, config.message_ttl);
+        assert!(stored[0].time_until_expiry().unwrap() <= config.message_ttl);
+    }
+
+    #[test]
+    fn test_cover_polls() {
+        let config = DeadDropConfig::default();
+        let client = DeadDropClient::new(config.clone());
+
+        // Cover polls look like a real contact's: the same number of tokens,
+        // drawn from a small set of decoys that is stable within the epoch
+        let start = config.drop_schedule.current_epoch();
+        let mut real = client.drop_ratchet(b"shared", start).unwrap();
+        let real_count = real.poll_addresses(SystemTime::now()).unwrap().len();
+
+        let mut decoys = HashSet::new();
+        for _ in 0..64 {
+            let tokens = client.generate_cover_poll();
+            assert_eq!(tokens.len(), real_count);
+            decoys.insert(tokens);
+        }
+        assert!(decoys.len() > 1);
+        assert!(decoys.len() <= COVER_CONTACTS);
+    }
+
+    fn drop_schedule() -> EpochSchedule {
+        EpochSchedule {
+            genesis: 1_000,
+            epoch_duration: Duration::from_secs(100),
+            grace_period: Duration::from_secs(150),
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_drop_ratchet_agreement() {
+        let mut sender = DropRatchet::new(b"conversation secret", 1, drop_schedule()).unwrap();
+        let mut recipient = DropRatchet::new(b"conversation secret", 1, drop_schedule()).unwrap();
+        let mut stranger = DropRatchet::new(b"other secret", 1, drop_schedule()).unwrap();
+
+        let first = sender.send_address(at(1_150)).unwrap();
+        assert_eq!(first.epoch, 1);
+        assert_eq!(recipient.poll_addresses(at(1_150)).unwrap(), vec![first]);
+        assert_ne!(stranger.send_address(at(1_150)).unwrap(), first);
+
+        // New epoch, unrelated address
+        let second = sender.send_address(at(1_250)).unwrap();
+        assert_eq!(second.epoch, 2);
+        assert_ne!(second.drop_id, first.drop_id);
+        assert_ne!(second.access_token, first.access_token);
+
+        // Skipping epochs still agrees
+        let fifth = sender.send_address(at(1_550)).unwrap();
+        let polled = recipient.poll_addresses(at(1_550)).unwrap();
+        assert_eq!(polled.last(), Some(&fifth));
+    }
+
+    #[test]
+    fn test_drop_ratchet_grace_window() {
+        let mut ratchet = DropRatchet::new(b"conversation secret", 1, drop_schedule()).unwrap();
+        let first = ratchet.send_address(at(1_150)).unwrap();
+
+        // Epoch 1 ends at 1200 and stays pollable until 1350
+        let polled = ratchet.poll_addresses(at(1_340)).unwrap();
+        assert_eq!(polled.iter().map(|a| a.epoch).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(polled[0], first);
+
+        let polled = ratchet.poll_addresses(at(1_350)).unwrap();
+        assert_eq!(polled.iter().map(|a| a.epoch).collect::<Vec<_>>(), vec![2, 3]);
 
-        // Should be random and different
-        assert_ne!(token1, token2);
+        // Erased for good
+        assert!(ratchet.send_address(at(1_150)).is_err());
     }
 
     #[test]
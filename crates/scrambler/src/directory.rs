@@ -0,0 +1,590 @@
+//! Mixnet Directory
+//!
+//! Signed relay descriptors and the consensus document clients build routes
+//! from.
+//!
+//! ## Architecture
+//!
+//! - **Relay Descriptors:** Each relay signs its layer, address, location,
+//!   epoch keys and bandwidth with its Ed25519 identity key
+//! - **Consensus:** Directory authorities collect descriptors into a consensus
+//!   document with a validity window, and each authority signs it
+//! - **Directory Client:** Verifies the consensus against a threshold of known
+//!   authorities, caches it on disk and refreshes it once it goes stale
+//!
+//! ## Security Properties
+//!
+//! - **Authenticated Membership:** Only relays listed in a consensus signed by
+//!   enough trusted authorities are used for routes
+//! - **Authenticated Keys:** Epoch keys are bound to the relay's identity, so a
+//!   directory mirror can't substitute its own keys
+//! - **Freshness:** Expired consensus documents are rejected, and a cached
+//!   consensus is never replaced by an older one
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use invisible_crypto::keys::IdentityKey;
+
+use crate::epoch::{EpochKey, EpochSchedule};
+use crate::error::{Result, ScramblerError};
+use crate::mixnet::{GeoLocation, Layer, MixNode, NodeId, NUM_LAYERS};
+
+/// Domain separator for relay descriptor signatures
+const DESCRIPTOR_CONTEXT: &[u8] = b"InvisibleRelayDescriptor-v1";
+
+/// Domain separator for consensus signatures
+const CONSENSUS_CONTEXT: &[u8] = b"InvisibleConsensus-v1";
+
+/// Relay self-description
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayDescriptor {
+    /// Ed25519 identity key of the relay
+    pub identity_key: Vec<u8>,
+    /// Network layer (0-4)
+    pub layer: Layer,
+    /// Network address (IP:port)
+    pub address: String,
+    /// Geographic location
+    pub location: GeoLocation,
+    /// Published Sphinx keys, one per upcoming epoch
+    pub epoch_keys: Vec<EpochKey>,
+    /// Advertised bandwidth (bytes/s)
+    pub bandwidth: u64,
+    /// Operator running the relay, if declared
+    ///
+    /// Clients never put two relays of the same operator on one route.
+    pub operator: Option<String>,
+    /// Unix time (seconds) the descriptor was published
+    pub published: u64,
+}
+
+impl RelayDescriptor {
+    /// Node identifier: SHA-256 of the identity key
+    pub fn node_id(&self) -> NodeId {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &self.identity_key);
+
+        let mut id = [0u8; 32];
+        id.copy_from_slice(digest.as_ref());
+        id
+    }
+
+    /// Sign the descriptor with the relay's identity key
+    pub fn sign(self, identity: &IdentityKey) -> Result<SignedDescriptor> {
+        if identity.public_key() != self.identity_key.as_slice() {
+            return Err(ScramblerError::CryptoError(
+                "Descriptor identity key doesn't match signing key".to_string(),
+            ));
+        }
+
+        let signature = identity.sign(&signed_bytes(DESCRIPTOR_CONTEXT, &self)?)?;
+
+        Ok(SignedDescriptor {
+            descriptor: self,
+            signature,
+        })
+    }
+}
+
+/// Relay descriptor with the relay's signature
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedDescriptor {
+    /// Descriptor contents
+    pub descriptor: RelayDescriptor,
+    /// Ed25519 signature by `descriptor.identity_key`
+    pub signature: Vec<u8>,
+}
+
+impl SignedDescriptor {
+    /// Verify the relay's signature and the descriptor's contents
+    pub fn verify(&self) -> Result<()> {
+        let descriptor = &self.descriptor;
+
+        IdentityKey::from_public(descriptor.identity_key.clone())
+            .verify(&signed_bytes(DESCRIPTOR_CONTEXT, descriptor)?, &self.signature)?;
+
+        if descriptor.layer >= NUM_LAYERS {
+            return Err(ScramblerError::ConfigError(format!(
+                "Invalid relay layer: {}",
+                descriptor.layer
+            )));
+        }
+
+        // Route diversity is judged by subnet, so hostnames aren't accepted
+        if descriptor.address.parse::<SocketAddr>().is_err() {
+            return Err(ScramblerError::ConfigError(format!(
+                "Relay address is not an IP socket address: {}",
+                descriptor.address
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Convert to the mix node used for route selection
+    pub fn to_mix_node(&self) -> MixNode {
+        let descriptor = &self.descriptor;
+
+        MixNode {
+            id: descriptor.node_id(),
+            layer: descriptor.layer,
+            epoch_keys: descriptor.epoch_keys.clone(),
+            address: descriptor.address.clone(),
+            location: descriptor.location.clone(),
+            bandwidth: descriptor.bandwidth,
+            operator: descriptor.operator.clone(),
+        }
+    }
+}
+
+/// Consensus document listing the relays of the mixnet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusDocument {
+    /// Unix time (seconds) from which the consensus is valid
+    pub valid_after: u64,
+    /// Unix time (seconds) after which clients should fetch a newer consensus
+    pub fresh_until: u64,
+    /// Unix time (seconds) after which the consensus must not be used
+    pub valid_until: u64,
+    /// Key rotation schedule of the listed relays
+    pub epoch_schedule: EpochSchedule,
+    /// Relay descriptors
+    pub relays: Vec<SignedDescriptor>,
+}
+
+impl ConsensusDocument {
+    /// Assemble a consensus from submitted descriptors
+    ///
+    /// Descriptors with bad signatures are skipped, as are all but the most
+    /// recently published descriptor of each relay.
+    ///
+    /// # Arguments
+    /// * `descriptors` - Descriptors submitted by relays
+    /// * `epoch_schedule` - Key rotation schedule of the relays
+    /// * `valid_after` - Start of the validity window
+    /// * `fresh_for` - How long clients may use it before refreshing
+    /// * `valid_for` - How long it may be used at all
+    pub fn build(
+        mut descriptors: Vec<SignedDescriptor>,
+        epoch_schedule: EpochSchedule,
+        valid_after: SystemTime,
+        fresh_for: Duration,
+        valid_for: Duration,
+    ) -> Self {
+        // Newest first, so the dedup below keeps each relay's latest descriptor
+        descriptors.sort_by_key(|relay| std::cmp::Reverse(relay.descriptor.published));
+
+        let mut seen = HashSet::new();
+        let relays = descriptors
+            .into_iter()
+            .filter(|relay| match relay.verify() {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Skipping invalid relay descriptor");
+                    false
+                }
+            })
+            .filter(|relay| seen.insert(relay.descriptor.node_id()))
+            .collect();
+
+        let valid_after = unix_secs(valid_after);
+
+        Self {
+            valid_after,
+            fresh_until: valid_after.saturating_add(fresh_for.as_secs()),
+            valid_until: valid_after.saturating_add(valid_for.as_secs()),
+            epoch_schedule,
+            relays,
+        }
+    }
+
+    /// Check whether the consensus may be used at `now`
+    pub fn is_valid_at(&self, now: SystemTime) -> bool {
+        let now = unix_secs(now);
+        self.valid_after <= now && now < self.valid_until
+    }
+
+    /// Mix nodes listed in the consensus
+    pub fn mix_nodes(&self) -> Vec<MixNode> {
+        self.relays.iter().map(SignedDescriptor::to_mix_node).collect()
+    }
+}
+
+/// One directory authority's signature over a consensus
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthoritySignature {
+    /// Ed25519 identity key of the authority
+    pub authority_key: Vec<u8>,
+    /// Signature over the consensus document
+    pub signature: Vec<u8>,
+}
+
+/// Consensus document with authority signatures
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedConsensus {
+    /// Consensus contents
+    pub document: ConsensusDocument,
+    /// Signatures by directory authorities
+    pub signatures: Vec<AuthoritySignature>,
+}
+
+impl SignedConsensus {
+    /// Wrap an unsigned consensus document
+    pub fn new(document: ConsensusDocument) -> Self {
+        Self {
+            document,
+            signatures: Vec::new(),
+        }
+    }
+
+    /// Add a directory authority's signature
+    pub fn sign(&mut self, authority: &IdentityKey) -> Result<()> {
+        let signature = authority.sign(&signed_bytes(CONSENSUS_CONTEXT, &self.document)?)?;
+
+        self.signatures.push(AuthoritySignature {
+            authority_key: authority.public_key().to_vec(),
+            signature,
+        });
+
+        Ok(())
+    }
+}
+
+/// Trusted directory authorities
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryAuthorities {
+    /// Ed25519 identity keys of the authorities
+    pub keys: Vec<Vec<u8>>,
+    /// Number of distinct authority signatures a consensus needs
+    pub threshold: usize,
+}
+
+impl DirectoryAuthorities {
+    /// Verify a consensus
+    ///
+    /// Checks that at least `threshold` distinct trusted authorities signed it,
+    /// that it's valid at `now`, and that every listed descriptor is signed by
+    /// its relay.
+    pub fn verify(&self, consensus: &SignedConsensus, now: SystemTime) -> Result<()> {
+        if self.threshold == 0 || self.threshold > self.keys.len() {
+            return Err(ScramblerError::ConfigError(format!(
+                "Invalid authority threshold: {} of {}",
+                self.threshold,
+                self.keys.len()
+            )));
+        }
+
+        let document = &consensus.document;
+        let message = signed_bytes(CONSENSUS_CONTEXT, document)?;
+
+        let mut signers = HashSet::new();
+        for signature in &consensus.signatures {
+            if !self.keys.contains(&signature.authority_key) || signers.contains(&signature.authority_key) {
+                continue;
+            }
+
+            let authority = IdentityKey::from_public(signature.authority_key.clone());
+            if authority.verify(&message, &signature.signature).is_ok() {
+                signers.insert(signature.authority_key.clone());
+            }
+        }
+
+        if signers.len() < self.threshold {
+            return Err(ScramblerError::CryptoError(format!(
+                "Consensus signed by {} of {} required authorities",
+                signers.len(),
+                self.threshold
+            )));
+        }
+
+        if !document.is_valid_at(now) {
+            return Err(ScramblerError::ConfigError(
+                "Consensus is not valid at this time".to_string(),
+            ));
+        }
+
+        let mut ids = HashSet::new();
+        for relay in &document.relays {
+            relay.verify()?;
+
+            if !ids.insert(relay.descriptor.node_id()) {
+                return Err(ScramblerError::ConfigError(
+                    "Consensus lists a relay twice".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Client-side directory state
+///
+/// Holds the latest verified consensus and optionally caches it on disk so a
+/// restart doesn't require a directory fetch.
+#[derive(Debug)]
+pub struct DirectoryClient {
+    authorities: DirectoryAuthorities,
+    consensus: Option<SignedConsensus>,
+    /// Consensus cache file (in-memory only if unset)
+    cache_path: Option<PathBuf>,
+}
+
+impl DirectoryClient {
+    /// Create a directory client, loading a cached consensus if `cache_path` is set
+    ///
+    /// A cached consensus that no longer verifies is discarded.
+    pub fn open(authorities: DirectoryAuthorities, cache_path: Option<PathBuf>) -> Result<Self> {
+        let consensus = match &cache_path {
+            Some(path) => load_cached(path)?.filter(|consensus| {
+                match authorities.verify(consensus, SystemTime::now()) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        tracing::info!(error = %e, "Discarding cached consensus");
+                        false
+                    }
+                }
+            }),
+            None => None,
+        };
+
+        Ok(Self {
+            authorities,
+            consensus,
+            cache_path,
+        })
+    }
+
+    /// Replace the current consensus with a newer one
+    ///
+    /// # Arguments
+    /// * `consensus` - Consensus fetched from a directory server
+    /// * `now` - Current time
+    pub fn update(&mut self, consensus: SignedConsensus, now: SystemTime) -> Result<()> {
+        self.authorities.verify(&consensus, now)?;
+
+        if let Some(current) = &self.consensus {
+            if consensus.document.valid_after < current.document.valid_after {
+                return Err(ScramblerError::ConfigError(
+                    "Consensus is older than the cached one".to_string(),
+                ));
+            }
+        }
+
+        if let Some(path) = &self.cache_path {
+            store_cached(path, &consensus)?;
+        }
+
+        tracing::info!(
+            relays = consensus.document.relays.len(),
+            valid_after = consensus.document.valid_after,
+            "Directory consensus updated"
+        );
+
+        self.consensus = Some(consensus);
+        Ok(())
+    }
+
+    /// Check whether a newer consensus should be fetched
+    pub fn needs_refresh(&self, now: SystemTime) -> bool {
+        match &self.consensus {
+            Some(consensus) => unix_secs(now) >= consensus.document.fresh_until,
+            None => true,
+        }
+    }
+
+    /// Consensus usable at `now`, if any
+    pub fn consensus(&self, now: SystemTime) -> Option<&ConsensusDocument> {
+        self.consensus
+            .as_ref()
+            .map(|consensus| &consensus.document)
+            .filter(|document| document.is_valid_at(now))
+    }
+}
+
+/// Bytes covered by a signature: domain separator followed by the encoded value
+fn signed_bytes<T: Serialize>(context: &[u8], value: &T) -> Result<Vec<u8>> {
+    let encoded = bincode::serialize(value)
+        .map_err(|e| ScramblerError::ConfigError(format!("Serialization failed: {}", e)))?;
+
+    let mut bytes = Vec::with_capacity(context.len() + encoded.len());
+    bytes.extend_from_slice(context);
+    bytes.extend_from_slice(&encoded);
+    Ok(bytes)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn load_cached(path: &Path) -> Result<Option<SignedConsensus>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(io_error("read", path, e)),
+    };
+
+    match bincode::deserialize(&bytes) {
+        Ok(consensus) => Ok(Some(consensus)),
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "Ignoring corrupt consensus cache");
+            Ok(None)
+        }
+    }
+}
+
+/// Atomically replace the consensus cache
+fn store_cached(path: &Path, consensus: &SignedConsensus) -> Result<()> {
+    let bytes = bincode::serialize(consensus)
+        .map_err(|e| ScramblerError::ConfigError(format!("Serialization failed: {}", e)))?;
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, &bytes).map_err(|e| io_error("write", &tmp_path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| io_error("rename", path, e))?;
+
+    Ok(())
+}
+
+fn io_error(action: &str, path: &Path, e: std::io::Error) -> ScramblerError {
+    ScramblerError::ConfigError(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mixnet::Jurisdiction;
+
+    fn descriptor(identity: &IdentityKey, layer: Layer, published: u64) -> SignedDescriptor {
+        RelayDescriptor {
+            identity_key: identity.public_key().to_vec(),
+            layer,
+            address: format!("10.0.0.{}:9000", layer),
+            location: GeoLocation {
+                country: "CH".to_string(),
+                jurisdiction: Jurisdiction::PrivacyFriendly,
+            },
+            epoch_keys: vec![EpochKey {
+                epoch: 0,
+                public_key: vec![layer; 32],
+            }],
+            bandwidth: 1_000_000,
+            operator: None,
+            published,
+        }
+        .sign(identity)
+        .unwrap()
+    }
+
+    fn consensus(relays: Vec<SignedDescriptor>, valid_after: SystemTime) -> ConsensusDocument {
+        ConsensusDocument::build(
+            relays,
+            EpochSchedule::default(),
+            valid_after,
+            Duration::from_secs(3600),
+            Duration::from_secs(3 * 3600),
+        )
+    }
+
+    fn authorities(count: usize, threshold: usize) -> (Vec<IdentityKey>, DirectoryAuthorities) {
+        let keys: Vec<_> = (0..count).map(|_| IdentityKey::generate().unwrap()).collect();
+        let trusted = DirectoryAuthorities {
+            keys: keys.iter().map(|key| key.public_key().to_vec()).collect(),
+            threshold,
+        };
+        (keys, trusted)
+    }
+
+    #[test]
+    fn test_descriptor_signature() {
+        let identity = IdentityKey::generate().unwrap();
+        let mut signed = descriptor(&identity, 2, 0);
+        assert!(signed.verify().is_ok());
+        assert_eq!(signed.to_mix_node().id, signed.descriptor.node_id());
+
+        // Tampering with the advertised keys breaks the signature
+        signed.descriptor.epoch_keys[0].public_key = vec![0xff; 32];
+        assert!(signed.verify().is_err());
+
+        // Hostnames are rejected even when properly signed
+        let mut hostname = descriptor(&identity, 2, 0).descriptor;
+        hostname.address = "mix.example.org:9000".to_string();
+        assert!(hostname.sign(&identity).unwrap().verify().is_err());
+    }
+
+    #[test]
+    fn test_build_keeps_latest_descriptor() {
+        let identity = IdentityKey::generate().unwrap();
+        let other = IdentityKey::generate().unwrap();
+
+        let mut forged = descriptor(&other, 1, 10);
+        forged.descriptor.layer = 3;
+
+        let document = consensus(
+            vec![descriptor(&identity, 0, 5), descriptor(&identity, 4, 9), forged],
+            SystemTime::now(),
+        );
+
+        assert_eq!(document.relays.len(), 1);
+        assert_eq!(document.relays[0].descriptor.layer, 4);
+    }
+
+    #[test]
+    fn test_consensus_threshold() {
+        let (keys, trusted) = authorities(3, 2);
+        let now = SystemTime::now();
+        let relay = IdentityKey::generate().unwrap();
+
+        let mut signed = SignedConsensus::new(consensus(vec![descriptor(&relay, 0, 0)], now));
+        signed.sign(&keys[0]).unwrap();
+
+        // A duplicate or untrusted signature doesn't count towards the threshold
+        signed.sign(&keys[0]).unwrap();
+        signed.sign(&IdentityKey::generate().unwrap()).unwrap();
+        assert!(trusted.verify(&signed, now).is_err());
+
+        signed.sign(&keys[1]).unwrap();
+        assert!(trusted.verify(&signed, now).is_ok());
+
+        // Any change to the document invalidates the signatures
+        let mut tampered = signed.clone();
+        tampered.document.valid_until += 3600;
+        assert!(trusted.verify(&tampered, now).is_err());
+
+        // Expired consensus is rejected
+        assert!(trusted.verify(&signed, now + Duration::from_secs(4 * 3600)).is_err());
+    }
+
+    #[test]
+    fn test_client_refresh_and_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.bin");
+        let (keys, trusted) = authorities(1, 1);
+        let relay = IdentityKey::generate().unwrap();
+        let now = SystemTime::now();
+
+        let mut client = DirectoryClient::open(trusted.clone(), Some(path.clone())).unwrap();
+        assert!(client.needs_refresh(now));
+        assert!(client.consensus(now).is_none());
+
+        let mut signed = SignedConsensus::new(consensus(vec![descriptor(&relay, 0, 0)], now));
+        signed.sign(&keys[0]).unwrap();
+        client.update(signed, now).unwrap();
+
+        assert!(!client.needs_refresh(now));
+        assert!(client.needs_refresh(now + Duration::from_secs(3600)));
+        assert_eq!(client.consensus(now).unwrap().mix_nodes().len(), 1);
+
+        // An older consensus doesn't replace the current one
+        let mut older = SignedConsensus::new(consensus(Vec::new(), now - Duration::from_secs(60)));
+        older.sign(&keys[0]).unwrap();
+        assert!(client.update(older, now).is_err());
+
+        // The cached consensus is picked up after a restart
+        let client = DirectoryClient::open(trusted, Some(path)).unwrap();
+        assert_eq!(client.consensus(now).unwrap().relays.len(), 1);
+    }
+}
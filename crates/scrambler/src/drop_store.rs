@@ -0,0 +1,786 @@
+//! Dead Drop Storage
+//!
+//! Storage backends for the messages a relay holds in its dead drops.
+//!
+//! ## Architecture
+//!
+//! - **DropStore:** Backend interface used by `DeadDropNode`; capacity and
+//!   retrieval policy stay in the node
+//! - **MemoryStore:** Messages and access tokens in memory, lost on restart
+//! - **LogStore:** `MemoryStore` backed by an append-only encrypted log that
+//!   is replayed on startup and compacted once mostly stale
+//! - **Expiry Index:** Messages and tokens are indexed by expiry time, so
+//!   expiring them only touches what has expired
+//!
+//! ## Security Properties
+//!
+//! - **Encrypted at Rest:** Each log record is sealed with XSalsa20-Poly1305
+//!   under a random nonce; drop IDs, tokens and payloads never hit the disk
+//!   in the clear
+//! - **Crash Recovery:** Each record is synced before the change it logs is
+//!   acknowledged. A record cut short at the end of the file by a crash is
+//!   truncated away; any other unreadable record (a wrong key, corruption
+//!   with records after it) fails `LogStore::open` instead of being dropped
+//! - **Bounded Retention:** Expired messages are dropped on load and never
+//!   carried into a compacted log
+
+use crypto_secretbox::aead::{Aead, KeyInit};
+use crypto_secretbox::{Nonce, XSalsa20Poly1305};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::dead_drop::{unix_now, AccessToken, DeadDropStats, DropId, StoredMessage};
+use crate::error::{Result, ScramblerError};
+
+/// Dead drop message identifier
+pub type MessageId = [u8; 16];
+
+/// Secretbox nonce length
+const NONCE_SIZE: usize = 24;
+
+/// Records a log may hold beyond twice its live entries before compaction
+const COMPACT_SLACK: usize = 1024;
+
+/// Storage backend for dead drop messages
+pub trait DropStore: Debug + Send {
+    /// Add a message to a drop and register the token that retrieves it
+    ///
+    /// A message whose ID the drop already holds is not stored again, but
+    /// the token is still registered.
+    fn insert(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        message: StoredMessage,
+    ) -> Result<()>;
+
+    /// Drop that a token retrieves, if the token is registered
+    fn drop_for_token(&self, access_token: &AccessToken) -> Option<DropId>;
+
+    /// Messages held in a drop, oldest first
+    fn messages(&self, drop_id: &DropId) -> Vec<StoredMessage>;
+
+    /// Number of messages held in a drop
+    fn message_count(&self, drop_id: &DropId) -> usize;
+
+    /// Remove messages from a drop
+    ///
+    /// # Returns
+    /// * Number of messages removed
+    fn remove(&mut self, drop_id: &DropId, message_ids: &[MessageId]) -> Result<usize>;
+
+    /// Remove messages and tokens that expired by `now` (Unix seconds)
+    ///
+    /// # Returns
+    /// * Number of messages removed
+    fn expire(&mut self, now: u64) -> Result<usize>;
+
+    /// Number of drops and messages held
+    fn stats(&self) -> DeadDropStats;
+}
+
+/// In-memory dead drop storage
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    /// Drop ID -> Messages
+    drops: HashMap<DropId, Vec<StoredMessage>>,
+    /// Access token -> Drop ID and expiry of the latest message it stored
+    access_tokens: HashMap<AccessToken, (DropId, u64)>,
+    /// Message expiry index
+    message_expiry: BTreeSet<(u64, MessageId, DropId)>,
+    /// Token expiry index
+    token_expiry: BTreeSet<(u64, AccessToken)>,
+}
+
+impl MemoryStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a message to a drop unless it already holds the ID
+    fn insert_message(&mut self, drop_id: DropId, message: StoredMessage) -> bool {
+        let messages = self.drops.entry(drop_id).or_default();
+        if messages.iter().any(|m| m.id == message.id) {
+            return false;
+        }
+
+        self.message_expiry
+            .insert((expiry(&message), message.id, drop_id));
+        messages.push(message);
+        true
+    }
+
+    /// Register a token, keeping it until the later of its current and new expiry
+    fn register_token(&mut self, access_token: AccessToken, drop_id: DropId, expires_at: u64) {
+        let expires_at = match self.access_tokens.get(&access_token) {
+            Some((_, current)) if *current >= expires_at => *current,
+            Some((_, current)) => {
+                self.token_expiry.remove(&(*current, access_token));
+                expires_at
+            }
+            None => expires_at,
+        };
+
+        self.access_tokens
+            .insert(access_token, (drop_id, expires_at));
+        self.token_expiry.insert((expires_at, access_token));
+    }
+
+    /// Number of entries a snapshot of the store holds
+    fn entries(&self) -> usize {
+        self.message_expiry.len() + self.access_tokens.len()
+    }
+}
+
+impl DropStore for MemoryStore {
+    fn insert(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        message: StoredMessage,
+    ) -> Result<()> {
+        let expires_at = expiry(&message);
+        self.insert_message(drop_id, message);
+        self.register_token(access_token, drop_id, expires_at);
+
+        Ok(())
+    }
+
+    fn drop_for_token(&self, access_token: &AccessToken) -> Option<DropId> {
+        self.access_tokens
+            .get(access_token)
+            .map(|(drop_id, _)| *drop_id)
+    }
+
+    fn messages(&self, drop_id: &DropId) -> Vec<StoredMessage> {
+        self.drops.get(drop_id).cloned().unwrap_or_default()
+    }
+
+    fn message_count(&self, drop_id: &DropId) -> usize {
+        self.drops.get(drop_id).map_or(0, Vec::len)
+    }
+
+    fn remove(&mut self, drop_id: &DropId, message_ids: &[MessageId]) -> Result<usize> {
+        let messages = match self.drops.get_mut(drop_id) {
+            Some(messages) => messages,
+            None => return Ok(0),
+        };
+
+        let before = messages.len();
+        for message in messages.iter().filter(|m| message_ids.contains(&m.id)) {
+            self.message_expiry
+                .remove(&(expiry(message), message.id, *drop_id));
+        }
+        messages.retain(|m| !message_ids.contains(&m.id));
+        let removed = before - messages.len();
+
+        if messages.is_empty() {
+            self.drops.remove(drop_id);
+        }
+
+        Ok(removed)
+    }
+
+    fn expire(&mut self, now: u64) -> Result<usize> {
+        let mut removed = 0;
+
+        while let Some(&(expires_at, id, drop_id)) = self.message_expiry.first() {
+            if expires_at > now {
+                break;
+            }
+            self.message_expiry.pop_first();
+
+            if let Some(messages) = self.drops.get_mut(&drop_id) {
+                messages.retain(|m| m.id != id);
+                if messages.is_empty() {
+                    self.drops.remove(&drop_id);
+                }
+            }
+            removed += 1;
+        }
+
+        while let Some(&(expires_at, access_token)) = self.token_expiry.first() {
+            if expires_at > now {
+                break;
+            }
+            self.token_expiry.pop_first();
+            self.access_tokens.remove(&access_token);
+        }
+
+        Ok(removed)
+    }
+
+    fn stats(&self) -> DeadDropStats {
+        DeadDropStats {
+            total_drops: self.drops.len(),
+            total_messages: self.message_expiry.len(),
+        }
+    }
+}
+
+/// Log record
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord {
+    /// Message stored under a token
+    Store {
+        drop_id: DropId,
+        access_token: AccessToken,
+        message: StoredMessage,
+    },
+    /// Message carried over by compaction
+    Message {
+        drop_id: DropId,
+        message: StoredMessage,
+    },
+    /// Token carried over by compaction
+    Token {
+        access_token: AccessToken,
+        drop_id: DropId,
+        expires_at: u64,
+    },
+    /// Messages removed from a drop
+    Remove {
+        drop_id: DropId,
+        message_ids: Vec<MessageId>,
+    },
+}
+
+/// Dead drop storage persisted in an encrypted append-only log
+///
+/// Every change is appended as a sealed record of
+/// `length (4 bytes) | nonce (24 bytes) | ciphertext`. Expiry isn't logged:
+/// expired messages are skipped when the log is replayed.
+pub struct LogStore {
+    memory: MemoryStore,
+    path: PathBuf,
+    file: File,
+    cipher: XSalsa20Poly1305,
+    /// Records in the log file
+    records: usize,
+}
+
+impl Debug for LogStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogStore")
+            .field("path", &self.path)
+            .field("records", &self.records)
+            .field("stats", &self.memory.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+impl LogStore {
+    /// Open the log at `path`, replaying the messages it holds
+    ///
+    /// The file is created if it doesn't exist. A record cut short at the end
+    /// of the file by a crash is truncated away. Fails if the first record
+    /// doesn't authenticate (most likely the wrong key), or if a record
+    /// doesn't and readable records follow it.
+    ///
+    /// # Arguments
+    /// * `path` - Log file
+    /// * `key` - Key the records are sealed with
+    pub fn open(path: impl AsRef<Path>, key: &[u8; 32]) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let cipher = XSalsa20Poly1305::new(key.into());
+
+        let mut bytes = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut bytes)
+                    .map_err(|e| io_error("read", &path, e))?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_error("open", &path, e)),
+        }
+
+        let mut memory = MemoryStore::new();
+        let mut records = 0;
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match open_record(&cipher, &bytes[offset..]) {
+                Ok((record, len)) => {
+                    apply(&mut memory, record);
+                    records += 1;
+                    offset += len;
+                }
+                Err(RecordError::Invalid) if records == 0 => {
+                    return Err(ScramblerError::StorageError(format!(
+                        "Dead drop log {} doesn't open with this key",
+                        path.display()
+                    )));
+                }
+                Err(_) if readable_record_after(&cipher, &bytes[offset + 1..]) => {
+                    return Err(ScramblerError::StorageError(format!(
+                        "Dead drop log {} is corrupt at offset {}",
+                        path.display(),
+                        offset
+                    )));
+                }
+                Err(_) => break,
+            }
+        }
+        let torn = offset < bytes.len();
+        let expired = memory.expire(unix_now())?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| io_error("open", &path, e))?;
+
+        // Cut the record a crash left half-written, so later appends stay aligned
+        if torn {
+            tracing::warn!(
+                path = %path.display(),
+                discarded = bytes.len() - offset,
+                "Truncating torn dead drop log record"
+            );
+            file.set_len(offset as u64)
+                .and_then(|()| file.sync_all())
+                .map_err(|e| io_error("truncate", &path, e))?;
+        }
+
+        let mut store = Self {
+            memory,
+            path,
+            file,
+            cipher,
+            records,
+        };
+        if expired > 0 {
+            store.compact_if_stale()?;
+        }
+
+        Ok(store)
+    }
+
+    /// Seal and durably append a record
+    fn append(&mut self, record: &LogRecord) -> Result<()> {
+        let sealed = seal_record(&self.cipher, record)?;
+        self.file
+            .write_all(&sealed)
+            .and_then(|()| self.file.sync_data())
+            .map_err(|e| io_error("write", &self.path, e))?;
+        self.records += 1;
+
+        Ok(())
+    }
+
+    /// Compact the log once most of its records no longer describe live entries
+    fn compact_if_stale(&mut self) -> Result<()> {
+        if self.records > 2 * self.memory.entries() + COMPACT_SLACK {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Atomically replace the log with a snapshot of the live entries
+    fn compact(&mut self) -> Result<()> {
+        let mut bytes = Vec::new();
+        let mut records = 0;
+        for (drop_id, messages) in &self.memory.drops {
+            for message in messages {
+                let record = LogRecord::Message {
+                    drop_id: *drop_id,
+                    message: message.clone(),
+                };
+                bytes.extend_from_slice(&seal_record(&self.cipher, &record)?);
+                records += 1;
+            }
+        }
+        for (access_token, (drop_id, expires_at)) in &self.memory.access_tokens {
+            let record = LogRecord::Token {
+                access_token: *access_token,
+                drop_id: *drop_id,
+                expires_at: *expires_at,
+            };
+            bytes.extend_from_slice(&seal_record(&self.cipher, &record)?);
+            records += 1;
+        }
+
+        // Sync the snapshot before it replaces the log, and the directory
+        // after, so a crash leaves one of the two complete
+        let tmp_path = self.path.with_extension("tmp");
+        File::create(&tmp_path)
+            .and_then(|mut tmp| {
+                tmp.write_all(&bytes)?;
+                tmp.sync_all()
+            })
+            .map_err(|e| io_error("write", &tmp_path, e))?;
+        fs::rename(&tmp_path, &self.path).map_err(|e| io_error("rename", &self.path, e))?;
+        sync_dir(&self.path)?;
+
+        self.file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| io_error("open", &self.path, e))?;
+        self.records = records;
+
+        Ok(())
+    }
+}
+
+impl DropStore for LogStore {
+    fn insert(
+        &mut self,
+        drop_id: DropId,
+        access_token: AccessToken,
+        message: StoredMessage,
+    ) -> Result<()> {
+        self.append(&LogRecord::Store {
+            drop_id,
+            access_token,
+            message: message.clone(),
+        })?;
+
+        self.memory.insert(drop_id, access_token, message)
+    }
+
+    fn drop_for_token(&self, access_token: &AccessToken) -> Option<DropId> {
+        self.memory.drop_for_token(access_token)
+    }
+
+    fn messages(&self, drop_id: &DropId) -> Vec<StoredMessage> {
+        self.memory.messages(drop_id)
+    }
+
+    fn message_count(&self, drop_id: &DropId) -> usize {
+        self.memory.message_count(drop_id)
+    }
+
+    fn remove(&mut self, drop_id: &DropId, message_ids: &[MessageId]) -> Result<usize> {
+        // Logged before memory changes, so a failed write leaves both as they were
+        let held = self.memory.messages(drop_id);
+        if !held.iter().any(|m| message_ids.contains(&m.id)) {
+            return Ok(0);
+        }
+        self.append(&LogRecord::Remove {
+            drop_id: *drop_id,
+            message_ids: message_ids.to_vec(),
+        })?;
+
+        let removed = self.memory.remove(drop_id, message_ids)?;
+        self.compact_if_stale()?;
+
+        Ok(removed)
+    }
+
+    fn expire(&mut self, now: u64) -> Result<usize> {
+        let removed = self.memory.expire(now)?;
+        if removed > 0 {
+            self.compact_if_stale()?;
+        }
+
+        Ok(removed)
+    }
+
+    fn stats(&self) -> DeadDropStats {
+        self.memory.stats()
+    }
+}
+
+/// Replay a log record into the in-memory store
+fn apply(memory: &mut MemoryStore, record: LogRecord) {
+    match record {
+        LogRecord::Store {
+            drop_id,
+            access_token,
+            message,
+        } => {
+            let expires_at = expiry(&message);
+            memory.insert_message(drop_id, message);
+            memory.register_token(access_token, drop_id, expires_at);
+        }
+        LogRecord::Message { drop_id, message } => {
+            memory.insert_message(drop_id, message);
+        }
+        LogRecord::Token {
+            access_token,
+            drop_id,
+            expires_at,
+        } => memory.register_token(access_token, drop_id, expires_at),
+        LogRecord::Remove {
+            drop_id,
+            message_ids,
+        } => {
+            // Removing from memory can't fail
+            let _ = memory.remove(&drop_id, &message_ids);
+        }
+    }
+}
+
+/// Encode and seal a record with its length prefix
+fn seal_record(cipher: &XSalsa20Poly1305, record: &LogRecord) -> Result<Vec<u8>> {
+    use rand::RngCore;
+
+    let plaintext = bincode::serialize(record)
+        .map_err(|e| ScramblerError::StorageError(format!("Serialization failed: {}", e)))?;
+
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| ScramblerError::StorageError("Record encryption failed".to_string()))?;
+
+    let mut sealed = Vec::with_capacity(4 + NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(&((NONCE_SIZE + ciphertext.len()) as u32).to_be_bytes());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+
+    Ok(sealed)
+}
+
+/// Why a log record couldn't be opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordError {
+    /// The file ends before the record does
+    Partial,
+    /// The record is complete but fails authentication or decoding
+    Invalid,
+}
+
+/// Open the record at the start of `bytes`
+///
+/// # Returns
+/// * The record and its encoded length
+fn open_record(
+    cipher: &XSalsa20Poly1305,
+    bytes: &[u8],
+) -> std::result::Result<(LogRecord, usize), RecordError> {
+    let prefix = bytes.get(..4).ok_or(RecordError::Partial)?;
+    let len = u32::from_be_bytes(prefix.try_into().expect("4 bytes")) as usize;
+    let body = bytes.get(4..4 + len).ok_or(RecordError::Partial)?;
+    if body.len() < NONCE_SIZE {
+        return Err(RecordError::Invalid);
+    }
+
+    let (nonce, ciphertext) = body.split_at(NONCE_SIZE);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| RecordError::Invalid)?;
+    let record = bincode::deserialize(&plaintext).map_err(|_| RecordError::Invalid)?;
+
+    Ok((record, 4 + len))
+}
+
+/// Whether a record that authenticates starts anywhere in `bytes`
+///
+/// A crash only ever tears the last record, so one after an unreadable
+/// record means the log is corrupt rather than torn.
+fn readable_record_after(cipher: &XSalsa20Poly1305, bytes: &[u8]) -> bool {
+    (0..bytes.len()).any(|start| open_record(cipher, &bytes[start..]).is_ok())
+}
+
+/// Time at which a message expires (Unix seconds)
+fn expiry(message: &StoredMessage) -> u64 {
+    message.stored_at.saturating_add(message.ttl)
+}
+
+/// Sync the directory holding `path` so a rename within it is durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| io_error("sync", dir, e))
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn io_error(action: &str, path: &Path, e: std::io::Error) -> ScramblerError {
+    ScramblerError::StorageError(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8, stored_at: u64, ttl: u64) -> StoredMessage {
+        StoredMessage {
+            id: [id; 16],
+            payload: vec![id; 8],
+            stored_at,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn test_memory_store_expiry_index() {
+        let mut store = MemoryStore::new();
+        store
+            .insert([1u8; 32], [10u8; 32], message(1, 100, 50))
+            .unwrap();
+        store
+            .insert([1u8; 32], [10u8; 32], message(2, 100, 200))
+            .unwrap();
+        store
+            .insert([2u8; 32], [20u8; 32], message(3, 100, 20))
+            .unwrap();
+
+        // Duplicate IDs aren't stored twice
+        store
+            .insert([1u8; 32], [10u8; 32], message(1, 100, 50))
+            .unwrap();
+        assert_eq!(store.message_count(&[1u8; 32]), 2);
+
+        assert_eq!(store.expire(119).unwrap(), 0);
+        assert_eq!(store.expire(150).unwrap(), 2);
+        assert_eq!(store.stats().total_drops, 1);
+        assert_eq!(store.messages(&[1u8; 32])[0].id, [2u8; 16]);
+
+        // Tokens live as long as the latest message they stored
+        assert_eq!(store.drop_for_token(&[20u8; 32]), None);
+        assert_eq!(store.drop_for_token(&[10u8; 32]), Some([1u8; 32]));
+        store.expire(300).unwrap();
+        assert_eq!(store.drop_for_token(&[10u8; 32]), None);
+        assert_eq!(store.stats().total_messages, 0);
+    }
+
+    #[test]
+    fn test_log_store_recovers_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drops.log");
+        let key = [7u8; 32];
+        let now = unix_now();
+
+        {
+            let mut store = LogStore::open(&path, &key).unwrap();
+            store
+                .insert([1u8; 32], [10u8; 32], message(1, now, 3600))
+                .unwrap();
+            store
+                .insert([1u8; 32], [10u8; 32], message(2, now, 3600))
+                .unwrap();
+            store
+                .insert([2u8; 32], [20u8; 32], message(3, now - 100, 10))
+                .unwrap();
+            assert_eq!(store.remove(&[1u8; 32], &[[1u8; 16]]).unwrap(), 1);
+        }
+
+        // Nothing readable on disk
+        let bytes = fs::read(&path).unwrap();
+        assert!(!bytes.windows(8).any(|w| w == [2u8; 8]));
+
+        let store = LogStore::open(&path, &key).unwrap();
+        assert_eq!(store.drop_for_token(&[10u8; 32]), Some([1u8; 32]));
+        let messages = store.messages(&[1u8; 32]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, [2u8; 16]);
+
+        // Expired while the relay was down
+        assert_eq!(store.drop_for_token(&[20u8; 32]), None);
+        assert_eq!(store.stats().total_messages, 1);
+
+        // Another key can't read the log, and doesn't wipe it either
+        let bytes = fs::read(&path).unwrap();
+        assert!(matches!(
+            LogStore::open(&path, &[8u8; 32]),
+            Err(ScramblerError::StorageError(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_log_store_corruption_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drops.log");
+        let key = [7u8; 32];
+        let now = unix_now();
+
+        {
+            let mut store = LogStore::open(&path, &key).unwrap();
+            for id in 1..=3 {
+                store
+                    .insert([1u8; 32], [10u8; 32], message(id, now, 3600))
+                    .unwrap();
+            }
+        }
+
+        // A flipped bit in the middle record, with a valid one after it
+        let mut bytes = fs::read(&path).unwrap();
+        let record_len = bytes.len() / 3;
+        bytes[record_len + 40] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            LogStore::open(&path, &key),
+            Err(ScramblerError::StorageError(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_log_store_torn_record_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drops.log");
+        let key = [7u8; 32];
+        let now = unix_now();
+
+        {
+            let mut store = LogStore::open(&path, &key).unwrap();
+            store
+                .insert([1u8; 32], [10u8; 32], message(1, now, 3600))
+                .unwrap();
+            store
+                .insert([1u8; 32], [10u8; 32], message(2, now, 3600))
+                .unwrap();
+        }
+
+        // Crash halfway through the second record
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 10]).unwrap();
+
+        let mut store = LogStore::open(&path, &key).unwrap();
+        assert_eq!(store.message_count(&[1u8; 32]), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, bytes.len() / 2);
+        store
+            .insert([1u8; 32], [10u8; 32], message(3, now, 3600))
+            .unwrap();
+        drop(store);
+
+        let store = LogStore::open(&path, &key).unwrap();
+        let ids: Vec<_> = store.messages(&[1u8; 32]).iter().map(|m| m.id[0]).collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_log_store_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drops.log");
+        let key = [7u8; 32];
+        let now = unix_now();
+
+        let mut store = LogStore::open(&path, &key).unwrap();
+        for i in 0..COMPACT_SLACK as u32 {
+            let mut stored = message(0, now, 3600);
+            stored.id[..4].copy_from_slice(&i.to_be_bytes());
+            store.insert([1u8; 32], [10u8; 32], stored.clone()).unwrap();
+            store.remove(&[1u8; 32], &[stored.id]).unwrap();
+        }
+        store
+            .insert([1u8; 32], [10u8; 32], message(9, now, 3600))
+            .unwrap();
+
+        // Compacted down to the live message and token
+        assert!(store.records < COMPACT_SLACK);
+        drop(store);
+
+        let store = LogStore::open(&path, &key).unwrap();
+        assert_eq!(store.messages(&[1u8; 32])[0].id, [9u8; 16]);
+        assert_eq!(store.drop_for_token(&[10u8; 32]), Some([1u8; 32]));
+    }
+}
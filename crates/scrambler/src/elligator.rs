@@ -0,0 +1,209 @@
+//! Elligator2 for Curve25519
+//!
+//! Encodes X25519 public keys as representatives: 32-byte strings that are
+//! indistinguishable from uniform random bytes. obfs4 sends its handshake keys
+//! this way so the handshake itself looks like noise.
+//!
+//! ## Security Properties
+//!
+//! - **Uniform Encoding:** Representatives of random keys are uniform over 254
+//!   bits; the two unused top bits are random
+//! - **Full-Curve Keys:** Public keys carry a random low-order component, so
+//!   they don't reveal that they lie in the prime-order subgroup. X25519
+//!   clamping removes that component in the key exchange.
+
+use crypto_bigint::modular::constant_mod::Residue;
+use crypto_bigint::{impl_modulus, Encoding, U256};
+use curve25519_dalek::constants::EIGHT_TORSION;
+use curve25519_dalek::edwards::EdwardsPoint;
+use subtle::ConstantTimeEq;
+
+impl_modulus!(
+    P25519,
+    U256,
+    "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffed"
+);
+
+/// Element of GF(2^255 - 19)
+type Fe = Residue<P25519, { U256::LIMBS }>;
+
+/// Montgomery curve coefficient A of Curve25519
+const A: u64 = 486662;
+
+/// (p - 1) / 2, the Legendre symbol exponent and the largest "non-negative" element
+const P_MINUS_1_HALF: U256 =
+    U256::from_be_hex("3ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff6");
+
+/// (p + 3) / 8, the square root exponent for p = 5 (mod 8)
+const P_PLUS_3_EIGHTH: U256 =
+    U256::from_be_hex("0ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe");
+
+/// sqrt(-1)
+const SQRT_M1: U256 =
+    U256::from_be_hex("2b8324804fc1df0b2b4d00993dfbd7a72f431806ad2fe478c4ee1b274a0ea0b0");
+
+/// Representative length
+pub const REPRESENTATIVE_LENGTH: usize = 32;
+
+fn fe(bytes: &[u8; 32]) -> Fe {
+    let mut bytes = *bytes;
+    bytes[31] &= 0x7f;
+    Fe::new(&U256::from_le_slice(&bytes))
+}
+
+fn fe_u64(value: u64) -> Fe {
+    Fe::new(&U256::from_u64(value))
+}
+
+fn fe_bytes(value: &Fe) -> [u8; 32] {
+    value.retrieve().to_le_bytes()
+}
+
+fn eq(a: &Fe, b: &Fe) -> bool {
+    bool::from(a.ct_eq(b))
+}
+
+/// Check whether `a` is a square (zero included)
+fn is_square(a: &Fe) -> bool {
+    let legendre = a.pow(&P_MINUS_1_HALF);
+    eq(&legendre, &Fe::ONE) || eq(&legendre, &Fe::ZERO)
+}
+
+/// Square root of `a`, if it has one
+fn sqrt(a: &Fe) -> Option<Fe> {
+    let candidate = a.pow(&P_PLUS_3_EIGHTH);
+    let square = candidate.square();
+
+    if eq(&square, a) {
+        Some(candidate)
+    } else if eq(&square, &a.neg()) {
+        Some(candidate.mul(&Fe::new(&SQRT_M1)))
+    } else {
+        None
+    }
+}
+
+/// Elligator2 map from a representative to an X25519 public key
+///
+/// Every 32-byte string decodes to a valid curve point.
+pub fn decode(representative: &[u8; REPRESENTATIVE_LENGTH]) -> [u8; 32] {
+    let mut bytes = *representative;
+    bytes[31] &= 0x3f;
+    let r = fe(&bytes);
+    let a = fe_u64(A);
+
+    // d = -A / (1 + 2r^2); 1 + 2r^2 is never zero since -1/2 is a non-square
+    let denominator = Fe::ONE.add(&r.square().add(&r.square()));
+    let (inverse, _) = denominator.invert();
+    let d = a.neg().mul(&inverse);
+
+    // d is the u-coordinate if d^3 + Ad^2 + d is a square, -d - A otherwise
+    let curve = d.square().mul(&d).add(&a.mul(&d.square())).add(&d);
+    let u = if is_square(&curve) { d } else { d.neg().sub(&a) };
+
+    fe_bytes(&u)
+}
+
+/// Inverse Elligator2 map from an X25519 public key to a representative
+///
+/// About half of all keys have no representative.
+///
+/// # Arguments
+/// * `public_key` - X25519 public key (Montgomery u-coordinate)
+/// * `branch` - Which of the key's two representatives to return
+/// * `high_bits` - Random bits for the two unused top bits
+pub fn encode(public_key: &[u8; 32], branch: bool, high_bits: u8) -> Option<[u8; REPRESENTATIVE_LENGTH]> {
+    let u = fe(public_key);
+    let a = fe_u64(A);
+    let u_plus_a = u.add(&a);
+    let two = fe_u64(2);
+
+    // Representable iff u != -A and -2u(u + A) is a square
+    if eq(&u_plus_a, &Fe::ZERO) || !is_square(&two.mul(&u).mul(&u_plus_a).neg()) {
+        return None;
+    }
+
+    let (numerator, denominator) = if branch || eq(&u, &Fe::ZERO) {
+        (u.neg(), two.mul(&u_plus_a))
+    } else {
+        (u_plus_a.neg(), two.mul(&u))
+    };
+    let (inverse, _) = denominator.invert();
+    let mut r = sqrt(&numerator.mul(&inverse))?;
+
+    // Canonical root in [0, (p - 1) / 2], which fits in 254 bits
+    if r.retrieve() > P_MINUS_1_HALF {
+        r = r.neg();
+    }
+
+    let mut representative = fe_bytes(&r);
+    representative[31] |= high_bits << 6;
+
+    Some(representative)
+}
+
+/// Generate an X25519 key pair whose public key has a representative
+///
+/// # Returns
+/// * Secret key bytes, public key, and representative
+pub fn generate_keypair() -> ([u8; 32], [u8; 32], [u8; REPRESENTATIVE_LENGTH]) {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    loop {
+        let secret: [u8; 32] = rng.gen();
+
+        // Add a random low-order point so the key covers the whole curve
+        let point = EdwardsPoint::mul_base_clamped(secret) + EIGHT_TORSION[rng.gen_range(0..8)];
+        let public_key = point.to_montgomery().to_bytes();
+
+        if let Some(representative) = encode(&public_key, rng.gen(), rng.gen::<u8>() & 0x03) {
+            return (secret, public_key, representative);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    #[test]
+    fn test_representative_round_trip() {
+        for _ in 0..32 {
+            let (_, public_key, representative) = generate_keypair();
+            assert_eq!(decode(&representative), public_key);
+        }
+    }
+
+    #[test]
+    fn test_both_branches_decode_to_key() {
+        let (_, public_key, _) = generate_keypair();
+
+        let first = encode(&public_key, true, 0).unwrap();
+        let second = encode(&public_key, false, 0).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(decode(&first), public_key);
+        assert_eq!(decode(&second), public_key);
+    }
+
+    #[test]
+    fn test_key_exchange_with_decoded_keys() {
+        let (secret_a, _, representative_a) = generate_keypair();
+        let (secret_b, _, representative_b) = generate_keypair();
+
+        let shared_a = StaticSecret::from(secret_a)
+            .diffie_hellman(&PublicKey::from(decode(&representative_b)));
+        let shared_b = StaticSecret::from(secret_b)
+            .diffie_hellman(&PublicKey::from(decode(&representative_a)));
+
+        assert_eq!(shared_a.as_bytes(), shared_b.as_bytes());
+    }
+
+    #[test]
+    fn test_high_bits_are_random() {
+        let high_bits: std::collections::HashSet<u8> =
+            (0..64).map(|_| generate_keypair().2[31] >> 6).collect();
+        assert_eq!(high_bits.len(), 4);
+    }
+}
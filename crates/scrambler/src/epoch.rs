@@ -0,0 +1,151 @@
+//! Key Epochs
+//!
+//! Mix node keys rotate on a fixed, public schedule. Time is divided into
+//! epochs of equal length; each node publishes the public keys for upcoming
+//! epochs ahead of time and erases a key once its epoch is over.
+//!
+//! ## Architecture
+//!
+//! - **Schedule:** Epoch boundaries are derived from a shared genesis time
+//! - **Published Keys:** Nodes advertise one `EpochKey` per upcoming epoch
+//! - **Grace Window:** Packets built for the previous epoch are still accepted
+//!   for a short while after the boundary, so packets in flight aren't lost
+//!
+//! ## Security Properties
+//!
+//! - **Forward Secrecy:** Once an epoch's grace window ends its private key is
+//!   erased, so captured packets from that epoch can no longer be unwrapped
+//! - **Bounded Replay State:** Replay tags only need to be kept for epochs
+//!   whose keys are still accepted
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Key epoch number
+pub type Epoch = u64;
+
+/// Key rotation schedule shared by all mix nodes and clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EpochSchedule {
+    /// Unix time (seconds) at which epoch 0 starts
+    pub genesis: u64,
+    /// Length of one epoch
+    pub epoch_duration: Duration,
+    /// How long after an epoch ends its keys still accept packets
+    pub grace_period: Duration,
+}
+
+impl Default for EpochSchedule {
+    fn default() -> Self {
+        Self {
+            genesis: 0,
+            epoch_duration: Duration::from_secs(3600),  // 1 hour
+            grace_period: Duration::from_secs(600),     // 10 minutes
+        }
+    }
+}
+
+impl EpochSchedule {
+    /// Epoch in effect at `time`
+    pub fn epoch_at(&self, time: SystemTime) -> Epoch {
+        let since_genesis = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(Duration::from_secs(self.genesis));
+
+        since_genesis.as_secs() / self.epoch_duration.as_secs().max(1)
+    }
+
+    /// Epoch in effect now
+    pub fn current_epoch(&self) -> Epoch {
+        self.epoch_at(SystemTime::now())
+    }
+
+    /// Time at which `epoch` starts
+    pub fn epoch_start(&self, epoch: Epoch) -> SystemTime {
+        let offset = self.epoch_duration.as_secs().max(1).saturating_mul(epoch);
+        UNIX_EPOCH + Duration::from_secs(self.genesis.saturating_add(offset))
+    }
+
+    /// Time at which `epoch` ends
+    pub fn epoch_end(&self, epoch: Epoch) -> SystemTime {
+        self.epoch_start(epoch.saturating_add(1))
+    }
+
+    /// Check whether packets built for `epoch` are accepted at `time`
+    ///
+    /// Packets are accepted during their own epoch and, within the grace
+    /// window, during the following one.
+    pub fn accepts(&self, epoch: Epoch, time: SystemTime) -> bool {
+        let current = self.epoch_at(time);
+
+        epoch == current
+            || (epoch.saturating_add(1) == current
+                && time < self.epoch_end(epoch) + self.grace_period)
+    }
+
+    /// Oldest epoch whose keys are still needed at `time`
+    pub fn oldest_accepted(&self, time: SystemTime) -> Epoch {
+        let current = self.epoch_at(time);
+
+        match current.checked_sub(1) {
+            Some(previous) if self.accepts(previous, time) => previous,
+            _ => current,
+        }
+    }
+}
+
+/// Public key a mix node publishes for one epoch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EpochKey {
+    /// Epoch the key is valid for
+    pub epoch: Epoch,
+    /// X25519 public key
+    pub public_key: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule() -> EpochSchedule {
+        EpochSchedule {
+            genesis: 1_000,
+            epoch_duration: Duration::from_secs(100),
+            grace_period: Duration::from_secs(10),
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_epoch_boundaries() {
+        let schedule = schedule();
+
+        assert_eq!(schedule.epoch_at(at(500)), 0);
+        assert_eq!(schedule.epoch_at(at(1_000)), 0);
+        assert_eq!(schedule.epoch_at(at(1_099)), 0);
+        assert_eq!(schedule.epoch_at(at(1_100)), 1);
+        assert_eq!(schedule.epoch_start(3), at(1_300));
+        assert_eq!(schedule.epoch_end(3), at(1_400));
+    }
+
+    #[test]
+    fn test_grace_window() {
+        let schedule = schedule();
+
+        // Epoch 1 runs 1100..1200, accepted until 1210
+        assert!(schedule.accepts(1, at(1_150)));
+        assert!(schedule.accepts(1, at(1_205)));
+        assert!(!schedule.accepts(1, at(1_210)));
+
+        // Future epochs are never accepted early
+        assert!(!schedule.accepts(2, at(1_150)));
+
+        assert_eq!(schedule.oldest_accepted(at(1_205)), 1);
+        assert_eq!(schedule.oldest_accepted(at(1_215)), 2);
+        assert_eq!(schedule.oldest_accepted(at(1_000)), 0);
+    }
+}
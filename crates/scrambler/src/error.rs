@@ -47,6 +47,18 @@ pub enum ScramblerError {
     /// VPN connection error
     #[error("VPN error: {0}")]
     VpnError(String),
+
+    /// Replay cache operation failed
+    #[error("Replay cache error: {0}")]
+    ReplayError(String),
+
+    /// Dead drop storage operation failed
+    #[error("Storage error: {0}")]
+    StorageError(String),
+
+    /// Dead drop store stamp rejected
+    #[error("Store stamp rejected: {0}")]
+    StampError(String),
 }
 
 impl From<invisible_crypto::CryptoError> for ScramblerError {
@@ -27,14 +27,28 @@
 )]
 
 pub mod error;
+pub mod epoch;
 pub mod sphinx;
+pub mod replay;
 pub mod mixnet;
+pub mod mixing;
 pub mod cover_traffic;
 pub mod shamir;
 pub mod temporal;
 pub mod vpn;
+pub mod wireguard;
+pub mod netstack;
 pub mod camouflage;
+mod elligator;
+pub mod obfs4;
+pub mod utls;
+pub mod transport;
+pub mod probing;
 pub mod dead_drop;
+pub mod drop_store;
+pub mod stamp;
+pub mod directory;
+pub mod network;
 pub mod orchestrator;
 
 pub use error::{ScramblerError, Result};
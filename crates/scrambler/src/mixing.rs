@@ -0,0 +1,377 @@
+//! Mixing Strategies
+//!
+//! Disciplines a mix node can use to decide when processed packets leave.
+//!
+//! ## Architecture
+//!
+//! - **Threshold Mix:** Collects a batch and flushes it shuffled once it is
+//!   full or has waited too long
+//! - **Poisson Mix:** Loopix-style continuous-time mixing; each packet is held
+//!   for the exponentially distributed delay its sender put in the Sphinx header
+//! - **Pool Mix:** Fires on a fixed interval and always keeps part of the pool
+//!   back, so any packet may stay for several rounds
+//!
+//! ## Security Properties
+//!
+//! - **Unlinkability:** Output order is independent of arrival order
+//! - **Tunable Anonymity:** Strategies trade latency for anonymity set size;
+//!   the Poisson mix lets senders choose the trade-off per packet
+//! - **Bounded Queueing:** Per-packet delays are capped so senders can't pin
+//!   packets in memory
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::sphinx::SphinxPacket;
+
+/// Mixing discipline of a mix node
+///
+/// Implementations take explicit timestamps so they can be driven by any
+/// clock; the caller polls at or after `next_deadline`.
+pub trait MixingStrategy: Debug + Send {
+    /// Queue a processed packet
+    ///
+    /// # Arguments
+    /// * `packet` - Packet for the next hop
+    /// * `next_hop` - Address of the next hop
+    /// * `delay` - Delay the sender requested for this hop
+    /// * `now` - Arrival time
+    fn add_packet(&mut self, packet: SphinxPacket, next_hop: SocketAddr, delay: Duration, now: Instant);
+
+    /// Remove the packets that are ready to leave at `now`
+    fn poll(&mut self, now: Instant) -> Vec<(SphinxPacket, SocketAddr)>;
+
+    /// Earliest time at which `poll` may release packets
+    fn next_deadline(&self) -> Option<Instant>;
+
+    /// Number of queued packets
+    fn len(&self) -> usize;
+
+    /// Check whether no packets are queued
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Threshold-or-timed batch mix
+#[derive(Debug)]
+pub struct ThresholdMix {
+    batch_size: usize,
+    min_delay: Duration,
+    max_delay: Duration,
+    batch: VecDeque<(SphinxPacket, SocketAddr)>,
+    /// Arrival time of the oldest packet in the batch
+    batch_start: Option<Instant>,
+}
+
+impl ThresholdMix {
+    /// Create a threshold mix
+    ///
+    /// # Arguments
+    /// * `batch_size` - Batch size that triggers a flush
+    /// * `min_delay` - Minimum time a full batch is held
+    /// * `max_delay` - Time after which a partial batch is flushed
+    pub fn new(batch_size: usize, min_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            batch_size,
+            min_delay,
+            max_delay,
+            batch: VecDeque::new(),
+            batch_start: None,
+        }
+    }
+}
+
+impl MixingStrategy for ThresholdMix {
+    fn add_packet(&mut self, packet: SphinxPacket, next_hop: SocketAddr, _delay: Duration, now: Instant) {
+        if self.batch.is_empty() {
+            self.batch_start = Some(now);
+        }
+        self.batch.push_back((packet, next_hop));
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<(SphinxPacket, SocketAddr)> {
+        use rand::seq::SliceRandom;
+
+        match self.next_deadline() {
+            Some(deadline) if now >= deadline => {}
+            _ => return Vec::new(),
+        }
+
+        let mut packets: Vec<_> = self.batch.drain(..).collect();
+        packets.shuffle(&mut rand::thread_rng());
+        self.batch_start = None;
+
+        packets
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let start = self.batch_start?;
+
+        if self.batch.len() >= self.batch_size {
+            Some(start + self.min_delay)
+        } else {
+            Some(start + self.max_delay)
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.batch.len()
+    }
+}
+
+/// Packet scheduled for release by the Poisson mix
+#[derive(Debug)]
+struct Scheduled {
+    release: Instant,
+    /// Arrival sequence number, for a total order
+    seq: u64,
+    packet: SphinxPacket,
+    next_hop: SocketAddr,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // Reversed so the max-heap yields the earliest release first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.release, other.seq).cmp(&(self.release, self.seq))
+    }
+}
+
+/// Loopix-style continuous-time mix
+///
+/// Each packet leaves after the delay its sender drew for this hop, so packets
+/// overtake each other independently of arrival order.
+#[derive(Debug)]
+pub struct PoissonMix {
+    /// Cap on per-packet delays
+    max_delay: Duration,
+    queue: BinaryHeap<Scheduled>,
+    next_seq: u64,
+}
+
+impl PoissonMix {
+    /// Create a Poisson mix
+    ///
+    /// # Arguments
+    /// * `max_delay` - Longest delay honored; longer requests are clamped
+    pub fn new(max_delay: Duration) -> Self {
+        Self {
+            max_delay,
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl MixingStrategy for PoissonMix {
+    fn add_packet(&mut self, packet: SphinxPacket, next_hop: SocketAddr, delay: Duration, now: Instant) {
+        self.queue.push(Scheduled {
+            release: now + delay.min(self.max_delay),
+            seq: self.next_seq,
+            packet,
+            next_hop,
+        });
+        self.next_seq += 1;
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<(SphinxPacket, SocketAddr)> {
+        let mut ready = Vec::new();
+
+        while self.queue.peek().is_some_and(|scheduled| scheduled.release <= now) {
+            if let Some(scheduled) = self.queue.pop() {
+                ready.push((scheduled.packet, scheduled.next_hop));
+            }
+        }
+
+        ready
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.queue.peek().map(|scheduled| scheduled.release)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// Timed dynamic pool mix
+///
+/// Every round, a random `send_fraction` of the packets above `min_pool`
+/// leaves; the rest stay in the pool for later rounds.
+#[derive(Debug)]
+pub struct PoolMix {
+    interval: Duration,
+    min_pool: usize,
+    send_fraction: f64,
+    pool: Vec<(SphinxPacket, SocketAddr)>,
+    /// Time of the next round while the pool is non-empty
+    next_round: Option<Instant>,
+}
+
+impl PoolMix {
+    /// Create a pool mix
+    ///
+    /// # Arguments
+    /// * `interval` - Time between rounds
+    /// * `min_pool` - Packets always kept back
+    /// * `send_fraction` - Fraction of the excess sent each round (0.0-1.0)
+    pub fn new(interval: Duration, min_pool: usize, send_fraction: f64) -> Self {
+        Self {
+            interval,
+            min_pool,
+            send_fraction: send_fraction.clamp(0.0, 1.0),
+            pool: Vec::new(),
+            next_round: None,
+        }
+    }
+}
+
+impl MixingStrategy for PoolMix {
+    fn add_packet(&mut self, packet: SphinxPacket, next_hop: SocketAddr, _delay: Duration, now: Instant) {
+        self.pool.push((packet, next_hop));
+        self.next_round.get_or_insert(now + self.interval);
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<(SphinxPacket, SocketAddr)> {
+        use rand::seq::SliceRandom;
+
+        match self.next_round {
+            Some(round) if now >= round => {}
+            _ => return Vec::new(),
+        }
+
+        let excess = self.pool.len().saturating_sub(self.min_pool);
+        // Round up so a non-zero fraction always drains the excess eventually
+        let send = ((excess as f64) * self.send_fraction).ceil() as usize;
+
+        self.pool.shuffle(&mut rand::thread_rng());
+        let sent = self.pool.split_off(self.pool.len() - send.min(excess));
+
+        self.next_round = if self.pool.is_empty() {
+            None
+        } else {
+            Some(now + self.interval)
+        };
+
+        sent
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.next_round
+    }
+
+    fn len(&self) -> usize {
+        self.pool.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sphinx::SphinxHeader;
+
+    fn packet(tag: u8) -> SphinxPacket {
+        SphinxPacket {
+            header: SphinxHeader {
+                epoch: 0,
+                ephemeral_key: [tag; 32],
+                routing_info: vec![],
+                mac: [0u8; 32],
+            },
+            payload: vec![],
+        }
+    }
+
+    fn hop() -> SocketAddr {
+        "127.0.0.1:8080".parse().unwrap()
+    }
+
+    fn tags(packets: &[(SphinxPacket, SocketAddr)]) -> Vec<u8> {
+        packets.iter().map(|(p, _)| p.header.ephemeral_key[0]).collect()
+    }
+
+    #[test]
+    fn test_threshold_mix() {
+        let start = Instant::now();
+        let mut mix = ThresholdMix::new(3, Duration::ZERO, Duration::from_secs(30));
+
+        mix.add_packet(packet(0), hop(), Duration::ZERO, start);
+        mix.add_packet(packet(1), hop(), Duration::ZERO, start);
+        assert!(mix.poll(start).is_empty());
+        assert_eq!(mix.next_deadline(), Some(start + Duration::from_secs(30)));
+
+        // Full batch flushes at once
+        mix.add_packet(packet(2), hop(), Duration::ZERO, start);
+        assert_eq!(mix.poll(start).len(), 3);
+        assert!(mix.is_empty());
+
+        // Partial batch flushes after max_delay
+        mix.add_packet(packet(3), hop(), Duration::ZERO, start);
+        assert!(mix.poll(start + Duration::from_secs(29)).is_empty());
+        assert_eq!(mix.poll(start + Duration::from_secs(30)).len(), 1);
+    }
+
+    #[test]
+    fn test_poisson_mix_honors_delays() {
+        let start = Instant::now();
+        let mut mix = PoissonMix::new(Duration::from_secs(60));
+
+        mix.add_packet(packet(0), hop(), Duration::from_secs(5), start);
+        mix.add_packet(packet(1), hop(), Duration::from_secs(1), start);
+        mix.add_packet(packet(2), hop(), Duration::from_secs(3600), start);
+
+        // Later arrivals with shorter delays overtake earlier ones
+        assert_eq!(mix.next_deadline(), Some(start + Duration::from_secs(1)));
+        assert_eq!(tags(&mix.poll(start + Duration::from_secs(1))), vec![1]);
+        assert!(mix.poll(start + Duration::from_secs(4)).is_empty());
+        assert_eq!(tags(&mix.poll(start + Duration::from_secs(5))), vec![0]);
+
+        // Oversized delays are clamped
+        assert_eq!(tags(&mix.poll(start + Duration::from_secs(60))), vec![2]);
+        assert!(mix.is_empty());
+    }
+
+    #[test]
+    fn test_pool_mix_keeps_packets_back() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(10);
+        let mut mix = PoolMix::new(interval, 2, 0.5);
+
+        for tag in 0..6 {
+            mix.add_packet(packet(tag), hop(), Duration::ZERO, start);
+        }
+        assert!(mix.poll(start).is_empty());
+
+        // Half of the 4 packets above the pool minimum leave
+        assert_eq!(mix.poll(start + interval).len(), 2);
+        assert_eq!(mix.len(), 4);
+
+        // The next round is a full interval later
+        assert!(mix.poll(start + interval).is_empty());
+        assert_eq!(mix.poll(start + interval * 2).len(), 1);
+        assert_eq!(mix.len(), 3);
+        assert_eq!(mix.poll(start + interval * 3).len(), 1);
+
+        // The minimum pool never drains
+        assert!(mix.poll(start + interval * 4).is_empty());
+        assert_eq!(mix.len(), 2);
+    }
+}
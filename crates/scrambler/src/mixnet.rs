@@ -1,14 +1,23 @@
 //! Mixnet Implementation
 //!
-//! 5-layer mix network using Sphinx packets with batch-shuffle-forward strategy.
+//! 5-layer mix network using Sphinx packets with pluggable mixing strategies.
 //! Inspired by Loopix design.
+//!
+//! Routes are drawn layer by layer, weighting each node by its advertised
+//! bandwidth and the reliability the client has measured for it. Advertised
+//! bandwidth is self-reported, so it's capped at a multiple of the layer's
+//! median before weighting. No route contains two nodes of the same operator
+//! or the same network.
 
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::{Duration, Instant};
 
+use crate::epoch::{Epoch, EpochKey};
 use crate::error::{Result, ScramblerError};
-use crate::sphinx::SphinxPacket;
+use crate::mixing::{MixingStrategy, PoissonMix, PoolMix, ThresholdMix};
+use crate::sphinx::{SphinxPacket, MAX_HOPS};
 
 /// Mix node identifier
 pub type NodeId = [u8; 32];
@@ -16,6 +25,9 @@ pub type NodeId = [u8; 32];
 /// Mix network layer (0-4)
 pub type Layer = u8;
 
+/// Number of layers in the mix network
+pub const NUM_LAYERS: Layer = 5;
+
 /// Mix node in the network
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MixNode {
@@ -23,16 +35,48 @@ pub struct MixNode {
     pub id: NodeId,
     /// Network layer (0-4)
     pub layer: Layer,
-    /// Node public key
-    pub public_key: Vec<u8>,
+    /// Published public keys, one per upcoming epoch
+    pub epoch_keys: Vec<EpochKey>,
     /// Network address
     pub address: String,
     /// Geographic location (for jurisdiction routing)
     pub location: GeoLocation,
+    /// Advertised bandwidth (bytes/s)
+    pub bandwidth: u64,
+    /// Operator the node declared, if any
+    pub operator: Option<String>,
+}
+
+impl MixNode {
+    /// Public key published for `epoch`
+    pub fn key_for_epoch(&self, epoch: Epoch) -> Option<&[u8]> {
+        self.epoch_keys
+            .iter()
+            .find(|key| key.epoch == epoch)
+            .map(|key| key.public_key.as_slice())
+    }
+
+    /// Network the node's address belongs to (/16 for IPv4, /32 for IPv6)
+    ///
+    /// `None` if the address isn't a socket address.
+    pub fn subnet(&self) -> Option<IpAddr> {
+        let addr: SocketAddr = self.address.parse().ok()?;
+
+        Some(match addr.ip() {
+            IpAddr::V4(ip) => {
+                let [a, b, _, _] = ip.octets();
+                IpAddr::V4(Ipv4Addr::new(a, b, 0, 0))
+            }
+            IpAddr::V6(ip) => {
+                let segments = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], 0, 0, 0, 0, 0, 0))
+            }
+        })
+    }
 }
 
 /// Geographic location for jurisdiction routing
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GeoLocation {
     /// Country code (ISO 3166-1 alpha-2)
     pub country: String,
@@ -54,22 +98,149 @@ pub enum Jurisdiction {
 }
 
 /// Mix strategy configuration
+///
+/// Selects the mixing discipline of a node; see `crate::mixing`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct MixStrategy {
-    /// Batch size before mixing
-    pub batch_size: usize,
-    /// Maximum delay before flushing batch
-    pub max_delay: Duration,
-    /// Minimum delay before forwarding
-    pub min_delay: Duration,
+pub enum MixStrategy {
+    /// Batch-shuffle-forward once a batch is full or has waited too long
+    Threshold {
+        /// Batch size before mixing
+        batch_size: usize,
+        /// Minimum delay before forwarding
+        min_delay: Duration,
+        /// Maximum delay before flushing batch
+        max_delay: Duration,
+    },
+    /// Per-packet exponential delays chosen by the sender (Loopix)
+    Poisson {
+        /// Longest per-hop delay honored
+        max_delay: Duration,
+    },
+    /// Timed pool mix that keeps packets back each round
+    Pool {
+        /// Time between rounds
+        interval: Duration,
+        /// Packets always kept in the pool
+        min_pool: usize,
+        /// Fraction of the excess sent each round (0.0-1.0)
+        send_fraction: f64,
+    },
 }
 
 impl Default for MixStrategy {
     fn default() -> Self {
-        Self {
+        Self::Threshold {
             batch_size: 10,
-            max_delay: Duration::from_secs(30),
             min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl MixStrategy {
+    /// Instantiate the configured mixing discipline
+    pub fn build(&self) -> Box<dyn MixingStrategy> {
+        match *self {
+            Self::Threshold { batch_size, min_delay, max_delay } => {
+                Box::new(ThresholdMix::new(batch_size, min_delay, max_delay))
+            }
+            Self::Poisson { max_delay } => Box::new(PoissonMix::new(max_delay)),
+            Self::Pool { interval, min_pool, send_fraction } => {
+                Box::new(PoolMix::new(interval, min_pool, send_fraction))
+            }
+        }
+    }
+}
+
+/// Constraints on route selection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteConstraints {
+    /// Number of hops (1 to `MAX_HOPS`)
+    pub hops: usize,
+    /// Jurisdictions no hop may be in
+    pub exclude_jurisdictions: Vec<Jurisdiction>,
+    /// Countries (ISO 3166-1 alpha-2) no hop may be in
+    pub exclude_countries: Vec<String>,
+}
+
+impl Default for RouteConstraints {
+    fn default() -> Self {
+        Self {
+            hops: usize::from(NUM_LAYERS),
+            exclude_jurisdictions: Vec::new(),
+            exclude_countries: Vec::new(),
+        }
+    }
+}
+
+impl RouteConstraints {
+    /// Layer of each hop
+    ///
+    /// Routes always enter at the first layer and leave from the last; shorter
+    /// routes skip evenly spaced middle layers.
+    pub fn layers(&self) -> Vec<Layer> {
+        let last = usize::from(NUM_LAYERS - 1);
+
+        match self.hops {
+            0 => Vec::new(),
+            1 => vec![0],
+            hops => (0..hops).map(|hop| (hop * last / (hops - 1)) as Layer).collect(),
+        }
+    }
+
+    /// Check whether a node's location is allowed
+    pub fn allows(&self, node: &MixNode) -> bool {
+        !self.exclude_jurisdictions.contains(&node.location.jurisdiction)
+            && !self
+                .exclude_countries
+                .iter()
+                .any(|country| country.eq_ignore_ascii_case(&node.location.country))
+    }
+}
+
+/// Number of recent observations a node's reliability is based on
+const RELIABILITY_WINDOW: u32 = 100;
+
+/// Client-side measurements of mix node reliability
+///
+/// Scores start neutral and move with observed successes and failures; old
+/// observations are halved away so a node can recover.
+#[derive(Debug, Clone, Default)]
+pub struct ReliabilityTracker {
+    /// (successes, failures) per node
+    observations: HashMap<NodeId, (u32, u32)>,
+}
+
+impl ReliabilityTracker {
+    /// Create a tracker with no observations
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that a node handled traffic successfully
+    pub fn record_success(&mut self, node: &NodeId) {
+        self.record(node, 1, 0);
+    }
+
+    /// Record that a node failed to handle traffic
+    pub fn record_failure(&mut self, node: &NodeId) {
+        self.record(node, 0, 1);
+    }
+
+    /// Reliability score in (0, 1); 0.5 for unmeasured nodes
+    pub fn score(&self, node: &NodeId) -> f64 {
+        let (successes, failures) = self.observations.get(node).copied().unwrap_or_default();
+        f64::from(successes + 1) / f64::from(successes + failures + 2)
+    }
+
+    fn record(&mut self, node: &NodeId, successes: u32, failures: u32) {
+        let entry = self.observations.entry(*node).or_default();
+        entry.0 += successes;
+        entry.1 += failures;
+
+        if entry.0 + entry.1 > RELIABILITY_WINDOW {
+            entry.0 /= 2;
+            entry.1 /= 2;
         }
     }
 }
@@ -79,12 +250,8 @@ impl Default for MixStrategy {
 pub struct MixNodeState {
     /// Node information
     pub node: MixNode,
-    /// Strategy configuration
-    pub strategy: MixStrategy,
-    /// Incoming packet batch
-    batch: VecDeque<SphinxPacket>,
-    /// Time when batch started
-    batch_start: Option<Instant>,
+    /// Mixing discipline holding processed packets
+    mixer: Box<dyn MixingStrategy>,
 }
 
 impl MixNodeState {
@@ -92,84 +259,82 @@ impl MixNodeState {
     pub fn new(node: MixNode, strategy: MixStrategy) -> Self {
         Self {
             node,
-            strategy,
-            batch: VecDeque::new(),
-            batch_start: None,
+            mixer: strategy.build(),
         }
     }
 
-    /// Add packet to batch
-    pub fn add_packet(&mut self, packet: SphinxPacket) {
-        if self.batch.is_empty() {
-            self.batch_start = Some(Instant::now());
-        }
-        self.batch.push_back(packet);
+    /// Queue a processed packet
+    ///
+    /// # Arguments
+    /// * `packet` - Packet for the next hop
+    /// * `next_hop` - Address of the next hop
+    /// * `delay` - Per-hop delay from the Sphinx header
+    pub fn add_packet(&mut self, packet: SphinxPacket, next_hop: SocketAddr, delay: Duration) {
+        self.mixer.add_packet(packet, next_hop, delay, Instant::now());
     }
 
-    /// Check if batch should be mixed and forwarded
-    pub fn should_forward(&self) -> bool {
-        if self.batch.is_empty() {
-            return false;
-        }
-
-        // Forward if batch is full
-        if self.batch.len() >= self.strategy.batch_size {
-            return true;
-        }
-
-        // Forward if max delay exceeded
-        if let Some(start) = self.batch_start {
-            if start.elapsed() >= self.strategy.max_delay {
-                return true;
-            }
-        }
-
-        false
+    /// Remove the packets that are ready to be forwarded
+    pub fn ready_packets(&mut self) -> Vec<(SphinxPacket, SocketAddr)> {
+        self.mixer.poll(Instant::now())
     }
 
-    /// Mix and extract batch for forwarding
-    pub fn mix_and_extract(&mut self) -> Vec<SphinxPacket> {
-        use rand::seq::SliceRandom;
-        use rand::thread_rng;
+    /// Earliest time at which packets may become ready
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.mixer.next_deadline()
+    }
 
-        let mut packets: Vec<_> = self.batch.drain(..).collect();
-        
-        // Shuffle packets
-        packets.shuffle(&mut thread_rng());
-        
-        // Reset batch timing
-        self.batch_start = None;
-        
-        packets
+    /// Number of packets held by the mixer
+    pub fn queued(&self) -> usize {
+        self.mixer.len()
     }
 }
 
+/// Attempts at drawing a route before giving up on the diversity constraints
+const ROUTE_ATTEMPTS: usize = 16;
+
+/// Largest multiple of its layer's median bandwidth a node is weighted with
+///
+/// Keeps a node that overstates its bandwidth from attracting most routes; it
+/// would take half of a layer's nodes colluding to move the median.
+const MAX_BANDWIDTH_MEDIAN_MULTIPLE: u64 = 10;
+
+/// A node eligible for a hop, with its subnet and capped bandwidth
+type Candidate<'a> = (&'a MixNode, IpAddr, u64);
+
 /// Select a route through the mix network
 ///
+/// Only nodes that published a key for `epoch` are eligible, so every hop can
+/// unwrap a packet built for that epoch. Within each layer, nodes are drawn
+/// with probability proportional to capped bandwidth times measured
+/// reliability. Node addresses must be IP socket addresses, as the directory
+/// enforces; any other address is an error.
+///
 /// # Arguments
 /// * `nodes` - Available mix nodes
-/// * `avoid_jurisdiction` - Jurisdiction to avoid (e.g., FiveEyes)
+/// * `constraints` - Hop count and excluded locations
+/// * `epoch` - Key epoch the packet will be built for
+/// * `reliability` - Measured node reliability
 pub fn select_route(
     nodes: &[MixNode],
-    avoid_jurisdiction: Option<Jurisdiction>,
+    constraints: &RouteConstraints,
+    epoch: Epoch,
+    reliability: &ReliabilityTracker,
 ) -> Result<Vec<MixNode>> {
-    use rand::seq::SliceRandom;
-    use rand::thread_rng;
-
-    let mut route = Vec::new();
+    if constraints.hops == 0 || constraints.hops > MAX_HOPS {
+        return Err(ScramblerError::MixnetError(format!(
+            "Invalid hop count: {} (maximum {})",
+            constraints.hops, MAX_HOPS
+        )));
+    }
 
-    // Select one node from each layer (0-4)
-    for layer in 0..5 {
-        let layer_nodes: Vec<_> = nodes
+    // Eligible nodes for each hop, with their subnets and capped bandwidth
+    let mut candidates = Vec::with_capacity(constraints.hops);
+    for layer in constraints.layers() {
+        let layer_nodes: Vec<&MixNode> = nodes
             .iter()
             .filter(|n| n.layer == layer)
-            .filter(|n| {
-                if let Some(avoid) = avoid_jurisdiction {
-                    n.location.jurisdiction != avoid
-                } else {
-                    true
-                }
-            })
+            .filter(|n| n.key_for_epoch(epoch).is_some())
+            .filter(|n| constraints.allows(n))
             .collect();
 
         if layer_nodes.is_empty() {
@@ -179,14 +344,71 @@ pub fn select_route(
             )));
         }
 
-        let selected = layer_nodes
-            .choose(&mut thread_rng())
-            .ok_or_else(|| ScramblerError::MixnetError("Failed to select node".to_string()))?;
+        let mut bandwidths: Vec<u64> = layer_nodes.iter().map(|n| n.bandwidth).collect();
+        bandwidths.sort_unstable();
+        let cap = bandwidths[bandwidths.len() / 2]
+            .saturating_mul(MAX_BANDWIDTH_MEDIAN_MULTIPLE)
+            .max(1);
+
+        let layer_candidates = layer_nodes
+            .into_iter()
+            .map(|n| {
+                let subnet = n.subnet().ok_or_else(|| {
+                    ScramblerError::MixnetError(format!(
+                        "Node address is not an IP socket address: {}",
+                        n.address
+                    ))
+                })?;
+                Ok((n, subnet, n.bandwidth.clamp(1, cap)))
+            })
+            .collect::<Result<Vec<Candidate<'_>>>>()?;
+
+        candidates.push(layer_candidates);
+    }
 
-        route.push((*selected).clone());
+    // Diversity constraints can dead-end a draw; retry with fresh randomness
+    for _ in 0..ROUTE_ATTEMPTS {
+        if let Some(route) = draw_route(&candidates, reliability) {
+            return Ok(route);
+        }
     }
 
-    Ok(route)
+    Err(ScramblerError::MixnetError(
+        "No route satisfies operator and subnet diversity".to_string(),
+    ))
+}
+
+/// Draw one node per hop, skipping nodes that share an operator or subnet with
+/// an earlier hop
+fn draw_route(
+    candidates: &[Vec<Candidate<'_>>],
+    reliability: &ReliabilityTracker,
+) -> Option<Vec<MixNode>> {
+    use rand::distributions::{Distribution, WeightedIndex};
+    use rand::thread_rng;
+
+    let mut route: Vec<Candidate<'_>> = Vec::with_capacity(candidates.len());
+
+    for layer_nodes in candidates {
+        let eligible: Vec<_> = layer_nodes
+            .iter()
+            .filter(|(node, subnet, _)| {
+                route.iter().all(|(chosen, chosen_subnet, _)| {
+                    chosen_subnet != subnet
+                        && (node.operator.is_none() || chosen.operator != node.operator)
+                })
+            })
+            .collect();
+
+        let weights = eligible
+            .iter()
+            .map(|(node, _, bandwidth)| *bandwidth as f64 * reliability.score(&node.id));
+        let index = WeightedIndex::new(weights).ok()?;
+
+        route.push(*eligible[index.sample(&mut thread_rng())]);
+    }
+
+    Some(route.into_iter().map(|(node, _, _)| node.clone()).collect())
 }
 
 #[cfg(test)]
@@ -194,40 +416,62 @@ mod tests {
     use super::*;
 
     fn create_test_node(layer: Layer, jurisdiction: Jurisdiction) -> MixNode {
+        use rand::Rng;
+
+        // Distinct /16 per node
+        let mut rng = rand::thread_rng();
+        let address = format!("{}.{}.0.1:8080", rng.gen_range(1..=223), rng.gen::<u8>());
+
         MixNode {
-            id: [0u8; 32],
+            id: rng.gen(),
             layer,
-            public_key: vec![0u8; 32],
-            address: "127.0.0.1:8080".to_string(),
+            epoch_keys: vec![EpochKey {
+                epoch: 7,
+                public_key: vec![0u8; 32],
+            }],
+            address,
             location: GeoLocation {
                 country: "US".to_string(),
                 jurisdiction,
             },
+            bandwidth: 1_000_000,
+            operator: None,
         }
     }
 
     #[test]
     fn test_mix_strategy_batch_size() {
         let node = create_test_node(0, Jurisdiction::PrivacyFriendly);
-        let mut state = MixNodeState::new(node, MixStrategy::default());
+        let strategy = MixStrategy::Threshold {
+            batch_size: 10,
+            min_delay: Duration::ZERO,
+            max_delay: Duration::from_secs(30),
+        };
+        let mut state = MixNodeState::new(node, strategy);
 
         // Not ready to forward
-        assert!(!state.should_forward());
+        assert!(state.ready_packets().is_empty());
 
         // Add packets up to batch size
         for _ in 0..10 {
-            state.add_packet(SphinxPacket {
-                header: crate::sphinx::SphinxHeader {
-                    ephemeral_key: [0u8; 32],
-                    routing_info: vec![],
-                    mac: [0u8; 32],
+            state.add_packet(
+                SphinxPacket {
+                    header: crate::sphinx::SphinxHeader {
+                        epoch: 0,
+                        ephemeral_key: [0u8; 32],
+                        routing_info: vec![],
+                        mac: [0u8; 32],
+                    },
+                    payload: vec![],
                 },
-                payload: vec![],
-            });
+                "127.0.0.1:8080".parse().unwrap(),
+                Duration::ZERO,
+            );
         }
 
         // Should forward when batch is full
-        assert!(state.should_forward());
+        assert_eq!(state.ready_packets().len(), 10);
+        assert_eq!(state.queued(), 0);
     }
 
     #[test]
@@ -238,13 +482,145 @@ mod tests {
             nodes.push(create_test_node(layer, Jurisdiction::FiveEyes));
         }
 
+        let reliability = ReliabilityTracker::new();
+
         // Should select 5 nodes, one per layer
-        let route = select_route(&nodes, None).unwrap();
+        let route = select_route(&nodes, &RouteConstraints::default(), 7, &reliability).unwrap();
         assert_eq!(route.len(), 5);
+        assert!(route.iter().enumerate().all(|(hop, n)| usize::from(n.layer) == hop));
 
         // Should avoid Five Eyes
-        let route = select_route(&nodes, Some(Jurisdiction::FiveEyes)).unwrap();
+        let constraints = RouteConstraints {
+            exclude_jurisdictions: vec![Jurisdiction::FiveEyes],
+            ..RouteConstraints::default()
+        };
+        let route = select_route(&nodes, &constraints, 7, &reliability).unwrap();
         assert_eq!(route.len(), 5);
         assert!(route.iter().all(|n| n.location.jurisdiction != Jurisdiction::FiveEyes));
+
+        // Excluding the only country leaves nothing to route through
+        let constraints = RouteConstraints {
+            exclude_countries: vec!["us".to_string()],
+            ..RouteConstraints::default()
+        };
+        assert!(select_route(&nodes, &constraints, 7, &reliability).is_err());
+    }
+
+    #[test]
+    fn test_route_hop_count() {
+        let nodes: Vec<_> = (0..NUM_LAYERS)
+            .map(|layer| create_test_node(layer, Jurisdiction::PrivacyFriendly))
+            .collect();
+        let reliability = ReliabilityTracker::new();
+
+        let constraints = RouteConstraints { hops: 3, ..RouteConstraints::default() };
+        assert_eq!(constraints.layers(), vec![0, 2, 4]);
+        let route = select_route(&nodes, &constraints, 7, &reliability).unwrap();
+        assert_eq!(route.iter().map(|n| n.layer).collect::<Vec<_>>(), vec![0, 2, 4]);
+
+        let constraints = RouteConstraints { hops: MAX_HOPS + 1, ..RouteConstraints::default() };
+        assert!(select_route(&nodes, &constraints, 7, &reliability).is_err());
+    }
+
+    #[test]
+    fn test_route_diversity() {
+        let mut nodes: Vec<_> = (0..NUM_LAYERS)
+            .map(|layer| create_test_node(layer, Jurisdiction::PrivacyFriendly))
+            .collect();
+        let reliability = ReliabilityTracker::new();
+        let constraints = RouteConstraints::default();
+
+        // Layer 1's only node shares a /16 with layer 0's
+        nodes[1].address = nodes[0].address.replace(".0.1:", ".7.9:");
+        assert!(select_route(&nodes, &constraints, 7, &reliability).is_err());
+
+        // An alternative in another network makes the route possible again
+        let mut alternative = create_test_node(1, Jurisdiction::PrivacyFriendly);
+        let alternative_id = alternative.id;
+        nodes.push(alternative.clone());
+        let route = select_route(&nodes, &constraints, 7, &reliability).unwrap();
+        assert_eq!(route[1].id, alternative_id);
+
+        // ...unless it's run by the same operator as another hop
+        let index = nodes.len() - 1;
+        nodes[index].operator = Some("op".to_string());
+        nodes[4].operator = Some("op".to_string());
+        assert!(select_route(&nodes, &constraints, 7, &reliability).is_err());
+
+        alternative.operator = Some("other".to_string());
+        nodes[index] = alternative;
+        assert!(select_route(&nodes, &constraints, 7, &reliability).is_ok());
+    }
+
+    #[test]
+    fn test_weighted_selection() {
+        let mut nodes: Vec<_> = (0..4)
+            .map(|_| create_test_node(0, Jurisdiction::PrivacyFriendly))
+            .collect();
+        let mut big = create_test_node(0, Jurisdiction::PrivacyFriendly);
+        big.bandwidth = 9_000_000;
+        let big_id = big.id;
+        nodes.push(big);
+
+        let mut reliability = ReliabilityTracker::new();
+        let constraints = RouteConstraints { hops: 1, ..RouteConstraints::default() };
+        let pick_big = |nodes: &[MixNode], reliability: &ReliabilityTracker| {
+            (0..200)
+                .filter(|_| select_route(nodes, &constraints, 7, reliability).unwrap()[0].id == big_id)
+                .count()
+        };
+
+        // 9 of 13 MB/s
+        let picks = pick_big(&nodes, &reliability);
+        assert!((110..=170).contains(&picks), "high-bandwidth node picked {} of 200 times", picks);
+
+        // Claiming far more bandwidth doesn't buy more than the median cap
+        nodes[4].bandwidth = 1_000_000_000_000;
+        let picks = pick_big(&nodes, &reliability);
+        assert!((115..=175).contains(&picks), "overstating node picked {} of 200 times", picks);
+
+        // A node that keeps failing loses its bandwidth advantage
+        for _ in 0..RELIABILITY_WINDOW {
+            reliability.record_failure(&big_id);
+        }
+        assert!(reliability.score(&big_id) < 0.02);
+        let picks = pick_big(&nodes, &reliability);
+        assert!(picks < 50, "unreliable node picked {} of 200 times", picks);
+    }
+
+    #[test]
+    fn test_hostname_address_rejected() {
+        let mut nodes: Vec<_> = (0..NUM_LAYERS)
+            .map(|layer| create_test_node(layer, Jurisdiction::PrivacyFriendly))
+            .collect();
+        nodes[2].address = "mix.example.org:8080".to_string();
+
+        assert!(select_route(&nodes, &RouteConstraints::default(), 7, &ReliabilityTracker::new()).is_err());
+    }
+
+    #[test]
+    fn test_route_selection_requires_epoch_key() {
+        let mut nodes: Vec<_> = (0..NUM_LAYERS)
+            .map(|layer| create_test_node(layer, Jurisdiction::PrivacyFriendly))
+            .collect();
+
+        // No node published keys for epoch 8 yet
+        assert!(select_route(&nodes, &RouteConstraints::default(), 8, &ReliabilityTracker::new()).is_err());
+
+        // A layer without a key for the epoch cannot be routed through
+        for node in nodes.iter_mut().skip(1) {
+            node.epoch_keys.push(EpochKey {
+                epoch: 8,
+                public_key: vec![1u8; 32],
+            });
+        }
+        assert!(select_route(&nodes, &RouteConstraints::default(), 8, &ReliabilityTracker::new()).is_err());
+
+        nodes[0].epoch_keys.push(EpochKey {
+            epoch: 8,
+            public_key: vec![1u8; 32],
+        });
+        let route = select_route(&nodes, &RouteConstraints::default(), 8, &ReliabilityTracker::new()).unwrap();
+        assert!(route.iter().all(|n| n.key_for_epoch(8) == Some(&[1u8; 32][..])));
     }
 }
@@ -0,0 +1,1401 @@
+//! Userspace TCP/IP
+//!
+//! A small IPv4 + TCP stack that runs over a packet link such as a WireGuard
+//! tunnel, so connections can go through the Ghost VPN without a TUN device.
+//!
+//! ## Architecture
+//!
+//! - **PacketLink:** Carries whole IP packets (`WireGuardTunnel`, or an
+//!   in-memory pipe in tests)
+//! - **NetStack:** Connection table shared by the streams and two background
+//!   tasks: one feeds incoming packets in, the other sends segments and runs
+//!   the retransmission and TIME-WAIT timers
+//! - **TunnelStream:** One TCP connection as an `AsyncRead + AsyncWrite`
+//!   stream, so it can go under any `PluggableTransport`
+//! - **Gateway Mode:** Accepts connections to any address, for the VPN side
+//!   of the tunnel
+//!
+//! ## Scope
+//!
+//! Enough TCP for bulk transfers over a lossy tunnel: MSS negotiation,
+//! RFC 6298 retransmission timeouts with Karn's rule, slow start and
+//! congestion avoidance, fast retransmit after three duplicate ACKs and
+//! zero-window probing. Out-of-order segments are dropped and recovered by
+//! go-back-N retransmission; there is no SACK, window scaling or IPv6.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::error::{Result, ScramblerError};
+
+/// Future returned by packet links
+pub type LinkFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Link carrying IP packets
+pub trait PacketLink: Send + Sync + 'static {
+    /// Send one packet
+    fn send(&self, packet: Vec<u8>) -> LinkFuture<'_, ()>;
+
+    /// Receive one packet, or `None` once the link is closed
+    fn recv(&self) -> LinkFuture<'_, Option<Vec<u8>>>;
+}
+
+/// Maximum segment size we advertise, leaving room for WireGuard framing
+/// within a 1500-byte path MTU
+const MSS: usize = 1380;
+
+/// MSS assumed when the peer sends none (RFC 9293)
+const DEFAULT_PEER_MSS: usize = 536;
+
+/// Receive buffer, and the largest window we advertise
+const RECV_BUFFER: usize = 65535;
+
+/// Bytes accepted from the application before writes wait
+const SEND_BUFFER: usize = 256 * 1024;
+
+/// Retransmission timeout bounds (RFC 6298)
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Consecutive timeouts before the connection is dropped
+const MAX_RETRANSMITS: u32 = 8;
+
+/// Time spent in TIME-WAIT; the tunnel never delivers stale duplicates for long
+const TIME_WAIT: Duration = Duration::from_secs(2);
+
+/// How long a closed stream's connection may take to finish closing
+const LINGER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Driver wake-up interval when no timer is pending
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+const IP_HEADER_SIZE: usize = 20;
+const TCP_HEADER_SIZE: usize = 20;
+const PROTOCOL_TCP: u8 = 6;
+const TTL: u8 = 64;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// TCP/IP stack over a packet link
+#[derive(Clone)]
+pub struct NetStack {
+    inner: Arc<Inner>,
+}
+
+/// Owns the background tasks; aborted when the stack and all its streams are dropped
+struct Inner {
+    shared: Arc<Shared>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+struct Shared {
+    link: Arc<dyn PacketLink>,
+    state: Mutex<StackState>,
+    /// Wakes the driver when there may be segments to send
+    notify: Notify,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, StackState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl NetStack {
+    /// Stack with a single address, for opening connections
+    ///
+    /// # Arguments
+    /// * `link` - Link to the network
+    /// * `local_ip` - Our address on that network
+    pub fn new<L: PacketLink>(link: L, local_ip: Ipv4Addr) -> Self {
+        Self::start(Arc::new(link), Some(local_ip))
+    }
+
+    /// Stack that accepts connections to any address
+    ///
+    /// Incoming connections are answered from the address they were sent to,
+    /// so the far end of the link can reach arbitrary hosts through
+    /// [`NetStack::accept`].
+    pub fn gateway<L: PacketLink>(link: L) -> Self {
+        Self::start(Arc::new(link), None)
+    }
+
+    fn start(link: Arc<dyn PacketLink>, local_ip: Option<Ipv4Addr>) -> Self {
+        let shared = Arc::new(Shared {
+            link,
+            state: Mutex::new(StackState {
+                local_ip,
+                connections: HashMap::new(),
+                accept_queue: VecDeque::new(),
+                accept_waker: None,
+                closed: false,
+                resets: Vec::new(),
+                next_ip_id: rand::thread_rng().gen(),
+            }),
+            notify: Notify::new(),
+        });
+        let tasks = [
+            tokio::spawn(receive(shared.clone())),
+            tokio::spawn(drive(shared.clone())),
+        ];
+
+        Self {
+            inner: Arc::new(Inner { shared, tasks }),
+        }
+    }
+
+    /// Open a TCP connection
+    ///
+    /// # Arguments
+    /// * `remote` - Destination address
+    ///
+    /// # Returns
+    /// * Established connection
+    pub async fn connect(&self, remote: SocketAddrV4) -> Result<TunnelStream> {
+        let shared = &self.inner.shared;
+        let id = {
+            let mut state = shared.state();
+            if state.closed {
+                return Err(ScramblerError::NetworkError("Link closed".to_string()));
+            }
+            let local_ip = state.local_ip.ok_or_else(|| {
+                ScramblerError::ConfigError("Gateway stacks can't connect".to_string())
+            })?;
+
+            let mut rng = rand::thread_rng();
+            let id = loop {
+                let id = ConnectionId {
+                    local: SocketAddrV4::new(local_ip, rng.gen_range(49152..=65535)),
+                    remote,
+                };
+                if !state.connections.contains_key(&id) {
+                    break id;
+                }
+            };
+            state
+                .connections
+                .insert(id, Connection::new(TcpState::SynSent, rng.gen()));
+            id
+        };
+        shared.notify.notify_one();
+
+        // Dropping the stream before the handshake completes aborts it
+        let stream = TunnelStream {
+            stack: self.inner.clone(),
+            id,
+        };
+        poll_fn(|cx| {
+            let mut state = shared.state();
+            let connection = state.connection(&id)?;
+            if let Some(kind) = connection.error {
+                return Poll::Ready(Err(io::Error::from(kind)));
+            }
+            if connection.state != TcpState::SynSent {
+                return Poll::Ready(Ok(()));
+            }
+            connection.write_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+        .map_err(|e| ScramblerError::NetworkError(format!("Connection failed: {}", e)))?;
+
+        Ok(stream)
+    }
+
+    /// Wait for an incoming connection (gateway stacks)
+    ///
+    /// # Returns
+    /// * Connection and the address it was sent to
+    pub async fn accept(&self) -> Result<(TunnelStream, SocketAddrV4)> {
+        let shared = &self.inner.shared;
+        let id = poll_fn(|cx| {
+            let mut state = shared.state();
+            if let Some(id) = state.accept_queue.pop_front() {
+                return Poll::Ready(Ok(id));
+            }
+            if state.closed {
+                return Poll::Ready(Err(ScramblerError::NetworkError("Link closed".to_string())));
+            }
+            state.accept_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await?;
+
+        Ok((
+            TunnelStream {
+                stack: self.inner.clone(),
+                id,
+            },
+            id.local,
+        ))
+    }
+}
+
+impl fmt::Debug for NetStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.inner.shared.state();
+        f.debug_struct("NetStack")
+            .field("local_ip", &state.local_ip)
+            .field("connections", &state.connections.len())
+            .finish()
+    }
+}
+
+/// TCP connection through a [`NetStack`]
+pub struct TunnelStream {
+    stack: Arc<Inner>,
+    id: ConnectionId,
+}
+
+impl TunnelStream {
+    /// Our end of the connection
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.id.local
+    }
+
+    /// Far end of the connection
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.id.remote
+    }
+}
+
+impl fmt::Debug for TunnelStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TunnelStream")
+            .field("local", &self.id.local)
+            .field("remote", &self.id.remote)
+            .finish()
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let shared = &self.stack.shared;
+        let mut state = shared.state();
+        let connection = state.connection(&self.id)?;
+
+        if !connection.recv_buffer.is_empty() {
+            let n = buf.remaining().min(connection.recv_buffer.len());
+            let (front, back) = connection.recv_buffer.as_slices();
+            let from_front = n.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            connection.recv_buffer.drain(..n);
+
+            // Tell the peer once a mostly closed window has opened up again
+            if connection.advertised_window < RECV_BUFFER / 2
+                && connection.window() >= RECV_BUFFER / 2
+            {
+                connection.ack_pending = true;
+                drop(state);
+                shared.notify.notify_one();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if connection.fin_received {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(io::Error::from(kind)));
+        }
+        connection.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let shared = &self.stack.shared;
+        let mut state = shared.state();
+        let connection = state.connection(&self.id)?;
+
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(io::Error::from(kind)));
+        }
+        if connection.fin_queued || !connection.state.can_send() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let space = SEND_BUFFER - connection.send_buffer.len();
+        if space == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = space.min(buf.len());
+        connection.send_buffer.extend(&buf[..n]);
+        drop(state);
+        shared.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let shared = &self.stack.shared;
+        let mut state = shared.state();
+        let connection = state.connection(&self.id)?;
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(io::Error::from(kind)));
+        }
+        connection.fin_queued = true;
+        drop(state);
+        shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TunnelStream {
+    fn drop(&mut self) {
+        let shared = &self.stack.shared;
+        if let Some(connection) = shared.state().connections.get_mut(&self.id) {
+            connection.release(Instant::now());
+        }
+        shared.notify.notify_one();
+    }
+}
+
+/// Feed packets from the link into the stack
+async fn receive(shared: Arc<Shared>) {
+    loop {
+        match shared.link.recv().await {
+            Ok(Some(packet)) => {
+                shared.state().input(&packet, Instant::now());
+                shared.notify.notify_one();
+            }
+            Ok(None) => break,
+            Err(e) => {
+                tracing::debug!(error = %e, "Packet link failed");
+                break;
+            }
+        }
+    }
+    shared.state().link_closed();
+}
+
+/// Send segments and run timers
+async fn drive(shared: Arc<Shared>) {
+    loop {
+        let (packets, deadline) = shared.state().poll(Instant::now());
+        for packet in packets {
+            if let Err(e) = shared.link.send(packet).await {
+                tracing::debug!(error = %e, "Packet send failed");
+            }
+        }
+
+        let deadline = deadline.unwrap_or_else(|| Instant::now() + IDLE_INTERVAL);
+        tokio::select! {
+            _ = shared.notify.notified() => {}
+            _ = tokio::time::sleep_until(deadline.into()) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ConnectionId {
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl TcpState {
+    /// Whether the application may still write
+    fn can_send(self) -> bool {
+        matches!(self, TcpState::Established | TcpState::CloseWait)
+    }
+
+    /// Whether data from the peer is still accepted
+    fn can_receive(self) -> bool {
+        matches!(
+            self,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        )
+    }
+}
+
+/// Segment to send, before addressing
+struct Outgoing {
+    seq: u32,
+    flags: u8,
+    payload: Vec<u8>,
+}
+
+struct Connection {
+    state: TcpState,
+
+    // Send side
+    /// Initial send sequence number
+    iss: u32,
+    /// Oldest unacknowledged sequence number
+    snd_una: u32,
+    /// Next sequence number to send
+    snd_nxt: u32,
+    /// Highest sequence number sent
+    snd_max: u32,
+    /// Peer's receive window
+    snd_wnd: usize,
+    peer_mss: usize,
+    /// Unacknowledged and unsent bytes, starting at `buffer_seq`
+    send_buffer: VecDeque<u8>,
+    buffer_seq: u32,
+    /// Application closed its sending side
+    fin_queued: bool,
+
+    // Receive side
+    rcv_nxt: u32,
+    recv_buffer: VecDeque<u8>,
+    fin_received: bool,
+    advertised_window: usize,
+    ack_pending: bool,
+
+    // Congestion control
+    cwnd: usize,
+    ssthresh: usize,
+    dup_acks: u32,
+
+    // Retransmission
+    rto: Duration,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// Segment end being timed, and when it was sent
+    rtt_sample: Option<(u32, Instant)>,
+    retransmit_at: Option<Instant>,
+    retransmits: u32,
+
+    time_wait_until: Option<Instant>,
+    /// When the stream was dropped
+    released: Option<Instant>,
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(state: TcpState, iss: u32) -> Self {
+        Self {
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: MSS,
+            peer_mss: DEFAULT_PEER_MSS,
+            send_buffer: VecDeque::new(),
+            buffer_seq: iss.wrapping_add(1),
+            fin_queued: false,
+            rcv_nxt: 0,
+            recv_buffer: VecDeque::new(),
+            fin_received: false,
+            advertised_window: RECV_BUFFER,
+            ack_pending: false,
+            cwnd: 10 * MSS,
+            ssthresh: usize::MAX,
+            dup_acks: 0,
+            rto: INITIAL_RTO,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rtt_sample: None,
+            retransmit_at: None,
+            retransmits: 0,
+            time_wait_until: None,
+            released: None,
+            error: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Receive window to advertise
+    fn window(&self) -> usize {
+        RECV_BUFFER - self.recv_buffer.len()
+    }
+
+    fn in_flight(&self) -> usize {
+        self.snd_nxt.wrapping_sub(self.snd_una) as usize
+    }
+
+    fn fin_seq(&self) -> u32 {
+        self.buffer_seq.wrapping_add(self.send_buffer.len() as u32)
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_queued && self.snd_una == self.fin_seq().wrapping_add(1)
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Drop the connection with an error
+    fn abort(&mut self, kind: io::ErrorKind) {
+        self.error.get_or_insert(kind);
+        self.state = TcpState::Closed;
+        self.retransmit_at = None;
+        self.wake();
+    }
+
+    /// The stream was dropped: close gracefully, or abort an unfinished handshake
+    fn release(&mut self, now: Instant) {
+        self.released = Some(now);
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => self.state = TcpState::Closed,
+            _ => self.fin_queued = true,
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        // RFC 6298, section 2
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > sample {
+                    srtt - sample
+                } else {
+                    sample - srtt
+                };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(sample);
+        self.rto = (srtt + (self.rttvar * 4).max(Duration::from_millis(1))).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Go back to the oldest unacknowledged segment
+    fn rewind(&mut self) {
+        self.ssthresh = (self.in_flight() / 2).max(2 * self.peer_mss);
+        self.snd_nxt = self.snd_una;
+        // Karn's rule: no RTT samples from retransmitted segments
+        self.rtt_sample = None;
+    }
+
+    /// Process a segment addressed to this connection
+    ///
+    /// # Returns
+    /// * Whether the connection just became established, or a reset to send
+    fn input(&mut self, segment: &Segment<'_>, now: Instant) -> InputResult {
+        if segment.flags & RST != 0 {
+            let acceptable = match self.state {
+                TcpState::SynSent => {
+                    segment.flags & ACK != 0 && segment.ack == self.iss.wrapping_add(1)
+                }
+                _ => segment.seq.wrapping_sub(self.rcv_nxt) < RECV_BUFFER as u32,
+            };
+            if acceptable {
+                let kind = match self.state {
+                    TcpState::SynSent => io::ErrorKind::ConnectionRefused,
+                    _ => io::ErrorKind::ConnectionReset,
+                };
+                self.abort(kind);
+            }
+            return InputResult::None;
+        }
+
+        let mut established = false;
+        match self.state {
+            TcpState::Closed => return InputResult::None,
+            TcpState::SynSent => {
+                if segment.flags & ACK != 0 && segment.ack != self.iss.wrapping_add(1) {
+                    return InputResult::Reset;
+                }
+                if segment.flags & (SYN | ACK) != SYN | ACK {
+                    return InputResult::None;
+                }
+                self.rcv_nxt = segment.seq.wrapping_add(1);
+                self.peer_mss = segment.mss.map_or(DEFAULT_PEER_MSS, usize::from).min(MSS);
+                self.state = TcpState::Established;
+                self.ack_pending = true;
+                established = true;
+            }
+            TcpState::SynReceived => {
+                if segment.flags & SYN != 0 {
+                    // Our SYN-ACK was lost
+                    self.snd_nxt = self.iss;
+                    return InputResult::None;
+                }
+                if segment.flags & ACK == 0 {
+                    return InputResult::None;
+                }
+                if segment.ack != self.iss.wrapping_add(1) {
+                    return InputResult::Reset;
+                }
+                self.state = TcpState::Established;
+                established = true;
+            }
+            _ => {}
+        }
+
+        if segment.flags & ACK != 0 {
+            self.process_ack(segment, now);
+        }
+        self.process_data(segment, now);
+
+        if established {
+            self.wake();
+            InputResult::Established
+        } else {
+            InputResult::None
+        }
+    }
+
+    fn process_ack(&mut self, segment: &Segment<'_>, now: Instant) {
+        let ack = segment.ack;
+        let acked = ack.wrapping_sub(self.snd_una);
+        let window = usize::from(segment.window);
+
+        if acked == 0 || acked > self.snd_max.wrapping_sub(self.snd_una) {
+            // Duplicate (or unacceptable) ACK
+            let duplicate = acked == 0
+                && segment.payload.is_empty()
+                && segment.flags & (SYN | FIN) == 0
+                && window == self.snd_wnd
+                && self.in_flight() > 0;
+            self.snd_wnd = window;
+            if duplicate {
+                self.dup_acks += 1;
+                if self.dup_acks == 3 {
+                    // Fast retransmit
+                    self.rewind();
+                    self.cwnd = self.ssthresh;
+                }
+            }
+            return;
+        }
+
+        // The SYN takes one sequence number
+        let mut data_acked = acked as usize;
+        if self.snd_una == self.iss && self.buffer_seq == self.iss.wrapping_add(1) {
+            data_acked -= 1;
+        }
+        let fin_acked_now = self.fin_queued && ack == self.fin_seq().wrapping_add(1);
+        if fin_acked_now {
+            data_acked -= 1;
+        }
+        let drained = data_acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..drained);
+        self.buffer_seq = self.buffer_seq.wrapping_add(drained as u32);
+
+        self.snd_una = ack;
+        if ack.wrapping_sub(self.snd_nxt) as i32 > 0 {
+            self.snd_nxt = ack;
+        }
+        self.snd_wnd = window;
+        self.dup_acks = 0;
+        self.retransmits = 0;
+
+        if let Some((end, sent)) = self.rtt_sample {
+            if ack.wrapping_sub(end) as i32 >= 0 {
+                self.update_rtt(now.duration_since(sent));
+                self.rtt_sample = None;
+            }
+        }
+        self.retransmit_at = (self.snd_max != self.snd_una).then(|| now + self.rto);
+
+        // Slow start, then congestion avoidance
+        if self.cwnd < self.ssthresh {
+            self.cwnd += drained.min(self.peer_mss);
+        } else {
+            self.cwnd += (self.peer_mss * self.peer_mss / self.cwnd).max(1);
+        }
+
+        if self.fin_acked() {
+            match self.state {
+                TcpState::FinWait1 => self.state = TcpState::FinWait2,
+                TcpState::Closing => self.enter_time_wait(now),
+                TcpState::LastAck => self.state = TcpState::Closed,
+                _ => {}
+            }
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn process_data(&mut self, segment: &Segment<'_>, now: Instant) {
+        let fin = segment.flags & FIN != 0;
+        if segment.payload.is_empty() && !fin {
+            return;
+        }
+        self.ack_pending = true;
+        if !self.state.can_receive() {
+            return;
+        }
+
+        // Accept the part of the segment starting at rcv_nxt, if any
+        let skip = self.rcv_nxt.wrapping_sub(segment.seq) as usize;
+        if skip > segment.payload.len() {
+            return;
+        }
+        let new_data = &segment.payload[skip..];
+        let taken = new_data.len().min(self.window());
+        if taken > 0 {
+            self.recv_buffer.extend(&new_data[..taken]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(taken as u32);
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+
+        if fin && taken == new_data.len() && !self.fin_received {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            match self.state {
+                TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 if self.fin_acked() => self.enter_time_wait(now),
+                TcpState::FinWait1 => self.state = TcpState::Closing,
+                TcpState::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.state = TcpState::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(now + TIME_WAIT);
+    }
+
+    /// Run timers and produce segments to send
+    fn poll(&mut self, now: Instant, out: &mut Vec<Outgoing>) {
+        if self.time_wait_until.is_some_and(|until| now >= until) {
+            self.state = TcpState::Closed;
+            self.time_wait_until = None;
+        }
+
+        if self.retransmit_at.is_some_and(|at| now >= at) {
+            // Zero-window probes don't count against the connection
+            if self.snd_wnd != 0 {
+                self.retransmits += 1;
+            }
+            if self.retransmits > MAX_RETRANSMITS {
+                out.push(Outgoing {
+                    seq: self.snd_nxt,
+                    flags: RST | ACK,
+                    payload: Vec::new(),
+                });
+                self.abort(io::ErrorKind::TimedOut);
+                return;
+            }
+            self.rto = (self.rto * 2).min(MAX_RTO);
+            self.rewind();
+            self.cwnd = self.peer_mss;
+            self.retransmit_at = None;
+        }
+
+        let sent_before = out.len();
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived if self.snd_nxt == self.iss => {
+                let flags = if self.state == TcpState::SynSent {
+                    SYN
+                } else {
+                    SYN | ACK
+                };
+                self.send(now, out, flags, Vec::new(), 1);
+            }
+            TcpState::Established
+            | TcpState::CloseWait
+            | TcpState::FinWait1
+            | TcpState::Closing
+            | TcpState::LastAck => self.send_data(now, out),
+            _ => {}
+        }
+
+        let can_ack = !matches!(self.state, TcpState::SynSent | TcpState::Closed);
+        if self.ack_pending && can_ack && out.len() == sent_before {
+            out.push(Outgoing {
+                seq: self.snd_nxt,
+                flags: ACK,
+                payload: Vec::new(),
+            });
+        }
+    }
+
+    fn send_data(&mut self, now: Instant, out: &mut Vec<Outgoing>) {
+        // A closed window still lets one byte through as a probe
+        let window = self.cwnd.min(self.snd_wnd.max(1));
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.buffer_seq) as usize;
+            let in_flight = self.in_flight();
+            if offset >= self.send_buffer.len() || in_flight >= window {
+                break;
+            }
+            let len = self
+                .peer_mss
+                .min(self.send_buffer.len() - offset)
+                .min(window - in_flight);
+            let payload: Vec<u8> = self
+                .send_buffer
+                .range(offset..offset + len)
+                .copied()
+                .collect();
+            self.send(now, out, ACK | PSH, payload, len as u32);
+        }
+
+        if self.fin_queued && self.snd_nxt == self.fin_seq() {
+            self.send(now, out, FIN | ACK, Vec::new(), 1);
+            match self.state {
+                TcpState::Established => self.state = TcpState::FinWait1,
+                TcpState::CloseWait => self.state = TcpState::LastAck,
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a segment that takes `len` sequence numbers
+    fn send(
+        &mut self,
+        now: Instant,
+        out: &mut Vec<Outgoing>,
+        flags: u8,
+        payload: Vec<u8>,
+        len: u32,
+    ) {
+        let seq = self.snd_nxt;
+        self.snd_nxt = seq.wrapping_add(len);
+        let new_data = self.snd_nxt.wrapping_sub(self.snd_max) as i32 > 0;
+        if new_data {
+            self.snd_max = self.snd_nxt;
+            if self.rtt_sample.is_none() {
+                self.rtt_sample = Some((self.snd_nxt, now));
+            }
+        }
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+        out.push(Outgoing {
+            seq,
+            flags,
+            payload,
+        });
+    }
+}
+
+enum InputResult {
+    None,
+    Established,
+    Reset,
+}
+
+struct StackState {
+    /// Our address; `None` for gateways, which answer as any address
+    local_ip: Option<Ipv4Addr>,
+    connections: HashMap<ConnectionId, Connection>,
+    accept_queue: VecDeque<ConnectionId>,
+    accept_waker: Option<Waker>,
+    closed: bool,
+    /// Resets for segments that matched no connection
+    resets: Vec<Vec<u8>>,
+    next_ip_id: u16,
+}
+
+impl StackState {
+    fn connection(&mut self, id: &ConnectionId) -> io::Result<&mut Connection> {
+        self.connections
+            .get_mut(id)
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    fn input(&mut self, packet: &[u8], now: Instant) {
+        let Some(segment) = Segment::parse(packet) else {
+            return;
+        };
+        if self.local_ip.is_some_and(|ip| ip != *segment.dst.ip()) {
+            return;
+        }
+
+        let id = ConnectionId {
+            local: segment.dst,
+            remote: segment.src,
+        };
+        let result = match self.connections.get_mut(&id) {
+            Some(connection) => connection.input(&segment, now),
+            None if self.local_ip.is_none() && segment.flags & (SYN | ACK) == SYN => {
+                // Gateway: accept connections to any address
+                let mut connection =
+                    Connection::new(TcpState::SynReceived, rand::thread_rng().gen());
+                connection.rcv_nxt = segment.seq.wrapping_add(1);
+                connection.peer_mss = segment.mss.map_or(DEFAULT_PEER_MSS, usize::from).min(MSS);
+                connection.snd_wnd = usize::from(segment.window);
+                self.connections.insert(id, connection);
+                InputResult::None
+            }
+            None => InputResult::Reset,
+        };
+
+        match result {
+            InputResult::None => {}
+            InputResult::Established => {
+                if self.local_ip.is_none() {
+                    self.accept_queue.push_back(id);
+                    if let Some(waker) = self.accept_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            InputResult::Reset if segment.flags & RST == 0 => {
+                // RFC 9293, section 3.10.7.1
+                let (seq, ack, flags) = if segment.flags & ACK != 0 {
+                    (segment.ack, 0, RST)
+                } else {
+                    (
+                        0,
+                        segment.seq.wrapping_add(segment.sequence_len()),
+                        RST | ACK,
+                    )
+                };
+                let id_field = self.ip_id();
+                self.resets.push(build_packet(
+                    segment.dst,
+                    segment.src,
+                    seq,
+                    ack,
+                    flags,
+                    0,
+                    None,
+                    &[],
+                    id_field,
+                ));
+            }
+            InputResult::Reset => {}
+        }
+    }
+
+    fn ip_id(&mut self) -> u16 {
+        self.next_ip_id = self.next_ip_id.wrapping_add(1);
+        self.next_ip_id
+    }
+
+    /// Run timers and collect packets to send
+    ///
+    /// # Returns
+    /// * Packets, and when to poll again at the latest
+    fn poll(&mut self, now: Instant) -> (Vec<Vec<u8>>, Option<Instant>) {
+        let mut packets = std::mem::take(&mut self.resets);
+        let mut deadline: Option<Instant> = None;
+        let mut finished = Vec::new();
+        let mut segments = Vec::new();
+
+        let ids: Vec<ConnectionId> = self.connections.keys().copied().collect();
+        for id in ids {
+            let connection = self.connections.get_mut(&id).expect("id from the map");
+            connection.poll(now, &mut segments);
+
+            let window = connection.window().min(u16::MAX as usize);
+            for segment in segments.drain(..) {
+                let mss = (segment.flags & SYN != 0).then_some(MSS as u16);
+                let ack = if segment.flags & ACK != 0 {
+                    connection.rcv_nxt
+                } else {
+                    0
+                };
+                connection.advertised_window = window;
+                connection.ack_pending = false;
+                self.next_ip_id = self.next_ip_id.wrapping_add(1);
+                packets.push(build_packet(
+                    id.local,
+                    id.remote,
+                    segment.seq,
+                    ack,
+                    segment.flags,
+                    window as u16,
+                    mss,
+                    &segment.payload,
+                    self.next_ip_id,
+                ));
+            }
+
+            let lingered = connection
+                .released
+                .is_some_and(|released| now.duration_since(released) >= LINGER_TIMEOUT);
+            if connection.released.is_some() && (connection.state == TcpState::Closed || lingered) {
+                finished.push(id);
+                continue;
+            }
+            for timer in [
+                connection.retransmit_at,
+                connection.time_wait_until,
+                connection.released.map(|r| r + LINGER_TIMEOUT),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                deadline = Some(deadline.map_or(timer, |d| d.min(timer)));
+            }
+        }
+
+        for id in finished {
+            self.connections.remove(&id);
+        }
+        (packets, deadline)
+    }
+
+    fn link_closed(&mut self) {
+        self.closed = true;
+        for connection in self.connections.values_mut() {
+            connection.abort(io::ErrorKind::ConnectionAborted);
+        }
+        if let Some(waker) = self.accept_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Parsed TCP segment
+struct Segment<'a> {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    /// Parse an IPv4 packet carrying TCP, checking both checksums
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < IP_HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = usize::from(packet[0] & 0x0f) * 4;
+        let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        // Fragments aren't reassembled
+        let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+        if header_len < IP_HEADER_SIZE
+            || total_len < header_len + TCP_HEADER_SIZE
+            || total_len > packet.len()
+            || fragmented
+            || packet[9] != PROTOCOL_TCP
+            || checksum(&[&packet[..header_len]]) != 0
+        {
+            return None;
+        }
+
+        let src_ip = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let dst_ip = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let tcp = &packet[header_len..total_len];
+        if checksum(&[&pseudo_header(src_ip, dst_ip, tcp.len()), tcp]) != 0 {
+            return None;
+        }
+
+        let data_offset = usize::from(tcp[12] >> 4) * 4;
+        if data_offset < TCP_HEADER_SIZE || data_offset > tcp.len() {
+            return None;
+        }
+
+        Some(Self {
+            src: SocketAddrV4::new(src_ip, u16::from_be_bytes([tcp[0], tcp[1]])),
+            dst: SocketAddrV4::new(dst_ip, u16::from_be_bytes([tcp[2], tcp[3]])),
+            seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            ack: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+            flags: tcp[13],
+            window: u16::from_be_bytes([tcp[14], tcp[15]]),
+            mss: parse_mss(&tcp[TCP_HEADER_SIZE..data_offset]),
+            payload: &tcp[data_offset..],
+        })
+    }
+
+    /// Sequence numbers the segment takes
+    fn sequence_len(&self) -> u32 {
+        self.payload.len() as u32
+            + u32::from(self.flags & SYN != 0)
+            + u32::from(self.flags & FIN != 0)
+    }
+}
+
+fn parse_mss(mut options: &[u8]) -> Option<u16> {
+    while let Some(&kind) = options.first() {
+        match kind {
+            0 => break,
+            1 => options = &options[1..],
+            _ => {
+                let len = usize::from(*options.get(1)?);
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if kind == 2 && len == 4 {
+                    return Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    None
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_packet(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &[u8],
+    ip_id: u16,
+) -> Vec<u8> {
+    let options_len = if mss.is_some() { 4 } else { 0 };
+    let tcp_len = TCP_HEADER_SIZE + options_len + payload.len();
+    let mut packet = Vec::with_capacity(IP_HEADER_SIZE + tcp_len);
+
+    // IPv4 header, don't fragment
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((IP_HEADER_SIZE + tcp_len) as u16).to_be_bytes());
+    packet.extend_from_slice(&ip_id.to_be_bytes());
+    packet.extend_from_slice(&[0x40, 0, TTL, PROTOCOL_TCP, 0, 0]);
+    packet.extend_from_slice(&src.ip().octets());
+    packet.extend_from_slice(&dst.ip().octets());
+    let ip_checksum = checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    // TCP header
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    packet.push((((TCP_HEADER_SIZE + options_len) / 4) as u8) << 4);
+    packet.push(flags);
+    packet.extend_from_slice(&window.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+        packet.extend_from_slice(&[2, 4]);
+        packet.extend_from_slice(&mss.to_be_bytes());
+    }
+    packet.extend_from_slice(payload);
+
+    let tcp_checksum = checksum(&[
+        &pseudo_header(*src.ip(), *dst.ip(), tcp_len),
+        &packet[IP_HEADER_SIZE..],
+    ]);
+    packet[IP_HEADER_SIZE + 16..IP_HEADER_SIZE + 18].copy_from_slice(&tcp_checksum.to_be_bytes());
+    packet
+}
+
+fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, tcp_len: usize) -> [u8; 12] {
+    let mut header = [0u8; 12];
+    header[..4].copy_from_slice(&src.octets());
+    header[4..8].copy_from_slice(&dst.octets());
+    header[9] = PROTOCOL_TCP;
+    header[10..].copy_from_slice(&(tcp_len as u16).to_be_bytes());
+    header
+}
+
+/// Internet checksum over the concatenation of `parts` (each even-length but the last)
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        let mut words = part.chunks_exact(2);
+        for word in &mut words {
+            sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = words.remainder() {
+            sum += u32::from(*last) << 8;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    /// In-memory link that drops a fraction of packets
+    struct MemoryLink {
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+        loss: f64,
+    }
+
+    impl PacketLink for MemoryLink {
+        fn send(&self, packet: Vec<u8>) -> LinkFuture<'_, ()> {
+            Box::pin(async move {
+                if !rand::thread_rng().gen_bool(self.loss) {
+                    let _ = self.tx.send(packet);
+                }
+                Ok(())
+            })
+        }
+
+        fn recv(&self) -> LinkFuture<'_, Option<Vec<u8>>> {
+            Box::pin(async move { Ok(self.rx.lock().await.recv().await) })
+        }
+    }
+
+    fn link_pair(loss: f64) -> (MemoryLink, MemoryLink) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        (
+            MemoryLink {
+                tx: a_tx,
+                rx: tokio::sync::Mutex::new(b_rx),
+                loss,
+            },
+            MemoryLink {
+                tx: b_tx,
+                rx: tokio::sync::Mutex::new(a_rx),
+                loss,
+            },
+        )
+    }
+
+    /// Client stack and a gateway echoing every connection
+    fn echo_gateway(loss: f64) -> NetStack {
+        let (client_link, gateway_link) = link_pair(loss);
+        let gateway = NetStack::gateway(gateway_link);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = gateway.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = tokio::io::split(stream);
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                    write.shutdown().await.unwrap();
+                });
+            }
+        });
+        NetStack::new(client_link, Ipv4Addr::new(10, 0, 0, 2))
+    }
+
+    async fn echo(stack: &NetStack, size: usize) {
+        let stream = stack
+            .connect("192.0.2.1:80".parse().unwrap())
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i * 7) as u8).collect();
+        let (mut read, mut write) = tokio::io::split(stream);
+
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            write.write_all(&sent).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+        let mut echoed = Vec::new();
+        read.read_to_end(&mut echoed).await.unwrap();
+        writer.await.unwrap();
+        assert!(echoed == data);
+    }
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let src = "10.0.0.2:50000".parse().unwrap();
+        let dst = "192.0.2.1:443".parse().unwrap();
+        let packet = build_packet(src, dst, 1, 2, SYN | ACK, 1000, Some(1380), b"odd", 7);
+
+        let segment = Segment::parse(&packet).unwrap();
+        assert_eq!((segment.src, segment.dst), (src, dst));
+        assert_eq!((segment.seq, segment.ack, segment.flags), (1, 2, SYN | ACK));
+        assert_eq!((segment.window, segment.mss), (1000, Some(1380)));
+        assert_eq!(segment.payload, b"odd");
+
+        // Trailing padding (as added by WireGuard) is ignored, corruption isn't
+        let mut padded = packet.clone();
+        padded.resize(64, 0);
+        assert!(Segment::parse(&padded).is_some());
+        let mut corrupted = packet;
+        corrupted[45] ^= 1;
+        assert!(Segment::parse(&corrupted).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_transfer() {
+        let (client_link, gateway_link) = link_pair(0.0);
+        let gateway = NetStack::gateway(gateway_link);
+        let client = NetStack::new(client_link, Ipv4Addr::new(10, 0, 0, 2));
+
+        let destination: SocketAddrV4 = "198.51.100.7:8080".parse().unwrap();
+        let (connected, accepted) = tokio::join!(client.connect(destination), gateway.accept());
+        let mut stream = connected.unwrap();
+        let (mut remote, original_destination) = accepted.unwrap();
+        assert_eq!(original_destination, destination);
+        assert_eq!(remote.peer_addr(), stream.local_addr());
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut buffer = [0u8; 5];
+        remote.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        remote.shutdown().await.unwrap();
+        assert_eq!(stream.read(&mut buffer).await.unwrap(), 0);
+
+        echo(&echo_gateway(0.0), 1 << 20).await;
+    }
+
+    #[tokio::test]
+    async fn test_lossy_link() {
+        echo(&echo_gateway(0.05), 200_000).await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused() {
+        // A plain stack listens on nothing and answers SYNs with resets
+        let (client_link, server_link) = link_pair(0.0);
+        let _server = NetStack::new(server_link, Ipv4Addr::new(192, 0, 2, 1));
+        let client = NetStack::new(client_link, Ipv4Addr::new(10, 0, 0, 2));
+
+        let result = client.connect("192.0.2.1:80".parse().unwrap()).await;
+        assert!(matches!(result, Err(ScramblerError::NetworkError(_))));
+    }
+}
@@ -10,21 +10,38 @@
 //!
 //! - **PacketTransmitter:** Sends Sphinx packets to mix nodes
 //! - **DeadDropProtocol:** Stores/retrieves messages from dead drop nodes
+//! - **DirectoryProtocol:** Fetches the signed consensus from directory servers
+//! - **ResponseCollector:** Gathers response shares from dead drops or SURB replies
+//! - **Transport:** Each handler opens its connections through a
+//!   `PluggableTransport` (plain TCP unless one is configured)
+//! - **Dialer:** The connection under the transport comes from a `Dialer`:
+//!   direct TCP by default, the Ghost VPN tunnel once it is up
 //! - **ConnectionPool:** Manages persistent connections to reduce latency
 //! - **RetryPolicy:** Handles transient failures with exponential backoff
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::{sleep, timeout};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout, Instant};
 use serde::{Deserialize, Serialize};
 
 use crate::error::{Result, ScramblerError};
-use crate::sphinx::SphinxPacket;
-use crate::dead_drop::{AccessToken, DeadDropClient, DeadDropConfig, StoredMessage};
+use crate::sphinx::{open_reply, surb_id, use_surb, SphinxPacket, Surb, SurbSecrets};
+use crate::dead_drop::{
+    unix_now, AccessToken, DeadDropClient, DeadDropConfig, DropRatchet, FetchedPage, LeaseId,
+    StoredMessage,
+};
+use crate::directory::SignedConsensus;
+use crate::epoch::Epoch;
+use crate::stamp::{
+    store_context, BlindedTokens, StoreStamp, StoreToken, TokenBatch, TokenRequest, WorkStamp,
+};
 use crate::shamir::{Share, reconstruct_secret, ShamirConfig};
+use crate::transport::{BoxedStream, Dialer, PlainTransport, PluggableTransport, TcpDialer};
 
 /// Network protocol for mix node communication
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -83,49 +100,284 @@ impl MixNodeAddr {
     }
 }
 
+/// Maximum size of a single wire frame (bytes)
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
 /// Wire protocol message types
+///
+/// Frames are sent as a 4-byte big-endian length prefix followed by the
+/// bincode-encoded message.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-enum WireMessage {
+pub enum WireMessage {
     /// Forward Sphinx packet to next hop
     ForwardPacket {
+        /// Packet to process
         packet: SphinxPacket,
     },
     /// Store message in dead drop
     StoreDeadDrop {
+        /// Drop identifier
         drop_id: [u8; 32],
+        /// Token for later retrieval
         access_token: AccessToken,
+        /// Encrypted message
         payload: Vec<u8>,
+        /// Proof of work or store token authorizing the store
+        stamp: Option<StoreStamp>,
     },
     /// Retrieve messages from dead drop
     RetrieveDeadDrop {
+        /// Token registered when storing
         access_token: AccessToken,
     },
     /// Response: stored successfully
     StoreSuccess {
+        /// Identifier of the stored message
         message_id: [u8; 16],
     },
     /// Response: retrieved messages
     RetrieveSuccess {
+        /// Messages in the drop
+        messages: Vec<StoredMessage>,
+    },
+    /// Fetch a page of messages from a dead drop under a lease
+    FetchDeadDrop {
+        /// Token registered when storing
+        access_token: AccessToken,
+        /// Maximum messages in the page
+        limit: u32,
+    },
+    /// Response: leased messages
+    FetchSuccess {
+        /// Lease to acknowledge the messages under
+        lease_id: LeaseId,
+        /// Leased messages, oldest first
         messages: Vec<StoredMessage>,
+        /// Unleased messages left in the drop
+        remaining: u32,
+    },
+    /// Delete messages fetched under a lease
+    AckDeadDrop {
+        /// Token the messages were fetched with
+        access_token: AccessToken,
+        /// Lease the messages were fetched under
+        lease_id: LeaseId,
+        /// Identifiers of the messages to delete
+        message_ids: Vec<[u8; 16]>,
+    },
+    /// Response: messages deleted
+    AckSuccess {
+        /// Number of messages deleted
+        deleted: u32,
+    },
+    /// Request a batch of store tokens for a dead drop
+    IssueStoreTokens {
+        /// Drop the tokens authorize stores to
+        drop_id: [u8; 32],
+        /// Blinded tokens and the work paying for them
+        request: TokenRequest,
+    },
+    /// Response: evaluated store tokens
+    StoreTokens {
+        /// Evaluated tokens with their proof
+        batch: TokenBatch,
+    },
+    /// Request the current directory consensus
+    FetchConsensus,
+    /// Response: directory consensus
+    Consensus {
+        /// Consensus with authority signatures
+        consensus: SignedConsensus,
+    },
+    /// Echo request used to probe a transport
+    Probe {
+        /// Random bytes to echo back
+        payload: Vec<u8>,
+    },
+    /// Response: echoed probe payload
+    ProbeEcho {
+        /// Payload from the probe
+        payload: Vec<u8>,
+    },
+    /// Copy of a dead drop message stored at a sibling relay
+    ReplicateDeadDrop {
+        /// Ed25519 identity key of the sibling
+        origin: Vec<u8>,
+        /// Drop identifier
+        drop_id: [u8; 32],
+        /// Token registered when storing
+        access_token: AccessToken,
+        /// Message as stored at the sibling
+        message: StoredMessage,
+        /// Stamp the client stored the message with
+        stamp: Option<StoreStamp>,
+        /// Sibling's signature over the other fields
+        signature: Vec<u8>,
+    },
+    /// Messages retrieved at a sibling relay, to delete from its replicas
+    DeleteReplicas {
+        /// Ed25519 identity key of the sibling
+        origin: Vec<u8>,
+        /// Token the messages were retrieved with; replicas delete only
+        /// messages of the drop it opens
+        access_token: AccessToken,
+        /// Identifiers of the retrieved messages
+        message_ids: Vec<[u8; 16]>,
+        /// Sibling's signature over the other fields
+        signature: Vec<u8>,
     },
     /// Error response
     Error {
+        /// Error description
         message: String,
     },
 }
 
+/// Write a length-prefixed wire message to a stream
+pub async fn write_message<S>(stream: &mut S, message: &WireMessage) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let serialized = bincode::serialize(message)
+        .map_err(|e| ScramblerError::NetworkError(format!("Serialization failed: {}", e)))?;
+
+    if serialized.len() > MAX_FRAME_SIZE {
+        return Err(ScramblerError::NetworkError(format!(
+            "Frame too large: {} bytes",
+            serialized.len()
+        )));
+    }
+
+    let len = serialized.len() as u32;
+    stream.write_all(&len.to_be_bytes()).await
+        .map_err(|e| ScramblerError::NetworkError(format!("Write failed: {}", e)))?;
+    stream.write_all(&serialized).await
+        .map_err(|e| ScramblerError::NetworkError(format!("Write failed: {}", e)))?;
+    stream.flush().await
+        .map_err(|e| ScramblerError::NetworkError(format!("Flush failed: {}", e)))?;
+
+    Ok(())
+}
+
+/// Read a length-prefixed wire message from a stream
+///
+/// Returns `Ok(None)` if the peer closed the connection before a new frame.
+pub async fn read_message<S>(stream: &mut S) -> Result<Option<WireMessage>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len_bytes = [0u8; 4];
+    match stream.read_exact(&mut len_bytes).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => {
+            return Err(ScramblerError::NetworkError(format!("Read failed: {}", e)));
+        }
+    }
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(ScramblerError::NetworkError(format!(
+            "Frame too large: {} bytes",
+            len
+        )));
+    }
+
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await
+        .map_err(|e| ScramblerError::NetworkError(format!("Read failed: {}", e)))?;
+
+    let message = bincode::deserialize(&buf)
+        .map_err(|e| ScramblerError::NetworkError(format!("Deserialization failed: {}", e)))?;
+
+    Ok(Some(message))
+}
+
+/// Request carrying a single-use reply block
+///
+/// Sent as the Sphinx message when the sender wants the destination to answer
+/// through a SURB rather than a dead drop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SurbRequest {
+    /// Request body (e.g. an encoded Shamir share)
+    pub body: Vec<u8>,
+    /// Reply block for the response
+    pub surb: Surb,
+}
+
+impl SurbRequest {
+    /// Encode for use as a Sphinx message
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| ScramblerError::InvalidPacket(format!("Serialization failed: {}", e)))
+    }
+
+    /// Decode a delivered Sphinx message
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        bincode::deserialize(bytes)
+            .map_err(|e| ScramblerError::InvalidPacket(format!("Invalid SURB request: {}", e)))
+    }
+}
+
+/// Open a connection to `node` through `dialer` and `transport`
+///
+/// The connect timeout covers both the TCP connection and the transport
+/// handshake.
+async fn open_connection(
+    config: &NetworkConfig,
+    dialer: &dyn Dialer,
+    transport: &dyn PluggableTransport,
+    node: &MixNodeAddr,
+) -> Result<BoxedStream> {
+    let addr = node.socket_addr()?;
+
+    let connect_future = async {
+        let stream = dialer.dial(addr).await?;
+        transport.connect(stream).await
+    };
+
+    timeout(Duration::from_millis(config.connect_timeout_ms), connect_future)
+        .await
+        .map_err(|_| ScramblerError::NetworkError("Connection timeout".to_string()))?
+}
+
 /// Packet transmitter
 ///
 /// Sends Sphinx packets through the network to mix nodes.
 #[derive(Debug)]
 pub struct PacketTransmitter {
     config: NetworkConfig,
+    dialer: Arc<dyn Dialer>,
+    transport: Arc<dyn PluggableTransport>,
 }
 
 impl PacketTransmitter {
     /// Create a new packet transmitter
     pub fn new(config: NetworkConfig) -> Self {
-        Self { config }
+        Self::with_transport(config, Arc::new(PlainTransport))
+    }
+
+    /// Create a packet transmitter that camouflages its connections
+    ///
+    /// # Arguments
+    /// * `config` - Network configuration
+    /// * `transport` - Transport applied to every connection to a mix node
+    pub fn with_transport(config: NetworkConfig, transport: Arc<dyn PluggableTransport>) -> Self {
+        Self {
+            config,
+            dialer: Arc::new(TcpDialer),
+            transport,
+        }
+    }
+
+    /// Open connections through `dialer` instead of direct TCP
+    pub fn set_dialer(&mut self, dialer: Arc<dyn Dialer>) {
+        self.dialer = dialer;
+    }
+
+    /// Camouflage later connections with `transport`
+    pub fn set_transport(&mut self, transport: Arc<dyn PluggableTransport>) {
+        self.transport = transport;
     }
 
     /// Send a Sphinx packet to a mix node
@@ -178,54 +430,49 @@ impl PacketTransmitter {
         }))
     }
 
+    /// Answer a request through its single-use reply block
+    ///
+    /// # Arguments
+    /// * `surb` - Reply block received with the request
+    /// * `reply` - Reply message
+    pub async fn send_reply(&self, surb: &Surb, reply: &[u8]) -> Result<()> {
+        let (first_hop, packet) = use_surb(surb, reply)?;
+
+        let node = MixNodeAddr {
+            address: first_hop.to_string(),
+            public_key: Vec::new(),
+        };
+
+        self.send_packet(&packet, &node).await
+    }
+
     /// Single attempt to send a packet
     async fn try_send_packet(
         &self,
         packet: &SphinxPacket,
         node: &MixNodeAddr,
     ) -> Result<()> {
-        let addr = node.socket_addr()?;
-
-        // Connect with timeout
-        let connect_future = TcpStream::connect(&addr);
-        let mut stream = timeout(
-            Duration::from_millis(self.config.connect_timeout_ms),
-            connect_future,
+        let mut stream = open_connection(
+            &self.config,
+            self.dialer.as_ref(),
+            self.transport.as_ref(),
+            node,
         )
-        .await
-        .map_err(|_| ScramblerError::NetworkError("Connection timeout".to_string()))?
-        .map_err(|e| ScramblerError::NetworkError(format!("Connection failed: {}", e)))?;
+        .await?;
 
-        // Serialize packet with wire protocol
+        // Send packet with wire protocol
         let message = WireMessage::ForwardPacket {
             packet: packet.clone(),
         };
 
-        let serialized = bincode::serialize(&message)
-            .map_err(|e| ScramblerError::NetworkError(format!("Serialization failed: {}", e)))?;
-
-        // Send length prefix (4 bytes)
-        let len = serialized.len() as u32;
-        let len_bytes = len.to_be_bytes();
-
-        // Write with timeout
-        let write_future = async {
-            stream.write_all(&len_bytes).await?;
-            stream.write_all(&serialized).await?;
-            stream.flush().await?;
-            Ok::<(), std::io::Error>(())
-        };
-
         timeout(
             Duration::from_millis(self.config.write_timeout_ms),
-            write_future,
+            write_message(&mut stream, &message),
         )
         .await
-        .map_err(|_| ScramblerError::NetworkError("Write timeout".to_string()))?
-        .map_err(|e| ScramblerError::NetworkError(format!("Write failed: {}", e)))?;
+        .map_err(|_| ScramblerError::NetworkError("Write timeout".to_string()))??;
 
         tracing::debug!(
-            bytes_sent = serialized.len(),
             node = %node.address,
             "Packet transmitted"
         );
@@ -234,6 +481,9 @@ impl PacketTransmitter {
     }
 }
 
+/// Token keys pinned per drop and token period
+type TokenKeys = HashMap<([u8; 32], u64), [u8; 32]>;
+
 /// Dead drop protocol handler
 ///
 /// Implements the dead drop protocol for storing and retrieving messages.
@@ -241,18 +491,49 @@ impl PacketTransmitter {
 pub struct DeadDropProtocol {
     config: NetworkConfig,
     client: DeadDropClient,
+    dialer: Arc<dyn Dialer>,
+    transport: Arc<dyn PluggableTransport>,
+    /// Token key of each drop and period seen in an issued batch
+    token_keys: Mutex<TokenKeys>,
 }
 
 impl DeadDropProtocol {
     /// Create a new dead drop protocol handler
     pub fn new(config: NetworkConfig, drop_config: DeadDropConfig) -> Self {
+        Self::with_transport(config, drop_config, Arc::new(PlainTransport))
+    }
+
+    /// Create a dead drop protocol handler that camouflages its connections
+    ///
+    /// # Arguments
+    /// * `config` - Network configuration
+    /// * `drop_config` - Dead drop configuration
+    /// * `transport` - Transport applied to every connection to a dead drop node
+    pub fn with_transport(
+        config: NetworkConfig,
+        drop_config: DeadDropConfig,
+        transport: Arc<dyn PluggableTransport>,
+    ) -> Self {
         Self {
             config,
             client: DeadDropClient::new(drop_config),
+            dialer: Arc::new(TcpDialer),
+            transport,
+            token_keys: Mutex::new(HashMap::new()),
         }
     }
 
-    /// Store a message in a dead drop node
+    /// Open connections through `dialer` instead of direct TCP
+    pub fn set_dialer(&mut self, dialer: Arc<dyn Dialer>) {
+        self.dialer = dialer;
+    }
+
+    /// Camouflage later connections with `transport`
+    pub fn set_transport(&mut self, transport: Arc<dyn PluggableTransport>) {
+        self.transport = transport;
+    }
+
+    /// Store a message in a dead drop node, paying for it with work
     ///
     /// # Arguments
     /// * `node` - Dead drop node address
@@ -269,57 +550,52 @@ impl DeadDropProtocol {
         access_token: AccessToken,
         payload: Vec<u8>,
     ) -> Result<[u8; 16]> {
-        let addr = node.socket_addr()?;
+        let bits = self.client.stamp_policy().work_bits;
+        let stamp = match bits {
+            0 => None,
+            bits => {
+                let context = store_context(&drop_id, &payload);
+                let work = tokio::task::spawn_blocking(move || {
+                    WorkStamp::mint(&context, bits, unix_now())
+                })
+                .await
+                .map_err(|e| ScramblerError::StampError(e.to_string()))?;
+                Some(StoreStamp::Work(work))
+            }
+        };
 
-        // Connect
-        let connect_future = TcpStream::connect(&addr);
-        let mut stream = timeout(
-            Duration::from_millis(self.config.connect_timeout_ms),
-            connect_future,
-        )
-        .await
-        .map_err(|_| ScramblerError::NetworkError("Connection timeout".to_string()))?
-        .map_err(|e| ScramblerError::NetworkError(format!("Connection failed: {}", e)))?;
+        self.store_stamped(node, drop_id, access_token, payload, stamp).await
+    }
+
+    /// Store a message in a dead drop node, spending a store token
+    pub async fn store_with_token(
+        &self,
+        node: &MixNodeAddr,
+        access_token: AccessToken,
+        payload: Vec<u8>,
+        token: &StoreToken,
+    ) -> Result<[u8; 16]> {
+        let stamp = token.redeem(&payload);
+        self.store_stamped(node, token.drop_id, access_token, payload, Some(stamp))
+            .await
+    }
 
-        // Send store request
-        let message = WireMessage::StoreDeadDrop {
+    async fn store_stamped(
+        &self,
+        node: &MixNodeAddr,
+        drop_id: [u8; 32],
+        access_token: AccessToken,
+        payload: Vec<u8>,
+        stamp: Option<StoreStamp>,
+    ) -> Result<[u8; 16]> {
+        let request = WireMessage::StoreDeadDrop {
             drop_id,
             access_token,
             payload,
+            stamp,
         };
 
-        let serialized = bincode::serialize(&message)
-            .map_err(|e| ScramblerError::NetworkError(format!("Serialization failed: {}", e)))?;
-
-        // Write request
-        let len = serialized.len() as u32;
-        stream.write_all(&len.to_be_bytes()).await
-            .map_err(|e| ScramblerError::NetworkError(format!("Write failed: {}", e)))?;
-        stream.write_all(&serialized).await
-            .map_err(|e| ScramblerError::NetworkError(format!("Write failed: {}", e)))?;
-        stream.flush().await
-            .map_err(|e| ScramblerError::NetworkError(format!("Flush failed: {}", e)))?;
-
-        // Read response
-        let mut len_bytes = [0u8; 4];
-        let read_future = stream.read_exact(&mut len_bytes);
-        timeout(
-            Duration::from_millis(self.config.read_timeout_ms),
-            read_future,
-        )
-        .await
-        .map_err(|_| ScramblerError::NetworkError("Read timeout".to_string()))?
-        .map_err(|e| ScramblerError::NetworkError(format!("Read failed: {}", e)))?;
-
-        let response_len = u32::from_be_bytes(len_bytes) as usize;
-        let mut response_bytes = vec![0u8; response_len];
-        stream.read_exact(&mut response_bytes).await
-            .map_err(|e| ScramblerError::NetworkError(format!("Read failed: {}", e)))?;
-
-        let response: WireMessage = bincode::deserialize(&response_bytes)
-            .map_err(|e| ScramblerError::NetworkError(format!("Deserialization failed: {}", e)))?;
-
-        match response {
+        match self.exchange(node, &request).await? {
             WireMessage::StoreSuccess { message_id } => {
                 tracing::debug!(
                     message_id = ?message_id,
@@ -350,55 +626,11 @@ impl DeadDropProtocol {
         node: &MixNodeAddr,
         access_token: &AccessToken,
     ) -> Result<Vec<StoredMessage>> {
-        let addr = node.socket_addr()?;
-
-        // Connect
-        let connect_future = TcpStream::connect(&addr);
-        let mut stream = timeout(
-            Duration::from_millis(self.config.connect_timeout_ms),
-            connect_future,
-        )
-        .await
-        .map_err(|_| ScramblerError::NetworkError("Connection timeout".to_string()))?
-        .map_err(|e| ScramblerError::NetworkError(format!("Connection failed: {}", e)))?;
-
-        // Send retrieve request
-        let message = WireMessage::RetrieveDeadDrop {
+        let request = WireMessage::RetrieveDeadDrop {
             access_token: *access_token,
         };
 
-        let serialized = bincode::serialize(&message)
-            .map_err(|e| ScramblerError::NetworkError(format!("Serialization failed: {}", e)))?;
-
-        // Write request
-        let len = serialized.len() as u32;
-        stream.write_all(&len.to_be_bytes()).await
-            .map_err(|e| ScramblerError::NetworkError(format!("Write failed: {}", e)))?;
-        stream.write_all(&serialized).await
-            .map_err(|e| ScramblerError::NetworkError(format!("Write failed: {}", e)))?;
-        stream.flush().await
-            .map_err(|e| ScramblerError::NetworkError(format!("Flush failed: {}", e)))?;
-
-        // Read response
-        let mut len_bytes = [0u8; 4];
-        let read_future = stream.read_exact(&mut len_bytes);
-        timeout(
-            Duration::from_millis(self.config.read_timeout_ms),
-            read_future,
-        )
-        .await
-        .map_err(|_| ScramblerError::NetworkError("Read timeout".to_string()))?
-        .map_err(|e| ScramblerError::NetworkError(format!("Read failed: {}", e)))?;
-
-        let response_len = u32::from_be_bytes(len_bytes) as usize;
-        let mut response_bytes = vec![0u8; response_len];
-        stream.read_exact(&mut response_bytes).await
-            .map_err(|e| ScramblerError::NetworkError(format!("Read failed: {}", e)))?;
-
-        let response: WireMessage = bincode::deserialize(&response_bytes)
-            .map_err(|e| ScramblerError::NetworkError(format!("Deserialization failed: {}", e)))?;
-
-        match response {
+        match self.exchange(node, &request).await? {
             WireMessage::RetrieveSuccess { messages } => {
                 tracing::debug!(
                     count = messages.len(),
@@ -416,6 +648,202 @@ impl DeadDropProtocol {
         }
     }
 
+    /// Fetch a page of messages from a dead drop node under a lease
+    ///
+    /// The messages stay on the node until acknowledged with `ack`; if the
+    /// lease runs out first they are delivered again.
+    ///
+    /// # Arguments
+    /// * `node` - Dead drop node address
+    /// * `access_token` - Access token
+    /// * `limit` - Maximum messages in the page
+    ///
+    /// # Returns
+    /// * Leased messages and the count still waiting
+    pub async fn fetch(
+        &self,
+        node: &MixNodeAddr,
+        access_token: &AccessToken,
+        limit: u32,
+    ) -> Result<FetchedPage> {
+        let request = WireMessage::FetchDeadDrop {
+            access_token: *access_token,
+            limit,
+        };
+
+        match self.exchange(node, &request).await? {
+            WireMessage::FetchSuccess {
+                lease_id,
+                messages,
+                remaining,
+            } => {
+                tracing::debug!(
+                    count = messages.len(),
+                    remaining,
+                    node = %node.address,
+                    "Messages fetched from dead drop"
+                );
+                Ok(FetchedPage {
+                    lease_id,
+                    messages,
+                    remaining: remaining as usize,
+                })
+            }
+            WireMessage::Error { message } => Err(ScramblerError::NetworkError(
+                format!("Dead drop fetch failed: {}", message),
+            )),
+            _ => Err(ScramblerError::NetworkError(
+                "Unexpected response from dead drop".to_string(),
+            )),
+        }
+    }
+
+    /// Delete fetched messages from a dead drop node
+    ///
+    /// # Arguments
+    /// * `node` - Dead drop node address
+    /// * `access_token` - Access token the messages were fetched with
+    /// * `lease_id` - Lease the messages were fetched under
+    /// * `message_ids` - Messages to delete
+    ///
+    /// # Returns
+    /// * Number of messages deleted (0 if the lease had expired)
+    pub async fn ack(
+        &self,
+        node: &MixNodeAddr,
+        access_token: &AccessToken,
+        lease_id: &LeaseId,
+        message_ids: Vec<[u8; 16]>,
+    ) -> Result<usize> {
+        let request = WireMessage::AckDeadDrop {
+            access_token: *access_token,
+            lease_id: *lease_id,
+            message_ids,
+        };
+
+        match self.exchange(node, &request).await? {
+            WireMessage::AckSuccess { deleted } => Ok(deleted as usize),
+            WireMessage::Error { message } => Err(ScramblerError::NetworkError(
+                format!("Dead drop ack failed: {}", message),
+            )),
+            _ => Err(ScramblerError::NetworkError(
+                "Unexpected response from dead drop".to_string(),
+            )),
+        }
+    }
+
+    /// Send a request to a dead drop node and read its response
+    /// Obtain store tokens for a drop to hand to a contact
+    ///
+    /// Pays the issuance work, then checks the batch's proof and that the
+    /// relay used the same key as for earlier batches of the drop and period,
+    /// so batches handed to different contacts can't be told apart.
+    ///
+    /// # Arguments
+    /// * `node` - Dead drop node holding the drop
+    /// * `drop_id` - Drop the tokens authorize stores to
+    /// * `count` - Number of tokens
+    pub async fn issue_tokens(
+        &self,
+        node: &MixNodeAddr,
+        drop_id: [u8; 32],
+        count: usize,
+    ) -> Result<Vec<StoreToken>> {
+        let policy = self.client.stamp_policy().clone();
+        let (request, blinded) = tokio::task::spawn_blocking(move || {
+            BlindedTokens::new(drop_id, count, &policy, unix_now())
+        })
+        .await
+        .map_err(|e| ScramblerError::StampError(e.to_string()))?;
+
+        let request = WireMessage::IssueStoreTokens { drop_id, request };
+        let batch = match self.exchange(node, &request).await? {
+            WireMessage::StoreTokens { batch } => batch,
+            WireMessage::Error { message } => {
+                return Err(ScramblerError::NetworkError(format!(
+                    "Store token issuance failed: {}",
+                    message
+                )))
+            }
+            _ => {
+                return Err(ScramblerError::NetworkError(
+                    "Unexpected response from dead drop".to_string(),
+                ))
+            }
+        };
+
+        let tokens = blinded.finalize(&batch)?;
+
+        let mut token_keys = self.token_keys.lock().unwrap_or_else(|e| e.into_inner());
+        let pinned = token_keys
+            .entry((drop_id, batch.period))
+            .or_insert(batch.public_key);
+        if *pinned != batch.public_key {
+            return Err(ScramblerError::StampError(
+                "Relay changed the drop's token key".to_string(),
+            ));
+        }
+
+        Ok(tokens)
+    }
+
+    async fn exchange(&self, node: &MixNodeAddr, request: &WireMessage) -> Result<WireMessage> {
+        let mut stream = open_connection(
+            &self.config,
+            self.dialer.as_ref(),
+            self.transport.as_ref(),
+            node,
+        )
+        .await?;
+
+        write_message(&mut stream, request).await?;
+
+        timeout(
+            Duration::from_millis(self.config.read_timeout_ms),
+            read_message(&mut stream),
+        )
+        .await
+        .map_err(|_| ScramblerError::NetworkError("Read timeout".to_string()))??
+        .ok_or_else(|| ScramblerError::NetworkError("Connection closed".to_string()))
+    }
+
+    /// Retrieve messages from the first reachable replica of a dead drop
+    ///
+    /// Relays replicating a drop hold the same messages under the same IDs,
+    /// so callers should ignore IDs they have already seen.
+    ///
+    /// # Arguments
+    /// * `nodes` - Relays holding the drop, in order of preference
+    /// * `access_token` - Access token
+    ///
+    /// # Returns
+    /// * Messages from the first relay that answered
+    pub async fn retrieve_from_any(
+        &self,
+        nodes: &[MixNodeAddr],
+        access_token: &AccessToken,
+    ) -> Result<Vec<StoredMessage>> {
+        let mut last_error = None;
+
+        for node in nodes {
+            match self.retrieve(node, access_token).await {
+                Ok(messages) => return Ok(messages),
+                Err(e) => {
+                    tracing::debug!(
+                        node = %node.address,
+                        error = %e,
+                        "Dead drop replica unreachable"
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            ScramblerError::NetworkError("No dead drop nodes given".to_string())
+        }))
+    }
+
     /// Derive drop ID from recipient key
     pub fn derive_drop_id(&self, recipient_key: &[u8]) -> [u8; 32] {
         self.client.derive_drop_id(recipient_key)
@@ -425,11 +853,101 @@ impl DeadDropProtocol {
     pub fn derive_access_token(&self, shared_secret: &[u8]) -> AccessToken {
         self.client.derive_access_token(shared_secret)
     }
+
+    /// Create a rotating drop address ratchet for a conversation
+    pub fn drop_ratchet(&self, shared_secret: &[u8], start: Epoch) -> Result<DropRatchet> {
+        self.client.drop_ratchet(shared_secret, start)
+    }
+
+    /// Access tokens for a cover poll
+    pub fn cover_poll(&self) -> Vec<AccessToken> {
+        self.client.generate_cover_poll()
+    }
+}
+
+/// Directory protocol handler
+///
+/// Fetches the signed consensus document from directory servers. The caller
+/// verifies it against its trusted authorities.
+#[derive(Debug)]
+pub struct DirectoryProtocol {
+    config: NetworkConfig,
+    dialer: Arc<dyn Dialer>,
+    transport: Arc<dyn PluggableTransport>,
+}
+
+impl DirectoryProtocol {
+    /// Create a new directory protocol handler
+    pub fn new(config: NetworkConfig) -> Self {
+        Self::with_transport(config, Arc::new(PlainTransport))
+    }
+
+    /// Create a directory protocol handler that camouflages its connections
+    ///
+    /// # Arguments
+    /// * `config` - Network configuration
+    /// * `transport` - Transport applied to every connection to a directory server
+    pub fn with_transport(config: NetworkConfig, transport: Arc<dyn PluggableTransport>) -> Self {
+        Self {
+            config,
+            dialer: Arc::new(TcpDialer),
+            transport,
+        }
+    }
+
+    /// Open connections through `dialer` instead of direct TCP
+    pub fn set_dialer(&mut self, dialer: Arc<dyn Dialer>) {
+        self.dialer = dialer;
+    }
+
+    /// Camouflage later connections with `transport`
+    pub fn set_transport(&mut self, transport: Arc<dyn PluggableTransport>) {
+        self.transport = transport;
+    }
+
+    /// Fetch the current consensus from a directory server
+    ///
+    /// # Arguments
+    /// * `server` - Directory server address
+    ///
+    /// # Returns
+    /// * Unverified consensus
+    pub async fn fetch_consensus(&self, server: &MixNodeAddr) -> Result<SignedConsensus> {
+        let mut stream = open_connection(
+            &self.config,
+            self.dialer.as_ref(),
+            self.transport.as_ref(),
+            server,
+        )
+        .await?;
+
+        write_message(&mut stream, &WireMessage::FetchConsensus).await?;
+
+        // Read response
+        let response = timeout(
+            Duration::from_millis(self.config.read_timeout_ms),
+            read_message(&mut stream),
+        )
+        .await
+        .map_err(|_| ScramblerError::NetworkError("Read timeout".to_string()))??
+        .ok_or_else(|| ScramblerError::NetworkError("Connection closed".to_string()))?;
+
+        match response {
+            WireMessage::Consensus { consensus } => Ok(consensus),
+            WireMessage::Error { message } => Err(ScramblerError::NetworkError(
+                format!("Consensus fetch failed: {}", message),
+            )),
+            _ => Err(ScramblerError::NetworkError(
+                "Unexpected response from directory server".to_string(),
+            )),
+        }
+    }
 }
 
 /// RPC response collector
 ///
-/// Collects RPC response shares from dead drops and reconstructs the response.
+/// Collects RPC response shares from dead drops or SURB replies and
+/// reconstructs the response.
 #[derive(Debug)]
 pub struct ResponseCollector {
     /// Dead drop protocol handler
@@ -450,6 +968,16 @@ impl ResponseCollector {
         }
     }
 
+    /// Open dead drop connections through `dialer` instead of direct TCP
+    pub fn set_dialer(&mut self, dialer: Arc<dyn Dialer>) {
+        self.dead_drop.set_dialer(dialer);
+    }
+
+    /// Camouflage later dead drop connections with `transport`
+    pub fn set_transport(&mut self, transport: Arc<dyn PluggableTransport>) {
+        self.dead_drop.set_transport(transport);
+    }
+
     /// Collect RPC response from dead drops
     ///
     /// # Arguments
@@ -474,6 +1002,7 @@ impl ResponseCollector {
         // Poll dead drops for shares
         let poll_start = std::time::Instant::now();
         let mut shares: Vec<Share> = Vec::new();
+        let mut answered = vec![false; drop_nodes.len()];
 
         while shares.len() < self.shamir_config.threshold {
             if poll_start.elapsed() > max_wait {
@@ -486,24 +1015,56 @@ impl ResponseCollector {
 
             // Try to retrieve from each dead drop
             for (i, (token, node)) in access_tokens.iter().zip(drop_nodes.iter()).enumerate() {
-                // Skip if we already have this share
-                if shares.iter().any(|s| s.index == (i + 1) as u8) {
+                // Skip if this drop already delivered its share
+                if answered[i] {
                     continue;
                 }
 
-                // Try to retrieve messages
-                match self.dead_drop.retrieve(node, token).await {
-                    Ok(messages) => {
-                        if !messages.is_empty() {
+                // Try to fetch the share
+                match self.dead_drop.fetch(node, token, 1).await {
+                    Ok(page) => {
+                        if let Some(message) = page.messages.first() {
+                            answered[i] = true;
+
+                            // Taken either way; an unacknowledged share is only redelivered
+                            let acked = self
+                                .dead_drop
+                                .ack(node, token, &page.lease_id, vec![message.id])
+                                .await;
+                            if let Err(e) = acked {
+                                tracing::debug!(
+                                    node = %node.address,
+                                    error = %e,
+                                    "Failed to acknowledge share"
+                                );
+                            }
+
                             // Take first message as the share
-                            let message = &messages[0];
-                            shares.push(Share {
-                                index: (i + 1) as u8,
-                                data: message.payload.clone(),
-                            });
+                            let share = match Share::from_bytes(&message.payload) {
+                                Ok(share) => share,
+                                Err(e) => {
+                                    tracing::warn!(
+                                        node = %node.address,
+                                        error = %e,
+                                        "Discarding malformed share"
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            if shares.iter().any(|s| s.index == share.index) {
+                                tracing::warn!(
+                                    share_index = share.index,
+                                    "Discarding duplicate share"
+                                );
+                                continue;
+                            }
+
+                            let share_index = share.index;
+                            shares.push(share);
 
                             tracing::debug!(
-                                share_index = i + 1,
+                                share_index,
                                 shares_collected = shares.len(),
                                 threshold = self.shamir_config.threshold,
                                 "Share retrieved"
@@ -542,6 +1103,119 @@ impl ResponseCollector {
 
         Ok(response)
     }
+
+    /// Collect RPC response from SURB replies
+    ///
+    /// # Arguments
+    /// * `listener` - Listener bound to the SURBs' reply address
+    /// * `surbs` - Secrets of the SURBs handed out (one per share)
+    /// * `max_wait` - Maximum wait time for responses
+    ///
+    /// # Returns
+    /// * Reconstructed RPC response
+    pub async fn collect_replies(
+        &self,
+        listener: &TcpListener,
+        surbs: &[SurbSecrets],
+        max_wait: Duration,
+    ) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + max_wait;
+        let mut shares: Vec<Share> = Vec::new();
+        let mut answered = vec![false; surbs.len()];
+
+        while shares.len() < self.shamir_config.threshold {
+            let timed_out = || {
+                ScramblerError::NetworkError(format!(
+                    "Timeout waiting for responses ({}/{})",
+                    shares.len(),
+                    self.shamir_config.threshold
+                ))
+            };
+
+            // Last reply hops connect to us to hand over the packet
+            let (mut stream, peer) = match timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                listener.accept(),
+            ).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    tracing::warn!(error = %e, "Failed to accept reply connection");
+                    continue;
+                }
+                Err(_) => return Err(timed_out()),
+            };
+
+            let packet = match timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                read_message(&mut stream),
+            ).await {
+                Ok(Ok(Some(WireMessage::ForwardPacket { packet }))) => packet,
+                Ok(Ok(_)) => {
+                    tracing::debug!(%peer, "Ignoring non-packet message on reply listener");
+                    continue;
+                }
+                Ok(Err(e)) => {
+                    tracing::debug!(%peer, error = %e, "Failed to read reply");
+                    continue;
+                }
+                Err(_) => return Err(timed_out()),
+            };
+
+            // Match the reply to the SURB it was sent through
+            let Some(i) = surbs.iter().position(|s| s.id == surb_id(&packet)) else {
+                tracing::warn!(%peer, "Discarding reply for unknown SURB");
+                continue;
+            };
+
+            if answered[i] {
+                tracing::warn!(%peer, "Discarding second reply through single-use SURB");
+                continue;
+            }
+
+            let reply = match open_reply(&surbs[i], &packet) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    tracing::warn!(%peer, error = %e, "Discarding undecryptable reply");
+                    continue;
+                }
+            };
+            answered[i] = true;
+
+            let share = match Share::from_bytes(&reply) {
+                Ok(share) => share,
+                Err(e) => {
+                    tracing::warn!(%peer, error = %e, "Discarding malformed share");
+                    continue;
+                }
+            };
+
+            if shares.iter().any(|s| s.index == share.index) {
+                tracing::warn!(share_index = share.index, "Discarding duplicate share");
+                continue;
+            }
+
+            let share_index = share.index;
+            shares.push(share);
+
+            tracing::debug!(
+                share_index,
+                shares_collected = shares.len(),
+                threshold = self.shamir_config.threshold,
+                "Share received through SURB"
+            );
+        }
+
+        // Reconstruct response from shares
+        let response = reconstruct_secret(&shares, &self.shamir_config)?;
+
+        tracing::info!(
+            shares_used = shares.len(),
+            response_size = response.len(),
+            "RPC response reconstructed from SURB replies"
+        );
+
+        Ok(response)
+    }
 }
 
 #[cfg(test)]
@@ -556,6 +1230,35 @@ mod tests {
         assert_eq!(config.protocol, NetworkProtocol::Tcp);
     }
 
+    #[tokio::test]
+    async fn test_wire_message_framing() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+
+        let message = WireMessage::RetrieveDeadDrop { access_token: [3u8; 32] };
+        write_message(&mut client, &message).await.unwrap();
+        drop(client);
+
+        match read_message(&mut server).await.unwrap() {
+            Some(WireMessage::RetrieveDeadDrop { access_token }) => {
+                assert_eq!(access_token, [3u8; 32]);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // Clean close between frames
+        assert!(read_message(&mut server).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let len = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        client.write_all(&len).await.unwrap();
+
+        assert!(read_message(&mut server).await.is_err());
+    }
+
     #[test]
     fn test_mix_node_addr_parsing() {
         let node = MixNodeAddr {
@@ -598,6 +1301,91 @@ mod tests {
         assert_ne!(drop_id, drop_id2);
     }
 
+    #[tokio::test]
+    async fn test_collect_replies_through_surbs() {
+        use crate::shamir::split_secret;
+        use crate::sphinx::{build_surb, process_packet, ProcessedPacket, RouteSpec};
+        use invisible_crypto::keys::KeyPair;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reply_to = listener.local_addr().unwrap();
+
+        let shamir = ShamirConfig::default();
+        let collector = ResponseCollector::new(
+            NetworkConfig::default(),
+            DeadDropConfig::default(),
+            shamir.clone(),
+        );
+
+        // Single reply hop standing in for the mixnet
+        let hop = KeyPair::generate().unwrap();
+        let route = RouteSpec {
+            node_keys: vec![hop.public_key().to_vec()],
+            node_addresses: vec!["127.0.0.1:9000".parse().unwrap()],
+            delays: vec![Duration::from_millis(0)],
+            epoch: 0,
+            destination: Vec::new(),
+        };
+
+        let response = b"{\"result\":\"0x1\"}".to_vec();
+        let shares = split_secret(&response, &shamir).unwrap();
+
+        let mut secrets = Vec::new();
+        let mut replies = Vec::new();
+        for share in shares.iter().take(shamir.threshold) {
+            let (surb, surb_secrets) = build_surb(&route, reply_to).unwrap();
+            secrets.push(surb_secrets);
+
+            // Destination answers; the hop forwards the reply to the listener
+            let (_, packet) = use_surb(&surb, &share.to_bytes()).unwrap();
+            match process_packet(&packet, hop.private_key()).unwrap() {
+                ProcessedPacket::Forward { next_hop, packet, .. } => {
+                    assert_eq!(next_hop, reply_to);
+                    replies.push(packet);
+                }
+                ProcessedPacket::Deliver { .. } => panic!("Reply hops must not deliver"),
+            }
+        }
+
+        tokio::spawn(async move {
+            let transmitter = PacketTransmitter::new(NetworkConfig::default());
+            let node = MixNodeAddr {
+                address: reply_to.to_string(),
+                public_key: Vec::new(),
+            };
+            for packet in replies {
+                transmitter.send_packet(&packet, &node).await.unwrap();
+            }
+        });
+
+        let collected = collector
+            .collect_replies(&listener, &secrets, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(collected, response);
+    }
+
+    #[test]
+    fn test_surb_request_roundtrip() {
+        use crate::sphinx::{build_surb, RouteSpec};
+
+        let route = RouteSpec {
+            node_keys: vec![vec![9u8; 32]],
+            node_addresses: vec!["127.0.0.1:9000".parse().unwrap()],
+            delays: vec![Duration::from_millis(0)],
+            epoch: 0,
+            destination: Vec::new(),
+        };
+        let (surb, _) = build_surb(&route, "127.0.0.1:7000".parse().unwrap()).unwrap();
+
+        let request = SurbRequest { body: vec![1, 0, 2], surb };
+        let decoded = SurbRequest::from_bytes(&request.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(decoded.body, request.body);
+        assert_eq!(decoded.surb.first_hop, request.surb.first_hop);
+        assert!(SurbRequest::from_bytes(b"garbage").is_err());
+    }
+
     #[tokio::test]
     #[ignore] // Requires mix node running
     async fn test_packet_transmission() {
@@ -611,6 +1399,9 @@ mod tests {
         let keypair = KeyPair::generate().unwrap();
         let route = RouteSpec {
             node_keys: vec![keypair.public_key().to_vec()],
+            node_addresses: vec!["127.0.0.1:8080".parse().unwrap()],
+            delays: vec![Duration::from_millis(0)],
+            epoch: 0,
             destination: vec![0u8; 32],
         };
         let packet = build_packet(&route, b"test message").unwrap();
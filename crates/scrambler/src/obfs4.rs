@@ -0,0 +1,1093 @@
+//! obfs4 Pluggable Transport
+//!
+//! Implements the obfs4 protocol: an authenticated key exchange with a bridge
+//! followed by an encrypted, length-obfuscated frame stream. Nothing an
+//! observer sees, including the handshake, is distinguishable from random bytes.
+//!
+//! ## Architecture
+//!
+//! - **Handshake:** ntor key exchange against the bridge's node ID and
+//!   identity key (`Obfs4Config::cert`). Ephemeral keys travel as Elligator2
+//!   representatives, followed by random padding and an HMAC mark that lets
+//!   the other side find the end of the handshake
+//! - **Framing:** XSalsa20-Poly1305 (secretbox) frames whose 2-byte length
+//!   is masked with a SipHash-2-4 OFB keystream
+//! - **Length Obfuscation:** Each write is padded up to a length drawn from a
+//!   per-connection distribution; the bridge seeds it right after the handshake
+//! - **Inter-Arrival Times:** `IatMode::Enabled` sends bursts as MSS-sized
+//!   segments with random gaps; `IatMode::Paranoid` also randomizes segment sizes
+//!
+//! Node IDs are 32 bytes rather than the 20 of Tor bridges, and the length and
+//! delay distributions are sampled with this crate's generator, so the wire
+//! format is obfs4's but peers must both be this implementation.
+//!
+//! ## Security Properties
+//!
+//! - **Looks Like Noise:** Keys, padding, marks, MACs, and frames are all
+//!   uniformly random to anyone without the bridge's cert
+//! - **Active Probing Resistance:** The bridge never answers a handshake that
+//!   doesn't prove knowledge of its node ID and identity key, and doesn't close
+//!   on it right away either: like obfs4proxy, it keeps reading and discarding
+//!   for a per-bridge random time and amount of data before hanging up
+//! - **Replay Resistance:** Handshake MACs are bound to the hour and remembered
+//!   by the bridge while they are valid
+//! - **Forward Secrecy:** Session keys come from ephemeral X25519 keys
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crypto_secretbox::aead::{Aead, KeyInit};
+use crypto_secretbox::{Nonce, XSalsa20Poly1305};
+use hmac::{Hmac, Mac};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+use siphasher::sip::SipHasher24;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::camouflage::Obfs4Config;
+use crate::elligator::{self, REPRESENTATIVE_LENGTH};
+use crate::error::{Result, ScramblerError};
+
+/// Longest handshake either side sends
+const MAX_HANDSHAKE_LENGTH: usize = 8192;
+const MARK_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 16;
+const AUTH_LENGTH: usize = 32;
+const SEED_LENGTH: usize = 24;
+
+/// Handshakes without padding
+const CLIENT_MIN_HANDSHAKE_LENGTH: usize = REPRESENTATIVE_LENGTH + MARK_LENGTH + MAC_LENGTH;
+const SERVER_MIN_HANDSHAKE_LENGTH: usize =
+    REPRESENTATIVE_LENGTH + AUTH_LENGTH + MARK_LENGTH + MAC_LENGTH;
+
+/// Length prefix plus secretbox tag
+const FRAME_OVERHEAD: usize = 2 + 16;
+/// Packet type plus payload length
+const PACKET_OVERHEAD: usize = 3;
+const INLINE_SEED_FRAME_LENGTH: usize = FRAME_OVERHEAD + PACKET_OVERHEAD + SEED_LENGTH;
+
+/// The client pads to at least the server's minimum response
+const CLIENT_MIN_PAD_LENGTH: usize =
+    SERVER_MIN_HANDSHAKE_LENGTH + INLINE_SEED_FRAME_LENGTH - CLIENT_MIN_HANDSHAKE_LENGTH;
+const CLIENT_MAX_PAD_LENGTH: usize = MAX_HANDSHAKE_LENGTH - CLIENT_MIN_HANDSHAKE_LENGTH;
+const SERVER_MAX_PAD_LENGTH: usize =
+    MAX_HANDSHAKE_LENGTH - (SERVER_MIN_HANDSHAKE_LENGTH + INLINE_SEED_FRAME_LENGTH);
+
+/// Largest TCP segment obfs4 writes at once
+const MAX_SEGMENT_LENGTH: usize = 1448;
+/// Largest sealed frame (excluding the length prefix)
+const MAX_FRAME_LENGTH: usize = MAX_SEGMENT_LENGTH - 2;
+/// Largest packet inside a frame
+const MAX_PACKET_LENGTH: usize = MAX_FRAME_LENGTH - 16;
+/// Largest payload inside a packet
+const MAX_PAYLOAD_LENGTH: usize = MAX_PACKET_LENGTH - PACKET_OVERHEAD;
+
+/// Longest gap between segments, in units of 100µs
+const MAX_IAT_DELAY: usize = 100;
+
+/// Number of values a seeded distribution draws from
+const DIST_BUCKETS: usize = 100;
+
+/// Time a client has to complete its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest extra time a failed handshake is held open, in seconds
+const MAX_CLOSE_DELAY: u64 = 60;
+/// Most data discarded from a failed handshake before closing
+const MAX_CLOSE_DELAY_BYTES: usize = MAX_HANDSHAKE_LENGTH;
+
+/// Validity of a handshake MAC: the hour it was made in plus one either side
+const REPLAY_WINDOW: Duration = Duration::from_secs(3 * 3600);
+
+const PROTO_ID: &[u8] = b"ntor-curve25519-sha256-1";
+const T_MAC: &[u8] = b"ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"ntor-curve25519-sha256-1:key_verify";
+const M_EXPAND: &[u8] = b"ntor-curve25519-sha256-1:key_expand";
+
+/// Secretbox key, nonce prefix, SipHash key, and SipHash IV
+const KEY_MATERIAL_LENGTH: usize = 32 + 16 + 16 + 8;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Inter-arrival time obfuscation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IatMode {
+    /// Writes leave as fast as possible
+    None,
+    /// Bursts are split into full segments with random gaps
+    Enabled,
+    /// Bursts are split into random-size segments with random gaps
+    Paranoid,
+}
+
+impl TryFrom<u8> for IatMode {
+    type Error = ScramblerError;
+
+    fn try_from(mode: u8) -> Result<Self> {
+        match mode {
+            0 => Ok(IatMode::None),
+            1 => Ok(IatMode::Enabled),
+            2 => Ok(IatMode::Paranoid),
+            _ => Err(ScramblerError::ConfigError(format!("Invalid obfs4 iat_mode {}", mode))),
+        }
+    }
+}
+
+impl From<IatMode> for u8 {
+    fn from(mode: IatMode) -> Self {
+        match mode {
+            IatMode::None => 0,
+            IatMode::Enabled => 1,
+            IatMode::Paranoid => 2,
+        }
+    }
+}
+
+/// Bridge side of obfs4
+pub struct Obfs4Bridge {
+    node_id: [u8; 32],
+    identity: StaticSecret,
+    public_key: [u8; 32],
+    iat_mode: IatMode,
+    /// Extra time a failed handshake is held open
+    close_delay: Duration,
+    /// Data discarded from a failed handshake before closing
+    close_delay_bytes: usize,
+    /// Client handshake MACs seen within the replay window
+    seen: Mutex<HashMap<[u8; MAC_LENGTH], SystemTime>>,
+}
+
+impl fmt::Debug for Obfs4Bridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Obfs4Bridge")
+            .field("node_id", &hex::encode(self.node_id))
+            .field("iat_mode", &self.iat_mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Obfs4Bridge {
+    /// Create a bridge from its long-term identity
+    ///
+    /// # Arguments
+    /// * `node_id` - Bridge node ID
+    /// * `identity_key` - X25519 identity secret key
+    /// * `iat_mode` - Inter-arrival time mode for traffic the bridge sends
+    pub fn new(node_id: [u8; 32], identity_key: [u8; 32], iat_mode: IatMode) -> Self {
+        let identity = StaticSecret::from(identity_key);
+        let public_key = PublicKey::from(&identity).to_bytes();
+
+        // Fixed per bridge, so probing it repeatedly doesn't average them out
+        let seed = Zeroizing::new(hmac256(&identity_key, &[b"obfs4 close delay"]));
+        let mut rng = StdRng::from_seed(*seed);
+
+        Self {
+            node_id,
+            identity,
+            public_key,
+            iat_mode,
+            close_delay: Duration::from_secs(rng.gen_range(0..MAX_CLOSE_DELAY)),
+            close_delay_bytes: rng.gen_range(0..MAX_CLOSE_DELAY_BYTES),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create a bridge with a fresh random identity
+    pub fn generate(iat_mode: IatMode) -> Self {
+        let mut rng = rand::thread_rng();
+        Self::new(rng.gen(), rng.gen(), iat_mode)
+    }
+
+    /// Client configuration for reaching this bridge
+    pub fn client_config(&self) -> Obfs4Config {
+        Obfs4Config {
+            node_id: self.node_id,
+            iat_mode: self.iat_mode.into(),
+            cert: self.public_key.to_vec(),
+        }
+    }
+
+    /// Remember a client handshake MAC
+    ///
+    /// # Returns
+    /// * `false` if it was already seen
+    fn check_replay(&self, mac: [u8; MAC_LENGTH], now: SystemTime) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+
+        seen.retain(|_, at| now.duration_since(*at).map_or(true, |age| age < REPLAY_WINDOW));
+        seen.insert(mac, now).is_none()
+    }
+}
+
+/// Connect to an obfs4 bridge over `stream`
+///
+/// # Arguments
+/// * `stream` - Connection to the bridge
+/// * `config` - Bridge node ID, identity key, and IAT mode
+///
+/// # Returns
+/// * Encrypted stream once the bridge has authenticated itself
+pub async fn connect<S>(mut stream: S, config: &Obfs4Config) -> Result<Obfs4Stream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let iat_mode = IatMode::try_from(config.iat_mode)?;
+    let bridge_key: [u8; 32] = config.cert.as_slice().try_into().map_err(|_| {
+        ScramblerError::ConfigError("obfs4 cert must be a 32-byte X25519 key".to_string())
+    })?;
+    let mac_key = [bridge_key.as_slice(), &config.node_id].concat();
+
+    let (secret, public_key, representative) = elligator::generate_keypair();
+    let secret = StaticSecret::from(secret);
+
+    // X' | P_C | M_C | MAC_C
+    let epoch = epoch_hour(SystemTime::now());
+    let mut handshake = representative.to_vec();
+    handshake.extend(random_bytes(
+        rand::thread_rng().gen_range(CLIENT_MIN_PAD_LENGTH..=CLIENT_MAX_PAD_LENGTH),
+    ));
+    handshake.extend_from_slice(&hmac128(&mac_key, &[&representative]));
+    let mac = hmac128(&mac_key, &[&handshake, epoch.as_bytes()]);
+    handshake.extend_from_slice(&mac);
+
+    stream.write_all(&handshake).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    // Y' | AUTH | P_S | M_S | MAC_S
+    let (buffer, end) = read_handshake(
+        &mut stream,
+        &mac_key,
+        SERVER_MIN_HANDSHAKE_LENGTH - MARK_LENGTH - MAC_LENGTH,
+        &[epoch],
+    )
+    .await?;
+
+    let server_representative: [u8; REPRESENTATIVE_LENGTH] =
+        buffer[..REPRESENTATIVE_LENGTH].try_into().expect("length checked");
+    let auth = &buffer[REPRESENTATIVE_LENGTH..REPRESENTATIVE_LENGTH + AUTH_LENGTH];
+    let server_key = elligator::decode(&server_representative);
+
+    let ntor = Ntor {
+        node_id: &config.node_id,
+        bridge_key: &bridge_key,
+        client_key: &public_key,
+        server_key: &server_key,
+    };
+    let (key_seed, expected_auth) = ntor.derive(
+        dh(&secret, &server_key)?.as_slice(),
+        dh(&secret, &bridge_key)?.as_slice(),
+    );
+
+    if !bool::from(expected_auth.ct_eq(auth)) {
+        return Err(ScramblerError::CryptoError(
+            "obfs4 bridge failed to authenticate".to_string(),
+        ));
+    }
+
+    let keys = session_keys(key_seed.as_slice());
+    let (read_half, write_half) = tokio::io::split(stream);
+    let mut reader = Obfs4Reader::new(read_half, &keys[KEY_MATERIAL_LENGTH..], buffer[end..].to_vec());
+
+    // The bridge follows its handshake with the seed for our distributions
+    let seed = match reader.read_packet().await? {
+        Some((PacketType::PrngSeed, seed)) if seed.len() == SEED_LENGTH => seed,
+        _ => {
+            return Err(ScramblerError::CryptoError(
+                "obfs4 bridge did not send a PRNG seed".to_string(),
+            ))
+        }
+    };
+    let writer = Obfs4Writer::new(write_half, &keys[..KEY_MATERIAL_LENGTH], &seed, iat_mode);
+
+    tracing::debug!(iat_mode = ?iat_mode, "obfs4 handshake completed");
+
+    Ok(Obfs4Stream { reader, writer })
+}
+
+/// Accept an obfs4 client on `stream`
+///
+/// Invalid or replayed handshakes get no reply at all. The error is only
+/// returned once the bridge's close delay has passed, so a prober can't tell
+/// a rejected handshake from an incomplete one; the caller should then drop
+/// the connection.
+///
+/// # Arguments
+/// * `stream` - Connection from the client
+/// * `bridge` - Bridge identity
+pub async fn accept<S>(mut stream: S, bridge: &Obfs4Bridge) -> Result<Obfs4Stream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mac_key = [bridge.public_key.as_slice(), &bridge.node_id].concat();
+
+    // Clocks may be off by up to an hour either way
+    let now = SystemTime::now();
+    let epochs = [
+        epoch_hour(now),
+        epoch_hour(now - Duration::from_secs(3600)),
+        epoch_hour(now + Duration::from_secs(3600)),
+    ];
+
+    // Probers that never finish a handshake don't hold the connection forever
+    let started = tokio::time::Instant::now();
+    let (buffer, end) = match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        read_handshake(&mut stream, &mac_key, REPRESENTATIVE_LENGTH + CLIENT_MIN_PAD_LENGTH, &epochs),
+    )
+    .await
+    {
+        Ok(Ok(handshake)) => handshake,
+        Ok(Err(e @ ScramblerError::CryptoError(_))) => {
+            close_after_delay(&mut stream, bridge, started).await;
+            return Err(e);
+        }
+        Ok(Err(e)) => return Err(e),
+        Err(_) => {
+            return Err(ScramblerError::NetworkError("obfs4 handshake timed out".to_string()))
+        }
+    };
+
+    let mac: [u8; MAC_LENGTH] = buffer[end - MAC_LENGTH..end].try_into().expect("length checked");
+    if !bridge.check_replay(mac, now) {
+        close_after_delay(&mut stream, bridge, started).await;
+        return Err(ScramblerError::CryptoError("Replayed obfs4 handshake".to_string()));
+    }
+
+    // The client's MAC told us which epoch it used
+    let epoch = epochs
+        .iter()
+        .find(|epoch| {
+            bool::from(hmac128(&mac_key, &[&buffer[..end - MAC_LENGTH], epoch.as_bytes()]).ct_eq(&mac))
+        })
+        .cloned()
+        .expect("read_handshake verified the MAC");
+
+    let client_representative: [u8; REPRESENTATIVE_LENGTH] =
+        buffer[..REPRESENTATIVE_LENGTH].try_into().expect("length checked");
+    let client_key = elligator::decode(&client_representative);
+
+    let (secret, public_key, representative) = elligator::generate_keypair();
+    let secret = StaticSecret::from(secret);
+
+    let ntor = Ntor {
+        node_id: &bridge.node_id,
+        bridge_key: &bridge.public_key,
+        client_key: &client_key,
+        server_key: &public_key,
+    };
+    let (key_seed, auth) = ntor.derive(
+        dh(&secret, &client_key)?.as_slice(),
+        dh(&bridge.identity, &client_key)?.as_slice(),
+    );
+    let keys = session_keys(key_seed.as_slice());
+
+    // Y' | AUTH | P_S | M_S | MAC_S, then the inline PRNG seed frame
+    let mut response = representative.to_vec();
+    response.extend_from_slice(&auth);
+    response.extend(random_bytes(rand::thread_rng().gen_range(0..=SERVER_MAX_PAD_LENGTH)));
+    response.extend_from_slice(&hmac128(&mac_key, &[&representative]));
+    let server_mac = hmac128(&mac_key, &[&response, epoch.as_bytes()]);
+    response.extend_from_slice(&server_mac);
+
+    let seed = random_bytes(SEED_LENGTH);
+    let (read_half, write_half) = tokio::io::split(stream);
+    let mut writer = Obfs4Writer::new(write_half, &keys[KEY_MATERIAL_LENGTH..], &seed, bridge.iat_mode);
+    writer.encoder.seal(PacketType::PrngSeed, &seed, 0, &mut response)?;
+    writer.inner.write_all(&response).await.map_err(io_error)?;
+    writer.inner.flush().await.map_err(io_error)?;
+
+    let reader = Obfs4Reader::new(read_half, &keys[..KEY_MATERIAL_LENGTH], buffer[end..].to_vec());
+
+    tracing::debug!("obfs4 client accepted");
+
+    Ok(Obfs4Stream { reader, writer })
+}
+
+/// Hold a connection with a failed handshake open before it's closed
+///
+/// Reads and discards until the handshake timeout plus the bridge's close
+/// delay has passed since `started`, the bridge's close delay in bytes has
+/// been discarded, or the peer hangs up.
+async fn close_after_delay<S>(stream: &mut S, bridge: &Obfs4Bridge, started: tokio::time::Instant)
+where
+    S: AsyncRead + Unpin,
+{
+    let deadline = started + HANDSHAKE_TIMEOUT + bridge.close_delay;
+    let mut discarded = 0;
+    let mut chunk = [0u8; 2048];
+
+    while discarded < bridge.close_delay_bytes {
+        match tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await {
+            Ok(Ok(n)) if n > 0 => discarded += n,
+            _ => break,
+        }
+    }
+}
+
+/// Read a handshake up to and including its MAC
+///
+/// # Arguments
+/// * `mac_key` - B | NODEID
+/// * `mark_offset` - Earliest position of the mark
+/// * `epochs` - Accepted epoch hours for the MAC
+///
+/// # Returns
+/// * Bytes read so far and the end of the handshake within them
+async fn read_handshake<S>(
+    stream: &mut S,
+    mac_key: &[u8],
+    mark_offset: usize,
+    epochs: &[String],
+) -> Result<(Vec<u8>, usize)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = Vec::with_capacity(MAX_HANDSHAKE_LENGTH);
+    let mut chunk = [0u8; 2048];
+    let mut mark = None;
+
+    loop {
+        let n = stream.read(&mut chunk).await.map_err(io_error)?;
+        if n == 0 {
+            return Err(ScramblerError::NetworkError(
+                "Connection closed during obfs4 handshake".to_string(),
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+
+        if buffer.len() < REPRESENTATIVE_LENGTH {
+            continue;
+        }
+        let mark = mark.get_or_insert_with(|| hmac128(mac_key, &[&buffer[..REPRESENTATIVE_LENGTH]]));
+
+        let search_end = buffer.len().min(MAX_HANDSHAKE_LENGTH);
+        let found = buffer
+            .get(mark_offset..search_end)
+            .and_then(|window| window.windows(MARK_LENGTH).position(|w| w == mark.as_slice()));
+
+        if let Some(position) = found {
+            let mac_start = mark_offset + position + MARK_LENGTH;
+            let end = mac_start + MAC_LENGTH;
+            if buffer.len() < end {
+                continue;
+            }
+
+            let valid = epochs.iter().any(|epoch| {
+                let expected = hmac128(mac_key, &[&buffer[..mac_start], epoch.as_bytes()]);
+                bool::from(expected.ct_eq(&buffer[mac_start..end]))
+            });
+            if !valid {
+                return Err(ScramblerError::CryptoError("Invalid obfs4 handshake MAC".to_string()));
+            }
+
+            return Ok((buffer, end));
+        }
+
+        if buffer.len() >= MAX_HANDSHAKE_LENGTH {
+            return Err(ScramblerError::CryptoError("obfs4 handshake mark not found".to_string()));
+        }
+    }
+}
+
+/// ntor handshake transcript
+struct Ntor<'a> {
+    node_id: &'a [u8],
+    bridge_key: &'a [u8],
+    client_key: &'a [u8],
+    server_key: &'a [u8],
+}
+
+impl Ntor<'_> {
+    /// Derive the key seed and the bridge's AUTH value
+    ///
+    /// # Arguments
+    /// * `ephemeral` - EXP(Y, x) on the client, EXP(X, y) on the bridge
+    /// * `static_dh` - EXP(B, x) on the client, EXP(X, b) on the bridge
+    fn derive(&self, ephemeral: &[u8], static_dh: &[u8]) -> (Zeroizing<[u8; 32]>, [u8; 32]) {
+        let secret_input = Zeroizing::new(
+            [
+                ephemeral,
+                static_dh,
+                self.node_id,
+                self.bridge_key,
+                self.client_key,
+                self.server_key,
+                PROTO_ID,
+            ]
+            .concat(),
+        );
+
+        let key_seed = Zeroizing::new(hmac256(T_KEY, &[&secret_input]));
+        let verify = hmac256(T_VERIFY, &[&secret_input]);
+        let auth = hmac256(
+            T_MAC,
+            &[
+                &verify,
+                self.node_id,
+                self.bridge_key,
+                self.server_key,
+                self.client_key,
+                PROTO_ID,
+                b"Server",
+            ],
+        );
+
+        (key_seed, auth)
+    }
+}
+
+/// Expand the key seed into client-to-bridge and bridge-to-client key material
+fn session_keys(key_seed: &[u8]) -> Zeroizing<[u8; 2 * KEY_MATERIAL_LENGTH]> {
+    let mut okm = Zeroizing::new([0u8; 2 * KEY_MATERIAL_LENGTH]);
+    hkdf::Hkdf::<Sha256>::new(Some(T_KEY), key_seed)
+        .expand(M_EXPAND, okm.as_mut())
+        .expect("output length is valid for HKDF-SHA256");
+    okm
+}
+
+fn dh(secret: &StaticSecret, public_key: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public_key));
+    if !shared.was_contributory() {
+        return Err(ScramblerError::CryptoError("Low-order obfs4 handshake key".to_string()));
+    }
+    Ok(Zeroizing::new(shared.to_bytes()))
+}
+
+fn hmac256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn hmac128(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut truncated = [0u8; 16];
+    truncated.copy_from_slice(&hmac256(key, parts)[..16]);
+    truncated
+}
+
+/// Hours since the Unix epoch, as decimal text
+fn epoch_hour(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    (secs / 3600).to_string()
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill(&mut bytes[..]);
+    bytes
+}
+
+fn io_error(e: std::io::Error) -> ScramblerError {
+    ScramblerError::NetworkError(format!("obfs4 I/O failed: {}", e))
+}
+
+/// Packet types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Payload,
+    PrngSeed,
+    Unknown(u8),
+}
+
+impl From<u8> for PacketType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => PacketType::Payload,
+            1 => PacketType::PrngSeed,
+            other => PacketType::Unknown(other),
+        }
+    }
+}
+
+/// SipHash-2-4 OFB keystream masking frame lengths
+struct LengthMask {
+    hasher_keys: (u64, u64),
+    block: [u8; 8],
+}
+
+impl LengthMask {
+    fn new(key: &[u8], iv: &[u8]) -> Self {
+        let k0 = u64::from_le_bytes(key[..8].try_into().expect("16-byte key"));
+        let k1 = u64::from_le_bytes(key[8..16].try_into().expect("16-byte key"));
+
+        Self {
+            hasher_keys: (k0, k1),
+            block: iv.try_into().expect("8-byte IV"),
+        }
+    }
+
+    fn next(&mut self) -> u16 {
+        use std::hash::Hasher;
+
+        let mut hasher = SipHasher24::new_with_keys(self.hasher_keys.0, self.hasher_keys.1);
+        hasher.write(&self.block);
+        self.block = hasher.finish().to_le_bytes();
+
+        u16::from_be_bytes([self.block[0], self.block[1]])
+    }
+}
+
+/// Frame keys for one direction
+struct FrameCipher {
+    cipher: XSalsa20Poly1305,
+    nonce_prefix: [u8; 16],
+    counter: u64,
+    mask: LengthMask,
+}
+
+impl FrameCipher {
+    fn new(keys: &[u8]) -> Self {
+        Self {
+            cipher: XSalsa20Poly1305::new_from_slice(&keys[..32]).expect("32-byte key"),
+            nonce_prefix: keys[32..48].try_into().expect("16-byte prefix"),
+            counter: 1,
+            mask: LengthMask::new(&keys[48..64], &keys[64..72]),
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<Nonce> {
+        // Never reuse a nonce
+        if self.counter == u64::MAX {
+            return Err(ScramblerError::CryptoError("obfs4 nonce counter exhausted".to_string()));
+        }
+
+        let mut nonce = Nonce::default();
+        nonce[..16].copy_from_slice(&self.nonce_prefix);
+        nonce[16..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+
+        Ok(nonce)
+    }
+
+    /// Seal a packet and append the frame to `out`
+    fn seal(&mut self, kind: PacketType, payload: &[u8], padding: usize, out: &mut Vec<u8>) -> Result<()> {
+        debug_assert!(payload.len() + padding <= MAX_PAYLOAD_LENGTH);
+
+        let mut packet = Zeroizing::new(Vec::with_capacity(PACKET_OVERHEAD + payload.len() + padding));
+        packet.push(match kind {
+            PacketType::Payload => 0,
+            PacketType::PrngSeed => 1,
+            PacketType::Unknown(value) => value,
+        });
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(payload);
+        let padded_length = packet.len() + padding;
+        packet.resize(padded_length, 0);
+
+        let nonce = self.next_nonce()?;
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, packet.as_slice())
+            .map_err(|_| ScramblerError::CryptoError("obfs4 frame encryption failed".to_string()))?;
+
+        let length = sealed.len() as u16 ^ self.mask.next();
+        out.extend_from_slice(&length.to_be_bytes());
+        out.extend_from_slice(&sealed);
+
+        Ok(())
+    }
+}
+
+/// Weighted distribution over a range, derived from a seed
+struct SeededDist {
+    values: Vec<usize>,
+    weights: WeightedIndex<f64>,
+}
+
+impl SeededDist {
+    fn new(seed: &[u8], domain: &[u8], max: usize) -> Self {
+        let digest: [u8; 32] = Sha256::new().chain_update(domain).chain_update(seed).finalize().into();
+        let mut rng = StdRng::from_seed(digest);
+
+        let values: Vec<usize> = (0..DIST_BUCKETS).map(|_| rng.gen_range(0..=max)).collect();
+        let weights = WeightedIndex::new((0..DIST_BUCKETS).map(|_| rng.gen_range(0.01..1.0)))
+            .expect("weights are positive");
+
+        Self { values, weights }
+    }
+
+    fn sample(&self) -> usize {
+        self.values[self.weights.sample(&mut rand::thread_rng())]
+    }
+}
+
+/// Reading half of an obfs4 stream
+pub struct Obfs4Reader<R> {
+    inner: R,
+    decoder: FrameCipher,
+    /// Bytes read but not yet decoded
+    buffer: Vec<u8>,
+}
+
+impl<R> fmt::Debug for Obfs4Reader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Obfs4Reader").field("buffered", &self.buffer.len()).finish_non_exhaustive()
+    }
+}
+
+impl<R: AsyncRead + Unpin> Obfs4Reader<R> {
+    fn new(inner: R, keys: &[u8], buffer: Vec<u8>) -> Self {
+        Self {
+            inner,
+            decoder: FrameCipher::new(keys),
+            buffer,
+        }
+    }
+
+    /// Receive the payload of the next data frame
+    ///
+    /// # Returns
+    /// * `None` once the peer closes the connection
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_packet().await? {
+                Some((PacketType::Payload, payload)) if !payload.is_empty() => return Ok(Some(payload)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    async fn read_packet(&mut self) -> Result<Option<(PacketType, Vec<u8>)>> {
+        if !self.fill(2).await? {
+            return Ok(None);
+        }
+
+        let masked = u16::from_be_bytes([self.buffer[0], self.buffer[1]]);
+        let length = usize::from(masked ^ self.decoder.mask.next());
+        if !(16 + PACKET_OVERHEAD..=MAX_FRAME_LENGTH).contains(&length) {
+            return Err(ScramblerError::CryptoError(format!("Invalid obfs4 frame length {}", length)));
+        }
+
+        if !self.fill(2 + length).await? {
+            return Err(ScramblerError::NetworkError("obfs4 frame truncated".to_string()));
+        }
+        let frame: Vec<u8> = self.buffer.drain(..2 + length).skip(2).collect();
+
+        let nonce = self.decoder.next_nonce()?;
+        let packet = Zeroizing::new(
+            self.decoder
+                .cipher
+                .decrypt(&nonce, frame.as_slice())
+                .map_err(|_| ScramblerError::CryptoError("obfs4 frame authentication failed".to_string()))?,
+        );
+
+        let payload_length = usize::from(u16::from_be_bytes([packet[1], packet[2]]));
+        if PACKET_OVERHEAD + payload_length > packet.len() {
+            return Err(ScramblerError::CryptoError("Invalid obfs4 packet length".to_string()));
+        }
+
+        Ok(Some((
+            PacketType::from(packet[0]),
+            packet[PACKET_OVERHEAD..PACKET_OVERHEAD + payload_length].to_vec(),
+        )))
+    }
+
+    /// Buffer at least `len` bytes
+    ///
+    /// # Returns
+    /// * `false` if the connection closed cleanly before any of them arrived
+    async fn fill(&mut self, len: usize) -> Result<bool> {
+        let mut chunk = [0u8; MAX_SEGMENT_LENGTH];
+
+        while self.buffer.len() < len {
+            let n = self.inner.read(&mut chunk).await.map_err(io_error)?;
+            if n == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(false);
+                }
+                return Err(ScramblerError::NetworkError("obfs4 frame truncated".to_string()));
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+
+        Ok(true)
+    }
+}
+
+/// Writing half of an obfs4 stream
+pub struct Obfs4Writer<W> {
+    inner: W,
+    encoder: FrameCipher,
+    iat_mode: IatMode,
+    /// Target lengths for the tail of each burst
+    length_dist: SeededDist,
+    /// Gaps between segments, in units of 100µs
+    iat_dist: SeededDist,
+}
+
+impl<W> fmt::Debug for Obfs4Writer<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Obfs4Writer").field("iat_mode", &self.iat_mode).finish_non_exhaustive()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Obfs4Writer<W> {
+    fn new(inner: W, keys: &[u8], seed: &[u8], iat_mode: IatMode) -> Self {
+        Self {
+            inner,
+            encoder: FrameCipher::new(keys),
+            iat_mode,
+            length_dist: SeededDist::new(seed, b"length", MAX_SEGMENT_LENGTH),
+            iat_dist: SeededDist::new(seed, b"iat", MAX_IAT_DELAY),
+        }
+    }
+
+    /// Send `data` as one burst
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let mut burst = Vec::with_capacity(data.len() + MAX_SEGMENT_LENGTH);
+        for chunk in data.chunks(MAX_PAYLOAD_LENGTH) {
+            self.encoder.seal(PacketType::Payload, chunk, 0, &mut burst)?;
+        }
+
+        // In paranoid mode the segment sizes already hide the burst length
+        if self.iat_mode != IatMode::Paranoid {
+            self.pad_burst(&mut burst)?;
+        }
+
+        match self.iat_mode {
+            IatMode::None => {
+                self.inner.write_all(&burst).await.map_err(io_error)?;
+            }
+            IatMode::Enabled | IatMode::Paranoid => {
+                let mut remaining = burst.as_slice();
+                while !remaining.is_empty() {
+                    let segment = match self.iat_mode {
+                        IatMode::Paranoid => self.length_dist.sample().max(1),
+                        _ => MAX_SEGMENT_LENGTH,
+                    };
+                    let (head, tail) = remaining.split_at(segment.min(remaining.len()));
+
+                    self.inner.write_all(head).await.map_err(io_error)?;
+                    self.inner.flush().await.map_err(io_error)?;
+                    remaining = tail;
+
+                    let delay = Duration::from_micros(100 * self.iat_dist.sample() as u64);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+
+        self.inner.flush().await.map_err(io_error)
+    }
+
+    /// Close the write direction
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.inner.shutdown().await.map_err(io_error)
+    }
+
+    /// Pad the final segment of a burst to a length from the distribution
+    fn pad_burst(&mut self, burst: &mut Vec<u8>) -> Result<()> {
+        let header_length = FRAME_OVERHEAD + PACKET_OVERHEAD;
+        let tail = burst.len() % MAX_SEGMENT_LENGTH;
+        let target = self.length_dist.sample();
+
+        let padding = if target >= tail {
+            target - tail
+        } else {
+            MAX_SEGMENT_LENGTH - tail + target
+        };
+
+        if padding > header_length {
+            self.encoder.seal(PacketType::Payload, &[], padding - header_length, burst)?;
+        } else if padding > 0 {
+            // Too short for a frame of its own: spill over into another segment
+            self.encoder.seal(PacketType::Payload, &[], MAX_PAYLOAD_LENGTH, burst)?;
+            self.encoder.seal(PacketType::Payload, &[], padding, burst)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Established obfs4 connection
+#[derive(Debug)]
+pub struct Obfs4Stream<S> {
+    reader: Obfs4Reader<ReadHalf<S>>,
+    writer: Obfs4Writer<WriteHalf<S>>,
+}
+
+impl<S: AsyncRead + AsyncWrite> Obfs4Stream<S> {
+    /// Send `data` as one burst
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.send(data).await
+    }
+
+    /// Receive the payload of the next data frame
+    ///
+    /// # Returns
+    /// * `None` once the peer closes the connection
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        self.reader.recv().await
+    }
+
+    /// Split into halves that can be used concurrently
+    pub fn into_split(self) -> (Obfs4Reader<ReadHalf<S>>, Obfs4Writer<WriteHalf<S>>) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Read until `len` payload bytes arrived
+    async fn recv_exact<S: AsyncRead + AsyncWrite>(stream: &mut Obfs4Stream<S>, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            received.extend(stream.recv().await.unwrap().unwrap());
+        }
+        received
+    }
+
+    async fn round_trip(iat_mode: IatMode) {
+        let bridge = std::sync::Arc::new(Obfs4Bridge::generate(iat_mode));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Echo bridge
+        let server_bridge = bridge.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = accept(stream, &server_bridge).await.unwrap();
+            while let Some(data) = stream.recv().await.unwrap() {
+                stream.send(&data).await.unwrap();
+            }
+        });
+
+        let tcp = TcpStream::connect(address).await.unwrap();
+        let mut stream = connect(tcp, &bridge.client_config()).await.unwrap();
+
+        for size in [1, 100, MAX_PAYLOAD_LENGTH, 5000] {
+            let data = random_bytes(size);
+            stream.send(&data).await.unwrap();
+            assert_eq!(recv_exact(&mut stream, size).await, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loopback_bridge() {
+        round_trip(IatMode::None).await;
+    }
+
+    #[tokio::test]
+    async fn test_iat_modes() {
+        round_trip(IatMode::Enabled).await;
+        round_trip(IatMode::Paranoid).await;
+    }
+
+    #[tokio::test]
+    async fn test_wrong_cert_gets_no_reply() {
+        let bridge = Obfs4Bridge::generate(IatMode::None);
+        let (client, server) = tokio::io::duplex(16 * 1024);
+
+        let mut config = bridge.client_config();
+        config.cert = Obfs4Bridge::generate(IatMode::None).client_config().cert;
+
+        let client = tokio::spawn(async move { connect(client, &config).await });
+
+        // The bridge can't find the mark and keeps waiting without answering
+        let accepting = accept(server, &bridge);
+        tokio::pin!(accepting);
+        assert!(tokio::time::timeout(Duration::from_millis(300), &mut accepting).await.is_err());
+        assert!(!client.is_finished());
+
+        // ...until the prober gives up
+        client.abort();
+        assert!(accepting.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replayed_handshake_rejected() {
+        let bridge = Obfs4Bridge::generate(IatMode::None);
+        let config = bridge.client_config();
+
+        // Capture a client handshake
+        let (client, mut observer) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(async move { connect(client, &config).await });
+
+        let mut handshake = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while let Ok(Ok(n)) =
+            tokio::time::timeout(Duration::from_millis(200), observer.read(&mut chunk)).await
+        {
+            if n == 0 {
+                break;
+            }
+            handshake.extend_from_slice(&chunk[..n]);
+        }
+
+        let bridge = &bridge;
+        let replay = |handshake: Vec<u8>, hang_up: bool| {
+            let (mut attacker, server) = tokio::io::duplex(16 * 1024);
+            async move {
+                attacker.write_all(&handshake).await.unwrap();
+                // A rejected handshake is held open until the peer gives up
+                let attacker = (!hang_up).then_some(attacker);
+                let result = accept(server, bridge).await.map(|_| ());
+                (result, attacker)
+            }
+        };
+
+        let (first, _attacker) = replay(handshake.clone(), false).await;
+        assert!(first.is_ok());
+
+        let (second, _attacker) = replay(handshake, true).await;
+        assert!(second.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_handshake_closed_after_delay() {
+        let bridge = Obfs4Bridge::generate(IatMode::None);
+        let (mut prober, server) = tokio::io::duplex(64 * 1024);
+
+        // Past the longest handshake without a mark, the bridge keeps reading
+        prober.write_all(&random_bytes(MAX_HANDSHAKE_LENGTH)).await.unwrap();
+        let accepting = accept(server, &bridge);
+        tokio::pin!(accepting);
+        assert!(tokio::time::timeout(Duration::from_millis(300), &mut accepting).await.is_err());
+
+        // ...and closes once it discarded its close delay in bytes
+        prober.write_all(&random_bytes(bridge.close_delay_bytes + 1)).await.unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), accepting).await.unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_close_delay_fixed_per_bridge() {
+        let a = Obfs4Bridge::new([1; 32], [2; 32], IatMode::None);
+        let b = Obfs4Bridge::new([1; 32], [2; 32], IatMode::None);
+        assert_eq!(a.close_delay, b.close_delay);
+        assert_eq!(a.close_delay_bytes, b.close_delay_bytes);
+        assert!(a.close_delay < Duration::from_secs(MAX_CLOSE_DELAY));
+        assert!(a.close_delay_bytes < MAX_CLOSE_DELAY_BYTES);
+    }
+
+    #[tokio::test]
+    async fn test_wire_bytes_look_random() {
+        let bridge = Obfs4Bridge::generate(IatMode::None);
+        let config = bridge.client_config();
+
+        let (client, mut observer) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(async move { connect(client, &config).await });
+
+        let mut handshake = vec![0u8; CLIENT_MIN_HANDSHAKE_LENGTH + CLIENT_MIN_PAD_LENGTH];
+        observer.read_exact(&mut handshake).await.unwrap();
+
+        // No plaintext structure: roughly half the bits are set
+        let ones: u32 = handshake.iter().map(|b| b.count_ones()).sum();
+        let bits = (handshake.len() * 8) as f64;
+        assert!((f64::from(ones) / bits - 0.5).abs() < 0.1);
+    }
+}
@@ -2,22 +2,41 @@
 //!
 //! Integrates all 7 layers of the Scrambler network obfuscation system:
 //! - Layer 0: Ghost VPN (mandatory WireGuard tunnel)
+//! - Layer 5: Protocol Camouflage (transport chosen per network by probing)
 //! - Layer 1: Shamir Fragmentation (K-of-N secret sharing)
 //! - Layer 2: 5-Layer Mixnet (Sphinx packets)
-//! - Layer 3: Cover Traffic (constant-rate stream)
+//! - Layer 3: Cover Traffic (Loopix loop, drop, and payload streams)
 //! - Layer 7: Temporal Scrambling (Poisson delays)
 //!
 //! Provides unified API for sending messages through all privacy layers.
 
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 
-use crate::cover_traffic::{CoverTrafficConfig, CoverTrafficGenerator};
+use crate::camouflage::{CamouflageLayer, TransportType};
+use crate::cover_traffic::{CoverStream, CoverTrafficConfig, CoverTrafficGenerator, LoopMonitor};
 use crate::dead_drop::{DeadDropConfig};
+use crate::directory::DirectoryClient;
 use crate::error::{Result, ScramblerError};
-use crate::mixnet::{select_route, Jurisdiction, MixNode};
-use crate::network::{MixNodeAddr, NetworkConfig, PacketTransmitter, ResponseCollector};
+use crate::epoch::EpochSchedule;
+use crate::mixnet::{
+    select_route, Jurisdiction, MixNode, NodeId, ReliabilityTracker, RouteConstraints,
+};
+use crate::network::{
+    read_message, DirectoryProtocol, MixNodeAddr, NetworkConfig, PacketTransmitter,
+    ResponseCollector, SurbRequest, WireMessage,
+};
+use crate::probing::{
+    NetworkId, ProbeConfig, ProbeOutcome, ProbeResult, ProbeTarget, TransportProber,
+    TransportSelector,
+};
 use crate::shamir::{split_secret, reconstruct_secret, ShamirConfig};
-use crate::sphinx::{build_packet, RouteSpec};
+use crate::sphinx::{build_packet, build_surb, RouteSpec, SphinxPacket};
 use crate::temporal::{TemporalConfig, TemporalDelayGenerator};
 use crate::vpn::{VpnConfig, VpnManager};
 
@@ -36,13 +55,38 @@ pub struct ScramblerConfig {
     pub network: NetworkConfig,
     /// Dead drop configuration
     pub dead_drop: DeadDropConfig,
-    /// Avoid specific jurisdictions
-    pub avoid_jurisdiction: Option<Jurisdiction>,
+    /// Route length and excluded jurisdictions/countries
+    pub route: RouteConstraints,
+    /// Mix node key rotation schedule
+    ///
+    /// Replaced by the consensus schedule when mix nodes come from the directory.
+    pub epoch_schedule: EpochSchedule,
+    /// Directory servers to fetch the consensus from (host:port)
+    pub directory_servers: Vec<String>,
+    /// Address to receive SURB replies on
+    ///
+    /// When set, RPC responses come back through single-use reply blocks
+    /// instead of dead-drop polling.
+    pub reply_address: Option<SocketAddr>,
+    /// Address to receive loop cover traffic on
+    ///
+    /// Loop packets are only sent, and loop loss only measured, when set.
+    pub loop_address: Option<SocketAddr>,
+    /// Camouflage transports the mix nodes can be reached through (Layer 5)
+    ///
+    /// One is picked per network, probing them against an entry node when
+    /// the network has no recent profile. Connections are plain when empty.
+    pub transports: Vec<Arc<CamouflageLayer>>,
+    /// File the per-network transport profiles are kept in
+    pub transport_profiles: Option<PathBuf>,
 }
 
+/// Returned loop packets buffered before `generate_cover_traffic` drains them
+const LOOP_QUEUE_SIZE: usize = 1024;
+
 impl Default for ScramblerConfig {
     fn default() -> Self {
-        use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+        use std::net::{IpAddr, Ipv4Addr};
         use crate::vpn::{VpnEndpoint};
 
         Self {
@@ -54,18 +98,31 @@ impl Default for ScramblerConfig {
                         public_key: vec![0u8; 32],
                         address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 51820),
                         location: "US-East".to_string(),
+                        jurisdiction: Some(Jurisdiction::FiveEyes),
                         latency_ms: None,
                     },
                 ],
                 keepalive_interval: 25,
                 max_session_time: 3600, // 1 hour
+                probe_interval: 300,
+                exclude_jurisdictions: Vec::new(),
+                preferred_jurisdictions: Vec::new(),
             },
             shamir: ShamirConfig::default(),
             cover_traffic: CoverTrafficConfig::default(),
             temporal: TemporalConfig::default(),
             network: NetworkConfig::default(),
             dead_drop: DeadDropConfig::default(),
-            avoid_jurisdiction: Some(Jurisdiction::FiveEyes),
+            route: RouteConstraints {
+                exclude_jurisdictions: vec![Jurisdiction::FiveEyes],
+                ..RouteConstraints::default()
+            },
+            epoch_schedule: EpochSchedule::default(),
+            directory_servers: Vec::new(),
+            reply_address: None,
+            loop_address: None,
+            transports: Vec::new(),
+            transport_profiles: None,
         }
     }
 }
@@ -81,14 +138,34 @@ pub struct Scrambler {
     vpn: VpnManager,
     /// Cover traffic generator (Layer 3)
     cover_traffic: CoverTrafficGenerator,
+    /// Outstanding loop cover packets
+    loop_monitor: LoopMonitor,
+    /// Packets received on the loop address
+    loop_receiver: Option<mpsc::Receiver<SphinxPacket>>,
+    /// Real packets waiting for a payload slot, with their first hop's ID
+    outgoing: VecDeque<(SphinxPacket, MixNodeAddr, NodeId)>,
     /// Temporal delay generator (Layer 7)
     temporal: TemporalDelayGenerator,
     /// Available mix nodes (Layer 2)
     mix_nodes: Vec<MixNode>,
+    /// Measured mix node reliability, used to weight route selection
+    reliability: ReliabilityTracker,
+    /// Signed directory the mix nodes come from, if any
+    directory: Option<DirectoryClient>,
+    /// Directory consensus fetcher
+    directory_protocol: DirectoryProtocol,
     /// Network packet transmitter
     packet_transmitter: PacketTransmitter,
     /// RPC response collector
     response_collector: ResponseCollector,
+    /// Probes the configured transports on a new network
+    prober: TransportProber,
+    /// Per-network transport ranking, built by `initialize`
+    selector: Option<TransportSelector>,
+    /// Network the client is on
+    network: NetworkId,
+    /// Transport connections currently go through
+    transport: Option<TransportType>,
 }
 
 impl Scrambler {
@@ -96,8 +173,13 @@ impl Scrambler {
     pub fn new(config: ScramblerConfig, mix_nodes: Vec<MixNode>) -> Self {
         let vpn = VpnManager::new(config.vpn.clone());
         let cover_traffic = CoverTrafficGenerator::new(config.cover_traffic.clone());
+        let loop_monitor = LoopMonitor::new(
+            config.cover_traffic.loss_window,
+            config.cover_traffic.loss_threshold,
+        );
         let temporal = TemporalDelayGenerator::new(config.temporal.clone());
         let packet_transmitter = PacketTransmitter::new(config.network.clone());
+        let directory_protocol = DirectoryProtocol::new(config.network.clone());
         let response_collector = ResponseCollector::new(
             config.network.clone(),
             config.dead_drop.clone(),
@@ -108,20 +190,98 @@ impl Scrambler {
             config,
             vpn,
             cover_traffic,
+            loop_monitor,
+            loop_receiver: None,
+            outgoing: VecDeque::new(),
             temporal,
             mix_nodes,
+            reliability: ReliabilityTracker::new(),
+            directory: None,
+            directory_protocol,
             packet_transmitter,
             response_collector,
+            prober: TransportProber::new(ProbeConfig::default()),
+            selector: None,
+            network: NetworkId::detect(),
+            transport: None,
         }
     }
 
+    /// Create a Scrambler that takes its mix nodes from the signed directory
+    ///
+    /// The consensus is fetched from `config.directory_servers` whenever it
+    /// goes stale, before routes are built.
+    pub fn with_directory(config: ScramblerConfig, directory: DirectoryClient) -> Self {
+        let mut scrambler = Self::new(config, Vec::new());
+        scrambler.directory = Some(directory);
+        scrambler
+    }
+
+    /// Refresh the directory consensus if it's stale
+    ///
+    /// Fetch failures fall back to the cached consensus while it is still
+    /// valid. Does nothing for a Scrambler created with a fixed node list.
+    pub async fn refresh_directory(&mut self) -> Result<()> {
+        let now = SystemTime::now();
+        let directory = match self.directory.as_mut() {
+            Some(directory) => directory,
+            None => return Ok(()),
+        };
+
+        if directory.needs_refresh(now) {
+            for server in &self.config.directory_servers {
+                let addr = MixNodeAddr {
+                    address: server.clone(),
+                    public_key: Vec::new(),
+                };
+
+                let result = match self.directory_protocol.fetch_consensus(&addr).await {
+                    Ok(consensus) => directory.update(consensus, now),
+                    Err(e) => Err(e),
+                };
+
+                match result {
+                    Ok(()) => break,
+                    Err(e) => {
+                        tracing::warn!(server = %server, error = %e, "Consensus refresh failed");
+                    }
+                }
+            }
+        }
+
+        let document = directory.consensus(now).ok_or_else(|| {
+            ScramblerError::MixnetError("No valid directory consensus".to_string())
+        })?;
+
+        self.mix_nodes = document.mix_nodes();
+        self.config.epoch_schedule = document.epoch_schedule.clone();
+
+        Ok(())
+    }
+
     /// Initialize the scrambler
     ///
-    /// Connects to VPN and starts background tasks.
+    /// Connects to VPN, picks the transport for the current network, and
+    /// starts background tasks. From then on every outgoing connection goes
+    /// through the tunnel; the loop and reply listeners still accept direct
+    /// connections.
     pub async fn initialize(&mut self) -> Result<()> {
         // Connect to VPN (Layer 0)
         self.vpn.connect().await?;
 
+        let dialer = self.vpn.dialer();
+        self.packet_transmitter.set_dialer(dialer.clone());
+        self.directory_protocol.set_dialer(dialer.clone());
+        self.response_collector.set_dialer(dialer.clone());
+        self.prober.set_dialer(dialer);
+
+        // Layer 5: camouflage for this network
+        self.network = NetworkId::detect();
+        self.selector = Some(TransportSelector::open(self.config.transport_profiles.clone())?);
+        self.choose_transport().await?;
+
+        self.listen_for_loops().await?;
+
         tracing::info!("VPN connected, scrambler initialized");
 
         // Note: Background tasks like cover traffic and maintenance should be
@@ -142,8 +302,153 @@ impl Scrambler {
         Ok(())
     }
 
+    /// Pick the transport for the current network
+    ///
+    /// Probes the configured transports first if the network has no recent
+    /// profile. Does nothing before `initialize` or without transports.
+    async fn choose_transport(&mut self) -> Result<()> {
+        let now = SystemTime::now();
+        let needs_probe = match &self.selector {
+            Some(selector) => selector.needs_probe(&self.network, now),
+            None => return Ok(()),
+        };
+
+        if needs_probe && !self.config.transports.is_empty() {
+            self.probe_transports().await?;
+        }
+        self.apply_selected_transport();
+
+        Ok(())
+    }
+
+    /// Probe every configured transport against an entry node
+    async fn probe_transports(&mut self) -> Result<()> {
+        let Some(entry) = self.mix_nodes.iter().find(|node| node.layer == 0) else {
+            tracing::warn!("No entry node to probe transports against");
+            return Ok(());
+        };
+
+        let node = MixNodeAddr {
+            address: entry.address.clone(),
+            public_key: Vec::new(),
+        };
+        let targets: Vec<ProbeTarget> = self
+            .config
+            .transports
+            .iter()
+            .map(|layer| ProbeTarget {
+                node: node.clone(),
+                layer: layer.clone(),
+            })
+            .collect();
+
+        let results = self.prober.probe_all(&targets).await?;
+        for result in &results {
+            tracing::debug!(
+                transport = ?result.transport,
+                outcome = ?result.outcome,
+                "Transport probed"
+            );
+        }
+
+        match self.selector.as_mut() {
+            Some(selector) => selector.record(self.network, &results, SystemTime::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Route every connection through the best transport on this network
+    ///
+    /// # Returns
+    /// * `true` if the transport changed
+    fn apply_selected_transport(&mut self) -> bool {
+        let candidates: Vec<TransportType> =
+            self.config.transports.iter().map(|layer| layer.transport_type()).collect();
+        let selected = match &self.selector {
+            Some(selector) => selector.select(&self.network, &candidates),
+            None => None,
+        };
+
+        let layer = match selected {
+            Some(selected) if self.transport != Some(selected) => self
+                .config
+                .transports
+                .iter()
+                .find(|layer| layer.transport_type() == selected)
+                .cloned(),
+            _ => None,
+        };
+        let Some(layer) = layer else {
+            return false;
+        };
+
+        self.packet_transmitter.set_transport(layer.clone());
+        self.directory_protocol.set_transport(layer.clone());
+        self.response_collector.set_transport(layer.clone());
+        self.transport = Some(layer.transport_type());
+
+        tracing::info!(transport = ?self.transport, "Transport selected for network");
+        true
+    }
+
+    /// Send a packet to its first hop, failing over to another transport
+    ///
+    /// A failed send counts against the current transport on this network.
+    /// If that moves another transport to the top of the ranking, the packet
+    /// is sent once more through it.
+    async fn send_to_first_hop(&mut self, packet: &SphinxPacket, node: &MixNodeAddr) -> Result<()> {
+        let error = match self.packet_transmitter.send_packet(packet, node).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let (Some(transport), Some(selector)) = (self.transport, self.selector.as_mut()) else {
+            return Err(error);
+        };
+        let failure = ProbeResult::failed(transport, ProbeOutcome::Reset);
+        selector.record(self.network, &[failure], SystemTime::now())?;
+
+        if !self.apply_selected_transport() {
+            return Err(error);
+        }
+
+        tracing::info!(
+            failed = ?transport,
+            error = %error,
+            "Transport failed, redialing through the next one"
+        );
+        self.packet_transmitter.send_packet(packet, node).await
+    }
+
+    /// Start receiving loop cover traffic on `config.loop_address`
+    ///
+    /// A zero port is replaced by the port actually bound.
+    async fn listen_for_loops(&mut self) -> Result<()> {
+        let loop_address = match self.config.loop_address {
+            Some(loop_address) if self.loop_receiver.is_none() => loop_address,
+            _ => return Ok(()),
+        };
+
+        let listener = TcpListener::bind(loop_address).await.map_err(|e| {
+            ScramblerError::NetworkError(format!("Failed to bind loop listener: {}", e))
+        })?;
+
+        self.config.loop_address = Some(listener.local_addr().map_err(|e| {
+            ScramblerError::NetworkError(format!("Failed to read loop address: {}", e))
+        })?);
+
+        let (sender, receiver) = mpsc::channel(LOOP_QUEUE_SIZE);
+        tokio::spawn(receive_loops(listener, sender));
+        self.loop_receiver = Some(receiver);
+
+        Ok(())
+    }
+
     /// Send a message through all privacy layers
     ///
+    /// Packets are queued and leave in the payload cover stream's slots, so
+    /// they are only sent while `generate_cover_traffic` runs.
+    ///
     /// # Arguments
     /// * `message` - The plaintext message to send
     /// * `destination` - Destination public key
@@ -162,6 +467,8 @@ impl Scrambler {
             ));
         }
 
+        self.refresh_directory().await?;
+
         // Layer 1: Fragment message using Shamir secret sharing
         let shares = split_secret(message, &self.config.shamir)?;
 
@@ -172,61 +479,47 @@ impl Scrambler {
         );
 
         // Layer 2: Create Sphinx packets through mixnet
-        let mut packet_handles = Vec::new();
-
         for share in shares.iter() {
-            // Select route through mixnet
-            let route = select_route(&self.mix_nodes, self.config.avoid_jurisdiction)?;
-
-            // Create route specification
-            let route_spec = RouteSpec {
-                node_keys: route.iter().map(|node| node.public_key.clone()).collect(),
-                destination: destination.to_vec(),
-            };
-
-            // Create Sphinx packet
-            let packet = build_packet(&route_spec, &share.data)?;
-
             // Layer 7: Apply temporal delay
             let delay = self.temporal.generate_delay();
 
-            packet_handles.push(PacketHandle {
-                packet,
-                route: route.iter().map(|n| n.id).collect(),
-                delay,
-            });
+            // Select route through mixnet
+            let (route, route_spec) = self.build_route(destination, delay)?;
+
+            // Create Sphinx packet (share index travels with the data)
+            let packet = build_packet(&route_spec, &share.to_bytes())?;
+
+            // Queue for the next payload slot (Layer 3)
+            self.outgoing
+                .push_back((packet, first_hop(&route, &route_spec)?, route[0].id));
         }
 
         tracing::info!(
-            packets = packet_handles.len(),
+            packets = shares.len(),
             "Message prepared for transmission"
         );
 
         Ok(MessageHandle {
             id: generate_message_id(),
-            packet_handles,
+            packets: shares.len(),
         })
     }
 
     /// Receive and reconstruct a message from shares
     ///
     /// # Arguments
-    /// * `shares` - Received message shares
+    /// * `shares` - Received message shares (encoded with `Share::to_bytes`)
     ///
     /// # Returns
     /// * `Vec<u8>` - Reconstructed plaintext message
     pub fn receive_message(&self, shares: &[Vec<u8>]) -> Result<Vec<u8>> {
         use crate::shamir::Share;
 
-        // Convert to Shamir shares
-        let shamir_shares: Vec<Share> = shares
+        // Decode Shamir shares (each carries its own index)
+        let shamir_shares = shares
             .iter()
-            .enumerate()
-            .map(|(i, data)| Share {
-                index: (i + 1) as u8,
-                data: data.clone(),
-            })
-            .collect();
+            .map(|data| Share::from_bytes(data))
+            .collect::<Result<Vec<Share>>>()?;
 
         // Reconstruct message from shares
         let message = reconstruct_secret(&shamir_shares, &self.config.shamir)?;
@@ -243,7 +536,9 @@ impl Scrambler {
     /// Route RPC call through Scrambler (Privacy Parity for Wallet Operations)
     ///
     /// Routes blockchain RPC requests through the full 8-layer Scrambler stack,
-    /// ensuring wallet queries have the same privacy as messages.
+    /// ensuring wallet queries have the same privacy as messages. Like
+    /// messages, the shares leave in payload cover stream slots; this call
+    /// runs the cover streams until they have all been sent.
     ///
     /// # Arguments
     /// * `rpc_request` - Serialized RPC request (JSON-RPC usually)
@@ -263,6 +558,8 @@ impl Scrambler {
             ));
         }
 
+        self.refresh_directory().await?;
+
         tracing::info!(
             request_size = rpc_request.len(),
             "Routing RPC call through Scrambler"
@@ -272,150 +569,314 @@ impl Scrambler {
         // This prevents any single node from seeing the full request
         let shares = split_secret(rpc_request, &self.config.shamir)?;
 
+        // Listen for SURB replies before any request leaves
+        let reply_listener = match self.config.reply_address {
+            Some(reply_address) => Some(TcpListener::bind(reply_address).await.map_err(|e| {
+                ScramblerError::NetworkError(format!("Failed to bind reply listener: {}", e))
+            })?),
+            None => None,
+        };
+
         // Layer 2: Route each share through different mixnet paths
         // This provides path diversity and timing obfuscation
-        let mut packet_handles = Vec::new();
         let mut access_tokens = Vec::new();
         let mut drop_nodes = Vec::new();
+        let mut surb_secrets = Vec::new();
 
-        for share in shares.iter() {
-            // Select independent route for this share
-            let route = select_route(&self.mix_nodes, self.config.avoid_jurisdiction)?;
-
-            // Create route specification
-            let route_spec = RouteSpec {
-                node_keys: route.iter().map(|node| node.public_key.clone()).collect(),
-                destination: destination.to_vec(),
-            };
-
-            // Wrap in Sphinx packet
-            let packet = build_packet(&route_spec, &share.data)?;
+        // Maximum wait time: 30 seconds for all shares to leave and the
+        // response to arrive
+        let max_wait = Duration::from_secs(30);
+        let deadline = Instant::now() + max_wait;
 
+        for share in shares.iter() {
             // Layer 7: Apply temporal delay
             let delay = self.temporal.generate_delay();
 
-            // Prepare dead drop for response
-            // Derive access token from share data
-            let access_token = self.response_collector.dead_drop.derive_access_token(
-                &share.data
-            );
-
-            access_tokens.push(access_token);
+            // Select independent route for this share
+            let (route, route_spec) = self.build_route(destination, delay)?;
+
+            let payload = match self.config.reply_address {
+                // Attach a SURB over an independent return route, usable until
+                // the response deadline
+                Some(reply_address) => {
+                    let (_, reply_spec) = self.build_route(&[], delay + max_wait)?;
+                    let (surb, secrets) = build_surb(&reply_spec, reply_address)?;
+                    surb_secrets.push(secrets);
+
+                    SurbRequest {
+                        body: share.to_bytes(),
+                        surb,
+                    }
+                    .to_bytes()?
+                }
+                // Prepare dead drop for response
+                None => {
+                    // Derive access token from share data
+                    let access_token = self.response_collector.dead_drop.derive_access_token(
+                        &share.data
+                    );
 
-            // Use last node in route as dead drop node
-            if let Some(last_node) = route.last() {
-                drop_nodes.push(MixNodeAddr {
-                    address: last_node.address.clone(),
-                    public_key: last_node.public_key.clone(),
-                });
-            } else {
-                return Err(ScramblerError::NetworkError(
-                    "Empty route selected".to_string()
-                ));
-            }
+                    access_tokens.push(access_token);
+
+                    // Use last node in route as dead drop node
+                    if let Some(last_node) = route.last() {
+                        drop_nodes.push(MixNodeAddr {
+                            address: last_node.address.clone(),
+                            public_key: route_spec.node_keys.last().cloned().unwrap_or_default(),
+                        });
+                    } else {
+                        return Err(ScramblerError::NetworkError(
+                            "Empty route selected".to_string()
+                        ));
+                    }
+
+                    share.to_bytes()
+                }
+            };
 
-            packet_handles.push((packet, route, delay));
+            // Wrap in Sphinx packet and queue it for a payload slot (Layer 3)
+            let packet = build_packet(&route_spec, &payload)?;
+            self.outgoing
+                .push_back((packet, first_hop(&route, &route_spec)?, route[0].id));
 
             tracing::debug!(
                 share_index = share.index,
                 delay_ms = delay.as_millis(),
-                "RPC share routed"
+                "RPC share queued"
             );
         }
 
-        // Step 1: Send packets with temporal delays
-        for (packet, route, delay) in packet_handles {
-            // Apply temporal delay
-            tokio::time::sleep(delay).await;
-
-            // Send packet through first hop
-            if let Some(first_node) = route.first() {
-                let node_addr = MixNodeAddr {
-                    address: first_node.address.clone(),
-                    public_key: first_node.public_key.clone(),
-                };
-
-                self.packet_transmitter.send_packet(&packet, &node_addr).await?;
-
-                tracing::debug!(
-                    first_hop = %first_node.address,
-                    "RPC share packet transmitted"
-                );
-            } else {
+        // Step 1: Run the cover streams until every queued packet, ours last,
+        // has taken a payload slot; sending directly would add packets on top
+        // of the constant-rate stream
+        while !self.outgoing.is_empty() {
+            if Instant::now() >= deadline {
                 return Err(ScramblerError::NetworkError(
-                    "Empty route - cannot send packet".to_string()
+                    "Timeout waiting for payload slots for RPC shares".to_string(),
                 ));
             }
+            self.generate_cover_traffic().await?;
         }
 
-        // Step 2: Collect responses from dead drops
-        // Maximum wait time: 30 seconds for all shares to arrive
-        let max_wait = Duration::from_secs(30);
-
-        let response = self.response_collector.collect_response(
-            &access_tokens,
-            &drop_nodes,
-            max_wait,
-        ).await?;
+        // Step 2: Collect responses through SURBs or dead drops
+        let max_wait = deadline.saturating_duration_since(Instant::now());
+        let response = match reply_listener {
+            Some(listener) => {
+                self.response_collector.collect_replies(
+                    &listener,
+                    &surb_secrets,
+                    max_wait,
+                ).await?
+            }
+            None => {
+                self.response_collector.collect_response(
+                    &access_tokens,
+                    &drop_nodes,
+                    max_wait,
+                ).await?
+            }
+        };
 
         tracing::info!(
             response_size = response.len(),
-            shares = access_tokens.len(),
+            shares = shares.len(),
             "RPC response collected and reconstructed"
         );
 
         Ok(response)
     }
 
-    /// Generate cover traffic
+    /// Select a route and build its Sphinx route specification
     ///
-    /// Should be called periodically to maintain constant-rate traffic.
-    pub async fn generate_cover_traffic(&self) -> Result<()> {
-        use rand::seq::SliceRandom;
-
-        let packet = self.cover_traffic.generate_cover_packet()?;
-        let delay = self.cover_traffic.next_delay();
-
-        // Select a random mix node to send cover traffic through
-        if let Some(node) = self.mix_nodes.choose(&mut rand::thread_rng()) {
-            let node_addr = MixNodeAddr {
-                address: node.address.clone(),
-                public_key: node.public_key.clone(),
-            };
+    /// Per-hop Poisson delays are drawn first so the route's keys can be chosen
+    /// for an epoch the nodes will still accept when the packet is delivered.
+    ///
+    /// # Arguments
+    /// * `destination` - Final destination address
+    /// * `send_delay` - Delay before the packet leaves this client
+    fn build_route(
+        &self,
+        destination: &[u8],
+        send_delay: Duration,
+    ) -> Result<(Vec<MixNode>, RouteSpec)> {
+        let delays = self.temporal.generate_batch_delays(self.config.route.hops);
+
+        // Pick keys for the current epoch, as long as they are still accepted
+        // at the expected delivery time
+        let schedule = &self.config.epoch_schedule;
+        let now = SystemTime::now();
+        let epoch = schedule.epoch_at(now);
+        let delivery = now + send_delay + delays.iter().sum::<Duration>();
+        if !schedule.accepts(epoch, delivery) {
+            return Err(ScramblerError::MixnetError(format!(
+                "Expected delivery falls outside the grace window of epoch {}",
+                epoch
+            )));
+        }
 
-            // Send cover packet (errors are logged but don't stop cover traffic)
-            match self.packet_transmitter.send_packet(&packet, &node_addr).await {
-                Ok(_) => {
-                    tracing::debug!(
-                        node = %hex::encode(&node.id[0..8]),
-                        delay_ms = delay.as_millis(),
-                        "Cover traffic packet sent"
-                    );
+        let route = select_route(&self.mix_nodes, &self.config.route, epoch, &self.reliability)?;
+
+        let node_keys = route
+            .iter()
+            .map(|node| {
+                node.key_for_epoch(epoch).map(<[u8]>::to_vec).ok_or_else(|| {
+                    ScramblerError::MixnetError(format!("No key published for epoch {}", epoch))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let node_addresses = route
+            .iter()
+            .map(|node| {
+                node.address.parse().map_err(|_| {
+                    ScramblerError::MixnetError(format!("Invalid node address: {}", node.address))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let route_spec = RouteSpec {
+            node_keys,
+            node_addresses,
+            delays,
+            epoch,
+            destination: destination.to_vec(),
+        };
+
+        Ok((route, route_spec))
+    }
+
+    /// Generate cover traffic
+    ///
+    /// Should be called in a loop: collects returned loops, expires lost
+    /// ones, sends the packets that are due on each cover stream, and sleeps
+    /// until the next one is due. Send failures are logged, not returned.
+    pub async fn generate_cover_traffic(&mut self) -> Result<()> {
+        let now = Instant::now();
+
+        // Returned loops vouch for every node on their route
+        if let Some(receiver) = self.loop_receiver.as_mut() {
+            while let Ok(packet) = receiver.try_recv() {
+                if let Some(route) = self.loop_monitor.receive(&packet) {
+                    for id in &route {
+                        self.reliability.record_success(id);
+                    }
                 }
-                Err(e) => {
-                    tracing::warn!(
-                        error = %e,
-                        "Failed to send cover traffic packet (non-critical)"
-                    );
+            }
+        }
+
+        for route in self.loop_monitor.expire(now) {
+            for id in &route {
+                self.reliability.record_failure(id);
+            }
+        }
+
+        if self.loop_monitor.attack_suspected() {
+            tracing::warn!(
+                loss_rate = self.loop_monitor.loss_rate(),
+                "Loop cover traffic is going missing; possible active attack"
+            );
+        }
+
+        for stream in self.cover_traffic.due_streams(now) {
+            if let Err(e) = self.send_cover(stream).await {
+                tracing::warn!(
+                    stream = ?stream,
+                    error = %e,
+                    "Failed to send cover traffic packet (non-critical)"
+                );
+            }
+        }
+
+        // Wake up for the next stream, or re-check the configuration shortly
+        // if every stream is disabled
+        let deadline = self
+            .cover_traffic
+            .next_deadline()
+            .unwrap_or(now + Duration::from_secs(1));
+        tokio::time::sleep_until(deadline.into()).await;
+
+        Ok(())
+    }
+
+    /// Send one packet on a cover stream
+    async fn send_cover(&mut self, stream: CoverStream) -> Result<()> {
+        match stream {
+            CoverStream::Loop => {
+                let loop_address = match self.config.loop_address {
+                    Some(loop_address) => loop_address,
+                    None => return Ok(()),
+                };
+
+                let (route, route_spec) = self.build_route(&[], Duration::ZERO)?;
+                let (_, packet, secrets) =
+                    self.cover_traffic.loop_packet(&route_spec, loop_address)?;
+
+                self.send_to_first_hop(&packet, &first_hop(&route, &route_spec)?).await?;
+
+                // Only loops that left can go missing
+                let deadline = Instant::now()
+                    + route_spec.delays.iter().sum::<Duration>()
+                    + self.config.cover_traffic.loop_grace;
+                self.loop_monitor
+                    .register(secrets, route.iter().map(|node| node.id).collect(), deadline);
+            }
+            CoverStream::Payload if !self.outgoing.is_empty() => {
+                if let Some((packet, node_addr, node_id)) = self.outgoing.pop_front() {
+                    // Feed the outcome back into route selection
+                    if let Err(e) = self.send_to_first_hop(&packet, &node_addr).await {
+                        self.reliability.record_failure(&node_id);
+                        return Err(e);
+                    }
+                    self.reliability.record_success(&node_id);
+                    tracing::debug!(first_hop = %node_addr.address, "Queued packet transmitted");
                 }
             }
+            CoverStream::Payload | CoverStream::Drop => {
+                let (route, route_spec) = self.build_route(&[], Duration::ZERO)?;
+                let packet = self.cover_traffic.drop_packet(&route_spec)?;
+
+                self.send_to_first_hop(&packet, &first_hop(&route, &route_spec)?).await?;
+            }
         }
 
-        tokio::time::sleep(delay).await;
+        tracing::trace!(stream = ?stream, "Cover stream slot used");
 
         Ok(())
     }
 
+    /// Fraction of recent loop cover packets that didn't return
+    pub fn loop_loss_rate(&self) -> f64 {
+        self.loop_monitor.loss_rate()
+    }
+
+    /// Check whether loop loss suggests the client is under active attack
+    pub fn under_active_attack(&self) -> bool {
+        self.loop_monitor.attack_suspected()
+    }
+
     /// Maintain scrambler state
     ///
     /// Should be called periodically to:
     /// - Check VPN connection health
+    /// - Re-pick the transport after moving to another network
+    /// - Refresh the directory consensus
     /// - Generate cover traffic
     /// - Clean up expired state
     pub async fn maintain(&mut self) -> Result<()> {
         // Maintain VPN connection
         self.vpn.maintain().await?;
 
+        // Another network may block other transports
+        self.network = NetworkId::detect();
+        if let Err(e) = self.choose_transport().await {
+            tracing::warn!(error = %e, "Transport selection failed");
+        }
+
+        // A missing consensus only blocks sending, not maintenance
+        if let Err(e) = self.refresh_directory().await {
+            tracing::warn!(error = %e, "Directory unavailable");
+        }
+
         // Generate cover traffic
         self.generate_cover_traffic().await?;
 
@@ -438,19 +899,48 @@ impl Scrambler {
 pub struct MessageHandle {
     /// Unique message ID
     pub id: [u8; 16],
-    /// Packet handles for each share
-    packet_handles: Vec<PacketHandle>,
+    /// Number of packets queued, one per share
+    pub packets: usize,
 }
 
-/// Handle for a single packet
-#[derive(Debug)]
-struct PacketHandle {
-    /// The Sphinx packet
-    packet: crate::sphinx::SphinxPacket,
-    /// Route through mixnet (node IDs)
-    route: Vec<[u8; 32]>,
-    /// Delay before sending
-    delay: Duration,
+/// Address of a route's first hop
+fn first_hop(route: &[MixNode], route_spec: &RouteSpec) -> Result<MixNodeAddr> {
+    match (route.first(), route_spec.node_keys.first()) {
+        (Some(node), Some(key)) => Ok(MixNodeAddr {
+            address: node.address.clone(),
+            public_key: key.clone(),
+        }),
+        _ => Err(ScramblerError::NetworkError("Empty route selected".to_string())),
+    }
+}
+
+/// Forward packets arriving on the loop listener to the orchestrator
+///
+/// Packets beyond the queue capacity are dropped; they count as lost loops.
+async fn receive_loops(listener: TcpListener, sender: mpsc::Sender<SphinxPacket>) {
+    while !sender.is_closed() {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to accept loop connection");
+                continue;
+            }
+        };
+
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = read_message(&mut stream).await {
+                match message {
+                    WireMessage::ForwardPacket { packet } => {
+                        if sender.try_send(packet).is_err() {
+                            tracing::debug!(%peer, "Loop queue full, dropping packet");
+                        }
+                    }
+                    _ => tracing::debug!(%peer, "Ignoring non-packet message on loop listener"),
+                }
+            }
+        });
+    }
 }
 
 /// Generate a unique message ID
@@ -464,7 +954,9 @@ fn generate_message_id() -> [u8; 16] {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mixnet::{GeoLocation, Jurisdiction, MixNode};
+    use crate::epoch::EpochKey;
+    use crate::mixnet::{GeoLocation, Jurisdiction, MixNode, NUM_LAYERS};
+    use std::sync::atomic::AtomicUsize;
 
     fn create_test_nodes() -> Vec<MixNode> {
         let mut nodes = Vec::new();
@@ -472,21 +964,28 @@ mod tests {
             nodes.push(MixNode {
                 id: [layer; 32],
                 layer,
-                public_key: vec![layer; 32],
-                address: format!("127.0.0.{}:8080", layer),
+                epoch_keys: vec![EpochKey {
+                    epoch: EpochSchedule::default().current_epoch(),
+                    public_key: vec![layer; 32],
+                }],
+                address: format!("10.{}.0.1:8080", layer),
                 location: GeoLocation {
                     country: "CH".to_string(),
                     jurisdiction: Jurisdiction::PrivacyFriendly,
                 },
+                bandwidth: 1_000_000,
+                operator: None,
             });
         }
         nodes
     }
 
     #[tokio::test]
-    #[ignore] // Requires WireGuard installed and sudo privileges
     async fn test_scrambler_initialization() {
-        let config = ScramblerConfig::default();
+        let config = ScramblerConfig {
+            vpn: crate::vpn::tests::local_vpn_config().await,
+            ..ScramblerConfig::default()
+        };
         let nodes = create_test_nodes();
         let mut scrambler = Scrambler::new(config, nodes);
 
@@ -496,9 +995,11 @@ mod tests {
     }
 
     #[tokio::test]
-    #[ignore] // Requires WireGuard installed and sudo privileges
     async fn test_message_fragmentation() {
-        let config = ScramblerConfig::default();
+        let config = ScramblerConfig {
+            vpn: crate::vpn::tests::local_vpn_config().await,
+            ..ScramblerConfig::default()
+        };
         let nodes = create_test_nodes();
         let mut scrambler = Scrambler::new(config, nodes);
         scrambler.initialize().await.unwrap();
@@ -513,7 +1014,7 @@ mod tests {
             .unwrap();
 
         // Should create N shares (default 5)
-        assert_eq!(handle.packet_handles.len(), 5);
+        assert_eq!(handle.packets, 5);
     }
 
     #[test]
@@ -527,8 +1028,8 @@ mod tests {
         // Split message
         let shares = split_secret(message, &scrambler.config.shamir).unwrap();
 
-        // Reconstruct from shares
-        let share_data: Vec<Vec<u8>> = shares.iter().map(|s| s.data.clone()).collect();
+        // Reconstruct from any K shares, in any order
+        let share_data: Vec<Vec<u8>> = shares.iter().rev().take(3).map(|s| s.to_bytes()).collect();
         let reconstructed = scrambler.receive_message(&share_data).unwrap();
 
         assert_eq!(reconstructed, message);
@@ -547,4 +1048,288 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_routes_from_fetched_consensus() {
+        use crate::directory::{
+            ConsensusDocument, DirectoryAuthorities, RelayDescriptor, SignedConsensus,
+        };
+        use crate::network::{read_message, write_message, WireMessage};
+        use invisible_crypto::keys::IdentityKey;
+        use std::time::SystemTime;
+
+        let schedule = EpochSchedule::default();
+        let epoch = schedule.current_epoch();
+
+        let relays = (0..NUM_LAYERS)
+            .map(|layer| {
+                let identity = IdentityKey::generate().unwrap();
+                RelayDescriptor {
+                    identity_key: identity.public_key().to_vec(),
+                    layer,
+                    address: format!("10.{}.0.1:9000", layer),
+                    location: GeoLocation {
+                        country: "CH".to_string(),
+                        jurisdiction: Jurisdiction::PrivacyFriendly,
+                    },
+                    epoch_keys: vec![EpochKey { epoch, public_key: vec![layer; 32] }],
+                    bandwidth: 1_000_000,
+                    operator: None,
+                    published: 0,
+                }
+                .sign(&identity)
+                .unwrap()
+            })
+            .collect();
+
+        let authority = IdentityKey::generate().unwrap();
+        let mut consensus = SignedConsensus::new(ConsensusDocument::build(
+            relays,
+            schedule,
+            SystemTime::now(),
+            Duration::from_secs(3600),
+            Duration::from_secs(3 * 3600),
+        ));
+        consensus.sign(&authority).unwrap();
+
+        // Minimal directory server
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while let Some(WireMessage::FetchConsensus) = read_message(&mut stream).await.unwrap() {
+                let response = WireMessage::Consensus { consensus: consensus.clone() };
+                write_message(&mut stream, &response).await.unwrap();
+            }
+        });
+
+        let authorities = DirectoryAuthorities {
+            keys: vec![authority.public_key().to_vec()],
+            threshold: 1,
+        };
+        let config = ScramblerConfig {
+            directory_servers: vec![server.to_string()],
+            ..ScramblerConfig::default()
+        };
+        let mut scrambler = Scrambler::with_directory(
+            config,
+            DirectoryClient::open(authorities, None).unwrap(),
+        );
+
+        scrambler.refresh_directory().await.unwrap();
+        assert_eq!(scrambler.mix_nodes.len(), usize::from(NUM_LAYERS));
+
+        let (route, route_spec) = scrambler.build_route(&[0u8; 32], Duration::ZERO).unwrap();
+        assert_eq!(route.len(), usize::from(NUM_LAYERS));
+        assert_eq!(route_spec.epoch, epoch);
+    }
+
+    /// Single-hop mix node reachable only through uTLS
+    ///
+    /// Echoes probes and counts forwarded packets.
+    async fn spawn_utls_node() -> (MixNode, Arc<CamouflageLayer>, Arc<AtomicUsize>) {
+        use crate::network::write_message;
+        use crate::transport::PluggableTransport;
+        use crate::utls::UTlsServer;
+        use invisible_crypto::keys::KeyPair;
+        use std::sync::atomic::Ordering;
+
+        let server = Arc::new(UTlsServer::generate("cdn.example.com").unwrap());
+        let layer = Arc::new(CamouflageLayer::utls_server(server));
+        let forwarded = Arc::new(AtomicUsize::new(0));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (server_layer, counter) = (layer.clone(), forwarded.clone());
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let (layer, counter) = (server_layer.clone(), counter.clone());
+                tokio::spawn(async move {
+                    let Ok(mut stream) = layer.accept(Box::new(tcp)).await else {
+                        return;
+                    };
+                    while let Ok(Some(message)) = read_message(&mut stream).await {
+                        match message {
+                            WireMessage::Probe { payload } => {
+                                let echo = WireMessage::ProbeEcho { payload };
+                                write_message(&mut stream, &echo).await.unwrap();
+                            }
+                            WireMessage::ForwardPacket { .. } => {
+                                counter.fetch_add(1, Ordering::SeqCst);
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+
+        let keypair = KeyPair::generate().unwrap();
+        let node = MixNode {
+            id: [9u8; 32],
+            layer: 0,
+            epoch_keys: vec![EpochKey {
+                epoch: EpochSchedule::default().current_epoch(),
+                public_key: keypair.public_key().to_vec(),
+            }],
+            address: address.to_string(),
+            location: GeoLocation {
+                country: "CH".to_string(),
+                jurisdiction: Jurisdiction::PrivacyFriendly,
+            },
+            bandwidth: 1_000_000,
+            operator: None,
+        };
+
+        (node, layer, forwarded)
+    }
+
+    /// Scrambler for a single-hop route with `transports` configured
+    fn transport_scrambler(node: MixNode, transports: Vec<Arc<CamouflageLayer>>) -> Scrambler {
+        let config = ScramblerConfig {
+            route: RouteConstraints { hops: 1, ..RouteConstraints::default() },
+            network: NetworkConfig { max_retries: 1, ..NetworkConfig::default() },
+            transports,
+            ..ScramblerConfig::default()
+        };
+        let mut scrambler = Scrambler::new(config, vec![node]);
+        scrambler.selector = Some(TransportSelector::open(None).unwrap());
+        scrambler
+    }
+
+    #[tokio::test]
+    async fn test_probed_transport_selected() {
+        use crate::obfs4::{IatMode, Obfs4Bridge};
+
+        let (node, utls, _) = spawn_utls_node().await;
+        let bridge = Obfs4Bridge::generate(IatMode::None);
+        let obfs4 = Arc::new(CamouflageLayer::obfs4(bridge.client_config()));
+        let mut scrambler = transport_scrambler(node, vec![obfs4, utls]);
+
+        // The node doesn't speak obfs4, so probing settles on uTLS
+        scrambler.choose_transport().await.unwrap();
+        assert_eq!(scrambler.transport, Some(TransportType::UTlsChrome));
+
+        let network = scrambler.network;
+        let profile = scrambler.selector.as_ref().unwrap().profile(&network).unwrap();
+        assert!(!profile.stats(TransportType::Obfs4).unwrap().last_outcome.is_usable());
+    }
+
+    #[tokio::test]
+    async fn test_failed_transport_redialed() {
+        use crate::obfs4::{IatMode, Obfs4Bridge};
+        use std::sync::atomic::Ordering;
+
+        let (node, utls, forwarded) = spawn_utls_node().await;
+        let bridge = Obfs4Bridge::generate(IatMode::None);
+        let obfs4 = Arc::new(CamouflageLayer::obfs4(bridge.client_config()));
+        let mut scrambler = transport_scrambler(node, vec![obfs4, utls]);
+
+        // A fresh profile saying obfs4 works means no probing
+        let network = scrambler.network;
+        let reachable = ProbeResult {
+            transport: TransportType::Obfs4,
+            outcome: ProbeOutcome::Reachable,
+            latency: Some(Duration::from_millis(10)),
+            throughput: None,
+        };
+        let selector = scrambler.selector.as_mut().unwrap();
+        selector.record(network, &[reachable], SystemTime::now()).unwrap();
+        scrambler.choose_transport().await.unwrap();
+        assert_eq!(scrambler.transport, Some(TransportType::Obfs4));
+
+        // The send fails through obfs4 and goes out through uTLS instead
+        scrambler.send_cover(CoverStream::Drop).await.unwrap();
+        assert_eq!(scrambler.transport, Some(TransportType::UTlsChrome));
+
+        for _ in 0..50 {
+            if forwarded.load(Ordering::SeqCst) == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_loop_cover_traffic_returns() {
+        use crate::network::write_message;
+        use crate::sphinx::{process_packet, ProcessedPacket};
+        use invisible_crypto::keys::KeyPair;
+        use tokio::net::TcpStream;
+
+        let keypair = KeyPair::generate().unwrap();
+        let private_key = keypair.private_key().to_vec();
+
+        // Single-hop mixnet that forwards packets without delay
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mix_address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                while let Ok(Some(WireMessage::ForwardPacket { packet })) =
+                    read_message(&mut stream).await
+                {
+                    if let Ok(ProcessedPacket::Forward { next_hop, packet, .. }) =
+                        process_packet(&packet, &private_key)
+                    {
+                        let mut next = TcpStream::connect(next_hop).await.unwrap();
+                        let message = WireMessage::ForwardPacket { packet };
+                        write_message(&mut next, &message).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        let node = MixNode {
+            id: [7u8; 32],
+            layer: 0,
+            epoch_keys: vec![EpochKey {
+                epoch: EpochSchedule::default().current_epoch(),
+                public_key: keypair.public_key().to_vec(),
+            }],
+            address: mix_address.to_string(),
+            location: GeoLocation {
+                country: "CH".to_string(),
+                jurisdiction: Jurisdiction::PrivacyFriendly,
+            },
+            bandwidth: 1_000_000,
+            operator: None,
+        };
+
+        let config = ScramblerConfig {
+            cover_traffic: CoverTrafficConfig {
+                loop_rate: 0.0,
+                drop_rate: 0.0,
+                payload_rate: 0.0,
+                ..CoverTrafficConfig::default()
+            },
+            temporal: TemporalConfig {
+                mean_delay: 0.001,
+                min_delay: Duration::ZERO,
+                max_delay: Duration::from_millis(10),
+            },
+            route: RouteConstraints { hops: 1, ..RouteConstraints::default() },
+            loop_address: Some("127.0.0.1:0".parse().unwrap()),
+            ..ScramblerConfig::default()
+        };
+        let mut scrambler = Scrambler::new(config, vec![node]);
+        scrambler.listen_for_loops().await.unwrap();
+
+        scrambler.send_cover(CoverStream::Loop).await.unwrap();
+        assert_eq!(scrambler.loop_monitor.outstanding(), 1);
+
+        for _ in 0..5 {
+            scrambler.generate_cover_traffic().await.unwrap();
+            if scrambler.loop_monitor.outstanding() == 0 {
+                break;
+            }
+        }
+
+        assert_eq!(scrambler.loop_monitor.outstanding(), 0);
+        assert_eq!(scrambler.loop_loss_rate(), 0.0);
+        assert!(!scrambler.under_active_attack());
+        assert!(scrambler.reliability.score(&[7u8; 32]) > 0.5);
+    }
 }
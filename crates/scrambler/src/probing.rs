@@ -0,0 +1,1038 @@
+//! Censorship Probing
+//!
+//! Measures which camouflage transports get through the local network and
+//! remembers the answer per network, so the client picks a working transport
+//! (and fails over to obfs4 or domain fronting) without user input.
+//!
+//! ## Architecture
+//!
+//! - **TransportProber:** Connects to a relay through each candidate
+//!   transport, completes the handshake and echoes a random payload. The TCP
+//!   socket underneath is observed, so a failure is classified by what the
+//!   network did: reset the connection, swallowed it, slowed it down, or
+//!   delivered data the handshake rejected.
+//! - **NetworkProfile:** Per-transport statistics for one network: last
+//!   outcome, reliability (moving average of outcomes), handshake latency and
+//!   echo throughput. Derives the `dpi_detected` / `tls_blocked` conditions
+//!   for `camouflage::select_transport`.
+//! - **TransportSelector:** Profiles keyed by network identity, persisted on
+//!   disk. Ranks the configured transports for the current network: working
+//!   transports first, then untested ones starting with the one
+//!   `select_transport` recommends, then failed ones.
+//!
+//! ## Security Properties
+//!
+//! - **Hashed Network Identities:** Networks are stored under a hash of their
+//!   descriptor, so the profile file doesn't list SSIDs or gateway addresses
+//! - **Ordinary Probes:** A probe is a regular connection over the transport
+//!   under test; the echo payload travels inside the camouflaged stream
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{timeout, Instant};
+
+use crate::camouflage::{select_transport, CamouflageLayer, TransportType};
+use crate::error::{Result, ScramblerError};
+use crate::network::{read_message, write_message, MixNodeAddr, WireMessage};
+use crate::transport::{BoxedStream, Dialer, PluggableTransport, TcpDialer};
+
+/// Domain separation for network identities
+const NETWORK_ID_CONTEXT: &[u8] = b"invisible-network-id-v1";
+
+/// Weight of the newest probe in the reliability and latency averages
+const SMOOTHING: f64 = 0.5;
+
+/// Age after which a network profile is probed again (24 hours)
+pub const PROFILE_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// What the network did to a probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProbeOutcome {
+    /// Handshake and echo completed at usable speed
+    Reachable,
+    /// Connection refused, reset or closed without an answer
+    Reset,
+    /// Nothing came back before the timeout
+    Blackholed,
+    /// Data got through, but below the minimum throughput
+    Throttled,
+    /// The handshake or echo failed on data the network delivered
+    Tampered,
+}
+
+impl ProbeOutcome {
+    /// Whether the transport can carry traffic on this network
+    pub fn is_usable(&self) -> bool {
+        matches!(self, ProbeOutcome::Reachable | ProbeOutcome::Throttled)
+    }
+
+    /// Contribution to the reliability average
+    fn score(&self) -> f64 {
+        match self {
+            ProbeOutcome::Reachable => 1.0,
+            ProbeOutcome::Throttled => 0.5,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Result of probing one transport
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    /// Transport that was probed
+    pub transport: TransportType,
+    /// Classified outcome
+    pub outcome: ProbeOutcome,
+    /// Time to connect and complete the transport handshake
+    pub latency: Option<Duration>,
+    /// Echo throughput (bytes/s, both directions)
+    pub throughput: Option<f64>,
+}
+
+impl ProbeResult {
+    pub(crate) fn failed(transport: TransportType, outcome: ProbeOutcome) -> Self {
+        Self {
+            transport,
+            outcome,
+            latency: None,
+            throughput: None,
+        }
+    }
+}
+
+/// Probe configuration
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    /// TCP connect timeout (ms)
+    pub connect_timeout_ms: u64,
+    /// Transport handshake timeout (ms)
+    pub handshake_timeout_ms: u64,
+    /// Echo timeout (ms)
+    pub transfer_timeout_ms: u64,
+    /// Size of the echoed payload (bytes)
+    pub probe_size: usize,
+    /// Echo throughput below which a transport counts as throttled (bytes/s)
+    pub min_throughput: u64,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 5000,    // 5 seconds
+            handshake_timeout_ms: 10000, // 10 seconds
+            transfer_timeout_ms: 10000,  // 10 seconds
+            probe_size: 16 * 1024,       // 16 KB
+            min_throughput: 16 * 1024,   // 16 KB/s
+        }
+    }
+}
+
+/// Relay and transport to probe
+#[derive(Debug, Clone)]
+pub struct ProbeTarget {
+    /// Relay (or bridge) serving the transport
+    pub node: MixNodeAddr,
+    /// Client side of the transport
+    pub layer: Arc<CamouflageLayer>,
+}
+
+/// Probes transports against relays and classifies failures
+#[derive(Debug, Clone)]
+pub struct TransportProber {
+    config: ProbeConfig,
+    dialer: Arc<dyn Dialer>,
+}
+
+impl TransportProber {
+    /// Create a new prober
+    pub fn new(config: ProbeConfig) -> Self {
+        Self {
+            config,
+            dialer: Arc::new(TcpDialer),
+        }
+    }
+
+    /// Open probe connections through `dialer` instead of direct TCP
+    pub fn set_dialer(&mut self, dialer: Arc<dyn Dialer>) {
+        self.dialer = dialer;
+    }
+
+    /// Probe one transport
+    ///
+    /// # Arguments
+    /// * `node` - Relay serving the transport
+    /// * `layer` - Client side of the transport
+    ///
+    /// # Returns
+    /// * Classified result; errors only for local problems such as an invalid address
+    pub async fn probe(&self, node: &MixNodeAddr, layer: &CamouflageLayer) -> Result<ProbeResult> {
+        let transport = layer.transport_type();
+        let addr = node.socket_addr()?;
+        let started = Instant::now();
+
+        let connect = timeout(
+            Duration::from_millis(self.config.connect_timeout_ms),
+            self.dialer.dial(addr),
+        );
+        let tcp = match connect.await {
+            Ok(Ok(tcp)) => tcp,
+            Ok(Err(e)) => {
+                tracing::debug!(?transport, error = %e, "Probe connection failed");
+                return Ok(ProbeResult::failed(transport, ProbeOutcome::Reset));
+            }
+            Err(_) => return Ok(ProbeResult::failed(transport, ProbeOutcome::Blackholed)),
+        };
+
+        let observation = Arc::new(SocketObservation::default());
+        let observed = ObservedStream {
+            inner: tcp,
+            observation: observation.clone(),
+        };
+
+        let handshake = timeout(
+            Duration::from_millis(self.config.handshake_timeout_ms),
+            layer.connect(Box::new(observed)),
+        );
+        let mut stream = match handshake.await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                tracing::debug!(?transport, error = %e, "Probe handshake failed");
+                return Ok(ProbeResult::failed(transport, observation.classify(false, 0)));
+            }
+            Err(_) => return Ok(ProbeResult::failed(transport, observation.classify(true, 0))),
+        };
+        let latency = started.elapsed();
+
+        let mut payload = vec![0u8; self.config.probe_size];
+        rand::thread_rng().fill_bytes(&mut payload);
+
+        let received_before = observation.bytes_read();
+        let echo_started = Instant::now();
+        let exchange = async {
+            write_message(&mut stream, &WireMessage::Probe { payload: payload.clone() }).await?;
+            read_message(&mut stream).await
+        };
+
+        let outcome = match timeout(Duration::from_millis(self.config.transfer_timeout_ms), exchange).await {
+            Ok(Ok(Some(WireMessage::ProbeEcho { payload: echoed }))) if echoed == payload => {
+                let elapsed = echo_started.elapsed().as_secs_f64().max(f64::EPSILON);
+                let throughput = (2 * payload.len()) as f64 / elapsed;
+                let outcome = if throughput < self.config.min_throughput as f64 {
+                    ProbeOutcome::Throttled
+                } else {
+                    ProbeOutcome::Reachable
+                };
+
+                return Ok(ProbeResult {
+                    transport,
+                    outcome,
+                    latency: Some(latency),
+                    throughput: Some(throughput),
+                });
+            }
+            Ok(Ok(Some(_))) => ProbeOutcome::Tampered,
+            Ok(Ok(None)) | Ok(Err(_)) => observation.classify(false, received_before),
+            Err(_) => observation.classify(true, received_before),
+        };
+
+        Ok(ProbeResult {
+            transport,
+            outcome,
+            latency: Some(latency),
+            throughput: None,
+        })
+    }
+
+    /// Probe each target in turn
+    ///
+    /// Probes run one after another so they don't compete for bandwidth.
+    pub async fn probe_all(&self, targets: &[ProbeTarget]) -> Result<Vec<ProbeResult>> {
+        let mut results = Vec::with_capacity(targets.len());
+        for target in targets {
+            results.push(self.probe(&target.node, &target.layer).await?);
+        }
+        Ok(results)
+    }
+}
+
+/// What a probe connection's TCP socket went through
+#[derive(Debug, Default)]
+struct SocketObservation {
+    bytes_read: AtomicU64,
+    eof: AtomicBool,
+    reset: AtomicBool,
+}
+
+impl SocketObservation {
+    fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    fn record_error(&self, error: &io::Error) {
+        if matches!(
+            error.kind(),
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+        ) {
+            self.reset.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Classify a failed probe phase
+    ///
+    /// # Arguments
+    /// * `timed_out` - Whether the phase ran into its timeout
+    /// * `received_before` - Bytes read from the socket before the phase started
+    fn classify(&self, timed_out: bool, received_before: u64) -> ProbeOutcome {
+        let received = self.bytes_read() > received_before;
+
+        if self.reset.load(Ordering::Relaxed) {
+            ProbeOutcome::Reset
+        } else if timed_out {
+            // Data trickling in without finishing is throttling; silence is a blackhole
+            if received {
+                ProbeOutcome::Throttled
+            } else {
+                ProbeOutcome::Blackholed
+            }
+        } else if self.eof.load(Ordering::Relaxed) && !received {
+            ProbeOutcome::Reset
+        } else {
+            ProbeOutcome::Tampered
+        }
+    }
+}
+
+/// Connection that reports reads, resets and EOF to a `SocketObservation`
+struct ObservedStream {
+    inner: BoxedStream,
+    observation: Arc<SocketObservation>,
+}
+
+impl AsyncRead for ObservedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        match &result {
+            Poll::Ready(Ok(())) => {
+                let read = buf.filled().len() - before;
+                if read == 0 && buf.remaining() > 0 {
+                    self.observation.eof.store(true, Ordering::Relaxed);
+                }
+                self.observation.bytes_read.fetch_add(read as u64, Ordering::Relaxed);
+            }
+            Poll::Ready(Err(e)) => self.observation.record_error(e),
+            Poll::Pending => {}
+        }
+
+        result
+    }
+}
+
+impl AsyncWrite for ObservedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Err(e)) = &result {
+            self.observation.record_error(e);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_flush(cx);
+        if let Poll::Ready(Err(e)) = &result {
+            self.observation.record_error(e);
+        }
+        result
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_shutdown(cx);
+        if let Poll::Ready(Err(e)) = &result {
+            self.observation.record_error(e);
+        }
+        result
+    }
+}
+
+/// Identity of the network the client is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NetworkId([u8; 32]);
+
+impl NetworkId {
+    /// Derive a network identity
+    ///
+    /// # Arguments
+    /// * `descriptor` - What identifies the network locally, e.g. Wi-Fi SSID
+    ///   and gateway MAC address, or mobile country and network code
+    pub fn new(descriptor: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(NETWORK_ID_CONTEXT);
+        hasher.update(descriptor.as_bytes());
+        Self(hasher.finalize().into())
+    }
+
+    /// Identify the network the client is on now
+    ///
+    /// Uses the default route's interface and gateway, and the gateway's MAC
+    /// address, so two Wi-Fi networks that both hand out 192.168.1.1 still
+    /// differ. Where the routing table can't be read, every network gets the
+    /// same identity.
+    pub fn detect() -> Self {
+        match read_network_descriptor() {
+            Some(descriptor) => Self::new(&descriptor),
+            None => Self::new("unknown"),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_network_descriptor() -> Option<String> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    let neighbours = fs::read_to_string("/proc/net/arp").unwrap_or_default();
+    describe_network(&routes, &neighbours)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_network_descriptor() -> Option<String> {
+    None
+}
+
+/// Describe the network behind the default route
+///
+/// # Arguments
+/// * `routes` - Contents of `/proc/net/route`
+/// * `neighbours` - Contents of `/proc/net/arp`
+///
+/// # Returns
+/// * Interface, gateway address and gateway MAC address, or `None` without
+///   a default route
+fn describe_network(routes: &str, neighbours: &str) -> Option<String> {
+    // Columns: Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+    let (interface, gateway, _) = routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [interface, "00000000", gateway, _, _, _, metric, "00000000", ..] => {
+                    // Network-order address printed as a native integer
+                    let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                    let gateway = std::net::Ipv4Addr::from(gateway.to_ne_bytes());
+                    Some((interface.to_string(), gateway, metric.parse::<u32>().ok()?))
+                }
+                _ => None,
+            }
+        })
+        .min_by_key(|(_, _, metric)| *metric)?;
+
+    // Columns: IP address, HW type, Flags, HW address, Mask, Device
+    let gateway_text = gateway.to_string();
+    let mac = neighbours
+        .lines()
+        .skip(1)
+        .find_map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [ip, _, _, mac, _, device] if *ip == gateway_text && *device == interface => {
+                Some(mac.to_ascii_lowercase())
+            }
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    Some(format!("{} {} {}", interface, gateway, mac))
+}
+
+/// Probe statistics for one transport on one network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransportStats {
+    /// Number of probes recorded
+    pub probes: u32,
+    /// Moving average of outcomes (1.0 reachable, 0.5 throttled, 0.0 failed)
+    pub reliability: f64,
+    /// Moving average of handshake latency (ms)
+    pub latency_ms: Option<f64>,
+    /// Most recent echo throughput (bytes/s)
+    pub throughput: Option<f64>,
+    /// Most recent outcome
+    pub last_outcome: ProbeOutcome,
+    /// Time of the most recent probe (unix seconds)
+    pub last_probed: u64,
+}
+
+impl TransportStats {
+    fn new(outcome: ProbeOutcome) -> Self {
+        Self {
+            probes: 0,
+            reliability: 0.0,
+            latency_ms: None,
+            throughput: None,
+            last_outcome: outcome,
+            last_probed: 0,
+        }
+    }
+
+    fn update(&mut self, result: &ProbeResult, now: u64) {
+        let score = result.outcome.score();
+        self.reliability = if self.probes == 0 {
+            score
+        } else {
+            SMOOTHING * score + (1.0 - SMOOTHING) * self.reliability
+        };
+
+        if let Some(latency) = result.latency {
+            let latency_ms = latency.as_secs_f64() * 1000.0;
+            self.latency_ms = Some(match self.latency_ms {
+                Some(previous) => SMOOTHING * latency_ms + (1.0 - SMOOTHING) * previous,
+                None => latency_ms,
+            });
+        }
+        if result.throughput.is_some() {
+            self.throughput = result.throughput;
+        }
+
+        self.probes += 1;
+        self.last_outcome = result.outcome;
+        self.last_probed = now;
+    }
+}
+
+/// Probe history of one network
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NetworkProfile {
+    transports: HashMap<TransportType, TransportStats>,
+    /// Time of the most recent probe (unix seconds)
+    updated: u64,
+}
+
+impl NetworkProfile {
+    /// Statistics of a transport, if it was probed on this network
+    pub fn stats(&self, transport: TransportType) -> Option<&TransportStats> {
+        self.transports.get(&transport)
+    }
+
+    /// Record a probe result
+    pub fn record(&mut self, result: &ProbeResult, now: SystemTime) {
+        let now = unix_secs(now);
+        self.transports
+            .entry(result.transport)
+            .or_insert_with(|| TransportStats::new(result.outcome))
+            .update(result, now);
+        self.updated = self.updated.max(now);
+    }
+
+    /// Whether every probed uTLS fingerprint failed
+    pub fn tls_blocked(&self) -> bool {
+        let mut utls = self.transports.iter().filter(|(transport, _)| {
+            matches!(
+                transport,
+                TransportType::UTlsChrome | TransportType::UTlsFirefox | TransportType::UTlsSafari
+            )
+        });
+
+        let mut probed = false;
+        let all_failed = utls.all(|(_, stats)| {
+            probed = true;
+            !stats.last_outcome.is_usable()
+        });
+        probed && all_failed
+    }
+
+    /// Whether the network treats transports differently
+    ///
+    /// One transport getting through while another is blocked or throttled
+    /// on the same network means the network looks inside connections.
+    pub fn dpi_detected(&self) -> bool {
+        let outcomes = || self.transports.values().map(|stats| stats.last_outcome);
+        outcomes().any(|outcome| outcome == ProbeOutcome::Reachable)
+            && outcomes().any(|outcome| outcome != ProbeOutcome::Reachable)
+    }
+
+    /// Whether the profile is too old to trust
+    pub fn is_stale(&self, now: SystemTime) -> bool {
+        unix_secs(now).saturating_sub(self.updated) >= PROFILE_TTL.as_secs()
+    }
+
+    /// Order transports by how well they work on this network
+    ///
+    /// Reachable transports come first, then throttled ones, then untested
+    /// ones (the transport `select_transport` recommends first), then failed
+    /// ones. Ties are broken by reliability, then latency.
+    ///
+    /// # Arguments
+    /// * `candidates` - Transports the client has configured
+    pub fn ranking(&self, candidates: &[TransportType]) -> Vec<TransportType> {
+        let preferred = select_transport(self.dpi_detected(), self.tls_blocked());
+        let tier = |transport: &TransportType| match self.transports.get(transport) {
+            Some(stats) if stats.last_outcome == ProbeOutcome::Reachable => 0,
+            Some(stats) if stats.last_outcome == ProbeOutcome::Throttled => 1,
+            None if *transport == preferred => 2,
+            None => 3,
+            Some(_) => 4,
+        };
+
+        let mut ranked = candidates.to_vec();
+        ranked.sort_by(|a, b| {
+            let (stats_a, stats_b) = (self.transports.get(a), self.transports.get(b));
+            let reliability = |stats: Option<&TransportStats>| stats.map_or(0.0, |s| s.reliability);
+            let latency = |stats: Option<&TransportStats>| {
+                stats.and_then(|s| s.latency_ms).unwrap_or(f64::MAX)
+            };
+
+            tier(a)
+                .cmp(&tier(b))
+                .then(reliability(stats_b).total_cmp(&reliability(stats_a)))
+                .then(latency(stats_a).total_cmp(&latency(stats_b)))
+        });
+        ranked
+    }
+}
+
+/// Per-network transport choice backed by probe history
+#[derive(Debug)]
+pub struct TransportSelector {
+    profiles: HashMap<NetworkId, NetworkProfile>,
+    /// Profile file, if persisted
+    path: Option<PathBuf>,
+}
+
+impl TransportSelector {
+    /// Create a selector, loading saved profiles if a path is given
+    ///
+    /// # Arguments
+    /// * `path` - Profile file; a missing or corrupt file starts empty
+    pub fn open(path: Option<PathBuf>) -> Result<Self> {
+        let profiles = match &path {
+            Some(path) => load_profiles(path)?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self { profiles, path })
+    }
+
+    /// Record probe results (or failures seen in use) for a network
+    ///
+    /// # Arguments
+    /// * `network` - Network the results were measured on
+    /// * `results` - Probe results
+    /// * `now` - Current time
+    pub fn record(&mut self, network: NetworkId, results: &[ProbeResult], now: SystemTime) -> Result<()> {
+        let profile = self.profiles.entry(network).or_default();
+        for result in results {
+            profile.record(result, now);
+        }
+
+        match &self.path {
+            Some(path) => store_profiles(path, &self.profiles),
+            None => Ok(()),
+        }
+    }
+
+    /// Probe history of a network
+    pub fn profile(&self, network: &NetworkId) -> Option<&NetworkProfile> {
+        self.profiles.get(network)
+    }
+
+    /// Whether a network has no recent probe results
+    pub fn needs_probe(&self, network: &NetworkId, now: SystemTime) -> bool {
+        self.profiles.get(network).map_or(true, |profile| profile.is_stale(now))
+    }
+
+    /// Order transports by how well they work on a network
+    ///
+    /// See `NetworkProfile::ranking`.
+    pub fn ranking(&self, network: &NetworkId, candidates: &[TransportType]) -> Vec<TransportType> {
+        match self.profiles.get(network) {
+            Some(profile) => profile.ranking(candidates),
+            None => NetworkProfile::default().ranking(candidates),
+        }
+    }
+
+    /// Best transport for a network
+    ///
+    /// # Arguments
+    /// * `network` - Current network
+    /// * `candidates` - Transports the client has configured
+    ///
+    /// # Returns
+    /// * Transport to use, or `None` if no candidates are configured
+    pub fn select(&self, network: &NetworkId, candidates: &[TransportType]) -> Option<TransportType> {
+        self.ranking(network, candidates).first().copied()
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn load_profiles(path: &Path) -> Result<HashMap<NetworkId, NetworkProfile>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(io_error("read", path, e)),
+    };
+
+    match bincode::deserialize(&bytes) {
+        Ok(profiles) => Ok(profiles),
+        Err(e) => {
+            tracing::warn!(path = %path.display(), error = %e, "Ignoring corrupt network profiles");
+            Ok(HashMap::new())
+        }
+    }
+}
+
+/// Atomically replace the profile file
+fn store_profiles(path: &Path, profiles: &HashMap<NetworkId, NetworkProfile>) -> Result<()> {
+    let bytes = bincode::serialize(profiles)
+        .map_err(|e| ScramblerError::ConfigError(format!("Serialization failed: {}", e)))?;
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, &bytes).map_err(|e| io_error("write", &tmp_path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| io_error("rename", path, e))?;
+
+    Ok(())
+}
+
+fn io_error(action: &str, path: &Path, e: io::Error) -> ScramblerError {
+    ScramblerError::ConfigError(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camouflage::{CdnProvider, DomainFrontingConfig};
+    use crate::obfs4::{IatMode, Obfs4Bridge};
+    use crate::utls::UTlsServer;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// What the censoring proxy does to connections
+    #[derive(Debug, Clone, Copy)]
+    enum Censor {
+        /// Forward unchanged
+        Pass,
+        /// Reset after the client's first bytes
+        Reset,
+        /// Reset TLS connections whose ClientHello names this server
+        ResetTls(&'static str),
+        /// Swallow everything
+        Blackhole,
+        /// Forward at this many bytes per second in each direction
+        Throttle(usize),
+    }
+
+    /// Relay stand-in that echoes probes behind `layer`
+    async fn spawn_responder(layer: Arc<CamouflageLayer>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let layer = layer.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = layer.accept(Box::new(tcp)).await else {
+                        return;
+                    };
+                    while let Ok(Some(WireMessage::Probe { payload })) = read_message(&mut stream).await {
+                        if write_message(&mut stream, &WireMessage::ProbeEcho { payload }).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    /// Local proxy in front of `upstream` that simulates a censor
+    async fn spawn_censor(upstream: SocketAddr, censor: Censor) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                tokio::spawn(censor_connection(client, upstream, censor));
+            }
+        });
+
+        address
+    }
+
+    async fn censor_connection(mut client: TcpStream, upstream: SocketAddr, censor: Censor) {
+        let mut first = vec![0u8; 4096];
+        let n = match client.read(&mut first).await {
+            Ok(n) if n > 0 => n,
+            _ => return,
+        };
+        first.truncate(n);
+
+        let reset = match censor {
+            Censor::Reset => true,
+            // Handshake record carrying a ClientHello
+            Censor::ResetTls(name) => {
+                first.len() > 5
+                    && first[0] == 0x16
+                    && first[1] == 0x03
+                    && first[5] == 0x01
+                    && first.windows(name.len()).any(|w| w == name.as_bytes())
+            }
+            _ => false,
+        };
+        if reset {
+            // Zero linger turns the close into a RST
+            #[allow(deprecated)]
+            client.set_linger(Some(Duration::ZERO)).unwrap();
+            return;
+        }
+        if let Censor::Blackhole = censor {
+            while matches!(client.read(&mut first).await, Ok(n) if n > 0) {}
+            return;
+        }
+
+        let mut server = TcpStream::connect(upstream).await.unwrap();
+        server.write_all(&first).await.unwrap();
+
+        match censor {
+            Censor::Throttle(rate) => {
+                let (client_read, client_write) = client.into_split();
+                let (server_read, server_write) = server.into_split();
+                tokio::join!(
+                    throttled_copy(client_read, server_write, rate),
+                    throttled_copy(server_read, client_write, rate),
+                );
+            }
+            _ => {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            }
+        }
+    }
+
+    async fn throttled_copy<R, W>(mut from: R, mut to: W, rate: usize)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buffer = [0u8; 512];
+        while let Ok(n) = from.read(&mut buffer).await {
+            if n == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f64(n as f64 / rate as f64)).await;
+            if to.write_all(&buffer[..n]).await.is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown().await;
+    }
+
+    fn prober() -> TransportProber {
+        TransportProber::new(ProbeConfig {
+            connect_timeout_ms: 1000,
+            handshake_timeout_ms: 1000,
+            transfer_timeout_ms: 3000,
+            probe_size: 8 * 1024,
+            min_throughput: 128 * 1024,
+        })
+    }
+
+    fn node(address: SocketAddr) -> MixNodeAddr {
+        MixNodeAddr {
+            address: address.to_string(),
+            public_key: vec![],
+        }
+    }
+
+    /// Client and server halves of an obfs4 transport
+    fn obfs4_pair() -> (Arc<CamouflageLayer>, Arc<CamouflageLayer>) {
+        let bridge = Arc::new(Obfs4Bridge::generate(IatMode::None));
+        let client = Arc::new(CamouflageLayer::obfs4(bridge.client_config()));
+        (client, Arc::new(CamouflageLayer::obfs4_bridge(bridge)))
+    }
+
+    /// Probe an obfs4 relay behind a censor
+    async fn probe_obfs4(censor: Censor) -> ProbeResult {
+        let (client, server) = obfs4_pair();
+        let relay = spawn_responder(server).await;
+        let proxy = spawn_censor(relay, censor).await;
+        prober().probe(&node(proxy), &client).await.unwrap()
+    }
+
+    fn result(transport: TransportType, outcome: ProbeOutcome, latency_ms: u64) -> ProbeResult {
+        ProbeResult {
+            transport,
+            outcome,
+            latency: outcome.is_usable().then(|| Duration::from_millis(latency_ms)),
+            throughput: None,
+        }
+    }
+
+    #[test]
+    fn test_network_description() {
+        let routes = "Iface\tDestination\tGateway\tFlags\tRefCnt\tUse\tMetric\tMask\tMTU\tWindow\tIRTT\n\
+                      wlan0\t0000A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0\n\
+                      eth0\t00000000\t0100000A\t0003\t0\t0\t700\t00000000\t0\t0\t0\n\
+                      wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n";
+        let neighbours = "IP address  HW type  Flags  HW address         Mask  Device\n\
+                          192.168.1.1 0x1      0x2    AA:BB:CC:DD:EE:FF  *     wlan0\n";
+
+        // Lowest-metric default route, with its gateway's MAC address
+        let description = describe_network(routes, neighbours).unwrap();
+        if cfg!(target_endian = "little") {
+            assert_eq!(description, "wlan0 192.168.1.1 aa:bb:cc:dd:ee:ff");
+        }
+
+        // Same gateway address behind another router
+        let other = neighbours.replace("AA:BB:CC:DD:EE:FF", "11:22:33:44:55:66");
+        let moved = describe_network(routes, &other).unwrap();
+        assert_ne!(NetworkId::new(&description), NetworkId::new(&moved));
+
+        assert!(describe_network("Iface\tDestination\n", neighbours).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reachable() {
+        let result = probe_obfs4(Censor::Pass).await;
+        assert_eq!(result.outcome, ProbeOutcome::Reachable);
+        assert!(result.latency.is_some());
+        assert!(result.throughput.unwrap() >= 128.0 * 1024.0);
+    }
+
+    #[tokio::test]
+    async fn test_censorship_classified() {
+        assert_eq!(probe_obfs4(Censor::Reset).await.outcome, ProbeOutcome::Reset);
+        assert_eq!(probe_obfs4(Censor::Blackhole).await.outcome, ProbeOutcome::Blackholed);
+
+        let throttled = probe_obfs4(Censor::Throttle(32 * 1024)).await;
+        assert_eq!(throttled.outcome, ProbeOutcome::Throttled);
+        assert!(throttled.throughput.unwrap() < 128.0 * 1024.0);
+
+        // Nothing listening
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let (client, _) = obfs4_pair();
+        let result = prober().probe(&node(closed), &client).await.unwrap();
+        assert_eq!(result.outcome, ProbeOutcome::Reset);
+    }
+
+    #[tokio::test]
+    async fn test_tls_blocking_fails_over() {
+        let utls = Arc::new(CamouflageLayer::utls_server(Arc::new(
+            UTlsServer::generate("cdn.example.com").unwrap(),
+        )));
+        let (obfs4_client, obfs4_server) = obfs4_pair();
+        let fronting = Arc::new(CamouflageLayer::domain_fronting_server(
+            DomainFrontingConfig {
+                front_domain: "cdn.cloudflare.com".to_string(),
+                front_key: vec![],
+                actual_destination: "invisible.example.com".to_string(),
+                provider: CdnProvider::Cloudflare,
+            },
+            Arc::new(UTlsServer::generate("cdn.cloudflare.com").unwrap()),
+        ));
+
+        let mut targets = Vec::new();
+        for (client, server) in [
+            (utls.clone(), utls),
+            (obfs4_client, obfs4_server),
+            (fronting.clone(), fronting),
+        ] {
+            let relay = spawn_responder(server).await;
+            let proxy = spawn_censor(relay, Censor::ResetTls("cdn.example.com")).await;
+            targets.push(ProbeTarget { node: node(proxy), layer: client });
+        }
+
+        let results = prober().probe_all(&targets).await.unwrap();
+        let outcomes: Vec<_> = results.iter().map(|r| (r.transport, r.outcome)).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (TransportType::UTlsChrome, ProbeOutcome::Reset),
+                (TransportType::Obfs4, ProbeOutcome::Reachable),
+                (TransportType::DomainFronting, ProbeOutcome::Reachable),
+            ]
+        );
+
+        let network = NetworkId::new("cafe-wifi");
+        let mut selector = TransportSelector::open(None).unwrap();
+        selector.record(network, &results, SystemTime::now()).unwrap();
+
+        let profile = selector.profile(&network).unwrap();
+        assert!(profile.tls_blocked());
+        assert!(profile.dpi_detected());
+
+        let candidates = [
+            TransportType::UTlsChrome,
+            TransportType::UTlsFirefox,
+            TransportType::Obfs4,
+            TransportType::DomainFronting,
+        ];
+        let ranking = selector.ranking(&network, &candidates);
+        assert!(matches!(
+            ranking[0],
+            TransportType::Obfs4 | TransportType::DomainFronting
+        ));
+        assert_eq!(&ranking[2..], &[TransportType::UTlsFirefox, TransportType::UTlsChrome]);
+    }
+
+    #[test]
+    fn test_selector_remembers_networks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("networks.bin");
+        let now = SystemTime::now();
+        let home = NetworkId::new("home");
+        let office = NetworkId::new("office");
+        let candidates = [TransportType::UTlsSafari, TransportType::Obfs4];
+
+        let mut selector = TransportSelector::open(Some(path.clone())).unwrap();
+        assert!(selector.needs_probe(&home, now));
+        selector
+            .record(
+                home,
+                &[
+                    result(TransportType::UTlsSafari, ProbeOutcome::Reachable, 80),
+                    result(TransportType::Obfs4, ProbeOutcome::Reachable, 120),
+                ],
+                now,
+            )
+            .unwrap();
+        selector
+            .record(office, &[result(TransportType::UTlsSafari, ProbeOutcome::Blackholed, 0)], now)
+            .unwrap();
+        assert_eq!(selector.select(&home, &candidates), Some(TransportType::UTlsSafari));
+        assert_eq!(selector.select(&office, &candidates), Some(TransportType::Obfs4));
+
+        // A failure in use moves the home network to the next transport
+        selector
+            .record(home, &[result(TransportType::UTlsSafari, ProbeOutcome::Reset, 0)], now)
+            .unwrap();
+        assert_eq!(selector.select(&home, &candidates), Some(TransportType::Obfs4));
+
+        let reopened = TransportSelector::open(Some(path)).unwrap();
+        assert!(!reopened.needs_probe(&home, now));
+        assert!(reopened.needs_probe(&home, now + PROFILE_TTL));
+        assert_eq!(reopened.select(&home, &candidates), Some(TransportType::Obfs4));
+        assert_eq!(reopened.select(&office, &candidates), Some(TransportType::Obfs4));
+
+        let stats = reopened.profile(&home).unwrap().stats(TransportType::UTlsSafari).unwrap();
+        assert_eq!(stats.probes, 2);
+        assert_eq!(stats.reliability, 0.5);
+        assert_eq!(stats.last_outcome, ProbeOutcome::Reset);
+
+        assert!(reopened.needs_probe(&NetworkId::new("airport"), now));
+        assert_eq!(reopened.select(&NetworkId::new("airport"), &[]), None);
+    }
+}
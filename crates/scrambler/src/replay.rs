@@ -0,0 +1,289 @@
+//! Replay Protection
+//!
+//! Per-node cache of Sphinx replay tags. A mix node that forwards the same
+//! packet twice lets an adversary resend a captured packet and watch where it
+//! comes out, so every processed packet's tag is recorded and duplicates are
+//! rejected.
+//!
+//! ## Architecture
+//!
+//! - **Replay Tags:** Derived from the per-hop shared secret, so a packet always
+//!   maps to the same tag at a node while its tags at different nodes are unlinkable
+//! - **Epochs:** Tags are grouped by key epoch; once an epoch's key is retired its
+//!   packets can no longer be processed, so its tags can be dropped
+//! - **Persistence:** Tags are appended to a log file and synced before the packet
+//!   is accepted, so a crash or restart doesn't reopen the replay window; the log
+//!   is compacted when epochs are dropped
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, ScramblerError};
+
+/// Replay tag derived from a packet's shared secret
+pub type ReplayTag = [u8; 32];
+
+/// Size of one log record: epoch (8 bytes) | tag (32 bytes)
+const RECORD_SIZE: usize = 8 + 32;
+
+/// Replay cache
+///
+/// Records the replay tags of processed packets, grouped by key epoch.
+#[derive(Debug, Default)]
+pub struct ReplayCache {
+    /// Seen tags per key epoch
+    epochs: BTreeMap<u64, HashSet<ReplayTag>>,
+    /// Append-only log backing the cache
+    log: Option<ReplayLog>,
+}
+
+/// On-disk log of replay tags
+#[derive(Debug)]
+struct ReplayLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl ReplayCache {
+    /// Create an in-memory replay cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a replay cache persisted at `path`
+    ///
+    /// Tags recorded by a previous run are loaded; the file is created if it
+    /// doesn't exist. A torn trailing record from a crash is ignored.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut bytes = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut bytes).map_err(|e| io_error("read", &path, e))?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_error("open", &path, e)),
+        }
+
+        let mut epochs: BTreeMap<u64, HashSet<ReplayTag>> = BTreeMap::new();
+        for record in bytes.chunks_exact(RECORD_SIZE) {
+            let (epoch, tag) = decode_record(record);
+            epochs.entry(epoch).or_default().insert(tag);
+        }
+
+        let mut cache = Self { epochs, log: None };
+
+        // Rewrite the log so a torn trailing record doesn't misalign later appends
+        if bytes.len() % RECORD_SIZE != 0 {
+            tracing::warn!(path = %path.display(), "Discarding torn replay cache record");
+            cache.write_snapshot(&path)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| io_error("open", &path, e))?;
+        cache.log = Some(ReplayLog { path, file });
+
+        Ok(cache)
+    }
+
+    /// Record a tag for a key epoch
+    ///
+    /// A new tag is on disk before this returns, so it's safe to forward the
+    /// packet afterwards.
+    ///
+    /// # Returns
+    /// * `true` if the tag is new, `false` if the packet is a replay
+    pub fn check_and_insert(&mut self, epoch: u64, tag: ReplayTag) -> Result<bool> {
+        if !self.epochs.entry(epoch).or_default().insert(tag) {
+            return Ok(false);
+        }
+
+        if let Some(log) = self.log.as_mut() {
+            log.file
+                .write_all(&encode_record(epoch, &tag))
+                .map_err(|e| io_error("write", &log.path, e))?;
+            log.file.sync_data().map_err(|e| io_error("sync", &log.path, e))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Check whether a tag has been seen in a key epoch
+    pub fn contains(&self, epoch: u64, tag: &ReplayTag) -> bool {
+        self.epochs.get(&epoch).is_some_and(|tags| tags.contains(tag))
+    }
+
+    /// Drop the tags of every epoch older than `min_epoch`
+    ///
+    /// Call once the keys of those epochs are retired; packets for them can no
+    /// longer be processed, so their tags are no longer needed.
+    pub fn retain_from(&mut self, min_epoch: u64) -> Result<()> {
+        let before = self.epochs.len();
+        self.epochs = self.epochs.split_off(&min_epoch);
+
+        if self.epochs.len() == before {
+            return Ok(());
+        }
+
+        // Compact the log to the remaining epochs
+        if let Some(path) = self.log.as_ref().map(|log| log.path.clone()) {
+            self.write_snapshot(&path)?;
+
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .map_err(|e| io_error("open", &path, e))?;
+            self.log = Some(ReplayLog { path, file });
+        }
+
+        Ok(())
+    }
+
+    /// Number of recorded tags across all epochs
+    pub fn len(&self) -> usize {
+        self.epochs.values().map(HashSet::len).sum()
+    }
+
+    /// Check whether no tags are recorded
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Atomically replace the log at `path` with the current contents
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let mut bytes = Vec::with_capacity(self.len() * RECORD_SIZE);
+        for (epoch, tags) in &self.epochs {
+            for tag in tags {
+                bytes.extend_from_slice(&encode_record(*epoch, tag));
+            }
+        }
+
+        // Sync the temp file before the rename and the directory after it, so a
+        // crash leaves either the old or the new log
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path).map_err(|e| io_error("create", &tmp_path, e))?;
+        tmp.write_all(&bytes).map_err(|e| io_error("write", &tmp_path, e))?;
+        tmp.sync_all().map_err(|e| io_error("sync", &tmp_path, e))?;
+        fs::rename(&tmp_path, path).map_err(|e| io_error("rename", path, e))?;
+        sync_dir(path)
+    }
+}
+
+fn encode_record(epoch: u64, tag: &ReplayTag) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[..8].copy_from_slice(&epoch.to_be_bytes());
+    record[8..].copy_from_slice(tag);
+    record
+}
+
+fn decode_record(record: &[u8]) -> (u64, ReplayTag) {
+    let mut epoch = [0u8; 8];
+    epoch.copy_from_slice(&record[..8]);
+    let mut tag = [0u8; 32];
+    tag.copy_from_slice(&record[8..RECORD_SIZE]);
+    (u64::from_be_bytes(epoch), tag)
+}
+
+/// Sync the directory holding `path` so a rename within it is durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| io_error("sync", dir, e))
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn io_error(action: &str, path: &Path, e: std::io::Error) -> ScramblerError {
+    ScramblerError::ReplayError(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_rejected() {
+        let mut cache = ReplayCache::new();
+
+        assert!(cache.check_and_insert(0, [1u8; 32]).unwrap());
+        assert!(!cache.check_and_insert(0, [1u8; 32]).unwrap());
+
+        // Same tag in another epoch is a different key's packet
+        assert!(cache.check_and_insert(1, [1u8; 32]).unwrap());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_retain_from_drops_old_epochs() {
+        let mut cache = ReplayCache::new();
+        cache.check_and_insert(0, [1u8; 32]).unwrap();
+        cache.check_and_insert(1, [2u8; 32]).unwrap();
+        cache.check_and_insert(2, [3u8; 32]).unwrap();
+
+        cache.retain_from(1).unwrap();
+
+        assert!(!cache.contains(0, &[1u8; 32]));
+        assert!(cache.contains(1, &[2u8; 32]));
+        assert!(cache.contains(2, &[3u8; 32]));
+    }
+
+    #[test]
+    fn test_persisted_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.log");
+
+        {
+            let mut cache = ReplayCache::open(&path).unwrap();
+            assert!(cache.check_and_insert(4, [7u8; 32]).unwrap());
+            assert!(cache.check_and_insert(5, [8u8; 32]).unwrap());
+        }
+
+        let mut cache = ReplayCache::open(&path).unwrap();
+        assert!(!cache.check_and_insert(4, [7u8; 32]).unwrap());
+        assert!(!cache.check_and_insert(5, [8u8; 32]).unwrap());
+
+        // Compaction survives a restart too
+        cache.retain_from(5).unwrap();
+        cache.check_and_insert(5, [9u8; 32]).unwrap();
+        drop(cache);
+
+        let cache = ReplayCache::open(&path).unwrap();
+        assert!(!cache.contains(4, &[7u8; 32]));
+        assert!(cache.contains(5, &[8u8; 32]));
+        assert!(cache.contains(5, &[9u8; 32]));
+    }
+
+    #[test]
+    fn test_torn_record_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.log");
+
+        let mut bytes = encode_record(0, &[1u8; 32]).to_vec();
+        bytes.extend_from_slice(&[0xff; 5]);
+        fs::write(&path, &bytes).unwrap();
+
+        let mut cache = ReplayCache::open(&path).unwrap();
+        assert!(cache.contains(0, &[1u8; 32]));
+        cache.check_and_insert(0, [2u8; 32]).unwrap();
+        drop(cache);
+
+        let cache = ReplayCache::open(&path).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(0, &[2u8; 32]));
+    }
+}
@@ -1,9 +1,36 @@
 //! Shamir Secret Sharing
 //!
 //! K-of-N secret sharing for fragmenting packets across multiple paths.
+//!
+//! ## Construction
+//!
+//! - **Field:** GF(256) with the AES reduction polynomial (x^8 + x^4 + x^3 + x + 1)
+//! - **Polynomials:** One random polynomial of degree K-1 per secret byte, with the
+//!   byte as the constant term and uniformly random higher coefficients
+//! - **Shares:** Evaluations at distinct non-zero points x = 1..N
+//! - **Integrity:** A SHA-256 tag of the secret is shared alongside it, so a
+//!   corrupted or forged share makes reconstruction fail instead of returning garbage
+//!
+//! ## Security Properties
+//!
+//! - **Information-Theoretic Secrecy:** Any K-1 shares are consistent with every
+//!   possible secret of the same length
+//! - **Constant-Time Arithmetic:** Field operations avoid secret-dependent table lookups
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::digest;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 use crate::error::{Result, ScramblerError};
 
+/// Size of the integrity tag appended to the secret before sharing
+const TAG_SIZE: usize = 32;
+
+/// Maximum number of shares (x = 0 is reserved for the secret itself)
+pub const MAX_SHARES: usize = 255;
+
 /// Shamir secret sharing parameters
 #[derive(Debug, Clone)]
 pub struct ShamirConfig {
@@ -25,12 +52,37 @@ impl Default for ShamirConfig {
 /// A share of a secret
 #[derive(Debug, Clone)]
 pub struct Share {
-    /// Share index (1..N)
+    /// Share index (1..N), the x-coordinate of the evaluation
     pub index: u8,
-    /// Share data
+    /// Share data (one polynomial evaluation per secret byte)
     pub data: Vec<u8>,
 }
 
+impl Share {
+    /// Serialize share for transmission
+    ///
+    /// Format: `[index(1)][data]`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.data.len());
+        bytes.push(self.index);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Parse a share produced by [`Share::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes.split_first() {
+            Some((&index, data)) if index != 0 && !data.is_empty() => Ok(Self {
+                index,
+                data: data.to_vec(),
+            }),
+            _ => Err(ScramblerError::ShamirError(
+                "Malformed share encoding".to_string(),
+            )),
+        }
+    }
+}
+
 /// Split a secret into shares
 ///
 /// # Arguments
@@ -49,20 +101,26 @@ pub fn split_secret(secret: &[u8], config: &ShamirConfig) -> Result<Vec<Share>>
         ));
     }
 
-    // TODO: Implement Shamir secret sharing
-    // - Use finite field arithmetic (GF(256))
-    // - Generate random polynomial of degree k-1
-    // - Evaluate polynomial at N points
-    // - Each point is a share
-
-    let mut shares = Vec::new();
-    for i in 1..=config.total_shares {
-        shares.push(Share {
-            index: i as u8,
-            data: secret.to_vec(), // Placeholder
-        });
+    if config.total_shares > MAX_SHARES {
+        return Err(ScramblerError::ShamirError(format!(
+            "Too many shares: {} (max {})",
+            config.total_shares, MAX_SHARES
+        )));
     }
 
+    // Share the secret together with its integrity tag
+    let mut payload = Vec::with_capacity(secret.len() + TAG_SIZE);
+    payload.extend_from_slice(secret);
+    payload.extend_from_slice(&integrity_tag(secret));
+
+    let shares = split_payload(&payload, config, &mut OsRng);
+
+    tracing::trace!(
+        shares = shares.len(),
+        threshold = config.threshold,
+        "Secret split"
+    );
+
     Ok(shares)
 }
 
@@ -71,6 +129,10 @@ pub fn split_secret(secret: &[u8], config: &ShamirConfig) -> Result<Vec<Share>>
 /// # Arguments
 /// * `shares` - K or more shares
 /// * `config` - Sharing configuration
+///
+/// # Errors
+/// Fails if fewer than K shares are given, if two shares carry the same index,
+/// or if any share was corrupted (integrity tag mismatch).
 pub fn reconstruct_secret(shares: &[Share], config: &ShamirConfig) -> Result<Vec<u8>> {
     if shares.len() < config.threshold {
         return Err(ScramblerError::ShamirError(format!(
@@ -80,17 +142,148 @@ pub fn reconstruct_secret(shares: &[Share], config: &ShamirConfig) -> Result<Vec
         )));
     }
 
-    // TODO: Implement Lagrange interpolation
-    // - Reconstruct polynomial from K points
-    // - Evaluate at x=0 to get secret
-    
-    // Placeholder: return first share's data
-    Ok(shares[0].data.clone())
+    let share_len = shares[0].data.len();
+    if share_len < TAG_SIZE {
+        return Err(ScramblerError::ShamirError("Share too short".to_string()));
+    }
+
+    let mut seen = [false; 256];
+    for share in shares {
+        if share.index == 0 {
+            return Err(ScramblerError::ShamirError(
+                "Invalid share index 0".to_string(),
+            ));
+        }
+        if share.data.len() != share_len {
+            return Err(ScramblerError::ShamirError(
+                "Share lengths do not match".to_string(),
+            ));
+        }
+        if seen[share.index as usize] {
+            return Err(ScramblerError::ShamirError(format!(
+                "Duplicate share index {}",
+                share.index
+            )));
+        }
+        seen[share.index as usize] = true;
+    }
+
+    // Interpolating through every share (not just K) means a single
+    // inconsistent share changes the result and trips the integrity check
+    let mut payload = interpolate_at(shares, 0);
+    let tag = payload.split_off(share_len - TAG_SIZE);
+
+    if !bool::from(tag.ct_eq(&integrity_tag(&payload))) {
+        return Err(ScramblerError::ShamirError(
+            "Integrity check failed: corrupted share".to_string(),
+        ));
+    }
+
+    Ok(payload)
+}
+
+/// Compute the integrity tag for a secret
+fn integrity_tag(secret: &[u8]) -> [u8; TAG_SIZE] {
+    let mut tag = [0u8; TAG_SIZE];
+    tag.copy_from_slice(digest::digest(&digest::SHA256, secret).as_ref());
+    tag
+}
+
+/// Split raw bytes into shares without adding an integrity tag
+fn split_payload<R: RngCore>(payload: &[u8], config: &ShamirConfig, rng: &mut R) -> Vec<Share> {
+    let mut shares: Vec<Share> = (1..=config.total_shares)
+        .map(|i| Share {
+            index: i as u8,
+            data: Vec::with_capacity(payload.len()),
+        })
+        .collect();
+
+    // coefficients[0] is the secret byte, the rest are uniformly random
+    // (zero included, otherwise K-1 shares would leak information)
+    let mut coefficients = vec![0u8; config.threshold];
+
+    for &byte in payload {
+        coefficients[0] = byte;
+        rng.fill_bytes(&mut coefficients[1..]);
+
+        for share in shares.iter_mut() {
+            share.data.push(evaluate(&coefficients, share.index));
+        }
+    }
+
+    coefficients.zeroize();
+
+    shares
+}
+
+/// Evaluate a polynomial (lowest degree first) at `x` using Horner's rule
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0u8, |acc, &c| gf_mul(acc, x) ^ c)
+}
+
+/// Lagrange interpolation of the shared polynomials, evaluated at `x`
+///
+/// Indices must be distinct; callers check this.
+fn interpolate_at(shares: &[Share], x: u8) -> Vec<u8> {
+    // Lagrange basis coefficients l_i(x) = prod_{j != i} (x - x_j) / (x_i - x_j)
+    // (subtraction is XOR in GF(2^8))
+    let basis: Vec<u8> = shares
+        .iter()
+        .map(|s_i| {
+            shares
+                .iter()
+                .filter(|s_j| s_j.index != s_i.index)
+                .fold(1u8, |acc, s_j| {
+                    let num = x ^ s_j.index;
+                    let den = s_i.index ^ s_j.index;
+                    gf_mul(acc, gf_mul(num, gf_inv(den)))
+                })
+        })
+        .collect();
+
+    (0..shares[0].data.len())
+        .map(|pos| {
+            shares
+                .iter()
+                .zip(basis.iter())
+                .fold(0u8, |acc, (share, &l)| acc ^ gf_mul(share.data[pos], l))
+        })
+        .collect()
+}
+
+/// Multiply in GF(256) (constant time, AES polynomial)
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    for _ in 0..8 {
+        // Masks instead of branches keep timing independent of the operands
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse in GF(256) via a^254 (constant time, inv(0) = 0)
+fn gf_inv(a: u8) -> u8 {
+    let a2 = gf_mul(a, a);
+    let a4 = gf_mul(a2, a2);
+    let a8 = gf_mul(a4, a4);
+    let a16 = gf_mul(a8, a8);
+    let a32 = gf_mul(a16, a16);
+    let a64 = gf_mul(a32, a32);
+    let a128 = gf_mul(a64, a64);
+    // 254 = 128 + 64 + 32 + 16 + 8 + 4 + 2
+    [a64, a32, a16, a8, a4, a2].iter().fold(a128, |acc, &p| gf_mul(acc, p))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_split_and_reconstruct() {
@@ -111,9 +304,136 @@ mod tests {
         let config = ShamirConfig::default();
 
         let shares = split_secret(secret, &config).unwrap();
-        
+
         // Should fail with K-1 shares
         let result = reconstruct_secret(&shares[..config.threshold - 1], &config);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_shares_do_not_contain_secret() {
+        let secret = b"a secret that must not appear verbatim";
+        let config = ShamirConfig::default();
+
+        let shares = split_secret(secret, &config).unwrap();
+        for (i, share) in shares.iter().enumerate() {
+            assert_eq!(share.index as usize, i + 1);
+            assert_ne!(&share.data[..secret.len()], secret);
+        }
+    }
+
+    #[test]
+    fn test_corrupted_share_detected() {
+        let config = ShamirConfig::default();
+        let mut shares = split_secret(b"test secret", &config).unwrap();
+
+        shares[1].data[0] ^= 0x01;
+
+        let result = reconstruct_secret(&shares[..config.threshold], &config);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_duplicate_share_detected() {
+        let config = ShamirConfig::default();
+        let shares = split_secret(b"test secret", &config).unwrap();
+
+        let duplicated = vec![shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        assert!(reconstruct_secret(&duplicated, &config).is_err());
+    }
+
+    #[test]
+    fn test_share_encoding_roundtrip() {
+        let config = ShamirConfig::default();
+        let shares = split_secret(b"test secret", &config).unwrap();
+
+        let decoded: Vec<Share> = shares
+            .iter()
+            .map(|s| Share::from_bytes(&s.to_bytes()).unwrap())
+            .collect();
+        assert_eq!(decoded[2].index, 3);
+        assert_eq!(reconstruct_secret(&decoded[2..], &config).unwrap(), b"test secret");
+
+        assert!(Share::from_bytes(&[]).is_err());
+        assert!(Share::from_bytes(&[0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let secret = b"test secret";
+
+        let config = ShamirConfig { total_shares: 3, threshold: 4 };
+        assert!(split_secret(secret, &config).is_err());
+
+        let config = ShamirConfig { total_shares: 3, threshold: 1 };
+        assert!(split_secret(secret, &config).is_err());
+
+        let config = ShamirConfig { total_shares: 256, threshold: 3 };
+        assert!(split_secret(secret, &config).is_err());
+    }
+
+    #[test]
+    fn test_gf_inverse() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1, "inverse of {}", a);
+        }
+    }
+
+    #[test]
+    fn test_single_share_is_uniform() {
+        // With K = 2 a single share byte must take every value, including the
+        // secret byte itself (a non-zero-coefficient dealer never produces it)
+        let config = ShamirConfig { total_shares: 2, threshold: 2 };
+        let mut seen = [false; 256];
+
+        for _ in 0..20_000 {
+            let shares = split_payload(&[0x42], &config, &mut OsRng);
+            seen[shares[0].data[0] as usize] = true;
+        }
+
+        assert!(seen.iter().all(|&s| s));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_any_k_shares_reconstruct(
+            secret in proptest::collection::vec(any::<u8>(), 0..512),
+            n in 2usize..=10,
+            k_offset in 0usize..10,
+            seed in any::<u64>(),
+        ) {
+            use rand::seq::SliceRandom;
+            use rand::SeedableRng;
+
+            let k = 2 + k_offset % (n - 1);
+            let config = ShamirConfig { total_shares: n, threshold: k };
+            let mut shares = split_secret(&secret, &config).unwrap();
+
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            shares.shuffle(&mut rng);
+
+            let reconstructed = reconstruct_secret(&shares[..k], &config).unwrap();
+            prop_assert_eq!(reconstructed, secret);
+        }
+
+        #[test]
+        fn prop_k_minus_one_shares_reveal_nothing(
+            secret in any::<u8>(),
+            k in 2usize..=6,
+        ) {
+            // For K-1 real shares, each candidate secret corresponds to exactly one
+            // value of the missing K-th share, so every secret is equally likely
+            let config = ShamirConfig { total_shares: k, threshold: k };
+            let shares = split_payload(&[secret], &config, &mut OsRng);
+
+            let mut candidates = [false; 256];
+            for forged in 0..=255u8 {
+                let mut guess = shares[..k - 1].to_vec();
+                guess.push(Share { index: k as u8, data: vec![forged] });
+                candidates[interpolate_at(&guess, 0)[0] as usize] = true;
+            }
+
+            prop_assert!(candidates.iter().all(|&c| c));
+        }
+    }
 }
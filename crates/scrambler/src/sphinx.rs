@@ -10,14 +10,33 @@
 //! - **Payload:** End-to-end encrypted message
 //! - **MAC:** Message authentication code
 //!
+//! Each hop's routing command is followed by the MAC of the routing info the
+//! next hop receives. A hop strips its command, shifts in bytes of its own
+//! keystream at the end so the routing info keeps its size, and passes the
+//! uncovered MAC on. The sender precomputes those shifted-in bytes (the filler)
+//! so every hop's MAC covers the routing info exactly as that hop will see it.
+//!
+//! Single-use reply blocks (SURBs) let a destination answer through the mixnet:
+//! the sender builds the reply header in advance and keeps the keys needed to
+//! open the reply. The reply is recognized by a random identifier the last
+//! reply hop uncovers in place of a MAC, never by the header's group element.
+//!
 //! ## Security Properties
 //!
-//! - **Unlinkability:** Cannot correlate input/output packets at mix nodes
+//! - **Unlinkability:** Cannot correlate input/output packets at mix nodes; each
+//!   hop blinds the ephemeral key, so no header field survives from hop to hop
 //! - **Forward Secrecy:** Compromise of long-term keys doesn't reveal past routes
-//! - **Replay Protection:** Each packet can only be processed once
-//! - **Tagging Prevention:** Modifications are cryptographically detected
+//! - **Replay Protection:** Each packet yields a per-node replay tag for the node's `ReplayCache`
+//! - **Tagging Prevention:** Every hop verifies the header MAC before acting on
+//!   the routing info, so a modified header is dropped at the next hop instead
+//!   of being redirected or marked; payloads are not authenticated by the mixes,
+//!   so end-to-end integrity is left to the message layer (replies are AEAD-sealed)
+//! - **Position Hiding:** Routing info is the same size at every hop, and the
+//!   filler a hop shifts in is indistinguishable from the rest of the ciphertext
 
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
@@ -25,18 +44,76 @@ use ring::hmac;
 use rand::rngs::OsRng;
 use rand::RngCore;
 
+use crate::epoch::Epoch;
 use crate::error::{Result, ScramblerError};
+use crate::replay::ReplayTag;
 use invisible_crypto::kdf::hkdf_sha256;
 
 /// Maximum number of hops in a Sphinx route
 pub const MAX_HOPS: usize = 5;
 
-/// Size of Sphinx header in bytes
-pub const HEADER_SIZE: usize = 256;
+/// Size of the routing info in a Sphinx header in bytes
+pub const HEADER_SIZE: usize = 288;
 
 /// Size of Sphinx payload in bytes
 pub const PAYLOAD_SIZE: usize = 2048;
 
+/// Size of the length prefix in front of padded payloads
+const LENGTH_PREFIX_SIZE: usize = 2;
+
+/// Size of the Poly1305 tag on reply payloads
+const AEAD_TAG_SIZE: usize = 16;
+
+/// Associated data of reply payloads (the payload key is single-use)
+const REPLY_AD: &[u8] = b"SphinxReply";
+
+/// Largest message that fits in a forward packet
+pub const MAX_MESSAGE_SIZE: usize = PAYLOAD_SIZE - LENGTH_PREFIX_SIZE;
+
+/// Largest message that fits in a reply sent through a SURB
+pub const MAX_REPLY_SIZE: usize = PAYLOAD_SIZE - AEAD_TAG_SIZE - LENGTH_PREFIX_SIZE;
+
+/// Size of an encoded next-hop address (IPv6 or IPv4-mapped address, port)
+const ADDRESS_SIZE: usize = 18;
+
+/// Size of a per-hop routing command: flag | delay (ms) | next-hop address
+pub const ROUTING_COMMAND_SIZE: usize = 1 + 4 + ADDRESS_SIZE;
+
+/// Size of a header MAC (HMAC-SHA256)
+const MAC_SIZE: usize = 32;
+
+/// Routing info consumed by each forwarding hop: its command and the next hop's MAC
+const HOP_SIZE: usize = ROUTING_COMMAND_SIZE + MAC_SIZE;
+
+/// HKDF info of the routing info keystream
+const ROUTING_STREAM_INFO: &[u8] = b"SphinxRouting";
+
+/// HKDF info of the payload keystream
+const PAYLOAD_STREAM_INFO: &[u8] = b"SphinxPayload";
+
+/// Routing command flag: deliver the payload at this hop
+const FLAG_DELIVER: u8 = 0x00;
+
+/// Routing command flag: forward the packet to the encoded next hop
+const FLAG_FORWARD: u8 = 0x01;
+
+/// Size of the destination carried in the final routing command
+const DESTINATION_SIZE: usize = 32;
+
+/// Size of a SURB identifier
+pub const SURB_ID_SIZE: usize = 16;
+
+/// Random identifier of a SURB, uncovered by the last reply hop
+pub type SurbId = [u8; SURB_ID_SIZE];
+
+// Every forwarding hop's command and MAC plus the final delivery command must fit
+const _: () = assert!(
+    (MAX_HOPS - 1) * HOP_SIZE + 1 + 4 + DESTINATION_SIZE <= HEADER_SIZE
+);
+
+// A SURB's last hop forwards too, uncovering the identifier in place of a MAC
+const _: () = assert!(MAX_HOPS * HOP_SIZE <= HEADER_SIZE && SURB_ID_SIZE <= MAC_SIZE);
+
 /// A Sphinx packet
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SphinxPacket {
@@ -49,12 +126,14 @@ pub struct SphinxPacket {
 /// Sphinx packet header
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SphinxHeader {
-    /// Ephemeral public key (in the clear)
+    /// Key epoch the packet was built for (in the clear)
+    pub epoch: Epoch,
+    /// Ephemeral public key (in the clear, blinded at every hop)
     pub ephemeral_key: [u8; 32],
     /// Routing information (encrypted for each hop)
     pub routing_info: Vec<u8>,
-    /// Message authentication code
-    pub mac: [u8; 32],
+    /// MAC of the routing info under this hop's key
+    pub mac: [u8; MAC_SIZE],
 }
 
 /// Route information for building Sphinx packets
@@ -63,11 +142,59 @@ pub struct RouteSpec {
     /// Public keys of mix nodes in the route
     #[zeroize(skip)]
     pub node_keys: Vec<Vec<u8>>,
+    /// Network addresses of mix nodes in the route (same order as `node_keys`)
+    #[zeroize(skip)]
+    pub node_addresses: Vec<SocketAddr>,
+    /// Delay each hop holds the packet before passing it on (same order as `node_keys`)
+    #[zeroize(skip)]
+    pub delays: Vec<Duration>,
+    /// Key epoch the node keys belong to
+    pub epoch: Epoch,
     /// Destination address
     #[zeroize(skip)]
     pub destination: Vec<u8>,
 }
 
+/// Encode a socket address as a 16-byte IPv6 address followed by a big-endian port
+fn encode_address(addr: &SocketAddr) -> [u8; ADDRESS_SIZE] {
+    let ip = match addr.ip() {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+
+    let mut encoded = [0u8; ADDRESS_SIZE];
+    encoded[..16].copy_from_slice(&ip.octets());
+    encoded[16..].copy_from_slice(&addr.port().to_be_bytes());
+    encoded
+}
+
+/// Decode a socket address produced by `encode_address`
+fn decode_address(encoded: &[u8]) -> SocketAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&encoded[..16]);
+    let ip = Ipv6Addr::from(octets);
+    let port = u16::from_be_bytes([encoded[16], encoded[17]]);
+
+    match ip.to_ipv4_mapped() {
+        Some(v4) => SocketAddr::new(IpAddr::V4(v4), port),
+        None => SocketAddr::new(IpAddr::V6(ip), port),
+    }
+}
+
+/// Encode a per-hop delay as big-endian milliseconds
+fn encode_delay(delay: Duration) -> Result<[u8; 4]> {
+    let millis = u32::try_from(delay.as_millis())
+        .map_err(|_| ScramblerError::SphinxError(format!("Delay too large: {:?}", delay)))?;
+
+    Ok(millis.to_be_bytes())
+}
+
+/// Decode a per-hop delay produced by `encode_delay`
+fn decode_delay(encoded: &[u8]) -> Duration {
+    let millis = u32::from_be_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]);
+    Duration::from_millis(u64::from(millis))
+}
+
 /// Derive encryption and MAC keys from shared secret
 fn derive_keys(shared_secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
     // Derive 64 bytes: 32 for encryption, 32 for MAC
@@ -79,37 +206,79 @@ fn derive_keys(shared_secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
     Ok((enc_key, mac_key))
 }
 
+/// Derive the factor a hop blinds the ephemeral key with
+fn derive_blinding_factor(ephemeral_key: &[u8; 32], shared_secret: &[u8]) -> Result<StaticSecret> {
+    let factor = hkdf_sha256(shared_secret, Some(ephemeral_key), b"SphinxBlinding", 32)?;
+
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&factor);
+    let secret = StaticSecret::from(bytes);
+    bytes.zeroize();
+    Ok(secret)
+}
+
+/// Blind an ephemeral key for the next hop (alpha_{i+1} = alpha_i * b_i)
+fn blind(ephemeral_key: &X25519PublicKey, factor: &StaticSecret) -> X25519PublicKey {
+    X25519PublicKey::from(*factor.diffie_hellman(ephemeral_key).as_bytes())
+}
+
+/// Derive the replay tag a node records for a packet
+fn derive_replay_tag(shared_secret: &[u8]) -> Result<ReplayTag> {
+    let tag_material = hkdf_sha256(shared_secret, None, b"SphinxReplayTag", 32)?;
+
+    let mut tag = [0u8; 32];
+    tag.copy_from_slice(&tag_material);
+    Ok(tag)
+}
 
-/// Encrypt routing data using stream cipher (XOR with keystream)
-/// This avoids the size expansion from AEAD tags
-fn encrypt_routing(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+/// Derive a keystream of `len` bytes from a hop key using HKDF
+fn keystream(key: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>> {
     use ring::hkdf;
 
-    // Derive a keystream from the key using HKDF
     let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, b"");
     let prk = salt.extract(key);
-    let info: &[&[u8]] = &[b"SphinxRouting"];
+    let info: &[&[u8]] = &[info];
     let okm = prk
-        .expand(info, MyLen(plaintext.len()))
+        .expand(info, MyLen(len))
         .map_err(|_| ScramblerError::SphinxError("Key expansion failed".to_string()))?;
 
-    let mut keystream = vec![0u8; plaintext.len()];
+    let mut keystream = vec![0u8; len];
     okm.fill(&mut keystream)
         .map_err(|_| ScramblerError::SphinxError("Keystream generation failed".to_string()))?;
 
-    // XOR plaintext with keystream
-    let mut ciphertext = plaintext.to_vec();
-    for (c, k) in ciphertext.iter_mut().zip(keystream.iter()) {
-        *c ^= k;
+    Ok(keystream)
+}
+
+/// Keystream a hop decrypts its routing info with
+///
+/// It is `HOP_SIZE` bytes longer than the routing info: the extra bytes are what
+/// the hop shifts in at the end after stripping its command and the next MAC.
+fn routing_stream(key: &[u8]) -> Result<Vec<u8>> {
+    keystream(key, ROUTING_STREAM_INFO, HEADER_SIZE + HOP_SIZE)
+}
+
+/// XOR `stream` into `data`, up to the shorter of the two
+fn xor_in_place(data: &mut [u8], stream: &[u8]) {
+    for (d, k) in data.iter_mut().zip(stream) {
+        *d ^= k;
     }
+}
 
-    Ok(ciphertext)
+/// Add or remove one hop's stream cipher layer on a payload
+///
+/// A stream cipher avoids the size expansion of an AEAD tag per hop.
+fn crypt_payload(key: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let mut crypted = payload.to_vec();
+    xor_in_place(&mut crypted, &keystream(key, PAYLOAD_STREAM_INFO, payload.len())?);
+    Ok(crypted)
 }
 
-/// Decrypt routing data (XOR is its own inverse)
-fn decrypt_routing(key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
-    // XOR is symmetric, so decrypt = encrypt
-    encrypt_routing(key, ciphertext)
+/// MAC a hop checks its routing info against
+fn header_mac(mac_key: &[u8], routing_info: &[u8]) -> [u8; MAC_SIZE] {
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, mac_key);
+    let mut mac = [0u8; MAC_SIZE];
+    mac.copy_from_slice(hmac::sign(&hmac_key, routing_info).as_ref());
+    mac
 }
 
 /// Helper struct for HKDF output length
@@ -162,12 +331,14 @@ fn decrypt_payload(key: &[u8], ciphertext: &[u8], associated_data: &[u8]) -> Res
     Ok(plaintext.to_vec())
 }
 
-/// Build a Sphinx packet
-///
-/// # Arguments
-/// * `route` - Route specification (node keys, destination)
-/// * `message` - Message to encapsulate
-pub fn build_packet(route: &RouteSpec, message: &[u8]) -> Result<SphinxPacket> {
+/// Check that a route's keys, addresses and delays line up
+fn validate_route(route: &RouteSpec) -> Result<()> {
+    if route.node_keys.is_empty() {
+        return Err(ScramblerError::SphinxError(
+            "Route must contain at least one hop".to_string()
+        ));
+    }
+
     if route.node_keys.len() > MAX_HOPS {
         return Err(ScramblerError::SphinxError(format!(
             "Too many hops: {} (max {})",
@@ -176,25 +347,34 @@ pub fn build_packet(route: &RouteSpec, message: &[u8]) -> Result<SphinxPacket> {
         )));
     }
 
-    if message.len() > PAYLOAD_SIZE {
+    if route.node_addresses.len() != route.node_keys.len()
+        || route.delays.len() != route.node_keys.len()
+    {
         return Err(ScramblerError::SphinxError(format!(
-            "Message too large: {} bytes (max {})",
-            message.len(),
-            PAYLOAD_SIZE
+            "Route has {} keys but {} addresses and {} delays",
+            route.node_keys.len(),
+            route.node_addresses.len(),
+            route.delays.len()
         )));
     }
 
-    // Step 1: Generate ephemeral key pair
-    // Use StaticSecret instead of EphemeralSecret so we can reuse it
+    Ok(())
+}
+
+/// Generate an ephemeral key and derive a shared secret with every hop
+///
+/// Hop `i` sees the ephemeral key blinded by the factors of hops `0..i`, so
+/// its shared secret is `x * b_0 * ... * b_{i-1} * Y_i`.
+fn derive_shared_secrets(route: &RouteSpec) -> Result<([u8; 32], Vec<Vec<u8>>)> {
     let mut ephemeral_bytes = [0u8; 32];
     RngCore::fill_bytes(&mut OsRng, &mut ephemeral_bytes);
     let ephemeral_secret = StaticSecret::from(ephemeral_bytes);
+    ephemeral_bytes.zeroize();
     let ephemeral_public = X25519PublicKey::from(&ephemeral_secret);
 
-    // Step 2 & 3: Perform ECDH with each node and derive shared secrets
-    // Use same ephemeral key for all hops (simpler onion routing style)
-    let num_hops = route.node_keys.len();
-    let mut shared_secrets = Vec::new();
+    let mut shared_secrets = Vec::with_capacity(route.node_keys.len());
+    let mut blinding_factors: Vec<StaticSecret> = Vec::with_capacity(route.node_keys.len());
+    let mut alpha = ephemeral_public;
 
     for node_key_bytes in &route.node_keys {
         // Parse node's public key
@@ -204,63 +384,157 @@ pub fn build_packet(route: &RouteSpec, message: &[u8]) -> Result<SphinxPacket> {
             .map_err(|_| ScramblerError::SphinxError("Invalid node key length".to_string()))?;
         let node_public = X25519PublicKey::from(node_key_array);
 
-        // Perform ECDH with same ephemeral secret for all hops
-        let shared_secret = ephemeral_secret.diffie_hellman(&node_public);
-        let shared_secret_bytes = shared_secret.as_bytes().to_vec();
+        // Apply the ephemeral secret and every earlier hop's blinding factor
+        let mut shared_secret = *ephemeral_secret.diffie_hellman(&node_public).as_bytes();
+        for factor in &blinding_factors {
+            shared_secret = *factor
+                .diffie_hellman(&X25519PublicKey::from(shared_secret))
+                .as_bytes();
+        }
+
+        let factor = derive_blinding_factor(alpha.as_bytes(), &shared_secret)?;
+        alpha = blind(&alpha, &factor);
+        blinding_factors.push(factor);
 
-        shared_secrets.push(shared_secret_bytes);
+        shared_secrets.push(shared_secret.to_vec());
+        shared_secret.zeroize();
     }
 
-    // Step 4: Build routing info layers (simple onion routing)
-    // Structure: [next_hop(32) | encrypted_inner_layer]
+    Ok((*ephemeral_public.as_bytes(), shared_secrets))
+}
 
-    // Start with innermost: destination (all zeros = deliver)
-    let mut routing_info = vec![0u8; HEADER_SIZE];
-    route.destination.iter().take(32).enumerate().for_each(|(i, &b)| routing_info[i] = b);
+/// Encode a forwarding command for one hop
+fn forward_command(next_hop: &SocketAddr, delay: Duration) -> Result<Vec<u8>> {
+    let mut command = Vec::with_capacity(ROUTING_COMMAND_SIZE);
+    command.push(FLAG_FORWARD);
+    command.extend_from_slice(&encode_delay(delay)?);
+    command.extend_from_slice(&encode_address(next_hop));
+    Ok(command)
+}
 
-    // Build layers in reverse (innermost first)
-    // For hop i, prepend hop i+1's address, then encrypt
-    for i in (0..num_hops).rev() {
-        let (enc_key, _) = derive_keys(&shared_secrets[i])?;
+/// Build the layered routing header
+///
+/// Hop `i` decrypts `[command_i | mac_{i+1} | routing_info_{i+1}]`, where
+/// `mac_{i+1}` is the MAC of the routing info hop `i + 1` receives. The last
+/// hop's layer ends in the filler the earlier hops shift in, so its MAC, and
+/// through it every earlier MAC, covers the whole routing info.
+///
+/// # Arguments
+/// * `route` - Validated route specification
+/// * `shared_secrets` - Shared secret with each hop
+/// * `ephemeral_key` - Ephemeral public key placed in the clear
+/// * `final_command` - Plaintext routing command read by the last hop
+fn build_header(
+    route: &RouteSpec,
+    shared_secrets: &[Vec<u8>],
+    ephemeral_key: [u8; 32],
+    final_command: &[u8],
+) -> Result<SphinxHeader> {
+    let num_hops = route.node_keys.len();
+    let keys = shared_secrets
+        .iter()
+        .map(|secret| derive_keys(secret))
+        .collect::<Result<Vec<_>>>()?;
+    let streams = keys
+        .iter()
+        .map(|(enc_key, _)| routing_stream(enc_key))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Filler: the tail of the last hop's routing info, built up from the bytes
+    // each earlier hop shifts in past the end
+    let mut filler = Vec::with_capacity((num_hops - 1) * HOP_SIZE);
+    for (i, stream) in streams.iter().take(num_hops - 1).enumerate() {
+        filler.extend_from_slice(&[0u8; HOP_SIZE]);
+        xor_in_place(&mut filler, &stream[HEADER_SIZE - i * HOP_SIZE..]);
+    }
 
-        // If not the last hop, prepend next hop's address
-        if i < num_hops - 1 {
-            let next_hop_address = &route.node_keys[i + 1];
-            let mut layer = Vec::new();
-            layer.extend_from_slice(next_hop_address);
-            layer.extend_from_slice(&routing_info);
-            layer.resize(HEADER_SIZE, 0);
-            routing_info = layer;
-        }
+    // Innermost layer: the last hop's command, then the filler
+    let mut routing_info = vec![0u8; HEADER_SIZE - filler.len()];
+    routing_info[..final_command.len()].copy_from_slice(final_command);
+    xor_in_place(&mut routing_info, &streams[num_hops - 1]);
+    routing_info.extend_from_slice(&filler);
+    let mut mac = header_mac(&keys[num_hops - 1].1, &routing_info);
+
+    // Wrap the layers of the earlier hops, innermost first; the bytes dropped
+    // off the end are the ones the hop shifts back in
+    for i in (0..num_hops - 1).rev() {
+        let mut layer = forward_command(&route.node_addresses[i + 1], route.delays[i])?;
+        layer.extend_from_slice(&mac);
+        layer.extend_from_slice(&routing_info[..HEADER_SIZE - HOP_SIZE]);
+        xor_in_place(&mut layer, &streams[i]);
+
+        routing_info = layer;
+        mac = header_mac(&keys[i].1, &routing_info);
+    }
+
+    Ok(SphinxHeader {
+        epoch: route.epoch,
+        ephemeral_key,
+        routing_info,
+        mac,
+    })
+}
+
+/// Length-prefix a message and zero-pad it to `size` bytes
+fn pad_message(message: &[u8], size: usize) -> Vec<u8> {
+    let mut padded = vec![0u8; size];
+    padded[..LENGTH_PREFIX_SIZE].copy_from_slice(&(message.len() as u16).to_be_bytes());
+    padded[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + message.len()].copy_from_slice(message);
+    padded
+}
 
-        // Encrypt this layer using stream cipher (no size expansion)
-        routing_info = encrypt_routing(&enc_key, &routing_info)?;
+/// Recover a message padded with `pad_message`
+fn unpad_message(padded: &[u8]) -> Result<Vec<u8>> {
+    if padded.len() < LENGTH_PREFIX_SIZE {
+        return Err(ScramblerError::InvalidPacket("Payload too short".to_string()));
     }
 
-    // Step 5: Compute MAC for first hop
-    let ephemeral_key_bytes: [u8; 32] = *ephemeral_public.as_bytes();
+    let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if len > padded.len() - LENGTH_PREFIX_SIZE {
+        return Err(ScramblerError::InvalidPacket(format!(
+            "Invalid payload length: {}",
+            len
+        )));
+    }
 
-    let (_, mac_key) = derive_keys(&shared_secrets[0])?;
-    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, &mac_key);
-    let mac_tag = hmac::sign(&hmac_key, &routing_info);
-    let mut mac = [0u8; 32];
-    mac.copy_from_slice(mac_tag.as_ref());
+    Ok(padded[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + len].to_vec())
+}
 
-    let header = SphinxHeader {
-        ephemeral_key: ephemeral_key_bytes,
-        routing_info,
-        mac,
-    };
+/// Build a Sphinx packet
+///
+/// # Arguments
+/// * `route` - Route specification (node keys, addresses, delays, destination)
+/// * `message` - Message to encapsulate
+pub fn build_packet(route: &RouteSpec, message: &[u8]) -> Result<SphinxPacket> {
+    validate_route(route)?;
+
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(ScramblerError::SphinxError(format!(
+            "Message too large: {} bytes (max {})",
+            message.len(),
+            MAX_MESSAGE_SIZE
+        )));
+    }
+
+    // Step 1-3: Generate ephemeral key and derive per-hop shared secrets
+    let num_hops = route.node_keys.len();
+    let (ephemeral_key, shared_secrets) = derive_shared_secrets(route)?;
+
+    // Step 4-5: Build routing info layers ending in delivery at the last hop,
+    // [FLAG_DELIVER | delay | destination]
+    let mut final_command = vec![FLAG_DELIVER];
+    final_command.extend_from_slice(&encode_delay(route.delays[num_hops - 1])?);
+    final_command.extend(route.destination.iter().take(DESTINATION_SIZE));
+
+    let header = build_header(route, &shared_secrets, ephemeral_key, &final_command)?;
 
     // Step 6: Encrypt payload using stream cipher in layers (no size expansion)
-    let mut payload = vec![0u8; PAYLOAD_SIZE];
-    payload[..message.len()].copy_from_slice(message);
+    let mut payload = pad_message(message, PAYLOAD_SIZE);
 
     // Encrypt payload in layers (innermost first)
     for i in (0..num_hops).rev() {
         let (enc_key, _) = derive_keys(&shared_secrets[i])?;
-        // Use stream cipher to avoid size expansion
-        payload = encrypt_routing(&enc_key, &payload)?;
+        payload = crypt_payload(&enc_key, &payload)?;
     }
 
     Ok(SphinxPacket { header, payload })
@@ -268,16 +542,27 @@ pub fn build_packet(route: &RouteSpec, message: &[u8]) -> Result<SphinxPacket> {
 
 /// Process a Sphinx packet at a mix node
 ///
+/// Fails if the header MAC doesn't verify, which is the case for any packet
+/// whose header was modified since it was built.
+///
 /// # Arguments
 /// * `packet` - The Sphinx packet to process
-/// * `node_private_key` - This node's private key
+/// * `node_private_key` - This node's private key for `packet.header.epoch`
 ///
 /// # Returns
-/// * Next hop address and transformed packet, or final destination and payload
+/// * Next hop address, delay and transformed packet, or delay and final payload
 pub fn process_packet(
     packet: &SphinxPacket,
     node_private_key: &[u8],
 ) -> Result<ProcessedPacket> {
+    if packet.header.routing_info.len() != HEADER_SIZE {
+        return Err(ScramblerError::InvalidPacket(format!(
+            "Routing info must be {} bytes, got {}",
+            HEADER_SIZE,
+            packet.header.routing_info.len()
+        )));
+    }
+
     // Step 1: Extract ephemeral public key from header
     let ephemeral_public = X25519PublicKey::from(packet.header.ephemeral_key);
 
@@ -293,69 +578,74 @@ pub fn process_packet(
     // Step 3: Derive encryption and MAC keys
     let (enc_key, mac_key) = derive_keys(shared_secret_bytes)?;
 
-    // Step 4: Verify MAC over routing_info (only if MAC is not all zeros)
-    let has_mac = packet.header.mac.iter().any(|&b| b != 0);
-    if has_mac {
-        let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, &mac_key);
-        hmac::verify(&hmac_key, &packet.header.routing_info, &packet.header.mac)
-            .map_err(|_| ScramblerError::SphinxError("MAC verification failed".to_string()))?;
-    }
-    // If MAC is zeros, skip verification (intermediate hops in our simplified protocol)
-
-    // Step 5: Decrypt one layer of routing info
-    let decrypted_routing = decrypt_routing(&enc_key, &packet.header.routing_info)?;
-
-    // Extract next hop address (first 32 bytes)
-    let mut next_hop = vec![0u8; 32];
-    next_hop.copy_from_slice(&decrypted_routing[0..32]);
-
-    // Remaining bytes are encrypted inner layers
-    let remaining_encrypted = &decrypted_routing[32..];
-
-    // Step 6: Check if this is the final destination (all zeros means deliver)
-    let is_final = next_hop.iter().all(|&b| b == 0);
-
-    if is_final {
-        // Step 7a: Decrypt payload and deliver
-        let decrypted_payload = decrypt_routing(&enc_key, &packet.payload)?;
-
-        // Remove padding (find first zero after message)
-        let message_len = decrypted_payload
-            .iter()
-            .position(|&b| b == 0)
-            .unwrap_or(decrypted_payload.len());
-
-        Ok(ProcessedPacket::Deliver {
-            message: decrypted_payload[..message_len].to_vec(),
-        })
-    } else {
-        // Step 7b: Transform packet for forwarding
-
-        // The remaining encrypted data becomes the new routing_info
-        let mut new_routing_info = remaining_encrypted.to_vec();
-        new_routing_info.resize(HEADER_SIZE, 0);
-
-        // Compute MAC for next hop (zeros for now - proper Sphinx would layer MACs)
-        let new_mac = [0u8; 32];
-
-        let new_header = SphinxHeader {
-            ephemeral_key: packet.header.ephemeral_key,
-            routing_info: new_routing_info,
-            mac: new_mac,
-        };
-
-        // Decrypt one layer of payload using stream cipher
-        let new_payload = decrypt_routing(&enc_key, &packet.payload)?;
-
-        let transformed_packet = SphinxPacket {
-            header: new_header,
-            payload: new_payload,
-        };
-
-        Ok(ProcessedPacket::Forward {
-            next_hop,
-            packet: transformed_packet,
-        })
+    // Step 4: Verify the MAC over routing_info before acting on any of it
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, &mac_key);
+    hmac::verify(&hmac_key, &packet.header.routing_info, &packet.header.mac)
+        .map_err(|_| ScramblerError::SphinxError("MAC verification failed".to_string()))?;
+
+    // Replay tag: identical for every copy of this packet at this node
+    let replay_tag = derive_replay_tag(shared_secret_bytes)?;
+
+    // Step 5: Decrypt one layer of routing info, extended by HOP_SIZE zero bytes
+    // that decrypt to the bytes this hop shifts in
+    let mut decrypted_routing = packet.header.routing_info.clone();
+    decrypted_routing.extend_from_slice(&[0u8; HOP_SIZE]);
+    xor_in_place(&mut decrypted_routing, &routing_stream(&enc_key)?);
+
+    // Parse this hop's routing command
+    let delay = decode_delay(&decrypted_routing[1..5]);
+
+    match decrypted_routing[0] {
+        FLAG_DELIVER => {
+            // Step 7a: Decrypt payload, strip padding and deliver
+            let decrypted_payload = crypt_payload(&enc_key, &packet.payload)?;
+
+            Ok(ProcessedPacket::Deliver {
+                message: unpad_message(&decrypted_payload)?,
+                delay,
+                replay_tag,
+            })
+        }
+        FLAG_FORWARD => {
+            // Step 7b: Transform packet for forwarding
+            let next_hop = decode_address(&decrypted_routing[5..ROUTING_COMMAND_SIZE]);
+
+            // The next hop's MAC follows the command, and the rest (still
+            // encrypted for later hops) becomes the new routing_info
+            let mut new_mac = [0u8; MAC_SIZE];
+            new_mac.copy_from_slice(&decrypted_routing[ROUTING_COMMAND_SIZE..HOP_SIZE]);
+            let new_routing_info = decrypted_routing[HOP_SIZE..].to_vec();
+
+            // Blind the ephemeral key so the next hop sees an unrelated value
+            let factor =
+                derive_blinding_factor(&packet.header.ephemeral_key, shared_secret_bytes)?;
+
+            let new_header = SphinxHeader {
+                epoch: packet.header.epoch,
+                ephemeral_key: *blind(&ephemeral_public, &factor).as_bytes(),
+                routing_info: new_routing_info,
+                mac: new_mac,
+            };
+
+            // Decrypt one layer of payload using stream cipher
+            let new_payload = crypt_payload(&enc_key, &packet.payload)?;
+
+            let transformed_packet = SphinxPacket {
+                header: new_header,
+                payload: new_payload,
+            };
+
+            Ok(ProcessedPacket::Forward {
+                next_hop,
+                delay,
+                packet: transformed_packet,
+                replay_tag,
+            })
+        }
+        flag => Err(ScramblerError::SphinxError(format!(
+            "Unknown routing command: {:#04x}",
+            flag
+        ))),
     }
 }
 
@@ -365,17 +655,177 @@ pub enum ProcessedPacket {
     /// Packet should be forwarded to next hop
     Forward {
         /// Address of next hop
-        next_hop: Vec<u8>,
+        next_hop: SocketAddr,
+        /// Delay to hold the packet before forwarding
+        delay: Duration,
         /// Transformed packet
         packet: SphinxPacket,
+        /// Tag to check against the node's replay cache
+        replay_tag: ReplayTag,
     },
     /// Packet reached final destination
     Deliver {
         /// Decrypted message
         message: Vec<u8>,
+        /// Delay to hold the message before delivery
+        delay: Duration,
+        /// Tag to check against the node's replay cache
+        replay_tag: ReplayTag,
     },
 }
 
+impl ProcessedPacket {
+    /// Tag identifying this packet at the processing node
+    ///
+    /// A node must drop the packet if the tag is already in its replay cache.
+    pub fn replay_tag(&self) -> &ReplayTag {
+        match self {
+            ProcessedPacket::Forward { replay_tag, .. } => replay_tag,
+            ProcessedPacket::Deliver { replay_tag, .. } => replay_tag,
+        }
+    }
+}
+
+/// Single-use reply block (SURB)
+///
+/// A pre-built reply header handed to a destination alongside a request. The
+/// destination answers with `use_surb` and the reply travels back through the
+/// mixnet without the destination learning who the sender is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Surb {
+    /// Address of the first hop of the reply route
+    pub first_hop: SocketAddr,
+    /// Pre-built reply header
+    pub header: SphinxHeader,
+    /// Key the replier uses to encrypt the reply payload
+    pub payload_key: [u8; 32],
+}
+
+/// Secrets kept by the SURB creator to open the reply
+#[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct SurbSecrets {
+    /// Random reply identifier, see `surb_id`
+    #[zeroize(skip)]
+    pub id: SurbId,
+    /// Payload stream keys of each reply hop
+    hop_keys: Vec<Vec<u8>>,
+    /// Key the replier encrypts the payload with
+    payload_key: [u8; 32],
+}
+
+/// Build a single-use reply block
+///
+/// The last hop of `route` forwards the reply to `reply_to`, where the SURB
+/// creator listens for it. `route.destination` is not used. The SURB can only
+/// be used while the nodes still accept packets for `route.epoch`. Its header
+/// carries the same per-hop MACs as a forward packet, so a replier that alters
+/// it to redirect or mark the reply has it dropped at the first hop.
+///
+/// # Arguments
+/// * `route` - Reply route specification (node keys, addresses, delays)
+/// * `reply_to` - Address the last hop forwards the reply to
+///
+/// # Returns
+/// * The SURB to hand out and the secrets needed to open the reply
+pub fn build_surb(route: &RouteSpec, reply_to: SocketAddr) -> Result<(Surb, SurbSecrets)> {
+    validate_route(route)?;
+
+    let num_hops = route.node_keys.len();
+    let (ephemeral_key, shared_secrets) = derive_shared_secrets(route)?;
+
+    // The last hop forwards to the creator instead of delivering, uncovering
+    // the identifier where a forwarding hop finds the next hop's MAC
+    let mut id = [0u8; SURB_ID_SIZE];
+    RngCore::fill_bytes(&mut OsRng, &mut id);
+    let mut final_command = forward_command(&reply_to, route.delays[num_hops - 1])?;
+    final_command.extend_from_slice(&id);
+    final_command.resize(HOP_SIZE, 0);
+    let header = build_header(route, &shared_secrets, ephemeral_key, &final_command)?;
+
+    let hop_keys = shared_secrets
+        .iter()
+        .map(|secret| derive_keys(secret).map(|(enc_key, _)| enc_key))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut payload_key = [0u8; 32];
+    RngCore::fill_bytes(&mut OsRng, &mut payload_key);
+
+    let surb = Surb {
+        first_hop: route.node_addresses[0],
+        header,
+        payload_key,
+    };
+
+    let secrets = SurbSecrets {
+        id,
+        hop_keys,
+        payload_key,
+    };
+
+    Ok((surb, secrets))
+}
+
+/// Answer through a single-use reply block
+///
+/// # Arguments
+/// * `surb` - Reply block received with the request
+/// * `message` - Reply message (at most `MAX_REPLY_SIZE` bytes)
+///
+/// # Returns
+/// * First hop address and the reply packet to send to it
+pub fn use_surb(surb: &Surb, message: &[u8]) -> Result<(SocketAddr, SphinxPacket)> {
+    if message.len() > MAX_REPLY_SIZE {
+        return Err(ScramblerError::SphinxError(format!(
+            "Reply too large: {} bytes (max {})",
+            message.len(),
+            MAX_REPLY_SIZE
+        )));
+    }
+
+    let padded = pad_message(message, PAYLOAD_SIZE - AEAD_TAG_SIZE);
+    let payload = encrypt_payload(&surb.payload_key, &padded, REPLY_AD)?;
+
+    let packet = SphinxPacket {
+        header: surb.header.clone(),
+        payload,
+    };
+
+    Ok((surb.first_hop, packet))
+}
+
+/// Identifier of the SURB a reply forwarded by the last reply hop came through
+///
+/// The value is only meaningful to the SURB's creator, who matches it against
+/// `SurbSecrets::id`; the AEAD check in `open_reply` rejects forgeries.
+pub fn surb_id(packet: &SphinxPacket) -> SurbId {
+    let mut id = [0u8; SURB_ID_SIZE];
+    id.copy_from_slice(&packet.header.mac[..SURB_ID_SIZE]);
+    id
+}
+
+/// Open a reply that arrived through a SURB
+///
+/// # Arguments
+/// * `secrets` - Secrets returned by `build_surb`
+/// * `packet` - Packet forwarded by the last reply hop
+///
+/// # Returns
+/// * The reply message
+pub fn open_reply(secrets: &SurbSecrets, packet: &SphinxPacket) -> Result<Vec<u8>> {
+    if surb_id(packet) != secrets.id {
+        return Err(ScramblerError::SphinxError("Reply does not match SURB".to_string()));
+    }
+
+    // Every hop stripped one stream cipher layer; undo them all
+    let mut payload = packet.payload.clone();
+    for hop_key in &secrets.hop_keys {
+        payload = crypt_payload(hop_key, &payload)?;
+    }
+
+    let padded = decrypt_payload(&secrets.payload_key, &payload, REPLY_AD)?;
+    unpad_message(&padded)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -383,12 +833,13 @@ mod tests {
 
     #[test]
     fn test_encrypt_decrypt_roundtrip() {
-        // Test routing encryption (stream cipher)
+        // Test payload layer encryption (stream cipher)
         let key = vec![1u8; 32];
         let plaintext = b"Hello, Sphinx routing!";
 
-        let ciphertext = encrypt_routing(&key, plaintext).unwrap();
-        let decrypted = decrypt_routing(&key, &ciphertext).unwrap();
+        let ciphertext = crypt_payload(&key, plaintext).unwrap();
+        assert_ne!(&ciphertext, plaintext);
+        let decrypted = crypt_payload(&key, &ciphertext).unwrap();
         assert_eq!(&decrypted, plaintext);
 
         // Test payload encryption (AEAD)
@@ -400,99 +851,226 @@ mod tests {
         assert_eq!(&decrypted2, plaintext2);
     }
 
+    fn test_route(node_keys: Vec<Vec<u8>>) -> RouteSpec {
+        let node_addresses = (0..node_keys.len())
+            .map(|i| SocketAddr::from(([127, 0, 0, 1], 9000 + i as u16)))
+            .collect();
+        let delays = (0..node_keys.len())
+            .map(|i| Duration::from_millis(100 * (i as u64 + 1)))
+            .collect();
+
+        RouteSpec {
+            node_keys,
+            node_addresses,
+            delays,
+            epoch: 0,
+            destination: vec![0u8; 32],
+        }
+    }
+
     #[test]
     fn test_packet_size_limits() {
-        let route = RouteSpec {
-            node_keys: vec![vec![0u8; 32]; 3],
-            destination: vec![0u8; 32],
-        };
+        let route = test_route(vec![vec![0u8; 32]; 3]);
 
         // Message within limits
         let small_msg = vec![0u8; 1024];
         assert!(build_packet(&route, &small_msg).is_ok());
 
+        // Largest message that fits alongside the length prefix
+        let max_msg = vec![0u8; MAX_MESSAGE_SIZE];
+        assert!(build_packet(&route, &max_msg).is_ok());
+
         // Message too large
-        let large_msg = vec![0u8; PAYLOAD_SIZE + 1];
+        let large_msg = vec![0u8; MAX_MESSAGE_SIZE + 1];
         assert!(build_packet(&route, &large_msg).is_err());
     }
 
+    #[test]
+    fn test_binary_payload_delivered_intact() {
+        let keypair = KeyPair::generate().unwrap();
+        let route = test_route(vec![keypair.public_key().to_vec()]);
+
+        // Zero bytes inside and at the end of the message must survive
+        let message = vec![7u8, 0, 0, 9, 0];
+        let packet = build_packet(&route, &message).unwrap();
+
+        match process_packet(&packet, keypair.private_key()).unwrap() {
+            ProcessedPacket::Deliver { message: delivered, .. } => assert_eq!(delivered, message),
+            ProcessedPacket::Forward { .. } => panic!("Should deliver"),
+        }
+    }
+
     #[test]
     fn test_hop_limits() {
-        let route = RouteSpec {
-            node_keys: vec![vec![0u8; 32]; MAX_HOPS + 1],
-            destination: vec![0u8; 32],
-        };
+        let route = test_route(vec![vec![0u8; 32]; MAX_HOPS + 1]);
 
         let message = vec![0u8; 100];
         assert!(build_packet(&route, &message).is_err());
+
+        let empty = test_route(Vec::new());
+        assert!(build_packet(&empty, &message).is_err());
+    }
+
+    #[test]
+    fn test_route_length_mismatch() {
+        let mut route = test_route(vec![vec![0u8; 32]; 3]);
+        route.node_addresses.pop();
+        assert!(build_packet(&route, b"message").is_err());
+
+        let mut route = test_route(vec![vec![0u8; 32]; 3]);
+        route.delays.push(Duration::from_millis(1));
+        assert!(build_packet(&route, b"message").is_err());
+    }
+
+    #[test]
+    fn test_address_encoding() {
+        let v4 = SocketAddr::from(([192, 168, 1, 20], 443));
+        assert_eq!(decode_address(&encode_address(&v4)), v4);
+
+        let v6: SocketAddr = "[2001:db8::1]:8443".parse().unwrap();
+        assert_eq!(decode_address(&encode_address(&v6)), v6);
     }
 
     #[test]
     fn test_sphinx_end_to_end() {
-        // Create 3 mix nodes with real key pairs
-        let node1_keypair = KeyPair::generate().unwrap();
-        let node2_keypair = KeyPair::generate().unwrap();
-        let node3_keypair = KeyPair::generate().unwrap();
+        // Create mix nodes with real key pairs
+        let keypairs: Vec<KeyPair> = (0..MAX_HOPS).map(|_| KeyPair::generate().unwrap()).collect();
 
         // Build route
-        let route = RouteSpec {
-            node_keys: vec![
-                node1_keypair.public_key().to_vec(),
-                node2_keypair.public_key().to_vec(),
-                node3_keypair.public_key().to_vec(),
-            ],
-            destination: vec![0u8; 32], // Final destination (all zeros = deliver)
-        };
+        let route = test_route(keypairs.iter().map(|k| k.public_key().to_vec()).collect());
 
         // Original message
         let original_message = b"Hello, Invisible!";
 
         // Build Sphinx packet
-        let packet = build_packet(&route, original_message).unwrap();
+        let mut packet = build_packet(&route, original_message).unwrap();
+
+        // Each hop learns only the next hop's address and its own delay
+        for (i, keypair) in keypairs.iter().enumerate().take(MAX_HOPS - 1) {
+            packet = match process_packet(&packet, keypair.private_key()).unwrap() {
+                ProcessedPacket::Forward { next_hop, delay, packet, .. } => {
+                    assert_eq!(next_hop, route.node_addresses[i + 1]);
+                    assert_eq!(delay, route.delays[i]);
+                    packet
+                }
+                ProcessedPacket::Deliver { .. } => panic!("Should not deliver at hop {}", i),
+            };
+        }
 
-        // Process at node 1
-        let result1 = process_packet(&packet, node1_keypair.private_key()).unwrap();
-        let packet2 = match result1 {
-            ProcessedPacket::Forward { next_hop, packet } => {
-                assert_eq!(next_hop, node2_keypair.public_key());
-                packet
+        // Final hop delivers
+        match process_packet(&packet, keypairs[MAX_HOPS - 1].private_key()).unwrap() {
+            ProcessedPacket::Forward { .. } => panic!("Should deliver at final hop"),
+            ProcessedPacket::Deliver { message, delay, .. } => {
+                assert_eq!(&message, original_message);
+                assert_eq!(delay, route.delays[MAX_HOPS - 1]);
             }
-            ProcessedPacket::Deliver { .. } => panic!("Should not deliver at node 1"),
-        };
+        }
+    }
 
-        // Process at node 2
-        let result2 = process_packet(&packet2, node2_keypair.private_key()).unwrap();
-        let packet3 = match result2 {
-            ProcessedPacket::Forward { next_hop, packet } => {
-                assert_eq!(next_hop, node3_keypair.public_key());
-                packet
-            }
-            ProcessedPacket::Deliver { .. } => panic!("Should not deliver at node 2"),
-        };
+    #[test]
+    fn test_truncated_routing_info_rejected() {
+        let keypair = KeyPair::generate().unwrap();
+        let route = test_route(vec![keypair.public_key().to_vec()]);
 
-        // Process at node 3 (final hop)
-        let result3 = process_packet(&packet3, node3_keypair.private_key()).unwrap();
-        match result3 {
-            ProcessedPacket::Forward { .. } => panic!("Should deliver at node 3"),
-            ProcessedPacket::Deliver { message } => {
-                assert_eq!(&message, original_message);
-            }
+        let mut packet = build_packet(&route, b"message").unwrap();
+        packet.header.routing_info.truncate(16);
+
+        assert!(process_packet(&packet, keypair.private_key()).is_err());
+    }
+
+    /// Process a packet at a hop that must forward it
+    fn forward(packet: &SphinxPacket, keypair: &KeyPair) -> SphinxPacket {
+        match process_packet(packet, keypair.private_key()).unwrap() {
+            ProcessedPacket::Forward { packet, .. } => packet,
+            ProcessedPacket::Deliver { .. } => panic!("Should forward"),
+        }
+    }
+
+    #[test]
+    fn test_tampered_header_rejected() {
+        let keypairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate().unwrap()).collect();
+        let route = test_route(keypairs.iter().map(|k| k.public_key().to_vec()).collect());
+        let packet = build_packet(&route, b"message").unwrap();
+
+        // A flipped bit in a later hop's layer is caught at the first hop
+        let mut tampered = packet.clone();
+        tampered.header.routing_info[HOP_SIZE + 1] ^= 0x01;
+        assert!(process_packet(&tampered, keypairs[0].private_key()).is_err());
+
+        // Intermediate hops check their MAC too, including the filler at the end
+        let second = forward(&packet, &keypairs[0]);
+        let mut tampered = second.clone();
+        tampered.header.routing_info[5] ^= 0x01;
+        assert!(process_packet(&tampered, keypairs[1].private_key()).is_err());
+
+        let third = forward(&second, &keypairs[1]);
+        let mut tampered = third.clone();
+        tampered.header.routing_info[HEADER_SIZE - 1] ^= 0x01;
+        assert!(process_packet(&tampered, keypairs[2].private_key()).is_err());
+
+        // An all-zero MAC is no exemption
+        let mut tampered = third.clone();
+        tampered.header.mac = [0u8; MAC_SIZE];
+        assert!(process_packet(&tampered, keypairs[2].private_key()).is_err());
+
+        assert!(process_packet(&third, keypairs[2].private_key()).is_ok());
+    }
+
+    #[test]
+    fn test_routing_info_hides_position() {
+        let keypairs: Vec<KeyPair> = (0..MAX_HOPS).map(|_| KeyPair::generate().unwrap()).collect();
+        let route = test_route(keypairs.iter().map(|k| k.public_key().to_vec()).collect());
+        let mut packet = build_packet(&route, b"message").unwrap();
+
+        // No run of zero padding builds up at the end as hops strip their commands
+        for keypair in keypairs.iter().take(MAX_HOPS - 1) {
+            packet = forward(&packet, keypair);
+            assert_eq!(packet.header.routing_info.len(), HEADER_SIZE);
+            assert!(packet.header.routing_info[HEADER_SIZE - HOP_SIZE..].iter().any(|&b| b != 0));
         }
     }
 
+    #[test]
+    fn test_replay_tag_stable_per_packet() {
+        let node1_keypair = KeyPair::generate().unwrap();
+        let node2_keypair = KeyPair::generate().unwrap();
+        let route = test_route(vec![
+            node1_keypair.public_key().to_vec(),
+            node2_keypair.public_key().to_vec(),
+        ]);
+
+        let packet = build_packet(&route, b"message").unwrap();
+        let first = process_packet(&packet, node1_keypair.private_key()).unwrap();
+        let replayed = process_packet(&packet, node1_keypair.private_key()).unwrap();
+
+        // A resent packet yields the same tag at the same node
+        assert_eq!(first.replay_tag(), replayed.replay_tag());
+
+        // A fresh packet over the same route does not
+        let other = build_packet(&route, b"message").unwrap();
+        let other = process_packet(&other, node1_keypair.private_key()).unwrap();
+        assert_ne!(first.replay_tag(), other.replay_tag());
+
+        // The next hop sees an unrelated tag
+        let next = match first {
+            ProcessedPacket::Forward { ref packet, .. } => {
+                process_packet(packet, node2_keypair.private_key()).unwrap()
+            }
+            ProcessedPacket::Deliver { .. } => panic!("Should forward"),
+        };
+        assert_ne!(first.replay_tag(), next.replay_tag());
+    }
+
     #[test]
     fn test_sphinx_unlinkability() {
         // Verify that packets are transformed at each hop (not just forwarded)
         let node1_keypair = KeyPair::generate().unwrap();
         let node2_keypair = KeyPair::generate().unwrap();
 
-        let route = RouteSpec {
-            node_keys: vec![
-                node1_keypair.public_key().to_vec(),
-                node2_keypair.public_key().to_vec(),
-            ],
-            destination: vec![0u8; 32],
-        };
+        let route = test_route(vec![
+            node1_keypair.public_key().to_vec(),
+            node2_keypair.public_key().to_vec(),
+        ]);
 
         let message = b"Test message";
         let packet = build_packet(&route, message).unwrap();
@@ -504,9 +1082,94 @@ mod tests {
             _ => panic!("Should forward"),
         };
 
-        // Packets should be different (transformed)
+        // Packets should be different (transformed), including the blinded key
+        assert_ne!(packet.header.ephemeral_key, packet2.header.ephemeral_key);
         assert_ne!(packet.header.routing_info, packet2.header.routing_info);
         assert_ne!(packet.header.mac, packet2.header.mac);
         assert_ne!(packet.payload, packet2.payload);
     }
+
+    #[test]
+    fn test_surb_reply_roundtrip() {
+        let keypairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate().unwrap()).collect();
+        let route = test_route(keypairs.iter().map(|k| k.public_key().to_vec()).collect());
+        let reply_to: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+
+        let (surb, secrets) = build_surb(&route, reply_to).unwrap();
+        assert_eq!(surb.first_hop, route.node_addresses[0]);
+
+        // The replier only sees the SURB
+        let reply = b"reply\x00with\x00zeros".to_vec();
+        let (first_hop, mut packet) = use_surb(&surb, &reply).unwrap();
+        assert_eq!(first_hop, route.node_addresses[0]);
+
+        // Each reply hop forwards; the last one forwards to the creator
+        let mut seen_keys = vec![packet.header.ephemeral_key];
+        for (i, keypair) in keypairs.iter().enumerate() {
+            packet = match process_packet(&packet, keypair.private_key()).unwrap() {
+                ProcessedPacket::Forward { next_hop, packet, .. } => {
+                    let expected = route.node_addresses.get(i + 1).copied().unwrap_or(reply_to);
+                    assert_eq!(next_hop, expected);
+                    packet
+                }
+                ProcessedPacket::Deliver { .. } => panic!("Reply hops must not deliver"),
+            };
+            assert!(!seen_keys.contains(&packet.header.ephemeral_key));
+            seen_keys.push(packet.header.ephemeral_key);
+        }
+
+        // Only the creator can tell which SURB the reply came through
+        assert_eq!(surb_id(&packet), secrets.id);
+        assert!(!surb.header.mac.starts_with(&secrets.id));
+        assert_eq!(open_reply(&secrets, &packet).unwrap(), reply);
+    }
+
+    #[test]
+    fn test_surb_reply_tampering_detected() {
+        let keypair = KeyPair::generate().unwrap();
+        let route = test_route(vec![keypair.public_key().to_vec()]);
+        let (surb, secrets) = build_surb(&route, "127.0.0.1:7000".parse().unwrap()).unwrap();
+
+        let (_, packet) = use_surb(&surb, b"reply").unwrap();
+        let mut packet = match process_packet(&packet, keypair.private_key()).unwrap() {
+            ProcessedPacket::Forward { packet, .. } => packet,
+            ProcessedPacket::Deliver { .. } => panic!("Reply hops must not deliver"),
+        };
+        packet.payload[10] ^= 0x01;
+
+        assert!(open_reply(&secrets, &packet).is_err());
+    }
+
+    #[test]
+    fn test_tampered_surb_header_rejected() {
+        let keypairs: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate().unwrap()).collect();
+        let route = test_route(keypairs.iter().map(|k| k.public_key().to_vec()).collect());
+        let (surb, _) = build_surb(&route, "127.0.0.1:7000".parse().unwrap()).unwrap();
+
+        // Every bit of the routing info and MAC is covered at the first hop
+        for (byte, bit) in [(0, 0x01), (HOP_SIZE + 3, 0x80), (HEADER_SIZE - 1, 0x10)] {
+            let mut tampered = surb.clone();
+            tampered.header.routing_info[byte] ^= bit;
+            let (_, packet) = use_surb(&tampered, b"reply").unwrap();
+            assert!(process_packet(&packet, keypairs[0].private_key()).is_err());
+        }
+
+        let mut tampered = surb.clone();
+        tampered.header.mac[0] ^= 0x01;
+        let (_, packet) = use_surb(&tampered, b"reply").unwrap();
+        assert!(process_packet(&packet, keypairs[0].private_key()).is_err());
+
+        // The untouched SURB still goes through
+        let (_, packet) = use_surb(&surb, b"reply").unwrap();
+        assert!(process_packet(&packet, keypairs[0].private_key()).is_ok());
+    }
+
+    #[test]
+    fn test_surb_reply_size_limit() {
+        let route = test_route(vec![vec![9u8; 32]]);
+        let (surb, _) = build_surb(&route, "127.0.0.1:7000".parse().unwrap()).unwrap();
+
+        assert!(use_surb(&surb, &vec![1u8; MAX_REPLY_SIZE]).is_ok());
+        assert!(use_surb(&surb, &vec![1u8; MAX_REPLY_SIZE + 1]).is_err());
+    }
 }
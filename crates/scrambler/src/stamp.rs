@@ -0,0 +1,1095 @@
+//! Store Stamps
+//!
+//! Anti-spam authorization for dead drop stores. Without it anyone who learns
+//! a drop ID can fill the drop until it hits its capacity, so relays require
+//! every store to carry a stamp: either a proof of work or an anonymous token
+//! the recipient obtained for the contact ahead of time.
+//!
+//! ## Architecture
+//!
+//! - **Work Stamps:** Hashcash over the drop ID, the payload and a timestamp;
+//!   the relay's policy sets the number of leading zero bits required
+//! - **Store Tokens:** Privacy Pass style tokens over ristretto255. The relay
+//!   evaluates a PRF keyed per drop and token period on blinded points; the
+//!   recipient unblinds the results and hands a batch to each contact, who
+//!   redeems one token per store by MACing the message with it
+//! - **Issuance:** A token batch costs the same work as one work stamp per
+//!   token, paid once by the recipient instead of by the contact
+//! - **Spent Stamps:** Relays remember redeemed tokens for their period and
+//!   work stamps for the clock skew window, so neither can be replayed. The
+//!   spent sets can be backed by an append-only log that is synced before a
+//!   store is accepted, so a restart doesn't reopen them
+//!
+//! ## Security Properties
+//!
+//! - **Sender Anonymity:** Redeeming a token reveals nothing about the batch
+//!   it was issued in; batches carry a DLEQ proof that they were evaluated
+//!   under the drop's public key, so a relay can't tag them with other keys
+//! - **Message Binding:** Stamps cover the drop ID and payload, so a stamp
+//!   seen on the wire can't authorize a different message
+//! - **Cheap Verification:** One hash for a work stamp; one hash to the
+//!   group, one scalar multiplication and one HMAC for a token
+
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+use invisible_crypto::kdf::hkdf_sha256;
+
+use crate::dead_drop::DropId;
+use crate::error::{Result, ScramblerError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Random token nonce chosen by the recipient
+pub type TokenNonce = [u8; 32];
+
+/// Size of one spent log record: kind (1) | hash or nonce (32) | expiry or
+/// period (8, big-endian)
+const SPENT_RECORD_SIZE: usize = 1 + 32 + 8;
+
+/// Spent log record kinds
+const SPENT_WORK: u8 = 0;
+const SPENT_TOKEN: u8 = 1;
+
+/// Records below which the spent log is never compacted
+const MIN_COMPACT_RECORDS: usize = 1024;
+
+/// Store stamp policy of a relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StampPolicy {
+    /// Leading zero bits a work stamp needs; 0 accepts unstamped stores
+    pub work_bits: u8,
+    /// How far a work stamp's timestamp may be from the relay clock (seconds)
+    pub max_skew_secs: u64,
+    /// Lifetime of a token period (seconds); tokens of the current and the
+    /// previous period are accepted
+    pub token_period_secs: u64,
+    /// Maximum tokens issued in one batch
+    pub max_batch: usize,
+}
+
+impl Default for StampPolicy {
+    fn default() -> Self {
+        Self {
+            work_bits: 18,
+            max_skew_secs: 300,        // 5 minutes
+            token_period_secs: 604800, // 1 week
+            max_batch: 64,
+        }
+    }
+}
+
+impl StampPolicy {
+    /// Token period in effect at `now` (Unix seconds)
+    pub fn token_period(&self, now: u64) -> u64 {
+        now / self.token_period_secs.max(1)
+    }
+
+    /// Work an issuance request for `count` tokens needs
+    ///
+    /// Each doubling of the batch adds a bit, so a token costs about as much
+    /// as a work stamp.
+    pub fn issue_bits(&self, count: usize) -> u8 {
+        let extra = count.max(1).next_power_of_two().trailing_zeros() as u8;
+        self.work_bits.saturating_add(extra)
+    }
+}
+
+/// Authorization attached to a dead drop store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreStamp {
+    /// Proof of work over the message
+    Work(WorkStamp),
+    /// Redeemed store token
+    Token(RedeemedToken),
+}
+
+/// Hashcash-style proof of work
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkStamp {
+    /// Unix time (seconds) the stamp was minted at
+    pub issued_at: u64,
+    /// Nonce making the stamp hash start with enough zero bits
+    pub nonce: u64,
+}
+
+impl WorkStamp {
+    /// Mint a work stamp
+    ///
+    /// # Arguments
+    /// * `context` - Digest of what the stamp authorizes
+    /// * `bits` - Leading zero bits required
+    /// * `issued_at` - Current Unix time (seconds)
+    pub fn mint(context: &[u8; 32], bits: u8, issued_at: u64) -> Self {
+        let mut nonce = rand::thread_rng().next_u64();
+        loop {
+            let stamp = Self { issued_at, nonce };
+            if leading_zero_bits(&stamp.hash(context)) >= u32::from(bits) {
+                return stamp;
+            }
+            nonce = nonce.wrapping_add(1);
+        }
+    }
+
+    /// Stamp hash over `context`
+    fn hash(&self, context: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"InvisibleWorkStamp");
+        hasher.update(context);
+        hasher.update(self.issued_at.to_be_bytes());
+        hasher.update(self.nonce.to_be_bytes());
+        hasher.finalize().into()
+    }
+}
+
+/// Store token spent on one message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedeemedToken {
+    /// Token period the token was issued in
+    pub period: u64,
+    /// Token nonce
+    pub nonce: TokenNonce,
+    /// MAC over the message keyed by the token
+    pub mac: [u8; 32],
+}
+
+/// Request for a batch of store tokens
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenRequest {
+    /// Token period the tokens are for
+    pub period: u64,
+    /// Blinded token points
+    pub blinded: Vec<[u8; 32]>,
+    /// Proof of work paying for the batch
+    pub work: WorkStamp,
+}
+
+/// Batch of evaluated store tokens
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenBatch {
+    /// Token period the tokens are for
+    pub period: u64,
+    /// Public key of the drop for the period
+    pub public_key: [u8; 32],
+    /// Evaluated points, in request order
+    pub evaluated: Vec<[u8; 32]>,
+    /// Proof that every point was evaluated under `public_key`
+    pub proof: DleqProof,
+}
+
+/// Batched proof of discrete log equality
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DleqProof {
+    /// Challenge scalar
+    pub challenge: [u8; 32],
+    /// Response scalar
+    pub response: [u8; 32],
+}
+
+/// Blinding factors of a pending token request
+pub struct BlindedTokens {
+    drop_id: DropId,
+    period: u64,
+    nonces: Vec<TokenNonce>,
+    factors: Vec<Scalar>,
+}
+
+impl std::fmt::Debug for BlindedTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlindedTokens")
+            .field("period", &self.period)
+            .field("count", &self.nonces.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlindedTokens {
+    /// Blind `count` fresh tokens for a drop
+    ///
+    /// # Arguments
+    /// * `drop_id` - Drop the tokens authorize stores to
+    /// * `count` - Number of tokens
+    /// * `policy` - Stamp policy of the issuing relay
+    /// * `now` - Current Unix time (seconds)
+    ///
+    /// # Returns
+    /// * Request to send to the relay and the state to finalize its answer with
+    pub fn new(
+        drop_id: DropId,
+        count: usize,
+        policy: &StampPolicy,
+        now: u64,
+    ) -> (TokenRequest, Self) {
+        let period = policy.token_period(now);
+        let mut nonces = Vec::with_capacity(count);
+        let mut factors = Vec::with_capacity(count);
+        let mut blinded = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let mut nonce = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            let factor = random_scalar();
+
+            blinded.push((factor * hash_to_point(&nonce)).compress().to_bytes());
+            nonces.push(nonce);
+            factors.push(factor);
+        }
+
+        let context = issue_context(&drop_id, period, &blinded);
+        let work = WorkStamp::mint(&context, policy.issue_bits(count), now);
+        let request = TokenRequest {
+            period,
+            blinded,
+            work,
+        };
+
+        (
+            request,
+            Self {
+                drop_id,
+                period,
+                nonces,
+                factors,
+            },
+        )
+    }
+
+    /// Unblind the relay's answer
+    ///
+    /// Fails if the batch doesn't match the request or its proof is invalid.
+    pub fn finalize(self, batch: &TokenBatch) -> Result<Vec<StoreToken>> {
+        if batch.period != self.period || batch.evaluated.len() != self.nonces.len() {
+            return Err(ScramblerError::StampError(
+                "Token batch doesn't match request".to_string(),
+            ));
+        }
+
+        let public_key = decompress(&batch.public_key)?;
+        let evaluated = batch
+            .evaluated
+            .iter()
+            .map(decompress)
+            .collect::<Result<Vec<_>>>()?;
+        let blinded: Vec<RistrettoPoint> = self
+            .nonces
+            .iter()
+            .zip(&self.factors)
+            .map(|(nonce, factor)| factor * hash_to_point(nonce))
+            .collect();
+
+        verify_dleq(&public_key, &blinded, &evaluated, &batch.proof)?;
+
+        Ok(self
+            .nonces
+            .iter()
+            .zip(&self.factors)
+            .zip(&evaluated)
+            .map(|((nonce, factor), point)| StoreToken {
+                drop_id: self.drop_id,
+                period: self.period,
+                public_key: batch.public_key,
+                nonce: *nonce,
+                evaluation: (factor.invert() * point).compress().to_bytes(),
+            })
+            .collect())
+    }
+}
+
+/// Unspent store token
+///
+/// Handed from the recipient to a contact, who redeems it for one store.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoreToken {
+    /// Drop the token authorizes stores to
+    pub drop_id: DropId,
+    /// Token period the token was issued in
+    pub period: u64,
+    /// Public key of the drop the token was issued under
+    pub public_key: [u8; 32],
+    nonce: TokenNonce,
+    evaluation: [u8; 32],
+}
+
+impl std::fmt::Debug for StoreToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StoreToken")
+            .field("period", &self.period)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StoreToken {
+    /// Spend the token on a message
+    pub fn redeem(&self, payload: &[u8]) -> StoreStamp {
+        let key = token_key(&self.nonce, &self.evaluation);
+
+        StoreStamp::Token(RedeemedToken {
+            period: self.period,
+            nonce: self.nonce,
+            mac: message_mac(&key, &self.drop_id, payload),
+        })
+    }
+}
+
+/// Relay side of store stamps
+///
+/// Issues store tokens and checks the stamps on incoming stores.
+pub struct StampVerifier {
+    policy: StampPolicy,
+    /// Secret the per-drop token keys are derived from
+    secret: Zeroizing<[u8; 32]>,
+    /// Hashes of accepted work stamps and when they leave the skew window
+    spent_work: HashMap<[u8; 32], u64>,
+    /// Redeemed token nonces and their period
+    spent_tokens: HashMap<TokenNonce, u64>,
+    /// Append-only log backing the spent sets
+    log: Option<SpentLog>,
+}
+
+/// On-disk log of spent stamps
+#[derive(Debug)]
+struct SpentLog {
+    path: PathBuf,
+    file: File,
+    /// Records in the file, including ones pruned from memory
+    records: usize,
+}
+
+impl std::fmt::Debug for StampVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StampVerifier")
+            .field("policy", &self.policy)
+            .field("spent_work", &self.spent_work.len())
+            .field("spent_tokens", &self.spent_tokens.len())
+            .field("log", &self.log)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StampVerifier {
+    /// Create a verifier
+    ///
+    /// # Arguments
+    /// * `policy` - Stamp policy to enforce
+    /// * `secret` - Token key secret; keep it across restarts so issued
+    ///   tokens stay valid
+    pub fn new(policy: StampPolicy, secret: &[u8; 32]) -> Self {
+        Self {
+            policy,
+            secret: Zeroizing::new(*secret),
+            spent_work: HashMap::new(),
+            spent_tokens: HashMap::new(),
+            log: None,
+        }
+    }
+
+    /// Open a verifier whose spent stamps are persisted at `path`
+    ///
+    /// Stamps spent in a previous run are loaded; the file is created if it
+    /// doesn't exist. A torn trailing record from a crash is ignored.
+    ///
+    /// # Arguments
+    /// * `policy` - Stamp policy to enforce
+    /// * `secret` - Token key secret
+    /// * `path` - Spent stamp log
+    pub fn open(policy: StampPolicy, secret: &[u8; 32], path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut bytes = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut bytes).map_err(|e| io_error("read", &path, e))?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(io_error("open", &path, e)),
+        }
+
+        let mut verifier = Self::new(policy, secret);
+        for record in bytes.chunks_exact(SPENT_RECORD_SIZE) {
+            let (kind, key, value) = decode_spent(record);
+            match kind {
+                SPENT_WORK => verifier.spent_work.insert(key, value),
+                SPENT_TOKEN => verifier.spent_tokens.insert(key, value),
+                _ => {
+                    return Err(ScramblerError::StampError(format!(
+                        "Corrupt spent stamp log {}",
+                        path.display()
+                    )))
+                }
+            };
+        }
+
+        // Rewrite the log so a torn trailing record doesn't misalign later appends
+        if bytes.len() % SPENT_RECORD_SIZE != 0 {
+            tracing::warn!(path = %path.display(), "Discarding torn spent stamp record");
+            verifier.write_snapshot(&path)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| io_error("open", &path, e))?;
+        let records = verifier.spent_work.len() + verifier.spent_tokens.len();
+        verifier.log = Some(SpentLog { path, file, records });
+
+        Ok(verifier)
+    }
+
+    /// Policy enforced by the verifier
+    pub fn policy(&self) -> &StampPolicy {
+        &self.policy
+    }
+
+    /// Evaluate a token request for a drop
+    ///
+    /// # Arguments
+    /// * `drop_id` - Drop the tokens authorize stores to
+    /// * `request` - Blinded tokens and the work paying for them
+    /// * `now` - Current Unix time (seconds)
+    pub fn issue(
+        &mut self,
+        drop_id: &DropId,
+        request: &TokenRequest,
+        now: u64,
+    ) -> Result<TokenBatch> {
+        let count = request.blinded.len();
+        if count == 0 || count > self.policy.max_batch {
+            return Err(ScramblerError::StampError(format!(
+                "Batch of {} tokens not allowed",
+                count
+            )));
+        }
+        if request.period != self.policy.token_period(now) {
+            return Err(ScramblerError::StampError(
+                "Tokens requested for another period".to_string(),
+            ));
+        }
+
+        let context = issue_context(drop_id, request.period, &request.blinded);
+        self.check_work(&context, &request.work, self.policy.issue_bits(count), now)?;
+
+        let key = self.drop_key(drop_id, request.period)?;
+        let public_key = *key * RISTRETTO_BASEPOINT_POINT;
+        let blinded = request
+            .blinded
+            .iter()
+            .map(decompress)
+            .collect::<Result<Vec<_>>>()?;
+        let evaluated: Vec<RistrettoPoint> = blinded.iter().map(|point| *key * point).collect();
+        let proof = prove_dleq(&key, &public_key, &blinded, &evaluated);
+
+        Ok(TokenBatch {
+            period: request.period,
+            public_key: public_key.compress().to_bytes(),
+            evaluated: evaluated.iter().map(|p| p.compress().to_bytes()).collect(),
+            proof,
+        })
+    }
+
+    /// Check the stamp on a store and mark it spent
+    ///
+    /// # Arguments
+    /// * `drop_id` - Drop the message is stored in
+    /// * `payload` - Message payload
+    /// * `stamp` - Stamp sent with the store, if any
+    /// * `now` - Current Unix time (seconds)
+    pub fn verify(
+        &mut self,
+        drop_id: &DropId,
+        payload: &[u8],
+        stamp: Option<&StoreStamp>,
+        now: u64,
+    ) -> Result<()> {
+        self.prune(now)?;
+
+        match stamp {
+            None if self.policy.work_bits == 0 => Ok(()),
+            None => Err(ScramblerError::StampError(
+                "Store stamp required".to_string(),
+            )),
+            Some(StoreStamp::Work(work)) => {
+                let context = store_context(drop_id, payload);
+                self.check_work(&context, work, self.policy.work_bits, now)
+            }
+            Some(StoreStamp::Token(token)) => self.check_token(drop_id, payload, token, now),
+        }
+    }
+
+    /// Check the stamp a sibling relay accepted a replicated store with
+    ///
+    /// Work stamps are checked as on a store. A token's MAC is keyed by the
+    /// relay that issued it, so only its period is checked here and the
+    /// sibling's signature on the replica vouches for the rest. Either way the
+    /// stamp is marked spent, so a replayed replica is rejected.
+    ///
+    /// # Arguments
+    /// * `drop_id` - Drop the message is stored in
+    /// * `payload` - Message payload
+    /// * `stamp` - Stamp the message was stored with, if any
+    /// * `now` - Current Unix time (seconds)
+    pub fn verify_replica(
+        &mut self,
+        drop_id: &DropId,
+        payload: &[u8],
+        stamp: Option<&StoreStamp>,
+        now: u64,
+    ) -> Result<()> {
+        match stamp {
+            Some(StoreStamp::Token(token)) => {
+                self.prune(now)?;
+                self.check_unspent(token, now)?;
+                self.spend(SPENT_TOKEN, token.nonce, token.period)
+            }
+            _ => self.verify(drop_id, payload, stamp, now),
+        }
+    }
+
+    /// Forget spent stamps that can no longer be replayed
+    ///
+    /// The log is compacted once most of its records have been forgotten.
+    pub fn prune(&mut self, now: u64) -> Result<()> {
+        let oldest_period = self.policy.token_period(now).saturating_sub(1);
+
+        self.spent_work.retain(|_, expires_at| *expires_at > now);
+        self.spent_tokens
+            .retain(|_, period| *period >= oldest_period);
+
+        let live = self.spent_work.len() + self.spent_tokens.len();
+        let path = match &self.log {
+            Some(log) if log.records > MIN_COMPACT_RECORDS && log.records > 2 * live => {
+                log.path.clone()
+            }
+            _ => return Ok(()),
+        };
+
+        self.write_snapshot(&path)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| io_error("open", &path, e))?;
+        self.log = Some(SpentLog { path, file, records: live });
+
+        Ok(())
+    }
+
+    /// Record a spent stamp
+    ///
+    /// It's on disk before this returns, so the store can be accepted.
+    fn spend(&mut self, kind: u8, key: [u8; 32], value: u64) -> Result<()> {
+        if let Some(log) = self.log.as_mut() {
+            log.file
+                .write_all(&encode_spent(kind, &key, value))
+                .map_err(|e| io_error("write", &log.path, e))?;
+            log.file.sync_data().map_err(|e| io_error("sync", &log.path, e))?;
+            log.records += 1;
+        }
+
+        match kind {
+            SPENT_WORK => self.spent_work.insert(key, value),
+            _ => self.spent_tokens.insert(key, value),
+        };
+        Ok(())
+    }
+
+    /// Atomically replace the log at `path` with the current spent sets
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let work = self.spent_work.iter().map(|(hash, expires)| (SPENT_WORK, hash, expires));
+        let tokens = self.spent_tokens.iter().map(|(nonce, period)| (SPENT_TOKEN, nonce, period));
+        let mut bytes = Vec::new();
+        for (kind, key, value) in work.chain(tokens) {
+            bytes.extend_from_slice(&encode_spent(kind, key, *value));
+        }
+
+        // Sync the temp file before the rename and the directory after it, so a
+        // crash leaves either the old or the new log
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path).map_err(|e| io_error("create", &tmp_path, e))?;
+        tmp.write_all(&bytes).map_err(|e| io_error("write", &tmp_path, e))?;
+        tmp.sync_all().map_err(|e| io_error("sync", &tmp_path, e))?;
+        fs::rename(&tmp_path, path).map_err(|e| io_error("rename", path, e))?;
+        sync_dir(path)
+    }
+
+    fn check_work(
+        &mut self,
+        context: &[u8; 32],
+        work: &WorkStamp,
+        bits: u8,
+        now: u64,
+    ) -> Result<()> {
+        if work.issued_at.abs_diff(now) > self.policy.max_skew_secs {
+            return Err(ScramblerError::StampError("Work stamp expired".to_string()));
+        }
+
+        let hash = work.hash(context);
+        if leading_zero_bits(&hash) < u32::from(bits) {
+            return Err(ScramblerError::StampError("Insufficient work".to_string()));
+        }
+        if self.spent_work.contains_key(&hash) {
+            return Err(ScramblerError::StampError(
+                "Work stamp already spent".to_string(),
+            ));
+        }
+
+        let expires_at = work.issued_at.saturating_add(self.policy.max_skew_secs + 1);
+        self.spend(SPENT_WORK, hash, expires_at)
+    }
+
+    fn check_token(
+        &mut self,
+        drop_id: &DropId,
+        payload: &[u8],
+        token: &RedeemedToken,
+        now: u64,
+    ) -> Result<()> {
+        self.check_unspent(token, now)?;
+
+        let key = self.drop_key(drop_id, token.period)?;
+        let evaluation = (*key * hash_to_point(&token.nonce)).compress().to_bytes();
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(token_key(&token.nonce, &evaluation).as_ref())
+                .expect("HMAC accepts any key length");
+        mac.update(&store_context(drop_id, payload));
+        mac.verify_slice(&token.mac)
+            .map_err(|_| ScramblerError::StampError("Invalid store token".to_string()))?;
+
+        self.spend(SPENT_TOKEN, token.nonce, token.period)
+    }
+
+    /// Check that a token is of an accepted period and hasn't been spent
+    fn check_unspent(&self, token: &RedeemedToken, now: u64) -> Result<()> {
+        let current = self.policy.token_period(now);
+        if token.period > current || token.period.saturating_add(1) < current {
+            return Err(ScramblerError::StampError(
+                "Store token expired".to_string(),
+            ));
+        }
+        if self.spent_tokens.contains_key(&token.nonce) {
+            return Err(ScramblerError::StampError(
+                "Store token already spent".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// PRF key of a drop for a token period
+    fn drop_key(&self, drop_id: &DropId, period: u64) -> Result<Zeroizing<Scalar>> {
+        let mut info = drop_id.to_vec();
+        info.extend_from_slice(&period.to_be_bytes());
+
+        let okm = Zeroizing::new(hkdf_sha256(
+            self.secret.as_ref(),
+            Some(b"InvisibleStoreTokenKey"),
+            &info,
+            64,
+        )?);
+        let mut wide = Zeroizing::new([0u8; 64]);
+        wide.copy_from_slice(&okm);
+
+        Ok(Zeroizing::new(Scalar::from_bytes_mod_order_wide(&wide)))
+    }
+}
+
+/// Digest a work stamp or token MAC over a store covers
+pub fn store_context(drop_id: &DropId, payload: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"InvisibleStoreStamp");
+    hasher.update(drop_id);
+    hasher.update(Sha256::digest(payload));
+    hasher.finalize().into()
+}
+
+/// Digest the work on a token request covers
+fn issue_context(drop_id: &DropId, period: u64, blinded: &[[u8; 32]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"InvisibleStoreIssue");
+    hasher.update(drop_id);
+    hasher.update(period.to_be_bytes());
+    for point in blinded {
+        hasher.update(point);
+    }
+    hasher.finalize().into()
+}
+
+/// MAC key of an unblinded token
+fn token_key(nonce: &TokenNonce, evaluation: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"InvisibleStoreTokenMac");
+    hasher.update(nonce);
+    hasher.update(evaluation);
+    Zeroizing::new(hasher.finalize().into())
+}
+
+fn message_mac(key: &[u8; 32], drop_id: &DropId, payload: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&store_context(drop_id, payload));
+    mac.finalize().into_bytes().into()
+}
+
+fn encode_spent(kind: u8, key: &[u8; 32], value: u64) -> [u8; SPENT_RECORD_SIZE] {
+    let mut record = [0u8; SPENT_RECORD_SIZE];
+    record[0] = kind;
+    record[1..33].copy_from_slice(key);
+    record[33..].copy_from_slice(&value.to_be_bytes());
+    record
+}
+
+fn decode_spent(record: &[u8]) -> (u8, [u8; 32], u64) {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&record[1..33]);
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&record[33..SPENT_RECORD_SIZE]);
+    (record[0], key, u64::from_be_bytes(value))
+}
+
+/// Sync the directory holding `path` so a rename within it is durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| io_error("sync", dir, e))
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn io_error(action: &str, path: &Path, e: std::io::Error) -> ScramblerError {
+    ScramblerError::StampError(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+fn leading_zero_bits(hash: &[u8; 32]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn hash_to_point(nonce: &TokenNonce) -> RistrettoPoint {
+    let mut hasher = Sha512::new();
+    hasher.update(b"InvisibleStoreTokenPoint");
+    hasher.update(nonce);
+    RistrettoPoint::from_uniform_bytes(&hasher.finalize().into())
+}
+
+fn random_scalar() -> Scalar {
+    let mut wide = Zeroizing::new([0u8; 64]);
+    rand::thread_rng().fill_bytes(wide.as_mut());
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+fn decompress(bytes: &[u8; 32]) -> Result<RistrettoPoint> {
+    CompressedRistretto(*bytes)
+        .decompress()
+        .ok_or_else(|| ScramblerError::StampError("Invalid group element".to_string()))
+}
+
+/// Combine a batch into a single pair with weights bound to the whole batch
+fn combine(
+    public_key: &RistrettoPoint,
+    blinded: &[RistrettoPoint],
+    evaluated: &[RistrettoPoint],
+) -> (RistrettoPoint, RistrettoPoint) {
+    let mut hasher = Sha256::new();
+    hasher.update(b"InvisibleStoreDleqBatch");
+    hasher.update(public_key.compress().as_bytes());
+    for point in blinded.iter().chain(evaluated) {
+        hasher.update(point.compress().as_bytes());
+    }
+    let seed: [u8; 32] = hasher.finalize().into();
+
+    blinded.iter().zip(evaluated).enumerate().fold(
+        (RistrettoPoint::default(), RistrettoPoint::default()),
+        |(m, z), (i, (b, e))| {
+            let weight = hash_to_scalar(&[&seed, &(i as u64).to_be_bytes()]);
+            (m + weight * b, z + weight * e)
+        },
+    )
+}
+
+fn dleq_challenge(
+    public_key: &RistrettoPoint,
+    m: &RistrettoPoint,
+    z: &RistrettoPoint,
+    a1: &RistrettoPoint,
+    a2: &RistrettoPoint,
+) -> Scalar {
+    hash_to_scalar(&[
+        b"InvisibleStoreDleq",
+        public_key.compress().as_bytes(),
+        m.compress().as_bytes(),
+        z.compress().as_bytes(),
+        a1.compress().as_bytes(),
+        a2.compress().as_bytes(),
+    ])
+}
+
+fn prove_dleq(
+    key: &Scalar,
+    public_key: &RistrettoPoint,
+    blinded: &[RistrettoPoint],
+    evaluated: &[RistrettoPoint],
+) -> DleqProof {
+    let (m, z) = combine(public_key, blinded, evaluated);
+    let nonce = Zeroizing::new(random_scalar());
+    let a1 = *nonce * RISTRETTO_BASEPOINT_POINT;
+    let a2 = *nonce * m;
+
+    let challenge = dleq_challenge(public_key, &m, &z, &a1, &a2);
+    let response = *nonce - challenge * key;
+
+    DleqProof {
+        challenge: challenge.to_bytes(),
+        response: response.to_bytes(),
+    }
+}
+
+fn verify_dleq(
+    public_key: &RistrettoPoint,
+    blinded: &[RistrettoPoint],
+    evaluated: &[RistrettoPoint],
+    proof: &DleqProof,
+) -> Result<()> {
+    let invalid = || ScramblerError::StampError("Invalid token batch proof".to_string());
+    let challenge: Scalar =
+        Option::from(Scalar::from_canonical_bytes(proof.challenge)).ok_or_else(invalid)?;
+    let response: Scalar =
+        Option::from(Scalar::from_canonical_bytes(proof.response)).ok_or_else(invalid)?;
+
+    let (m, z) = combine(public_key, blinded, evaluated);
+    let a1 = response * RISTRETTO_BASEPOINT_POINT + challenge * public_key;
+    let a2 = response * m + challenge * z;
+
+    if dleq_challenge(public_key, &m, &z, &a1, &a2) == challenge {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> StampPolicy {
+        StampPolicy {
+            work_bits: 8,
+            ..StampPolicy::default()
+        }
+    }
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn test_work_stamp() {
+        let mut verifier = StampVerifier::new(policy(), &[7u8; 32]);
+        let drop_id = [1u8; 32];
+        let context = store_context(&drop_id, b"message");
+        let others = [
+            store_context(&drop_id, b"other"),
+            store_context(&[2u8; 32], b"message"),
+        ];
+        assert!(!others.contains(&context));
+
+        // With 8 bits a stamp meets another context's target 1 time in 256,
+        // so take one that doesn't for the binding checks below
+        let work = loop {
+            let work = WorkStamp::mint(&context, 8, NOW);
+            if others.iter().all(|other| leading_zero_bits(&work.hash(other)) < 8) {
+                break work;
+            }
+        };
+        let stamp = StoreStamp::Work(work);
+
+        // Bound to the message
+        assert!(verifier
+            .verify(&drop_id, b"other", Some(&stamp), NOW)
+            .is_err());
+        assert!(verifier
+            .verify(&[2u8; 32], b"message", Some(&stamp), NOW)
+            .is_err());
+
+        verifier
+            .verify(&drop_id, b"message", Some(&stamp), NOW)
+            .unwrap();
+        // Replayed
+        assert!(verifier
+            .verify(&drop_id, b"message", Some(&stamp), NOW)
+            .is_err());
+
+        // Unstamped and stale stores are rejected
+        assert!(verifier.verify(&drop_id, b"message", None, NOW).is_err());
+        let stale = StoreStamp::Work(WorkStamp::mint(&context, 8, NOW - 3600));
+        assert!(verifier
+            .verify(&drop_id, b"message", Some(&stale), NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn test_work_disabled() {
+        let policy = StampPolicy {
+            work_bits: 0,
+            ..StampPolicy::default()
+        };
+        let mut verifier = StampVerifier::new(policy, &[7u8; 32]);
+
+        verifier.verify(&[1u8; 32], b"message", None, NOW).unwrap();
+    }
+
+    #[test]
+    fn test_store_tokens() {
+        let mut verifier = StampVerifier::new(policy(), &[7u8; 32]);
+        let drop_id = [1u8; 32];
+
+        let (request, blinded) = BlindedTokens::new(drop_id, 4, &policy(), NOW);
+        let batch = verifier.issue(&drop_id, &request, NOW).unwrap();
+        let tokens = blinded.finalize(&batch).unwrap();
+        assert_eq!(tokens.len(), 4);
+
+        // The relay only ever saw blinded points
+        for token in &tokens {
+            let point = hash_to_point(&token.nonce).compress().to_bytes();
+            assert!(!request.blinded.contains(&point));
+        }
+
+        let stamp = tokens[0].redeem(b"message");
+        assert!(verifier
+            .verify(&drop_id, b"other", Some(&stamp), NOW)
+            .is_err());
+        assert!(verifier
+            .verify(&[2u8; 32], b"message", Some(&stamp), NOW)
+            .is_err());
+        verifier
+            .verify(&drop_id, b"message", Some(&stamp), NOW)
+            .unwrap();
+        // Spent
+        let again = tokens[0].redeem(b"again");
+        assert!(verifier
+            .verify(&drop_id, b"again", Some(&again), NOW)
+            .is_err());
+
+        // Still valid in the next period, not after that
+        let next = NOW + policy().token_period_secs;
+        let stamp = tokens[1].redeem(b"message");
+        verifier
+            .verify(&drop_id, b"message", Some(&stamp), next)
+            .unwrap();
+        let later = next + policy().token_period_secs;
+        let stamp = tokens[2].redeem(b"message");
+        assert!(verifier
+            .verify(&drop_id, b"message", Some(&stamp), later)
+            .is_err());
+
+        // Tokens survive a relay restart with the same secret
+        let mut restarted = StampVerifier::new(policy(), &[7u8; 32]);
+        let stamp = tokens[3].redeem(b"message");
+        restarted
+            .verify(&drop_id, b"message", Some(&stamp), NOW)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_spent_stamps_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spent.log");
+        let drop_id = [1u8; 32];
+
+        let (request, blinded) = BlindedTokens::new(drop_id, 1, &policy(), NOW);
+        let mut verifier = StampVerifier::open(policy(), &[7u8; 32], &path).unwrap();
+        let batch = verifier.issue(&drop_id, &request, NOW).unwrap();
+        let token = blinded.finalize(&batch).unwrap()[0].redeem(b"message");
+        let context = store_context(&drop_id, b"message");
+        let work = StoreStamp::Work(WorkStamp::mint(&context, 8, NOW));
+        verifier.verify(&drop_id, b"message", Some(&token), NOW).unwrap();
+        verifier.verify(&drop_id, b"message", Some(&work), NOW).unwrap();
+        drop(verifier);
+
+        // Neither can be replayed after a restart, even past a torn record
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(&[0xff; 5]).unwrap();
+        drop(log);
+        let mut verifier = StampVerifier::open(policy(), &[7u8; 32], &path).unwrap();
+        assert!(verifier.verify(&drop_id, b"message", Some(&token), NOW).is_err());
+        assert!(verifier.verify(&drop_id, b"message", Some(&work), NOW).is_err());
+        assert!(verifier.issue(&drop_id, &request, NOW).is_err());
+
+        // The issuance work, the token and the work stamp
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            3 * SPENT_RECORD_SIZE
+        );
+    }
+
+    #[test]
+    fn test_spent_log_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spent.log");
+        let policy = StampPolicy {
+            work_bits: 0,
+            ..StampPolicy::default()
+        };
+        let mut verifier = StampVerifier::open(policy.clone(), &[7u8; 32], &path).unwrap();
+
+        for nonce in 0..=MIN_COMPACT_RECORDS as u64 {
+            let stamp = StoreStamp::Work(WorkStamp { issued_at: NOW, nonce });
+            verifier.verify(&[1u8; 32], b"message", Some(&stamp), NOW).unwrap();
+        }
+
+        // Once the work stamps leave the skew window the log is rewritten
+        verifier.prune(NOW + policy.max_skew_secs + 1).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        let stamp = StoreStamp::Work(WorkStamp { issued_at: NOW + 600, nonce: 0 });
+        verifier.verify(&[1u8; 32], b"message", Some(&stamp), NOW + 600).unwrap();
+        drop(verifier);
+
+        let verifier = StampVerifier::open(policy, &[7u8; 32], &path).unwrap();
+        assert_eq!(verifier.spent_work.len(), 1);
+    }
+
+    #[test]
+    fn test_token_batch_proof() {
+        let mut verifier = StampVerifier::new(policy(), &[7u8; 32]);
+        let mut tagging = StampVerifier::new(policy(), &[8u8; 32]);
+        let drop_id = [1u8; 32];
+
+        // Evaluations under a different key than the one claimed are caught
+        let (request, blinded) = BlindedTokens::new(drop_id, 2, &policy(), NOW);
+        let mut batch = verifier.issue(&drop_id, &request, NOW).unwrap();
+        let tagged = tagging.issue(&drop_id, &request, NOW).unwrap();
+        batch.evaluated[1] = tagged.evaluated[1];
+        assert!(blinded.finalize(&batch).is_err());
+
+        // The work paying for a batch can't be reused
+        assert!(verifier.issue(&drop_id, &request, NOW).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! Pluggable Transports
+//!
+//! Connection-level camouflage: a transport takes an established byte stream
+//! (usually a TCP connection) and hands back a duplex stream that carries the
+//! same bytes disguised on the wire.
+//!
+//! ## Architecture
+//!
+//! - **PluggableTransport:** Client (`connect`) and server (`accept`) halves
+//!   of a transport; object-safe so callers can hold `Arc<dyn PluggableTransport>`
+//! - **PlainTransport:** No camouflage; the stream is passed through
+//! - **Dialer:** Opens the connection a transport runs over: direct TCP
+//!   (`TcpDialer`), or TCP through the Ghost VPN tunnel (`crate::vpn::VpnDialer`)
+//! - **Message Pump:** Adapts message-oriented transports (obfs4 frames, TLS
+//!   records, HTTP requests) to a plain byte stream. The caller gets one end of
+//!   an in-memory duplex pipe; a background task moves bytes between the other
+//!   end and the camouflaged connection.
+//!
+//! ## Security Properties
+//!
+//! - **Uniform Use:** Every connection opened by `PacketTransmitter`,
+//!   `DeadDropProtocol`, and `DirectoryProtocol`, and every connection the
+//!   relay accepts, goes through the configured transport
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::error::{Result, ScramblerError};
+
+/// Byte stream a transport runs over or hands back
+pub trait TransportStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> TransportStream for T {}
+
+/// Type-erased transport stream
+pub type BoxedStream = Box<dyn TransportStream>;
+
+/// Future returned by transport handshakes
+pub type TransportFuture<'a> = Pin<Box<dyn Future<Output = Result<BoxedStream>> + Send + 'a>>;
+
+/// Connection camouflage with client and server halves
+pub trait PluggableTransport: Debug + Send + Sync {
+    /// Camouflage an outgoing connection
+    ///
+    /// # Arguments
+    /// * `stream` - Connection to the server
+    ///
+    /// # Returns
+    /// * Stream carrying plaintext bytes once any handshake completed
+    fn connect(&self, stream: BoxedStream) -> TransportFuture<'_>;
+
+    /// Unwrap an incoming connection
+    ///
+    /// # Arguments
+    /// * `stream` - Connection from the client
+    ///
+    /// # Returns
+    /// * Stream carrying plaintext bytes once any handshake completed
+    fn accept(&self, stream: BoxedStream) -> TransportFuture<'_>;
+}
+
+/// Transport without camouflage
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlainTransport;
+
+impl PluggableTransport for PlainTransport {
+    fn connect(&self, stream: BoxedStream) -> TransportFuture<'_> {
+        Box::pin(async move { Ok(stream) })
+    }
+
+    fn accept(&self, stream: BoxedStream) -> TransportFuture<'_> {
+        Box::pin(async move { Ok(stream) })
+    }
+}
+
+/// Opens connections for transports to run over
+pub trait Dialer: Debug + Send + Sync {
+    /// Connect to an address
+    ///
+    /// # Arguments
+    /// * `address` - Server address
+    ///
+    /// # Returns
+    /// * Connected byte stream
+    fn dial(&self, address: SocketAddr) -> TransportFuture<'_>;
+}
+
+/// Direct TCP connections
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpDialer;
+
+impl Dialer for TcpDialer {
+    fn dial(&self, address: SocketAddr) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(address)
+                .await
+                .map_err(|e| ScramblerError::NetworkError(format!("Connection failed: {}", e)))?;
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+}
+
+/// Buffer size of the duplex pipe between caller and pump
+pub(crate) const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Future returned by message sinks and sources
+pub(crate) type MessageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Sending side of a message-oriented transport
+pub(crate) trait MessageSink: Send + 'static {
+    /// Send one message
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> MessageFuture<'a, ()>;
+
+    /// Close the sending direction
+    fn close(&mut self) -> MessageFuture<'_, ()>;
+}
+
+/// Receiving side of a message-oriented transport
+pub(crate) trait MessageSource: Send + 'static {
+    /// Receive one message, or `None` once the peer closed the connection
+    fn recv(&mut self) -> MessageFuture<'_, Option<Vec<u8>>>;
+}
+
+/// Expose a message-oriented transport as a byte stream
+///
+/// # Arguments
+/// * `source` - Messages from the peer
+/// * `sink` - Messages to the peer
+/// * `max_message` - Largest chunk of caller bytes sent as one message
+pub(crate) fn spawn_pump<R, W>(mut source: R, mut sink: W, max_message: usize) -> BoxedStream
+where
+    R: MessageSource,
+    W: MessageSink,
+{
+    let (caller, pump) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+    let (mut pump_read, mut pump_write) = tokio::io::split(pump);
+
+    tokio::spawn(async move {
+        // Caller -> peer; the caller closing its end closes our sending side
+        let upstream = async {
+            let mut buffer = vec![0u8; max_message];
+            loop {
+                let n = pump_read
+                    .read(&mut buffer)
+                    .await
+                    .map_err(|e| ScramblerError::NetworkError(e.to_string()))?;
+                if n == 0 {
+                    return sink.close().await;
+                }
+                sink.send(&buffer[..n]).await?;
+            }
+        };
+
+        // Peer -> caller; the peer closing its side gives the caller EOF
+        let downstream = async {
+            while let Some(message) = source.recv().await? {
+                pump_write
+                    .write_all(&message)
+                    .await
+                    .map_err(|e| ScramblerError::NetworkError(e.to_string()))?;
+            }
+            pump_write
+                .shutdown()
+                .await
+                .map_err(|e| ScramblerError::NetworkError(e.to_string()))
+        };
+
+        let (sent, received) = tokio::join!(upstream, downstream);
+        if let Err(e) = sent.and(received) {
+            tracing::debug!(error = %e, "Transport connection closed with error");
+        }
+    });
+
+    Box::new(caller)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camouflage::{CamouflageLayer, CdnProvider, DomainFrontingConfig};
+    use crate::obfs4::{IatMode, Obfs4Bridge};
+    use crate::utls::UTlsServer;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// Echo server behind `transport`, returning its address
+    async fn spawn_echo<T>(transport: Arc<T>) -> SocketAddr
+    where
+        T: PluggableTransport + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let transport = transport.clone();
+                tokio::spawn(async move {
+                    let stream = transport.accept(Box::new(tcp)).await.unwrap();
+                    let (mut read, mut write) = tokio::io::split(stream);
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                });
+            }
+        });
+
+        address
+    }
+
+    /// Send data through `transport` to an echo server and read it back
+    async fn echo_through<T>(transport: Arc<T>)
+    where
+        T: PluggableTransport + 'static,
+    {
+        let address = spawn_echo(transport.clone()).await;
+        let tcp = TcpStream::connect(address).await.unwrap();
+        let mut stream = transport.connect(Box::new(tcp)).await.unwrap();
+
+        let data: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        stream.write_all(&data).await.unwrap();
+
+        let mut echoed = vec![0u8; data.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, data);
+    }
+
+    #[tokio::test]
+    async fn test_plain_transport() {
+        echo_through(Arc::new(PlainTransport)).await;
+    }
+
+    #[tokio::test]
+    async fn test_obfs4_transport() {
+        let bridge = Arc::new(Obfs4Bridge::generate(IatMode::None));
+        echo_through(Arc::new(CamouflageLayer::obfs4_bridge(bridge))).await;
+    }
+
+    #[tokio::test]
+    async fn test_obfs4_accept_requires_bridge() {
+        let bridge = Obfs4Bridge::generate(IatMode::None);
+        let layer = CamouflageLayer::obfs4(bridge.client_config());
+
+        let (_, server) = tokio::io::duplex(64);
+        let result = layer.accept(Box::new(server)).await;
+        assert!(matches!(result, Err(ScramblerError::ConfigError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_utls_transport() {
+        let server = Arc::new(UTlsServer::generate("cdn.example.com").unwrap());
+        echo_through(Arc::new(CamouflageLayer::utls_server(server))).await;
+    }
+
+    #[tokio::test]
+    async fn test_domain_fronting_transport() {
+        let config = DomainFrontingConfig {
+            front_domain: "cdn.cloudflare.com".to_string(),
+            front_key: vec![],
+            actual_destination: "invisible.example.com".to_string(),
+            provider: CdnProvider::Cloudflare,
+        };
+        let front = Arc::new(UTlsServer::generate("cdn.cloudflare.com").unwrap());
+        echo_through(Arc::new(CamouflageLayer::domain_fronting_server(config, front))).await;
+    }
+}
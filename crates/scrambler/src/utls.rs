@@ -0,0 +1,1371 @@
+//! uTLS Client
+//!
+//! A TLS 1.3 client whose ClientHello is byte-for-byte what a browser sends,
+//! so the connection carries that browser's JA3/JA4 fingerprint. The relay
+//! side is an ordinary TLS 1.3 server.
+//!
+//! ## Architecture
+//!
+//! - **ClientHello Builder:** Emits cipher suites, extensions, groups, and
+//!   signature algorithms exactly as listed in a `UTlsFingerprint`, including
+//!   GREASE values, padding, and Chrome's extension permutation
+//! - **Handshake:** Minimal TLS 1.3 client for the suites browsers put first
+//!   (`TLS_AES_128_GCM_SHA256`, `TLS_CHACHA20_POLY1305_SHA256`) with X25519 or
+//!   P-256 key exchange
+//! - **UTlsServer:** rustls server with a self-signed ECDSA P-256 certificate
+//! - **Fingerprints:** `ja3` and `ja4` compute the fingerprints of a captured
+//!   ClientHello
+//!
+//! ## Security Properties
+//!
+//! - **Fingerprint Fidelity:** Everything a passive observer fingerprints
+//!   matches the target browser; offered-but-unsupported options (TLS 1.2
+//!   suites, ECH GREASE) are never selected by the relay
+//! - **Key Pinning:** Instead of a CA chain the client pins the relay's P-256
+//!   key, which must appear in the certificate and sign the transcript
+//! - **Real Record Layer:** Application data is AEAD-protected TLS 1.3 records
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, ECDH_P256};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::camouflage::{UTlsConfig, UTlsFingerprint};
+use crate::error::{Result, ScramblerError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Record content types
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const ALERT: u8 = 21;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+/// Handshake message types
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const ENCRYPTED_EXTENSIONS: u8 = 8;
+const CERTIFICATE: u8 = 11;
+const CERTIFICATE_REQUEST: u8 = 13;
+const CERTIFICATE_VERIFY: u8 = 15;
+const FINISHED: u8 = 20;
+
+/// Largest plaintext fragment in one record
+const MAX_FRAGMENT_LENGTH: usize = 16384;
+
+/// Largest encrypted record body (fragment, content type, padding, tag)
+const MAX_RECORD_LENGTH: usize = MAX_FRAGMENT_LENGTH + 256;
+
+/// ClientHellos shorter than this are padded up to it
+const PADDED_HELLO_LENGTH: usize = 512;
+
+/// Sizes BoringSSL picks among for the GREASE ECH payload
+const ECH_PAYLOAD_LENGTHS: [usize; 4] = [144, 176, 208, 240];
+
+/// Time a client gets to finish its handshake with the relay
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Random value of a HelloRetryRequest (RFC 8446, section 4.1.3)
+const HELLO_RETRY_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// Extension code points
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_PADDING: u16 = 21;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_KEY_SHARE: u16 = 51;
+
+/// Named groups the handshake can complete with
+const GROUP_X25519: u16 = 29;
+const GROUP_SECP256R1: u16 = 23;
+
+const TLS13: u16 = 0x0304;
+const ECDSA_SECP256R1_SHA256: u16 = 0x0403;
+
+fn extension_id(name: &str) -> Result<u16> {
+    Ok(match name {
+        "server_name" => EXT_SERVER_NAME,
+        "status_request" => 5,
+        "supported_groups" => EXT_SUPPORTED_GROUPS,
+        "ec_point_formats" => EXT_EC_POINT_FORMATS,
+        "signature_algorithms" => EXT_SIGNATURE_ALGORITHMS,
+        "application_layer_protocol_negotiation" => EXT_ALPN,
+        "signed_certificate_timestamp" => 18,
+        "padding" => EXT_PADDING,
+        "extended_master_secret" => 23,
+        "compress_certificate" => 27,
+        "record_size_limit" => 28,
+        "delegated_credentials" => 34,
+        "session_ticket" => 35,
+        "supported_versions" => EXT_SUPPORTED_VERSIONS,
+        "psk_key_exchange_modes" => 45,
+        "key_share" => EXT_KEY_SHARE,
+        "application_settings" => 17513,
+        "encrypted_client_hello" => 65037,
+        "renegotiation_info" => 65281,
+        _ => return Err(unknown("extension", name)),
+    })
+}
+
+fn cipher_suite_id(name: &str) -> Result<u16> {
+    Ok(match name {
+        "TLS_AES_128_GCM_SHA256" => 0x1301,
+        "TLS_AES_256_GCM_SHA384" => 0x1302,
+        "TLS_CHACHA20_POLY1305_SHA256" => 0x1303,
+        "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256" => 0xc02b,
+        "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256" => 0xc02f,
+        "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384" => 0xc02c,
+        "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384" => 0xc030,
+        "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256" => 0xcca9,
+        "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256" => 0xcca8,
+        "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA" => 0xc009,
+        "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA" => 0xc00a,
+        "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA" => 0xc013,
+        "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA" => 0xc014,
+        "TLS_ECDHE_ECDSA_WITH_3DES_EDE_CBC_SHA" => 0xc008,
+        "TLS_ECDHE_RSA_WITH_3DES_EDE_CBC_SHA" => 0xc012,
+        "TLS_RSA_WITH_AES_128_GCM_SHA256" => 0x009c,
+        "TLS_RSA_WITH_AES_256_GCM_SHA384" => 0x009d,
+        "TLS_RSA_WITH_AES_128_CBC_SHA" => 0x002f,
+        "TLS_RSA_WITH_AES_256_CBC_SHA" => 0x0035,
+        "TLS_RSA_WITH_3DES_EDE_CBC_SHA" => 0x000a,
+        _ => return Err(unknown("cipher suite", name)),
+    })
+}
+
+fn group_id(name: &str) -> Result<u16> {
+    Ok(match name {
+        "x25519" => GROUP_X25519,
+        "secp256r1" => GROUP_SECP256R1,
+        "secp384r1" => 24,
+        "secp521r1" => 25,
+        "ffdhe2048" => 256,
+        "ffdhe3072" => 257,
+        _ => return Err(unknown("group", name)),
+    })
+}
+
+fn signature_algorithm_id(name: &str) -> Result<u16> {
+    Ok(match name {
+        "ecdsa_secp256r1_sha256" => ECDSA_SECP256R1_SHA256,
+        "ecdsa_secp384r1_sha384" => 0x0503,
+        "ecdsa_secp521r1_sha512" => 0x0603,
+        "ecdsa_sha1" => 0x0203,
+        "rsa_pss_rsae_sha256" => 0x0804,
+        "rsa_pss_rsae_sha384" => 0x0805,
+        "rsa_pss_rsae_sha512" => 0x0806,
+        "rsa_pkcs1_sha256" => 0x0401,
+        "rsa_pkcs1_sha384" => 0x0501,
+        "rsa_pkcs1_sha512" => 0x0601,
+        "rsa_pkcs1_sha1" => 0x0201,
+        _ => return Err(unknown("signature algorithm", name)),
+    })
+}
+
+fn version_id(name: &str) -> Result<u16> {
+    Ok(match name {
+        "1.3" => TLS13,
+        "1.2" => 0x0303,
+        "1.1" => 0x0302,
+        "1.0" => 0x0301,
+        _ => return Err(unknown("TLS version", name)),
+    })
+}
+
+fn cert_compression_id(name: &str) -> Result<u16> {
+    Ok(match name {
+        "zlib" => 1,
+        "brotli" => 2,
+        "zstd" => 3,
+        _ => return Err(unknown("certificate compression algorithm", name)),
+    })
+}
+
+fn unknown(kind: &str, name: &str) -> ScramblerError {
+    ScramblerError::ConfigError(format!("Unknown uTLS {}: {}", kind, name))
+}
+
+/// Placeholder for a GREASE value in fingerprint lists
+const GREASE: &str = "GREASE";
+
+/// Check whether a code point is a GREASE value (RFC 8701)
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Per-connection GREASE values, drawn independently per list like BoringSSL
+struct Grease {
+    cipher: u16,
+    group: u16,
+    version: u16,
+    extensions: [u16; 2],
+}
+
+impl Grease {
+    fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut value = || 0x0a0a + 0x1010 * rng.gen_range(0..16u16);
+
+        let first = value();
+        let mut second = value();
+        // The two GREASE extensions must differ
+        if second == first {
+            second ^= 0x1010;
+        }
+
+        Self {
+            cipher: value(),
+            group: value(),
+            version: value(),
+            extensions: [first, second],
+        }
+    }
+}
+
+/// Map fingerprint names to code points, substituting GREASE
+fn code_points(names: &[String], grease: u16, lookup: fn(&str) -> Result<u16>) -> Result<Vec<u16>> {
+    names
+        .iter()
+        .map(|name| if name == GREASE { Ok(grease) } else { lookup(name) })
+        .collect()
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u24(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+}
+
+/// Append `data` with a length prefix of `width` bytes
+fn put_vec(out: &mut Vec<u8>, width: usize, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes()[4 - width..]);
+    out.extend_from_slice(data);
+}
+
+fn u16_list(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+/// Ephemeral secrets behind the key shares of a ClientHello
+struct KeyShares {
+    x25519: Option<StaticSecret>,
+    p256: Option<EphemeralPrivateKey>,
+}
+
+impl KeyShares {
+    /// Complete the key exchange with the server's share
+    fn agree(self, group: u16, server_share: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        match group {
+            GROUP_X25519 => {
+                let secret = self.x25519.ok_or_else(|| unexpected("key share group"))?;
+                let public: [u8; 32] =
+                    server_share.try_into().map_err(|_| unexpected("X25519 key share"))?;
+                let shared = secret.diffie_hellman(&PublicKey::from(public));
+                if !shared.was_contributory() {
+                    return Err(ScramblerError::CryptoError("Low-order X25519 key share".to_string()));
+                }
+                Ok(Zeroizing::new(shared.as_bytes().to_vec()))
+            }
+            GROUP_SECP256R1 => {
+                let secret = self.p256.ok_or_else(|| unexpected("key share group"))?;
+                let public = agreement::UnparsedPublicKey::new(&ECDH_P256, server_share);
+                agreement::agree_ephemeral(secret, &public, |shared| Zeroizing::new(shared.to_vec()))
+                    .map_err(|_| ScramblerError::CryptoError("Invalid P-256 key share".to_string()))
+            }
+            _ => Err(unexpected("key share group")),
+        }
+    }
+}
+
+/// Build a ClientHello handshake message for `fingerprint`
+///
+/// # Returns
+/// * Handshake message and the secrets behind its key shares
+fn build_client_hello(fingerprint: &UTlsFingerprint, server_name: &str) -> Result<(Vec<u8>, KeyShares)> {
+    let mut rng = rand::thread_rng();
+    let grease = Grease::generate();
+
+    let cipher_suites = code_points(&fingerprint.cipher_suites, grease.cipher, cipher_suite_id)?;
+    let groups = code_points(&fingerprint.supported_groups, grease.group, group_id)?;
+    let key_share_groups = code_points(&fingerprint.key_share_groups, grease.group, group_id)?;
+    let versions = code_points(&fingerprint.supported_versions, grease.version, version_id)?;
+    let signature_algorithms = fingerprint
+        .signature_algorithms
+        .iter()
+        .map(|name| signature_algorithm_id(name))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut shares = KeyShares { x25519: None, p256: None };
+    let mut key_shares = Vec::new();
+    for group in &key_share_groups {
+        put_u16(&mut key_shares, *group);
+        match *group {
+            GROUP_X25519 => {
+                let secret = StaticSecret::from(rng.gen::<[u8; 32]>());
+                put_vec(&mut key_shares, 2, PublicKey::from(&secret).as_bytes());
+                shares.x25519 = Some(secret);
+            }
+            GROUP_SECP256R1 => {
+                let secret = EphemeralPrivateKey::generate(&ECDH_P256, &ring::rand::SystemRandom::new())
+                    .map_err(|_| ScramblerError::CryptoError("RNG failed".to_string()))?;
+                let public = secret
+                    .compute_public_key()
+                    .map_err(|_| ScramblerError::CryptoError("P-256 key generation failed".to_string()))?;
+                put_vec(&mut key_shares, 2, public.as_ref());
+                shares.p256 = Some(secret);
+            }
+            // GREASE shares carry a single zero byte
+            group if is_grease(group) => put_vec(&mut key_shares, 2, &[0]),
+            _ => return Err(ScramblerError::ConfigError("uTLS key shares must use x25519 or secp256r1".to_string())),
+        }
+    }
+
+    let mut extensions: Vec<(u16, Vec<u8>)> = Vec::new();
+    let mut grease_extensions = grease.extensions.iter();
+    for name in &fingerprint.extensions {
+        if name == GREASE {
+            let id = *grease_extensions
+                .next()
+                .ok_or_else(|| ScramblerError::ConfigError("Too many GREASE extensions".to_string()))?;
+            // BoringSSL sends the first GREASE extension empty, the second with one byte
+            let body = if id == grease.extensions[0] { vec![] } else { vec![0] };
+            extensions.push((id, body));
+            continue;
+        }
+
+        let id = extension_id(name)?;
+        let mut body = Vec::new();
+        match name.as_str() {
+            "server_name" => {
+                let mut entry = vec![0];
+                put_vec(&mut entry, 2, server_name.as_bytes());
+                put_vec(&mut body, 2, &entry);
+            }
+            "status_request" => body.extend_from_slice(&[1, 0, 0, 0, 0]),
+            "supported_groups" => put_vec(&mut body, 2, &u16_list(&groups)),
+            "ec_point_formats" => put_vec(&mut body, 1, &[0]),
+            "signature_algorithms" => put_vec(&mut body, 2, &u16_list(&signature_algorithms)),
+            "application_layer_protocol_negotiation" => {
+                let mut protocols = Vec::new();
+                for protocol in &fingerprint.alpn {
+                    put_vec(&mut protocols, 1, protocol.as_bytes());
+                }
+                put_vec(&mut body, 2, &protocols);
+            }
+            "record_size_limit" => put_u16(&mut body, 0x4001),
+            "delegated_credentials" => put_vec(&mut body, 2, &u16_list(&[0x0403, 0x0503, 0x0603, 0x0203])),
+            "supported_versions" => put_vec(&mut body, 1, &u16_list(&versions)),
+            "psk_key_exchange_modes" => put_vec(&mut body, 1, &[1]),
+            "key_share" => put_vec(&mut body, 2, &key_shares),
+            "compress_certificate" => {
+                let algorithms = fingerprint
+                    .cert_compression
+                    .iter()
+                    .map(|name| cert_compression_id(name))
+                    .collect::<Result<Vec<_>>>()?;
+                put_vec(&mut body, 1, &u16_list(&algorithms));
+            }
+            "application_settings" => {
+                let mut protocols = Vec::new();
+                put_vec(&mut protocols, 1, b"h2");
+                put_vec(&mut body, 2, &protocols);
+            }
+            "renegotiation_info" => body.push(0),
+            "encrypted_client_hello" => {
+                // GREASE ECH: outer hello with HKDF-SHA256 / AES-128-GCM and random contents
+                body.extend_from_slice(&[0, 0x00, 0x01, 0x00, 0x01, rng.gen()]);
+                put_vec(&mut body, 2, &rng.gen::<[u8; 32]>());
+                let payload: Vec<u8> = (0..*ECH_PAYLOAD_LENGTHS.choose(&mut rng).expect("non-empty"))
+                    .map(|_| rng.gen())
+                    .collect();
+                put_vec(&mut body, 2, &payload);
+            }
+            // Empty extensions; padding is sized once the rest is known
+            _ => {}
+        }
+        extensions.push((id, body));
+    }
+
+    // Chrome shuffles everything but GREASE and padding
+    if fingerprint.permute_extensions {
+        let movable: Vec<usize> = (0..extensions.len())
+            .filter(|&i| !is_grease(extensions[i].0) && extensions[i].0 != EXT_PADDING)
+            .collect();
+        let mut shuffled: Vec<(u16, Vec<u8>)> = movable.iter().map(|&i| extensions[i].clone()).collect();
+        shuffled.shuffle(&mut rng);
+        for (i, extension) in movable.into_iter().zip(shuffled) {
+            extensions[i] = extension;
+        }
+    }
+
+    let mut body = Vec::new();
+    put_u16(&mut body, 0x0303);
+    body.extend_from_slice(&rng.gen::<[u8; 32]>());
+    // Browsers send a random session ID for middlebox compatibility
+    put_vec(&mut body, 1, &rng.gen::<[u8; 32]>());
+    put_vec(&mut body, 2, &u16_list(&cipher_suites));
+    put_vec(&mut body, 1, &[0]);
+
+    let extensions_length: usize = extensions.iter().map(|(_, data)| 4 + data.len()).sum();
+    let unpadded = 4 + body.len() + 2 + extensions_length;
+    if let Some((_, padding)) = extensions.iter_mut().find(|(id, _)| *id == EXT_PADDING) {
+        padding.resize(PADDED_HELLO_LENGTH.saturating_sub(unpadded).max(1), 0);
+    }
+
+    let mut encoded = Vec::new();
+    for (id, data) in &extensions {
+        put_u16(&mut encoded, *id);
+        put_vec(&mut encoded, 2, data);
+    }
+    put_vec(&mut body, 2, &encoded);
+
+    let mut message = vec![CLIENT_HELLO];
+    put_u24(&mut message, body.len());
+    message.extend_from_slice(&body);
+
+    Ok((message, shares))
+}
+
+/// Cursor over a TLS structure
+struct Parser<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Parser<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(ScramblerError::CryptoError("Truncated TLS message".to_string()));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Read a vector with a length prefix of `width` bytes
+    fn vec(&mut self, width: usize) -> Result<&'a [u8]> {
+        let len = self.bytes(width)?.iter().fold(0usize, |len, byte| len << 8 | usize::from(*byte));
+        self.bytes(len)
+    }
+
+    fn u16_list(&mut self, width: usize) -> Result<Vec<u16>> {
+        let mut list = Parser::new(self.vec(width)?);
+        let mut values = Vec::new();
+        while !list.is_empty() {
+            values.push(list.u16()?);
+        }
+        Ok(values)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Fields of a ClientHello that fingerprints are computed from
+struct ClientHelloInfo {
+    legacy_version: u16,
+    cipher_suites: Vec<u16>,
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    versions: Vec<u16>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl ClientHelloInfo {
+    fn parse(message: &[u8]) -> Result<Self> {
+        let mut message = Parser::new(message);
+        if message.u8()? != CLIENT_HELLO {
+            return Err(unexpected("handshake message"));
+        }
+        let mut hello = Parser::new(message.vec(3)?);
+
+        let legacy_version = hello.u16()?;
+        hello.bytes(32)?;
+        hello.vec(1)?;
+        let cipher_suites = hello.u16_list(2)?;
+        hello.vec(1)?;
+
+        let mut info = Self {
+            legacy_version,
+            cipher_suites,
+            extensions: Vec::new(),
+            groups: Vec::new(),
+            point_formats: Vec::new(),
+            signature_algorithms: Vec::new(),
+            versions: Vec::new(),
+            alpn: Vec::new(),
+        };
+
+        let mut extensions = Parser::new(hello.vec(2)?);
+        while !extensions.is_empty() {
+            let id = extensions.u16()?;
+            let mut body = Parser::new(extensions.vec(2)?);
+            info.extensions.push(id);
+
+            match id {
+                EXT_SUPPORTED_GROUPS => info.groups = body.u16_list(2)?,
+                EXT_EC_POINT_FORMATS => info.point_formats = body.vec(1)?.to_vec(),
+                EXT_SIGNATURE_ALGORITHMS => info.signature_algorithms = body.u16_list(2)?,
+                EXT_SUPPORTED_VERSIONS => info.versions = body.u16_list(1)?,
+                EXT_ALPN => {
+                    let mut protocols = Parser::new(body.vec(2)?);
+                    while !protocols.is_empty() {
+                        info.alpn.push(protocols.vec(1)?.to_vec());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+fn join<T: ToString>(values: impl Iterator<Item = T>, separator: &str) -> String {
+    values.map(|value| value.to_string()).collect::<Vec<_>>().join(separator)
+}
+
+/// JA3 fingerprint string of a ClientHello
+///
+/// The JA3 hash is the MD5 digest of this string.
+///
+/// # Arguments
+/// * `client_hello` - ClientHello handshake message
+pub fn ja3(client_hello: &[u8]) -> Result<String> {
+    let info = ClientHelloInfo::parse(client_hello)?;
+    let without_grease = |values: &[u16]| join(values.iter().filter(|v| !is_grease(**v)), "-");
+
+    Ok(format!(
+        "{},{},{},{},{}",
+        info.legacy_version,
+        without_grease(&info.cipher_suites),
+        without_grease(&info.extensions),
+        without_grease(&info.groups),
+        join(info.point_formats.iter(), "-"),
+    ))
+}
+
+/// JA4 fingerprint of a ClientHello sent over TCP
+///
+/// # Arguments
+/// * `client_hello` - ClientHello handshake message
+pub fn ja4(client_hello: &[u8]) -> Result<String> {
+    let info = ClientHelloInfo::parse(client_hello)?;
+
+    let version = info
+        .versions
+        .iter()
+        .copied()
+        .filter(|v| !is_grease(*v))
+        .max()
+        .unwrap_or(info.legacy_version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        _ => "00",
+    };
+
+    let mut cipher_suites: Vec<u16> = info.cipher_suites.into_iter().filter(|v| !is_grease(*v)).collect();
+    let extensions: Vec<u16> = info.extensions.into_iter().filter(|v| !is_grease(*v)).collect();
+    let alpn = match info.alpn.first() {
+        Some(protocol) if !protocol.is_empty() => {
+            format!("{}{}", protocol[0] as char, protocol[protocol.len() - 1] as char)
+        }
+        _ => "00".to_string(),
+    };
+
+    let prefix = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        if extensions.contains(&EXT_SERVER_NAME) { "d" } else { "i" },
+        cipher_suites.len().min(99),
+        extensions.len().min(99),
+        alpn,
+    );
+
+    let truncated_hash = |input: String| -> String {
+        if input.is_empty() {
+            "000000000000".to_string()
+        } else {
+            hex::encode(Sha256::digest(input.as_bytes()))[..12].to_string()
+        }
+    };
+    let hex_list = |values: &[u16]| join(values.iter().map(|v| format!("{:04x}", v)), ",");
+
+    cipher_suites.sort_unstable();
+    let mut sorted_extensions: Vec<u16> = extensions
+        .into_iter()
+        .filter(|id| *id != EXT_SERVER_NAME && *id != EXT_ALPN)
+        .collect();
+    sorted_extensions.sort_unstable();
+
+    let mut extension_input = hex_list(&sorted_extensions);
+    if !info.signature_algorithms.is_empty() {
+        extension_input = format!("{}_{}", extension_input, hex_list(&info.signature_algorithms));
+    }
+
+    Ok(format!(
+        "{}_{}_{}",
+        prefix,
+        truncated_hash(hex_list(&cipher_suites)),
+        truncated_hash(extension_input),
+    ))
+}
+
+/// TLS 1.3 cipher suites the client can complete a handshake with
+#[derive(Debug, Clone, Copy)]
+enum CipherSuite {
+    Aes128GcmSha256,
+    ChaCha20Poly1305Sha256,
+}
+
+impl CipherSuite {
+    fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0x1301 => Some(Self::Aes128GcmSha256),
+            0x1303 => Some(Self::ChaCha20Poly1305Sha256),
+            _ => None,
+        }
+    }
+
+    fn algorithm(self) -> &'static ring::aead::Algorithm {
+        match self {
+            Self::Aes128GcmSha256 => &AES_128_GCM,
+            Self::ChaCha20Poly1305Sha256 => &CHACHA20_POLY1305,
+        }
+    }
+}
+
+fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Zeroizing<Vec<u8>> {
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), ikm);
+    Zeroizing::new(prk.to_vec())
+}
+
+/// HKDF-Expand-Label (RFC 8446, section 7.1)
+fn expand_label(secret: &[u8], label: &str, context: &[u8], len: usize) -> Result<Zeroizing<Vec<u8>>> {
+    let mut info = Vec::new();
+    put_u16(&mut info, len as u16);
+    put_vec(&mut info, 1, format!("tls13 {}", label).as_bytes());
+    put_vec(&mut info, 1, context);
+
+    let hkdf = Hkdf::<Sha256>::from_prk(secret)
+        .map_err(|_| ScramblerError::CryptoError("Invalid TLS secret".to_string()))?;
+    let mut output = Zeroizing::new(vec![0u8; len]);
+    hkdf.expand(&info, &mut output)
+        .map_err(|_| ScramblerError::CryptoError("TLS key derivation failed".to_string()))?;
+
+    Ok(output)
+}
+
+/// Finished verify_data for `transcript_hash` under a handshake traffic secret
+fn finished_mac(traffic_secret: &[u8], transcript_hash: &[u8]) -> Result<Vec<u8>> {
+    let key = expand_label(traffic_secret, "finished", &[], 32)?;
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&key)
+        .map_err(|_| ScramblerError::CryptoError("Invalid HMAC key".to_string()))?;
+    mac.update(transcript_hash);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// AEAD protection of one direction of the record layer
+struct RecordCipher {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    sequence: u64,
+}
+
+impl RecordCipher {
+    fn new(suite: CipherSuite, traffic_secret: &[u8]) -> Result<Self> {
+        let algorithm = suite.algorithm();
+        let key = expand_label(traffic_secret, "key", &[], algorithm.key_len())?;
+        let iv = expand_label(traffic_secret, "iv", &[], 12)?;
+
+        Ok(Self {
+            key: LessSafeKey::new(
+                UnboundKey::new(algorithm, &key)
+                    .map_err(|_| ScramblerError::CryptoError("Invalid TLS key".to_string()))?,
+            ),
+            iv: iv.as_slice().try_into().expect("12-byte IV"),
+            sequence: 0,
+        })
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = self.iv;
+        for (byte, seq) in nonce[4..].iter_mut().zip(self.sequence.to_be_bytes()) {
+            *byte ^= seq;
+        }
+        self.sequence += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Encrypt `data` as a complete record of `content_type`
+    fn seal(&mut self, content_type: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut inner = data.to_vec();
+        inner.push(content_type);
+
+        let mut header = [APPLICATION_DATA, 0x03, 0x03, 0, 0];
+        header[3..].copy_from_slice(&((inner.len() + self.key.algorithm().tag_len()) as u16).to_be_bytes());
+
+        let nonce = self.next_nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(header), &mut inner)
+            .map_err(|_| ScramblerError::CryptoError("TLS record encryption failed".to_string()))?;
+
+        let mut record = header.to_vec();
+        record.extend_from_slice(&inner);
+        Ok(record)
+    }
+
+    /// Decrypt a record body
+    ///
+    /// # Returns
+    /// * Inner content type and plaintext
+    fn open(&mut self, header: [u8; 5], mut body: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+        let nonce = self.next_nonce();
+        let length = self
+            .key
+            .open_in_place(nonce, Aad::from(header), &mut body)
+            .map_err(|_| ScramblerError::CryptoError("TLS record authentication failed".to_string()))?
+            .len();
+        body.truncate(length);
+
+        // Inner plaintext: [data][content_type][zero padding]
+        while body.last() == Some(&0) {
+            body.pop();
+        }
+        let content_type = body
+            .pop()
+            .ok_or_else(|| ScramblerError::CryptoError("TLS record without content type".to_string()))?;
+
+        Ok((content_type, body))
+    }
+}
+
+/// Read one record
+///
+/// # Returns
+/// * Header and body, or `None` if the connection closed between records
+async fn read_record<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<([u8; 5], Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    if reader.read(&mut header[..1]).await.map_err(io_error)? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).await.map_err(io_error)?;
+
+    let length = usize::from(u16::from_be_bytes([header[3], header[4]]));
+    if length > MAX_RECORD_LENGTH {
+        return Err(ScramblerError::CryptoError(format!("TLS record too long: {}", length)));
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await.map_err(io_error)?;
+
+    Ok(Some((header, body)))
+}
+
+/// Handshake messages reassembled from records
+#[derive(Default)]
+struct HandshakeBuffer {
+    pending: Vec<u8>,
+}
+
+impl HandshakeBuffer {
+    /// Next complete handshake message, including its header
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        if self.pending.len() < 4 {
+            return None;
+        }
+        let length = usize::from(self.pending[1]) << 16
+            | usize::from(self.pending[2]) << 8
+            | usize::from(self.pending[3]);
+        if self.pending.len() < 4 + length {
+            return None;
+        }
+        Some(self.pending.drain(..4 + length).collect())
+    }
+}
+
+/// Client half of a TLS 1.3 handshake in progress
+struct ClientHandshake<'a, S> {
+    stream: &'a mut S,
+    buffer: HandshakeBuffer,
+    transcript: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ClientHandshake<'_, S> {
+    fn transcript_hash(&self) -> Vec<u8> {
+        Sha256::digest(&self.transcript).to_vec()
+    }
+
+    /// Next handshake message, read from plaintext or `cipher`-protected records
+    async fn next_message(&mut self, mut cipher: Option<&mut RecordCipher>) -> Result<Vec<u8>> {
+        loop {
+            if let Some(message) = self.buffer.next_message() {
+                self.transcript.extend_from_slice(&message);
+                return Ok(message);
+            }
+
+            let (header, body) = read_record(self.stream)
+                .await?
+                .ok_or_else(|| ScramblerError::NetworkError("TLS server closed the connection".to_string()))?;
+
+            let (content_type, data) = match (header[0], cipher.as_deref_mut()) {
+                (CHANGE_CIPHER_SPEC, _) => continue,
+                (APPLICATION_DATA, Some(cipher)) => cipher.open(header, body)?,
+                (ALERT, _) | (_, None) => (header[0], body),
+                _ => return Err(unexpected("record type")),
+            };
+
+            match content_type {
+                HANDSHAKE => self.buffer.pending.extend_from_slice(&data),
+                ALERT => {
+                    return Err(ScramblerError::NetworkError(format!(
+                        "TLS alert {} from server",
+                        data.get(1).copied().unwrap_or_default()
+                    )))
+                }
+                _ => return Err(unexpected("record type")),
+            }
+        }
+    }
+}
+
+/// Connect to a uTLS relay over `stream`
+///
+/// # Arguments
+/// * `stream` - Connection to the relay
+/// * `config` - Browser fingerprint, server name, and pinned relay key
+///
+/// # Returns
+/// * Encrypted stream once the relay has proven possession of its key
+pub async fn connect<S>(mut stream: S, config: &UTlsConfig) -> Result<UTlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (client_hello, shares) = build_client_hello(&config.fingerprint, &config.server_name)?;
+
+    // The first record uses the TLS 1.0 legacy version, as browsers do
+    let mut record = vec![HANDSHAKE, 0x03, 0x01];
+    put_vec(&mut record, 2, &client_hello);
+    stream.write_all(&record).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    let mut handshake = ClientHandshake {
+        stream: &mut stream,
+        buffer: HandshakeBuffer::default(),
+        transcript: client_hello,
+    };
+
+    let server_hello = handshake.next_message(None).await?;
+    let (suite, group, server_share) = parse_server_hello(&server_hello)?;
+    let shared_secret = shares.agree(group, &server_share)?;
+
+    // Key schedule (RFC 8446, section 7.1), without PSKs
+    let empty_hash = Sha256::digest([]).to_vec();
+    let early_secret = hkdf_extract(&[0u8; 32], &[0u8; 32]);
+    let derived = expand_label(&early_secret, "derived", &empty_hash, 32)?;
+    let handshake_secret = hkdf_extract(&derived, &shared_secret);
+
+    let hello_hash = handshake.transcript_hash();
+    let client_handshake_secret = expand_label(&handshake_secret, "c hs traffic", &hello_hash, 32)?;
+    let server_handshake_secret = expand_label(&handshake_secret, "s hs traffic", &hello_hash, 32)?;
+    let mut server_cipher = RecordCipher::new(suite, &server_handshake_secret)?;
+
+    let message = handshake.next_message(Some(&mut server_cipher)).await?;
+    if message[0] != ENCRYPTED_EXTENSIONS {
+        return Err(unexpected("handshake message"));
+    }
+
+    let message = handshake.next_message(Some(&mut server_cipher)).await?;
+    match message[0] {
+        CERTIFICATE => check_certificate(&message, &config.server_key)?,
+        CERTIFICATE_REQUEST => {
+            return Err(ScramblerError::CryptoError("TLS server requested a client certificate".to_string()))
+        }
+        _ => return Err(unexpected("handshake message")),
+    }
+
+    let certificate_hash = handshake.transcript_hash();
+    let message = handshake.next_message(Some(&mut server_cipher)).await?;
+    check_certificate_verify(&message, &config.server_key, &certificate_hash)?;
+
+    let verify_hash = handshake.transcript_hash();
+    let message = handshake.next_message(Some(&mut server_cipher)).await?;
+    let expected = finished_mac(&server_handshake_secret, &verify_hash)?;
+    if message[0] != FINISHED || !bool::from(message[4..].ct_eq(&expected)) {
+        return Err(ScramblerError::CryptoError("TLS server Finished is invalid".to_string()));
+    }
+
+    let finished_hash = handshake.transcript_hash();
+    let derived = expand_label(&handshake_secret, "derived", &empty_hash, 32)?;
+    let master_secret = hkdf_extract(&derived, &[0u8; 32]);
+    let client_traffic_secret = expand_label(&master_secret, "c ap traffic", &finished_hash, 32)?;
+    let server_traffic_secret = expand_label(&master_secret, "s ap traffic", &finished_hash, 32)?;
+
+    // Compatibility ChangeCipherSpec, then our Finished
+    let mut finished = vec![FINISHED];
+    put_vec(&mut finished, 3, &finished_mac(&client_handshake_secret, &finished_hash)?);
+    let mut flight = vec![CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01];
+    flight.extend(RecordCipher::new(suite, &client_handshake_secret)?.seal(HANDSHAKE, &finished)?);
+    stream.write_all(&flight).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)?;
+
+    tracing::debug!(
+        browser = %config.fingerprint.browser,
+        suite = ?suite,
+        group,
+        "uTLS handshake completed"
+    );
+
+    let (read_half, write_half) = tokio::io::split(stream);
+    Ok(UTlsStream {
+        reader: UTlsReader {
+            inner: read_half,
+            cipher: RecordCipher::new(suite, &server_traffic_secret)?,
+        },
+        writer: UTlsWriter {
+            inner: write_half,
+            cipher: RecordCipher::new(suite, &client_traffic_secret)?,
+        },
+    })
+}
+
+/// Parse a ServerHello
+///
+/// # Returns
+/// * Selected cipher suite, key exchange group, and server key share
+fn parse_server_hello(message: &[u8]) -> Result<(CipherSuite, u16, Vec<u8>)> {
+    let mut message = Parser::new(message);
+    if message.u8()? != SERVER_HELLO {
+        return Err(unexpected("handshake message"));
+    }
+    let mut hello = Parser::new(message.vec(3)?);
+
+    hello.u16()?;
+    if hello.bytes(32)? == HELLO_RETRY_RANDOM {
+        return Err(ScramblerError::CryptoError(
+            "TLS server sent a HelloRetryRequest".to_string(),
+        ));
+    }
+    hello.vec(1)?;
+    let suite = CipherSuite::from_id(hello.u16()?).ok_or_else(|| unexpected("cipher suite"))?;
+    hello.u8()?;
+
+    let mut version = None;
+    let mut key_share = None;
+    let mut extensions = Parser::new(hello.vec(2)?);
+    while !extensions.is_empty() {
+        let id = extensions.u16()?;
+        let mut body = Parser::new(extensions.vec(2)?);
+        match id {
+            EXT_SUPPORTED_VERSIONS => version = Some(body.u16()?),
+            EXT_KEY_SHARE => key_share = Some((body.u16()?, body.vec(2)?.to_vec())),
+            _ => {}
+        }
+    }
+
+    if version != Some(TLS13) {
+        return Err(ScramblerError::CryptoError("TLS server did not select TLS 1.3".to_string()));
+    }
+    let (group, share) = key_share.ok_or_else(|| unexpected("ServerHello without key share"))?;
+
+    Ok((suite, group, share))
+}
+
+/// Check that the end-entity certificate carries the pinned key
+fn check_certificate(message: &[u8], server_key: &[u8]) -> Result<()> {
+    let mut message = Parser::new(&message[4..]);
+    message.vec(1)?;
+    let mut entries = Parser::new(message.vec(3)?);
+    let certificate = entries.vec(3)?;
+
+    if server_key.is_empty() || !certificate.windows(server_key.len()).any(|window| window == server_key) {
+        return Err(ScramblerError::CryptoError(
+            "TLS certificate does not carry the pinned key".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check the server's signature over the transcript
+fn check_certificate_verify(message: &[u8], server_key: &[u8], transcript_hash: &[u8]) -> Result<()> {
+    if message[0] != CERTIFICATE_VERIFY {
+        return Err(unexpected("handshake message"));
+    }
+    let mut body = Parser::new(&message[4..]);
+    if body.u16()? != ECDSA_SECP256R1_SHA256 {
+        return Err(unexpected("signature scheme"));
+    }
+    let signature = body.vec(2)?;
+
+    let mut signed = vec![0x20u8; 64];
+    signed.extend_from_slice(b"TLS 1.3, server CertificateVerify\0");
+    signed.extend_from_slice(transcript_hash);
+
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, server_key)
+        .verify(&signed, signature)
+        .map_err(|_| ScramblerError::CryptoError("TLS CertificateVerify signature is invalid".to_string()))
+}
+
+/// Reading half of a uTLS stream
+pub struct UTlsReader<R> {
+    inner: R,
+    cipher: RecordCipher,
+}
+
+impl<R> fmt::Debug for UTlsReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UTlsReader").field("records", &self.cipher.sequence).finish_non_exhaustive()
+    }
+}
+
+impl<R: AsyncRead + Unpin> UTlsReader<R> {
+    /// Receive the data of the next application data record
+    ///
+    /// # Returns
+    /// * `None` once the server closes the connection
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let Some((header, body)) = read_record(&mut self.inner).await? else {
+                return Ok(None);
+            };
+            if header[0] == CHANGE_CIPHER_SPEC {
+                continue;
+            }
+            if header[0] != APPLICATION_DATA {
+                return Err(unexpected("record type"));
+            }
+
+            match self.cipher.open(header, body)? {
+                (APPLICATION_DATA, data) if !data.is_empty() => return Ok(Some(data)),
+                // Session tickets are never used
+                (APPLICATION_DATA, _) | (HANDSHAKE, _) => continue,
+                (ALERT, alert) if alert.get(1) == Some(&0) => return Ok(None),
+                (ALERT, alert) => {
+                    return Err(ScramblerError::NetworkError(format!(
+                        "TLS alert {} from server",
+                        alert.get(1).copied().unwrap_or_default()
+                    )))
+                }
+                _ => return Err(unexpected("record type")),
+            }
+        }
+    }
+}
+
+/// Writing half of a uTLS stream
+pub struct UTlsWriter<W> {
+    inner: W,
+    cipher: RecordCipher,
+}
+
+impl<W> fmt::Debug for UTlsWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UTlsWriter").field("records", &self.cipher.sequence).finish_non_exhaustive()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> UTlsWriter<W> {
+    /// Send `data` as application data records
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let mut records = Vec::new();
+        for fragment in data.chunks(MAX_FRAGMENT_LENGTH) {
+            records.extend(self.cipher.seal(APPLICATION_DATA, fragment)?);
+        }
+
+        self.inner.write_all(&records).await.map_err(io_error)?;
+        self.inner.flush().await.map_err(io_error)
+    }
+
+    /// Send close_notify and close the write direction
+    pub async fn shutdown(&mut self) -> Result<()> {
+        let alert = self.cipher.seal(ALERT, &[1, 0])?;
+        self.inner.write_all(&alert).await.map_err(io_error)?;
+        self.inner.shutdown().await.map_err(io_error)
+    }
+}
+
+/// Established uTLS connection
+#[derive(Debug)]
+pub struct UTlsStream<S> {
+    reader: UTlsReader<ReadHalf<S>>,
+    writer: UTlsWriter<WriteHalf<S>>,
+}
+
+impl<S: AsyncRead + AsyncWrite> UTlsStream<S> {
+    /// Send `data` as application data records
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.send(data).await
+    }
+
+    /// Receive the data of the next application data record
+    ///
+    /// # Returns
+    /// * `None` once the server closes the connection
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        self.reader.recv().await
+    }
+
+    /// Split into halves that can be used concurrently
+    pub fn into_split(self) -> (UTlsReader<ReadHalf<S>>, UTlsWriter<WriteHalf<S>>) {
+        (self.reader, self.writer)
+    }
+}
+
+/// Relay side of uTLS: a TLS 1.3 server with a self-signed P-256 certificate
+pub struct UTlsServer {
+    server_name: String,
+    public_key: Vec<u8>,
+    acceptor: TlsAcceptor,
+}
+
+impl fmt::Debug for UTlsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UTlsServer")
+            .field("server_name", &self.server_name)
+            .field("public_key", &hex::encode(&self.public_key))
+            .finish_non_exhaustive()
+    }
+}
+
+impl UTlsServer {
+    /// Create a server with a fresh key and certificate
+    ///
+    /// # Arguments
+    /// * `server_name` - Name in the certificate, and the SNI clients send
+    pub fn generate(server_name: &str) -> Result<Self> {
+        let key_pair = rcgen::KeyPair::generate().map_err(certificate_error)?;
+        let certificate = rcgen::CertificateParams::new(vec![server_name.to_string()])
+            .and_then(|params| params.self_signed(&key_pair))
+            .map_err(certificate_error)?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_config_error)?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certificate.der().clone()],
+                PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into(),
+            )
+            .map_err(tls_config_error)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        config.send_tls13_tickets = 0;
+
+        Ok(Self {
+            server_name: server_name.to_string(),
+            public_key: key_pair.public_key_raw().to_vec(),
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// ECDSA P-256 public key clients pin
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Client configuration for reaching this server with `fingerprint`
+    pub fn client_config(&self, fingerprint: UTlsFingerprint) -> UTlsConfig {
+        UTlsConfig {
+            fingerprint,
+            server_name: self.server_name.clone(),
+            server_key: self.public_key.clone(),
+        }
+    }
+
+    /// Accept a TLS client on `stream`
+    ///
+    /// # Arguments
+    /// * `stream` - Connection from the client
+    pub async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream))
+            .await
+            .map_err(|_| ScramblerError::NetworkError("TLS handshake timed out".to_string()))?
+            .map_err(io_error)
+    }
+}
+
+fn certificate_error(error: rcgen::Error) -> ScramblerError {
+    ScramblerError::CryptoError(format!("Certificate generation failed: {}", error))
+}
+
+fn tls_config_error(error: rustls::Error) -> ScramblerError {
+    ScramblerError::ConfigError(format!("Invalid TLS server config: {}", error))
+}
+
+fn unexpected(what: &str) -> ScramblerError {
+    ScramblerError::CryptoError(format!("Unexpected TLS {}", what))
+}
+
+fn io_error(error: std::io::Error) -> ScramblerError {
+    ScramblerError::NetworkError(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use md5::Md5;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Capture the ClientHello a uTLS client sends over TCP
+    async fn capture_client_hello(fingerprint: UTlsFingerprint) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let config = UTlsConfig {
+            fingerprint,
+            server_name: "www.example.com".to_string(),
+            server_key: vec![4u8; 65],
+        };
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let _ = connect(stream, &config).await;
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (header, body) = read_record(&mut stream).await.unwrap().unwrap();
+        assert_eq!(&header[..3], &[HANDSHAKE, 0x03, 0x01]);
+
+        drop(stream);
+        client.await.unwrap();
+        body
+    }
+
+    #[tokio::test]
+    async fn test_chrome_fingerprint() {
+        let hello = capture_client_hello(UTlsFingerprint::chrome()).await;
+        assert_eq!(ja4(&hello).unwrap(), "t13d1516h2_8daaf6152771_02713d6af862");
+
+        // Extension order is permuted per connection, GREASE values are redrawn
+        let other = capture_client_hello(UTlsFingerprint::chrome()).await;
+        assert_eq!(ja4(&other).unwrap(), ja4(&hello).unwrap());
+        assert_ne!(ja3(&other).unwrap(), ja3(&hello).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_firefox_fingerprint() {
+        let hello = capture_client_hello(UTlsFingerprint::firefox()).await;
+
+        let ja3 = ja3(&hello).unwrap();
+        assert_eq!(
+            ja3,
+            "771,4865-4867-4866-49195-49199-52393-52392-49196-49200-49162-49161-49171-49172-156-157-47-53,\
+             0-23-65281-10-11-35-16-5-34-51-43-13-45-28-21,29-23-24-25-256-257,0"
+        );
+        assert_eq!(hex::encode(Md5::digest(ja3.as_bytes())), "579ccef312d18482fc42e2b822ca2430");
+        assert_eq!(ja4(&hello).unwrap(), "t13d1715h2_5b57614c22b0_3d5424432f57");
+    }
+
+    #[tokio::test]
+    async fn test_safari_fingerprint() {
+        let hello = capture_client_hello(UTlsFingerprint::safari()).await;
+        assert_eq!(ja4(&hello).unwrap(), "t13d2014h2_a09f3c656075_14788d8d241b");
+    }
+
+    #[test]
+    fn test_grease_and_padding() {
+        let (hello, _) = build_client_hello(&UTlsFingerprint::safari(), "www.example.com").unwrap();
+        let info = ClientHelloInfo::parse(&hello).unwrap();
+
+        assert!(is_grease(info.cipher_suites[0]));
+        assert!(is_grease(info.extensions[0]));
+        assert!(is_grease(*info.extensions.last().unwrap()));
+        assert_ne!(info.extensions[0], *info.extensions.last().unwrap());
+        assert!(hello.len() >= PADDED_HELLO_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_tls_server() {
+        let server = Arc::new(UTlsServer::generate("cdn.example.com").unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let acceptor = server.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = acceptor.accept(tcp).await.unwrap();
+                    let (mut read, mut write) = tokio::io::split(stream);
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                });
+            }
+        });
+
+        for fingerprint in [UTlsFingerprint::chrome(), UTlsFingerprint::firefox(), UTlsFingerprint::safari()] {
+            let tcp = TcpStream::connect(address).await.unwrap();
+            let mut stream = connect(tcp, &server.client_config(fingerprint)).await.unwrap();
+
+            let data = vec![0x5au8; 40_000];
+            stream.send(&data).await.unwrap();
+
+            let mut echoed = Vec::new();
+            while echoed.len() < data.len() {
+                echoed.extend(stream.recv().await.unwrap().unwrap());
+            }
+            assert_eq!(echoed, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unpinned_server_rejected() {
+        let server = Arc::new(UTlsServer::generate("cdn.example.com").unwrap());
+        let impostor = UTlsServer::generate("cdn.example.com").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let _ = server.accept(tcp).await;
+        });
+
+        let tcp = TcpStream::connect(address).await.unwrap();
+        let result = connect(tcp, &impostor.client_config(UTlsFingerprint::chrome())).await;
+        assert!(matches!(result, Err(ScramblerError::CryptoError(_))));
+    }
+}
@@ -6,7 +6,16 @@
 //! ## Architecture
 //!
 //! - **Always On:** VPN cannot be disabled, all traffic routed through tunnel
-//! - **Random Endpoints:** Connects to random global WireGuard servers
+//! - **Userspace:** WireGuard (`crate::wireguard`) over a UDP socket, with
+//!   TCP inside the tunnel from `crate::netstack`; no TUN device or root
+//! - **VpnDialer:** Opens TCP connections through the current tunnel; the
+//!   network handlers use it once the VPN is up
+//! - **Endpoint Health:** Handshake round trips are probed periodically;
+//!   each endpoint keeps a reliability average, latency and failure history
+//! - **Failover:** Connections go to the best healthy endpoint, honoring
+//!   jurisdiction exclusions and preferences; failed endpoints cool down
+//! - **Make-Before-Break:** Session renewal rekeys in place or brings up the
+//!   new tunnel before retiring the old one, which drains its connections
 //! - **Automatic Reconnection:** Exponential backoff on failures
 //! - **Kill Switch:** Blocks all traffic if tunnel fails
 //!
@@ -18,17 +27,46 @@
 //! - **No DNS Leaks:** DNS queries tunneled through VPN
 
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use crate::error::{Result, ScramblerError};
+use crate::mixnet::Jurisdiction;
+use crate::netstack::NetStack;
+use crate::transport::{BoxedStream, Dialer, TransportFuture};
+use crate::wireguard::{PeerConfig, WireGuardDevice};
+
+/// How long to wait for a WireGuard handshake (two attempts)
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Weight of the newest observation in the health averages
+const SMOOTHING: f64 = 0.5;
+
+/// Round trip at which an endpoint's score is halved
+const LATENCY_SCALE_MS: f64 = 200.0;
+
+/// Failures remembered per endpoint
+const FAILURE_HISTORY: usize = 32;
+
+/// Cooldown after a failure, doubled for each consecutive one
+const BASE_COOLDOWN: Duration = Duration::from_secs(30);
+const MAX_COOLDOWN: Duration = Duration::from_secs(30 * 60);
+
+/// How long a replaced tunnel keeps carrying its existing connections
+const DRAIN_TIME: Duration = Duration::from_secs(60);
+
+fn default_probe_interval() -> u64 {
+    300
+}
 
 /// WireGuard configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VpnConfig {
-    /// Local private key
+    /// Local private key (32 bytes, X25519)
     pub private_key: Vec<u8>,
-    /// Local IP address inside tunnel
+    /// Local IPv4 address inside tunnel, optionally with a prefix length
     pub local_address: String,
     /// List of available VPN servers
     pub endpoints: Vec<VpnEndpoint>,
@@ -36,6 +74,15 @@ pub struct VpnConfig {
     pub keepalive_interval: u16,
     /// Maximum time before forcing reconnect (seconds)
     pub max_session_time: u64,
+    /// Time between endpoint latency probes (seconds, 0 disables probing)
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval: u64,
+    /// Jurisdictions never connected to
+    #[serde(default)]
+    pub exclude_jurisdictions: Vec<Jurisdiction>,
+    /// Jurisdictions preferred among healthy endpoints
+    #[serde(default)]
+    pub preferred_jurisdictions: Vec<Jurisdiction>,
 }
 
 /// VPN server endpoint
@@ -47,7 +94,10 @@ pub struct VpnEndpoint {
     pub address: SocketAddr,
     /// Geographic location for routing
     pub location: String,
-    /// Connection latency (ms) - updated on connect
+    /// Jurisdiction the server operates under, if known
+    #[serde(default)]
+    pub jurisdiction: Option<Jurisdiction>,
+    /// Handshake round trip (ms) - updated on connect and by probes
     pub latency_ms: Option<u32>,
 }
 
@@ -66,6 +116,127 @@ pub enum VpnState {
     Failed,
 }
 
+/// How an endpoint failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointFailure {
+    /// No handshake when connecting
+    Connect,
+    /// No handshake when probing
+    Probe,
+    /// The connected tunnel stopped answering
+    TunnelLost,
+}
+
+/// Handshake round trip, or how the handshake failed
+type HandshakeOutcome = std::result::Result<Duration, EndpointFailure>;
+
+/// Observed health of one endpoint
+#[derive(Debug, Clone, Default)]
+pub struct EndpointHealth {
+    /// Handshakes attempted (connects and probes)
+    pub attempts: u32,
+    /// Moving average of handshake outcomes (1.0 success, 0.0 failure)
+    pub reliability: f64,
+    /// Moving average of the handshake round trip (ms)
+    pub rtt_ms: Option<f64>,
+    /// Failures since the last successful handshake
+    pub consecutive_failures: u32,
+    /// Most recent failures, oldest first
+    pub failures: VecDeque<(Instant, EndpointFailure)>,
+    /// Time of the most recent handshake attempt
+    pub last_attempt: Option<Instant>,
+}
+
+impl EndpointHealth {
+    fn record(&mut self, outcome: HandshakeOutcome, now: Instant) {
+        let score = if outcome.is_ok() { 1.0 } else { 0.0 };
+        self.reliability = if self.attempts == 0 {
+            score
+        } else {
+            SMOOTHING * score + (1.0 - SMOOTHING) * self.reliability
+        };
+        self.attempts += 1;
+        self.last_attempt = Some(now);
+
+        match outcome {
+            Ok(rtt) => {
+                let rtt_ms = rtt.as_secs_f64() * 1000.0;
+                self.rtt_ms = Some(match self.rtt_ms {
+                    Some(previous) => SMOOTHING * rtt_ms + (1.0 - SMOOTHING) * previous,
+                    None => rtt_ms,
+                });
+                self.consecutive_failures = 0;
+            }
+            Err(failure) => {
+                self.consecutive_failures += 1;
+                if self.failures.len() == FAILURE_HISTORY {
+                    self.failures.pop_front();
+                }
+                self.failures.push_back((now, failure));
+            }
+        }
+    }
+
+    /// Whether the endpoint is out of its post-failure cooldown
+    pub fn is_healthy(&self, now: Instant) -> bool {
+        let last_failure = match self.failures.back() {
+            Some((at, _)) if self.consecutive_failures > 0 => *at,
+            _ => return true,
+        };
+        let cooldown = BASE_COOLDOWN
+            .saturating_mul(1 << (self.consecutive_failures - 1).min(10))
+            .min(MAX_COOLDOWN);
+        now.duration_since(last_failure) >= cooldown
+    }
+
+    /// Score in [0, 1]: reliability discounted by round trip; 0.5 when untested
+    pub fn score(&self) -> f64 {
+        if self.attempts == 0 {
+            return 0.5;
+        }
+        let latency_factor = self.rtt_ms.map_or(1.0, |rtt| 1.0 / (1.0 + rtt / LATENCY_SCALE_MS));
+        self.reliability * latency_factor
+    }
+}
+
+/// Opens TCP connections through the VPN tunnel
+///
+/// Clones share the tunnel, which follows reconnections. Dialing fails while
+/// the VPN is down, so nothing leaks around it.
+#[derive(Debug, Clone, Default)]
+pub struct VpnDialer {
+    stack: Arc<Mutex<Option<NetStack>>>,
+}
+
+impl VpnDialer {
+    fn set(&self, stack: Option<NetStack>) {
+        *self.stack.lock().unwrap_or_else(|e| e.into_inner()) = stack;
+    }
+}
+
+impl Dialer for VpnDialer {
+    fn dial(&self, address: SocketAddr) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let stack = self
+                .stack
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+                .ok_or_else(|| ScramblerError::VpnError("VPN not connected".to_string()))?;
+
+            let address = match address {
+                SocketAddr::V4(address) => address,
+                SocketAddr::V6(_) => {
+                    return Err(ScramblerError::VpnError(
+                        "IPv6 destinations aren't routed through the tunnel".to_string(),
+                    ))
+                }
+            };
+            Ok(Box::new(stack.connect(address).await?) as BoxedStream)
+        })
+    }
+}
+
 /// VPN connection manager
 #[derive(Debug)]
 pub struct VpnManager {
@@ -81,6 +252,18 @@ pub struct VpnManager {
     failure_count: u32,
     /// Reconnection backoff duration
     backoff: Duration,
+    /// WireGuard device of the current connection
+    device: Option<WireGuardDevice>,
+    /// Replaced devices draining their connections, with the time they were replaced
+    retiring: Vec<(WireGuardDevice, Instant)>,
+    /// Dialer handed to the network handlers
+    dialer: VpnDialer,
+    /// Health of each endpoint, by address
+    health: HashMap<SocketAddr, EndpointHealth>,
+    /// Time of the most recent probe round
+    last_probe: Option<Instant>,
+    /// How long to wait for a handshake
+    handshake_timeout: Duration,
 }
 
 impl VpnManager {
@@ -93,6 +276,12 @@ impl VpnManager {
             connected_since: None,
             failure_count: 0,
             backoff: Duration::from_secs(1),
+            device: None,
+            retiring: Vec::new(),
+            dialer: VpnDialer::default(),
+            health: HashMap::new(),
+            last_probe: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
         }
     }
 
@@ -106,57 +295,286 @@ impl VpnManager {
         self.state == VpnState::Connected
     }
 
-    /// Select a random VPN endpoint
-    fn select_endpoint(&self) -> Result<VpnEndpoint> {
+    /// Configured endpoints, with their latest measured round trips
+    pub fn endpoints(&self) -> &[VpnEndpoint] {
+        &self.config.endpoints
+    }
+
+    /// Observed health of an endpoint
+    pub fn endpoint_health(&self, address: &SocketAddr) -> Option<&EndpointHealth> {
+        self.health.get(address)
+    }
+
+    /// Endpoints in the order they should be tried
+    ///
+    /// Excluded jurisdictions are left out. Healthy endpoints come before
+    /// ones cooling down after failures, preferred jurisdictions before
+    /// others, then higher scores first; equal endpoints are shuffled.
+    pub fn ranked_endpoints(&self) -> Vec<VpnEndpoint> {
         use rand::seq::SliceRandom;
-        use rand::thread_rng;
 
-        self.config
+        let now = Instant::now();
+        let mut endpoints: Vec<VpnEndpoint> = self
+            .config
             .endpoints
-            .choose(&mut thread_rng())
+            .iter()
+            .filter(|endpoint| {
+                endpoint
+                    .jurisdiction
+                    .map_or(true, |j| !self.config.exclude_jurisdictions.contains(&j))
+            })
             .cloned()
+            .collect();
+        endpoints.shuffle(&mut rand::thread_rng());
+
+        let default_health = EndpointHealth::default();
+        let key = |endpoint: &VpnEndpoint| {
+            let health = self.health.get(&endpoint.address).unwrap_or(&default_health);
+            let preferred = endpoint
+                .jurisdiction
+                .is_some_and(|j| self.config.preferred_jurisdictions.contains(&j));
+            (health.is_healthy(now), preferred, health.score())
+        };
+        endpoints.sort_by(|a, b| {
+            let (healthy_a, preferred_a, score_a) = key(a);
+            let (healthy_b, preferred_b, score_b) = key(b);
+            healthy_b
+                .cmp(&healthy_a)
+                .then(preferred_b.cmp(&preferred_a))
+                .then(score_b.total_cmp(&score_a))
+        });
+        endpoints
+    }
+
+    /// Select the best endpoint to connect to
+    pub fn select_endpoint(&self) -> Result<VpnEndpoint> {
+        self.ranked_endpoints()
+            .into_iter()
+            .next()
             .ok_or_else(|| ScramblerError::VpnError("No endpoints available".to_string()))
     }
 
+    /// Dialer that connects through the tunnel
+    ///
+    /// Stays valid across reconnections; fails while the VPN is down.
+    pub fn dialer(&self) -> Arc<dyn Dialer> {
+        Arc::new(self.dialer.clone())
+    }
+
     /// Connect to VPN
     ///
-    /// This is a placeholder - actual WireGuard integration would use:
-    /// - wireguard-rs crate for userspace implementation
-    /// - Or system WireGuard via netlink/IPC
+    /// Tries endpoints best first until one completes a WireGuard handshake,
+    /// then routes the dialer's connections through it.
     pub async fn connect(&mut self) -> Result<()> {
         self.state = VpnState::Connecting;
+        self.close_tunnels();
 
-        // Select random endpoint
-        let endpoint = self.select_endpoint()?;
-
-        // TODO: Actual WireGuard connection
-        // 1. Configure WireGuard interface
-        // 2. Set private key
-        // 3. Add peer (endpoint)
-        // 4. Set allowed IPs (0.0.0.0/0 for all traffic)
-        // 5. Bring interface up
+        if let Err(e) = self.switch_to_best().await {
+            self.state = VpnState::Failed;
+            return Err(e);
+        }
 
-        // Placeholder: simulate connection
-        self.current_endpoint = Some(endpoint.clone());
-        self.connected_since = Some(Instant::now());
         self.state = VpnState::Connected;
         self.failure_count = 0;
         self.backoff = Duration::from_secs(1);
 
-        tracing::info!(
-            endpoint = %endpoint.address,
-            location = %endpoint.location,
-            "VPN connected"
-        );
+        if let Some(endpoint) = &self.current_endpoint {
+            tracing::info!(
+                endpoint = %endpoint.address,
+                location = %endpoint.location,
+                "VPN connected"
+            );
+        }
 
         Ok(())
     }
 
+    /// Move to the best endpoint that answers, keeping the current tunnel until then
+    ///
+    /// If the best endpoint is the current one, its session is rekeyed in
+    /// place. Otherwise the dialer switches to a new tunnel and the old one
+    /// drains its connections for `DRAIN_TIME`.
+    async fn switch_to_best(&mut self) -> Result<()> {
+        let (private_key, local_ip) = self.local_settings()?;
+        let candidates = self.ranked_endpoints();
+        if candidates.is_empty() {
+            return Err(ScramblerError::VpnError("No endpoints available".to_string()));
+        }
+
+        let mut last_error = None;
+        for endpoint in candidates {
+            let current = self.current_endpoint.as_ref().map(|e| e.address);
+            let result = match (&self.device, current) {
+                (Some(device), Some(address)) if address == endpoint.address => {
+                    let public_key = endpoint_key(&endpoint)?;
+                    device
+                        .handshake(&public_key, self.handshake_timeout)
+                        .await
+                        .map(|rtt| (None, rtt))
+                }
+                _ => self
+                    .open_tunnel(&endpoint, private_key, local_ip)
+                    .await
+                    .map(|(device, stack, rtt)| (Some((device, stack)), rtt)),
+            };
+
+            match result {
+                Ok((tunnel, rtt)) => {
+                    self.record(&endpoint.address, Ok(rtt));
+                    if let Some((device, stack)) = tunnel {
+                        self.dialer.set(Some(stack));
+                        if let Some(old) = self.device.replace(device) {
+                            self.retiring.push((old, Instant::now()));
+                        }
+                    }
+                    self.current_endpoint = self
+                        .config
+                        .endpoints
+                        .iter()
+                        .find(|e| e.address == endpoint.address)
+                        .cloned();
+                    self.connected_since = Some(Instant::now());
+                    return Ok(());
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        endpoint = %endpoint.address,
+                        error = %e,
+                        "VPN endpoint failed"
+                    );
+                    self.record(&endpoint.address, Err(EndpointFailure::Connect));
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            ScramblerError::VpnError("No endpoints available".to_string())
+        }))
+    }
+
+    /// Private key and tunnel address from the configuration
+    fn local_settings(&self) -> Result<([u8; 32], Ipv4Addr)> {
+        let private_key: [u8; 32] = self
+            .config
+            .private_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| {
+                ScramblerError::ConfigError("VPN private key must be 32 bytes".to_string())
+            })?;
+        let local_ip: Ipv4Addr = self
+            .config
+            .local_address
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|e| ScramblerError::ConfigError(format!("Invalid tunnel address: {}", e)))?;
+
+        Ok((private_key, local_ip))
+    }
+
+    /// Bind a WireGuard device, handshake with `endpoint` and start TCP over it
+    ///
+    /// # Returns
+    /// * Device, TCP stack over the tunnel, and the handshake round trip
+    async fn open_tunnel(
+        &self,
+        endpoint: &VpnEndpoint,
+        private_key: [u8; 32],
+        local_ip: Ipv4Addr,
+    ) -> Result<(WireGuardDevice, NetStack, Duration)> {
+        let keepalive = (self.config.keepalive_interval > 0)
+            .then(|| Duration::from_secs(u64::from(self.config.keepalive_interval)));
+        let (device, rtt) =
+            handshake(endpoint, private_key, keepalive, self.handshake_timeout).await?;
+        let stack = NetStack::new(device.tunnel(&endpoint_key(endpoint)?)?, local_ip);
+
+        Ok((device, stack, rtt))
+    }
+
+    /// Measure the handshake round trip of every endpoint
+    ///
+    /// The connected endpoint is probed by rekeying the live tunnel, others
+    /// with a throwaway device. Results update `latency_ms` and the endpoint
+    /// health; probes run concurrently.
+    pub async fn probe_endpoints(&mut self) -> Result<()> {
+        let (private_key, _) = self.local_settings()?;
+        let current = self.current_endpoint.as_ref().map(|e| e.address);
+        let timeout = self.handshake_timeout;
+
+        let mut probes = tokio::task::JoinSet::new();
+        for endpoint in self.config.endpoints.clone() {
+            let live = match (&self.device, current) {
+                (Some(device), Some(address)) if address == endpoint.address => {
+                    Some(device.clone())
+                }
+                _ => None,
+            };
+            probes.spawn(async move {
+                let rtt = match live {
+                    Some(device) => match endpoint_key(&endpoint) {
+                        Ok(key) => device.handshake(&key, timeout).await,
+                        Err(e) => Err(e),
+                    },
+                    None => handshake(&endpoint, private_key, None, timeout)
+                        .await
+                        .map(|(device, rtt)| {
+                            device.close();
+                            rtt
+                        }),
+                };
+                (endpoint.address, rtt)
+            });
+        }
+
+        while let Some(probe) = probes.join_next().await {
+            let (address, rtt) = probe
+                .map_err(|e| ScramblerError::VpnError(format!("Probe task failed: {}", e)))?;
+            let failure = if Some(address) == current {
+                EndpointFailure::TunnelLost
+            } else {
+                EndpointFailure::Probe
+            };
+            tracing::debug!(endpoint = %address, rtt = ?rtt.as_ref().ok(), "VPN endpoint probed");
+            self.record(&address, rtt.map_err(|_| failure));
+        }
+
+        self.last_probe = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Record a handshake outcome and update the endpoint's latency
+    fn record(&mut self, address: &SocketAddr, outcome: HandshakeOutcome) {
+        let health = self.health.entry(*address).or_default();
+        health.record(outcome, Instant::now());
+
+        let latency_ms = health.rtt_ms.map(|rtt| rtt.round() as u32);
+        for endpoint in self.config.endpoints.iter_mut().chain(self.current_endpoint.as_mut()) {
+            if endpoint.address == *address {
+                endpoint.latency_ms = latency_ms;
+            }
+        }
+    }
+
+    /// Close the current tunnel and any draining ones
+    fn close_tunnels(&mut self) {
+        self.dialer.set(None);
+        if let Some(device) = self.device.take() {
+            device.close();
+        }
+        for (device, _) in self.retiring.drain(..) {
+            device.close();
+        }
+    }
+
     /// Disconnect from VPN
+    ///
+    /// Connections through the tunnel are cut and new ones fail until the
+    /// next `connect`.
     pub async fn disconnect(&mut self) -> Result<()> {
-        // TODO: Actual WireGuard disconnection
-        // 1. Remove peer configuration
-        // 2. Bring interface down
+        self.close_tunnels();
 
         self.state = VpnState::Disconnected;
         self.current_endpoint = None;
@@ -199,6 +617,14 @@ impl VpnManager {
         }
     }
 
+    /// Whether a probe round is due
+    fn probe_due(&self) -> bool {
+        self.config.probe_interval > 0
+            && self.last_probe.map_or(true, |last| {
+                last.elapsed() >= Duration::from_secs(self.config.probe_interval)
+            })
+    }
+
     /// Maintain VPN connection (call periodically)
     pub async fn maintain(&mut self) -> Result<()> {
         match self.state {
@@ -207,17 +633,40 @@ impl VpnManager {
                 self.connect().await?;
             }
             VpnState::Connected => {
-                // Check if renewal needed
-                if self.needs_renewal() {
-                    tracing::info!("VPN session expired, reconnecting");
-                    self.disconnect().await?;
-                    self.connect().await?;
+                if self.probe_due() {
+                    self.probe_endpoints().await?;
+                }
+
+                let tunnel_lost = self
+                    .current_endpoint
+                    .as_ref()
+                    .and_then(|endpoint| self.health.get(&endpoint.address))
+                    .is_some_and(|health| health.consecutive_failures > 0);
+
+                if tunnel_lost {
+                    // Fail over; the dead tunnel can't carry anything anyway
+                    tracing::warn!("VPN tunnel lost, failing over");
+                    if let Err(e) = self.switch_to_best().await {
+                        self.close_tunnels();
+                        self.state = VpnState::Failed;
+                        return Err(e);
+                    }
+                } else if self.needs_renewal() {
+                    // Make-before-break: keep the session until its successor is up
+                    tracing::info!("VPN session expired, rotating");
+                    if let Err(e) = self.switch_to_best().await {
+                        tracing::warn!(error = %e, "VPN rotation failed, keeping current session");
+                    }
                 }
 
-                // TODO: Check connection health
-                // - Ping endpoint
-                // - Verify traffic flow
-                // - Monitor latency
+                let now = Instant::now();
+                self.retiring.retain(|(device, replaced)| {
+                    let draining = now.duration_since(*replaced) < DRAIN_TIME;
+                    if !draining {
+                        device.close();
+                    }
+                    draining
+                });
             }
             VpnState::Reconnecting => {
                 // Already reconnecting, wait
@@ -250,10 +699,118 @@ impl VpnManager {
     }
 }
 
+/// Endpoint's public key as a fixed-size array
+fn endpoint_key(endpoint: &VpnEndpoint) -> Result<[u8; 32]> {
+    endpoint
+        .public_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| ScramblerError::ConfigError("VPN endpoint key must be 32 bytes".to_string()))
+}
+
+/// Bind a WireGuard device with `endpoint` as its only peer and handshake
+///
+/// # Returns
+/// * Device with an established session, and the handshake round trip
+async fn handshake(
+    endpoint: &VpnEndpoint,
+    private_key: [u8; 32],
+    keepalive: Option<Duration>,
+    timeout: Duration,
+) -> Result<(WireGuardDevice, Duration)> {
+    let public_key = endpoint_key(endpoint)?;
+    let bind_address: SocketAddr = if endpoint.address.is_ipv4() {
+        "0.0.0.0:0".parse().expect("valid address")
+    } else {
+        "[::]:0".parse().expect("valid address")
+    };
+    let device = WireGuardDevice::bind(
+        bind_address,
+        private_key,
+        vec![PeerConfig {
+            public_key,
+            endpoint: Some(endpoint.address),
+            preshared_key: None,
+            persistent_keepalive: keepalive,
+        }],
+    )
+    .await?;
+
+    match device.handshake(&public_key, timeout).await {
+        Ok(rtt) => Ok((device, rtt)),
+        Err(e) => {
+            device.close();
+            Err(e)
+        }
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use std::net::{IpAddr, Ipv4Addr};
+    use crate::epoch::EpochSchedule;
+    use crate::network::{
+        read_message, MixNodeAddr, NetworkConfig, PacketTransmitter, WireMessage,
+    };
+    use crate::sphinx::{build_packet, RouteSpec};
+    use crate::wireguard::generate_keypair;
+    use invisible_crypto::keys::KeyPair;
+    use std::net::IpAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Local WireGuard peer that forwards tunneled connections to real TCP
+    ///
+    /// # Arguments
+    /// * `client_public` - Public key of the client allowed to connect
+    ///
+    /// # Returns
+    /// * Endpoint to configure in the client
+    pub(crate) async fn spawn_gateway(client_public: [u8; 32]) -> VpnEndpoint {
+        let (private_key, public_key) = generate_keypair();
+        let device = WireGuardDevice::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            private_key,
+            vec![PeerConfig {
+                public_key: client_public,
+                endpoint: None,
+                preshared_key: None,
+                persistent_keepalive: None,
+            }],
+        )
+        .await
+        .unwrap();
+        let address = device.local_addr().unwrap();
+        let gateway = NetStack::gateway(device.tunnel(&client_public).unwrap());
+
+        tokio::spawn(async move {
+            while let Ok((mut tunneled, destination)) = gateway.accept().await {
+                tokio::spawn(async move {
+                    if let Ok(mut upstream) = TcpStream::connect(destination).await {
+                        let _ = tokio::io::copy_bidirectional(&mut tunneled, &mut upstream).await;
+                    }
+                });
+            }
+        });
+
+        VpnEndpoint {
+            public_key: public_key.to_vec(),
+            address,
+            location: "Local".to_string(),
+            jurisdiction: None,
+            latency_ms: None,
+        }
+    }
+
+    /// Configuration with a local gateway as the only endpoint
+    pub(crate) async fn local_vpn_config() -> VpnConfig {
+        let (private_key, public_key) = generate_keypair();
+        VpnConfig {
+            private_key: private_key.to_vec(),
+            endpoints: vec![spawn_gateway(public_key).await],
+            ..create_test_config()
+        }
+    }
 
     fn create_test_config() -> VpnConfig {
         VpnConfig {
@@ -264,23 +821,28 @@ mod tests {
                     public_key: vec![2u8; 32],
                     address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 51820),
                     location: "US-East".to_string(),
+                    jurisdiction: Some(Jurisdiction::FiveEyes),
                     latency_ms: None,
                 },
                 VpnEndpoint {
                     public_key: vec![3u8; 32],
                     address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)), 51820),
                     location: "EU-West".to_string(),
+                    jurisdiction: Some(Jurisdiction::PrivacyFriendly),
                     latency_ms: None,
                 },
             ],
             keepalive_interval: 25,
             max_session_time: 3600, // 1 hour
+            probe_interval: 300,
+            exclude_jurisdictions: Vec::new(),
+            preferred_jurisdictions: Vec::new(),
         }
     }
 
     #[tokio::test]
     async fn test_vpn_connect() {
-        let config = create_test_config();
+        let config = local_vpn_config().await;
         let mut manager = VpnManager::new(config);
 
         assert_eq!(manager.state(), VpnState::Disconnected);
@@ -295,7 +857,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_vpn_disconnect() {
-        let config = create_test_config();
+        let config = local_vpn_config().await;
         let mut manager = VpnManager::new(config);
 
         manager.connect().await.unwrap();
@@ -306,6 +868,80 @@ mod tests {
         assert!(!manager.is_connected());
     }
 
+    #[tokio::test]
+    async fn test_invalid_key_fails() {
+        let mut config = local_vpn_config().await;
+        config.private_key = vec![0u8; 16];
+        let mut manager = VpnManager::new(config);
+
+        assert!(matches!(manager.connect().await, Err(ScramblerError::ConfigError(_))));
+        assert_eq!(manager.state(), VpnState::Failed);
+        assert!(!manager.is_connected());
+    }
+
+    #[tokio::test]
+    async fn test_traffic_through_tunnel() {
+        // Echo server, reached through the tunnel
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                });
+            }
+        });
+
+        let mut manager = VpnManager::new(local_vpn_config().await);
+        let dialer = manager.dialer();
+        assert!(matches!(dialer.dial(echo_address).await, Err(ScramblerError::VpnError(_))));
+
+        manager.connect().await.unwrap();
+        let mut stream = dialer.dial(echo_address).await.unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        stream.write_all(&data).await.unwrap();
+        let mut echoed = vec![0u8; data.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert!(echoed == data);
+
+        // Mixnet packets go through the tunnel too
+        let mix = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mix_address = mix.local_addr().unwrap();
+        let keypair = KeyPair::generate().unwrap();
+        let route = RouteSpec {
+            node_keys: vec![keypair.public_key().to_vec()],
+            node_addresses: vec![mix_address],
+            delays: vec![Duration::ZERO],
+            epoch: EpochSchedule::default().current_epoch(),
+            destination: vec![0u8; 32],
+        };
+        let packet = build_packet(&route, b"through the tunnel").unwrap();
+        let node = MixNodeAddr {
+            address: mix_address.to_string(),
+            public_key: keypair.public_key().to_vec(),
+        };
+
+        let mut transmitter = PacketTransmitter::new(NetworkConfig::default());
+        transmitter.set_dialer(manager.dialer());
+        let (sent, accepted) = tokio::join!(transmitter.send_packet(&packet, &node), mix.accept());
+        sent.unwrap();
+        let (mut mix_stream, _) = accepted.unwrap();
+        match read_message(&mut mix_stream).await.unwrap() {
+            Some(WireMessage::ForwardPacket { packet: received }) => {
+                assert_eq!(received.payload, packet.payload)
+            }
+            _ => panic!("Expected the forwarded packet"),
+        }
+
+        // Kill switch: tunneled connections are cut and new ones fail
+        manager.disconnect().await.unwrap();
+        let mut buffer = [0u8; 1];
+        assert!(stream.read(&mut buffer).await.is_err());
+        assert!(matches!(dialer.dial(echo_address).await, Err(ScramblerError::VpnError(_))));
+    }
+
     #[test]
     fn test_endpoint_selection() {
         let config = create_test_config();
@@ -316,6 +952,219 @@ mod tests {
         assert!(!endpoint.public_key.is_empty());
     }
 
+    #[test]
+    fn test_endpoint_health() {
+        let now = Instant::now();
+        let mut health = EndpointHealth::default();
+        assert!(health.is_healthy(now));
+        assert_eq!(health.score(), 0.5);
+
+        health.record(Ok(Duration::from_millis(200)), now);
+        assert_eq!(health.reliability, 1.0);
+        assert_eq!(health.rtt_ms, Some(200.0));
+        assert_eq!(health.score(), 0.5);
+
+        health.record(Ok(Duration::from_millis(100)), now);
+        assert_eq!(health.rtt_ms, Some(150.0));
+
+        // Failures start a cooldown that doubles each time
+        health.record(Err(EndpointFailure::Probe), now);
+        assert_eq!(health.reliability, 0.5);
+        assert!(!health.is_healthy(now));
+        assert!(health.is_healthy(now + BASE_COOLDOWN));
+        health.record(Err(EndpointFailure::Probe), now);
+        assert!(!health.is_healthy(now + BASE_COOLDOWN));
+        assert!(health.is_healthy(now + BASE_COOLDOWN * 2));
+
+        // A success ends the cooldown but keeps the history
+        health.record(Ok(Duration::from_millis(100)), now);
+        assert!(health.is_healthy(now));
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.failures.len(), 2);
+
+        for _ in 0..FAILURE_HISTORY + 5 {
+            health.record(Err(EndpointFailure::Connect), now);
+        }
+        assert_eq!(health.failures.len(), FAILURE_HISTORY);
+        assert!(health.is_healthy(now + MAX_COOLDOWN));
+    }
+
+    #[test]
+    fn test_endpoint_ranking() {
+        let mut config = create_test_config();
+        let us_east = config.endpoints[0].address;
+        let eu_west = config.endpoints[1].address;
+
+        // Faster endpoints rank first
+        let mut manager = VpnManager::new(config.clone());
+        manager.record(&us_east, Ok(Duration::from_millis(20)));
+        manager.record(&eu_west, Ok(Duration::from_millis(300)));
+        assert_eq!(manager.ranked_endpoints()[0].address, us_east);
+        assert_eq!(manager.endpoints()[0].latency_ms, Some(20));
+
+        // Preferred jurisdictions beat speed, failures beat both
+        config.preferred_jurisdictions = vec![Jurisdiction::PrivacyFriendly];
+        manager.config = config.clone();
+        assert_eq!(manager.ranked_endpoints()[0].address, eu_west);
+        manager.record(&eu_west, Err(EndpointFailure::Connect));
+        assert_eq!(manager.ranked_endpoints()[0].address, us_east);
+
+        // Excluded jurisdictions are never chosen
+        config.exclude_jurisdictions = vec![Jurisdiction::FiveEyes];
+        manager.config = config;
+        let ranked = manager.ranked_endpoints();
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].address, eu_west);
+
+        manager.config.exclude_jurisdictions.push(Jurisdiction::PrivacyFriendly);
+        assert!(matches!(manager.select_endpoint(), Err(ScramblerError::VpnError(_))));
+    }
+
+    /// Start an echo server, returning its address
+    async fn spawn_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        address
+    }
+
+    fn client_public(config: &VpnConfig) -> [u8; 32] {
+        let private_key: [u8; 32] = config.private_key.as_slice().try_into().unwrap();
+        x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(private_key)).to_bytes()
+    }
+
+    async fn assert_echoes(stream: &mut BoxedStream, data: &[u8]) {
+        stream.write_all(data).await.unwrap();
+        let mut echoed = vec![0u8; data.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, data);
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_healthy_endpoint() {
+        // Preferred endpoint never answers
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = local_vpn_config().await;
+        config.endpoints[0].jurisdiction = Some(Jurisdiction::FiveEyes);
+        config.endpoints.push(VpnEndpoint {
+            public_key: generate_keypair().1.to_vec(),
+            address: silent.local_addr().unwrap(),
+            location: "Silent".to_string(),
+            jurisdiction: Some(Jurisdiction::PrivacyFriendly),
+            latency_ms: None,
+        });
+        config.preferred_jurisdictions = vec![Jurisdiction::PrivacyFriendly];
+        let live = config.endpoints[0].address;
+        let dead = config.endpoints[1].address;
+
+        let mut manager = VpnManager::new(config);
+        manager.handshake_timeout = Duration::from_millis(300);
+        manager.connect().await.unwrap();
+
+        assert_eq!(manager.current_endpoint().unwrap().address, live);
+        assert!(manager.current_endpoint().unwrap().latency_ms.is_some());
+        let health = manager.endpoint_health(&dead).unwrap();
+        assert_eq!(health.consecutive_failures, 1);
+        assert_eq!(health.failures[0].1, EndpointFailure::Connect);
+        assert!(!health.is_healthy(Instant::now()));
+
+        // The failed endpoint now ranks last despite the preference
+        assert_eq!(manager.ranked_endpoints()[0].address, live);
+
+        let echo = spawn_echo().await;
+        let mut stream = manager.dialer().dial(echo).await.unwrap();
+        assert_echoes(&mut stream, b"failed over").await;
+    }
+
+    #[tokio::test]
+    async fn test_probe_endpoints() {
+        let mut config = local_vpn_config().await;
+        config.endpoints.push(spawn_gateway(client_public(&config)).await);
+
+        let mut manager = VpnManager::new(config);
+        manager.connect().await.unwrap();
+        manager.probe_endpoints().await.unwrap();
+
+        for endpoint in manager.endpoints() {
+            assert!(endpoint.latency_ms.is_some());
+            let health = manager.endpoint_health(&endpoint.address).unwrap();
+            assert!(health.attempts >= 1);
+            assert_eq!(health.consecutive_failures, 0);
+        }
+        assert!(!manager.probe_due());
+    }
+
+    #[tokio::test]
+    async fn test_rotation_rekeys_in_place() {
+        let mut config = local_vpn_config().await;
+        config.max_session_time = 0;
+        config.probe_interval = 0;
+        let mut manager = VpnManager::new(config);
+        manager.connect().await.unwrap();
+
+        let echo = spawn_echo().await;
+        let mut stream = manager.dialer().dial(echo).await.unwrap();
+        assert_echoes(&mut stream, b"before").await;
+
+        assert!(manager.needs_renewal());
+        manager.maintain().await.unwrap();
+        assert!(manager.is_connected());
+        assert!(manager.retiring.is_empty());
+
+        // Same tunnel, new session: the connection survives
+        assert_echoes(&mut stream, b"after").await;
+        assert_eq!(manager.endpoint_health(&manager.endpoints()[0].address).unwrap().attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_make_before_break_rotation() {
+        let mut config = local_vpn_config().await;
+        let mut second = spawn_gateway(client_public(&config)).await;
+        second.jurisdiction = Some(Jurisdiction::PrivacyFriendly);
+        config.endpoints[0].jurisdiction = Some(Jurisdiction::FiveEyes);
+        config.endpoints.push(second.clone());
+        config.preferred_jurisdictions = vec![Jurisdiction::FiveEyes];
+        config.probe_interval = 0;
+        let first = config.endpoints[0].address;
+
+        let mut manager = VpnManager::new(config);
+        manager.connect().await.unwrap();
+        assert_eq!(manager.current_endpoint().unwrap().address, first);
+
+        let echo = spawn_echo().await;
+        let dialer = manager.dialer();
+        let mut old_stream = dialer.dial(echo).await.unwrap();
+        assert_echoes(&mut old_stream, b"first").await;
+
+        // Session expires while the other endpoint has become preferred
+        manager.config.preferred_jurisdictions = vec![Jurisdiction::PrivacyFriendly];
+        manager.config.max_session_time = 0;
+        manager.maintain().await.unwrap();
+        assert_eq!(manager.current_endpoint().unwrap().address, second.address);
+        assert_eq!(manager.retiring.len(), 1);
+
+        // New connections use the new tunnel, the old one keeps draining
+        let mut new_stream = dialer.dial(echo).await.unwrap();
+        assert_echoes(&mut new_stream, b"second").await;
+        assert_echoes(&mut old_stream, b"still here").await;
+
+        // Drained tunnels are closed
+        manager.config.max_session_time = 3600;
+        manager.retiring[0].1 -= DRAIN_TIME;
+        manager.maintain().await.unwrap();
+        assert!(manager.retiring.is_empty());
+        let mut buffer = [0u8; 1];
+        assert!(old_stream.read(&mut buffer).await.is_err());
+        assert_echoes(&mut new_stream, b"unaffected").await;
+    }
+
     #[test]
     fn test_exponential_backoff() {
         // Test backoff calculation without actually connecting
@@ -0,0 +1,1618 @@
+//! WireGuard
+//!
+//! Userspace WireGuard over UDP: the Noise_IKpsk2 handshake, transport data
+//! messages and the protocol timers, without a TUN device or root.
+//!
+//! ## Architecture
+//!
+//! - **WireGuardDevice:** One UDP socket and static key with a set of
+//!   configured peers, like a WireGuard interface. A background task handles
+//!   incoming messages and the timers: handshake retransmission, rekeying,
+//!   keepalives and session expiry.
+//! - **Handshake:** Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s as specified by
+//!   WireGuard, with mac1/mac2 and cookie replies when under load
+//! - **WireGuardTunnel:** IP packets to and from one peer; `crate::netstack`
+//!   runs TCP over it
+//!
+//! ## Security Properties
+//!
+//! - **Mutual Authentication:** Only configured peers complete a handshake
+//! - **Forward Secrecy:** Session keys come from ephemeral keys and are
+//!   replaced every two minutes
+//! - **Replay Protection:** Monotonic timestamps in initiations and a sliding
+//!   counter window on transport data
+//! - **Silence:** Messages without a valid mac1 get no response, so scanners
+//!   can't tell a WireGuard endpoint is there
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use blake2::digest::consts::U16;
+use blake2::digest::{KeyInit, Mac};
+use blake2::{Blake2s256, Blake2sMac, Digest};
+use hmac::SimpleHmac;
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use subtle::ConstantTimeEq;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+use crate::error::{Result, ScramblerError};
+use crate::netstack::{LinkFuture, PacketLink};
+
+/// Noise protocol name
+const CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+
+/// WireGuard protocol identifier
+const IDENTIFIER: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+
+/// Label for the mac1 key
+const LABEL_MAC1: &[u8] = b"mac1----";
+
+/// Label for the cookie encryption key
+const LABEL_COOKIE: &[u8] = b"cookie--";
+
+/// Message sizes
+const INITIATION_SIZE: usize = 148;
+const RESPONSE_SIZE: usize = 92;
+const COOKIE_REPLY_SIZE: usize = 64;
+const DATA_HEADER_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+
+/// Protocol timers and limits from the WireGuard paper
+const REKEY_AFTER_MESSAGES: u64 = 1 << 60;
+const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
+const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+const COOKIE_REFRESH_TIME: Duration = Duration::from_secs(120);
+
+/// Timer resolution of the background task
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Packets queued per peer while a handshake is in flight
+const MAX_QUEUED_PACKETS: usize = 1024;
+
+/// Decrypted packets buffered per peer before new ones are dropped
+const INBOUND_QUEUE_SIZE: usize = 1024;
+
+/// Size of the replay window (counters)
+const REPLAY_WINDOW: u64 = 2048;
+
+/// Offset between the Unix and TAI64 epochs
+const TAI64_BASE: u64 = 0x400000000000000a;
+
+/// Nanosecond bits dropped from timestamps so they don't fingerprint the clock
+const TIMESTAMP_WHITENER: u32 = 0x1000000 - 1;
+
+/// Generate a WireGuard key pair
+///
+/// # Returns
+/// * Private key and public key
+pub fn generate_keypair() -> ([u8; 32], [u8; 32]) {
+    let private_key: [u8; 32] = rand::thread_rng().gen();
+    let public_key = PublicKey::from(&StaticSecret::from(private_key)).to_bytes();
+    (private_key, public_key)
+}
+
+/// A peer the device talks to
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    /// Peer's static public key
+    pub public_key: [u8; 32],
+    /// Peer's UDP address; learned from its packets when unset
+    pub endpoint: Option<SocketAddr>,
+    /// Optional pre-shared key mixed into the handshake
+    pub preshared_key: Option<[u8; 32]>,
+    /// Send a keepalive when nothing was sent for this long
+    pub persistent_keepalive: Option<Duration>,
+}
+
+/// Traffic and handshake statistics of a peer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerStats {
+    /// Time the most recent handshake completed
+    pub last_handshake: Option<Instant>,
+    /// Round trip of the most recent handshake we initiated
+    pub handshake_rtt: Option<Duration>,
+    /// Bytes sent, including WireGuard overhead
+    pub tx_bytes: u64,
+    /// Bytes received, including WireGuard overhead
+    pub rx_bytes: u64,
+}
+
+/// Userspace WireGuard interface
+#[derive(Clone)]
+pub struct WireGuardDevice {
+    handle: Arc<DeviceHandle>,
+}
+
+/// Owns the background task; aborted when the last device or tunnel is dropped
+struct DeviceHandle {
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for DeviceHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Shared {
+    socket: UdpSocket,
+    public_key: [u8; 32],
+    state: Mutex<DeviceState>,
+}
+
+/// Datagrams to send once the state lock is released
+type Outgoing = Vec<(Vec<u8>, SocketAddr)>;
+
+impl WireGuardDevice {
+    /// Bind a device to a UDP address
+    ///
+    /// # Arguments
+    /// * `address` - Local UDP address (port 0 picks a free port)
+    /// * `private_key` - Device's static private key
+    /// * `peers` - Peers allowed to handshake with the device
+    pub async fn bind(
+        address: SocketAddr,
+        private_key: [u8; 32],
+        peers: Vec<PeerConfig>,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(address)
+            .await
+            .map_err(|e| ScramblerError::VpnError(format!("Failed to bind UDP socket: {}", e)))?;
+
+        let keys = LocalKeys::new(private_key);
+        let public_key = keys.public;
+        let mut peer_states = HashMap::new();
+        for config in peers {
+            let peer = PeerState::new(&keys, config)?;
+            peer_states.insert(peer.public_key, peer);
+        }
+
+        let shared = Arc::new(Shared {
+            socket,
+            public_key,
+            state: Mutex::new(DeviceState {
+                keys,
+                peers: peer_states,
+                indices: HashMap::new(),
+                cookies: CookieChecker::new(),
+                under_load: false,
+            }),
+        });
+        let task = tokio::spawn(run(shared.clone()));
+
+        Ok(Self {
+            handle: Arc::new(DeviceHandle { shared, task }),
+        })
+    }
+
+    /// Local UDP address
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.handle
+            .shared
+            .socket
+            .local_addr()
+            .map_err(|e| ScramblerError::VpnError(format!("Failed to read local address: {}", e)))
+    }
+
+    /// Device's static public key
+    pub fn public_key(&self) -> [u8; 32] {
+        self.handle.shared.public_key
+    }
+
+    /// Run a new handshake with a peer and wait for it to complete
+    ///
+    /// Packets keep flowing over the previous session until the new one is
+    /// established.
+    ///
+    /// # Arguments
+    /// * `peer` - Peer's public key; its endpoint must be known
+    /// * `timeout` - How long to keep retrying
+    ///
+    /// # Returns
+    /// * Round trip of the completed handshake
+    pub async fn handshake(&self, peer: &[u8; 32], timeout: Duration) -> Result<Duration> {
+        let shared = &self.handle.shared;
+        let (mut sessions, out) = {
+            let mut state = shared.state();
+            let now = Instant::now();
+            let peer_state = state.peer_mut(peer)?;
+            if peer_state.endpoint.is_none() {
+                return Err(ScramblerError::VpnError(
+                    "Peer endpoint unknown".to_string(),
+                ));
+            }
+            let mut sessions = peer_state.sessions.subscribe();
+            sessions.borrow_and_update();
+
+            let mut out = Vec::new();
+            state.begin_handshake(peer, now, &mut out);
+            (sessions, out)
+        };
+        shared.transmit(out).await;
+
+        match tokio::time::timeout(timeout, sessions.changed()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(ScramblerError::VpnError("Device closed".to_string())),
+            Err(_) => return Err(ScramblerError::VpnError("Handshake timed out".to_string())),
+        }
+
+        let stats = self.peer_stats(peer).unwrap_or_default();
+        Ok(stats.handshake_rtt.unwrap_or_default())
+    }
+
+    /// Tunnel carrying IP packets to and from a peer
+    ///
+    /// Each peer's tunnel can be taken once.
+    pub fn tunnel(&self, peer: &[u8; 32]) -> Result<WireGuardTunnel> {
+        let inbound = self
+            .handle
+            .shared
+            .state()
+            .peer_mut(peer)?
+            .inbound_rx
+            .take()
+            .ok_or_else(|| ScramblerError::ConfigError("Tunnel already taken".to_string()))?;
+
+        Ok(WireGuardTunnel {
+            device: self.clone(),
+            peer: *peer,
+            inbound: tokio::sync::Mutex::new(inbound),
+        })
+    }
+
+    /// Statistics of a peer
+    pub fn peer_stats(&self, peer: &[u8; 32]) -> Option<PeerStats> {
+        let state = self.handle.shared.state();
+        state.peers.get(peer).map(|peer| PeerStats {
+            last_handshake: peer.last_handshake,
+            handshake_rtt: peer.handshake_rtt,
+            tx_bytes: peer.tx_bytes,
+            rx_bytes: peer.rx_bytes,
+        })
+    }
+
+    /// Shut the device down
+    ///
+    /// Forgets all peers and sessions and stops the background task. Tunnels
+    /// see the end of their packet stream and can no longer send.
+    pub fn close(&self) {
+        let shared = &self.handle.shared;
+        let mut state = shared.state();
+        state.peers.clear();
+        state.indices.clear();
+        drop(state);
+        self.handle.task.abort();
+    }
+
+    /// Answer initiations with cookie replies until the initiator proves its address
+    pub fn set_under_load(&self, under_load: bool) {
+        self.handle.shared.state().under_load = under_load;
+    }
+
+    async fn send(&self, peer: &[u8; 32], packet: &[u8]) -> Result<()> {
+        let shared = &self.handle.shared;
+        let out = {
+            let mut out = Vec::new();
+            shared
+                .state()
+                .send_packet(peer, packet, Instant::now(), &mut out)?;
+            out
+        };
+        shared.transmit(out).await;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for WireGuardDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireGuardDevice")
+            .field("public_key", &hex::encode(self.public_key()))
+            .field("local_addr", &self.local_addr().ok())
+            .finish()
+    }
+}
+
+/// IP packets to and from one peer
+#[derive(Debug)]
+pub struct WireGuardTunnel {
+    device: WireGuardDevice,
+    peer: [u8; 32],
+    inbound: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl WireGuardTunnel {
+    /// Device the tunnel belongs to
+    pub fn device(&self) -> &WireGuardDevice {
+        &self.device
+    }
+
+    /// Peer at the other end
+    pub fn peer(&self) -> &[u8; 32] {
+        &self.peer
+    }
+}
+
+impl PacketLink for WireGuardTunnel {
+    fn send(&self, packet: Vec<u8>) -> LinkFuture<'_, ()> {
+        Box::pin(async move { self.device.send(&self.peer, &packet).await })
+    }
+
+    fn recv(&self) -> LinkFuture<'_, Option<Vec<u8>>> {
+        Box::pin(async move { Ok(self.inbound.lock().await.recv().await) })
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, DeviceState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn transmit(&self, out: Outgoing) {
+        for (datagram, endpoint) in out {
+            if let Err(e) = self.socket.send_to(&datagram, endpoint).await {
+                tracing::debug!(%endpoint, error = %e, "WireGuard send failed");
+            }
+        }
+    }
+}
+
+/// Background task: incoming datagrams and timers
+async fn run(shared: Arc<Shared>) {
+    let mut buffer = vec![0u8; 65536];
+    let mut timer = tokio::time::interval(TIMER_INTERVAL);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        let mut out = Vec::new();
+        tokio::select! {
+            received = shared.socket.recv_from(&mut buffer) => match received {
+                Ok((len, from)) => {
+                    if let Err(e) = shared.state().handle(&buffer[..len], from, Instant::now(), &mut out) {
+                        tracing::debug!(%from, error = %e, "Dropped WireGuard message");
+                    }
+                }
+                Err(e) => tracing::debug!(error = %e, "WireGuard receive failed"),
+            },
+            _ = timer.tick() => shared.state().tick(Instant::now(), &mut out),
+        }
+        shared.transmit(out).await;
+    }
+}
+
+/// Our static key and the keys derived from it
+struct LocalKeys {
+    secret: StaticSecret,
+    public: [u8; 32],
+    /// Key for mac1 of messages sent to us
+    mac1_key: [u8; 32],
+    /// Key for cookie replies we send
+    cookie_key: [u8; 32],
+}
+
+impl LocalKeys {
+    fn new(private_key: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(private_key);
+        let public = PublicKey::from(&secret).to_bytes();
+        Self {
+            secret,
+            mac1_key: hash(&[LABEL_MAC1, &public]),
+            cookie_key: hash(&[LABEL_COOKIE, &public]),
+            public,
+        }
+    }
+}
+
+/// Established session keys in one direction pair
+struct Session {
+    local_index: u32,
+    remote_index: u32,
+    send_key: LessSafeKey,
+    recv_key: LessSafeKey,
+    send_counter: u64,
+    replay: ReplayWindow,
+    created: Instant,
+    /// Whether we initiated the handshake (only initiators rekey on time)
+    initiator: bool,
+}
+
+impl Session {
+    fn new(
+        local_index: u32,
+        remote_index: u32,
+        send_key: &[u8; 32],
+        recv_key: &[u8; 32],
+        now: Instant,
+        initiator: bool,
+    ) -> Self {
+        Self {
+            local_index,
+            remote_index,
+            send_key: aead_key(send_key),
+            recv_key: aead_key(recv_key),
+            send_counter: 0,
+            replay: ReplayWindow::default(),
+            created: now,
+            initiator,
+        }
+    }
+
+    fn can_send(&self, now: Instant) -> bool {
+        now.duration_since(self.created) < REJECT_AFTER_TIME
+            && self.send_counter < REJECT_AFTER_MESSAGES
+    }
+
+    fn needs_rekey(&self, now: Instant) -> bool {
+        self.send_counter >= REKEY_AFTER_MESSAGES
+            || (self.initiator && now.duration_since(self.created) >= REKEY_AFTER_TIME)
+    }
+
+    /// Encrypt a packet into a transport data message
+    fn encrypt(&mut self, packet: &[u8]) -> Vec<u8> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        // Plaintext is padded to a multiple of 16 bytes
+        let mut payload = packet.to_vec();
+        payload.resize((packet.len() + 15) & !15, 0);
+        self.send_key
+            .seal_in_place_append_tag(counter_nonce(counter), Aad::empty(), &mut payload)
+            .expect("ChaCha20-Poly1305 sealing can't fail for packet-sized input");
+
+        let mut message = Vec::with_capacity(DATA_HEADER_SIZE + payload.len());
+        message.extend_from_slice(&[4, 0, 0, 0]);
+        message.extend_from_slice(&self.remote_index.to_le_bytes());
+        message.extend_from_slice(&counter.to_le_bytes());
+        message.extend_from_slice(&payload);
+        message
+    }
+}
+
+/// Handshake we initiated and are waiting on
+struct PendingHandshake {
+    local_index: u32,
+    state: SymmetricState,
+    ephemeral: StaticSecret,
+    /// First attempt, for giving up after `REKEY_ATTEMPT_TIME`
+    started: Instant,
+    /// Most recent transmission
+    sent: Instant,
+    /// mac1 of the most recent initiation, authenticating cookie replies
+    mac1: [u8; 16],
+}
+
+/// Which of a peer's sessions a message belongs to
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Current,
+    Previous,
+    Next,
+}
+
+struct PeerState {
+    public_key: [u8; 32],
+    /// Key for mac1 of messages sent to the peer
+    mac1_key: [u8; 32],
+    /// Key for cookie replies from the peer
+    cookie_key: [u8; 32],
+    /// DH of our static key and the peer's
+    static_static: [u8; 32],
+    preshared_key: [u8; 32],
+    persistent_keepalive: Option<Duration>,
+    endpoint: Option<SocketAddr>,
+
+    pending: Option<PendingHandshake>,
+    /// Session used for sending
+    current: Option<Session>,
+    /// Session replaced by `current`, still accepted for receiving
+    previous: Option<Session>,
+    /// Session we responded to, used once the initiator sends on it
+    next: Option<Session>,
+
+    /// Greatest initiation timestamp received from the peer
+    latest_timestamp: [u8; 12],
+    /// Greatest initiation timestamp sent to the peer
+    sent_timestamp: [u8; 12],
+    /// Cookie from the peer's most recent cookie reply
+    cookie: Option<([u8; 16], Instant)>,
+    /// Packets waiting for a session
+    queue: VecDeque<Vec<u8>>,
+
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    last_data_sent: Option<Instant>,
+    last_data_received: Option<Instant>,
+    last_handshake: Option<Instant>,
+    handshake_rtt: Option<Duration>,
+    tx_bytes: u64,
+    rx_bytes: u64,
+
+    inbound: mpsc::Sender<Vec<u8>>,
+    inbound_rx: Option<mpsc::Receiver<Vec<u8>>>,
+    /// Bumped whenever a new session is established
+    sessions: watch::Sender<u64>,
+}
+
+impl PeerState {
+    fn new(keys: &LocalKeys, config: PeerConfig) -> Result<Self> {
+        let static_static = dh(&keys.secret, &config.public_key)?;
+        let (inbound, inbound_rx) = mpsc::channel(INBOUND_QUEUE_SIZE);
+
+        Ok(Self {
+            public_key: config.public_key,
+            mac1_key: hash(&[LABEL_MAC1, &config.public_key]),
+            cookie_key: hash(&[LABEL_COOKIE, &config.public_key]),
+            static_static,
+            preshared_key: config.preshared_key.unwrap_or([0u8; 32]),
+            persistent_keepalive: config.persistent_keepalive,
+            endpoint: config.endpoint,
+            pending: None,
+            current: None,
+            previous: None,
+            next: None,
+            latest_timestamp: [0u8; 12],
+            sent_timestamp: [0u8; 12],
+            cookie: None,
+            queue: VecDeque::new(),
+            last_sent: None,
+            last_received: None,
+            last_data_sent: None,
+            last_data_received: None,
+            last_handshake: None,
+            handshake_rtt: None,
+            tx_bytes: 0,
+            rx_bytes: 0,
+            inbound,
+            inbound_rx: Some(inbound_rx),
+            sessions: watch::channel(0).0,
+        })
+    }
+
+    fn slot(&mut self, slot: Slot) -> &mut Option<Session> {
+        match slot {
+            Slot::Current => &mut self.current,
+            Slot::Previous => &mut self.previous,
+            Slot::Next => &mut self.next,
+        }
+    }
+
+    fn find_slot(&self, local_index: u32) -> Option<Slot> {
+        [
+            (Slot::Current, &self.current),
+            (Slot::Previous, &self.previous),
+            (Slot::Next, &self.next),
+        ]
+        .into_iter()
+        .find(|(_, session)| {
+            session
+                .as_ref()
+                .is_some_and(|s| s.local_index == local_index)
+        })
+        .map(|(slot, _)| slot)
+    }
+
+    fn fresh_cookie(&self, now: Instant) -> Option<&[u8; 16]> {
+        self.cookie
+            .as_ref()
+            .filter(|(_, received)| now.duration_since(*received) < COOKIE_REFRESH_TIME)
+            .map(|(cookie, _)| cookie)
+    }
+
+    /// Next initiation timestamp, strictly greater than the last one sent
+    fn next_timestamp(&mut self) -> [u8; 12] {
+        let mut timestamp = tai64n(SystemTime::now());
+        if timestamp <= self.sent_timestamp {
+            timestamp = self.sent_timestamp;
+            let nanos = u32::from_be_bytes(timestamp[8..].try_into().expect("4 bytes")) + 1;
+            timestamp[8..].copy_from_slice(&nanos.to_be_bytes());
+        }
+        self.sent_timestamp = timestamp;
+        timestamp
+    }
+
+    fn session_established(&mut self, now: Instant) {
+        self.last_handshake = Some(now);
+        self.sessions.send_modify(|generation| *generation += 1);
+    }
+}
+
+/// Secret for cookies, rotated every two minutes
+struct CookieChecker {
+    secret: [u8; 32],
+    rotated: Instant,
+}
+
+impl CookieChecker {
+    fn new() -> Self {
+        Self {
+            secret: rand::thread_rng().gen(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn cookie(&mut self, from: SocketAddr, now: Instant) -> [u8; 16] {
+        if now.duration_since(self.rotated) >= COOKIE_REFRESH_TIME {
+            self.secret = rand::thread_rng().gen();
+            self.rotated = now;
+        }
+
+        let mut source = match from.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        source.extend_from_slice(&from.port().to_be_bytes());
+        mac(&self.secret, &source)
+    }
+}
+
+struct DeviceState {
+    keys: LocalKeys,
+    peers: HashMap<[u8; 32], PeerState>,
+    /// Our session and handshake indices, mapped to their peer
+    indices: HashMap<u32, [u8; 32]>,
+    cookies: CookieChecker,
+    under_load: bool,
+}
+
+impl DeviceState {
+    fn peer_mut(&mut self, peer: &[u8; 32]) -> Result<&mut PeerState> {
+        self.peers
+            .get_mut(peer)
+            .ok_or_else(|| ScramblerError::VpnError("Unknown peer".to_string()))
+    }
+
+    /// Send an initiation to a peer, replacing any pending one
+    fn begin_handshake(&mut self, peer_key: &[u8; 32], now: Instant, out: &mut Outgoing) {
+        let Some(peer) = self.peers.get_mut(peer_key) else {
+            return;
+        };
+        let Some(endpoint) = peer.endpoint else {
+            return;
+        };
+
+        let started = match peer.pending.take() {
+            Some(pending) => {
+                self.indices.remove(&pending.local_index);
+                pending.started
+            }
+            None => now,
+        };
+        let local_index = allocate_index(&mut self.indices, *peer_key);
+
+        let mut state = SymmetricState::new(&peer.public_key);
+        let ephemeral = StaticSecret::from(rand::thread_rng().gen::<[u8; 32]>());
+        let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+        state.mix_key(&ephemeral_public);
+        state.mix_hash(&ephemeral_public);
+
+        let shared = match dh(&ephemeral, &peer.public_key) {
+            Ok(shared) => shared,
+            Err(e) => {
+                tracing::warn!(error = %e, "Can't handshake with peer");
+                self.indices.remove(&local_index);
+                return;
+            }
+        };
+        let key = state.mix_key_and_key(&shared);
+        let encrypted_static = state.encrypt_and_hash(&key, &self.keys.public);
+
+        let key = state.mix_key_and_key(&peer.static_static);
+        let timestamp = peer.next_timestamp();
+        let encrypted_timestamp = state.encrypt_and_hash(&key, &timestamp);
+
+        let mut message = Vec::with_capacity(INITIATION_SIZE);
+        message.extend_from_slice(&[1, 0, 0, 0]);
+        message.extend_from_slice(&local_index.to_le_bytes());
+        message.extend_from_slice(&ephemeral_public);
+        message.extend_from_slice(&encrypted_static);
+        message.extend_from_slice(&encrypted_timestamp);
+        let mac1 = add_macs(&mut message, &peer.mac1_key, peer.fresh_cookie(now));
+
+        peer.pending = Some(PendingHandshake {
+            local_index,
+            state,
+            ephemeral,
+            started,
+            sent: now,
+            mac1,
+        });
+        peer.last_sent = Some(now);
+        peer.tx_bytes += message.len() as u64;
+        out.push((message, endpoint));
+    }
+
+    /// Encrypt a packet to a peer, or queue it until a session exists
+    fn send_packet(
+        &mut self,
+        peer_key: &[u8; 32],
+        packet: &[u8],
+        now: Instant,
+        out: &mut Outgoing,
+    ) -> Result<()> {
+        let peer = self.peer_mut(peer_key)?;
+
+        let (endpoint, session) = match (peer.endpoint, peer.current.as_mut()) {
+            (Some(endpoint), Some(session)) if session.can_send(now) => (endpoint, session),
+            _ => {
+                if peer.queue.len() >= MAX_QUEUED_PACKETS {
+                    peer.queue.pop_front();
+                }
+                peer.queue.push_back(packet.to_vec());
+                if peer.pending.is_none() {
+                    self.begin_handshake(peer_key, now, out);
+                }
+                return Ok(());
+            }
+        };
+
+        let message = session.encrypt(packet);
+        let rekey = session.needs_rekey(now);
+
+        peer.last_sent = Some(now);
+        if !packet.is_empty() {
+            peer.last_data_sent = Some(now);
+        }
+        peer.tx_bytes += message.len() as u64;
+        out.push((message, endpoint));
+
+        if rekey && peer.pending.is_none() {
+            self.begin_handshake(peer_key, now, out);
+        }
+        Ok(())
+    }
+
+    /// Send queued packets, or a keepalive to confirm a fresh session
+    fn flush_queue(&mut self, peer_key: &[u8; 32], now: Instant, out: &mut Outgoing) {
+        let Some(peer) = self.peers.get_mut(peer_key) else {
+            return;
+        };
+        let queued = std::mem::take(&mut peer.queue);
+        if queued.is_empty() {
+            let _ = self.send_packet(peer_key, &[], now, out);
+        }
+        for packet in queued {
+            let _ = self.send_packet(peer_key, &packet, now, out);
+        }
+    }
+
+    fn drop_session(&mut self, peer_key: &[u8; 32], slot: Slot) {
+        if let Some(session) = self
+            .peers
+            .get_mut(peer_key)
+            .and_then(|peer| peer.slot(slot).take())
+        {
+            self.indices.remove(&session.local_index);
+        }
+    }
+
+    fn handle(
+        &mut self,
+        message: &[u8],
+        from: SocketAddr,
+        now: Instant,
+        out: &mut Outgoing,
+    ) -> Result<()> {
+        if message.len() < 4 || message[1..4] != [0, 0, 0] {
+            return Err(protocol_error("Malformed message"));
+        }
+
+        match (message[0], message.len()) {
+            (1, INITIATION_SIZE) => self.handle_initiation(message, from, now, out),
+            (2, RESPONSE_SIZE) => self.handle_response(message, from, now, out),
+            (3, COOKIE_REPLY_SIZE) => self.handle_cookie_reply(message, now, out),
+            (4, len) if len >= DATA_HEADER_SIZE + TAG_SIZE => {
+                self.handle_data(message, from, now, out)
+            }
+            _ => Err(protocol_error("Malformed message")),
+        }
+    }
+
+    fn handle_initiation(
+        &mut self,
+        message: &[u8],
+        from: SocketAddr,
+        now: Instant,
+        out: &mut Outgoing,
+    ) -> Result<()> {
+        verify_mac(&self.keys.mac1_key, &message[..116], &message[116..132])?;
+
+        if self.under_load {
+            let cookie = self.cookies.cookie(from, now);
+            if verify_mac(&cookie, &message[..132], &message[132..148]).is_err() {
+                let receiver = &message[4..8];
+                out.push((
+                    self.cookie_reply(receiver, &message[116..132], &cookie),
+                    from,
+                ));
+                return Ok(());
+            }
+        }
+
+        let remote_index = u32::from_le_bytes(message[4..8].try_into().expect("4 bytes"));
+        let initiator_ephemeral: [u8; 32] = message[8..40].try_into().expect("32 bytes");
+
+        let mut state = SymmetricState::new(&self.keys.public);
+        state.mix_key(&initiator_ephemeral);
+        state.mix_hash(&initiator_ephemeral);
+        let key = state.mix_key_and_key(&dh(&self.keys.secret, &initiator_ephemeral)?);
+        let initiator_static: [u8; 32] = state
+            .decrypt_and_hash(&key, &message[40..88])?
+            .try_into()
+            .map_err(|_| protocol_error("Malformed static key"))?;
+
+        let peer = self
+            .peers
+            .get_mut(&initiator_static)
+            .ok_or_else(|| protocol_error("Initiation from unknown peer"))?;
+        let key = state.mix_key_and_key(&peer.static_static);
+        let timestamp: [u8; 12] = state
+            .decrypt_and_hash(&key, &message[88..116])?
+            .try_into()
+            .map_err(|_| protocol_error("Malformed timestamp"))?;
+        if timestamp <= peer.latest_timestamp {
+            return Err(protocol_error("Replayed initiation"));
+        }
+        peer.latest_timestamp = timestamp;
+
+        // Response
+        let ephemeral = StaticSecret::from(rand::thread_rng().gen::<[u8; 32]>());
+        let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+        state.mix_key(&ephemeral_public);
+        state.mix_hash(&ephemeral_public);
+        state.mix_key(&dh(&ephemeral, &initiator_ephemeral)?);
+        state.mix_key(&dh(&ephemeral, &initiator_static)?);
+        let [chaining_key, tau, key] = kdf::<3>(&state.chaining_key, &peer.preshared_key);
+        state.chaining_key = chaining_key;
+        state.mix_hash(&tau);
+        let encrypted_nothing = state.encrypt_and_hash(&key, &[]);
+        let (recv_key, send_key) = state.split();
+
+        let local_index = allocate_index(&mut self.indices, initiator_static);
+        let mut response = Vec::with_capacity(RESPONSE_SIZE);
+        response.extend_from_slice(&[2, 0, 0, 0]);
+        response.extend_from_slice(&local_index.to_le_bytes());
+        response.extend_from_slice(&remote_index.to_le_bytes());
+        response.extend_from_slice(&ephemeral_public);
+        response.extend_from_slice(&encrypted_nothing);
+        add_macs(&mut response, &peer.mac1_key, peer.fresh_cookie(now));
+
+        let session = Session::new(local_index, remote_index, &send_key, &recv_key, now, false);
+        peer.endpoint = Some(from);
+        peer.last_received = Some(now);
+        peer.last_sent = Some(now);
+        peer.rx_bytes += message.len() as u64;
+        peer.tx_bytes += response.len() as u64;
+        if let Some(old) = peer.next.replace(session) {
+            self.indices.remove(&old.local_index);
+        }
+
+        out.push((response, from));
+        Ok(())
+    }
+
+    fn handle_response(
+        &mut self,
+        message: &[u8],
+        from: SocketAddr,
+        now: Instant,
+        out: &mut Outgoing,
+    ) -> Result<()> {
+        verify_mac(&self.keys.mac1_key, &message[..60], &message[60..76])?;
+
+        let remote_index = u32::from_le_bytes(message[4..8].try_into().expect("4 bytes"));
+        let local_index = u32::from_le_bytes(message[8..12].try_into().expect("4 bytes"));
+        let peer_key = *self
+            .indices
+            .get(&local_index)
+            .ok_or_else(|| protocol_error("Response for unknown index"))?;
+        let peer = self
+            .peers
+            .get_mut(&peer_key)
+            .ok_or_else(|| protocol_error("Unknown peer"))?;
+        let pending = peer
+            .pending
+            .as_ref()
+            .filter(|pending| pending.local_index == local_index)
+            .ok_or_else(|| protocol_error("Response without pending handshake"))?;
+
+        let responder_ephemeral: [u8; 32] = message[12..44].try_into().expect("32 bytes");
+        let mut state = pending.state.clone();
+        state.mix_key(&responder_ephemeral);
+        state.mix_hash(&responder_ephemeral);
+        state.mix_key(&dh(&pending.ephemeral, &responder_ephemeral)?);
+        state.mix_key(&dh(&self.keys.secret, &responder_ephemeral)?);
+        let [chaining_key, tau, key] = kdf::<3>(&state.chaining_key, &peer.preshared_key);
+        state.chaining_key = chaining_key;
+        state.mix_hash(&tau);
+        state.decrypt_and_hash(&key, &message[44..60])?;
+        let (send_key, recv_key) = state.split();
+
+        let sent = pending.sent;
+        peer.pending = None;
+        let session = Session::new(local_index, remote_index, &send_key, &recv_key, now, true);
+        let replaced = peer.previous.take();
+        peer.previous = peer.current.replace(session);
+        peer.endpoint = Some(from);
+        peer.last_received = Some(now);
+        peer.rx_bytes += message.len() as u64;
+        peer.handshake_rtt = Some(now.duration_since(sent));
+        peer.session_established(now);
+
+        if let Some(old) = replaced {
+            self.indices.remove(&old.local_index);
+        }
+        self.flush_queue(&peer_key, now, out);
+        Ok(())
+    }
+
+    fn handle_cookie_reply(
+        &mut self,
+        message: &[u8],
+        now: Instant,
+        out: &mut Outgoing,
+    ) -> Result<()> {
+        let local_index = u32::from_le_bytes(message[4..8].try_into().expect("4 bytes"));
+        let peer_key = *self
+            .indices
+            .get(&local_index)
+            .ok_or_else(|| protocol_error("Cookie reply for unknown index"))?;
+        let peer = self.peer_mut(&peer_key)?;
+        let pending = peer
+            .pending
+            .as_ref()
+            .filter(|pending| pending.local_index == local_index)
+            .ok_or_else(|| protocol_error("Cookie reply without pending handshake"))?;
+
+        let nonce: [u8; 24] = message[8..32].try_into().expect("24 bytes");
+        let cookie: [u8; 16] =
+            xaead_open(&peer.cookie_key, &nonce, &message[32..64], &pending.mac1)?
+                .try_into()
+                .map_err(|_| protocol_error("Malformed cookie"))?;
+        peer.cookie = Some((cookie, now));
+
+        // Retry right away; the new initiation carries a valid mac2
+        self.begin_handshake(&peer_key, now, out);
+        Ok(())
+    }
+
+    fn handle_data(
+        &mut self,
+        message: &[u8],
+        from: SocketAddr,
+        now: Instant,
+        out: &mut Outgoing,
+    ) -> Result<()> {
+        let local_index = u32::from_le_bytes(message[4..8].try_into().expect("4 bytes"));
+        let counter = u64::from_le_bytes(message[8..16].try_into().expect("8 bytes"));
+        let peer_key = *self
+            .indices
+            .get(&local_index)
+            .ok_or_else(|| protocol_error("Data for unknown index"))?;
+        let peer = self.peer_mut(&peer_key)?;
+        let slot = peer
+            .find_slot(local_index)
+            .ok_or_else(|| protocol_error("Data for unknown session"))?;
+        let session = peer.slot(slot).as_mut().expect("slot found above");
+
+        if now.duration_since(session.created) >= REJECT_AFTER_TIME
+            || !session.replay.check(counter)
+        {
+            return Err(protocol_error("Stale or replayed data"));
+        }
+        let mut payload = message[DATA_HEADER_SIZE..].to_vec();
+        let plaintext_len = session
+            .recv_key
+            .open_in_place(counter_nonce(counter), Aad::empty(), &mut payload)
+            .map_err(|_| protocol_error("Data authentication failed"))?
+            .len();
+        payload.truncate(plaintext_len);
+        session.replay.update(counter);
+
+        peer.endpoint = Some(from);
+        peer.last_received = Some(now);
+        peer.rx_bytes += message.len() as u64;
+        if !payload.is_empty() {
+            peer.last_data_received = Some(now);
+            if peer.inbound.try_send(payload).is_err() {
+                tracing::trace!("Inbound queue full, dropping packet");
+            }
+        }
+
+        // The initiator used the session we responded with: it's confirmed
+        if slot == Slot::Next {
+            let confirmed = peer.next.take();
+            let replaced = peer.previous.take();
+            peer.previous = std::mem::replace(&mut peer.current, confirmed);
+            peer.session_established(now);
+            if let Some(old) = replaced {
+                self.indices.remove(&old.local_index);
+            }
+            self.flush_queue_if_any(&peer_key, now, out);
+        }
+        Ok(())
+    }
+
+    fn flush_queue_if_any(&mut self, peer_key: &[u8; 32], now: Instant, out: &mut Outgoing) {
+        if self
+            .peers
+            .get(peer_key)
+            .is_some_and(|peer| !peer.queue.is_empty())
+        {
+            self.flush_queue(peer_key, now, out);
+        }
+    }
+
+    fn cookie_reply(&self, receiver: &[u8], mac1: &[u8], cookie: &[u8; 16]) -> Vec<u8> {
+        let nonce: [u8; 24] = rand::thread_rng().gen();
+        let encrypted = xaead_seal(&self.keys.cookie_key, &nonce, cookie, mac1);
+
+        let mut message = Vec::with_capacity(COOKIE_REPLY_SIZE);
+        message.extend_from_slice(&[3, 0, 0, 0]);
+        message.extend_from_slice(receiver);
+        message.extend_from_slice(&nonce);
+        message.extend_from_slice(&encrypted);
+        message
+    }
+
+    /// Run the protocol timers for every peer
+    fn tick(&mut self, now: Instant, out: &mut Outgoing) {
+        let peer_keys: Vec<[u8; 32]> = self.peers.keys().copied().collect();
+
+        for peer_key in peer_keys {
+            // Handshake retransmission and giving up
+            let pending = self.peers[&peer_key]
+                .pending
+                .as_ref()
+                .map(|p| (p.started, p.sent, p.local_index));
+            if let Some((started, sent, local_index)) = pending {
+                if now.duration_since(started) >= REKEY_ATTEMPT_TIME {
+                    let peer = self.peers.get_mut(&peer_key).expect("peer exists");
+                    peer.pending = None;
+                    peer.queue.clear();
+                    self.indices.remove(&local_index);
+                } else if now.duration_since(sent) >= REKEY_TIMEOUT {
+                    self.begin_handshake(&peer_key, now, out);
+                }
+            }
+
+            // Session expiry
+            for slot in [Slot::Current, Slot::Previous, Slot::Next] {
+                let expired = self
+                    .peers
+                    .get_mut(&peer_key)
+                    .and_then(|peer| {
+                        peer.slot(slot)
+                            .as_ref()
+                            .map(|s| now.duration_since(s.created) >= REJECT_AFTER_TIME)
+                    })
+                    .unwrap_or(false);
+                if expired {
+                    self.drop_session(&peer_key, slot);
+                }
+            }
+
+            let peer = &self.peers[&peer_key];
+            let has_session = peer.current.is_some();
+
+            // Rekey on age, or when sent data went unanswered
+            let stale = peer.current.as_ref().is_some_and(|s| s.needs_rekey(now));
+            let unanswered = peer.last_data_sent > peer.last_received
+                && peer.last_data_sent.is_some_and(|sent| {
+                    now.duration_since(sent) >= KEEPALIVE_TIMEOUT + REKEY_TIMEOUT
+                });
+            if peer.pending.is_none() && (stale || unanswered) {
+                self.begin_handshake(&peer_key, now, out);
+            }
+
+            // Keepalives: persistent, and passive after receiving data we haven't answered
+            let peer = &self.peers[&peer_key];
+            let persistent = peer.persistent_keepalive.is_some_and(|interval| {
+                peer.last_sent
+                    .map_or(true, |sent| now.duration_since(sent) >= interval)
+            });
+            let passive = peer.last_data_received > peer.last_sent
+                && peer
+                    .last_data_received
+                    .is_some_and(|received| now.duration_since(received) >= KEEPALIVE_TIMEOUT);
+            if has_session && (persistent || passive) {
+                let _ = self.send_packet(&peer_key, &[], now, out);
+            }
+        }
+    }
+}
+
+/// Random index not in use
+fn allocate_index(indices: &mut HashMap<u32, [u8; 32]>, peer: [u8; 32]) -> u32 {
+    let mut rng = rand::thread_rng();
+    loop {
+        let index: u32 = rng.gen();
+        if let std::collections::hash_map::Entry::Vacant(entry) = indices.entry(index) {
+            entry.insert(peer);
+            return index;
+        }
+    }
+}
+
+/// Append mac1 and mac2 (zero without a cookie)
+///
+/// # Returns
+/// * The mac1
+fn add_macs(message: &mut Vec<u8>, mac1_key: &[u8; 32], cookie: Option<&[u8; 16]>) -> [u8; 16] {
+    let mac1 = mac(mac1_key, message);
+    message.extend_from_slice(&mac1);
+    let mac2 = cookie.map_or([0u8; 16], |cookie| mac(cookie, message));
+    message.extend_from_slice(&mac2);
+    mac1
+}
+
+fn verify_mac(key: &[u8], data: &[u8], expected: &[u8]) -> Result<()> {
+    if bool::from(mac(key, data).ct_eq(expected)) {
+        Ok(())
+    } else {
+        Err(protocol_error("Invalid MAC"))
+    }
+}
+
+fn protocol_error(message: &str) -> ScramblerError {
+    ScramblerError::VpnError(message.to_string())
+}
+
+/// Noise chaining key and handshake hash
+#[derive(Clone)]
+struct SymmetricState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+}
+
+impl SymmetricState {
+    fn new(responder_static: &[u8; 32]) -> Self {
+        let chaining_key = hash(&[CONSTRUCTION]);
+        let hash = hash(&[&hash(&[&chaining_key, IDENTIFIER]), responder_static]);
+        Self { chaining_key, hash }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = hash(&[&self.hash, data]);
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        [self.chaining_key] = kdf::<1>(&self.chaining_key, input);
+    }
+
+    fn mix_key_and_key(&mut self, input: &[u8]) -> [u8; 32] {
+        let [chaining_key, key] = kdf::<2>(&self.chaining_key, input);
+        self.chaining_key = chaining_key;
+        key
+    }
+
+    fn encrypt_and_hash(&mut self, key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = seal(key, counter_nonce(0), plaintext, &self.hash);
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, key: &[u8; 32], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let plaintext = open(key, counter_nonce(0), ciphertext, &self.hash)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Transport keys: (initiator to responder, responder to initiator)
+    fn split(&self) -> ([u8; 32], [u8; 32]) {
+        let [first, second] = kdf::<2>(&self.chaining_key, &[]);
+        (first, second)
+    }
+}
+
+impl Drop for SymmetricState {
+    fn drop(&mut self) {
+        self.chaining_key.zeroize();
+        self.hash.zeroize();
+    }
+}
+
+/// Sliding window of received counters
+#[derive(Default)]
+struct ReplayWindow {
+    /// One more than the greatest counter accepted
+    next: u64,
+    bitmap: [u64; (REPLAY_WINDOW / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn bit(counter: u64) -> (usize, u64) {
+        let position = counter % REPLAY_WINDOW;
+        ((position / 64) as usize, 1 << (position % 64))
+    }
+
+    /// Whether a counter is new and within the window
+    fn check(&self, counter: u64) -> bool {
+        if counter >= REJECT_AFTER_MESSAGES {
+            return false;
+        }
+        if counter >= self.next {
+            return true;
+        }
+        if self.next - counter > REPLAY_WINDOW {
+            return false;
+        }
+        let (word, mask) = Self::bit(counter);
+        self.bitmap[word] & mask == 0
+    }
+
+    /// Mark a checked, authenticated counter as received
+    fn update(&mut self, counter: u64) {
+        if counter >= self.next {
+            // Forget the counters the window slides past
+            let skipped = (counter - self.next).min(REPLAY_WINDOW);
+            for offset in 0..skipped {
+                let (word, mask) = Self::bit(self.next + offset);
+                self.bitmap[word] &= !mask;
+            }
+            self.next = counter + 1;
+        }
+        let (word, mask) = Self::bit(counter);
+        self.bitmap[word] |= mask;
+    }
+}
+
+/// TAI64N timestamp with whitened nanoseconds
+fn tai64n(now: SystemTime) -> [u8; 12] {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut timestamp = [0u8; 12];
+    timestamp[..8].copy_from_slice(&(TAI64_BASE + since_epoch.as_secs()).to_be_bytes());
+    timestamp[8..]
+        .copy_from_slice(&(since_epoch.subsec_nanos() & !TIMESTAMP_WHITENER).to_be_bytes());
+    timestamp
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    for part in parts {
+        Digest::update(&mut hasher, part);
+    }
+    hasher.finalize().into()
+}
+
+/// Keyed BLAKE2s with a 16-byte output
+fn mac(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut mac = <Blake2sMac<U16> as KeyInit>::new_from_slice(key)
+        .expect("BLAKE2s keys are at most 32 bytes");
+    Mac::update(&mut mac, data);
+    mac.finalize().into_bytes().into()
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <SimpleHmac<Blake2s256> as KeyInit>::new_from_slice(key)
+        .expect("HMAC takes keys of any length");
+    for part in parts {
+        Mac::update(&mut mac, part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// WireGuard's HKDF with HMAC-BLAKE2s, returning `N` outputs
+fn kdf<const N: usize>(key: &[u8; 32], input: &[u8]) -> [[u8; 32]; N] {
+    let mut secret = hmac(key, &[input]);
+    let mut outputs = [[0u8; 32]; N];
+    for i in 0..N {
+        let previous: &[u8] = if i == 0 { &[] } else { &outputs[i - 1] };
+        outputs[i] = hmac(&secret, &[previous, &[i as u8 + 1]]);
+    }
+    secret.zeroize();
+    outputs
+}
+
+fn dh(secret: &StaticSecret, public: &[u8; 32]) -> Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+        return Err(protocol_error("Low-order public key"));
+    }
+    Ok(shared.to_bytes())
+}
+
+fn aead_key(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(
+        UnboundKey::new(&CHACHA20_POLY1305, key).expect("ChaCha20-Poly1305 keys are 32 bytes"),
+    )
+}
+
+/// 96-bit AEAD nonce: 32 zero bits followed by the little-endian counter
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn seal(key: &[u8; 32], nonce: Nonce, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut buffer = plaintext.to_vec();
+    aead_key(key)
+        .seal_in_place_append_tag(nonce, Aad::from(aad), &mut buffer)
+        .expect("ChaCha20-Poly1305 sealing can't fail for handshake-sized input");
+    buffer
+}
+
+fn open(key: &[u8; 32], nonce: Nonce, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let mut buffer = ciphertext.to_vec();
+    let len = aead_key(key)
+        .open_in_place(nonce, Aad::from(aad), &mut buffer)
+        .map_err(|_| protocol_error("Handshake authentication failed"))?
+        .len();
+    buffer.truncate(len);
+    Ok(buffer)
+}
+
+/// XChaCha20-Poly1305: HChaCha20 subkey, then ChaCha20-Poly1305
+fn xaead_seal(key: &[u8; 32], nonce: &[u8; 24], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let (subkey, nonce) = xchacha_subkey(key, nonce);
+    seal(&subkey, nonce, plaintext, aad)
+}
+
+fn xaead_open(key: &[u8; 32], nonce: &[u8; 24], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let (subkey, nonce) = xchacha_subkey(key, nonce);
+    open(&subkey, nonce, ciphertext, aad)
+}
+
+fn xchacha_subkey(key: &[u8; 32], nonce: &[u8; 24]) -> ([u8; 32], Nonce) {
+    let subkey = hchacha20(key, nonce[..16].try_into().expect("16 bytes"));
+    let mut chacha_nonce = [0u8; 12];
+    chacha_nonce[4..].copy_from_slice(&nonce[16..]);
+    (subkey, Nonce::assume_unique_for_key(chacha_nonce))
+}
+
+/// HChaCha20 (draft-irtf-cfrg-xchacha, section 2.2)
+fn hchacha20(key: &[u8; 32], nonce: &[u8; 16]) -> [u8; 32] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (i, word) in key.chunks_exact(4).enumerate() {
+        state[4 + i] = u32::from_le_bytes(word.try_into().expect("4 bytes"));
+    }
+    for (i, word) in nonce.chunks_exact(4).enumerate() {
+        state[12 + i] = u32::from_le_bytes(word.try_into().expect("4 bytes"));
+    }
+
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut output = [0u8; 32];
+    for (i, word) in state[..4].iter().chain(&state[12..]).enumerate() {
+        output[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    state.zeroize();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two devices configured as each other's peer
+    async fn device_pair() -> (WireGuardDevice, WireGuardDevice) {
+        let (client_private, client_public) = generate_keypair();
+        let (server_private, server_public) = generate_keypair();
+
+        let server = WireGuardDevice::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            server_private,
+            vec![PeerConfig {
+                public_key: client_public,
+                endpoint: None,
+                preshared_key: None,
+                persistent_keepalive: None,
+            }],
+        )
+        .await
+        .unwrap();
+
+        let client = WireGuardDevice::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            client_private,
+            vec![PeerConfig {
+                public_key: server_public,
+                endpoint: Some(server.local_addr().unwrap()),
+                preshared_key: None,
+                persistent_keepalive: None,
+            }],
+        )
+        .await
+        .unwrap();
+
+        (client, server)
+    }
+
+    async fn exchange(client: &WireGuardTunnel, server: &WireGuardTunnel, packet: &[u8]) {
+        client.send(packet.to_vec()).await.unwrap();
+        let received = server.recv().await.unwrap().unwrap();
+        // Plaintext is padded to 16 bytes
+        assert_eq!(&received[..packet.len()], packet);
+        assert!(received[packet.len()..].iter().all(|&b| b == 0));
+
+        server.send(received.clone()).await.unwrap();
+        assert_eq!(client.recv().await.unwrap().unwrap(), received);
+    }
+
+    #[test]
+    fn test_protocol_constants() {
+        // Initial chaining key and hash from the WireGuard reference implementation
+        let state = SymmetricState::new(&[0u8; 32]);
+        assert_eq!(
+            hex::encode(state.chaining_key),
+            "60e26daef327efc02ec335e2a025d2d016eb4206f87277f52d38d1988b78cd36"
+        );
+        assert_eq!(
+            hex::encode(hash(&[&state.chaining_key, IDENTIFIER])),
+            "2211b361081ac566691243db458ad5322d9c6c662293e8b70ee19c65ba079ef3"
+        );
+
+        assert_eq!(
+            hex::encode(hash(&[b"abc"])),
+            "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+        );
+
+        // draft-irtf-cfrg-xchacha-03, section 2.2.1
+        let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let nonce: [u8; 16] = hex::decode("000000090000004a0000000031415927")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            hex::encode(hchacha20(&key, &nonce)),
+            "82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc"
+        );
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        for counter in [0, 1, 5, 3] {
+            assert!(window.check(counter));
+            window.update(counter);
+        }
+        assert!(!window.check(3));
+        assert!(window.check(2));
+
+        window.update(5000);
+        assert!(!window.check(5000 - REPLAY_WINDOW - 1));
+        assert!(window.check(5000 - 100));
+        assert!(!window.check(REJECT_AFTER_MESSAGES));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_transport() {
+        let (client, server) = device_pair().await;
+        let server_key = server.public_key();
+        let client_key = client.public_key();
+
+        client
+            .handshake(&server_key, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let client_tunnel = client.tunnel(&server_key).unwrap();
+        let server_tunnel = server.tunnel(&client_key).unwrap();
+        assert!(client.tunnel(&server_key).is_err());
+
+        exchange(&client_tunnel, &server_tunnel, b"first packet").await;
+        exchange(&client_tunnel, &server_tunnel, &[0xab; 1400]).await;
+
+        // Rekeying keeps the tunnel working
+        let first = client.peer_stats(&server_key).unwrap().last_handshake;
+        client
+            .handshake(&server_key, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_ne!(
+            client.peer_stats(&server_key).unwrap().last_handshake,
+            first
+        );
+        exchange(&client_tunnel, &server_tunnel, b"after rekey").await;
+
+        let stats = client.peer_stats(&server_key).unwrap();
+        assert!(stats.handshake_rtt.is_some());
+        assert!(stats.tx_bytes > 0 && stats.rx_bytes > 0);
+    }
+
+    #[tokio::test]
+    async fn test_packets_queued_until_handshake() {
+        let (client, server) = device_pair().await;
+        let client_tunnel = client.tunnel(&server.public_key()).unwrap();
+        let server_tunnel = server.tunnel(&client.public_key()).unwrap();
+
+        // Sending without a session starts the handshake
+        client_tunnel.send(b"queued".to_vec()).await.unwrap();
+        let received = server_tunnel.recv().await.unwrap().unwrap();
+        assert_eq!(&received[..6], b"queued");
+    }
+
+    #[tokio::test]
+    async fn test_cookie_reply_under_load() {
+        let (client, server) = device_pair().await;
+        server.set_under_load(true);
+
+        client
+            .handshake(&server.public_key(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        let client_tunnel = client.tunnel(&server.public_key()).unwrap();
+        let server_tunnel = server.tunnel(&client.public_key()).unwrap();
+        exchange(&client_tunnel, &server_tunnel, b"under load").await;
+    }
+
+    #[tokio::test]
+    async fn test_unknown_peer_gets_no_response() {
+        let (client, server) = device_pair().await;
+        let (stranger_private, _) = generate_keypair();
+        let stranger = WireGuardDevice::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            stranger_private,
+            vec![PeerConfig {
+                public_key: server.public_key(),
+                endpoint: Some(server.local_addr().unwrap()),
+                preshared_key: None,
+                persistent_keepalive: None,
+            }],
+        )
+        .await
+        .unwrap();
+
+        let result = stranger
+            .handshake(&server.public_key(), Duration::from_millis(500))
+            .await;
+        assert!(matches!(result, Err(ScramblerError::VpnError(_))));
+
+        // A client expecting a different server key is ignored too
+        let (_, wrong_key) = generate_keypair();
+        let mistaken = WireGuardDevice::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            generate_keypair().0,
+            vec![PeerConfig {
+                public_key: wrong_key,
+                endpoint: Some(server.local_addr().unwrap()),
+                preshared_key: None,
+                persistent_keepalive: None,
+            }],
+        )
+        .await
+        .unwrap();
+        assert!(mistaken
+            .handshake(&wrong_key, Duration::from_millis(500))
+            .await
+            .is_err());
+
+        // The configured client still gets through
+        client
+            .handshake(&server.public_key(), Duration::from_secs(5))
+            .await
+            .unwrap();
+    }
+}
@@ -158,6 +158,13 @@ impl Database {
                 FOREIGN KEY (account_id) REFERENCES wallet_accounts(id)
             );
 
+            CREATE TABLE IF NOT EXISTS sessions (
+                contact_id TEXT PRIMARY KEY,
+                state BLOB NOT NULL,
+                state_version INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+
             CREATE INDEX IF NOT EXISTS idx_messages_conversation ON messages(conversation_id);
             CREATE INDEX IF NOT EXISTS idx_messages_timestamp ON messages(timestamp);
             CREATE INDEX IF NOT EXISTS idx_transactions_account ON transactions(account_id);
@@ -172,6 +179,11 @@ impl Database {
         &self.conn
     }
 
+    /// Get the SQLCipher key (for deriving per-record keys)
+    pub(crate) fn encryption_key(&self) -> &str {
+        &self.config.encryption_key
+    }
+
     /// Close database and clear sensitive data
     pub fn close(self) -> Result<()> {
         // Connection will be closed when dropped
@@ -47,6 +47,12 @@ impl From<rusqlite::Error> for StorageError {
     }
 }
 
+impl From<invisible_crypto::CryptoError> for StorageError {
+    fn from(err: invisible_crypto::CryptoError) -> Self {
+        StorageError::EncryptionError(err.to_string())
+    }
+}
+
 impl From<serde_json::Error> for StorageError {
     fn from(err: serde_json::Error) -> Self {
         StorageError::SerializationError(err.to_string())
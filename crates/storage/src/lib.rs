@@ -18,7 +18,8 @@
 //! - `messages` - End-to-end encrypted messages
 //! - `conversations` - Conversation metadata
 //! - `contacts` - Contact identity keys and info
-//! - `keys` - Ratchet state and pre-keys
+//! - `keys` - Pre-keys
+//! - `sessions` - Sealed Double Ratchet state per contact
 //! - `wallet_accounts` - Wallet accounts and balances
 //! - `transactions` - Transaction history
 
@@ -34,6 +35,7 @@ pub mod error;
 pub mod database;
 pub mod messages;
 pub mod contacts;
+pub mod sessions;
 pub mod wallet;
 pub mod migrations;
 
@@ -0,0 +1,141 @@
+//! Double Ratchet session storage
+//!
+//! Sessions are sealed with `DoubleRatchet::export_state` under a key derived
+//! from the database key and the contact ID, so a state can't be moved to
+//! another contact's row without failing authentication on load.
+
+use invisible_crypto::double_ratchet::STATE_VERSION;
+use invisible_crypto::kdf::hkdf_sha256;
+use invisible_crypto::DoubleRatchet;
+use rusqlite::{params, OptionalExtension};
+use zeroize::Zeroizing;
+
+use crate::database::Database;
+use crate::error::Result;
+
+impl Database {
+    /// Store a contact's session, replacing any previous one
+    pub fn store_session(&self, contact_id: &str, session: &DoubleRatchet) -> Result<()> {
+        let state = session.export_state(&self.session_key(contact_id)?)?;
+
+        self.connection().execute(
+            "INSERT OR REPLACE INTO sessions (contact_id, state, state_version, updated_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                contact_id,
+                &state,
+                STATE_VERSION,
+                chrono::Utc::now().timestamp(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Load a contact's session
+    ///
+    /// Sessions stored by an older state format are upgraded on load and
+    /// written back in the current format by the next `store_session`.
+    pub fn load_session(&self, contact_id: &str) -> Result<Option<DoubleRatchet>> {
+        let state: Option<Vec<u8>> = self
+            .connection()
+            .query_row(
+                "SELECT state FROM sessions WHERE contact_id = ?1",
+                params![contact_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match state {
+            Some(state) => Ok(Some(DoubleRatchet::import_state(
+                &state,
+                &self.session_key(contact_id)?,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete a contact's session
+    pub fn delete_session(&self, contact_id: &str) -> Result<()> {
+        self.connection()
+            .execute("DELETE FROM sessions WHERE contact_id = ?1", params![contact_id])?;
+        Ok(())
+    }
+
+    /// Key a contact's session state is sealed with
+    fn session_key(&self, contact_id: &str) -> Result<Zeroizing<Vec<u8>>> {
+        let info = [b"InvisibleSessionState".as_slice(), contact_id.as_bytes()].concat();
+
+        Ok(Zeroizing::new(hkdf_sha256(
+            self.encryption_key().as_bytes(),
+            None,
+            &info,
+            32,
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::DatabaseConfig;
+    use invisible_crypto::KeyPair;
+    use tempfile::tempdir;
+
+    fn config(path: std::path::PathBuf) -> DatabaseConfig {
+        DatabaseConfig {
+            path,
+            encryption_key: "test_key_12345678901234567890".to_string(),
+            kdf_iter: 64000,
+        }
+    }
+
+    #[test]
+    fn test_session_persistence() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let shared_secret = [1u8; 32];
+        let bob_key = KeyPair::generate().unwrap();
+        let mut alice =
+            DoubleRatchet::init_alice(&shared_secret, bob_key.public_key().to_vec()).unwrap();
+        let mut bob = DoubleRatchet::init_bob(&shared_secret, bob_key).unwrap();
+
+        let first = alice.encrypt(b"first", b"ad").unwrap();
+        let second = alice.encrypt(b"second", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&second, b"ad").unwrap(), b"second");
+
+        {
+            let db = Database::open(config(path.clone())).unwrap();
+            assert!(db.load_session("alice").unwrap().is_none());
+            db.store_session("alice", &bob).unwrap();
+        }
+
+        // Reopened, including the skipped key for the first message
+        let db = Database::open(config(path)).unwrap();
+        let mut bob = db.load_session("alice").unwrap().unwrap();
+        assert_eq!(bob.decrypt(&first, b"ad").unwrap(), b"first");
+
+        db.delete_session("alice").unwrap();
+        assert!(db.load_session("alice").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_session_bound_to_contact() {
+        let dir = tempdir().unwrap();
+        let db = Database::open(config(dir.path().join("test.db"))).unwrap();
+        let session = DoubleRatchet::init_bob(&[1u8; 32], KeyPair::generate().unwrap()).unwrap();
+        db.store_session("alice", &session).unwrap();
+
+        // Copy Alice's state into Mallory's row
+        db.connection()
+            .execute(
+                "INSERT INTO sessions (contact_id, state, state_version, updated_at)
+                 SELECT 'mallory', state, state_version, updated_at FROM sessions
+                 WHERE contact_id = 'alice'",
+                [],
+            )
+            .unwrap();
+
+        assert!(db.load_session("alice").unwrap().is_some());
+        assert!(db.load_session("mallory").is_err());
+    }
+}